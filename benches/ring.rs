@@ -0,0 +1,54 @@
+// Cost of moving audio between the sound card and the MAC
+//
+// `cargo bench --bench ring`. The process callback is timed at a 64 sample
+// JACK buffer, recording and playing at once, with the other side of both
+// rings drained and refilled outside the timed part.
+
+use criterion::{Criterion, black_box, criterion_group, criterion_main};
+
+use trackmaker_rs::audio::recorder::{
+    AppShared, AppState, build_process_closure,
+};
+use trackmaker_rs::audio::ring::SampleRing;
+
+const BLOCK: usize = 64;
+
+fn ring(c: &mut Criterion) {
+    let ring = SampleRing::new(BLOCK * 16);
+    let block = [0.5; BLOCK];
+    let mut out = [0.0; BLOCK];
+    c.bench_function("ring push+pop 64", |b| {
+        b.iter(|| {
+            ring.push_slice(black_box(&block));
+            ring.pop_slice(black_box(&mut out))
+        })
+    });
+}
+
+fn process_callback(c: &mut Criterion) {
+    let shared = AppShared::new(BLOCK * 16);
+    let mut process = build_process_closure(shared.clone());
+    let input = [0.25; BLOCK];
+    let mut output = [0.0; BLOCK];
+    let outgoing = [0.5; BLOCK * 8];
+    c.bench_function("process callback 64 recording+playing", |b| {
+        b.iter_batched(
+            || {
+                shared.record_buffer.pop_all();
+                if shared.playback_buffer.len() < BLOCK {
+                    shared
+                        .playback_buffer
+                        .push_slice(&outgoing);
+                }
+                shared
+                    .app_state
+                    .set(AppState::RecordingAndPlaying);
+            },
+            |()| process(black_box(&input), black_box(&mut output)),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, ring, process_callback);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 use audio::recorder;
-use device::jack::print_jack_info;
+use device::jack::{port_process_closure, print_jack_info};
 use dialoguer::{Select, theme::ColorfulTheme};
 use jack;
 use rand::{self, Rng, SeedableRng};
@@ -9,12 +9,13 @@ use trackmaker_rs::{audio, device, ui, utils};
 use ui::print_banner;
 use ui::progress::{ProgressManager, templates};
 use utils::consts::*;
-use utils::logging::init_logging;
+use utils::logging::{LogOptions, init_logging_with};
 
 use crate::device::jack::connect_system_ports;
 
 fn main() {
-    init_logging();
+    init_logging_with(&LogOptions::default())
+        .expect("Default logging can be set up");
     print_banner();
 
     let (client, status) = jack::Client::new(
@@ -42,11 +43,10 @@ fn main() {
     let out_port_name = out_port.name().unwrap();
 
     // Process Callback
-    let process_cb = recorder::build_process_closure(
+    let process_cb = port_process_closure(
         in_port,
         out_port,
-        shared_cb,
-        max_duration_samples,
+        recorder::build_process_closure(shared_cb),
     );
     let process = jack::contrib::ClosureProcessHandler::new(process_cb);
 
@@ -60,7 +60,9 @@ fn main() {
         active_client.as_client(),
         in_port_name.as_str(),
         out_port_name.as_str(),
-    );
+        &Default::default(),
+    )
+    .unwrap();
 
     let selections = &["Sender", "Receiver", "Test (no JACK)"];
     let selection = Select::with_theme(&ColorfulTheme::default())
@@ -70,13 +72,7 @@ fn main() {
         .interact()
         .unwrap();
 
-    {
-        shared
-            .record_buffer
-            .lock()
-            .unwrap()
-            .clear();
-    }
+    shared.record_buffer.clear();
 
     if selection == 0 {
         // Sender
@@ -219,14 +215,11 @@ fn run_sender(
 
     let output_track_len = output_track.len();
 
-    {
-        let mut playback = shared
-            .playback_buffer
-            .lock()
-            .unwrap();
-        playback.extend(output_track);
-        info!("Output track length: {} samples", playback.len());
-    }
+    shared.queue_playback(&output_track);
+    info!(
+        "Output track length: {} samples",
+        shared.playback_buffer.len()
+    );
 
     progress_manager
         .create_bar(
@@ -237,23 +230,16 @@ fn run_sender(
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Playing;
+        .set(recorder::AppState::Playing);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
 
         ui::update_progress(&shared, output_track_len, &progress_manager);
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
@@ -304,10 +290,9 @@ fn run_receiver(
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Recording;
+        .set(recorder::AppState::Recording);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
@@ -318,29 +303,17 @@ fn run_receiver(
             &progress_manager,
         );
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
         }
     }
 
-    let rx_fifo: std::collections::VecDeque<f32> = {
-        let record = shared
-            .record_buffer
-            .lock()
-            .unwrap();
-        record
-            .iter()
-            .copied()
-            .collect()
-    };
+    let rx_fifo: std::collections::VecDeque<f32> = shared
+        .record_buffer
+        .peek_all()
+        .into();
 
     let mut power = 0.0f32;
     let mut power_debug = vec![0.0f32; rx_fifo.len()];
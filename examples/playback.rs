@@ -95,10 +95,9 @@ fn main() {
     }
 
     let shared = recorder::AppShared::new(10 * sample_rate as usize);
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Recording;
+        .set(recorder::AppState::Recording);
     println!("Audio processing started. Running for 10 seconds...");
 
     // Wait for 10 seconds
@@ -2,7 +2,7 @@ use audio::recorder;
 use device::jack::{
     connect_input_from_first_system_output,
     connect_output_to_first_system_input, disconnect_input_sources,
-    disconnect_output_sinks, print_jack_info,
+    disconnect_output_sinks, port_process_closure, print_jack_info,
 };
 use jack;
 use tracing::info;
@@ -10,12 +10,13 @@ use trackmaker_rs::*;
 use ui::print_banner;
 use ui::progress::{ProgressManager, templates};
 use utils::consts::*;
-use utils::logging::init_logging;
+use utils::logging::{LogOptions, init_logging_with};
 
 use crate::device::jack::connect_system_ports;
 
 fn main() {
-    init_logging();
+    init_logging_with(&LogOptions::default())
+        .expect("Default logging can be set up");
     print_banner();
     let (client, status) = jack::Client::new(
         JACK_CLIENT_NAME,
@@ -47,11 +48,10 @@ fn main() {
     let out_port_name = out_port.name().unwrap();
 
     // Process Callback
-    let process_cb = recorder::build_process_closure(
+    let process_cb = port_process_closure(
         in_port,
         out_port,
-        shared_cb,
-        recording_duration_samples,
+        recorder::build_process_closure(shared_cb),
     );
     let process = jack::contrib::ClosureProcessHandler::new(process_cb);
 
@@ -75,10 +75,9 @@ fn main() {
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Recording;
+        .set(recorder::AppState::Recording);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
@@ -89,13 +88,7 @@ fn main() {
             &progress_manager,
         );
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
@@ -110,18 +103,7 @@ fn main() {
     );
 
     // Copy to playback buffer
-    {
-        let mut recorded = shared
-            .record_buffer
-            .lock()
-            .unwrap();
-        let mut playback = shared
-            .playback_buffer
-            .lock()
-            .unwrap();
-
-        playback.extend(recorded.drain(..));
-    }
+    shared.queue_playback(&shared.record_buffer.pop_all());
 
     progress_manager
         .create_bar(
@@ -132,10 +114,9 @@ fn main() {
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Playing;
+        .set(recorder::AppState::Playing);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
@@ -146,13 +127,7 @@ fn main() {
             &progress_manager,
         );
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
@@ -165,18 +140,14 @@ fn main() {
         active_client.as_client(),
         &in_port_name,
         &out_port_name,
-    );
+        &Default::default(),
+    )
+    .unwrap();
 
     // Copy to playback buffer
-    {
-        let mut playback = shared
-            .playback_buffer
-            .lock()
-            .unwrap();
-
-        info!("Filling playback buffer with music from sample.flac");
+    info!("Filling playback buffer with music from sample.flac");
 
-        let mut music = Vec::new();
+    let music: Vec<f32> =
         audio::codec::decode_flac_to_f32("./assets/sample.flac")
             .unwrap_or_else(|_| {
                 tracing::warn!("Failed to decode sample.flac, using silence");
@@ -184,12 +155,11 @@ fn main() {
             })
             .into_iter()
             .take(recording_duration_samples as usize)
-            .for_each(|s| music.push(s));
+            .collect();
 
-        info!("Music length: {} samples", music.len());
+    info!("Music length: {} samples", music.len());
 
-        playback.extend(music.drain(..));
-    }
+    shared.queue_playback(&music);
 
     progress_manager
         .create_bar(
@@ -200,10 +170,9 @@ fn main() {
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::RecordingAndPlaying;
+        .set(recorder::AppState::RecordingAndPlaying);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
@@ -214,13 +183,7 @@ fn main() {
             &progress_manager,
         );
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
@@ -231,18 +194,7 @@ fn main() {
     disconnect_input_sources(active_client.as_client(), &in_port_name);
 
     // Copy to playback buffer
-    {
-        let mut recorded = shared
-            .record_buffer
-            .lock()
-            .unwrap();
-        let mut playback = shared
-            .playback_buffer
-            .lock()
-            .unwrap();
-
-        playback.extend(recorded.drain(..));
-    }
+    shared.queue_playback(&shared.record_buffer.pop_all());
 
     progress_manager
         .create_bar(
@@ -253,10 +205,9 @@ fn main() {
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Playing;
+        .set(recorder::AppState::Playing);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
@@ -267,13 +218,7 @@ fn main() {
             &progress_manager,
         );
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
@@ -0,0 +1,73 @@
+//! Send one command to a running router's management socket and print the
+//! answer.
+//!
+//!   cargo run --example router-ctl -- show arp
+//!   cargo run --example router-ctl -- add route 10.5.0.0/16:192.168.2.2:wifi
+//!   cargo run --example router-ctl -- --addr 127.0.0.1:7878 del fw 0
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+
+use serde_json::{Value, json};
+use trackmaker_rs::utils::consts::ROUTER_MGMT_ADDR;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args()
+        .skip(1)
+        .collect();
+    let addr = match args
+        .first()
+        .map(String::as_str)
+    {
+        Some("--addr") if args.len() > 1 => {
+            let addr = args.remove(1);
+            args.remove(0);
+            addr
+        }
+        _ => ROUTER_MGMT_ADDR.to_string(),
+    };
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: router-ctl [--addr host:port] <show|add|del|flush> <what> [arg...]"
+        );
+        std::process::exit(2);
+    }
+
+    // Two words name the command, whatever follows is its argument
+    let command = args[..2].join(" ");
+    let request = if args.len() > 2 {
+        json!({ "command": command, "arg": args[2..].join(" ") })
+    } else {
+        json!({ "command": command })
+    };
+
+    let stream = TcpStream::connect(&addr).unwrap_or_else(|e| {
+        eprintln!("Cannot reach the router at {}: {}", addr, e);
+        std::process::exit(1);
+    });
+    let mut writer = stream.try_clone().unwrap();
+    writeln!(writer, "{}", request).unwrap();
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .unwrap();
+
+    let response: Value = serde_json::from_str(&line).unwrap_or_else(|e| {
+        eprintln!("Bad answer from the router: {}", e);
+        std::process::exit(1);
+    });
+    if response["ok"] == true {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response["result"]).unwrap()
+        );
+    } else {
+        eprintln!(
+            "Error: {}",
+            response["error"]
+                .as_str()
+                .unwrap_or("?")
+        );
+        std::process::exit(1);
+    }
+}
@@ -7,7 +7,9 @@ use tracing::{debug, error, info, trace, warn};
 use trackmaker_rs::{audio, device, mac, phy, ui, utils};
 
 use audio::recorder;
-use device::jack::{connect_system_ports, print_jack_info};
+use device::jack::{
+    connect_system_ports, port_process_closure, print_jack_info,
+};
 use rand::Rng;
 use std::sync::{
     Arc,
@@ -16,9 +18,11 @@ use std::sync::{
 use ui::print_banner;
 use ui::progress::{ProgressManager, templates};
 use utils::consts::*;
-use utils::logging::init_logging;
+use utils::logging::{LogOptions, init_logging_with};
 
-use phy::{Frame, FrameType, LineCodingKind, PhyDecoder, PhyEncoder};
+use mac::noise::NoiseFloor;
+use phy::params::PhyParams;
+use phy::{FecKind, Frame, FrameType, LineCodingKind, PhyDecoder, PhyEncoder};
 
 #[derive(Parser)]
 #[command(name = "trackmaker-rs")]
@@ -91,7 +95,8 @@ fn parse_line_coding(encoding: &str) -> LineCodingKind {
 }
 
 fn main() {
-    init_logging();
+    init_logging_with(&LogOptions::default())
+        .expect("Default logging can be set up");
     print_banner();
 
     let cli = Cli::parse();
@@ -169,11 +174,10 @@ fn main() {
     let out_port_name = out_port.name().unwrap();
 
     // Process Callback
-    let process_cb = recorder::build_process_closure(
+    let process_cb = port_process_closure(
         in_port,
         out_port,
-        shared_cb,
-        max_duration_samples,
+        recorder::build_process_closure(shared_cb),
     );
     let process = jack::contrib::ClosureProcessHandler::new(process_cb);
 
@@ -187,14 +191,12 @@ fn main() {
         active_client.as_client(),
         in_port_name.as_str(),
         out_port_name.as_str(),
-    );
+        &Default::default(),
+    )
+    .unwrap();
 
     {
-        shared
-            .record_buffer
-            .lock()
-            .unwrap()
-            .clear();
+        shared.record_buffer.clear();
     }
 
     if selection == 0 {
@@ -311,18 +313,15 @@ fn run_sender(
     };
 
     // Create PHY encoder and decoder (for ACKs)
-    let encoder =
-        PhyEncoder::new(SAMPLES_PER_LEVEL, PREAMBLE_PATTERN_BYTES, line_coding);
-    let mut decoder = PhyDecoder::new(
-        SAMPLES_PER_LEVEL,
-        PREAMBLE_PATTERN_BYTES,
-        line_coding,
-        sender_addr,
-    );
+    let params = PhyParams::default();
+    let channel = NoiseFloor::new(params.energy_threshold);
+    let encoder = PhyEncoder::new(&params, line_coding, FecKind::None);
+    let mut decoder =
+        PhyDecoder::new(&params, line_coding, FecKind::None, sender_addr);
 
     // Split data into frames
     let mut frames = Vec::new();
-    let mut seq = 0u8;
+    let mut seq = 0u16;
     for chunk in file_data.chunks(MAX_FRAME_DATA_SIZE) {
         let frame =
             Frame::new_data(seq, sender_addr, receiver_addr, chunk.to_vec()); // TODO: change this!
@@ -337,17 +336,16 @@ fn run_sender(
     let mut frames_sent = 0;
 
     let sender_progress = progress_manager
-        .create_bar("sender", total_frames as u64, templates::SENDER, "sender")
+        .add_frames_bar("sender", total_frames as u64)
         .unwrap();
 
     let overall_start_time = std::time::Instant::now();
 
     for frame_to_send in &frames {
         state = mac::CSMAState::Sensing;
-        *shared
+        shared
             .app_state
-            .lock()
-            .unwrap() = recorder::AppState::Recording;
+            .set(recorder::AppState::Recording);
         let mut stage = 0;
 
         'csma_loop: loop {
@@ -358,29 +356,17 @@ fn run_sender(
                         ENERGY_DETECTION_SAMPLES as u64 * 1000
                             / sample_rate as u64,
                     ));
-                    let recorded_samples = {
-                        shared
-                            .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    };
-                    match mac::is_channel_busy(&recorded_samples) {
+                    let recorded_samples = shared
+                        .record_buffer
+                        .peek_all();
+                    match channel.is_channel_busy(&recorded_samples) {
                         Some(true) => {
                             trace!("Channel busy detected during sensing.");
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            shared.record_buffer.clear();
                         }
                         Some(false) => {
                             state = mac::CSMAState::WaitingForDIFS;
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            shared.record_buffer.clear();
                         }
                         None => {
                             trace!(
@@ -396,24 +382,18 @@ fn run_sender(
                         std::thread::sleep(std::time::Duration::from_millis(
                             SLOT_TIME_MS,
                         ));
-                        match mac::is_channel_busy(&{
-                            shared
+                        match channel.is_channel_busy(
+                            &shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        }) {
+                                .peek_all(),
+                        ) {
                             Some(true) => {
                                 trace!("Channel busy detected during backoff.");
                                 state = mac::CSMAState::BackoffPaused(counter);
                             }
                             Some(false) => {
                                 // Channel idle, continue countdown
-                                shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
+                                shared.record_buffer.clear();
                                 counter -= 1;
                                 state = mac::CSMAState::Backoff(counter);
                             }
@@ -433,33 +413,23 @@ fn run_sender(
                     std::thread::sleep(std::time::Duration::from_millis(
                         DIFS_DURATION_MS,
                     ));
-                    match mac::is_channel_busy(&{
-                        shared
+                    match channel.is_channel_busy(
+                        &shared
                             .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    }) {
+                            .peek_all(),
+                    ) {
                         Some(true) => {
                             trace!("Channel still busy during backoff pause.");
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            shared.record_buffer.clear();
                             state = mac::CSMAState::BackoffPaused(counter);
                         }
                         Some(false) => {
                             trace!("Channel idle again, resuming backoff.");
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            shared.record_buffer.clear();
                             state = mac::CSMAState::Backoff(counter);
 
                             // // DIFS 结束后，必须再次检查信道，因为可能有别人在我们等待时开始发送
-                            // if let Some(false) = mac::is_channel_busy(&{ shared.record_buffer.lock().unwrap().clone() }) {
+                            // if let Some(false) = mac::is_channel_busy(&shared.record_buffer.peek_all()) {
                             //     // 如果信道在 DIFS 后仍然空闲，那么我们可以恢复倒计时
                             //     trace!("DIFS wait over, channel still idle. Resuming backoff.");
                             //     state = mac::CSMAState::Backoff(counter);
@@ -467,18 +437,12 @@ fn run_sender(
                             //     // 如果在 DIFS 期间信道又变忙了，我们必须保持 Paused 状态
                             //     trace!("Channel became busy during DIFS wait. Staying paused.");
                             // }
-                            // shared.record_buffer.lock().unwrap().clear();
+                            // shared.record_buffer.clear();
                         }
                         None => {
                             trace!(
                                 "Not enough samples {} to determine channel state during backoff pause.",
-                                &{
-                                    shared
-                                        .record_buffer
-                                        .lock()
-                                        .unwrap()
-                                        .len()
-                                }
+                                &{ shared.record_buffer.len() }
                             );
                         }
                     }
@@ -489,13 +453,11 @@ fn run_sender(
                         DIFS_DURATION_MS,
                     ));
 
-                    match mac::is_channel_busy(&{
-                        shared
+                    match channel.is_channel_busy(
+                        &shared
                             .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    }) {
+                            .peek_all(),
+                    ) {
                         Some(false) => {
                             trace!(
                                 "DIFS wait is over and channel is still idle. Starting backoff."
@@ -506,22 +468,14 @@ fn run_sender(
                             state = mac::CSMAState::Backoff(rand::random_range(
                                 0..=cw,
                             ));
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            shared.record_buffer.clear();
                         }
                         Some(true) => {
                             trace!(
                                 "Channel became busy during DIFS wait. Returning to sensing."
                             );
                             state = mac::CSMAState::Sensing;
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            shared.record_buffer.clear();
                         }
                         None => {
                             trace!(
@@ -538,49 +492,20 @@ fn run_sender(
                     // 1. Encode and send the frame
                     let output_track = encoder.encode_frames(
                         &[frame_to_send.clone()],
-                        INTER_FRAME_GAP_SAMPLES,
+                        params.inter_frame_gap_samples,
                     );
-                    {
-                        let mut playback = shared
-                            .playback_buffer
-                            .lock()
-                            .unwrap();
-                        playback.clear();
-                        playback.extend(output_track);
-                        {
-                            // Clear previous recordings before listening for ACK
-                            let mut rec_buf = shared
-                                .record_buffer
-                                .lock()
-                                .unwrap();
-                            rec_buf.clear();
-                        }
-                    }
-                    *shared
-                        .app_state
-                        .lock()
-                        .unwrap() = recorder::AppState::Playing;
-
-                    // Wait for playback to finish
-                    while let recorder::AppState::Playing = {
-                        shared
-                            .app_state
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    } {
-                        std::thread::sleep(std::time::Duration::from_millis(1));
-                    }
+                    // Clear previous recordings before listening for ACK
+                    shared.record_buffer.clear();
+                    shared.play(&output_track);
                     debug!(
                         "Frame {} sent, waiting for ACK...",
                         frame_to_send.sequence
                     );
 
                     // 2. Switch to recording to wait for ACK
-                    *shared
+                    shared
                         .app_state
-                        .lock()
-                        .unwrap() = recorder::AppState::Recording;
+                        .set(recorder::AppState::Recording);
                     state = mac::CSMAState::WaitingForAck;
                 }
                 mac::CSMAState::WaitingForAck => {
@@ -610,13 +535,9 @@ fn run_sender(
 
                         std::thread::sleep(std::time::Duration::from_millis(10));
 
-                        let current_samples = {
-                            shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        };
+                        let current_samples = shared
+                            .record_buffer
+                            .peek_all();
 
                         if current_samples.len() > processed_samples_len {
                             let new_samples =
@@ -651,7 +572,9 @@ fn run_sender(
                         }
                     } // end ack_wait_loop
                 }
-                mac::CSMAState::Idle => unreachable!(),
+                mac::CSMAState::Idle
+                | mac::CSMAState::SendingRts
+                | mac::CSMAState::WaitingForCts => unreachable!(),
             } // end retransmit_loop
         } // end csma_loop
     } // end for frame_to_send
@@ -672,8 +595,8 @@ fn run_sender(
     //     "./tmp/sender_final_ack_recording.wav",
     //     &utils::dump::AudioData {
     //         sample_rate,
-    //         audio_data: shared.record_buffer.lock().unwrap().clone(),
-    //         duration: shared.record_buffer.lock().unwrap().len() as f32 / sample_rate as f32,
+    //         audio_data: shared.record_buffer.peek_all(),
+    //         duration: shared.record_buffer.len() as f32 / sample_rate as f32,
     //         channels: 1,
     //     },
     // ) {
@@ -694,14 +617,10 @@ fn run_receiver(
     info!("Using line coding: {}", line_coding.name());
 
     // Create decoder and encoder for ACKs
-    let mut decoder = PhyDecoder::new(
-        SAMPLES_PER_LEVEL,
-        PREAMBLE_PATTERN_BYTES,
-        line_coding,
-        receiver_addr,
-    );
-    let encoder =
-        PhyEncoder::new(SAMPLES_PER_LEVEL, PREAMBLE_PATTERN_BYTES, line_coding);
+    let params = PhyParams::default();
+    let mut decoder =
+        PhyDecoder::new(&params, line_coding, FecKind::None, receiver_addr);
+    let encoder = PhyEncoder::new(&params, line_coding, FecKind::None);
 
     let mut all_data = Vec::new();
     let mut received_sequences = std::collections::HashSet::new();
@@ -711,15 +630,14 @@ fn run_receiver(
         .create_bar(
             "recording",
             max_recording_duration_samples as u64,
-            templates::RECEIVER,
+            templates::RECORDING,
             "receiver",
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Recording;
+        .set(recorder::AppState::Recording);
 
     let start_time = std::time::Instant::now();
     let recording_timeout = std::time::Duration::from_secs(rx_duration);
@@ -747,19 +665,8 @@ fn run_receiver(
         // Wait for some audio to be recorded
         std::thread::sleep(std::time::Duration::from_millis(25));
 
-        if shared
-            .record_buffer
-            .lock()
-            .unwrap()
-            .len()
-            > 50
-        {
-            let new_samples = &shared
-                .record_buffer
-                .lock()
-                .unwrap()
-                .drain(..)
-                .collect::<Vec<_>>()[..];
+        if shared.record_buffer.len() > 50 {
+            let new_samples = &shared.record_buffer.pop_all()[..];
             let decoded_frames = decoder.process_samples(new_samples);
             processed_samples_len += new_samples.len();
 
@@ -790,39 +697,14 @@ fn run_receiver(
                     ); // TODO: change this to actual sender addr
                     let ack_track = encoder.encode_frames(&[ack_frame], 0);
 
-                    // Put ACK in playback buffer
-                    {
-                        let mut playback = shared
-                            .playback_buffer
-                            .lock()
-                            .unwrap();
-                        playback.clear();
-                        playback.extend(ack_track);
-                    }
-
-                    // Switch to playing state
-                    *shared
-                        .app_state
-                        .lock()
-                        .unwrap() = recorder::AppState::Playing;
-
-                    // Wait for ACK playback to complete
-                    while let recorder::AppState::Playing = {
-                        shared
-                            .app_state
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    } {
-                        std::thread::sleep(std::time::Duration::from_millis(1));
-                    }
+                    // Play the ACK and wait for it to complete
+                    shared.play(&ack_track);
                     debug!("ACK sent for seq: {}", frame.sequence);
 
                     // After sending ACK, switch back to recording for the next frame
-                    *shared
+                    shared
                         .app_state
-                        .lock()
-                        .unwrap() = recorder::AppState::Recording;
+                        .set(recorder::AppState::Recording);
                     debug!("Switched back to recording mode.");
                 }
             } // end for frame
@@ -833,13 +715,7 @@ fn run_receiver(
             .unwrap();
 
         // Check if user manually stopped (e.g., by letting recording finish)
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             info!("Recording finished by user or duration limit.");
             break 'main_loop;
@@ -855,13 +731,9 @@ fn run_receiver(
         .unwrap();
 
     // Final processing for any remaining samples
-    let final_samples = {
-        let buffer = shared
-            .record_buffer
-            .lock()
-            .unwrap();
-        buffer.clone()
-    };
+    let final_samples = shared
+        .record_buffer
+        .peek_all();
     if final_samples.len() > processed_samples_len {
         let remaining_samples = &final_samples[processed_samples_len..];
         let decoded_frames = decoder.process_samples(remaining_samples);
@@ -925,18 +797,13 @@ fn test_transmission(line_coding: LineCodingKind) {
     info!("Content: {}", String::from_utf8_lossy(&test_data));
 
     // Create encoder and decoder
-    let encoder =
-        PhyEncoder::new(SAMPLES_PER_LEVEL, PREAMBLE_PATTERN_BYTES, line_coding);
-    let mut decoder = PhyDecoder::new(
-        SAMPLES_PER_LEVEL,
-        PREAMBLE_PATTERN_BYTES,
-        line_coding,
-        2,
-    );
+    let params = PhyParams::default();
+    let encoder = PhyEncoder::new(&params, line_coding, FecKind::None);
+    let mut decoder = PhyDecoder::new(&params, line_coding, FecKind::None, 2);
 
     // Create frames
     let mut frames = Vec::new();
-    let mut seq = 0u8;
+    let mut seq = 0u16;
 
     for chunk in test_data.chunks(MAX_FRAME_DATA_SIZE) {
         let frame = Frame::new_data(seq, 0, 1, chunk.to_vec());
@@ -947,7 +814,7 @@ fn test_transmission(line_coding: LineCodingKind) {
     info!("Created {} frames", frames.len());
 
     // Encode
-    let samples = encoder.encode_frames(&frames, INTER_FRAME_GAP_SAMPLES);
+    let samples = encoder.encode_frames(&frames, params.inter_frame_gap_samples);
     info!(
         "Encoded to {} samples ({:.2} seconds at {} Hz)",
         samples.len(),
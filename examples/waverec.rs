@@ -2,7 +2,7 @@ use audio::recorder;
 use device::jack::{
     connect_input_from_first_system_output,
     connect_output_to_first_system_input, disconnect_input_sources,
-    disconnect_output_sinks, print_jack_info,
+    disconnect_output_sinks, port_process_closure, print_jack_info,
 };
 use jack;
 use tracing::{info, warn};
@@ -10,12 +10,13 @@ use trackmaker_rs::*;
 use ui::print_banner;
 use ui::progress::{ProgressManager, templates};
 use utils::consts::*;
-use utils::logging::init_logging;
+use utils::logging::{LogOptions, init_logging_with};
 
 use crate::device::jack::connect_system_ports;
 
 fn main() {
-    init_logging();
+    init_logging_with(&LogOptions::default())
+        .expect("Default logging can be set up");
     print_banner();
     let (client, status) = jack::Client::new(
         JACK_CLIENT_NAME,
@@ -47,11 +48,10 @@ fn main() {
     let out_port_name = out_port.name().unwrap();
 
     // Process Callback
-    let process_cb = recorder::build_process_closure(
+    let process_cb = port_process_closure(
         in_port,
         out_port,
-        shared_cb,
-        recording_duration_samples,
+        recorder::build_process_closure(shared_cb),
     );
     let process = jack::contrib::ClosureProcessHandler::new(process_cb);
 
@@ -75,10 +75,9 @@ fn main() {
         )
         .unwrap();
 
-    *shared
+    shared
         .app_state
-        .lock()
-        .unwrap() = recorder::AppState::Recording;
+        .set(recorder::AppState::Recording);
 
     loop {
         std::thread::sleep(std::time::Duration::from_millis(50));
@@ -89,13 +88,7 @@ fn main() {
             &progress_manager,
         );
 
-        let state = {
-            shared
-                .app_state
-                .lock()
-                .unwrap()
-                .clone()
-        };
+        let state = shared.app_state.get();
         if let recorder::AppState::Idle = state {
             progress_manager.finish_all();
             break;
@@ -111,15 +104,8 @@ fn main() {
             sample_rate: sample_rate as u32,
             audio_data: shared
                 .record_buffer
-                .lock()
-                .unwrap()
-                .clone(),
-            duration: shared
-                .record_buffer
-                .lock()
-                .unwrap()
-                .len() as f32
-                / sample_rate as f32,
+                .peek_all(),
+            duration: shared.record_buffer.len() as f32 / sample_rate as f32,
             channels: 1,
         },
     ) {
@@ -0,0 +1,377 @@
+// Audio backends
+//
+// Everything that talks over the sound card goes through AppShared's
+// buffers, filled and drained by one process callback per block of
+// samples. An AudioBackend runs that callback: JACK when a server is
+// running, cpal (ALSA, CoreAudio, WASAPI) otherwise. `--backend` picks one
+// for the whole process; without it JACK is tried first and cpal takes
+// over if no JACK server answers. `--input-port` / `--output-port` pick
+// the physical ports (JACK) or devices (cpal) instead of the first ones,
+// `--dump-audio` keeps what every client hears and plays (see
+// audio::dump). A device not running at SAMPLE_RATE is resampled (see
+// audio::resample), and every client plays at `--tx-gain` (see
+// audio::level). Tests route every open to a loopback medium instead.
+
+use std::sync::OnceLock;
+
+use tracing::{info, warn};
+
+use crate::audio::dump::{DumpOptions, DumpingBackend};
+use crate::audio::level::LevelBackend;
+use crate::audio::resample::ResamplingBackend;
+use crate::device::cpal::CpalBackend;
+use crate::device::jack::JackBackend;
+use crate::utils::consts::{SAMPLE_RATE, TX_GAIN};
+
+/// Called with each block of input samples and the output block to fill
+pub type ProcessFn = Box<dyn FnMut(&[f32], &mut [f32]) + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Jack,
+    Cpal,
+}
+
+impl BackendKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendKind::Jack => "jack",
+            BackendKind::Cpal => "cpal",
+        }
+    }
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "jack" => Ok(BackendKind::Jack),
+            "cpal" => Ok(BackendKind::Cpal),
+            _ => Err(format!("Unknown audio backend '{}'", s)),
+        }
+    }
+}
+
+/// A sound card connection, before it starts running the process callback
+pub trait AudioBackend {
+    fn name(&self) -> &'static str;
+
+    fn sample_rate(&self) -> usize;
+
+    /// Set up the mono input and output
+    fn register(&mut self) -> Result<(), String>;
+
+    /// Start calling `process` for every block, until the returned handle
+    /// is deactivated or dropped
+    fn activate(
+        self: Box<Self>,
+        process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String>;
+}
+
+/// A backend running the process callback
+pub trait ActiveAudio {
+    fn deactivate(self: Box<Self>) -> Result<(), String>;
+}
+
+/// Physical ends to connect to, None = the first / default one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortSelection {
+    /// Capture port (JACK) or input device (cpal) we record from
+    pub input: Option<String>,
+    /// Playback port (JACK) or output device (cpal) we play to
+    pub output: Option<String>,
+}
+
+static SELECTED: OnceLock<BackendKind> = OnceLock::new();
+static PORTS: OnceLock<PortSelection> = OnceLock::new();
+static DUMP: OnceLock<DumpOptions> = OnceLock::new();
+static GAIN: OnceLock<f32> = OnceLock::new();
+
+/// Use `kind` for every audio client this process opens, instead of
+/// trying JACK first
+pub fn select(kind: BackendKind) {
+    if SELECTED.set(kind).is_err() {
+        warn!("Audio backend already selected, ignoring {}", kind.name());
+    }
+}
+
+/// Connect every audio client this process opens to `ports`
+pub fn select_ports(ports: PortSelection) {
+    if PORTS.set(ports).is_err() {
+        warn!("Audio ports already selected, ignoring");
+    }
+}
+
+/// Dump the audio of every client this process opens from now on
+pub fn select_dump(options: DumpOptions) {
+    if DUMP.set(options).is_err() {
+        warn!("Audio dump already selected, ignoring");
+    }
+}
+
+/// Play every audio client this process opens at `gain` of full scale,
+/// instead of TX_GAIN
+pub fn select_tx_gain(gain: f32) {
+    if GAIN.set(gain).is_err() {
+        warn!("Output gain already selected, ignoring {}", gain);
+    }
+}
+
+pub fn selected_ports() -> PortSelection {
+    PORTS
+        .get()
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The one of `available` that `requested` names: itself, otherwise the
+/// only one containing it, ignoring case. Fails listing what there is.
+pub fn match_port(
+    requested: &str,
+    available: &[String],
+) -> Result<String, String> {
+    if let Some(exact) = available
+        .iter()
+        .find(|name| *name == requested)
+    {
+        return Ok(exact.clone());
+    }
+    let wanted = requested.to_lowercase();
+    let matches: Vec<&String> = available
+        .iter()
+        .filter(|name| {
+            name.to_lowercase()
+                .contains(&wanted)
+        })
+        .collect();
+    match matches.as_slice() {
+        [only] => Ok((*only).clone()),
+        [] => Err(format!(
+            "No port matches '{}', available:{}",
+            requested,
+            bullet_list(available)
+        )),
+        several => Err(format!(
+            "'{}' matches several ports:{}",
+            requested,
+            bullet_list(several)
+        )),
+    }
+}
+
+fn bullet_list<S: AsRef<str>>(ports: &[S]) -> String {
+    if ports.is_empty() {
+        return " none".to_string();
+    }
+    ports
+        .iter()
+        .map(|port| format!("\n  {}", port.as_ref()))
+        .collect()
+}
+
+fn open_kind(
+    kind: BackendKind,
+    client_name: &str,
+) -> Result<Box<dyn AudioBackend>, String> {
+    let mut backend: Box<dyn AudioBackend> = match kind {
+        BackendKind::Jack => Box::new(JackBackend::new(client_name)?),
+        BackendKind::Cpal => Box::new(CpalBackend::new()?),
+    };
+    backend.register()?;
+    info!("Audio backend: {}", backend.name());
+    if backend.sample_rate() != SAMPLE_RATE as usize {
+        backend = Box::new(ResamplingBackend::new(backend));
+    }
+    // Dumps at SAMPLE_RATE, so they replay as they were decoded
+    if let Some(options) = DUMP.get() {
+        backend = Box::new(DumpingBackend::new(backend, options.clone()));
+    }
+    // Outermost, so dumps hold what was really played
+    let gain = GAIN
+        .get()
+        .copied()
+        .unwrap_or(TX_GAIN);
+    Ok(Box::new(LevelBackend::new(backend, gain)))
+}
+
+/// Print the physical ports / devices the backends can connect to
+pub fn list_devices(client_name: &str) {
+    match crate::device::jack::list_ports(client_name) {
+        Ok(ports) => println!("JACK ports:\n{}", ports),
+        Err(e) => println!("JACK: {}", e),
+    }
+    match crate::device::cpal::list_devices() {
+        Ok(devices) => println!("{}", devices),
+        Err(e) => println!("cpal: {}", e),
+    }
+}
+
+/// Open the selected backend as `client_name`, its input and output
+/// registered
+pub fn open(client_name: &str) -> Result<Box<dyn AudioBackend>, String> {
+    #[cfg(test)]
+    if let Some(backend) = crate::audio::loopback::routed_backend() {
+        return Ok(Box::new(backend));
+    }
+    if let Some(kind) = SELECTED.get() {
+        return open_kind(*kind, client_name);
+    }
+    open_kind(BackendKind::Jack, client_name).or_else(|e| {
+        warn!("{}, falling back to cpal", e);
+        open_kind(BackendKind::Cpal, client_name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::recorder::{AppShared, AppState, build_process_closure};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Feeds `input` to the process callback from its own thread, one
+    /// block at a time, and keeps what it plays
+    struct MockBackend {
+        input: Vec<f32>,
+        block: usize,
+        played: Arc<Mutex<Vec<f32>>>,
+    }
+
+    struct MockActive(thread::JoinHandle<()>);
+
+    impl AudioBackend for MockBackend {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn sample_rate(&self) -> usize {
+            48000
+        }
+
+        fn register(&mut self) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn activate(
+            self: Box<Self>,
+            mut process: ProcessFn,
+        ) -> Result<Box<dyn ActiveAudio>, String> {
+            let handle = thread::spawn(move || {
+                let mut output = vec![0.0; self.block];
+                for input in self.input.chunks(self.block) {
+                    process(input, &mut output[..input.len()]);
+                    self.played
+                        .lock()
+                        .unwrap()
+                        .extend_from_slice(&output[..input.len()]);
+                }
+            });
+            Ok(Box::new(MockActive(handle)))
+        }
+    }
+
+    impl ActiveAudio for MockActive {
+        fn deactivate(self: Box<Self>) -> Result<(), String> {
+            self.0
+                .join()
+                .map_err(|_| "Mock audio thread panicked".to_string())
+        }
+    }
+
+    #[test]
+    fn test_port_names_match_exactly_or_by_unique_part() {
+        let ports: Vec<String> = [
+            "system:capture_1",
+            "system:capture_2",
+            "usb:capture_1",
+            "system:playback_1",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        assert_eq!(
+            match_port("system:capture_1", &ports),
+            Ok("system:capture_1".to_string())
+        );
+        assert_eq!(
+            match_port("PLAYBACK", &ports),
+            Ok("system:playback_1".to_string())
+        );
+        assert_eq!(match_port("usb", &ports), Ok("usb:capture_1".to_string()));
+
+        // Ambiguous or unknown names list the candidates
+        let several = match_port("capture_1", &ports).unwrap_err();
+        assert!(several.contains("system:capture_1"));
+        assert!(several.contains("usb:capture_1"));
+        assert!(!several.contains("capture_2"));
+        let unknown = match_port("hdmi", &ports).unwrap_err();
+        assert!(unknown.contains("hdmi"));
+        assert!(unknown.contains("system:playback_1"));
+        assert!(
+            match_port("hdmi", &[])
+                .unwrap_err()
+                .contains("none")
+        );
+    }
+
+    #[test]
+    fn test_process_callback_drives_shared_buffers() {
+        assert_eq!("JACK".parse(), Ok(BackendKind::Jack));
+        assert_eq!(
+            BackendKind::Cpal
+                .name()
+                .parse(),
+            Ok(BackendKind::Cpal)
+        );
+        assert!(
+            "pulse"
+                .parse::<BackendKind>()
+                .is_err()
+        );
+
+        let shared = AppShared::new(100);
+        shared.queue_playback(&[0.5; 10]);
+        shared
+            .app_state
+            .set(AppState::RecordingAndPlaying);
+        let input: Vec<f32> = (0..40)
+            .map(|i| i as f32 / 100.0)
+            .collect();
+        let played = Arc::new(Mutex::new(Vec::new()));
+        let backend = Box::new(MockBackend {
+            input: input.clone(),
+            block: 8,
+            played: played.clone(),
+        });
+        assert_eq!(backend.name(), "mock");
+
+        assert_eq!(backend.sample_rate(), 48000);
+        let active = backend
+            .activate(build_process_closure(shared.clone()))
+            .unwrap();
+        active.deactivate().unwrap();
+
+        // Played out, then back to recording only
+        let played = played.lock().unwrap();
+        assert_eq!(played.len(), 40);
+        assert_eq!(&played[..10], &[0.5; 10]);
+        assert!(
+            played[10..]
+                .iter()
+                .all(|s| *s == 0.0)
+        );
+        assert_eq!(
+            shared
+                .record_buffer
+                .peek_all(),
+            input
+        );
+        assert!(matches!(shared.app_state.get(), AppState::Recording));
+    }
+}
@@ -0,0 +1,371 @@
+// Audio dumps
+//
+// With `--dump-audio <dir>`, every audio client the process opens keeps
+// what the sound card heard (rx) and what it played (tx) in WAV files
+// under <dir>, rotated every few minutes (see utils::dump::WavDumper). The
+// process callback only copies each block into a pair of sample rings; a
+// dump thread drains them into the files. `replay` decodes a dump offline,
+// `replay_capture` a capture file (see phy::capture), which `capture`
+// records.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::audio::backend::{self, ActiveAudio, AudioBackend, ProcessFn};
+use crate::audio::recorder::{AppShared, AppState, build_process_closure};
+use crate::audio::resample::Resampler;
+use crate::audio::ring::SampleRing;
+use crate::phy::capture::{Capture, CaptureHeader};
+use crate::phy::decoder::DecodeStats;
+use crate::phy::{Frame, PhyDecoder};
+use crate::utils::consts::{DUMP_POLL_MS, JACK_CLIENT_NAME, SAMPLE_RATE};
+use crate::utils::dump::{WavDumper, read_wav};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DumpOptions {
+    pub dir: PathBuf,
+    /// A new pair of files after this long
+    pub rotate: Duration,
+}
+
+/// An AudioBackend whose client dumps its audio while active
+pub struct DumpingBackend {
+    inner: Box<dyn AudioBackend>,
+    options: DumpOptions,
+}
+
+impl DumpingBackend {
+    pub fn new(inner: Box<dyn AudioBackend>, options: DumpOptions) -> Self {
+        Self { inner, options }
+    }
+}
+
+impl AudioBackend for DumpingBackend {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.inner.sample_rate()
+    }
+
+    fn register(&mut self) -> Result<(), String> {
+        self.inner.register()
+    }
+
+    fn activate(
+        self: Box<Self>,
+        mut process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String> {
+        let sample_rate = self.inner.sample_rate() as u32;
+        // Plenty of time for the dump thread to come around
+        let capacity = sample_rate as usize * 2;
+        let rx = Arc::new(SampleRing::new(capacity));
+        let tx = Arc::new(SampleRing::new(capacity));
+        let rotate_samples = (self
+            .options
+            .rotate
+            .as_secs_f64()
+            * sample_rate as f64) as usize;
+        let mut dumpers = [("rx", &rx), ("tx", &tx)]
+            .into_iter()
+            .map(|(prefix, ring)| {
+                WavDumper::new(
+                    &self.options.dir,
+                    prefix,
+                    sample_rate,
+                    rotate_samples,
+                )
+                .map(|dumper| (dumper, ring.clone()))
+            })
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|e| {
+                format!(
+                    "Cannot dump audio to {}: {}",
+                    self.options.dir.display(),
+                    e
+                )
+            })?;
+        info!("Dumping audio to {}", self.options.dir.display());
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                loop {
+                    // Checked first so the last round drains what is left
+                    let stopping = !running.load(Ordering::SeqCst);
+                    for (dumper, ring) in &mut dumpers {
+                        if let Err(e) = dumper.write(&ring.pop_all()) {
+                            error!("Audio dump failed: {}", e);
+                            return;
+                        }
+                    }
+                    if stopping {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(DUMP_POLL_MS));
+                }
+                for (dumper, ring) in dumpers {
+                    if ring.overflows() > 0 {
+                        warn!(
+                            "Audio dump fell behind, {} samples missing",
+                            ring.overflows()
+                        );
+                    }
+                    match dumper.finish() {
+                        Ok(files) => info!("Dumped audio to {:?}", files),
+                        Err(e) => error!("Audio dump failed: {}", e),
+                    }
+                }
+            })
+        };
+
+        let tapped: ProcessFn =
+            Box::new(move |in_buffer: &[f32], out_buffer: &mut [f32]| {
+                process(in_buffer, out_buffer);
+                rx.push_slice(in_buffer);
+                tx.push_slice(out_buffer);
+            });
+        Ok(Box::new(DumpingActive {
+            inner: Some(self.inner.activate(tapped)?),
+            running,
+            thread: Some(thread),
+        }))
+    }
+}
+
+/// Stops and finishes the dump once the client is deactivated or dropped
+struct DumpingActive {
+    inner: Option<Box<dyn ActiveAudio>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl DumpingActive {
+    fn stop(&mut self) -> Result<(), String> {
+        let result = match self.inner.take() {
+            Some(inner) => inner.deactivate(),
+            None => Ok(()),
+        };
+        self.running
+            .store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        result
+    }
+}
+
+impl ActiveAudio for DumpingActive {
+    fn deactivate(mut self: Box<Self>) -> Result<(), String> {
+        self.stop()
+    }
+}
+
+impl Drop for DumpingActive {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Decode the WAV file at `path` (a dump or any recording, resampled if
+/// need be) with `decoder`, fed in blocks as it would have been live
+pub fn replay(
+    path: &Path,
+    decoder: &mut PhyDecoder,
+) -> Result<Vec<Frame>, String> {
+    let (sample_rate, samples) = read_wav(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    info!(
+        "Replaying {} ({} samples at {} Hz)",
+        path.display(),
+        samples.len(),
+        sample_rate
+    );
+    Ok(resampled(sample_rate, samples)
+        .chunks(SAMPLE_RATE as usize * DUMP_POLL_MS as usize / 1000)
+        .flat_map(|block| decoder.process_samples(block))
+        .collect())
+}
+
+/// Decode the capture file at `path` with a decoder set up from its
+/// header, returning the frames and the decoder's counters
+pub fn replay_capture(path: &Path) -> Result<(Vec<Frame>, DecodeStats), String> {
+    let mut capture = Capture::read(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    info!(
+        "Replaying {} ({} samples at {} Hz, {} / {}, taken for {})",
+        path.display(),
+        capture.samples.len(),
+        capture.header.sample_rate,
+        capture.header.line_coding,
+        capture.header.fec,
+        capture.header.reason
+    );
+    capture.samples = resampled(
+        capture.header.sample_rate,
+        std::mem::take(&mut capture.samples),
+    );
+    capture.header.sample_rate = SAMPLE_RATE;
+    let (frames, _, stats) = capture.decode()?;
+    Ok((frames, stats))
+}
+
+/// `samples` at SAMPLE_RATE
+fn resampled(sample_rate: u32, samples: Vec<f32>) -> Vec<f32> {
+    if sample_rate == SAMPLE_RATE {
+        return samples;
+    }
+    let mut resampled = Vec::new();
+    Resampler::new(sample_rate, SAMPLE_RATE).process(&samples, &mut resampled);
+    resampled
+}
+
+/// Record `duration` of input from the selected backend into a capture
+/// file at `path`, `header` saying how to decode it
+pub fn record_capture(
+    path: &Path,
+    duration: Duration,
+    mut header: CaptureHeader,
+) -> Result<usize, String> {
+    let client = backend::open(&format!(
+        "{}_capture_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))?;
+    header.sample_rate = client.sample_rate() as u32;
+    let shared = AppShared::new(header.sample_rate as usize);
+    let active_client =
+        client.activate(build_process_closure(shared.clone()))?;
+    info!(
+        "Capturing {:.1} s at {} Hz to {}",
+        duration.as_secs_f64(),
+        header.sample_rate,
+        path.display()
+    );
+
+    let wanted = (duration.as_secs_f64() * header.sample_rate as f64) as usize;
+    let mut samples = Vec::with_capacity(wanted);
+    shared
+        .app_state
+        .set(AppState::Recording);
+    while samples.len() < wanted {
+        thread::sleep(Duration::from_millis(DUMP_POLL_MS));
+        samples.extend(shared.record_buffer.pop_all());
+    }
+    shared
+        .app_state
+        .set(AppState::Idle);
+    active_client.deactivate()?;
+    if shared.record_overflows() > 0 {
+        warn!(
+            "Capture fell behind, {} samples missing",
+            shared.record_overflows()
+        );
+    }
+
+    samples.truncate(wanted);
+    let captured = samples.len();
+    Capture { header, samples }
+        .write(path)
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+    Ok(captured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::loopback::{LoopbackConfig, LoopbackMedium};
+    use crate::phy::capture::Sidecar;
+    use crate::phy::params::PhyParams;
+    use crate::phy::{FecKind, LineCodingKind, PhyEncoder};
+    use crate::utils::consts::*;
+
+    #[test]
+    fn test_dumped_transfer_replays_to_the_same_frames() {
+        let dir = std::env::temp_dir()
+            .join(format!("trackmaker-dump-{}-replay", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        let dumping = |name: &str| {
+            Box::new(DumpingBackend::new(
+                Box::new(medium.backend()),
+                DumpOptions {
+                    dir: dir.join(name),
+                    rotate: Duration::from_secs(60),
+                },
+            ))
+        };
+        let a = AppShared::new(SAMPLE_RATE as usize);
+        let b = AppShared::new(SAMPLE_RATE as usize);
+        let a_audio = dumping("a")
+            .activate(build_process_closure(a.clone()))
+            .unwrap();
+        let b_audio = dumping("b")
+            .activate(build_process_closure(b.clone()))
+            .unwrap();
+
+        let kind = LineCodingKind::FourBFiveB;
+        let frames: Vec<Frame> = (0..3u8)
+            .map(|i| Frame::new_data(i as u16, 1, 2, vec![i; 40]))
+            .collect();
+        let encoder =
+            PhyEncoder::new(&PhyParams::default(), kind, FecKind::None);
+        b.app_state
+            .set(AppState::Recording);
+        a.play(&encoder.encode_frames(&frames, INTER_FRAME_GAP_SAMPLES));
+        thread::sleep(Duration::from_millis(50));
+        a_audio.deactivate().unwrap();
+        b_audio.deactivate().unwrap();
+
+        // What a played and what b heard both decode to what was sent
+        for (node, kind_of) in [("a", "tx"), ("b", "rx")] {
+            let files: Vec<PathBuf> = std::fs::read_dir(dir.join(node))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| {
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .starts_with(kind_of)
+                })
+                .collect();
+            assert_eq!(files.len(), 1, "{:?}", files);
+            let mut decoder =
+                PhyDecoder::new(&PhyParams::default(), kind, FecKind::None, 2);
+            let replayed = replay(&files[0], &mut decoder).unwrap();
+            assert_eq!(replayed.len(), frames.len());
+            for (replayed, sent) in replayed.iter().zip(&frames) {
+                assert_eq!(replayed.sequence, sent.sequence);
+                assert_eq!(replayed.data, sent.data);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corpus_captures_decode_to_their_sidecars() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        let captures = crate::phy::capture::list(&dir).unwrap();
+        assert!(!captures.is_empty(), "No captures in {}", dir.display());
+        for capture in captures {
+            let expected = Sidecar::read(&capture)
+                .unwrap_or_else(|e| panic!("{}: {}", capture.display(), e));
+            let (frames, stats) = replay_capture(&capture).unwrap();
+            assert_eq!(
+                frames.len(),
+                expected.frames,
+                "{} ({}): {}",
+                capture.display(),
+                expected.note,
+                stats
+            );
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// Audio round-trip latency
+//
+// The CSMA timing (see mac::timing) assumes the sound card adds next to
+// nothing between the process callback and the air, but USB interfaces
+// hold 20-80 ms of audio each way. `calibrate` plays a chirp while
+// recording, over a cable loopback or through the air, and finds it in the
+// recording by cross-correlation: its offset is the round trip. CsmaNode
+// allows for it with --audio-latency-ms.
+
+use std::thread;
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::audio::backend;
+use crate::audio::recorder::{AppShared, AppState, build_process_closure};
+use crate::phy::preamble::{cross_correlate, generate_chirp, refine_peak};
+use crate::utils::consts::{
+    CALIBRATE_GAP_MS, CALIBRATE_MAX_LATENCY_MS, CHIRP_CORRELATION_THRESHOLD,
+    CHIRP_F0_HZ, CHIRP_F1_HZ, CHIRP_LEN_SAMPLES, JACK_CLIENT_NAME,
+};
+
+/// Round trips measured by `measure`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyReport {
+    pub rounds: Vec<Duration>,
+}
+
+impl LatencyReport {
+    pub fn mean(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.rounds
+                .iter()
+                .map(Duration::as_secs_f64)
+                .sum::<f64>()
+                / self.rounds.len() as f64,
+        )
+    }
+
+    pub fn stddev(&self) -> Duration {
+        let mean = self.mean().as_secs_f64();
+        let variance = self
+            .rounds
+            .iter()
+            .map(|round| (round.as_secs_f64() - mean).powi(2))
+            .sum::<f64>()
+            / self.rounds.len() as f64;
+        Duration::from_secs_f64(variance.sqrt())
+    }
+}
+
+/// Play a chirp on `shared`'s running client and time it back. Its record
+/// ring must hold CALIBRATE_MAX_LATENCY_MS and the chirp.
+pub fn measure_round_trip(
+    shared: &AppShared,
+    sample_rate: u32,
+) -> Result<Duration, String> {
+    let chirp = generate_chirp(CHIRP_F0_HZ, CHIRP_F1_HZ, CHIRP_LEN_SAMPLES);
+    let wanted = chirp.len()
+        + sample_rate as usize * CALIBRATE_MAX_LATENCY_MS as usize / 1000;
+
+    // Recording starts with the chirp's first sample
+    shared.record_buffer.pop_all();
+    shared.play_recording(&chirp);
+    while shared.record_buffer.len() < wanted
+        && shared.app_state.get() == AppState::Recording
+    {
+        thread::sleep(Duration::from_millis(1));
+    }
+    shared
+        .app_state
+        .set(AppState::Idle);
+    let recording = shared.record_buffer.pop_all();
+
+    let correlation = cross_correlate(&recording, &chirp);
+    let Some((peak, &score)) = correlation
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+    else {
+        return Err("Recording too short".to_string());
+    };
+    if score < CHIRP_CORRELATION_THRESHOLD {
+        return Err(format!(
+            "Chirp not heard back within {} ms (best correlation {:.2})",
+            CALIBRATE_MAX_LATENCY_MS, score
+        ));
+    }
+    let frac = match (peak.checked_sub(1), correlation.get(peak + 1)) {
+        (Some(before), Some(after)) => {
+            refine_peak(correlation[before], score, *after)
+        }
+        _ => 0.0,
+    };
+    Ok(Duration::from_secs_f64(
+        (peak as f64 + frac as f64).max(0.0) / sample_rate as f64,
+    ))
+}
+
+/// `rounds` round trips, skipping the ones where the chirp got lost.
+/// Fails if every one did.
+pub fn measure(
+    shared: &AppShared,
+    sample_rate: u32,
+    rounds: usize,
+) -> Result<LatencyReport, String> {
+    let mut measured = Vec::new();
+    let mut last_error = None;
+    for round in 0..rounds {
+        match measure_round_trip(shared, sample_rate) {
+            Ok(latency) => {
+                info!(
+                    "Round {}: {:.2} ms",
+                    round + 1,
+                    latency.as_secs_f64() * 1000.0
+                );
+                measured.push(latency);
+            }
+            Err(e) => {
+                warn!("Round {}: {}", round + 1, e);
+                last_error = Some(e);
+            }
+        }
+        // Let the room quiet down
+        thread::sleep(Duration::from_millis(CALIBRATE_GAP_MS));
+    }
+    if measured.is_empty() {
+        return Err(last_error.unwrap_or_else(|| "No rounds".to_string()));
+    }
+    Ok(LatencyReport { rounds: measured })
+}
+
+/// Measure the round trip of the selected backend and print it
+pub fn run_calibration(rounds: usize) {
+    info!("=== Latency Calibration ===");
+    info!("Loop the output back to the input (cable or speaker and mic)");
+
+    let client = backend::open(&format!(
+        "{}_calibrate_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))
+    .unwrap();
+    let sample_rate = client.sample_rate() as u32;
+    let shared = AppShared::new(sample_rate as usize);
+    let active_client = client
+        .activate(build_process_closure(shared.clone()))
+        .unwrap();
+
+    match measure(&shared, sample_rate, rounds) {
+        Ok(report) => {
+            let mean_ms = report.mean().as_secs_f64() * 1000.0;
+            println!(
+                "Round trip over {} of {} rounds: mean {:.2} ms, stddev {:.2} ms",
+                report.rounds.len(),
+                rounds,
+                mean_ms,
+                report.stddev().as_secs_f64() * 1000.0
+            );
+            println!(
+                "Pass --audio-latency-ms {:.0} to allow for it",
+                mean_ms.ceil()
+            );
+        }
+        Err(e) => error!("Calibration failed: {}", e),
+    }
+
+    if let Err(err) = active_client.deactivate() {
+        error!("Error deactivating client: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::loopback::{LinkParams, LoopbackConfig, LoopbackMedium};
+    use crate::utils::consts::SAMPLE_RATE;
+
+    #[test]
+    fn test_measures_injected_delay() {
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        // Cable loopback of a sluggish USB interface
+        medium.set_link(
+            0,
+            0,
+            LinkParams {
+                latency: Duration::from_millis(37),
+                gain: 0.4,
+                noise: 0.01,
+                ..LinkParams::default()
+            },
+        );
+        let (shared, _audio) = medium
+            .nodes(1, SAMPLE_RATE as usize)
+            .pop()
+            .unwrap();
+
+        let report = measure(&shared, SAMPLE_RATE, 3).unwrap();
+        assert_eq!(report.rounds.len(), 3);
+        let error = report.mean().as_secs_f64() - 0.037;
+        assert!(error.abs() < 0.002, "{:?}", report);
+        assert!(report.stddev() < Duration::from_millis(1), "{:?}", report);
+    }
+
+    #[test]
+    fn test_nothing_heard_back_fails() {
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        let (shared, _audio) = medium
+            .nodes(1, SAMPLE_RATE as usize)
+            .pop()
+            .unwrap();
+
+        // No self link, the chirp never comes back
+        assert!(measure_round_trip(&shared, SAMPLE_RATE).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+// Output level and input clipping
+//
+// The encoders emit +-1 levels, which overdrives some speakers into
+// distortion the receiver cannot decode. Every client plays at `--tx-gain`
+// of full scale instead, through a soft clipper that rounds off whatever
+// would still go past it. The process callback also meters what it plays
+// and watches the input for clipping; a monitor thread logs both.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::audio::backend::{ActiveAudio, AudioBackend, ProcessFn};
+use crate::utils::consts::{
+    CLIP_LEVEL, CLIP_WARN_MS, DUMP_POLL_MS, LEVEL_LOG_MS, SOFT_CLIP_KNEE,
+};
+
+/// `sample` unchanged up to the knee, bent smoothly towards +-1 past it
+pub fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= SOFT_CLIP_KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - SOFT_CLIP_KNEE;
+    let bent = SOFT_CLIP_KNEE
+        + headroom * ((magnitude - SOFT_CLIP_KNEE) / headroom).tanh();
+    bent.copysign(sample)
+}
+
+/// Peak and RMS of what went through, since it was last taken. Fed by the
+/// process callback without a lock.
+#[derive(Default)]
+pub struct LevelMeter {
+    /// f32 bits, positive floats order like their bits
+    peak: AtomicU32,
+    /// Sum of squares, f64 bits
+    energy: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl LevelMeter {
+    pub fn feed(&self, samples: &[f32]) {
+        let (peak, energy) =
+            samples
+                .iter()
+                .fold((0.0f32, 0.0f64), |(peak, energy), s| {
+                    (peak.max(s.abs()), energy + (*s as f64) * (*s as f64))
+                });
+        self.peak
+            .fetch_max(peak.to_bits(), Ordering::Relaxed);
+        let _ = self.energy.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |bits| Some((f64::from_bits(bits) + energy).to_bits()),
+        );
+        self.samples
+            .fetch_add(samples.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Peak and RMS since the last time, None if nothing was fed
+    pub fn take(&self) -> Option<(f32, f32)> {
+        let samples = self
+            .samples
+            .swap(0, Ordering::Relaxed);
+        let peak = f32::from_bits(
+            self.peak
+                .swap(0, Ordering::Relaxed),
+        );
+        let energy = f64::from_bits(
+            self.energy
+                .swap(0, Ordering::Relaxed),
+        );
+        (samples > 0).then(|| (peak, (energy / samples as f64).sqrt() as f32))
+    }
+}
+
+/// Spots the input staying at full scale longer than a click would
+pub struct ClipDetector {
+    /// Consecutive samples over CLIP_LEVEL so far
+    run: usize,
+    min_run: usize,
+}
+
+impl ClipDetector {
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            run: 0,
+            min_run: sample_rate * CLIP_WARN_MS as usize / 1000,
+        }
+    }
+
+    /// Whether the input clipped long enough within `samples`, counting
+    /// the run it carries over
+    pub fn feed(&mut self, samples: &[f32]) -> bool {
+        let mut clipped = false;
+        for sample in samples {
+            if sample.abs() > CLIP_LEVEL {
+                self.run += 1;
+                clipped |= self.run >= self.min_run;
+            } else {
+                self.run = 0;
+            }
+        }
+        clipped
+    }
+}
+
+/// An AudioBackend whose client plays at `gain`, soft clipped
+pub struct LevelBackend {
+    inner: Box<dyn AudioBackend>,
+    gain: f32,
+}
+
+impl LevelBackend {
+    pub fn new(inner: Box<dyn AudioBackend>, gain: f32) -> Self {
+        Self { inner, gain }
+    }
+}
+
+impl AudioBackend for LevelBackend {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.inner.sample_rate()
+    }
+
+    fn register(&mut self) -> Result<(), String> {
+        self.inner.register()
+    }
+
+    fn activate(
+        self: Box<Self>,
+        mut process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String> {
+        let gain = self.gain;
+        let meter = Arc::new(LevelMeter::default());
+        let mut clips = ClipDetector::new(self.inner.sample_rate());
+        let clipped = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let (meter, clipped, running) =
+                (meter.clone(), clipped.clone(), running.clone());
+            thread::spawn(move || {
+                let mut logged = Instant::now();
+                while running.load(Ordering::SeqCst) {
+                    // Short naps, so stopping doesn't wait for the next log
+                    thread::sleep(Duration::from_millis(DUMP_POLL_MS));
+                    if logged.elapsed() < Duration::from_millis(LEVEL_LOG_MS) {
+                        continue;
+                    }
+                    logged = Instant::now();
+                    if let Some((peak, rms)) = meter.take() {
+                        info!("TX level: peak {:.2}, RMS {:.2}", peak, rms);
+                    }
+                    if clipped.swap(false, Ordering::Relaxed) {
+                        warn!(
+                            "Input clipping (over {} for {} ms or more), \
+                             turn the capture level down",
+                            CLIP_LEVEL, CLIP_WARN_MS
+                        );
+                    }
+                }
+            })
+        };
+
+        let leveled: ProcessFn =
+            Box::new(move |in_buffer: &[f32], out_buffer: &mut [f32]| {
+                if clips.feed(in_buffer) {
+                    clipped.store(true, Ordering::Relaxed);
+                }
+                process(in_buffer, out_buffer);
+                for sample in out_buffer.iter_mut() {
+                    *sample = soft_clip(*sample * gain);
+                }
+                // Metered while transmitting, silence isn't worth a line
+                if out_buffer
+                    .iter()
+                    .any(|s| *s != 0.0)
+                {
+                    meter.feed(out_buffer);
+                }
+            });
+        Ok(Box::new(LevelActive {
+            inner: Some(self.inner.activate(leveled)?),
+            running,
+            thread: Some(thread),
+        }))
+    }
+}
+
+/// Stops the monitor once the client is deactivated or dropped
+struct LevelActive {
+    inner: Option<Box<dyn ActiveAudio>>,
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LevelActive {
+    fn stop(&mut self) -> Result<(), String> {
+        let result = match self.inner.take() {
+            Some(inner) => inner.deactivate(),
+            None => Ok(()),
+        };
+        self.running
+            .store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        result
+    }
+}
+
+impl ActiveAudio for LevelActive {
+    fn deactivate(mut self: Box<Self>) -> Result<(), String> {
+        self.stop()
+    }
+}
+
+impl Drop for LevelActive {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::loopback::{LoopbackConfig, LoopbackMedium};
+    use crate::audio::recorder::{AppShared, AppState, build_process_closure};
+    use crate::phy::params::PhyParams;
+    use crate::phy::{FecKind, Frame, LineCodingKind, PhyDecoder, PhyEncoder};
+    use crate::utils::consts::*;
+
+    #[test]
+    fn test_gain_scales_and_soft_clip_bounds() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-SOFT_CLIP_KNEE), -SOFT_CLIP_KNEE);
+        // Bent past the knee, never past full scale
+        for sample in [0.9, 1.0, 1.5, 10.0] {
+            let clipped = soft_clip(sample);
+            assert!(clipped > SOFT_CLIP_KNEE && clipped <= 1.0, "{}", clipped);
+            assert_eq!(soft_clip(-sample), -clipped);
+        }
+        assert!(soft_clip(0.9) < soft_clip(1.0));
+
+        let meter = LevelMeter::default();
+        assert_eq!(meter.take(), None);
+        meter.feed(&[0.3, -0.3, 0.3, -0.3]);
+        meter.feed(&[-0.6]);
+        let (peak, rms) = meter.take().unwrap();
+        assert_eq!(peak, 0.6);
+        assert!((rms - (0.72f32 / 5.0).sqrt()).abs() < 1e-6, "{}", rms);
+        assert_eq!(meter.take(), None);
+    }
+
+    #[test]
+    fn test_clipping_detected_past_a_few_ms() {
+        let min_run = 48000 * CLIP_WARN_MS as usize / 1000;
+        let mut detector = ClipDetector::new(48000);
+        // Short clicks are not clipping
+        assert!(!detector.feed(&vec![1.0; min_run - 1]));
+        assert!(!detector.feed(&[0.5]));
+        assert!(!detector.feed(&vec![-1.0; min_run - 1]));
+        // A run carried across blocks is
+        assert!(detector.feed(&[-1.0]));
+        assert!(detector.feed(&[0.995; 3]));
+        assert!(!detector.feed(&[0.99; 3]));
+    }
+
+    #[test]
+    fn test_transfer_decodes_at_low_gain() {
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        let a = AppShared::new(SAMPLE_RATE as usize);
+        let b = AppShared::new(SAMPLE_RATE as usize);
+        let a_audio =
+            Box::new(LevelBackend::new(Box::new(medium.backend()), 0.3))
+                .activate(build_process_closure(a.clone()))
+                .unwrap();
+        let b_audio = Box::new(medium.backend())
+            .activate(build_process_closure(b.clone()))
+            .unwrap();
+
+        let kind = LineCodingKind::FourBFiveB;
+        let sent: Vec<Frame> = (0..3u8)
+            .map(|i| Frame::new_data(i as u16, 1, 2, vec![i; 40]))
+            .collect();
+        let encoder =
+            PhyEncoder::new(&PhyParams::default(), kind, FecKind::None);
+        b.app_state
+            .set(AppState::Recording);
+        a.play(&encoder.encode_frames(&sent, INTER_FRAME_GAP_SAMPLES));
+        thread::sleep(Duration::from_millis(50));
+        a_audio.deactivate().unwrap();
+        b_audio.deactivate().unwrap();
+
+        let heard = b.record_buffer.pop_all();
+        let peak = heard
+            .iter()
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 0.3).abs() < 1e-6, "{}", peak);
+        let mut decoder =
+            PhyDecoder::new(&PhyParams::default(), kind, FecKind::None, 2);
+        let decoded = decoder.process_samples(&heard);
+        assert_eq!(decoded.len(), sent.len());
+        for (decoded, sent) in decoded.iter().zip(&sent) {
+            assert_eq!(decoded.data, sent.data);
+        }
+    }
+}
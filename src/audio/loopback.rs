@@ -0,0 +1,519 @@
+// Loopback audio backend, for tests and the bench command
+//
+// Virtual sound cards on a simulated medium, so whole nodes (MAC, net
+// tools, router) can run against each other in one process without JACK.
+// A timer thread steps every attached node's process callback one block at
+// a time, at the nominal sample rate or as fast as it can. What a node
+// plays reaches each peer the link's latency later (a block at least),
+// scaled by the link's gain, with Gaussian noise added; the link loses whole
+// transmissions (silence to silence) at its loss rate.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::audio::backend::{ActiveAudio, AudioBackend, ProcessFn};
+use crate::audio::recorder::{AppShared, build_process_closure};
+
+/// What the medium does to the signal from one node to another
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkParams {
+    /// A block at least, whatever is set
+    pub latency: Duration,
+    pub gain: f32,
+    /// Standard deviation of the added noise
+    pub noise: f32,
+    /// Chance that a transmission doesn't arrive at all
+    pub loss: f64,
+}
+
+impl Default for LinkParams {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            gain: 1.0,
+            noise: 0.0,
+            loss: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pace {
+    /// One block per block duration, as a sound card would
+    RealTime,
+    #[cfg(test)]
+    /// Back to back, for tests that don't wait on the clock
+    Fast,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LoopbackConfig {
+    pub sample_rate: u32,
+    /// Samples per process call
+    pub block: usize,
+    pub pace: Pace,
+    /// Seeds the noise and each link's losses
+    pub seed: u64,
+    /// Every link, until set_link changes one
+    pub link: LinkParams,
+}
+
+impl Default for LoopbackConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000,
+            block: 48,
+            pace: Pace::RealTime,
+            seed: 0,
+            link: LinkParams::default(),
+        }
+    }
+}
+
+struct Link {
+    from: usize,
+    to: usize,
+    params: LinkParams,
+    delay: VecDeque<f32>,
+    /// The transmission under way is lost
+    dropping: bool,
+    /// Losses of this link alone, whatever the others and the noise draw
+    loss_rng: StdRng,
+}
+
+struct MediumState {
+    config: LoopbackConfig,
+    /// Process callback of each node, None until activated
+    nodes: Vec<Option<ProcessFn>>,
+    /// Whether each node played anything in the last block
+    on_air: Vec<bool>,
+    links: Vec<Link>,
+    /// Links set apart from the config's, attached or not
+    overrides: HashMap<(usize, usize), LinkParams>,
+    /// Noise
+    rng: StdRng,
+}
+
+impl MediumState {
+    fn link(&self, from: usize, to: usize) -> Link {
+        let params = self
+            .overrides
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(self.config.link);
+        let latency = (params.latency.as_secs_f64()
+            * self.config.sample_rate as f64)
+            .round() as usize;
+        Link {
+            from,
+            to,
+            params,
+            delay: VecDeque::from(vec![0.0; latency]),
+            dropping: false,
+            loss_rng: StdRng::seed_from_u64(
+                self.config.seed ^ ((from as u64) << 32 | to as u64),
+            ),
+        }
+    }
+
+    fn attach(&mut self) -> usize {
+        let id = self.nodes.len();
+        for peer in 0..id {
+            let there = self.link(id, peer);
+            let back = self.link(peer, id);
+            self.links
+                .extend([there, back]);
+        }
+        if self
+            .overrides
+            .contains_key(&(id, id))
+        {
+            let echo = self.link(id, id);
+            self.links.push(echo);
+        }
+        self.nodes.push(None);
+        self.on_air.push(false);
+        id
+    }
+
+    /// Run every active node for one block
+    fn step(&mut self) {
+        let block = self.config.block;
+        let mut inputs = vec![vec![0.0f32; block]; self.nodes.len()];
+        for link in &mut self.links {
+            // No noise for nodes that aren't listening yet
+            let listening = self.nodes[link.to].is_some();
+            let input = &mut inputs[link.to];
+            for sample in input.iter_mut() {
+                *sample += link
+                    .delay
+                    .pop_front()
+                    .unwrap_or(0.0);
+                if listening && link.params.noise > 0.0 {
+                    *sample += link.params.noise * gaussian(&mut self.rng);
+                }
+            }
+        }
+
+        let mut outputs = vec![vec![0.0f32; block]; self.nodes.len()];
+        for ((process, input), output) in self
+            .nodes
+            .iter_mut()
+            .zip(&inputs)
+            .zip(&mut outputs)
+        {
+            if let Some(process) = process {
+                process(input, output);
+            }
+        }
+
+        for link in &mut self.links {
+            let output = &outputs[link.from];
+            let playing = output
+                .iter()
+                .any(|s| *s != 0.0);
+            if playing && !self.on_air[link.from] {
+                link.dropping = link
+                    .loss_rng
+                    .random_bool(link.params.loss);
+            }
+            let gain = if link.dropping { 0.0 } else { link.params.gain };
+            link.delay.extend(
+                output
+                    .iter()
+                    .map(|s| s * gain),
+            );
+        }
+        for (on_air, output) in self
+            .on_air
+            .iter_mut()
+            .zip(&outputs)
+        {
+            *on_air = output
+                .iter()
+                .any(|s| *s != 0.0);
+        }
+    }
+}
+
+/// Standard normal sample, Box-Muller
+pub fn gaussian(rng: &mut StdRng) -> f32 {
+    let u1: f32 = rng
+        .random::<f32>()
+        .max(f32::MIN_POSITIVE);
+    let u2: f32 = rng.random();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+/// Medium that backend::open attaches new clients to, if any
+static ROUTED: Mutex<Option<Arc<Mutex<MediumState>>>> = Mutex::new(None);
+
+#[cfg(test)]
+/// A loopback sound card for backend::open, if a medium takes them
+pub(crate) fn routed_backend() -> Option<LoopbackBackend> {
+    let routed = ROUTED.lock().ok()?;
+    routed
+        .as_ref()
+        .map(|state| LoopbackBackend::attach(state.clone()))
+}
+
+/// The simulated medium and its timer thread, stopped when dropped
+pub struct LoopbackMedium {
+    state: Arc<Mutex<MediumState>>,
+    running: Arc<AtomicBool>,
+    timer: Option<thread::JoinHandle<()>>,
+}
+
+impl LoopbackMedium {
+    pub fn new(config: LoopbackConfig) -> Self {
+        let state = Arc::new(Mutex::new(MediumState {
+            config,
+            nodes: Vec::new(),
+            on_air: Vec::new(),
+            links: Vec::new(),
+            overrides: HashMap::new(),
+            rng: StdRng::seed_from_u64(config.seed),
+        }));
+        let running = Arc::new(AtomicBool::new(true));
+        let timer = {
+            let state = state.clone();
+            let running = running.clone();
+            thread::spawn(move || {
+                let block_time = Duration::from_secs_f64(
+                    config.block as f64 / config.sample_rate as f64,
+                );
+                let start = Instant::now();
+                let mut steps = 0u32;
+                while running.load(Ordering::SeqCst) {
+                    let due = match config.pace {
+                        Pace::RealTime => {
+                            (start.elapsed().as_secs_f64()
+                                / block_time.as_secs_f64())
+                                as u32
+                        }
+                        #[cfg(test)]
+                        Pace::Fast => steps + 1,
+                    };
+                    while steps < due {
+                        state.lock().unwrap().step();
+                        steps += 1;
+                    }
+                    match config.pace {
+                        Pace::RealTime => thread::sleep(block_time / 2),
+                        #[cfg(test)]
+                        Pace::Fast => thread::yield_now(),
+                    }
+                }
+            })
+        };
+        Self {
+            state,
+            running,
+            timer: Some(timer),
+        }
+    }
+
+    /// A new sound card on the medium
+    pub fn backend(&self) -> LoopbackBackend {
+        LoopbackBackend::attach(self.state.clone())
+    }
+
+    /// `count` new nodes, each an AppShared driven by its own process
+    /// callback as with a sound card, recording at most `record_samples`.
+    /// The nodes are attached in order, so they are numbered as returned.
+    pub fn nodes(
+        &self,
+        count: usize,
+        record_samples: usize,
+    ) -> Vec<(AppShared, Box<dyn ActiveAudio>)> {
+        (0..count)
+            .map(|_| {
+                let shared = AppShared::new(record_samples);
+                let active = Box::new(self.backend())
+                    .activate(build_process_closure(shared.clone()))
+                    .expect("Loopback activation cannot fail");
+                (shared, active)
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    /// Change the link from node `from` to node `to`, dropping whatever is
+    /// on its way. Nodes not attached yet get it once they are. A node only
+    /// hears its own echo through a link set from it to itself.
+    pub fn set_link(&self, from: usize, to: usize, params: LinkParams) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .overrides
+            .insert((from, to), params);
+        let link = state.link(from, to);
+        if let Some(old) = state
+            .links
+            .iter_mut()
+            .find(|link| link.from == from && link.to == to)
+        {
+            *old = link;
+        } else if from == to && from < state.nodes.len() {
+            state.links.push(link);
+        }
+    }
+
+    #[cfg(test)]
+    /// Let backend::open attach every client it opens to this medium,
+    /// until the medium is dropped
+    pub fn take_opens(&self) {
+        *ROUTED.lock().unwrap() = Some(self.state.clone());
+    }
+}
+
+impl Drop for LoopbackMedium {
+    fn drop(&mut self) {
+        if let Ok(mut routed) = ROUTED.lock()
+            && routed
+                .as_ref()
+                .is_some_and(|state| Arc::ptr_eq(state, &self.state))
+        {
+            *routed = None;
+        }
+        self.running
+            .store(false, Ordering::SeqCst);
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
+
+/// One node's sound card on a LoopbackMedium
+pub struct LoopbackBackend {
+    state: Arc<Mutex<MediumState>>,
+    id: usize,
+    sample_rate: u32,
+}
+
+impl LoopbackBackend {
+    fn attach(state: Arc<Mutex<MediumState>>) -> Self {
+        let (id, sample_rate) = {
+            let mut medium = state.lock().unwrap();
+            (medium.attach(), medium.config.sample_rate)
+        };
+        Self {
+            state,
+            id,
+            sample_rate,
+        }
+    }
+}
+
+impl AudioBackend for LoopbackBackend {
+    fn name(&self) -> &'static str {
+        "loopback"
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.sample_rate as usize
+    }
+
+    fn register(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn activate(
+        self: Box<Self>,
+        process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String> {
+        self.state
+            .lock()
+            .map_err(|_| "Loopback medium poisoned".to_string())?
+            .nodes[self.id] = Some(process);
+        Ok(Box::new(LoopbackActive {
+            state: self.state,
+            id: self.id,
+        }))
+    }
+}
+
+/// Goes silent once deactivated or dropped
+struct LoopbackActive {
+    state: Arc<Mutex<MediumState>>,
+    id: usize,
+}
+
+impl ActiveAudio for LoopbackActive {
+    fn deactivate(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Drop for LoopbackActive {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.nodes[self.id] = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::recorder::AppState;
+
+    #[test]
+    fn test_medium_delays_scales_and_loses() {
+        let medium = LoopbackMedium::new(LoopbackConfig {
+            pace: Pace::Fast,
+            link: LinkParams {
+                latency: Duration::from_millis(2),
+                gain: 0.5,
+                ..LinkParams::default()
+            },
+            ..LoopbackConfig::default()
+        });
+        let nodes = medium.nodes(3, 48000);
+        let (a, b, c) = (&nodes[0].0, &nodes[1].0, &nodes[2].0);
+        // Node 2 hears nothing from node 0
+        medium.set_link(
+            0,
+            2,
+            LinkParams {
+                loss: 1.0,
+                ..LinkParams::default()
+            },
+        );
+        for node in [b, c] {
+            node.app_state
+                .set(AppState::Recording);
+        }
+        a.queue_playback(&[0.8; 480]);
+        a.app_state
+            .set(AppState::Playing);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while b.record_buffer.len() < 2000 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        let heard = b.record_buffer.peek_all();
+        let first = heard
+            .iter()
+            .position(|s| *s != 0.0)
+            .unwrap();
+        // The link's 96 samples, later if b started listening earlier
+        assert!(first >= 96, "{}", first);
+        assert_eq!(heard[first..first + 480], [0.4; 480]);
+        assert!(
+            heard[first + 480..]
+                .iter()
+                .all(|s| *s == 0.0)
+        );
+        assert!(
+            c.record_buffer
+                .peek_all()
+                .iter()
+                .all(|s| *s == 0.0)
+        );
+        assert!(matches!(a.app_state.get(), AppState::Idle));
+    }
+
+    #[test]
+    fn test_noise_is_seeded() {
+        let heard = |seed| {
+            let medium = LoopbackMedium::new(LoopbackConfig {
+                pace: Pace::Fast,
+                seed,
+                link: LinkParams {
+                    noise: 0.1,
+                    ..LinkParams::default()
+                },
+                ..LoopbackConfig::default()
+            });
+            // Only b listens, from its first block on
+            let _a = medium.backend();
+            let b = AppShared::new(960);
+            b.app_state
+                .set(AppState::Recording);
+            let _active = Box::new(medium.backend())
+                .activate(build_process_closure(b.clone()))
+                .unwrap();
+            while matches!(b.app_state.get(), AppState::Recording) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            b.record_buffer.peek_all()
+        };
+        let noise = heard(3);
+        assert_eq!(noise.len(), 960);
+        let power = noise
+            .iter()
+            .map(|s| s * s)
+            .sum::<f32>()
+            / 960.0;
+        assert!((0.005..0.02).contains(&power), "{}", power);
+        assert_eq!(noise, heard(3));
+        assert_ne!(noise, heard(4));
+    }
+}
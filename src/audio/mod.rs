@@ -1,2 +1,9 @@
+pub mod backend;
 pub mod codec;
+pub mod dump;
+pub mod latency;
+pub mod level;
+pub mod loopback;
 pub mod recorder;
+pub mod resample;
+pub mod ring;
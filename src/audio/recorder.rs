@@ -1,8 +1,10 @@
-use jack;
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
-#[derive(Clone, Debug)]
+use crate::audio::backend::ProcessFn;
+use crate::audio::ring::SampleRing;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AppState {
     Recording,
     Playing,
@@ -10,144 +12,210 @@ pub enum AppState {
     RecordingAndPlaying,
 }
 
-/// Thread-safe shared state
+impl AppState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => AppState::Recording,
+            1 => AppState::Playing,
+            3 => AppState::RecordingAndPlaying,
+            _ => AppState::Idle,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            AppState::Recording => 0,
+            AppState::Playing => 1,
+            AppState::Idle => 2,
+            AppState::RecordingAndPlaying => 3,
+        }
+    }
+}
+
+/// AppState the process callback reads and changes without a lock
+pub struct StateCell(AtomicU8);
+
+impl StateCell {
+    pub fn new(state: AppState) -> Self {
+        Self(AtomicU8::new(state.as_u8()))
+    }
+
+    pub fn get(&self) -> AppState {
+        AppState::from_u8(self.0.load(Ordering::Acquire))
+    }
+
+    pub fn set(&self, state: AppState) {
+        self.0
+            .store(state.as_u8(), Ordering::Release);
+    }
+
+    /// Move from `from` to `to`, unless the state changed in the meantime.
+    /// Returns whether it moved.
+    pub fn transition(&self, from: AppState, to: AppState) -> bool {
+        self.0
+            .compare_exchange(
+                from.as_u8(),
+                to.as_u8(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+}
+
+/// Thread-safe shared state. The process callback only pushes into
+/// `record_buffer` and pops from `playback_buffer`, everything else takes
+/// the other side of each ring.
 #[derive(Clone)]
 pub struct AppShared {
-    pub record_buffer: Arc<Mutex<Vec<f32>>>,
-    pub playback_buffer: Arc<Mutex<VecDeque<f32>>>,
-    pub app_state: Arc<Mutex<AppState>>,
-    pub sample_counter: Arc<Mutex<usize>>,
+    pub record_buffer: Arc<SampleRing>,
+    pub playback_buffer: Arc<SampleRing>,
+    pub app_state: Arc<StateCell>,
+    pub sample_counter: Arc<AtomicUsize>,
 }
 
 impl AppShared {
     pub fn new(capacity_samples: usize) -> Self {
         Self {
-            record_buffer: Arc::new(Mutex::new(Vec::with_capacity(
-                capacity_samples,
-            ))),
-            playback_buffer: Arc::new(Mutex::new(VecDeque::new())),
-            app_state: Arc::new(Mutex::new(AppState::Idle)),
-            sample_counter: Arc::new(Mutex::new(0usize)),
+            record_buffer: Arc::new(SampleRing::new(capacity_samples)),
+            playback_buffer: Arc::new(SampleRing::new(capacity_samples)),
+            app_state: Arc::new(StateCell::new(AppState::Idle)),
+            sample_counter: Arc::new(AtomicUsize::new(0)),
         }
     }
-}
 
-pub fn build_process_closure(
-    in_port: jack::Port<jack::AudioIn>,
-    mut out_port: jack::Port<jack::AudioOut>,
-    shared: AppShared,
-    recording_duration_samples: usize,
-) -> impl FnMut(&jack::Client, &jack::ProcessScope) -> jack::Control + Send + 'static
-{
-    let shared_cb = shared.clone();
-
-    let process_cb =
-        move |_: &jack::Client, ps: &jack::ProcessScope| -> jack::Control {
-            let in_buffer = in_port.as_slice(ps);
-            let out_buffer = out_port.as_mut_slice(ps);
-
-            for sample in out_buffer.iter_mut() {
-                *sample = 0.0;
-            }
+    /// Queue `samples` in place of whatever was waiting to be played.
+    /// Returns how many fit, the stream is finished if all of them did.
+    pub fn queue_playback(&self, samples: &[f32]) -> usize {
+        self.playback_buffer.clear();
+        let queued = self
+            .playback_buffer
+            .push_slice(samples);
+        if queued == samples.len() {
+            self.playback_buffer.finish();
+        }
+        queued
+    }
+
+    /// Play `samples` and wait until they are out, leaving the state Idle.
+    /// A burst longer than the ring is fed in as it drains.
+    pub fn play(&self, samples: &[f32]) {
+        self.play_in(samples, AppState::Playing);
+    }
+
+    /// Like play, recording all along, leaving the state Recording
+    pub fn play_recording(&self, samples: &[f32]) {
+        self.play_in(samples, AppState::RecordingAndPlaying);
+    }
+
+    fn play_in(&self, samples: &[f32], state: AppState) {
+        self.playback_buffer.clear();
+        let mut queued = 0;
+        self.feed_playback(samples, &mut queued);
+        self.app_state.set(state);
+        while self.app_state.get() == state {
+            self.feed_playback(samples, &mut queued);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
 
-            let current_state = {
-                let state = shared_cb
-                    .app_state
-                    .lock()
-                    .unwrap();
-                state.clone()
-            };
-
-            match current_state {
-                AppState::Recording => {
-                    let mut recorded = shared_cb
-                        .record_buffer
-                        .lock()
-                        .unwrap();
-                    let mut counter = shared_cb
-                        .sample_counter
-                        .lock()
-                        .unwrap();
-
-                    for &sample in in_buffer {
-                        if recorded.len() < recording_duration_samples {
-                            recorded.push(sample);
-                            *counter += 1;
-                        } else {
-                            let mut state = shared_cb
-                                .app_state
-                                .lock()
-                                .unwrap();
-                            *state = AppState::Idle;
-                            break;
-                        }
-                    }
-
-                    // out_buffer.copy_from_slice(in_buffer);
+    /// Push what fits of `samples` past `queued`, finishing the stream
+    /// with the last of them
+    fn feed_playback(&self, samples: &[f32], queued: &mut usize) {
+        let n = (samples.len() - *queued).min(self.playback_buffer.free());
+        *queued += self
+            .playback_buffer
+            .push_slice(&samples[*queued..*queued + n]);
+        if *queued == samples.len() {
+            self.playback_buffer.finish();
+        }
+    }
+
+    /// Samples dropped because the record ring was full
+    pub fn record_overflows(&self) -> u64 {
+        self.record_buffer.overflows()
+    }
+
+    /// Samples the sound card asked for before playback had them
+    pub fn playback_underflows(&self) -> u64 {
+        self.playback_buffer
+            .underflows()
+    }
+
+    pub fn reset_xruns(&self) {
+        self.record_buffer
+            .reset_counters();
+        self.playback_buffer
+            .reset_counters();
+    }
+
+    /// Callback side of recording. Returns false once the ring is full.
+    fn record(&self, samples: &[f32]) -> bool {
+        let taken = self
+            .record_buffer
+            .push_slice(samples);
+        self.sample_counter
+            .fetch_add(taken, Ordering::Relaxed);
+        taken == samples.len()
+    }
+}
+
+/// Process callback moving samples between the sound card and `shared`.
+/// It takes no lock and allocates nothing, recording stops once the record
+/// ring is full.
+pub fn build_process_closure(shared: AppShared) -> ProcessFn {
+    let process_cb = move |in_buffer: &[f32], out_buffer: &mut [f32]| {
+        out_buffer.fill(0.0);
+
+        match shared.app_state.get() {
+            AppState::Recording => {
+                if !shared.record(in_buffer) {
+                    shared
+                        .app_state
+                        .transition(AppState::Recording, AppState::Idle);
                 }
-                AppState::Playing => {
-                    let mut playback = shared_cb
+
+                // out_buffer.copy_from_slice(in_buffer);
+            }
+            AppState::Playing => {
+                let played = shared
+                    .playback_buffer
+                    .pop_slice(out_buffer);
+                if played < out_buffer.len()
+                    && shared
                         .playback_buffer
-                        .lock()
-                        .unwrap();
-                    for out_sample in out_buffer.iter_mut() {
-                        if let Some(sample) = playback.pop_front() {
-                            *out_sample = sample;
-                        } else {
-                            let mut state = shared_cb
-                                .app_state
-                                .lock()
-                                .unwrap();
-                            *state = AppState::Idle;
-                            break;
-                        }
-                    }
+                        .is_finished()
+                {
+                    shared
+                        .app_state
+                        .transition(AppState::Playing, AppState::Idle);
                 }
-                AppState::Idle => {}
-                AppState::RecordingAndPlaying => {
-                    // Record: in_buffer -> record_buffer
-                    let mut recorded = shared_cb
-                        .record_buffer
-                        .lock()
-                        .unwrap();
-
-                    let mut counter = shared_cb
-                        .sample_counter
-                        .lock()
-                        .unwrap();
-
-                    for &sample in in_buffer {
-                        if recorded.len() < recording_duration_samples {
-                            recorded.push(sample);
-                            *counter += 1;
-                        } else {
-                            break;
-                        }
-                    }
-
-                    // Play: playback_buffer -> out_buffer
-                    let mut playback = shared_cb
+            }
+            AppState::Idle => {}
+            AppState::RecordingAndPlaying => {
+                // Each half ends on its own, the state keeps the other
+                let recording = shared.record(in_buffer);
+                let played = shared
+                    .playback_buffer
+                    .pop_slice(out_buffer);
+                let playing = played == out_buffer.len()
+                    || !shared
                         .playback_buffer
-                        .lock()
-                        .unwrap();
-
-                    for out_sample in out_buffer.iter_mut() {
-                        if let Some(sample) = playback.pop_front() {
-                            *out_sample = sample;
-                        } else {
-                            let mut state = shared_cb
-                                .app_state
-                                .lock()
-                                .unwrap();
-                            *state = AppState::Recording;
-                            break;
-                        }
-                    }
+                        .is_finished();
+                let next = match (recording, playing) {
+                    (true, true) => None,
+                    (true, false) => Some(AppState::Recording),
+                    (false, true) => Some(AppState::Playing),
+                    (false, false) => Some(AppState::Idle),
+                };
+                if let Some(next) = next {
+                    shared
+                        .app_state
+                        .transition(AppState::RecordingAndPlaying, next);
                 }
             }
+        }
+    };
 
-            jack::Control::Continue
-        };
-
-    process_cb
+    Box::new(process_cb)
 }
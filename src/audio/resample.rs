@@ -0,0 +1,240 @@
+// Sample rate conversion
+//
+// The PHY is designed for SAMPLE_RATE: SAMPLES_PER_LEVEL and every timing
+// constant count samples at 48 kHz. A sound card running at another rate
+// (44.1 kHz, typically) is wrapped in a ResamplingBackend, so the process
+// callback still sees SAMPLE_RATE blocks, converted from and to the device
+// rate by linear interpolation on the way in and out.
+
+use tracing::info;
+
+use crate::audio::backend::{ActiveAudio, AudioBackend, ProcessFn};
+use crate::utils::consts::SAMPLE_RATE;
+
+/// Streaming linear interpolation from one rate to another
+pub struct Resampler {
+    from_rate: i64,
+    to_rate: i64,
+    /// Input position of the next output sample, in 1/to_rate of a sample:
+    /// 0 = the first of the next chunk, -to_rate = the last of the previous
+    position: i64,
+    /// Last sample of the previous chunk
+    last: f32,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        Self {
+            from_rate: from_rate as i64,
+            to_rate: to_rate as i64,
+            position: 0,
+            last: 0.0,
+        }
+    }
+
+    /// Most samples `input_len` input samples convert to
+    pub fn max_output(&self, input_len: usize) -> usize {
+        input_len * self.to_rate as usize / self.from_rate as usize + 2
+    }
+
+    /// Append the next `input` samples, converted, to `output`
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if input.is_empty() {
+            return;
+        }
+        let at = |index: i64| {
+            if index < 0 {
+                self.last
+            } else {
+                input[index as usize]
+            }
+        };
+        let end = (input.len() as i64 - 1) * self.to_rate;
+        while self.position < end {
+            let index = self
+                .position
+                .div_euclid(self.to_rate);
+            let fraction = self
+                .position
+                .rem_euclid(self.to_rate) as f32
+                / self.to_rate as f32;
+            let (a, b) = (at(index), at(index + 1));
+            output.push(a + (b - a) * fraction);
+            self.position += self.from_rate;
+        }
+        self.position -= input.len() as i64 * self.to_rate;
+        self.last = input[input.len() - 1];
+    }
+}
+
+/// An AudioBackend whose client runs at SAMPLE_RATE whatever the device
+/// rate is
+pub struct ResamplingBackend {
+    inner: Box<dyn AudioBackend>,
+}
+
+impl ResamplingBackend {
+    pub fn new(inner: Box<dyn AudioBackend>) -> Self {
+        Self { inner }
+    }
+}
+
+impl AudioBackend for ResamplingBackend {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn sample_rate(&self) -> usize {
+        SAMPLE_RATE as usize
+    }
+
+    fn register(&mut self) -> Result<(), String> {
+        self.inner.register()
+    }
+
+    fn activate(
+        self: Box<Self>,
+        mut process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String> {
+        let device_rate = self.inner.sample_rate() as u32;
+        info!("Resampling {} Hz <-> {} Hz", device_rate, SAMPLE_RATE);
+        let mut record = Resampler::new(device_rate, SAMPLE_RATE);
+        let mut playback = Resampler::new(SAMPLE_RATE, device_rate);
+
+        // Room for the largest blocks sound servers use, so the callback
+        // doesn't allocate
+        let block = record.max_output(8192);
+        let mut input = Vec::with_capacity(block);
+        let mut output = Vec::with_capacity(block);
+        // Played samples converted but not out yet. Primed with a couple so
+        // the interpolation lag never leaves a block short.
+        let mut pending = Vec::with_capacity(block);
+        pending.extend_from_slice(&[0.0; 2]);
+
+        let converted: ProcessFn =
+            Box::new(move |in_buffer: &[f32], out_buffer: &mut [f32]| {
+                input.clear();
+                record.process(in_buffer, &mut input);
+                output.clear();
+                output.resize(input.len(), 0.0);
+                process(&input, &mut output);
+
+                playback.process(&output, &mut pending);
+                let n = pending
+                    .len()
+                    .min(out_buffer.len());
+                out_buffer[..n].copy_from_slice(&pending[..n]);
+                out_buffer[n..].fill(0.0);
+                pending.drain(..n);
+            });
+        self.inner.activate(converted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::loopback::{LoopbackConfig, LoopbackMedium};
+    use crate::audio::recorder::{AppShared, AppState, build_process_closure};
+    use crate::phy::params::PhyParams;
+    use crate::phy::{FecKind, Frame, LineCodingKind, PhyDecoder, PhyEncoder};
+    use crate::utils::consts::*;
+    use std::time::Duration;
+
+    fn frames() -> Vec<Frame> {
+        (0..3u8)
+            .map(|i| Frame::new_data(i as u16, 1, 2, vec![i ^ 0x5A; 40]))
+            .collect()
+    }
+
+    fn codec(kind: LineCodingKind) -> (PhyEncoder, PhyDecoder) {
+        (
+            PhyEncoder::new(&PhyParams::default(), kind, FecKind::None),
+            PhyDecoder::new(&PhyParams::default(), kind, FecKind::None, 2),
+        )
+    }
+
+    fn assert_same(decoded: &[Frame], sent: &[Frame]) {
+        assert_eq!(decoded.len(), sent.len());
+        for (decoded, sent) in decoded.iter().zip(sent) {
+            assert_eq!(decoded.sequence, sent.sequence);
+            assert_eq!(decoded.data, sent.data);
+        }
+    }
+
+    #[test]
+    fn test_chunking_irrelevant_and_rate_kept() {
+        let input: Vec<f32> = (0..4410)
+            .map(|n| (n as f32 * 0.01).sin())
+            .collect();
+        let mut whole = Vec::new();
+        Resampler::new(44100, 48000).process(&input, &mut whole);
+        let mut chunked = Vec::new();
+        let mut resampler = Resampler::new(44100, 48000);
+        for chunk in input.chunks(37) {
+            resampler.process(chunk, &mut chunked);
+        }
+
+        assert_eq!(whole, chunked);
+        // 100 ms either way, short of the sample still to interpolate
+        assert!((4799..=4800).contains(&whole.len()), "{}", whole.len());
+        // Exactly on the input where the positions line up
+        assert_eq!(whole[160], input[147]);
+    }
+
+    #[test]
+    fn test_frames_survive_44_1_khz_and_back() {
+        for kind in [LineCodingKind::FourBFiveB, LineCodingKind::Nrzi] {
+            let (encoder, mut decoder) = codec(kind);
+            let sent = frames();
+            let mut signal =
+                encoder.encode_frames(&sent, INTER_FRAME_GAP_SAMPLES);
+            signal.extend_from_slice(&[0.0; INTER_FRAME_GAP_SAMPLES]);
+
+            // What a 44.1 kHz sound card plays, then hands back to the PHY
+            let mut device = Vec::new();
+            Resampler::new(SAMPLE_RATE, 44100).process(&signal, &mut device);
+            let mut back = Vec::new();
+            let mut resampler = Resampler::new(44100, SAMPLE_RATE);
+            for block in device.chunks(512) {
+                resampler.process(block, &mut back);
+            }
+
+            assert_same(&decoder.process_samples(&back), &sent);
+        }
+    }
+
+    #[test]
+    fn test_transfer_over_44_1_khz_devices() {
+        let medium = LoopbackMedium::new(LoopbackConfig {
+            sample_rate: 44100,
+            block: 441,
+            ..LoopbackConfig::default()
+        });
+        let resampling =
+            || Box::new(ResamplingBackend::new(Box::new(medium.backend())));
+        let a = AppShared::new(SAMPLE_RATE as usize);
+        let b = AppShared::new(SAMPLE_RATE as usize);
+        let a_client = resampling();
+        assert_eq!(a_client.sample_rate(), SAMPLE_RATE as usize);
+        let a_audio = a_client
+            .activate(build_process_closure(a.clone()))
+            .unwrap();
+        let b_audio = resampling()
+            .activate(build_process_closure(b.clone()))
+            .unwrap();
+
+        let kind = LineCodingKind::FourBFiveB;
+        let (encoder, mut decoder) = codec(kind);
+        let sent = frames();
+        b.app_state
+            .set(AppState::Recording);
+        a.play(&encoder.encode_frames(&sent, INTER_FRAME_GAP_SAMPLES));
+        std::thread::sleep(Duration::from_millis(50));
+        a_audio.deactivate().unwrap();
+        b_audio.deactivate().unwrap();
+
+        let heard = b.record_buffer.pop_all();
+        assert_same(&decoder.process_samples(&heard), &sent);
+    }
+}
@@ -0,0 +1,356 @@
+// Lock-free sample ring
+//
+// The process callback runs on the sound card's realtime thread, where a
+// lock held by a MAC or UI thread means an xrun. Samples cross between the
+// two through fixed rings instead: one side pushes, the other peeks and
+// pops, neither waits for or allocates on behalf of the other. Positions
+// only ever grow, a slot is `position % capacity`. Samples that don't fit
+// and reads that come up short are counted rather than blocked on, unless
+// the producer has marked the stream finished.
+//
+// A clear moves the read position up to the write position. When the
+// producer clears, it may then overwrite slots the consumer is still
+// copying, so a read checks the read position again after copying and
+// starts over if it moved (the seqlock pattern, with `head` as the
+// sequence).
+
+use std::sync::atomic::{
+    AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence,
+};
+
+/// Single producer, single consumer ring of samples
+pub struct SampleRing {
+    slots: Box<[AtomicU32]>,
+    /// Next position to read
+    head: AtomicUsize,
+    /// Next position to write
+    tail: AtomicUsize,
+    /// Nothing more follows what was pushed
+    finished: AtomicBool,
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+}
+
+impl SampleRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: (0..capacity.max(1))
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            finished: AtomicBool::new(false),
+            overflows: AtomicU64::new(0),
+            underflows: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Samples waiting to be read
+    pub fn len(&self) -> usize {
+        let head = self
+            .head
+            .load(Ordering::Acquire);
+        self.tail
+            .load(Ordering::Acquire)
+            .saturating_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Room left for the producer
+    pub fn free(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    fn slot(&self, position: usize) -> &AtomicU32 {
+        &self.slots[position % self.slots.len()]
+    }
+
+    /// Producer: append what fits of `samples`, counting the rest as
+    /// overflow. Returns how many were taken.
+    pub fn push_slice(&self, samples: &[f32]) -> usize {
+        let tail = self
+            .tail
+            .load(Ordering::Relaxed);
+        let free = self.capacity()
+            - (tail
+                - self
+                    .head
+                    .load(Ordering::Acquire));
+        let n = samples.len().min(free);
+        for (i, sample) in samples[..n]
+            .iter()
+            .enumerate()
+        {
+            self.slot(tail + i)
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.tail
+            .store(tail + n, Ordering::Release);
+        if n < samples.len() {
+            self.overflows
+                .fetch_add((samples.len() - n) as u64, Ordering::Relaxed);
+        }
+        n
+    }
+
+    /// Producer: push_slice for anything iterable, off the realtime thread
+    pub fn extend(&self, samples: impl IntoIterator<Item = f32>) -> usize {
+        let samples: Vec<f32> = samples.into_iter().collect();
+        self.push_slice(&samples)
+    }
+
+    /// Producer: everything of the stream has been pushed, running dry
+    /// from here on is its end rather than an underflow
+    pub fn finish(&self) {
+        self.finished
+            .store(true, Ordering::Release);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+            .load(Ordering::Acquire)
+    }
+
+    /// Consumer: fill `out` from the front, counting what was missing as
+    /// underflow unless the stream is finished. Returns how many were read.
+    pub fn pop_slice(&self, out: &mut [f32]) -> usize {
+        let (head, n) = self.read(0, out);
+        self.advance(head, n);
+        if n < out.len() && !self.is_finished() {
+            self.underflows
+                .fetch_add((out.len() - n) as u64, Ordering::Relaxed);
+        }
+        n
+    }
+
+    /// Consumer: everything waiting, taken out of the ring
+    pub fn pop_all(&self) -> Vec<f32> {
+        let mut samples = vec![0.0; self.len()];
+        let (head, n) = self.read(0, &mut samples);
+        self.advance(head, n);
+        samples.truncate(n);
+        samples
+    }
+
+    /// Consumer: a copy of everything waiting, left in the ring
+    pub fn peek_all(&self) -> Vec<f32> {
+        self.peek_from(0)
+    }
+
+    /// Consumer: a copy of what is waiting past the first `skip` samples
+    pub fn peek_from(&self, skip: usize) -> Vec<f32> {
+        let mut samples = vec![
+            0.0;
+            self.len()
+                .saturating_sub(skip)
+        ];
+        let (_, n) = self.read(skip, &mut samples);
+        samples.truncate(n);
+        samples
+    }
+
+    /// Drop everything waiting and start a new stream. Either side may
+    /// call it: a read the producer clears under is started over from the
+    /// new position, and its pop doesn't move the read position again.
+    pub fn clear(&self) {
+        self.finished
+            .store(false, Ordering::Release);
+        self.head.fetch_max(
+            self.tail
+                .load(Ordering::Acquire),
+            Ordering::AcqRel,
+        );
+        // Slot stores after this can't be seen by a reader that still
+        // sees the old head
+        fence(Ordering::Release);
+    }
+
+    /// Samples pushed into a full ring and dropped
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+            .load(Ordering::Relaxed)
+    }
+
+    /// Samples asked for that weren't there
+    pub fn underflows(&self) -> u64 {
+        self.underflows
+            .load(Ordering::Relaxed)
+    }
+
+    pub fn reset_counters(&self) {
+        self.overflows
+            .store(0, Ordering::Relaxed);
+        self.underflows
+            .store(0, Ordering::Relaxed);
+    }
+
+    /// Copy into `out` from `skip` past the read position. Returns the
+    /// read position the copy was made at and how many were copied.
+    fn read(&self, skip: usize, out: &mut [f32]) -> (usize, usize) {
+        loop {
+            let head = self
+                .head
+                .load(Ordering::Acquire);
+            let tail = self
+                .tail
+                .load(Ordering::Acquire);
+            let n = out
+                .len()
+                .min(tail.saturating_sub(head + skip));
+            for (i, sample) in out[..n]
+                .iter_mut()
+                .enumerate()
+            {
+                *sample = f32::from_bits(
+                    self.slot(head + skip + i)
+                        .load(Ordering::Relaxed),
+                );
+            }
+            // Cleared meanwhile: the producer may have overwritten what
+            // was copied
+            fence(Ordering::Acquire);
+            if self
+                .head
+                .load(Ordering::Relaxed)
+                == head
+            {
+                return (head, n);
+            }
+        }
+    }
+
+    /// Move the read position from `head` past `n` samples, unless a clear
+    /// got there first
+    fn advance(&self, head: usize, n: usize) {
+        let _ = self.head.compare_exchange(
+            head,
+            head + n,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_ring_wraps_and_counts_xruns() {
+        let ring = SampleRing::new(8);
+        assert_eq!(ring.push_slice(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]), 6);
+        let mut out = [0.0; 4];
+        assert_eq!(ring.pop_slice(&mut out), 4);
+        assert_eq!(out, [1.0, 2.0, 3.0, 4.0]);
+
+        // Wraps around the end, the last two don't fit
+        assert_eq!(ring.extend((7..15).map(|i| i as f32)), 6);
+        assert_eq!(ring.overflows(), 2);
+        assert_eq!(ring.len(), 8);
+        assert_eq!(ring.peek_from(6), vec![11.0, 12.0]);
+        assert_eq!(ring.peek_all().len(), 8);
+        assert_eq!(
+            ring.pop_all(),
+            vec![5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0]
+        );
+
+        assert_eq!(ring.pop_slice(&mut out), 0);
+        assert_eq!(ring.underflows(), 4);
+        ring.push_slice(&[1.0; 3]);
+        ring.clear();
+        assert!(ring.is_empty());
+        assert_eq!(ring.free(), 8);
+
+        // Running dry at the end of a finished stream is no underflow
+        ring.push_slice(&[1.0; 3]);
+        ring.finish();
+        assert_eq!(ring.pop_slice(&mut out), 3);
+        assert_eq!(ring.underflows(), 4);
+        ring.clear();
+        assert!(!ring.is_finished());
+    }
+
+    #[test]
+    fn test_spsc_keeps_order_across_threads() {
+        let ring = Arc::new(SampleRing::new(256));
+        let total = 200_000;
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                let mut next = 0;
+                while next < total {
+                    let block: Vec<f32> = (next..(next + 64).min(total))
+                        .map(|i| i as f32)
+                        .collect();
+                    let mut sent = 0;
+                    while sent < block.len() {
+                        sent += ring.push_slice(&block[sent..]);
+                        thread::yield_now();
+                    }
+                    next += block.len();
+                }
+            })
+        };
+        let mut received = Vec::with_capacity(total);
+        let mut out = [0.0; 48];
+        while received.len() < total {
+            let n = ring.len().min(out.len());
+            ring.pop_slice(&mut out[..n]);
+            received.extend_from_slice(&out[..n]);
+        }
+        producer.join().unwrap();
+        assert!(
+            received
+                .iter()
+                .enumerate()
+                .all(|(i, s)| *s == i as f32)
+        );
+        assert_eq!(ring.underflows(), 0);
+    }
+
+    #[test]
+    fn test_clear_while_reading_never_returns_torn_samples() {
+        // The producer stamps samples with a counter and clears now and
+        // then; whatever the consumer gets must still count upwards
+        let ring = Arc::new(SampleRing::new(64));
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                let deadline = Instant::now() + Duration::from_millis(500);
+                let mut next = 0;
+                // Stamps stay exact as f32 up to 2^24
+                while Instant::now() < deadline && next < 1 << 24 {
+                    let block: Vec<f32> = (next..next + 16)
+                        .map(|i| i as f32)
+                        .collect();
+                    let pushed = ring.push_slice(&block);
+                    next += pushed;
+                    if pushed > 0 && next % 7 == 0 {
+                        ring.clear();
+                    }
+                }
+                ring.finish();
+            })
+        };
+        let mut last = -1.0;
+        let mut out = [0.0; 48];
+        while !(ring.is_finished() && ring.is_empty()) {
+            let n = ring.pop_slice(&mut out);
+            for &sample in &out[..n] {
+                assert!(sample > last, "{} after {}", sample, last);
+                last = sample;
+            }
+        }
+        producer.join().unwrap();
+    }
+}
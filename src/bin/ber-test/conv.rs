@@ -0,0 +1,164 @@
+// Bit and frame error rates of the rate-1/2 convolutional code
+//
+// The Waterfall's frames go out as unit-amplitude levels, uncoded and
+// through the convolutional code, get Gaussian noise added and are decoded
+// with hard and with soft Viterbi decisions. No line code is involved: SNR
+// is per channel symbol (Es/N0), what one level sees on the air.
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+use trackmaker_rs::audio::loopback::gaussian;
+use trackmaker_rs::phy::fec;
+
+use crate::waterfall::{BerPoint, Waterfall, bit_errors};
+
+/// Default SNR points in dB, where the curves part
+pub const SNR_DB: [f32; 6] = [0.0, 1.0, 2.0, 3.0, 4.0, 6.0];
+/// SNR from which both decoders must beat the uncoded levels
+pub const CODING_GAIN_SNR_DB: f32 = 3.0;
+
+pub const UNCODED: &str = "uncoded";
+pub const CONV_HARD: &str = "conv-hard";
+pub const CONV_SOFT: &str = "conv-soft";
+
+/// Points in the order uncoded, hard, soft, each swept from low to high
+/// SNR, with the frames, SNR points and seed of `waterfall`
+pub fn run(waterfall: &Waterfall) -> Vec<BerPoint> {
+    let frames = waterfall.data_frames();
+    let mut points = Vec::new();
+    for (i, scheme) in [UNCODED, CONV_HARD, CONV_SOFT]
+        .into_iter()
+        .enumerate()
+    {
+        for (j, &snr_db) in waterfall
+            .snr_db
+            .iter()
+            .enumerate()
+        {
+            let point_seed = waterfall
+                .seed
+                .wrapping_add(((i as u64) << 32) | (j as u64 + 1));
+            points.push(measure(scheme, snr_db, &frames, point_seed));
+        }
+    }
+    points
+}
+
+fn measure(
+    scheme: &str,
+    snr_db: f32,
+    frames: &[Vec<u8>],
+    seed: u64,
+) -> BerPoint {
+    let mut rng = StdRng::seed_from_u64(seed);
+    // Unit-amplitude levels: Es = 1, sigma^2 = N0 / 2
+    let sigma = (0.5 / 10f32.powf(snr_db / 10.0)).sqrt();
+
+    let mut point = BerPoint {
+        snr_db,
+        scheme: scheme.to_string(),
+        bits: 0,
+        bit_errors: 0,
+        frames: 0,
+        frame_errors: 0,
+    };
+    for bits in frames {
+        let sent = match scheme {
+            UNCODED => bits.clone(),
+            _ => fec::conv_encode(bits),
+        };
+        let levels: Vec<f32> = sent
+            .iter()
+            .map(|&b| if b == 1 { 1.0 } else { -1.0 } + sigma * gaussian(&mut rng))
+            .collect();
+        let sliced = || {
+            levels
+                .iter()
+                .map(|&v| (v > 0.0) as u8)
+                .collect::<Vec<u8>>()
+        };
+        let decoded = match scheme {
+            UNCODED => sliced(),
+            CONV_HARD => fec::viterbi_decode_hard(&sliced()),
+            _ => fec::viterbi_decode_soft(&levels),
+        };
+
+        let errors = bit_errors(bits, &decoded);
+        point.bits += bits.len() as u64;
+        point.bit_errors += errors as u64;
+        point.frames += 1;
+        point.frame_errors += (errors > 0) as u64;
+    }
+    point
+}
+
+/// Check that hard and soft Viterbi decoding beat the uncoded levels from
+/// CODING_GAIN_SNR_DB on, wherever the uncoded levels had errors to fix
+pub fn check(points: &[BerPoint]) -> Result<(), String> {
+    let uncoded = points
+        .iter()
+        .filter(|p| p.scheme == UNCODED && p.snr_db >= CODING_GAIN_SNR_DB);
+    for uncoded in uncoded.filter(|p| p.bit_errors > 0) {
+        for coded in points
+            .iter()
+            .filter(|p| p.scheme != UNCODED && p.snr_db == uncoded.snr_db)
+        {
+            if coded.ber() >= uncoded.ber() {
+                return Err(format!(
+                    "{} BER {:.2e} not below {} {:.2e} at {} dB",
+                    coded.scheme,
+                    coded.ber(),
+                    uncoded.scheme,
+                    uncoded.ber(),
+                    uncoded.snr_db
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coding_gain_from_moderate_snr() {
+        let waterfall = Waterfall {
+            snr_db: SNR_DB.to_vec(),
+            frames: 40,
+            ..Waterfall::default()
+        };
+        let points = run(&waterfall);
+        assert_eq!(points.len(), 3 * SNR_DB.len());
+        assert_eq!(check(&points), Ok(()));
+        // Soft decisions gain over hard ones where the noise is worst
+        let at_0_db = |scheme: &str| {
+            points
+                .iter()
+                .find(|p| p.scheme == scheme && p.snr_db == 0.0)
+                .unwrap()
+                .ber()
+        };
+        assert!(at_0_db(CONV_SOFT) < at_0_db(CONV_HARD));
+        assert!(at_0_db(CONV_HARD) < at_0_db(UNCODED));
+        assert_eq!(run(&waterfall), points);
+    }
+
+    #[test]
+    fn test_check_rejects_missing_gain() {
+        let point = |scheme: &str, bit_errors| BerPoint {
+            snr_db: 4.0,
+            scheme: scheme.to_string(),
+            bits: 10_000,
+            bit_errors,
+            frames: 40,
+            frame_errors: 0,
+        };
+        assert!(check(&[point(UNCODED, 100), point(CONV_SOFT, 10)]).is_ok());
+        assert!(check(&[point(UNCODED, 100), point(CONV_HARD, 100)]).is_err());
+        // Nothing to beat
+        assert!(check(&[point(UNCODED, 0), point(CONV_HARD, 0)]).is_ok());
+    }
+}
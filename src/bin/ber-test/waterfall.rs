@@ -0,0 +1,370 @@
+// Bit and frame error rates over additive white Gaussian noise
+//
+// Pseudo-random frames go through FEC, the interleaver if any and a line
+// code, get noise added and are sliced and decoded again, without preamble
+// or sync: what is measured is the coding alone. SNR is per line level
+// (Es/N0): the signal power over the noise power left after averaging a
+// level's samples, which is what the slicers do. A frame with any bit wrong, or cut short because
+// the decoder gave up on an invalid symbol, is a frame error; the missing
+// bits count as bit errors.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use trackmaker_rs::audio::loopback::gaussian;
+use trackmaker_rs::phy::interleaver::Interleaver;
+use trackmaker_rs::phy::params::PhyParams;
+use trackmaker_rs::phy::{FecKind, LineCodingKind};
+
+/// A line code with the FEC and interleaver in front of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scheme {
+    pub line_coding: LineCodingKind,
+    pub fec: FecKind,
+    pub interleaver: Option<Interleaver>,
+}
+
+impl Scheme {
+    pub fn new(line_coding: LineCodingKind, fec: FecKind) -> Self {
+        Self {
+            line_coding,
+            fec,
+            interleaver: None,
+        }
+    }
+
+    pub fn interleaved(mut self, interleaver: Interleaver) -> Self {
+        self.interleaver = Some(interleaver);
+        self
+    }
+
+    /// `4B5B`, `4B5B+hamming74` or `4B5B+hamming74+8x16`
+    pub fn name(&self) -> String {
+        let mut name = self
+            .line_coding
+            .name()
+            .to_string();
+        if self.fec != FecKind::None {
+            name.push('+');
+            name.push_str(self.fec.name());
+        }
+        if let Some(interleaver) = self.interleaver {
+            name.push_str(&format!(
+                "+{}x{}",
+                interleaver.rows(),
+                interleaver.cols()
+            ));
+        }
+        name
+    }
+}
+
+/// Errors counted for one scheme at one SNR
+#[derive(Debug, Clone, PartialEq)]
+pub struct BerPoint {
+    pub snr_db: f32,
+    pub scheme: String,
+    pub bits: u64,
+    pub bit_errors: u64,
+    pub frames: u64,
+    pub frame_errors: u64,
+}
+
+impl BerPoint {
+    pub fn ber(&self) -> f64 {
+        self.bit_errors as f64 / self.bits.max(1) as f64
+    }
+
+    pub fn fer(&self) -> f64 {
+        self.frame_errors as f64 / self.frames.max(1) as f64
+    }
+}
+
+/// An SNR sweep over a set of schemes
+#[derive(Debug, Clone)]
+pub struct Waterfall {
+    pub schemes: Vec<Scheme>,
+    pub snr_db: Vec<f32>,
+    pub frames: usize,
+    /// Data bits per frame, a multiple of 8
+    pub frame_bits: usize,
+    /// Seeds the data and the noise, the same seed gives the same curves
+    pub seed: u64,
+    /// Samples per line level, the rest of the PHY isn't involved
+    pub params: PhyParams,
+}
+
+impl Default for Waterfall {
+    /// Every line code uncoded, with Hamming(7,4) and with Hamming(7,4)
+    /// interleaved, 0 to 14 dB
+    fn default() -> Self {
+        let line_codings = [
+            LineCodingKind::FourBFiveB,
+            LineCodingKind::Manchester,
+            LineCodingKind::EightBTenB,
+            LineCodingKind::Nrzi,
+        ];
+        Self {
+            schemes: line_codings
+                .iter()
+                .flat_map(|&line_coding| {
+                    [
+                        Scheme::new(line_coding, FecKind::None),
+                        Scheme::new(line_coding, FecKind::Hamming74),
+                        Scheme::new(line_coding, FecKind::Hamming74)
+                            .interleaved(Interleaver::default()),
+                    ]
+                })
+                .collect(),
+            snr_db: (0..=7)
+                .map(|i| 2.0 * i as f32)
+                .collect(),
+            frames: 200,
+            frame_bits: 256,
+            seed: 0x1086,
+            params: PhyParams::default(),
+        }
+    }
+}
+
+impl Waterfall {
+    /// The pseudo-random data of every frame, the same for every scheme
+    pub fn data_frames(&self) -> Vec<Vec<u8>> {
+        let mut data_rng = StdRng::seed_from_u64(self.seed);
+        (0..self.frames)
+            .map(|_| {
+                (0..self.frame_bits)
+                    .map(|_| data_rng.random_range(0..=1))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Points in scheme order, each scheme swept from low to high SNR
+    pub fn run(&self) -> Vec<BerPoint> {
+        let frames = self.data_frames();
+        let mut points = Vec::new();
+        for (i, scheme) in self
+            .schemes
+            .iter()
+            .enumerate()
+        {
+            for (j, &snr_db) in self.snr_db.iter().enumerate() {
+                // Each point has its own noise, whatever else is swept
+                let point_seed = self
+                    .seed
+                    .wrapping_add(((i as u64) << 32) | (j as u64 + 1));
+                points.push(measure(
+                    scheme,
+                    snr_db,
+                    &frames,
+                    point_seed,
+                    &self.params,
+                ));
+            }
+        }
+        points
+    }
+}
+
+fn measure(
+    scheme: &Scheme,
+    snr_db: f32,
+    frames: &[Vec<u8>],
+    seed: u64,
+    params: &PhyParams,
+) -> BerPoint {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let line_code = scheme
+        .line_coding
+        .create(params.samples_per_level);
+    let snr = 10f32.powf(snr_db / 10.0);
+
+    let mut point = BerPoint {
+        snr_db,
+        scheme: scheme.name(),
+        bits: 0,
+        bit_errors: 0,
+        frames: 0,
+        frame_errors: 0,
+    };
+    for bits in frames {
+        let mut coded = scheme.fec.encode(bits);
+        if let Some(interleaver) = scheme.interleaver {
+            coded = interleaver.interleave(&coded);
+        }
+        let mut samples = line_code.encode(&coded);
+        let power = samples
+            .iter()
+            .map(|s| s * s)
+            .sum::<f32>()
+            / samples.len().max(1) as f32;
+        // Averaging a level's samples divides the variance by as many
+        let sigma = (power * params.samples_per_level as f32 / snr).sqrt();
+        for sample in samples.iter_mut() {
+            *sample += sigma * gaussian(&mut rng);
+        }
+        let mut sliced = line_code.decode(&samples);
+        if let Some(interleaver) = scheme.interleaver {
+            sliced.truncate(coded.len());
+            sliced = interleaver.deinterleave(&sliced);
+        }
+        let decoded = scheme.fec.decode(&sliced);
+
+        let errors = bit_errors(bits, &decoded);
+        point.bits += bits.len() as u64;
+        point.bit_errors += errors as u64;
+        point.frames += 1;
+        point.frame_errors += (errors > 0) as u64;
+    }
+    point
+}
+
+/// Bits of `sent` that `received` got wrong or doesn't have
+pub fn bit_errors(sent: &[u8], received: &[u8]) -> usize {
+    sent.iter()
+        .zip(received)
+        .filter(|(a, b)| a != b)
+        .count()
+        + sent
+            .len()
+            .saturating_sub(received.len())
+}
+
+/// `snr_db,scheme,ber,fer`, one line per point
+pub fn to_csv(points: &[BerPoint]) -> String {
+    let mut csv = String::from("snr_db,scheme,ber,fer\n");
+    for point in points {
+        csv.push_str(&format!(
+            "{},{},{:e},{:e}\n",
+            point.snr_db,
+            point.scheme,
+            point.ber(),
+            point.fer()
+        ));
+    }
+    csv
+}
+
+/// BER that rises with the SNR by more than chance allows: the counting
+/// noise of the lower point plus a little slack
+const MONOTONIC_SLACK: f64 = 1e-3;
+/// SNR at which interleaved Hamming(7,4) must beat uncoded 4B5B
+pub const FEC_GAIN_SNR_DB: f32 = 10.0;
+
+/// Check that every curve falls with the SNR and that interleaved
+/// Hamming(7,4) pays off for 4B5B at FEC_GAIN_SNR_DB, if both were swept
+pub fn check(points: &[BerPoint]) -> Result<(), String> {
+    for pair in points.windows(2) {
+        let [low, high] = pair else { unreachable!() };
+        if low.scheme != high.scheme || high.snr_db <= low.snr_db {
+            continue;
+        }
+        let chance = 3.0 * (low.ber() / low.bits.max(1) as f64).sqrt();
+        if high.ber() > low.ber() + chance + MONOTONIC_SLACK {
+            return Err(format!(
+                "{}: BER rises from {:.2e} at {} dB to {:.2e} at {} dB",
+                low.scheme,
+                low.ber(),
+                low.snr_db,
+                high.ber(),
+                high.snr_db
+            ));
+        }
+    }
+
+    let at_gain_snr = |scheme: Scheme| {
+        let name = scheme.name();
+        points
+            .iter()
+            .find(|p| p.scheme == name && p.snr_db == FEC_GAIN_SNR_DB)
+    };
+    let uncoded = Scheme::new(LineCodingKind::FourBFiveB, FecKind::None);
+    let coded = Scheme::new(LineCodingKind::FourBFiveB, FecKind::Hamming74)
+        .interleaved(Interleaver::default());
+    if let (Some(uncoded), Some(coded)) =
+        (at_gain_snr(uncoded), at_gain_snr(coded))
+        && coded.ber() >= uncoded.ber()
+    {
+        return Err(format!(
+            "{} BER {:.2e} not below {} {:.2e} at {} dB",
+            coded.scheme,
+            coded.ber(),
+            uncoded.scheme,
+            uncoded.ber(),
+            FEC_GAIN_SNR_DB
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_channel_decodes_every_scheme() {
+        for samples_per_level in [2, PhyParams::default().samples_per_level] {
+            let waterfall = Waterfall {
+                snr_db: vec![60.0],
+                frames: 4,
+                params: PhyParams {
+                    samples_per_level,
+                    ..PhyParams::default()
+                },
+                ..Waterfall::default()
+            };
+            for point in waterfall.run() {
+                assert_eq!(point.bit_errors, 0, "{:?}", point);
+                assert_eq!(point.bits, 4 * 256);
+            }
+        }
+    }
+
+    #[test]
+    fn test_waterfall_falls_and_fec_pays_off() {
+        let waterfall = Waterfall {
+            schemes: vec![
+                Scheme::new(LineCodingKind::FourBFiveB, FecKind::None),
+                Scheme::new(LineCodingKind::FourBFiveB, FecKind::Hamming74)
+                    .interleaved(Interleaver::default()),
+                Scheme::new(LineCodingKind::Manchester, FecKind::None),
+            ],
+            snr_db: vec![0.0, 4.0, 8.0, 10.0, 14.0],
+            frames: 100,
+            ..Waterfall::default()
+        };
+        let points = waterfall.run();
+        assert_eq!(check(&points), Ok(()));
+        // Noise at 0 dB, none to speak of at 14
+        assert!(points[0].ber() > 1e-2, "{:?}", points[0]);
+        assert!(points[0].fer() > points[0].ber());
+        assert!(points[4].ber() < 1e-3, "{:?}", points[4]);
+        // The FEC gain check had both points to compare
+        assert_eq!(points[3].snr_db, FEC_GAIN_SNR_DB);
+        assert!(points[5 + 3].ber() < points[3].ber());
+
+        // Same seed, same curves
+        assert_eq!(waterfall.run(), points);
+
+        let csv = to_csv(&points);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "snr_db,scheme,ber,fer");
+        assert_eq!(lines.len(), 1 + 3 * 5);
+        assert!(lines[6].starts_with("0,4B5B+hamming74+8x16,"));
+    }
+
+    #[test]
+    fn test_check_rejects_rising_curve() {
+        let point = |snr_db, bit_errors| BerPoint {
+            snr_db,
+            scheme: "NRZI".to_string(),
+            bits: 100_000,
+            bit_errors,
+            frames: 100,
+            frame_errors: 0,
+        };
+        assert!(check(&[point(0.0, 5000), point(2.0, 4000)]).is_ok());
+        assert!(check(&[point(0.0, 4000), point(2.0, 5000)]).is_err());
+    }
+}
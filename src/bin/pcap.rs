@@ -6,12 +6,13 @@ use etherparse::{
 };
 use pcap::{Capture, Device, Linktype};
 use tracing::{debug, error, info};
-use trackmaker_rs::utils::logging::init_logging;
+use trackmaker_rs::utils::logging::{LogOptions, init_logging_with};
 
 // Note: Run this with SUDO!
 
 fn main() {
-    init_logging();
+    init_logging_with(&LogOptions::default())
+        .expect("Default logging can be set up");
     info!("Starting packet capture example...");
     let main_device =
         trackmaker_rs::net::pcap_utils::get_device_by_name("wlan0").unwrap();
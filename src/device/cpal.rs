@@ -0,0 +1,317 @@
+// cpal audio backend
+//
+// The system's default input and output devices (ALSA, CoreAudio, WASAPI),
+// or those --input-port / --output-port name, for machines without a JACK
+// server. cpal runs capture and playback as
+// two streams, so captured samples wait in a short queue and the output
+// callback, one block at a time, hands them to the process callback along
+// with the block to play. Only the first channel is recorded; the output
+// goes to every channel.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample, StreamConfig};
+use tracing::{error, info, warn};
+
+use crate::audio::backend::{self, ActiveAudio, AudioBackend, ProcessFn};
+
+/// Captured samples not yet handed to the process callback
+type CaptureQueue = Arc<Mutex<VecDeque<f32>>>;
+
+pub struct CpalBackend {
+    input: cpal::Device,
+    output: cpal::Device,
+    sample_rate: u32,
+    formats: Option<(SampleFormat, StreamConfig, SampleFormat, StreamConfig)>,
+}
+
+impl CpalBackend {
+    /// The default host's selected input and output devices, its default
+    /// ones unless backend::select_ports named others
+    pub fn new() -> Result<Self, String> {
+        let host = cpal::default_host();
+        let ports = backend::selected_ports();
+        let input = match &ports.input {
+            Some(name) => find_device(host.input_devices(), name)
+                .map_err(|e| format!("Bad --input-port: {}", e))?,
+            None => host
+                .default_input_device()
+                .ok_or("No default audio input device")?,
+        };
+        let output = match &ports.output {
+            Some(name) => find_device(host.output_devices(), name)
+                .map_err(|e| format!("Bad --output-port: {}", e))?,
+            None => host
+                .default_output_device()
+                .ok_or("No default audio output device")?,
+        };
+        let sample_rate = output
+            .default_output_config()
+            .map_err(|e| format!("Cannot query audio output: {}", e))?
+            .sample_rate()
+            .0;
+        info!("cpal audio ({:?}):", host.id());
+        info!(
+            "  Input: {}",
+            input
+                .name()
+                .unwrap_or_default()
+        );
+        info!(
+            "  Output: {}",
+            output
+                .name()
+                .unwrap_or_default()
+        );
+        info!("  Sample Rate: {} Hz", sample_rate);
+        Ok(Self {
+            input,
+            output,
+            sample_rate,
+            formats: None,
+        })
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn name(&self) -> &'static str {
+        "cpal"
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.sample_rate as usize
+    }
+
+    fn register(&mut self) -> Result<(), String> {
+        let input = self
+            .input
+            .default_input_config()
+            .map_err(|e| format!("Cannot query audio input: {}", e))?;
+        let output = self
+            .output
+            .default_output_config()
+            .map_err(|e| format!("Cannot query audio output: {}", e))?;
+        if input.sample_rate().0 != self.sample_rate {
+            warn!(
+                "Audio input runs at {} Hz, asking for {} Hz",
+                input.sample_rate().0,
+                self.sample_rate
+            );
+        }
+        // Both streams at the output's rate, one capture sample per played one
+        let config = |channels| StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(self.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        self.formats = Some((
+            input.sample_format(),
+            config(input.channels()),
+            output.sample_format(),
+            config(output.channels()),
+        ));
+        Ok(())
+    }
+
+    fn activate(
+        self: Box<Self>,
+        process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String> {
+        let (in_format, in_config, out_format, out_config) = self
+            .formats
+            .ok_or("Audio streams are not registered")?;
+        // About a second of capture the output side hasn't caught up with
+        let capacity = self.sample_rate as usize;
+        let queue: CaptureQueue =
+            Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+
+        let input = match in_format {
+            SampleFormat::F32 => {
+                capture::<f32>(&self.input, &in_config, queue.clone(), capacity)
+            }
+            SampleFormat::I16 => {
+                capture::<i16>(&self.input, &in_config, queue.clone(), capacity)
+            }
+            SampleFormat::U16 => {
+                capture::<u16>(&self.input, &in_config, queue.clone(), capacity)
+            }
+            other => Err(format!("Unsupported audio input format {:?}", other)),
+        }?;
+        let output = match out_format {
+            SampleFormat::F32 => {
+                playback::<f32>(&self.output, &out_config, queue, process)
+            }
+            SampleFormat::I16 => {
+                playback::<i16>(&self.output, &out_config, queue, process)
+            }
+            SampleFormat::U16 => {
+                playback::<u16>(&self.output, &out_config, queue, process)
+            }
+            other => Err(format!("Unsupported audio output format {:?}", other)),
+        }?;
+
+        for stream in [&input, &output] {
+            stream
+                .play()
+                .map_err(|e| format!("Cannot start audio stream: {}", e))?;
+        }
+        Ok(Box::new(CpalActive { input, output }))
+    }
+}
+
+/// The device of `devices` that `requested` names, see backend::match_port
+fn find_device<I>(
+    devices: Result<I, cpal::DevicesError>,
+    requested: &str,
+) -> Result<cpal::Device, String>
+where
+    I: Iterator<Item = cpal::Device>,
+{
+    let mut devices: Vec<(String, cpal::Device)> = devices
+        .map_err(|e| format!("Cannot list audio devices: {}", e))?
+        .filter_map(|device| Some((device.name().ok()?, device)))
+        .collect();
+    let names: Vec<String> = devices
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+    let name = backend::match_port(requested, &names)?;
+    let index = names
+        .iter()
+        .position(|n| *n == name)
+        .expect("Matched name is one of them");
+    Ok(devices.swap_remove(index).1)
+}
+
+/// The default host's input and output devices, formatted
+pub fn list_devices() -> Result<String, String> {
+    let host = cpal::default_host();
+    let default_input = host
+        .default_input_device()
+        .and_then(|device| device.name().ok());
+    let default_output = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let devices = host
+        .devices()
+        .map_err(|e| format!("Cannot list audio devices: {}", e))?;
+
+    let mut out = format!("cpal devices ({:?}):\n", host.id());
+    for device in devices {
+        let Ok(name) = device.name() else {
+            continue;
+        };
+        let mut kinds = Vec::new();
+        if device
+            .default_input_config()
+            .is_ok()
+        {
+            kinds.push(if default_input.as_ref() == Some(&name) {
+                "input (default)"
+            } else {
+                "input"
+            });
+        }
+        if device
+            .default_output_config()
+            .is_ok()
+        {
+            kinds.push(if default_output.as_ref() == Some(&name) {
+                "output (default)"
+            } else {
+                "output"
+            });
+        }
+        out.push_str(&format!("  {:<40}{}\n", name, kinds.join(", ")));
+    }
+    Ok(out)
+}
+
+fn capture<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    queue: CaptureQueue,
+    capacity: usize,
+) -> Result<cpal::Stream, String>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| {
+                let mut queue = queue.lock().unwrap();
+                for frame in data.chunks(channels) {
+                    if queue.len() == capacity {
+                        queue.pop_front();
+                    }
+                    queue.push_back(f32::from_sample(frame[0]));
+                }
+            },
+            |e| error!("Audio input error: {}", e),
+            None,
+        )
+        .map_err(|e| format!("Cannot open audio input: {}", e))
+}
+
+fn playback<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    queue: CaptureQueue,
+    mut process: ProcessFn,
+) -> Result<cpal::Stream, String>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut in_block = Vec::new();
+    let mut out_block = Vec::new();
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                let frames = data.len() / channels;
+                in_block.clear();
+                {
+                    let mut queue = queue.lock().unwrap();
+                    let ready = frames.min(queue.len());
+                    in_block.extend(queue.drain(..ready));
+                }
+                // Capture running behind, play on regardless
+                in_block.resize(frames, 0.0);
+                out_block.clear();
+                out_block.resize(frames, 0.0);
+                process(&in_block, &mut out_block);
+                for (frame, sample) in data
+                    .chunks_mut(channels)
+                    .zip(&out_block)
+                {
+                    frame.fill(T::from_sample(*sample));
+                }
+            },
+            |e| error!("Audio output error: {}", e),
+            None,
+        )
+        .map_err(|e| format!("Cannot open audio output: {}", e))
+}
+
+/// Both streams running; dropping them stops them too
+struct CpalActive {
+    input: cpal::Stream,
+    output: cpal::Stream,
+}
+
+impl ActiveAudio for CpalActive {
+    fn deactivate(self: Box<Self>) -> Result<(), String> {
+        for stream in [&self.output, &self.input] {
+            stream
+                .pause()
+                .map_err(|e| format!("Cannot stop audio stream: {}", e))?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,114 @@
 use jack;
 use tracing::{debug, error, info, warn};
 
+use crate::audio::backend::{
+    self, ActiveAudio, AudioBackend, PortSelection, ProcessFn,
+};
+use crate::utils::consts::{INPUT_PORT_NAME, OUTPUT_PORT_NAME};
+
+/// JACK client with one mono port each way, wired to the selected physical
+/// ports on activation (see backend::select_ports)
+pub struct JackBackend {
+    client: jack::Client,
+    ports: Option<(jack::Port<jack::AudioIn>, jack::Port<jack::AudioOut>)>,
+}
+
+impl JackBackend {
+    /// Connect to a running JACK server, never starting one
+    pub fn new(client_name: &str) -> Result<Self, String> {
+        let (client, status) =
+            jack::Client::new(client_name, jack::ClientOptions::NO_START_SERVER)
+                .map_err(|e| format!("Cannot connect to JACK: {}", e))?;
+        debug!("JACK client status: {:?}", status);
+        print_jack_info(&client);
+        Ok(Self {
+            client,
+            ports: None,
+        })
+    }
+}
+
+impl AudioBackend for JackBackend {
+    fn name(&self) -> &'static str {
+        "jack"
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.client.sample_rate()
+    }
+
+    fn register(&mut self) -> Result<(), String> {
+        let in_port = self
+            .client
+            .register_port(INPUT_PORT_NAME, jack::AudioIn::default())
+            .map_err(|e| {
+                format!("Cannot register {}: {}", INPUT_PORT_NAME, e)
+            })?;
+        let out_port = self
+            .client
+            .register_port(OUTPUT_PORT_NAME, jack::AudioOut::default())
+            .map_err(|e| {
+                format!("Cannot register {}: {}", OUTPUT_PORT_NAME, e)
+            })?;
+        self.ports = Some((in_port, out_port));
+        Ok(())
+    }
+
+    fn activate(
+        self: Box<Self>,
+        process: ProcessFn,
+    ) -> Result<Box<dyn ActiveAudio>, String> {
+        let (in_port, out_port) = self
+            .ports
+            .ok_or("JACK ports are not registered")?;
+        let port_name = |name: Result<String, jack::Error>| {
+            name.map_err(|e| format!("Cannot name JACK port: {}", e))
+        };
+        let in_name = port_name(in_port.name())?;
+        let out_name = port_name(out_port.name())?;
+
+        let handler = jack::contrib::ClosureProcessHandler::new(
+            port_process_closure(in_port, out_port, process),
+        );
+        let active_client = self
+            .client
+            .activate_async((), handler)
+            .map_err(|e| format!("Cannot activate JACK client: {}", e))?;
+        connect_system_ports(
+            active_client.as_client(),
+            &in_name,
+            &out_name,
+            &backend::selected_ports(),
+        )?;
+        Ok(Box::new(active_client))
+    }
+}
+
+/// JACK process callback running `process` on the ports' buffers
+pub fn port_process_closure(
+    in_port: jack::Port<jack::AudioIn>,
+    mut out_port: jack::Port<jack::AudioOut>,
+    mut process: ProcessFn,
+) -> impl FnMut(&jack::Client, &jack::ProcessScope) -> jack::Control + Send + 'static
+{
+    move |_: &jack::Client, ps: &jack::ProcessScope| {
+        process(in_port.as_slice(ps), out_port.as_mut_slice(ps));
+        jack::Control::Continue
+    }
+}
+
+impl<N, P> ActiveAudio for jack::AsyncClient<N, P>
+where
+    N: 'static + Send + Sync + jack::NotificationHandler,
+    P: 'static + Send + jack::ProcessHandler,
+{
+    fn deactivate(self: Box<Self>) -> Result<(), String> {
+        jack::AsyncClient::deactivate(*self)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
 pub fn print_jack_info(client: &jack::Client) -> (usize, usize) {
     let sample_rate = client.sample_rate();
     let buffer_size = client.buffer_size();
@@ -14,27 +122,36 @@ pub fn print_jack_info(client: &jack::Client) -> (usize, usize) {
     (sample_rate as usize, buffer_size as usize)
 }
 
+/// Wire our ports to the physical ports `ports` names, the first ones
+/// where it names none. Fails before connecting anything if a named port
+/// doesn't exist, listing those that do.
 pub fn connect_system_ports(
     client: &jack::Client,
     in_port_name: &str,
     out_port_name: &str,
-) {
-    let system_input_ports = client.ports(
-        None,
-        None,
-        jack::PortFlags::IS_INPUT | jack::PortFlags::IS_PHYSICAL,
-    );
-
-    let system_output_ports = client.ports(
-        None,
-        None,
-        jack::PortFlags::IS_OUTPUT | jack::PortFlags::IS_PHYSICAL,
-    );
+    ports: &PortSelection,
+) -> Result<(), String> {
+    let system_input_ports = list_system_input_ports(client);
+    let system_output_ports = list_system_output_ports(client);
 
     debug!("{} physical input found.", system_input_ports.len());
     debug!("{} physical output found.", system_output_ports.len());
 
-    if let Some(system_out) = system_output_ports.first() {
+    // We record from a physical output (capture) and play to a physical
+    // input (playback)
+    let pick = |requested: &Option<String>, available: &[String]| {
+        requested
+            .as_deref()
+            .map(|name| backend::match_port(name, available))
+            .transpose()
+            .map(|picked| picked.or_else(|| available.first().cloned()))
+    };
+    let system_out = pick(&ports.input, &system_output_ports)
+        .map_err(|e| format!("Bad --input-port: {}", e))?;
+    let system_in = pick(&ports.output, &system_input_ports)
+        .map_err(|e| format!("Bad --output-port: {}", e))?;
+
+    if let Some(system_out) = &system_out {
         match client.connect_ports_by_name(system_out, in_port_name) {
             Ok(_) => {
                 info!("Connected Input: {} -> {}", system_out, in_port_name)
@@ -46,7 +163,7 @@ pub fn connect_system_ports(
         }
     }
 
-    if let Some(system_in) = system_input_ports.first() {
+    if let Some(system_in) = &system_in {
         match client.connect_ports_by_name(out_port_name, system_in) {
             Ok(_) => {
                 info!("Connected Output: {} -> {}", out_port_name, system_in)
@@ -58,9 +175,89 @@ pub fn connect_system_ports(
         }
     }
 
-    if system_output_ports.is_empty() || system_input_ports.is_empty() {
+    if system_out.is_none() || system_in.is_none() {
         warn!("Missing input / output");
     }
+    Ok(())
+}
+
+/// A physical port as list-devices shows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortInfo {
+    pub name: String,
+    /// Capture ports feed our input, playback ports take our output
+    pub capture: bool,
+    pub port_type: String,
+    pub aliases: Vec<String>,
+    pub connections: Vec<String>,
+}
+
+/// Every physical port of the server, capture ports first
+pub fn physical_ports(client: &jack::Client) -> Vec<PortInfo> {
+    let capture = list_system_output_ports(client)
+        .into_iter()
+        .map(|name| (name, true));
+    let playback = list_system_input_ports(client)
+        .into_iter()
+        .map(|name| (name, false));
+    capture
+        .chain(playback)
+        .filter_map(|(name, capture)| {
+            let port = client.port_by_name(&name)?;
+            Some(PortInfo {
+                capture,
+                port_type: port
+                    .port_type()
+                    .unwrap_or_default(),
+                aliases: port
+                    .aliases()
+                    .unwrap_or_default(),
+                connections: port.get_connections(),
+                name,
+            })
+        })
+        .collect()
+}
+
+/// Print physical ports the way the list-devices command shows them
+pub fn format_ports(ports: &[PortInfo]) -> String {
+    if ports.is_empty() {
+        return "No physical JACK ports".to_string();
+    }
+    let mut out = format!(
+        "{:<28}{:<10}{:<24}{}\n",
+        "Port", "Kind", "Type", "Aliases / connections"
+    );
+    for port in ports {
+        let mut extra = port.aliases.join(", ");
+        if !port.connections.is_empty() {
+            if !extra.is_empty() {
+                extra.push_str("; ");
+            }
+            extra.push_str(&format!("<-> {}", port.connections.join(", ")));
+        }
+        out.push_str(&format!(
+            "{:<28}{:<10}{:<24}{}\n",
+            port.name,
+            if port.capture { "capture" } else { "playback" },
+            port.port_type,
+            if extra.is_empty() { "-" } else { &extra }
+        ));
+    }
+    out
+}
+
+/// The physical ports of a running JACK server, asked as `client_name`
+pub fn query_physical_ports(client_name: &str) -> Result<Vec<PortInfo>, String> {
+    let (client, _) =
+        jack::Client::new(client_name, jack::ClientOptions::NO_START_SERVER)
+            .map_err(|e| format!("Cannot connect to JACK: {}", e))?;
+    Ok(physical_ports(&client))
+}
+
+/// query_physical_ports, formatted
+pub fn list_ports(client_name: &str) -> Result<String, String> {
+    query_physical_ports(client_name).map(|ports| format_ports(&ports))
 }
 
 pub fn list_system_input_ports(client: &jack::Client) -> Vec<String> {
@@ -1 +1,2 @@
+pub mod cpal;
 pub mod jack;
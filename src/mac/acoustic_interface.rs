@@ -1,20 +1,43 @@
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, trace, warn};
 
 use crate::audio::recorder::{AppShared, AppState};
-use crate::mac::{self, CSMAState};
+use crate::mac::delay;
+use crate::mac::discovery::{Discovery, Neighbor};
+use crate::mac::noise::NoiseFloor;
+use crate::mac::params::MacParams;
+use crate::mac::queue::{Priority, TxQueue};
+use crate::mac::{self, CSMAState, stats::LinkStats, timing::CsmaTiming};
+use crate::net::PayloadKind;
 use crate::net::fragmentation::{IpFragmenter, IpReassembler};
-use crate::phy::{Frame, FrameType, LineCodingKind, PhyDecoder, PhyEncoder};
+use crate::net::pcap_utils::PcapWriter;
+use crate::phy::backend::{BasebandBackend, ModulationBackend};
+use crate::phy::frame::FrameTimestamp;
+use crate::phy::params::PhyParams;
+use crate::phy::{FecKind, Frame, FrameType, LineCodingKind};
+use crate::ui::dashboard::{DashboardEvent, DashboardFeed, LinkStatus};
 use crate::utils::consts::*;
+use crate::utils::metrics;
 
 pub struct AcousticInterface {
     shared: AppShared,
-    encoder: PhyEncoder,
-    decoder: PhyDecoder,
+    backend: Box<dyn ModulationBackend>,
     local_mac: u8,
-    sample_rate: u32,
+    timing: CsmaTiming,
+    mac_params: MacParams,
+    /// Silence between frames sent back to back
+    inter_frame_gap: usize,
     fragmenter: IpFragmenter,
     reassembler: IpReassembler,
+    stats: LinkStats,
+    discovery: Discovery,
+    noise: NoiseFloor,
+    queue: TxQueue,
+    capture: Option<PcapWriter>,
+    /// Stamp our data frames with when they were queued and sent
+    timestamps: bool,
+    /// Link status and CSMA states for --tui, None = no dashboard
+    dashboard: Option<DashboardFeed>,
 }
 
 impl AcousticInterface {
@@ -23,107 +46,295 @@ impl AcousticInterface {
         sample_rate: u32,
         line_coding: LineCodingKind,
         local_mac: u8,
+        phy_params: &PhyParams,
+        mac_params: &MacParams,
     ) -> Self {
-        let encoder = PhyEncoder::new(
-            SAMPLES_PER_LEVEL,
-            PREAMBLE_PATTERN_BYTES,
-            line_coding,
-        );
-        let decoder = PhyDecoder::new(
-            SAMPLES_PER_LEVEL,
-            PREAMBLE_PATTERN_BYTES,
-            line_coding,
+        Self::with_backend(
+            shared,
+            sample_rate,
+            Box::new(BasebandBackend::new(
+                phy_params,
+                line_coding,
+                FecKind::None,
+                local_mac,
+            )),
             local_mac,
+            phy_params,
+            mac_params,
+        )
+    }
+
+    /// Create an interface on top of any modulation backend
+    pub fn with_backend(
+        shared: AppShared,
+        sample_rate: u32,
+        backend: Box<dyn ModulationBackend>,
+        local_mac: u8,
+        phy_params: &PhyParams,
+        mac_params: &MacParams,
+    ) -> Self {
+        let timing =
+            mac_params.timing(sample_rate, backend.samples_per_symbol());
+        debug!(
+            "Acoustic interface using {} backend ({} byte payload limit)",
+            backend.name(),
+            backend.effective_payload_limit()
         );
 
         Self {
             shared,
-            encoder,
-            decoder,
+            backend,
             local_mac,
-            sample_rate,
+            timing,
+            mac_params: *mac_params,
+            inter_frame_gap: phy_params.inter_frame_gap_samples,
             fragmenter: IpFragmenter::new(DEFAULT_MTU),
             reassembler: IpReassembler::new(),
+            stats: LinkStats::default(),
+            discovery: Discovery::new(local_mac, None),
+            noise: NoiseFloor::new(phy_params.energy_threshold),
+            queue: TxQueue::new(),
+            capture: None,
+            timestamps: delay::timestamps_selected(),
+            dashboard: None,
+        }
+    }
+
+    pub fn local_mac(&self) -> u8 {
+        self.local_mac
+    }
+
+    /// Snapshot of the link counters since creation or the last reset
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            crc_failures: self
+                .backend
+                .decode_stats()
+                .map_or(0, |d| d.frames_crc_failed),
+            rx_overflows: self.shared.record_overflows(),
+            tx_underflows: self
+                .shared
+                .playback_underflows(),
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = LinkStats::default();
+        self.shared.reset_xruns();
+        self.backend
+            .reset_decode_stats();
+    }
+
+    /// Record every IPv4 packet sent or received, whole (before
+    /// fragmentation, after reassembly)
+    pub fn set_capture(&mut self, capture: PcapWriter) {
+        self.capture = Some(capture);
+    }
+
+    /// Timestamp our data frames, for the receiver to measure queueing and
+    /// one-way delays (see mac::delay)
+    pub fn set_timestamps(&mut self, enabled: bool) {
+        self.timestamps = enabled;
+    }
+
+    /// Publish the link status and CSMA state changes to `events`
+    pub fn set_dashboard(
+        &mut self,
+        events: crossbeam_channel::Sender<DashboardEvent>,
+    ) {
+        self.dashboard = Some(DashboardFeed::new(events));
+    }
+
+    /// Send the dashboard a status if one is due
+    fn publish_status(&mut self) {
+        if !self
+            .dashboard
+            .as_ref()
+            .is_some_and(DashboardFeed::status_due)
+        {
+            return;
+        }
+        let status = LinkStatus::snapshot(
+            &self.shared,
+            self.backend.decode_stats(),
+            self.stats(),
+        );
+        if let Some(feed) = &mut self.dashboard {
+            feed.status(status);
+        }
+    }
+
+    fn publish_csma(&mut self, state: &CSMAState) {
+        if let Some(feed) = &mut self.dashboard {
+            feed.csma(state.name());
         }
     }
 
-    // Send a packet for the given destination MAC address
+    fn capture(&mut self, packet: &[u8]) {
+        if let Some(capture) = &mut self.capture
+            && let Err(e) = capture.write_packet(SystemTime::now(), packet)
+        {
+            warn!("Capture stopped: {}", e);
+            self.capture = None;
+        }
+    }
+
+    /// Beacon every `interval`, announcing `ip`
+    pub fn set_discovery(
+        &mut self,
+        interval: Duration,
+        ip: Option<std::net::Ipv4Addr>,
+    ) {
+        self.discovery
+            .set_interval(interval);
+        self.discovery.set_ip(ip);
+    }
+
+    /// Nodes heard recently, from their beacons and traffic
+    pub fn neighbors(&self) -> Vec<Neighbor> {
+        self.discovery
+            .neighbors(Instant::now())
+    }
+
+    /// Broadcast a beacon if one is due. Beacons are held back until no
+    /// packet has been sent or received for a full interval.
+    pub fn send_beacon_if_due(&mut self) -> Result<bool, String> {
+        match self
+            .discovery
+            .poll_beacon(Instant::now())
+        {
+            Some(beacon) => {
+                debug!("Sending beacon {}", beacon.sequence);
+                self.queue
+                    .enqueue(beacon, Priority::Control);
+                self.flush_queue()?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Send a packet for the given destination MAC address, along with
+    // anything queued before it (control frames first)
     pub fn send_packet(
         &mut self,
         data: &[u8],
         dest_mac: u8,
         frame_type: FrameType,
     ) -> Result<(), String> {
+        self.enqueue_packet(data, dest_mac, frame_type)?;
+        self.flush_queue()
+    }
+
+    // Fragment a packet into the transmit queue, ACKs at control priority
+    pub fn enqueue_packet(
+        &mut self,
+        data: &[u8],
+        dest_mac: u8,
+        frame_type: FrameType,
+    ) -> Result<(), String> {
+        if matches!(PayloadKind::of(data), PayloadKind::Ipv4 | PayloadKind::Ipv6)
+        {
+            self.capture(data);
+        }
         // Fragment the packet if it's too large
         let packets_to_send = self
             .fragmenter
             .fragment_packet(data)?;
+        self.discovery
+            .note_traffic(Instant::now());
 
-        // Send each fragment
+        let priority = Priority::for_frame(frame_type);
+        trace!(
+            "Queueing {} fragment(s) at {} priority",
+            packets_to_send.len(),
+            priority.name()
+        );
         for packet_data in packets_to_send {
-            self.send_single_packet(&packet_data, dest_mac, frame_type)?;
+            let frame = if let FrameType::Ack = frame_type {
+                Frame::new_ack_mix(0, self.local_mac, dest_mac, packet_data)
+            } else {
+                let mut frame =
+                    Frame::new_data(0, self.local_mac, dest_mac, packet_data);
+                if self.timestamps {
+                    let now = delay::now_ms();
+                    frame.timestamp = Some(FrameTimestamp {
+                        queued_ms: now,
+                        sent_ms: now,
+                    });
+                }
+                frame
+            };
+            self.queue
+                .enqueue(frame, priority);
         }
-
         Ok(())
     }
 
-    // Send a single packet
-    fn send_single_packet(
-        &mut self,
-        data: &[u8],
-        dest_mac: u8,
-        frame_type: FrameType,
-    ) -> Result<(), String> {
-        // Create frame
-        let frame = if let FrameType::Ack = frame_type {
-            Frame::new_ack_mix(0, self.local_mac, dest_mac, data.to_vec())
-        } else {
-            Frame::new_data(0, self.local_mac, dest_mac, data.to_vec())
-        };
-        let frames = vec![frame.clone()];
+    // Send everything queued: all control frames in one burst at each
+    // channel access, data one frame at a time in between
+    pub fn flush_queue(&mut self) -> Result<(), String> {
+        loop {
+            let control = self.queue.drain_control();
+            if !control.is_empty() {
+                self.transmit_frames(control)?;
+                continue;
+            }
+            match self.queue.pop() {
+                Some((frame, _)) => self.transmit_frames(vec![frame])?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Energy detection against the tracked noise floor, see mac::noise
+    fn is_channel_busy(&mut self, samples: &[f32]) -> Option<bool> {
+        self.noise
+            .observe(samples, Instant::now());
+        let busy = self
+            .noise
+            .is_channel_busy(samples);
+        if let Some(busy) = busy {
+            metrics::global().record_channel_sense(busy);
+        }
+        self.publish_status();
+        busy
+    }
 
+    // Contend for the channel and play `frames` back-to-back
+    fn transmit_frames(&mut self, mut frames: Vec<Frame>) -> Result<(), String> {
         let mut state = CSMAState::Sensing;
         let mut stage = 0;
 
         // Start recording for sensing
-        *self
-            .shared
+        self.shared
             .app_state
-            .lock()
-            .unwrap() = AppState::Recording;
+            .set(AppState::Recording);
 
         'csma_loop: loop {
+            self.publish_csma(&state);
             match state {
                 CSMAState::Sensing => {
                     trace!("Sensing channel...");
-                    std::thread::sleep(Duration::from_millis(
-                        ENERGY_DETECTION_SAMPLES as u64 * 1000
-                            / self.sample_rate as u64,
-                    ));
+                    std::thread::sleep(self.timing.sense);
 
-                    let recorded_samples = {
-                        self.shared
-                            .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    };
+                    let recorded_samples = self
+                        .shared
+                        .record_buffer
+                        .peek_all();
 
-                    match mac::is_channel_busy(&recorded_samples) {
+                    match self.is_channel_busy(&recorded_samples) {
                         Some(true) => {
                             trace!("Channel busy.");
                             self.shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
                                 .clear();
                         }
                         Some(false) => {
                             state = CSMAState::WaitingForDIFS;
                             self.shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
                                 .clear();
                         }
                         None => continue,
@@ -131,33 +342,28 @@ impl AcousticInterface {
                 }
                 CSMAState::WaitingForDIFS => {
                     trace!("Waiting for DIFS...");
-                    std::thread::sleep(Duration::from_millis(DIFS_DURATION_MS));
+                    std::thread::sleep(self.timing.difs);
 
-                    match mac::is_channel_busy(&{
-                        self.shared
+                    match self.is_channel_busy(
+                        &self
+                            .shared
                             .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    }) {
+                            .peek_all(),
+                    ) {
                         Some(false) => {
-                            let cw = (CW_MIN as u16 * 2_u16 * (stage))
-                                .min(CW_MAX as u16)
-                                as usize;
+                            let cw = self
+                                .mac_params
+                                .contention_window(stage);
                             state =
                                 CSMAState::Backoff(rand::random_range(0..=cw));
                             self.shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
                                 .clear();
                         }
                         Some(true) => {
                             state = CSMAState::Sensing;
                             self.shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
                                 .clear();
                         }
                         None => {}
@@ -165,22 +371,19 @@ impl AcousticInterface {
                 }
                 CSMAState::Backoff(mut counter) => {
                     if counter > 0 {
-                        std::thread::sleep(Duration::from_millis(SLOT_TIME_MS));
-                        match mac::is_channel_busy(&{
-                            self.shared
+                        std::thread::sleep(self.timing.slot);
+                        match self.is_channel_busy(
+                            &self
+                                .shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        }) {
+                                .peek_all(),
+                        ) {
                             Some(true) => {
                                 state = CSMAState::BackoffPaused(counter);
                             }
                             Some(false) => {
                                 self.shared
                                     .record_buffer
-                                    .lock()
-                                    .unwrap()
                                     .clear();
                                 counter -= 1;
                                 state = CSMAState::Backoff(counter);
@@ -192,27 +395,22 @@ impl AcousticInterface {
                     }
                 }
                 CSMAState::BackoffPaused(counter) => {
-                    std::thread::sleep(Duration::from_millis(DIFS_DURATION_MS));
-                    match mac::is_channel_busy(&{
-                        self.shared
+                    std::thread::sleep(self.timing.difs);
+                    match self.is_channel_busy(
+                        &self
+                            .shared
                             .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    }) {
+                            .peek_all(),
+                    ) {
                         Some(true) => {
                             self.shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
                                 .clear();
                             state = CSMAState::BackoffPaused(counter);
                         }
                         Some(false) => {
                             self.shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
                                 .clear();
                             state = CSMAState::Backoff(counter);
                         }
@@ -220,108 +418,98 @@ impl AcousticInterface {
                     }
                 }
                 CSMAState::Transmitting => {
-                    debug!("Transmitting frame...");
-                    let output_track = self
-                        .encoder
-                        .encode_frames(&frames, INTER_FRAME_GAP_SAMPLES);
-
+                    debug!("Transmitting {} frame(s)...", frames.len());
+                    self.stats.frames_sent += frames.len() as u64;
+                    metrics::global()
+                        .frames_tx
+                        .add(frames.len() as u64);
+                    let sent_ms = delay::now_ms();
+                    for stamp in frames
+                        .iter_mut()
+                        .filter_map(|frame| frame.timestamp.as_mut())
                     {
-                        let mut playback = self
-                            .shared
-                            .playback_buffer
-                            .lock()
-                            .unwrap();
-                        playback.clear();
-                        playback.extend(output_track);
-                        self.shared
-                            .record_buffer
-                            .lock()
-                            .unwrap()
-                            .clear();
+                        stamp.sent_ms = sent_ms;
                     }
-
-                    *self
-                        .shared
-                        .app_state
-                        .lock()
-                        .unwrap() = AppState::Playing;
-
-                    while let AppState::Playing = {
-                        self.shared
-                            .app_state
-                            .lock()
-                            .unwrap()
-                            .clone()
-                    } {
-                        std::thread::sleep(Duration::from_millis(1));
+                    let mut samples = Vec::new();
+                    for chunk in self
+                        .backend
+                        .encode_frames_iter(&frames, self.inter_frame_gap)
+                    {
+                        self.stats.tx_airtime_samples += chunk.len() as u64;
+                        samples.extend(chunk);
                     }
+                    self.shared
+                        .record_buffer
+                        .clear();
+                    self.shared.play(&samples);
 
-                    *self
-                        .shared
+                    self.shared
                         .app_state
-                        .lock()
-                        .unwrap() = AppState::Recording;
+                        .set(AppState::Recording);
+                    self.publish_csma(&CSMAState::Idle);
                     // state = CSMAState::WaitingForAck
                     return Ok(());
                 }
                 CSMAState::WaitingForAck => {
                     let start = Instant::now();
-                    let timeout = Duration::from_millis(ACK_TIMEOUT_MS);
+                    let timeout = self.mac_params.ack_timeout();
 
                     loop {
                         if start.elapsed() > timeout {
                             warn!("ACK timeout, retrying...");
+                            self.stats.ack_timeouts += 1;
+                            self.stats.retransmissions += 1;
                             stage = (stage + 1).min(10);
-                            let cw = (CW_MIN as u16 * 2_u16 * (stage))
-                                .min(CW_MAX as u16)
-                                as usize;
+                            let cw = self
+                                .mac_params
+                                .contention_window(stage);
                             state =
                                 CSMAState::Backoff(rand::random_range(0..=cw));
                             break;
                         }
 
                         std::thread::sleep(Duration::from_millis(10));
-                        let samples = {
-                            let mut buf = self
-                                .shared
-                                .record_buffer
-                                .lock()
-                                .unwrap();
-                            let s = buf.clone();
-                            buf.clear();
-                            s
-                        };
+                        let samples = self
+                            .shared
+                            .record_buffer
+                            .pop_all();
 
                         if !samples.is_empty() {
                             let decoded = self
-                                .decoder
-                                .process_samples(&samples);
+                                .backend
+                                .feed_samples(&samples);
 
                             for f in decoded {
                                 if f.frame_type == FrameType::Ack
                                     && f.sequence == 0
                                 {
                                     debug!("ACK received!");
+                                    self.stats.acks_received += 1;
+                                    self.stats
+                                        .record_rtt(start.elapsed());
+                                    metrics::global()
+                                        .record_rtt(start.elapsed());
                                     return Ok(());
                                 }
                             }
                         }
                     }
                 }
-                CSMAState::Idle => unreachable!(),
+                CSMAState::Idle
+                | CSMAState::SendingRts
+                | CSMAState::WaitingForCts => unreachable!(),
             }
         }
     }
 
-    pub fn receive_packet(
+    /// Next packet for us, along with the MAC address of the sender
+    pub fn receive_packet_from(
         &mut self,
         timeout: Option<Duration>,
-    ) -> Result<Vec<u8>, String> {
-        *self
-            .shared
+    ) -> Result<(Vec<u8>, u8), String> {
+        self.shared
             .app_state
-            .lock()
-            .unwrap() = AppState::Recording;
+            .set(AppState::Recording);
         let start = Instant::now();
 
         loop {
@@ -336,40 +524,70 @@ impl AcousticInterface {
             // Check for user interrupt or logic to stop?
             // For now just loop
 
-            let samples = {
-                let mut buf = self
-                    .shared
-                    .record_buffer
-                    .lock()
-                    .unwrap();
-                let s = buf.clone();
-                buf.clear();
-                s
-            };
+            let samples = self
+                .shared
+                .record_buffer
+                .pop_all();
 
             if !samples.is_empty() {
                 let decoded = self
-                    .decoder
-                    .process_samples(&samples);
+                    .backend
+                    .feed_samples(&samples);
+                self.publish_status();
 
                 for f in decoded {
+                    if self
+                        .discovery
+                        .observe(&f, Instant::now())
+                    {
+                        debug!("Beacon from {}", f.src);
+                        continue;
+                    }
+                    // Overheard for its NAV only
+                    if f.dst != self.local_mac && f.dst != mac::types::BROADCAST
+                    {
+                        continue;
+                    }
                     if f.frame_type == FrameType::Data
                         || f.frame_type == FrameType::Ack && !f.data.is_empty()
                     {
+                        self.stats.frames_received += 1;
+                        if let Some(stamp) = f.timestamp {
+                            self.stats
+                                .delays
+                                .record(stamp, delay::now_ms());
+                        }
+                        self.discovery
+                            .note_traffic(Instant::now());
+                        // Only IPv4 is ever fragmented, ARP and the like
+                        // go up as they are
+                        match PayloadKind::of(&f.data) {
+                            PayloadKind::Ipv4 => {}
+                            PayloadKind::Ipv6 => {
+                                self.capture(&f.data);
+                                return Ok((f.data, f.src));
+                            }
+                            _ => return Ok((f.data, f.src)),
+                        }
                         // Try to reassemble fragments
                         match self
                             .reassembler
-                            .process_fragment(&f.data)?
+                            .process_fragment(&f.data)
                         {
-                            Some(reassembled_packet) => {
-                                return Ok(reassembled_packet);
+                            Ok(Some(reassembled_packet)) => {
+                                self.capture(&reassembled_packet);
+                                return Ok((reassembled_packet, f.src));
                             }
-                            None => {
+                            Ok(None) => {
                                 // Fragment received but packet not complete yet
                                 debug!(
                                     "Fragment received, waiting for more fragments..."
                                 );
                             }
+                            Err(e) => {
+                                // One bad fragment shouldn't end the wait
+                                warn!("Dropping fragment from {}: {}", f.src, e);
+                            }
                         }
                     }
                 }
@@ -377,3 +595,141 @@ impl AcousticInterface {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::audio::recorder::build_process_closure;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    pub(crate) const SAMPLE_RATE: u32 = 48000;
+
+    /// An interface at SAMPLE_RATE with the default PHY and MAC parameters
+    pub(crate) fn test_interface(
+        shared: AppShared,
+        kind: LineCodingKind,
+        local_mac: u8,
+    ) -> AcousticInterface {
+        AcousticInterface::new(
+            shared,
+            SAMPLE_RATE,
+            kind,
+            local_mac,
+            &PhyParams::default(),
+            &MacParams::default(),
+        )
+    }
+
+    /// Stand-in for the sound cards and the air between nodes: every
+    /// millisecond each node's process callback runs on what the others
+    /// played the millisecond before
+    pub(crate) fn spawn_mock_channel(
+        nodes: Vec<AppShared>,
+        running: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let period = SAMPLE_RATE as usize / 1000;
+            let mut processes: Vec<_> = nodes
+                .iter()
+                .map(|node| build_process_closure(node.clone()))
+                .collect();
+            let mut emitted = vec![vec![0.0; period]; nodes.len()];
+            while running.load(Ordering::SeqCst) {
+                let heard: Vec<Vec<f32>> = (0..nodes.len())
+                    .map(|i| {
+                        (0..period)
+                            .map(|k| {
+                                emitted
+                                    .iter()
+                                    .enumerate()
+                                    .filter(|(j, _)| *j != i)
+                                    .map(|(_, chunk)| chunk[k])
+                                    .sum::<f32>()
+                            })
+                            .collect()
+                    })
+                    .collect();
+                for ((process, input), output) in processes
+                    .iter_mut()
+                    .zip(&heard)
+                    .zip(emitted.iter_mut())
+                {
+                    process(input, output);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        })
+    }
+
+    /// Minimal IPv4 packet that fits in one fragment
+    fn ipv4_packet(id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[2..4]
+            .copy_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        packet[4..6].copy_from_slice(&id.to_be_bytes());
+        packet[8] = 64;
+        packet[12..16].copy_from_slice(&[192, 168, 1, 1]);
+        packet[16..20].copy_from_slice(&[192, 168, 1, 2]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_stats_count_transfers() {
+        let a = AppShared::new(SAMPLE_RATE as usize);
+        let b = AppShared::new(SAMPLE_RATE as usize);
+        let running = Arc::new(AtomicBool::new(true));
+        let channel =
+            spawn_mock_channel(vec![a.clone(), b.clone()], running.clone());
+
+        let kind = LineCodingKind::FourBFiveB;
+        let mut sender = test_interface(a, kind, 1);
+        let mut receiver = test_interface(b, kind, 2);
+        // Listen before anything is sent
+        receiver
+            .shared
+            .app_state
+            .set(AppState::Recording);
+
+        let packets: Vec<Vec<u8>> = (0..3)
+            .map(|i| ipv4_packet(i, &[i as u8; 20]))
+            .collect();
+        let expected = packets.clone();
+        let rx = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            for _ in 0..expected.len() {
+                match receiver.receive_packet_from(Some(Duration::from_secs(10)))
+                {
+                    Ok((packet, _)) => received.push(packet),
+                    Err(_) => break,
+                }
+            }
+            (receiver, received)
+        });
+
+        for packet in &packets {
+            sender
+                .send_packet(packet, 2, FrameType::Data)
+                .unwrap();
+        }
+        let (mut receiver, received) = rx.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+
+        assert_eq!(received, packets);
+        let sent = sender.stats();
+        assert_eq!(sent.frames_sent, 3);
+        assert_eq!(sent.retransmissions, 0);
+        assert_eq!(sent.ack_timeouts, 0);
+        assert!(sent.tx_airtime_samples > 0);
+        let got = receiver.stats();
+        assert_eq!(got.frames_received, 3);
+        assert_eq!(got.crc_failures, 0);
+        assert_eq!(got.frames_sent, 0);
+
+        receiver.reset_stats();
+        assert_eq!(receiver.stats(), LinkStats::default());
+    }
+}
@@ -0,0 +1,361 @@
+// Frame aggregation: several sub-packets share one PHY frame and one ACK.
+//
+// Aggregate payload: repeated [Id:1] [Len:1] [CRC8:1] [Data:Len]
+//   - CRC8 covers Id, Len and Data of that sub-packet only
+// ACK payload (bitmap TLV): [Tag:1] [Len:1] [Bitmap:Len]
+//   - bit i (LSB first) set = i-th sub-packet of the aggregate was received
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::phy::crc::calculate_crc8;
+use tracing::debug;
+
+/// Per sub-packet overhead: Id + Len + CRC8
+pub const SUBPACKET_HEADER_BYTES: usize = 3;
+
+/// TLV tag for the sub-packet bitmap carried in an aggregate ACK
+pub const ACK_BITMAP_TAG: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubPacket {
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+impl SubPacket {
+    fn encoded_len(&self) -> usize {
+        SUBPACKET_HEADER_BYTES + self.data.len()
+    }
+
+    fn checksum(id: u8, data: &[u8]) -> u8 {
+        let mut bytes = Vec::with_capacity(data.len() + 2);
+        bytes.push(id);
+        bytes.push(data.len() as u8);
+        bytes.extend_from_slice(data);
+        calculate_crc8(&bytes)
+    }
+}
+
+/// Serialize sub-packets into an aggregate frame payload
+pub fn encode_aggregate(pieces: &[SubPacket]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(
+        pieces
+            .iter()
+            .map(SubPacket::encoded_len)
+            .sum(),
+    );
+    for piece in pieces {
+        assert!(piece.data.len() <= u8::MAX as usize);
+        bytes.push(piece.id);
+        bytes.push(piece.data.len() as u8);
+        bytes.push(SubPacket::checksum(piece.id, &piece.data));
+        bytes.extend_from_slice(&piece.data);
+    }
+    bytes
+}
+
+/// Parse an aggregate payload.
+/// Returns the intact sub-packets and a per-position receive status; parsing
+/// stops at a truncated sub-packet, so trailing positions are simply absent.
+pub fn decode_aggregate(payload: &[u8]) -> (Vec<SubPacket>, Vec<bool>) {
+    let mut pieces = Vec::new();
+    let mut status = Vec::new();
+    let mut offset = 0;
+
+    while offset + SUBPACKET_HEADER_BYTES <= payload.len() {
+        let id = payload[offset];
+        let len = payload[offset + 1] as usize;
+        let crc = payload[offset + 2];
+        let start = offset + SUBPACKET_HEADER_BYTES;
+        if start + len > payload.len() {
+            debug!("Sub-packet {} truncated, stop parsing aggregate", id);
+            break;
+        }
+
+        let data = &payload[start..start + len];
+        let ok = SubPacket::checksum(id, data) == crc;
+        if ok {
+            pieces.push(SubPacket {
+                id,
+                data: data.to_vec(),
+            });
+        } else {
+            debug!("Sub-packet {} at position {} corrupted", id, status.len());
+        }
+        status.push(ok);
+        offset = start + len;
+    }
+
+    (pieces, status)
+}
+
+/// Build the bitmap TLV carried by the ACK of an aggregate
+pub fn encode_ack_bitmap(status: &[bool]) -> Vec<u8> {
+    let mut bitmap = vec![0u8; status.len().div_ceil(8)];
+    for (i, _) in status
+        .iter()
+        .enumerate()
+        .filter(|(_, ok)| **ok)
+    {
+        bitmap[i / 8] |= 1 << (i % 8);
+    }
+
+    let mut tlv = vec![ACK_BITMAP_TAG, bitmap.len() as u8];
+    tlv.extend(bitmap);
+    tlv
+}
+
+/// Parse the bitmap TLV of an aggregate ACK, expanded to `count` positions
+pub fn decode_ack_bitmap(tlv: &[u8], count: usize) -> Option<Vec<bool>> {
+    if tlv.len() < 2 || tlv[0] != ACK_BITMAP_TAG {
+        return None;
+    }
+    let bitmap = tlv.get(2..2 + tlv[1] as usize)?;
+
+    Some(
+        (0..count)
+            .map(|i| {
+                bitmap
+                    .get(i / 8)
+                    .is_some_and(|b| b & (1 << (i % 8)) != 0)
+            })
+            .collect(),
+    )
+}
+
+/// Sender side: packs queued data into aggregates and keeps the sub-packets
+/// that still need to be retransmitted.
+pub struct Aggregator {
+    max_bytes: usize,
+    next_id: u8,
+    retransmit: VecDeque<SubPacket>,
+    backlog: VecDeque<Vec<u8>>,
+}
+
+impl Aggregator {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            next_id: 0,
+            retransmit: VecDeque::new(),
+            backlog: VecDeque::new(),
+        }
+    }
+
+    /// Queue new data; each call becomes one sub-packet
+    pub fn push(&mut self, data: Vec<u8>) {
+        self.backlog.push_back(data);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.retransmit.is_empty() && self.backlog.is_empty()
+    }
+
+    /// Whether the next aggregate still has room for more queued data
+    pub fn wants_more(&self) -> bool {
+        let pending: usize = self
+            .retransmit
+            .iter()
+            .map(SubPacket::encoded_len)
+            .chain(
+                self.backlog
+                    .iter()
+                    .map(|d| SUBPACKET_HEADER_BYTES + d.len()),
+            )
+            .sum();
+        pending < self.max_bytes
+    }
+
+    /// Take the next aggregate: failed sub-packets first, then new data.
+    /// Always returns at least one sub-packet if anything is pending.
+    pub fn next_aggregate(&mut self) -> Vec<SubPacket> {
+        let mut pieces = Vec::new();
+        let mut size = 0;
+
+        while let Some(piece) = self.retransmit.front() {
+            if !pieces.is_empty() && size + piece.encoded_len() > self.max_bytes
+            {
+                return pieces;
+            }
+            size += piece.encoded_len();
+            pieces.push(
+                self.retransmit
+                    .pop_front()
+                    .unwrap(),
+            );
+        }
+
+        while let Some(data) = self.backlog.front() {
+            let len = SUBPACKET_HEADER_BYTES + data.len();
+            if !pieces.is_empty() && size + len > self.max_bytes {
+                break;
+            }
+            size += len;
+            pieces.push(SubPacket {
+                id: self.next_id,
+                data: self
+                    .backlog
+                    .pop_front()
+                    .unwrap(),
+            });
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+
+        pieces
+    }
+
+    /// Apply the ACK bitmap of an aggregate.
+    /// Failed sub-packets are scheduled ahead of new data, in their original
+    /// order. Returns the number of sub-packets delivered.
+    pub fn on_ack(&mut self, sent: &[SubPacket], status: &[bool]) -> usize {
+        let mut delivered = 0;
+        for (i, piece) in sent.iter().enumerate().rev() {
+            if status
+                .get(i)
+                .copied()
+                .unwrap_or(false)
+            {
+                delivered += 1;
+            } else {
+                self.retransmit
+                    .push_front(piece.clone());
+            }
+        }
+        delivered
+    }
+}
+
+/// Receiver side: delivers sub-packets in id order and drops duplicates
+pub struct Deaggregator {
+    next_id: u8,
+    pending: HashMap<u8, Vec<u8>>,
+}
+
+impl Deaggregator {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Accept intact sub-packets, returns data now deliverable in order
+    pub fn accept(&mut self, pieces: Vec<SubPacket>) -> Vec<Vec<u8>> {
+        for piece in pieces {
+            // Ids behind the delivery point are retransmitted duplicates
+            if piece
+                .id
+                .wrapping_sub(self.next_id)
+                >= 128
+            {
+                debug!("Duplicate sub-packet {}", piece.id);
+                continue;
+            }
+            self.pending
+                .entry(piece.id)
+                .or_insert(piece.data);
+        }
+
+        let mut ready = Vec::new();
+        while let Some(data) = self
+            .pending
+            .remove(&self.next_id)
+        {
+            ready.push(data);
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        ready
+    }
+}
+
+impl Default for Deaggregator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_retransmission() {
+        let mut sender = Aggregator::new(256);
+        let mut receiver = Deaggregator::new();
+        for i in 0..5u8 {
+            sender.push(vec![i; 40]);
+        }
+
+        let first = sender.next_aggregate();
+        assert_eq!(first.len(), 5);
+        let mut payload = encode_aggregate(&first);
+
+        // Corrupt the data of sub-packets 1 and 3
+        let stride = SUBPACKET_HEADER_BYTES + 40;
+        payload[stride + SUBPACKET_HEADER_BYTES] ^= 0xFF;
+        payload[3 * stride + SUBPACKET_HEADER_BYTES + 5] ^= 0x01;
+
+        let (pieces, status) = decode_aggregate(&payload);
+        assert_eq!(status, vec![true, false, true, false, true]);
+        assert_eq!(receiver.accept(pieces), vec![vec![0u8; 40]]);
+
+        let ack = encode_ack_bitmap(&status);
+        let acked = decode_ack_bitmap(&ack, first.len()).unwrap();
+        assert_eq!(sender.on_ack(&first, &acked), 3);
+
+        // Exactly one retransmitted aggregate holding the two failed pieces
+        let retry = sender.next_aggregate();
+        assert_eq!(
+            retry
+                .iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+
+        let (pieces, status) = decode_aggregate(&encode_aggregate(&retry));
+        assert_eq!(status, vec![true, true]);
+        let delivered = receiver.accept(pieces);
+        assert_eq!(
+            delivered,
+            vec![vec![1u8; 40], vec![2u8; 40], vec![3u8; 40], vec![4u8; 40]]
+        );
+
+        assert_eq!(sender.on_ack(&retry, &status), 2);
+        assert!(sender.is_empty());
+    }
+
+    #[test]
+    fn test_failed_pieces_lead_new_data() {
+        let mut sender = Aggregator::new(256);
+        sender.push(vec![0xAA; 10]);
+        sender.push(vec![0xBB; 10]);
+        let first = sender.next_aggregate();
+        sender.on_ack(&first, &[true, false]);
+        sender.push(vec![0xCC; 10]);
+
+        let next = sender.next_aggregate();
+        assert_eq!(next[0].id, 1);
+        assert_eq!(next[1].id, 2);
+    }
+
+    #[test]
+    fn test_duplicate_subpackets_dropped() {
+        let mut receiver = Deaggregator::new();
+        let piece = SubPacket {
+            id: 0,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(
+            receiver
+                .accept(vec![piece.clone()])
+                .len(),
+            1
+        );
+        assert!(
+            receiver
+                .accept(vec![piece])
+                .is_empty()
+        );
+    }
+}
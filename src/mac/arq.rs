@@ -0,0 +1,764 @@
+// Sliding-window ARQ
+//
+// Go-Back-N: the sender keeps up to `window` unacknowledged frames on the
+// air. ACKs are cumulative: ACK(n) confirms every frame up to and including
+// n. When the oldest unacknowledged frame times out, it and every frame sent
+// after it are retransmitted. The receiver only accepts the next frame in
+// order, so with a window of 1 this is plain stop-and-wait.
+//
+// Fast retransmit: every burst the receiver hears after a lost frame is
+// answered with the same cumulative ACK again. Each of the first duplicate
+// ACKs lets one new frame past the window so more duplicates can come back;
+// FAST_RETRANSMIT_DUP_ACKS of them resend the lost frame (Go-Back-N: with
+// everything after it) without waiting for its timer and halve the window.
+// The window then grows back by one frame per ACK that frees frames.
+//
+// Selective repeat: the receiver also buffers frames that arrive after a
+// gap and answers with a NACK, the sender retransmits only what is missing.
+// NACK frame: Seq = first missing sequence (everything before it is ACKed)
+// NACK payload: [Span:1] [Bitmap:ceil(Span/8)]
+//   - bit i (LSB first) set = Seq + i is missing, clear = received
+//   - sequences from Seq + Span on are not covered (not received yet)
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::phy::Frame;
+use crate::phy::frame::SeqType;
+use crate::utils::consts::{ARQ_MAX_WINDOW, FAST_RETRANSMIT_DUP_ACKS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArqMode {
+    #[default]
+    GoBackN,
+    SelectiveRepeat,
+}
+
+impl ArqMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArqMode::GoBackN => "Go-Back-N",
+            ArqMode::SelectiveRepeat => "selective repeat",
+        }
+    }
+}
+
+/// Serialize the missing flags of a NACK, `missing[0]` is the NACK's Seq
+pub fn encode_nack_bitmap(missing: &[bool]) -> Vec<u8> {
+    assert!(missing.len() <= u8::MAX as usize);
+    let mut payload = vec![0u8; 1 + missing.len().div_ceil(8)];
+    payload[0] = missing.len() as u8;
+    for (i, _) in missing
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| **m)
+    {
+        payload[1 + i / 8] |= 1 << (i % 8);
+    }
+    payload
+}
+
+/// Parse a NACK payload back into per-sequence missing flags
+pub fn decode_nack_bitmap(payload: &[u8]) -> Option<Vec<bool>> {
+    let span = *payload.first()? as usize;
+    let bitmap = payload.get(1..1 + span.div_ceil(8))?;
+    Some(
+        (0..span)
+            .map(|i| bitmap[i / 8] & (1 << (i % 8)) != 0)
+            .collect(),
+    )
+}
+
+/// Distance from `base` to `seq`, None if `seq` lies before `base`
+fn offset(base: SeqType, seq: SeqType) -> Option<usize> {
+    let d = seq.wrapping_sub(base);
+    (d <= SeqType::MAX / 2).then_some(d as usize)
+}
+
+struct InFlight {
+    frame: Frame,
+    /// None until the frame is (re)transmitted
+    sent_at: Option<Instant>,
+    acked: bool,
+    transmissions: u32,
+}
+
+/// Sender side of the sliding window, independent of the audio I/O
+pub struct ArqSender {
+    mode: ArqMode,
+    /// Configured window
+    max_window: usize,
+    /// Effective window, halved by fast retransmits
+    window: usize,
+    frames: VecDeque<InFlight>,
+    /// Sequence of the last ACK that freed frames
+    last_acked: Option<SeqType>,
+    duplicate_acks: usize,
+}
+
+impl ArqSender {
+    pub fn new(mode: ArqMode, window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            mode,
+            max_window: window,
+            window,
+            frames: VecDeque::with_capacity(window),
+            last_acked: None,
+            duplicate_acks: 0,
+        }
+    }
+
+    /// Frames from the oldest unacknowledged one that may be on the air:
+    /// the window, widened by one frame per duplicate ACK short of a fast
+    /// retransmit
+    fn limit(&self) -> usize {
+        let limited_transmit = self
+            .duplicate_acks
+            .min(FAST_RETRANSMIT_DUP_ACKS - 1);
+        self.window + limited_transmit
+    }
+
+    /// Whether another frame fits into the window
+    pub fn has_room(&self) -> bool {
+        self.frames.len() < self.limit()
+    }
+
+    /// Current effective window
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Duplicate ACKs since the last ACK that freed frames
+    pub fn duplicate_acks(&self) -> usize {
+        self.duplicate_acks
+    }
+
+    /// Nothing queued or waiting for an ACK
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Queue a new frame, sent with the next `unsent()` batch
+    pub fn push(&mut self, frame: Frame) {
+        debug_assert!(self.has_room());
+        self.frames
+            .push_back(InFlight {
+                frame,
+                sent_at: None,
+                acked: false,
+                transmissions: 0,
+            });
+    }
+
+    /// Frames waiting to go on the air, oldest first. After the window
+    /// shrank, frames beyond it wait for ACKs to make room.
+    pub fn unsent(&self) -> Vec<Frame> {
+        self.frames
+            .iter()
+            .take(self.limit())
+            .filter(|f| !f.acked && f.sent_at.is_none())
+            .map(|f| f.frame.clone())
+            .collect()
+    }
+
+    /// Start the timers of everything returned by `unsent()`.
+    /// Returns how many of those frames were retransmissions.
+    pub fn mark_sent(&mut self, now: Instant) -> usize {
+        let mut retransmissions = 0;
+        let limit = self.limit();
+        for f in self
+            .frames
+            .iter_mut()
+            .take(limit)
+            .filter(|f| !f.acked && f.sent_at.is_none())
+        {
+            if f.transmissions > 0 {
+                retransmissions += 1;
+            }
+            f.transmissions += 1;
+            f.sent_at = Some(now);
+        }
+        retransmissions
+    }
+
+    /// Apply a cumulative ACK, returns how many frames left the window.
+    /// ACKs for frames not in flight (stale or unknown) release nothing;
+    /// a repeat of the last ACK while frames are in flight is counted as a
+    /// duplicate.
+    pub fn on_ack(&mut self, seq: SeqType) -> usize {
+        let Some(pos) = self
+            .frames
+            .iter()
+            .position(|f| f.transmissions > 0 && f.frame.sequence == seq)
+        else {
+            if self.last_acked == Some(seq)
+                && self
+                    .frames
+                    .iter()
+                    .any(|f| f.sent_at.is_some())
+            {
+                self.duplicate_acks += 1;
+            }
+            return 0;
+        };
+        for f in self
+            .frames
+            .iter_mut()
+            .take(pos + 1)
+        {
+            f.acked = true;
+        }
+        self.last_acked = Some(seq);
+        self.duplicate_acks = 0;
+        self.window = (self.window + 1).min(self.max_window);
+        self.slide()
+    }
+
+    /// After FAST_RETRANSMIT_DUP_ACKS duplicate ACKs, queue the oldest
+    /// unacknowledged frame again (Go-Back-N: and every frame after it, the
+    /// receiver dropped them) and halve the window.
+    /// Returns the number of frames queued again.
+    pub fn fast_retransmit(&mut self) -> usize {
+        if self.duplicate_acks < FAST_RETRANSMIT_DUP_ACKS {
+            return 0;
+        }
+        self.duplicate_acks = 0;
+        self.window = (self.window / 2).max(1);
+        let take = match self.mode {
+            ArqMode::GoBackN => usize::MAX,
+            ArqMode::SelectiveRepeat => 1,
+        };
+        self.frames
+            .iter_mut()
+            .filter(|f| !f.acked)
+            .take(take)
+            .filter_map(|f| f.sent_at.take())
+            .count()
+    }
+
+    /// Apply a NACK for `first_missing` with the decoded bitmap: frames
+    /// before it are ACKed, with selective repeat the covered frames are
+    /// ACKed or queued again. Returns how many frames left the window.
+    pub fn on_nack(
+        &mut self,
+        first_missing: SeqType,
+        missing: &[bool],
+    ) -> usize {
+        let selective = self.mode == ArqMode::SelectiveRepeat;
+        self.duplicate_acks = 0;
+        for f in self.frames.iter_mut() {
+            match offset(first_missing, f.frame.sequence) {
+                None => f.acked = true,
+                Some(i) if selective && i < missing.len() => {
+                    if !missing[i] {
+                        f.acked = true;
+                    } else if f.sent_at.is_some() {
+                        f.sent_at = None;
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        self.slide()
+    }
+
+    /// When the oldest unacknowledged frame in flight times out
+    pub fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.frames
+            .iter()
+            .filter(|f| !f.acked)
+            .filter_map(|f| f.sent_at)
+            .min()
+            .map(|t| t + timeout)
+    }
+
+    pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        self.deadline(timeout)
+            .is_some_and(|d| now >= d)
+    }
+
+    /// Queue the frames to retransmit after a timeout: Go-Back-N rewinds to
+    /// the oldest unacknowledged frame and resends everything in flight,
+    /// selective repeat only the frames whose own timer expired.
+    /// Returns the number of frames queued again.
+    pub fn on_timeout(&mut self, now: Instant, timeout: Duration) -> usize {
+        if !self.timed_out(now, timeout) {
+            return 0;
+        }
+        self.duplicate_acks = 0;
+        let mode = self.mode;
+        self.frames
+            .iter_mut()
+            .filter(|f| !f.acked)
+            .filter(|f| {
+                mode == ArqMode::GoBackN
+                    || f.sent_at
+                        .is_some_and(|t| now >= t + timeout)
+            })
+            .filter_map(|f| f.sent_at.take())
+            .count()
+    }
+
+    fn slide(&mut self) -> usize {
+        let mut released = 0;
+        while self
+            .frames
+            .front()
+            .is_some_and(|f| f.acked)
+        {
+            self.frames.pop_front();
+            released += 1;
+        }
+        released
+    }
+}
+
+/// What the receiver did with an arriving data frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arrival {
+    /// In order: this data (and any buffered data it unblocked), in order
+    Delivered(Vec<Vec<u8>>),
+    /// After a gap, held until the gap is filled (selective repeat)
+    Buffered,
+    /// Already received
+    Duplicate,
+    /// After a gap and not kept (Go-Back-N, or beyond the window)
+    Dropped,
+}
+
+/// Receiver side of the sliding window
+#[derive(Default)]
+pub struct ArqReceiver {
+    mode: ArqMode,
+    expected: SeqType,
+    received_any: bool,
+    buffered: HashMap<SeqType, Vec<u8>>,
+}
+
+impl ArqReceiver {
+    pub fn new(mode: ArqMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Accept a data frame, returns what became deliverable
+    pub fn receive(&mut self, seq: SeqType, data: Vec<u8>) -> Arrival {
+        match offset(self.expected, seq) {
+            Some(0) => {
+                let mut ready = vec![data];
+                self.expected = self.expected.wrapping_add(1);
+                while let Some(data) = self
+                    .buffered
+                    .remove(&self.expected)
+                {
+                    ready.push(data);
+                    self.expected = self.expected.wrapping_add(1);
+                }
+                self.received_any = true;
+                Arrival::Delivered(ready)
+            }
+            None => Arrival::Duplicate,
+            Some(ahead)
+                if self.mode == ArqMode::SelectiveRepeat
+                    && ahead < ARQ_MAX_WINDOW =>
+            {
+                if self
+                    .buffered
+                    .contains_key(&seq)
+                {
+                    return Arrival::Duplicate;
+                }
+                self.buffered
+                    .insert(seq, data);
+                Arrival::Buffered
+            }
+            Some(_) => Arrival::Dropped,
+        }
+    }
+
+    /// Highest in-order sequence received, carried by the cumulative ACK
+    pub fn cumulative_ack(&self) -> Option<SeqType> {
+        self.received_any
+            .then(|| self.expected.wrapping_sub(1))
+    }
+
+    /// The ACK or NACK answering what was received so far
+    pub fn feedback(&self, local: u8, remote: u8) -> Option<Frame> {
+        let span = self
+            .buffered
+            .keys()
+            .filter_map(|&seq| offset(self.expected, seq))
+            .max()
+            .map(|ahead| ahead + 1);
+        let Some(span) = span else {
+            return self
+                .cumulative_ack()
+                .map(|seq| Frame::new_ack(seq, local, remote));
+        };
+
+        let missing: Vec<bool> = (0..span)
+            .map(|i| {
+                !self.buffered.contains_key(
+                    &self
+                        .expected
+                        .wrapping_add(i as SeqType),
+                )
+            })
+            .collect();
+        Some(Frame::new_nack(
+            self.expected,
+            local,
+            remote,
+            encode_nack_bitmap(&missing),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mac::params::MacParams;
+    use crate::phy::agc::Agc;
+    use crate::phy::params::PhyParams;
+    use crate::phy::{
+        FecKind, FrameType, LineCodingKind, PhyDecoder, PhyEncoder,
+    };
+    use crate::utils::consts::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(ACK_TIMEOUT_MS);
+
+    fn data(seq: SeqType) -> Frame {
+        Frame::new_data(seq, 0, 1, vec![seq as u8])
+    }
+
+    #[test]
+    fn test_cumulative_ack_releases_prefix() {
+        let mut sender = ArqSender::new(ArqMode::GoBackN, 4);
+        for seq in 0..4 {
+            sender.push(data(seq));
+        }
+        assert!(!sender.has_room());
+        assert_eq!(sender.unsent().len(), 4);
+        assert_eq!(sender.mark_sent(Instant::now()), 0);
+        assert!(sender.unsent().is_empty());
+
+        assert_eq!(sender.on_ack(2), 3);
+        // Stale ACK
+        assert_eq!(sender.on_ack(1), 0);
+        assert!(sender.has_room());
+        assert_eq!(sender.on_ack(3), 1);
+        assert!(sender.is_empty());
+    }
+
+    #[test]
+    fn test_timeout_goes_back_to_first_unacked() {
+        let t0 = Instant::now();
+        let mut sender = ArqSender::new(ArqMode::GoBackN, 4);
+        let mut receiver = ArqReceiver::new(ArqMode::GoBackN);
+        assert_eq!(receiver.cumulative_ack(), None);
+        for seq in 0..4 {
+            sender.push(data(seq));
+        }
+        sender.mark_sent(t0);
+
+        // Frame 1 is lost, 2 and 3 arrive out of order and are dropped
+        assert_eq!(
+            receiver.receive(0, vec![0]),
+            Arrival::Delivered(vec![vec![0]])
+        );
+        assert_eq!(receiver.receive(2, vec![2]), Arrival::Dropped);
+        assert_eq!(receiver.receive(3, vec![3]), Arrival::Dropped);
+        assert_eq!(receiver.receive(0, vec![0]), Arrival::Duplicate);
+        assert_eq!(receiver.cumulative_ack(), Some(0));
+        assert_eq!(sender.on_ack(0), 1);
+
+        assert_eq!(sender.on_timeout(t0 + TIMEOUT / 2, TIMEOUT), 0);
+        assert_eq!(sender.on_timeout(t0 + TIMEOUT, TIMEOUT), 3);
+        let resent: Vec<_> = sender
+            .unsent()
+            .iter()
+            .map(|f| f.sequence)
+            .collect();
+        assert_eq!(resent, vec![1, 2, 3]);
+        assert_eq!(sender.deadline(TIMEOUT), None);
+        assert_eq!(sender.mark_sent(t0 + TIMEOUT), 3);
+    }
+
+    #[test]
+    fn test_nack_bitmap_roundtrip() {
+        let missing =
+            [true, false, false, true, false, false, false, false, true];
+        let payload = encode_nack_bitmap(&missing);
+        assert_eq!(payload, vec![9, 0b0000_1001, 0b0000_0001]);
+        assert_eq!(decode_nack_bitmap(&payload).unwrap(), missing);
+        assert!(decode_nack_bitmap(&payload[..2]).is_none());
+    }
+
+    #[test]
+    fn test_selective_repeat_resends_only_missing() {
+        let t0 = Instant::now();
+        let mut sender = ArqSender::new(ArqMode::SelectiveRepeat, 6);
+        let mut receiver = ArqReceiver::new(ArqMode::SelectiveRepeat);
+        for seq in 0..6 {
+            sender.push(data(seq));
+        }
+        sender.mark_sent(t0);
+
+        // 1 and 3 are lost, 5 has not arrived yet
+        for seq in [0, 2, 4] {
+            receiver.receive(seq, vec![seq as u8]);
+        }
+        let nack = receiver
+            .feedback(1, 0)
+            .unwrap();
+        assert_eq!(nack.frame_type, FrameType::Nack);
+        assert_eq!(nack.sequence, 1);
+        let missing = decode_nack_bitmap(&nack.data).unwrap();
+        assert_eq!(missing, vec![true, false, true, false]);
+
+        assert_eq!(sender.on_nack(nack.sequence, &missing), 1);
+        let resent: Vec<_> = sender
+            .unsent()
+            .iter()
+            .map(|f| f.sequence)
+            .collect();
+        assert_eq!(resent, vec![1, 3]);
+        assert_eq!(sender.mark_sent(t0), 2);
+
+        assert_eq!(receiver.receive(3, vec![3]), Arrival::Buffered);
+        assert_eq!(
+            receiver.receive(1, vec![1]),
+            Arrival::Delivered(vec![vec![1], vec![2], vec![3], vec![4]])
+        );
+        let ack = receiver
+            .feedback(1, 0)
+            .unwrap();
+        assert_eq!(ack.frame_type, FrameType::Ack);
+        assert_eq!(sender.on_ack(ack.sequence), 4);
+
+        // Only frame 5's own timer is left
+        assert_eq!(sender.on_timeout(t0 + TIMEOUT, TIMEOUT), 1);
+        assert_eq!(sender.unsent()[0].sequence, 5);
+    }
+
+    #[test]
+    fn test_fast_retransmit_beats_timeout() {
+        let kind = LineCodingKind::FourBFiveB;
+        let params = PhyParams::default();
+        let encoder = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut rx_decoder = PhyDecoder::new(&params, kind, FecKind::None, 1);
+        let mut tx_decoder = PhyDecoder::new(&params, kind, FecKind::None, 0);
+        let air = |frame: &Frame| {
+            let mut samples = encoder.encode_frame(frame);
+            samples.extend(vec![0.0; 100]);
+            samples
+        };
+
+        let t0 = Instant::now();
+        let mut sender = ArqSender::new(ArqMode::GoBackN, 4);
+        let mut receiver = ArqReceiver::new(ArqMode::GoBackN);
+        let mut next = 0;
+        let mut dropped = false;
+        let mut resent_at = None;
+
+        // One burst every 10 ms, far below the ACK timeout
+        for round in 0..8 {
+            let now = t0 + Duration::from_millis(10 * round);
+            while sender.has_room() {
+                sender.push(data(next));
+                next += 1;
+            }
+            for frame in sender.unsent() {
+                if frame.sequence == 1 {
+                    if !dropped {
+                        dropped = true;
+                        continue;
+                    }
+                    resent_at.get_or_insert(now);
+                }
+                for frame in rx_decoder.process_samples(&air(&frame)) {
+                    receiver.receive(frame.sequence, frame.data);
+                }
+            }
+            sender.mark_sent(now);
+
+            let feedback = receiver
+                .feedback(1, 0)
+                .unwrap();
+            for ack in tx_decoder.process_samples(&air(&feedback)) {
+                sender.on_ack(ack.sequence);
+            }
+            if sender.fast_retransmit() > 0 {
+                // Frame 1 went out at t0, its timer is far from expiring
+                assert!(!sender.timed_out(now, TIMEOUT));
+                assert_eq!(sender.window(), 2);
+                assert_eq!(sender.unsent()[0].sequence, 1);
+            }
+        }
+
+        let resent_at = resent_at.expect("frame 1 never resent");
+        assert!(resent_at < t0 + TIMEOUT);
+        // Everything up to the last frame sent arrived in order
+        assert_eq!(receiver.cumulative_ack(), Some(next - 1));
+    }
+
+    /// Transfer `message` over a simulated half-duplex link, optionally
+    /// dropping every `loss_every`-th data frame. Returns the delivered
+    /// bytes and the time the link was busy, in samples.
+    fn simulate(
+        mode: ArqMode,
+        window: usize,
+        message: &[u8],
+        loss_every: Option<usize>,
+    ) -> (Vec<u8>, u64) {
+        let kind = LineCodingKind::FourBFiveB;
+        let params = PhyParams::default();
+        let encoder = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut rx_decoder = PhyDecoder::new(&params, kind, FecKind::None, 1);
+        let mut tx_decoder = PhyDecoder::new(&params, kind, FecKind::None, 0);
+        let timing = MacParams::default().timing(SAMPLE_RATE, 3);
+        let to_samples =
+            |d: Duration| (d.as_secs_f64() * SAMPLE_RATE as f64) as u64;
+        // Channel access (sensing, DIFS, mean backoff) and the turnaround
+        // until the ACK is heard: one receiver poll plus the AGC look-ahead
+        // on both ends
+        let access =
+            to_samples(timing.sense + timing.difs + timing.slot * CW_MIN / 2);
+        let turnaround = to_samples(Duration::from_millis(25))
+            + 2 * Agc::default().latency() as u64;
+
+        let t0 = Instant::now();
+        let at = |samples: u64| {
+            t0 + Duration::from_secs_f64(samples as f64 / SAMPLE_RATE as f64)
+        };
+
+        let mut sender = ArqSender::new(mode, window);
+        let mut receiver = ArqReceiver::new(mode);
+        let mut chunks = message.chunks(32).enumerate();
+        let mut delivered = Vec::new();
+        let mut clock = 0u64;
+        let mut transmissions = 0;
+
+        loop {
+            while sender.has_room()
+                && let Some((seq, chunk)) = chunks.next()
+            {
+                sender.push(Frame::new_data(
+                    seq as SeqType,
+                    0,
+                    1,
+                    chunk.to_vec(),
+                ));
+            }
+            if sender.is_empty() {
+                break;
+            }
+
+            clock += access;
+            for frame in sender.unsent() {
+                let mut samples = encoder.encode_frame(&frame);
+                clock += (samples.len() + INTER_FRAME_GAP_SAMPLES) as u64;
+                transmissions += 1;
+                if loss_every.is_some_and(|n| transmissions % n == 0) {
+                    continue;
+                }
+                samples.extend(vec![0.0; 100]);
+                for frame in rx_decoder.process_samples(&samples) {
+                    if let Arrival::Delivered(ready) =
+                        receiver.receive(frame.sequence, frame.data)
+                    {
+                        delivered.extend(ready.into_iter().flatten());
+                    }
+                }
+            }
+            sender.mark_sent(at(clock));
+
+            let mut released = 0;
+            if let Some(feedback) = receiver.feedback(1, 0) {
+                let mut samples = encoder.encode_frame(&feedback);
+                clock += turnaround + samples.len() as u64;
+                samples.extend(vec![0.0; 100]);
+                for reply in tx_decoder.process_samples(&samples) {
+                    released += match reply.frame_type {
+                        FrameType::Ack => sender.on_ack(reply.sequence),
+                        FrameType::Nack => sender.on_nack(
+                            reply.sequence,
+                            &decode_nack_bitmap(&reply.data).unwrap(),
+                        ),
+                        other => panic!("unexpected {:?}", other),
+                    };
+                }
+            }
+            sender.fast_retransmit();
+            if released == 0 && sender.unsent().is_empty() && !sender.is_empty()
+            {
+                // Nothing new got through: wait out the oldest timer
+                let deadline = sender
+                    .deadline(TIMEOUT)
+                    .unwrap();
+                clock = clock.max(to_samples(deadline - t0));
+                sender.on_timeout(at(clock), TIMEOUT);
+            }
+        }
+        (delivered, clock)
+    }
+
+    #[test]
+    fn test_window_doubles_loopback_throughput() {
+        let message: Vec<u8> = (0..2000u32)
+            .map(|i| (i * 13 % 251) as u8)
+            .collect();
+
+        let (stop_and_wait, sw_samples) =
+            simulate(ArqMode::GoBackN, 1, &message, None);
+        let (go_back_n, gbn_samples) =
+            simulate(ArqMode::GoBackN, ARQ_WINDOW, &message, None);
+        assert_eq!(stop_and_wait, message);
+        assert_eq!(go_back_n, message);
+
+        let speedup = sw_samples as f64 / gbn_samples as f64;
+        assert!(
+            speedup >= 2.0,
+            "window {} only {:.2}x faster",
+            ARQ_WINDOW,
+            speedup
+        );
+    }
+
+    #[test]
+    fn test_lossy_link_delivers_in_order() {
+        let message: Vec<u8> = (0..1000u32)
+            .map(|i| (i * 7 % 253) as u8)
+            .collect();
+        for window in [1, 2, ARQ_WINDOW, 7] {
+            let (delivered, _) =
+                simulate(ArqMode::GoBackN, window, &message, Some(5));
+            assert_eq!(delivered, message, "window {}", window);
+        }
+    }
+
+    #[test]
+    fn test_selective_repeat_with_ten_percent_loss() {
+        let message: Vec<u8> = (0..3000u32)
+            .map(|i| (i * 31 % 257) as u8)
+            .collect();
+
+        let (delivered, sr_samples) =
+            simulate(ArqMode::SelectiveRepeat, 8, &message, Some(10));
+        assert_eq!(delivered, message);
+
+        // Resending only the lost frames beats resending the whole window
+        let (go_back_n, gbn_samples) =
+            simulate(ArqMode::GoBackN, 8, &message, Some(10));
+        assert_eq!(go_back_n, message);
+        assert!(
+            sr_samples < gbn_samples,
+            "{} vs {}",
+            sr_samples,
+            gbn_samples
+        );
+    }
+}
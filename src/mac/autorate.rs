@@ -0,0 +1,296 @@
+// Rate negotiation
+//
+// Before a transfer the sender probes progressively faster body rates (see
+// phy::rate): PROBES_PER_RATE Probe frames at a time, then waits for the
+// receiver's report, an ACK carrying
+//
+//   Report payload: [Rate:1] [Sent:1] [Received:1] [SnrDb:1, signed]
+//
+// A rate is reliable if at least PROBE_MIN_SUCCESS of its probes passed the
+// CRC and the estimated SNR reaches PROBE_MIN_SNR_DB. Probing stops at the
+// first rate that is not (or whose report never arrives), and both sides
+// carry on at the fastest reliable one. Each data frame names its rate in
+// the header, so the receiver needs no further agreement.
+//
+//   Probe payload: [Sent:1] [Filler]
+
+use tracing::debug;
+
+use crate::mac::types::MacAddr;
+use crate::phy::Frame;
+use crate::phy::frame::FrameType;
+use crate::phy::rate::{MAX_RATE_CODE, RateCode};
+use crate::utils::consts::{
+    PROBE_MIN_SNR_DB, PROBE_MIN_SUCCESS, PROBE_PAYLOAD_BYTES, PROBES_PER_RATE,
+};
+
+/// SNR byte of a report from a receiver that can't estimate it
+pub const SNR_UNKNOWN: i8 = i8::MAX;
+
+/// What the receiver made of one burst of probes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeReport {
+    pub rate: RateCode,
+    pub sent: u8,
+    pub received: u8,
+    pub snr_db: i8,
+}
+
+impl ProbeReport {
+    pub fn to_bytes(self) -> Vec<u8> {
+        vec![self.rate, self.sent, self.received, self.snr_db as u8]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        match bytes {
+            [rate, sent, received, snr] => Ok(Self {
+                rate: *rate,
+                sent: *sent,
+                received: *received,
+                snr_db: *snr as i8,
+            }),
+            _ => Err(format!("Invalid probe report of {} bytes", bytes.len())),
+        }
+    }
+
+    /// Enough probes got through, with a margin in SNR
+    pub fn is_reliable(&self) -> bool {
+        self.sent > 0
+            && self.received as f32 / self.sent as f32 >= PROBE_MIN_SUCCESS
+            && (self.snr_db == SNR_UNKNOWN
+                || self.snr_db as f32 >= PROBE_MIN_SNR_DB)
+    }
+}
+
+/// One burst of probes at `rate`
+pub fn probe_burst(
+    local: MacAddr,
+    remote: MacAddr,
+    rate: RateCode,
+) -> Vec<Frame> {
+    (0..PROBES_PER_RATE)
+        .map(|i| {
+            let mut payload = vec![0x55; PROBE_PAYLOAD_BYTES];
+            payload[0] = PROBES_PER_RATE as u8;
+            Frame::new_probe(i as u16, local, remote, rate, payload)
+        })
+        .collect()
+}
+
+/// Sender side: which rate to probe next and which one to settle on
+#[derive(Debug, Clone)]
+pub struct RateProber {
+    next: Option<RateCode>,
+    chosen: RateCode,
+}
+
+impl Default for RateProber {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateProber {
+    /// Start at the first rate above the base one
+    pub fn new() -> Self {
+        Self {
+            next: (MAX_RATE_CODE > 0).then_some(1),
+            chosen: 0,
+        }
+    }
+
+    /// Rate to probe now, None once negotiation is over
+    pub fn current(&self) -> Option<RateCode> {
+        self.next
+    }
+
+    /// Take the receiver's report for the current rate, None if it never
+    /// came back
+    pub fn on_report(&mut self, report: Option<ProbeReport>) {
+        let Some(rate) = self.next else {
+            return;
+        };
+        match report {
+            Some(report) if report.rate == rate && report.is_reliable() => {
+                debug!("Rate {} reliable: {:?}", rate, report);
+                self.chosen = rate;
+                self.next = (rate < MAX_RATE_CODE).then_some(rate + 1);
+            }
+            _ => {
+                debug!("Rate {} unreliable ({:?}), stopping", rate, report);
+                self.next = None;
+            }
+        }
+    }
+
+    /// Fastest rate found reliable so far, 0 = the base rate
+    pub fn chosen(&self) -> RateCode {
+        self.chosen
+    }
+}
+
+/// Receiver side: counts the probes of the current burst
+#[derive(Debug, Clone, Default)]
+pub struct ProbeCollector {
+    peer: MacAddr,
+    rate: RateCode,
+    sent: u8,
+    received: u8,
+    snr_sum: f32,
+    snr_known: bool,
+}
+
+impl ProbeCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count `frame` if it is a probe. `snr_db` is the decoder's estimate
+    /// for it, if any. A probe of another rate or peer starts a new burst.
+    /// Returns false for other frames.
+    pub fn observe(&mut self, frame: &Frame, snr_db: Option<f32>) -> bool {
+        if frame.frame_type != FrameType::Probe {
+            return false;
+        }
+        if self.received == 0
+            || frame.rate != self.rate
+            || frame.src != self.peer
+        {
+            *self = Self {
+                peer: frame.src,
+                rate: frame.rate,
+                snr_known: true,
+                ..Self::default()
+            };
+        }
+        self.sent = frame
+            .data
+            .first()
+            .copied()
+            .unwrap_or(PROBES_PER_RATE as u8);
+        self.received = self
+            .received
+            .saturating_add(1);
+        match snr_db {
+            Some(snr) => self.snr_sum += snr,
+            None => self.snr_known = false,
+        }
+        true
+    }
+
+    /// Report for the burst so far and who to send it to, None without
+    /// probes. Starts over.
+    pub fn take_report(&mut self) -> Option<(MacAddr, ProbeReport)> {
+        if self.received == 0 {
+            return None;
+        }
+        let snr_db = if self.snr_known {
+            (self.snr_sum / self.received as f32)
+                .round()
+                .clamp(i8::MIN as f32, (SNR_UNKNOWN - 1) as f32)
+                as i8
+        } else {
+            SNR_UNKNOWN
+        };
+        let report = ProbeReport {
+            rate: self.rate,
+            sent: self.sent,
+            received: self.received.min(self.sent),
+            snr_db,
+        };
+        let peer = self.peer;
+        *self = Self::default();
+        Some((peer, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a full negotiation over a channel that delivers probe `i` at
+    /// `rate` if `delivers(rate, i)`, with SNR `snr(rate)`. Reports are lost
+    /// when no probe arrived.
+    fn negotiate(
+        delivers: impl Fn(RateCode, usize) -> bool,
+        snr: impl Fn(RateCode) -> f32,
+    ) -> (RateCode, Vec<RateCode>) {
+        let mut prober = RateProber::new();
+        let mut collector = ProbeCollector::new();
+        let mut probed = Vec::new();
+        while let Some(rate) = prober.current() {
+            probed.push(rate);
+            for (i, probe) in probe_burst(1, 2, rate)
+                .iter()
+                .enumerate()
+            {
+                let bytes = probe.to_bytes();
+                if delivers(rate, i) {
+                    let frame = Frame::from_bytes(&bytes).unwrap();
+                    assert!(collector.observe(&frame, Some(snr(rate))));
+                }
+            }
+            let report = collector
+                .take_report()
+                .map(|(peer, report)| {
+                    assert_eq!(peer, 1);
+                    ProbeReport::from_bytes(&report.to_bytes()).unwrap()
+                });
+            prober.on_report(report);
+        }
+        (prober.chosen(), probed)
+    }
+
+    #[test]
+    fn test_settles_on_fastest_reliable_rate() {
+        // Clean channel: every rate works
+        assert_eq!(negotiate(|_, _| true, |_| 30.0), (2, vec![1, 2]));
+
+        // Rate 2 loses half its probes
+        assert_eq!(
+            negotiate(|rate, i| rate < 2 || i % 2 == 0, |_| 30.0),
+            (1, vec![1, 2])
+        );
+
+        // Rate 1 loses one probe in four, still enough; rate 2 is silent
+        assert_eq!(
+            negotiate(|rate, i| rate == 1 && i != 3, |_| 30.0),
+            (1, vec![1, 2])
+        );
+
+        // Nothing faster gets through: keep the base rate
+        assert_eq!(negotiate(|_, _| false, |_| 30.0), (0, vec![1]));
+    }
+
+    #[test]
+    fn test_low_snr_stops_probing() {
+        // All probes pass the CRC, but rate 2 has no margin left
+        assert_eq!(
+            negotiate(|_, _| true, |rate| 25.0 - 10.0 * rate as f32),
+            (1, vec![1, 2])
+        );
+        // Receivers without an estimate go by the CRC alone
+        let report = ProbeReport {
+            rate: 1,
+            sent: 4,
+            received: 4,
+            snr_db: SNR_UNKNOWN,
+        };
+        assert!(report.is_reliable());
+    }
+
+    #[test]
+    fn test_stale_reports_ignored() {
+        let mut prober = RateProber::new();
+        // A report for another rate doesn't vouch for this one
+        prober.on_report(Some(ProbeReport {
+            rate: 2,
+            sent: 4,
+            received: 4,
+            snr_db: 30,
+        }));
+        assert_eq!(prober.current(), None);
+        assert_eq!(prober.chosen(), 0);
+        assert!(ProbeReport::from_bytes(&[1, 2, 3]).is_err());
+    }
+}
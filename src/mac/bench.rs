@@ -0,0 +1,480 @@
+// Throughput benchmark over the loopback medium
+//
+// Every cell of the matrix (line coding x frame size x FEC x noise) sends
+// the same payload from one CsmaNode to another over a fresh
+// LoopbackMedium, in real time, and measures what got through:
+//   goodput          payload bits delivered per second of sending
+//   frame loss       share of transmissions the receiver didn't decode
+//   retransmissions  frames sent again after an ACK timeout
+// Results go to a JSON or CSV file and are printed as a table.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::audio::loopback::{LinkParams, LoopbackConfig, LoopbackMedium};
+use crate::audio::recorder;
+use crate::mac::csma::CsmaNode;
+use crate::mac::params::MacParams;
+use crate::phy::backend::BasebandBackend;
+use crate::phy::params::PhyParams;
+use crate::phy::{FecKind, LineCodingKind};
+use crate::ui::progress::ProgressManager;
+use crate::utils::consts::{MAX_FRAME_DATA_SIZE, SAMPLE_RATE};
+use crate::utils::report;
+
+/// The parameters to sweep and what each cell sends
+#[derive(Debug, Clone)]
+pub struct BenchMatrix {
+    pub encodings: Vec<LineCodingKind>,
+    /// Payload bytes per frame
+    pub frame_sizes: Vec<usize>,
+    pub fecs: Vec<FecKind>,
+    /// Standard deviation of the noise the medium adds
+    pub noise: Vec<f32>,
+    /// Bytes sent in every cell
+    pub payload_bytes: usize,
+    /// Seconds after which a cell gives up
+    pub timeout_secs: u64,
+    /// Parameters every cell runs with
+    pub phy: PhyParams,
+    pub mac: MacParams,
+}
+
+impl BenchMatrix {
+    /// Every line coding and FEC, a few frame sizes and noise levels
+    pub fn full() -> Self {
+        Self {
+            encodings: vec![
+                LineCodingKind::FourBFiveB,
+                LineCodingKind::Manchester,
+                LineCodingKind::EightBTenB,
+                LineCodingKind::Nrzi,
+            ],
+            frame_sizes: vec![32, 64, MAX_FRAME_DATA_SIZE],
+            fecs: vec![FecKind::None, FecKind::Hamming74],
+            noise: vec![0.0, 0.05, 0.1],
+            payload_bytes: 1024,
+            timeout_secs: 30,
+            phy: PhyParams::default(),
+            mac: MacParams::default(),
+        }
+    }
+
+    /// Four short cells, well under a minute for CI
+    pub fn quick() -> Self {
+        Self {
+            encodings: vec![LineCodingKind::FourBFiveB, LineCodingKind::Nrzi],
+            frame_sizes: vec![64],
+            fecs: vec![FecKind::None],
+            noise: vec![0.0, 0.05],
+            payload_bytes: 256,
+            timeout_secs: 10,
+            phy: PhyParams::default(),
+            mac: MacParams::default(),
+        }
+    }
+
+    pub fn cells(&self) -> Vec<BenchCell> {
+        let mut cells = Vec::new();
+        for &encoding in &self.encodings {
+            for &frame_bytes in &self.frame_sizes {
+                for &fec in &self.fecs {
+                    for &noise in &self.noise {
+                        cells.push(BenchCell {
+                            encoding,
+                            frame_bytes,
+                            fec,
+                            noise,
+                        });
+                    }
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// One point of the matrix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchCell {
+    pub encoding: LineCodingKind,
+    pub frame_bytes: usize,
+    pub fec: FecKind,
+    pub noise: f32,
+}
+
+/// What one cell measured
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchResult {
+    pub encoding: String,
+    pub frame_bytes: usize,
+    pub fec: String,
+    pub noise: f32,
+    pub payload_bytes: u64,
+    pub delivered_bytes: u64,
+    /// Everything arrived, in order and unchanged
+    pub intact: bool,
+    /// Time the sender took, until the last ACK or its timeout
+    pub secs: f64,
+    /// Payload bits delivered per second
+    pub goodput_bps: f64,
+    pub frames_sent: u64,
+    /// Transmissions decoded by the receiver, duplicates included
+    pub frames_received: u64,
+    /// Share of transmissions the receiver didn't decode
+    pub frame_loss: f64,
+    pub retransmissions: u64,
+    pub frames_dropped: u64,
+}
+
+impl BenchResult {
+    const CSV_HEADER: &str = "encoding,frame_bytes,fec,noise,payload_bytes,\
+        delivered_bytes,intact,secs,goodput_bps,frames_sent,frames_received,\
+        frame_loss,retransmissions,frames_dropped";
+
+    fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{:.3},{:.1},{},{},{:.4},{},{}",
+            self.encoding,
+            self.frame_bytes,
+            self.fec,
+            self.noise,
+            self.payload_bytes,
+            self.delivered_bytes,
+            self.intact,
+            self.secs,
+            self.goodput_bps,
+            self.frames_sent,
+            self.frames_received,
+            self.frame_loss,
+            self.retransmissions,
+            self.frames_dropped
+        )
+    }
+}
+
+/// All cells of a run, written as JSON or CSV
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BenchReport {
+    pub started_unix_secs: u64,
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchReport {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{}\n", BenchResult::CSV_HEADER);
+        for result in &self.results {
+            csv.push_str(&result.csv_row());
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Write to `path`, as CSV if it ends in .csv and JSON otherwise
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+        {
+            self.to_csv()
+        } else {
+            self.to_json()?
+        };
+        fs::write(path, contents)
+    }
+
+    /// The results as an aligned table
+    pub fn table(&self) -> String {
+        let mut table = format!(
+            "{:<11} {:>5} {:<9} {:>6} {:>10} {:>7} {:>6} {:>6}\n",
+            "encoding", "frame", "fec", "noise", "goodput", "loss", "retx", "ok"
+        );
+        for r in &self.results {
+            let _ = writeln!(
+                table,
+                "{:<11} {:>5} {:<9} {:>6.3} {:>6.0} b/s {:>6.1}% {:>6} {:>6}",
+                r.encoding,
+                r.frame_bytes,
+                r.fec,
+                r.noise,
+                r.goodput_bps,
+                r.frame_loss * 100.0,
+                r.retransmissions,
+                if r.intact { "yes" } else { "NO" }
+            );
+        }
+        table
+    }
+}
+
+/// Send `payload_bytes` through one cell's link and measure it
+pub fn run_cell(
+    cell: &BenchCell,
+    payload_bytes: usize,
+    timeout_secs: u64,
+    phy_params: &PhyParams,
+    mac_params: &MacParams,
+) -> BenchResult {
+    let medium = LoopbackMedium::new(LoopbackConfig {
+        seed: 1,
+        link: LinkParams {
+            noise: cell.noise,
+            ..LinkParams::default()
+        },
+        ..LoopbackConfig::default()
+    });
+    let record_samples = SAMPLE_RATE as usize * (timeout_secs as usize + 5);
+    let mut nodes = medium.nodes(2, record_samples);
+    let (b, _b_audio) = nodes.pop().unwrap();
+    let (a, _a_audio) = nodes.pop().unwrap();
+    let frames = payload_bytes.div_ceil(cell.frame_bytes.max(1)) as u64;
+    let progress = ProgressManager::new();
+    for (id, total) in [("sender", frames), ("receiver", 0)] {
+        if let Err(e) = progress.add_frames_bar(id, total) {
+            warn!("{}", e);
+        }
+    }
+    let progress = Arc::new(Mutex::new(progress));
+    let node = |shared, local, remote| {
+        let mut node = CsmaNode::with_backend(
+            shared,
+            progress.clone(),
+            SAMPLE_RATE,
+            Box::new(BasebandBackend::new(
+                phy_params,
+                cell.encoding,
+                cell.fec,
+                local,
+            )),
+            local,
+            remote,
+            phy_params,
+        );
+        node.set_mac_params(mac_params);
+        node
+    };
+    let mut sender = node(a, 1, 2);
+    let mut receiver = node(b.clone(), 2, 1);
+
+    let (delivered_tx, delivered_rx) = crossbeam_channel::unbounded();
+    let receiving = thread::spawn(move || {
+        // Stopped below once the sender is done
+        let _ = receiver.run_receiver_loop(
+            record_samples as u32,
+            timeout_secs + 5,
+            delivered_tx,
+        );
+        receiver.stats()
+    });
+
+    let payload: Vec<u8> = (0..payload_bytes)
+        .map(|i| (i * 131 % 251) as u8)
+        .collect();
+    let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+    for chunk in payload.chunks(cell.frame_bytes) {
+        let _ = queue_tx.send(chunk.to_vec());
+    }
+    drop(queue_tx);
+    let (failures_tx, _failures_rx) = crossbeam_channel::unbounded();
+    let start = Instant::now();
+    if let Err(e) = sender.run_sender_loop(timeout_secs, queue_rx, failures_tx) {
+        warn!("{:?}: {}", cell, e);
+    }
+    let secs = start.elapsed().as_secs_f64();
+    b.app_state
+        .set(recorder::AppState::Idle);
+    let rx_stats = receiving
+        .join()
+        .unwrap_or_default();
+    let tx_stats = sender.stats();
+
+    let delivered: Vec<u8> = delivered_rx
+        .try_iter()
+        .flatten()
+        .collect();
+    let frame_loss = if tx_stats.frames_sent == 0 {
+        0.0
+    } else {
+        1.0 - (rx_stats.frames_received as f64 / tx_stats.frames_sent as f64)
+            .min(1.0)
+    };
+    BenchResult {
+        encoding: cell
+            .encoding
+            .name()
+            .to_string(),
+        frame_bytes: cell.frame_bytes,
+        fec: cell.fec.name().to_string(),
+        noise: cell.noise,
+        payload_bytes: payload_bytes as u64,
+        delivered_bytes: delivered.len() as u64,
+        intact: delivered == payload,
+        secs,
+        goodput_bps: delivered.len() as f64 * 8.0 / secs.max(f64::EPSILON),
+        frames_sent: tx_stats.frames_sent,
+        frames_received: rx_stats.frames_received,
+        frame_loss,
+        retransmissions: tx_stats.retransmissions,
+        frames_dropped: tx_stats.frames_dropped,
+    }
+}
+
+/// Run every cell of `matrix` in turn
+pub fn run_matrix(matrix: &BenchMatrix) -> BenchReport {
+    let started_unix_secs = report::unix_now_secs();
+    let cells = matrix.cells();
+    let mut results = Vec::with_capacity(cells.len());
+    for (i, cell) in cells.iter().enumerate() {
+        info!(
+            "Cell {}/{}: {} {} B/frame FEC {} noise {}",
+            i + 1,
+            cells.len(),
+            cell.encoding.name(),
+            cell.frame_bytes,
+            cell.fec.name(),
+            cell.noise
+        );
+        results.push(run_cell(
+            cell,
+            matrix.payload_bytes,
+            matrix.timeout_secs,
+            &matrix.phy,
+            &matrix.mac,
+        ));
+    }
+    BenchReport {
+        started_unix_secs,
+        results,
+    }
+}
+
+/// The bench command: run `matrix`, print the table and write the results
+/// to `output` (default ./tmp/bench/bench-<time>.json)
+pub fn run_bench(mut matrix: BenchMatrix, output: Option<PathBuf>) {
+    matrix
+        .frame_sizes
+        .retain(|&size| {
+            let valid = (1..=MAX_FRAME_DATA_SIZE).contains(&size);
+            if !valid {
+                warn!(
+                    "Frame size {} outside 1..={}, skipped",
+                    size, MAX_FRAME_DATA_SIZE
+                );
+            }
+            valid
+        });
+    info!(
+        "Benchmarking {} cells of {} bytes",
+        matrix.cells().len(),
+        matrix.payload_bytes
+    );
+
+    let report = run_matrix(&matrix);
+    print!("{}", report.table());
+    let path = output.unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "tmp/bench/bench-{}.json",
+            report.started_unix_secs
+        ))
+    });
+    match report.write(&path) {
+        Ok(()) => info!("Results written to {}", path.display()),
+        Err(e) => error!("Failed to write {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_result() -> BenchResult {
+        BenchResult {
+            encoding: "4B5B".to_string(),
+            frame_bytes: 64,
+            fec: "none".to_string(),
+            noise: 0.05,
+            payload_bytes: 256,
+            delivered_bytes: 256,
+            intact: true,
+            secs: 1.5,
+            goodput_bps: 1365.3,
+            frames_sent: 5,
+            frames_received: 4,
+            frame_loss: 0.2,
+            retransmissions: 1,
+            frames_dropped: 0,
+        }
+    }
+
+    #[test]
+    fn test_result_schema() {
+        let report = BenchReport {
+            started_unix_secs: 1,
+            results: vec![sample_result()],
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        let fields: Vec<&str> = json["results"][0]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        let columns: Vec<&str> = BenchResult::CSV_HEADER
+            .split(',')
+            .collect();
+        // Same fields in both formats
+        assert_eq!(fields.len(), columns.len());
+        for column in &columns {
+            assert!(fields.contains(column), "{} not in the JSON", column);
+        }
+        assert_eq!(serde_json::from_value::<BenchReport>(json).unwrap(), report);
+
+        let csv = report.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].split(',').count(), columns.len());
+        assert!(lines[1].starts_with("4B5B,64,none,0.05,256,256,true,1.500,"));
+    }
+
+    #[test]
+    fn test_small_matrix_run() {
+        let matrix = BenchMatrix {
+            encodings: vec![LineCodingKind::FourBFiveB],
+            frame_sizes: vec![48],
+            fecs: vec![FecKind::None],
+            noise: vec![0.0],
+            payload_bytes: 160,
+            timeout_secs: 10,
+            phy: PhyParams::default(),
+            mac: MacParams::default(),
+        };
+        let report = run_matrix(&matrix);
+        assert_eq!(report.results.len(), 1);
+        let result = &report.results[0];
+        assert!(result.intact, "{:?}", result);
+        assert_eq!(result.delivered_bytes, 160);
+        // 48 + 48 + 48 + 16
+        assert!(result.frames_sent >= 4, "{:?}", result);
+        assert!(result.goodput_bps > 0.0);
+        assert!(
+            report
+                .table()
+                .contains("4B5B")
+        );
+    }
+}
@@ -0,0 +1,292 @@
+// Authenticated encryption of data frames with a pre-shared key
+//
+// Sealed body: [Nonce:8] [Ciphertext] [Tag:16]
+//
+// XChaCha20-Poly1305 over the data of Data and Aggregate frames, with the
+// frame type, sequence number and both addresses as associated data, so a
+// body can't be replayed under another header. The 24-byte nonce is
+// [Src] [Dst] [0:14] [Nonce:8], the last part sent along: a counter
+// starting at a random value per node and taken once per sealed frame.
+// Retransmissions send the sealed frame again as it is, so no nonce is ever
+// used for two different bodies. Including the addresses keeps the nonces
+// of both directions apart under the one key.
+//
+// ACKs, NACKs, RTS/CTS, probes and beacons stay in the clear, as does what
+// rides in front of the data (NAV, timestamps, piggybacked ACK): they are
+// answered within tight timeouts and carry nothing worth hiding. The hop
+// count isn't covered either, relays re-seal what they forward.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use crate::phy::frame::ChecksumKind;
+use crate::phy::{Frame, FrameType};
+
+pub const KEY_BYTES: usize = 32;
+const NONCE_BYTES: usize = 8;
+const TAG_BYTES: usize = 16;
+/// Bytes sealing adds to a frame's data
+pub const SEAL_OVERHEAD: usize = NONCE_BYTES + TAG_BYTES;
+
+/// Read a key file: 32 raw bytes, or 64 hex digits
+pub fn load_key(path: &Path) -> Result<[u8; KEY_BYTES], String> {
+    let bytes = fs::read(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    if let Ok(key) = <[u8; KEY_BYTES]>::try_from(&bytes[..]) {
+        return Ok(key);
+    }
+    let hex = String::from_utf8_lossy(&bytes);
+    let hex = hex.trim();
+    if hex.len() != 2 * KEY_BYTES || !hex.is_ascii() {
+        return Err(format!(
+            "{} must hold {} bytes, raw or as {} hex digits",
+            path.display(),
+            KEY_BYTES,
+            2 * KEY_BYTES
+        ));
+    }
+    let mut key = [0u8; KEY_BYTES];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|e| format!("{}: not a hex key: {}", path.display(), e))?;
+    }
+    Ok(key)
+}
+
+/// Seals our frames and opens the ones for us
+pub struct FrameCipher {
+    aead: XChaCha20Poly1305,
+    next_nonce: AtomicU64,
+}
+
+impl FrameCipher {
+    pub fn new(key: &[u8; KEY_BYTES]) -> Self {
+        Self::with_first_nonce(key, rand::random())
+    }
+
+    fn with_first_nonce(key: &[u8; KEY_BYTES], first: u64) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(Key::from_slice(key)),
+            next_nonce: AtomicU64::new(first),
+        }
+    }
+
+    /// Frame types whose data is sealed
+    pub fn protects(frame_type: FrameType) -> bool {
+        matches!(frame_type, FrameType::Data | FrameType::Aggregate)
+    }
+
+    /// Encrypt the data of `frame` under a fresh nonce. Other frame types
+    /// are left alone.
+    pub fn seal(&self, frame: &mut Frame) {
+        if !Self::protects(frame.frame_type) {
+            return;
+        }
+        let explicit = self
+            .next_nonce
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let sealed = self
+            .aead
+            .encrypt(
+                &nonce(frame, explicit),
+                Payload {
+                    msg: &frame.data,
+                    aad: &associated_data(frame),
+                },
+            )
+            .expect("frame data fits the cipher");
+        let mut data = Vec::with_capacity(NONCE_BYTES + sealed.len());
+        data.extend_from_slice(&explicit);
+        data.extend(sealed);
+        frame.data = data;
+        frame.checksum =
+            ChecksumKind::for_frame(frame.frame_type, frame.data.len());
+    }
+
+    /// Decrypt the data of `frame` in place. Fails, leaving the frame as
+    /// it was, if it was not sealed with our key for this header.
+    pub fn open(&self, frame: &mut Frame) -> Result<(), String> {
+        if !Self::protects(frame.frame_type) {
+            return Ok(());
+        }
+        if frame.data.len() < SEAL_OVERHEAD {
+            return Err(format!(
+                "{} bytes are too short to be sealed",
+                frame.data.len()
+            ));
+        }
+        let (explicit, sealed) = frame
+            .data
+            .split_at(NONCE_BYTES);
+        let explicit: [u8; NONCE_BYTES] = explicit.try_into().unwrap();
+        let data = self
+            .aead
+            .decrypt(
+                &nonce(frame, explicit),
+                Payload {
+                    msg: sealed,
+                    aad: &associated_data(frame),
+                },
+            )
+            .map_err(|_| "Authentication failed".to_string())?;
+        frame.data = data;
+        Ok(())
+    }
+}
+
+fn nonce(frame: &Frame, explicit: [u8; NONCE_BYTES]) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[0] = frame.src;
+    nonce[1] = frame.dst;
+    nonce[24 - NONCE_BYTES..].copy_from_slice(&explicit);
+    nonce
+}
+
+/// [Type] [Seq:2] [Src] [Dst]
+fn associated_data(frame: &Frame) -> [u8; 5] {
+    let [seq_high, seq_low] = frame.sequence.to_be_bytes();
+    [
+        frame.frame_type.to_u8(),
+        seq_high,
+        seq_low,
+        frame.src,
+        frame.dst,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_xchacha20_poly1305_known_answer() {
+        // draft-irtf-cfrg-xchacha-03, appendix A.3.1
+        let key: [u8; KEY_BYTES] = hex(
+            "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+        )
+        .try_into()
+        .unwrap();
+        let nonce = hex("404142434445464748494a4b4c4d4e4f5051525354555657");
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could \
+            offer you only one tip for the future, sunscreen would be it.";
+        let expected = hex(concat!(
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb",
+            "731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452",
+            "2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9",
+            "21f9664c97637da9768812f615c68b13b52e",
+            // Tag
+            "c0875924c1c7987947deafd8780acf49",
+        ));
+
+        let cipher = FrameCipher::new(&key);
+        let sealed = cipher
+            .aead
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        assert_eq!(sealed, expected);
+    }
+
+    #[test]
+    fn test_sealed_frame_opens_and_rejects_tampering() {
+        let key = [7u8; KEY_BYTES];
+        let sender = FrameCipher::with_first_nonce(&key, u64::MAX);
+        let receiver = FrameCipher::new(&key);
+        let data = b"attack at dawn".to_vec();
+
+        let mut frame = Frame::new_data(42, 1, 2, data.clone());
+        sender.seal(&mut frame);
+        assert_eq!(frame.data.len(), data.len() + SEAL_OVERHEAD);
+        assert_eq!(frame.data[..NONCE_BYTES], u64::MAX.to_be_bytes());
+        // The counter wraps rather than repeating a nonce soon
+        let mut next = Frame::new_data(43, 1, 2, data.clone());
+        sender.seal(&mut next);
+        assert_eq!(next.data[..NONCE_BYTES], 0u64.to_be_bytes());
+
+        let mut opened = frame.clone();
+        assert_eq!(receiver.open(&mut opened), Ok(()));
+        assert_eq!(opened.data, data);
+
+        let mut flipped = frame.clone();
+        flipped.data[NONCE_BYTES + 3] ^= 0x01;
+        let before = flipped.data.clone();
+        assert!(
+            receiver
+                .open(&mut flipped)
+                .is_err()
+        );
+        assert_eq!(flipped.data, before);
+
+        // The same body under another header
+        let mut moved = frame.clone();
+        moved.sequence = 43;
+        assert!(
+            receiver
+                .open(&mut moved)
+                .is_err()
+        );
+        let mut redirected = frame.clone();
+        redirected.dst = 3;
+        assert!(
+            receiver
+                .open(&mut redirected)
+                .is_err()
+        );
+
+        let mut wrong_key = frame.clone();
+        assert!(
+            FrameCipher::new(&[8; KEY_BYTES])
+                .open(&mut wrong_key)
+                .is_err()
+        );
+
+        // ACKs go as they are
+        let mut ack = Frame::new_ack(42, 2, 1);
+        sender.seal(&mut ack);
+        assert!(ack.data.is_empty());
+        assert_eq!(receiver.open(&mut ack), Ok(()));
+    }
+
+    #[test]
+    fn test_key_file_raw_or_hex() {
+        let dir = std::env::temp_dir()
+            .join(format!("trackmaker-key-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key: [u8; KEY_BYTES] = std::array::from_fn(|i| i as u8 * 3);
+
+        let raw = dir.join("raw.key");
+        fs::write(&raw, key).unwrap();
+        assert_eq!(load_key(&raw), Ok(key));
+
+        let hex_file = dir.join("hex.key");
+        let text: String = key
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        fs::write(&hex_file, format!("{}\n", text)).unwrap();
+        assert_eq!(load_key(&hex_file), Ok(key));
+
+        let short = dir.join("short.key");
+        fs::write(&short, "abcd").unwrap();
+        assert!(load_key(&short).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use std::sync::Mutex;
 use std::sync::{
@@ -8,21 +8,233 @@ use std::sync::{
 
 use crate::{
     audio::recorder,
-    mac,
-    phy::{Frame, FrameType, LineCodingKind, PhyDecoder, PhyEncoder},
-    ui::progress::ProgressManager,
-    utils::consts::*,
+    mac::{
+        self,
+        aggregation::{self, Aggregator, Deaggregator, SubPacket},
+        arq::{self as arq, ArqMode, ArqReceiver, ArqSender, Arrival},
+        autorate::{self, ProbeCollector, ProbeReport, RateProber},
+        crypto::{self, FrameCipher},
+        delay,
+        discovery::{Discovery, Neighbor},
+        nav::{self, Nav},
+        noise::{JamEvent, NoiseFloor},
+        params::MacParams,
+        queue::{Priority, TxQueue},
+        relay::{self, RelayAction, RelayCore},
+        stats::LinkStats,
+        timing::CsmaTiming,
+        types::BROADCAST,
+    },
+    phy::{
+        FecKind, Frame, FrameType, LineCodingKind,
+        backend::{BasebandBackend, ModulationBackend},
+        decoder::DecodeStats,
+        echo::EchoCanceller,
+        frame::{FrameTimestamp, SeqType},
+        interleaver::Interleaver,
+        params::PhyParams,
+        rate::{RateCode, samples_per_level},
+    },
+    ui::{
+        dashboard::{DashboardEvent, DashboardFeed, LinkStatus},
+        progress::ProgressManager,
+    },
+    utils::{consts::*, metrics},
 };
 use tracing::{debug, error, info, trace, warn};
 
+/// Receiver-side duplicate ACK suppression.
+/// After a duplicate has been re-ACKed, further duplicates of the same
+/// sequence are not ACKed again until `interval` has passed.
+pub struct AckSuppressor {
+    interval: std::time::Duration,
+    last_dup_ack: HashMap<SeqType, std::time::Instant>,
+}
+
+impl AckSuppressor {
+    pub fn new(interval: std::time::Duration) -> Self {
+        Self {
+            interval,
+            last_dup_ack: HashMap::new(),
+        }
+    }
+
+    /// Whether a frame with `seq` should be ACKed now
+    pub fn should_ack(
+        &mut self,
+        seq: SeqType,
+        duplicate: bool,
+        now: std::time::Instant,
+    ) -> bool {
+        if !duplicate {
+            self.last_dup_ack.remove(&seq);
+            return true;
+        }
+
+        match self.last_dup_ack.get(&seq) {
+            Some(&last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                self.last_dup_ack
+                    .insert(seq, now);
+                true
+            }
+        }
+    }
+}
+
+/// An ACK held back so it can ride on our next data frame (piggyback).
+/// It goes out as a standalone ACK once `delay` has passed without data to
+/// carry it.
+pub struct PendingAck {
+    delay: std::time::Duration,
+    pending: Option<(SeqType, std::time::Instant)>,
+}
+
+impl PendingAck {
+    pub fn new(delay: std::time::Duration) -> Self {
+        Self {
+            delay,
+            pending: None,
+        }
+    }
+
+    /// Hold the ACK for `seq`; a copy of the same frame keeps the original
+    /// deadline, a newer frame replaces it (the peer is stop-and-wait)
+    pub fn defer(&mut self, seq: SeqType, now: std::time::Instant) {
+        match self.pending {
+            Some((pending, _)) if pending == seq => {}
+            _ => self.pending = Some((seq, now)),
+        }
+    }
+
+    /// Take the ACK to piggyback on an outgoing data frame
+    pub fn take(&mut self) -> Option<SeqType> {
+        self.pending
+            .take()
+            .map(|(seq, _)| seq)
+    }
+
+    /// Take the ACK once it has waited `delay`, to be sent on its own
+    pub fn take_expired(&mut self, now: std::time::Instant) -> Option<SeqType> {
+        match self.pending {
+            Some((_, since)) if now.duration_since(since) >= self.delay => {
+                self.take()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Receiver-side duplicate detection over the most recent sequence numbers
+pub struct SequenceWindow {
+    capacity: usize,
+    recent: VecDeque<SeqType>,
+    seen: HashSet<SeqType>,
+}
+
+impl SequenceWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Record `seq`; false if it is already in the window (a duplicate)
+    pub fn insert(&mut self, seq: SeqType) -> bool {
+        if !self.seen.insert(seq) {
+            return false;
+        }
+        if self.recent.len() == self.capacity
+            && let Some(oldest) = self.recent.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        self.recent.push_back(seq);
+        true
+    }
+}
+
+/// Why the sender did not get a frame acknowledged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    /// No ACK after `attempts` tries, the frame was dropped
+    RetriesExceeded { sequence: SeqType, attempts: u32 },
+    /// The transfer deadline passed first
+    Deadline { sequence: SeqType },
+}
+
+impl std::fmt::Display for TxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TxError::RetriesExceeded { sequence, attempts } => {
+                write!(f, "seq {} dropped after {} attempts", sequence, attempts)
+            }
+            TxError::Deadline { sequence } => {
+                write!(f, "Timed out before seq {} was acknowledged", sequence)
+            }
+        }
+    }
+}
+
+/// Inbound half of a duplex transfer, also served while we wait for the
+/// ACKs of our own frames
+struct DuplexInbound {
+    pending: PendingAck,
+    received: SequenceWindow,
+    delivered: crossbeam_channel::Sender<Vec<u8>>,
+}
+
 pub struct CsmaNode {
     shared: recorder::AppShared,
     progress_manager: Arc<Mutex<ProgressManager>>,
-    encoder: PhyEncoder,
-    decoder: PhyDecoder,
+    backend: Box<dyn ModulationBackend>,
     sample_rate: u32,
+    ack_airtime: std::time::Duration,
     local_addr: mac::types::MacAddr,
     remote_addr: mac::types::MacAddr,
+    timing: CsmaTiming,
+    mac_params: MacParams,
+    /// Silence between frames sent back to back
+    inter_frame_gap: usize,
+    stats: LinkStats,
+    aggregation: bool,
+    conv_coding: bool,
+    interleaver: Option<Interleaver>,
+    dup_ack_suppression: std::time::Duration,
+    window: usize,
+    arq_mode: ArqMode,
+    piggyback: bool,
+    /// Keep recording while transmitting
+    full_duplex: bool,
+    /// Subtracts our own transmissions from the recording, None = off
+    echo: Option<EchoCanceller>,
+    /// Round trip through the sound card, allowed for in ack_airtime
+    audio_latency: std::time::Duration,
+    /// Stamp our data frames with when they were queued and sent
+    timestamps: bool,
+    /// Seals our data frames and opens the ones for us, None = in the clear
+    cipher: Option<FrameCipher>,
+    /// Only while running the duplex loop
+    duplex: Option<DuplexInbound>,
+    /// RTS/CTS before bursts longer than this many bytes, None = off
+    rts_threshold: Option<usize>,
+    nav: Nav,
+    max_retries: u32,
+    /// Recorded samples already decoded while sensing the channel, echo
+    /// cancelled
+    sensed: Vec<f32>,
+    discovery: Discovery,
+    /// Negotiate the body rate before sending, answer probes when receiving
+    auto_rate: bool,
+    /// Body rate of our data frames
+    rate: RateCode,
+    noise: NoiseFloor,
+    /// Frames queued besides the data handed to the sender loop
+    queue: TxQueue,
+    /// Link status and CSMA states for --tui, None = no dashboard
+    dashboard: Option<DashboardFeed>,
 }
 
 impl CsmaNode {
@@ -33,406 +245,1528 @@ impl CsmaNode {
         line_coding: LineCodingKind,
         local_mac: mac::types::MacAddr,
         remote_mac: mac::types::MacAddr,
+        phy_params: &PhyParams,
     ) -> Self {
-        let encoder = PhyEncoder::new(
-            SAMPLES_PER_LEVEL,
-            PREAMBLE_PATTERN_BYTES,
-            line_coding,
-        );
-        let decoder = PhyDecoder::new(
-            SAMPLES_PER_LEVEL,
-            PREAMBLE_PATTERN_BYTES,
-            line_coding,
+        Self::with_backend(
+            shared,
+            progress_manager,
+            sample_rate,
+            Box::new(BasebandBackend::new(
+                phy_params,
+                line_coding,
+                FecKind::None,
+                local_mac,
+            )),
             local_mac,
+            remote_mac,
+            phy_params,
+        )
+    }
+
+    /// Create a node on top of any modulation backend, with the default
+    /// MacParams until set_mac_params
+    pub fn with_backend(
+        shared: recorder::AppShared,
+        progress_manager: Arc<Mutex<ProgressManager>>,
+        sample_rate: u32,
+        backend: Box<dyn ModulationBackend>,
+        local_mac: mac::types::MacAddr,
+        remote_mac: mac::types::MacAddr,
+        phy_params: &PhyParams,
+    ) -> Self {
+        let mac_params = MacParams::default();
+        let timing =
+            mac_params.timing(sample_rate, backend.samples_per_symbol());
+        // A slow backend needs longer to get the ACK back to us, and both
+        // receive paths (theirs for the frame, ours for the ACK) add latency
+        let ack_airtime = std::time::Duration::from_secs_f64(
+            (backend.airtime(&Frame::new_ack(0, remote_mac, local_mac))
+                + 2 * backend.rx_latency()) as f64
+                / sample_rate as f64,
+        );
+        info!(
+            "CSMA node using {} backend ({} byte payload limit)",
+            backend.name(),
+            backend.effective_payload_limit()
         );
 
         Self {
             shared,
             progress_manager,
-            encoder,
-            decoder,
+            backend,
             sample_rate,
+            ack_airtime,
             local_addr: local_mac,
             remote_addr: remote_mac,
+            timing,
+            mac_params,
+            inter_frame_gap: phy_params.inter_frame_gap_samples,
+            stats: LinkStats::default(),
+            aggregation: false,
+            conv_coding: false,
+            interleaver: None,
+            dup_ack_suppression: std::time::Duration::from_millis(
+                DUP_ACK_SUPPRESSION_MS,
+            ),
+            window: ARQ_WINDOW,
+            arq_mode: ArqMode::default(),
+            piggyback: false,
+            full_duplex: false,
+            echo: None,
+            audio_latency: std::time::Duration::ZERO,
+            timestamps: delay::timestamps_selected(),
+            cipher: None,
+            duplex: None,
+            rts_threshold: None,
+            nav: Nav::new(),
+            max_retries: MAX_RETRIES,
+            sensed: Vec::new(),
+            discovery: Discovery::new(local_mac, None),
+            auto_rate: false,
+            rate: 0,
+            noise: NoiseFloor::new(phy_params.energy_threshold),
+            queue: TxQueue::new(),
+            dashboard: None,
         }
     }
 
-    pub fn run_sender_loop(
+    /// CSMA timing, contention window and ACK timeout. Set before
+    /// set_dup_ack_suppression, which is capped by the ACK timeout.
+    pub fn set_mac_params(&mut self, mac_params: &MacParams) {
+        self.timing = mac_params.timing(
+            self.sample_rate,
+            self.backend
+                .samples_per_symbol(),
+        );
+        self.mac_params = *mac_params;
+    }
+
+    /// How long to hold back further ACKs for a sequence after re-ACKing a
+    /// duplicate. Capped at half the sender's ACK timeout so the ACK the
+    /// sender is waiting for is never suppressed.
+    pub fn set_dup_ack_suppression(&mut self, interval: std::time::Duration) {
+        let cap = self.mac_params.ack_timeout() / 2;
+        if interval > cap {
+            warn!(
+                "Duplicate ACK suppression {:?} too close to ACK timeout, using {:?}",
+                interval, cap
+            );
+        }
+        self.dup_ack_suppression = interval.min(cap);
+    }
+
+    /// Pack queued chunks into aggregate frames with a single bitmap ACK
+    pub fn set_aggregation(&mut self, enabled: bool) {
+        self.aggregation = enabled;
+    }
+
+    /// Send data frames with a convolutionally coded body (flagged in the
+    /// header, the receiver needs no setting)
+    pub fn set_conv_coding(&mut self, enabled: bool) {
+        self.conv_coding = enabled;
+    }
+
+    /// Interleave frame bodies against burst errors (size is sent in the
+    /// header, the receiver needs no setting)
+    pub fn set_interleaver(&mut self, interleaver: Option<Interleaver>) {
+        if let Some(il) = interleaver {
+            info!("Interleaving frame bodies {}x{}", il.rows(), il.cols());
+        }
+        self.interleaver = interleaver;
+    }
+
+    /// Sliding window, must match on both ends: a sender keeps this many
+    /// data frames in flight, a receiver above 1 answers each burst with one
+    /// cumulative ACK (or NACK). 1 is stop-and-wait.
+    pub fn set_window(&mut self, window: usize) {
+        let clamped = window.clamp(1, ARQ_MAX_WINDOW);
+        if clamped != window {
+            warn!("ARQ window {} out of range, using {}", window, clamped);
+        }
+        self.window = clamped;
+    }
+
+    /// How a window of frames is recovered after losses, must match on both
+    /// ends
+    pub fn set_arq_mode(&mut self, mode: ArqMode) {
+        self.arq_mode = mode;
+    }
+
+    /// In duplex transfers, carry ACKs on outgoing data frames instead of
+    /// sending them on their own
+    pub fn set_piggyback(&mut self, enabled: bool) {
+        self.piggyback = enabled;
+    }
+
+    /// Keep recording while transmitting, so a frame that overlaps ours is
+    /// still heard and the channel is sensed without switching states.
+    /// Our own frames heard back are ignored.
+    pub fn set_full_duplex(&mut self, enabled: bool) {
+        self.full_duplex = enabled;
+    }
+
+    /// Subtract a delayed, scaled copy of what we play from what we record,
+    /// so our own frames are neither decoded nor sensed as traffic
+    pub fn set_echo_cancel(&mut self, enabled: bool) {
+        self.echo = enabled.then(|| EchoCanceller::new(ECHO_MAX_DELAY_SAMPLES));
+    }
+
+    /// Allow for `latency` of round trip through the sound card (see the
+    /// calibrate command) in ACK timeouts and NAVs. Both ends' go by before
+    /// an ACK is back, ours is taken for theirs.
+    pub fn set_audio_latency(&mut self, latency: std::time::Duration) {
+        self.ack_airtime = self
+            .ack_airtime
+            .saturating_sub(2 * self.audio_latency)
+            + 2 * latency;
+        self.audio_latency = latency;
+    }
+
+    /// Timestamp our data frames, for the receiver to measure queueing and
+    /// one-way delays (see mac::delay). Received timestamps are measured
+    /// either way.
+    pub fn set_timestamps(&mut self, enabled: bool) {
+        self.timestamps = enabled;
+    }
+
+    /// Encrypt and authenticate data frames with a pre-shared `key` (see
+    /// mac::crypto). Must match on both ends, frames for us that don't
+    /// authenticate are dropped.
+    pub fn set_key(&mut self, key: Option<[u8; crypto::KEY_BYTES]>) {
+        self.cipher = key
+            .as_ref()
+            .map(FrameCipher::new);
+    }
+
+    /// Data bytes a frame loses to sealing
+    pub fn seal_overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            crypto::SEAL_OVERHEAD
+        } else {
+            0
+        }
+    }
+
+    /// `frame` sealed if we have a key
+    fn sealed(&self, mut frame: Frame) -> Frame {
+        if let Some(cipher) = &self.cipher {
+            cipher.seal(&mut frame);
+        }
+        frame
+    }
+
+    /// Reserve the channel with RTS/CTS before bursts of more than
+    /// `threshold` bytes, and honour reservations overheard from others.
+    /// Must match on both ends.
+    pub fn set_rts_cts(&mut self, threshold: Option<usize>) {
+        self.rts_threshold = threshold;
+    }
+
+    /// Failed exchanges (ACK or CTS timeouts) after which a frame is
+    /// dropped
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// Probe for the fastest reliable body rate before a transfer (sender),
+    /// answer the peer's probes (receiver). Off keeps SAMPLES_PER_LEVEL.
+    pub fn set_auto_rate(&mut self, enabled: bool) {
+        self.auto_rate = enabled;
+    }
+
+    /// Report the channel as jammed once the noise floor has stayed above
+    /// ENERGY_THRESHOLD for `jam_after`
+    pub fn set_jam_detection(&mut self, jam_after: std::time::Duration) {
+        self.noise
+            .set_jam_after(jam_after);
+    }
+
+    /// Also send jam warnings to `alerts`, e.g. for the UI
+    pub fn set_jam_alerts(
         &mut self,
-        tx_timeout: u64,
-        queue: crossbeam_channel::Receiver<Vec<u8>>,
+        alerts: crossbeam_channel::Sender<JamEvent>,
     ) {
-        let overall_start_time = std::time::Instant::now();
-        let mut seq = 0u8;
-        let mut state = mac::CSMAState::Idle;
+        self.noise.set_alerts(alerts);
+    }
 
-        while let Ok(chunk) = queue.recv() {
-            let frame =
-                Frame::new_data(seq, self.local_addr, self.remote_addr, chunk);
-            seq = seq.wrapping_add(1);
-            state = mac::CSMAState::Sensing;
-            *self
+    /// Publish the link status and CSMA state changes to `events`
+    pub fn set_dashboard(
+        &mut self,
+        events: crossbeam_channel::Sender<DashboardEvent>,
+    ) {
+        self.dashboard = Some(DashboardFeed::new(events));
+    }
+
+    /// Beacon every `interval` in the discovery loop, announcing `ip`
+    pub fn set_discovery(
+        &mut self,
+        interval: std::time::Duration,
+        ip: Option<std::net::Ipv4Addr>,
+    ) {
+        self.discovery
+            .set_interval(interval);
+        self.discovery.set_ip(ip);
+    }
+
+    /// Nodes heard recently, from their beacons and traffic
+    pub fn neighbors(&self) -> Vec<Neighbor> {
+        self.discovery
+            .neighbors(std::time::Instant::now())
+    }
+
+    /// Queue `frame` for sending. Control frames go out ahead of the next
+    /// transmission, in the same burst. Data frames join the transfer ahead
+    /// of the next queued chunk and take its sequence number.
+    pub fn enqueue(&self, frame: Frame, priority: Priority) {
+        self.queue
+            .enqueue(frame, priority);
+    }
+
+    /// A handle on the transmit queue, for other threads to enqueue while
+    /// the node runs. The receiver loop sends queued control frames once
+    /// the sender's burst is over.
+    pub fn tx_queue(&self) -> TxQueue {
+        self.queue.clone()
+    }
+
+    /// Contend for the channel and send all queued control frames in one
+    /// burst. Returns false if there were none.
+    fn flush_control(
+        &mut self,
+        deadline: std::time::Instant,
+    ) -> Result<bool, String> {
+        let control = self.queue.drain_control();
+        if control.is_empty() {
+            return Ok(false);
+        }
+        self.acquire_channel(mac::CSMAState::Sensing, 0, deadline)?;
+        self.stats.frames_sent += control.len() as u64;
+        self.play_frames(&control);
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+        Ok(true)
+    }
+
+    /// Play `frames` behind any queued control frames
+    fn send_burst(&mut self, frames: &[Frame]) {
+        let mut burst = self.queue.drain_control();
+        if !burst.is_empty() {
+            debug!("{} control frame(s) ahead of the burst", burst.len());
+            self.stats.frames_sent += burst.len() as u64;
+        }
+        burst.extend_from_slice(frames);
+        self.play_frames(&burst);
+    }
+
+    /// A data frame carrying `chunk` with the configured body coding
+    fn data_frame(&self, seq: SeqType, chunk: Vec<u8>) -> Frame {
+        let mut frame =
+            Frame::new_data(seq, self.local_addr, self.remote_addr, chunk);
+        frame.conv_coded = self.conv_coding;
+        frame.interleave = self.interleaver;
+        frame.rate = self.rate;
+        if self.timestamps {
+            let now = delay::now_ms();
+            frame.timestamp = Some(FrameTimestamp {
+                queued_ms: now,
+                sent_ms: now,
+            });
+        }
+        self.sealed(frame)
+    }
+
+    /// Build the next frame to send, blocking until data is queued.
+    /// Returns the sub-packets carried when aggregating, or None once the
+    /// queue is closed and drained.
+    fn next_frame(
+        &self,
+        seq: SeqType,
+        queue: &crossbeam_channel::Receiver<Vec<u8>>,
+        aggregator: Option<&mut Aggregator>,
+    ) -> Option<(Frame, Vec<SubPacket>)> {
+        if let Some(mut frame) = self.queue.pop_data() {
+            frame.sequence = seq;
+            return Some((self.sealed(frame), Vec::new()));
+        }
+        let Some(aggregator) = aggregator else {
+            let chunk = queue.recv().ok()?;
+            return Some((self.data_frame(seq, chunk), Vec::new()));
+        };
+
+        if aggregator.is_empty() {
+            aggregator.push(queue.recv().ok()?);
+        }
+        while aggregator.wants_more() {
+            match queue.try_recv() {
+                Ok(chunk) => aggregator.push(chunk),
+                Err(_) => break,
+            }
+        }
+
+        let pieces = aggregator.next_aggregate();
+        debug!(
+            "Aggregate seq {} carries sub-packets {:?}",
+            seq,
+            pieces
+                .iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>()
+        );
+        let mut frame = Frame::new(
+            FrameType::Aggregate,
+            seq,
+            self.local_addr,
+            self.remote_addr,
+            aggregation::encode_aggregate(&pieces),
+        );
+        frame.conv_coded = self.conv_coding;
+        frame.interleave = self.interleaver;
+        frame.rate = self.rate;
+        Some((self.sealed(frame), pieces))
+    }
+
+    /// Counters collected by the sender / receiver loops so far
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            crc_failures: self
+                .backend
+                .decode_stats()
+                .map_or(0, |d| d.frames_crc_failed),
+            rx_overflows: self.shared.record_overflows(),
+            tx_underflows: self
                 .shared
-                .app_state
-                .lock()
-                .unwrap() = recorder::AppState::Recording;
-            let mut stage = 0;
-
-            'csma_loop: loop {
-                match state {
-                    mac::CSMAState::Sensing => {
-                        trace!("Sensing channel for idleness...");
-                        std::thread::sleep(std::time::Duration::from_millis(
-                            ENERGY_DETECTION_SAMPLES as u64 * 1000
-                                / self.sample_rate as u64,
-                        ));
-                        let recorded_samples = {
-                            self.shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        };
-                        match mac::is_channel_busy(&recorded_samples) {
-                            Some(true) => {
-                                trace!("Channel busy detected during sensing.");
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
-                            }
-                            Some(false) => {
-                                state = mac::CSMAState::WaitingForDIFS;
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
-                            }
-                            None => {
-                                trace!(
-                                    "Not enough samples to determine channel state during sensing."
-                                );
-                                continue 'csma_loop;
-                            }
+                .playback_underflows(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Receive path statistics, if the backend tracks them
+    pub fn decode_stats(&self) -> Option<DecodeStats> {
+        self.backend.decode_stats()
+    }
+
+    /// Send the dashboard a status if one is due
+    fn publish_status(&mut self) {
+        if !self
+            .dashboard
+            .as_ref()
+            .is_some_and(DashboardFeed::status_due)
+        {
+            return;
+        }
+        let status = LinkStatus::snapshot(
+            &self.shared,
+            self.decode_stats(),
+            self.stats(),
+        );
+        if let Some(feed) = &mut self.dashboard {
+            feed.status(status);
+        }
+    }
+
+    fn publish_csma(&mut self, state: &mac::CSMAState) {
+        if let Some(feed) = &mut self.dashboard {
+            feed.csma(state.name());
+        }
+    }
+
+    /// Note resent frames on the sender bar, if there is one
+    fn note_retransmissions(&self, count: u64, sequence: SeqType) {
+        let _ = self
+            .progress_manager
+            .lock()
+            .unwrap()
+            .note_retransmissions("sender", count, sequence);
+    }
+
+    /// Backoff state with a random counter for contention stage `stage`
+    fn backoff_state(&self, stage: u16) -> mac::CSMAState {
+        let cw = self
+            .mac_params
+            .contention_window(stage);
+        trace!("Random range to {}", cw);
+        mac::CSMAState::Backoff(rand::random_range(0..=cw))
+    }
+
+    /// Run the CSMA/CA state machine from `state` until the channel is ours.
+    /// Returns Err once `deadline` has passed.
+    fn acquire_channel(
+        &mut self,
+        mut state: mac::CSMAState,
+        stage: u16,
+        deadline: std::time::Instant,
+    ) -> Result<(), String> {
+        loop {
+            if std::time::Instant::now() > deadline {
+                return Err("Deadline passed while contending for the channel"
+                    .to_string());
+            }
+            self.publish_csma(&state);
+
+            match state {
+                mac::CSMAState::Sensing => {
+                    trace!("Sensing channel for idleness...");
+                    std::thread::sleep(self.timing.sense);
+                    let recorded_samples = self
+                        .shared
+                        .record_buffer
+                        .peek_all();
+                    match self.is_channel_busy(&recorded_samples) {
+                        Some(true) => {
+                            trace!("Channel busy detected during sensing.");
+                            self.clear_sensed_samples();
                         }
-                    }
-                    mac::CSMAState::Backoff(mut counter) => {
-                        trace!("Backoff counter: {}", counter);
-                        if counter > 0 {
-                            std::thread::sleep(
-                                std::time::Duration::from_millis(SLOT_TIME_MS),
+                        Some(false) => {
+                            state = mac::CSMAState::WaitingForDIFS;
+                            self.clear_sensed_samples();
+                        }
+                        None => {
+                            trace!(
+                                "Not enough samples to determine channel state during sensing."
                             );
-                            match mac::is_channel_busy(&{
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clone()
-                            }) {
-                                Some(true) => {
-                                    trace!(
-                                        "Channel busy detected during backoff."
-                                    );
-                                    state =
-                                        mac::CSMAState::BackoffPaused(counter);
-                                }
-                                Some(false) => {
-                                    // Channel idle, continue countdown
-                                    self.shared
-                                        .record_buffer
-                                        .lock()
-                                        .unwrap()
-                                        .clear();
-                                    counter -= 1;
-                                    state = mac::CSMAState::Backoff(counter);
-                                }
-                                None => {
-                                    trace!(
-                                        "Not enough samples to determine channel state during backoff."
-                                    );
-                                }
-                            }
-                        } else {
-                            state = mac::CSMAState::Transmitting;
+                            continue;
                         }
                     }
-                    mac::CSMAState::BackoffPaused(counter) => {
-                        trace!("Backoff paused at counter {}", counter);
-                        // 等待一个 DIFS 周期
-                        std::thread::sleep(std::time::Duration::from_millis(
-                            DIFS_DURATION_MS,
-                        ));
-                        match mac::is_channel_busy(&{
-                            self.shared
+                }
+                mac::CSMAState::Backoff(mut counter) => {
+                    trace!("Backoff counter: {}", counter);
+                    if counter > 0 {
+                        std::thread::sleep(self.timing.slot);
+                        match self.is_channel_busy(
+                            &self
+                                .shared
                                 .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        }) {
+                                .peek_all(),
+                        ) {
                             Some(true) => {
-                                trace!(
-                                    "Channel still busy during backoff pause."
-                                );
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
+                                trace!("Channel busy detected during backoff.");
                                 state = mac::CSMAState::BackoffPaused(counter);
                             }
                             Some(false) => {
-                                trace!("Channel idle again, resuming backoff.");
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
+                                // Channel idle, continue countdown
+                                self.clear_sensed_samples();
+                                counter -= 1;
                                 state = mac::CSMAState::Backoff(counter);
                             }
                             None => {
                                 trace!(
-                                    "Not enough samples {} to determine channel state during backoff pause.",
-                                    &{
-                                        self.shared
-                                            .record_buffer
-                                            .lock()
-                                            .unwrap()
-                                            .len()
-                                    }
+                                    "Not enough samples to determine channel state during backoff."
                                 );
                             }
                         }
+                    } else {
+                        return Ok(());
                     }
-                    mac::CSMAState::WaitingForDIFS => {
-                        trace!("Channel idle, waiting for DIFS...");
-                        std::thread::sleep(std::time::Duration::from_millis(
-                            DIFS_DURATION_MS,
-                        ));
-
-                        match mac::is_channel_busy(&{
-                            self.shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        }) {
-                            Some(false) => {
-                                trace!(
-                                    "DIFS wait is over and channel is still idle. Starting backoff."
-                                );
-                                let cw = (CW_MIN as u16 * 2_u16 * (stage))
-                                    .min(CW_MAX as u16)
-                                    as usize;
-                                state = mac::CSMAState::Backoff(
-                                    rand::random_range(0..=cw),
-                                );
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
-                            }
-                            Some(true) => {
-                                trace!(
-                                    "Channel became busy during DIFS wait. Returning to sensing."
-                                );
-                                state = mac::CSMAState::Sensing;
+                }
+                mac::CSMAState::BackoffPaused(counter) => {
+                    trace!("Backoff paused at counter {}", counter);
+                    // 等待一个 DIFS 周期
+                    std::thread::sleep(self.timing.difs);
+                    match self.is_channel_busy(
+                        &self
+                            .shared
+                            .record_buffer
+                            .peek_all(),
+                    ) {
+                        Some(true) => {
+                            trace!("Channel still busy during backoff pause.");
+                            self.clear_sensed_samples();
+                            state = mac::CSMAState::BackoffPaused(counter);
+                        }
+                        Some(false) => {
+                            trace!("Channel idle again, resuming backoff.");
+                            self.clear_sensed_samples();
+                            state = mac::CSMAState::Backoff(counter);
+                        }
+                        None => {
+                            trace!(
+                                "Not enough samples {} to determine channel state during backoff pause.",
                                 self.shared
                                     .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clear();
-                            }
-                            None => {
-                                trace!(
-                                    "Not enough samples to determine channel state after DIFS wait."
-                                );
-                            }
+                                    .len()
+                            );
                         }
                     }
-                    mac::CSMAState::Transmitting => {
-                        trace!(
-                            "Channel idle, proceeding to transmit frame seq: {}",
-                            frame.sequence
-                        );
-                        // 1. Encode and send the frame
-                        let output_track = self.encoder.encode_frames(
-                            &[frame.clone()],
-                            INTER_FRAME_GAP_SAMPLES,
-                        );
-                        {
-                            let mut playback = self
-                                .shared
-                                .playback_buffer
-                                .lock()
-                                .unwrap();
-                            playback.clear();
-                            playback.extend(output_track);
-                            {
-                                // Clear previous recordings before listening for ACK
-                                let mut rec_buf = self
-                                    .shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap();
-                                rec_buf.clear();
-                            }
-                        }
-                        *self
+                }
+                mac::CSMAState::WaitingForDIFS => {
+                    trace!("Channel idle, waiting for DIFS...");
+                    std::thread::sleep(self.timing.difs);
+
+                    match self.is_channel_busy(
+                        &self
                             .shared
-                            .app_state
-                            .lock()
-                            .unwrap() = recorder::AppState::Playing;
-
-                        // Wait for playback to finish
-                        while let recorder::AppState::Playing = {
-                            self.shared
-                                .app_state
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        } {
-                            std::thread::sleep(
-                                std::time::Duration::from_millis(1),
+                            .record_buffer
+                            .peek_all(),
+                    ) {
+                        Some(false) => {
+                            trace!(
+                                "DIFS wait is over and channel is still idle. Starting backoff."
                             );
+                            state = self.backoff_state(stage);
+                            self.clear_sensed_samples();
                         }
-                        debug!(
-                            "Frame {} sent, waiting for ACK...",
-                            frame.sequence
-                        );
-
-                        // 2. Switch to recording to wait for ACK
-                        *self
-                            .shared
-                            .app_state
-                            .lock()
-                            .unwrap() = recorder::AppState::Recording;
-                        state = mac::CSMAState::WaitingForAck;
-                    }
-                    mac::CSMAState::WaitingForAck => {
-                        let mut processed_samples_len = 0;
-                        let ack_wait_start = std::time::Instant::now();
-                        // Timeout for ACK
-                        let ack_timeout =
-                            std::time::Duration::from_millis(ACK_TIMEOUT_MS);
-
-                        // 3. ACK waiting loop
-                        'ack_wait_loop: loop {
-                            if ack_wait_start.elapsed() > ack_timeout {
-                                warn!(
-                                    "ACK timeout for seq: {}, stage {}",
-                                    frame.sequence, stage
-                                );
-                                stage = (stage + 1).min(20);
-                                let cw = (CW_MIN as u16 * 2_u16 * (stage))
-                                    .min(CW_MAX as u16)
-                                    as usize; // Not BEB
-                                warn!("Random range to {}", cw);
-                                state = mac::CSMAState::Backoff(
-                                    rand::random_range(0..=cw),
-                                );
-                                break 'ack_wait_loop; // Timed out, retransmit
-                            }
-
-                            std::thread::sleep(
-                                std::time::Duration::from_millis(10),
+                        Some(true) => {
+                            trace!(
+                                "Channel became busy during DIFS wait. Returning to sensing."
                             );
-
-                            let current_samples = {
-                                self.shared
-                                    .record_buffer
-                                    .lock()
-                                    .unwrap()
-                                    .clone()
-                            };
-
-                            if current_samples.len() > processed_samples_len {
-                                let new_samples =
-                                    &current_samples[processed_samples_len..];
-                                let decoded_frames = self
-                                    .decoder
-                                    .process_samples(new_samples);
-                                processed_samples_len = current_samples.len();
-
-                                for ack_frame in decoded_frames {
-                                    if ack_frame.frame_type == FrameType::Ack
-                                        && ack_frame.sequence == frame.sequence
-                                    {
-                                        debug!(
-                                            "ACK received for seq: {}",
-                                            frame.sequence
-                                        );
-                                        // frames_sent += 1;
-                                        self.progress_manager
-                                            .lock()
-                                            .unwrap()
-                                            .inc("sender", 1)
-                                            .unwrap();
-                                        break 'csma_loop; // ACK OK, send next frame
-                                    } else {
-                                        warn!(
-                                            "Received unexpected frame while waiting for ACK {}: type={:?}, seq={}",
-                                            frame.sequence,
-                                            ack_frame.frame_type,
-                                            ack_frame.sequence
-                                        );
-                                    }
-                                }
-                            }
-                        } // end ack_wait_loop
+                            state = mac::CSMAState::Sensing;
+                            self.clear_sensed_samples();
+                        }
+                        None => {
+                            trace!(
+                                "Not enough samples to determine channel state after DIFS wait."
+                            );
+                        }
                     }
-                    mac::CSMAState::Idle => unreachable!(),
-                } // end retransmit_loop
-            } // end csma_loop
-        } // end for frame_to_send
+                }
+                mac::CSMAState::Transmitting => return Ok(()),
+                mac::CSMAState::WaitingForAck
+                | mac::CSMAState::SendingRts
+                | mac::CSMAState::WaitingForCts
+                | mac::CSMAState::Idle => unreachable!(),
+            }
+        }
+    }
+
+    /// Busy while the NAV is set, otherwise energy detection on `samples`
+    /// against the noise floor. The samples are decoded as well to pick up
+    /// NAVs of other exchanges, those without frames update the floor.
+    fn is_channel_busy(&mut self, samples: &[f32]) -> Option<bool> {
+        let now = std::time::Instant::now();
+        if samples.len() < self.sensed.len() {
+            self.sensed.clear();
+        }
+        let fresh = self.cancel_echo(&samples[self.sensed.len()..]);
+        let frames = self.decode_samples(&fresh);
+        if frames.is_empty() {
+            self.noise
+                .observe(&fresh, now);
+        }
+        for frame in frames {
+            if !self.accept_duplex_data(&frame) {
+                debug!(
+                    "Dropping {:?} seq {} decoded while sensing",
+                    frame.frame_type, frame.sequence
+                );
+            }
+        }
+        self.sensed.extend(fresh);
+        let busy =
+            nav::is_channel_busy(&self.sensed, &self.noise, &self.nav, now);
+        if let Some(busy) = busy {
+            metrics::global().record_channel_sense(busy);
+        }
+        self.publish_status();
+        busy
+    }
+
+    fn clear_sensed_samples(&mut self) {
+        self.shared
+            .record_buffer
+            .clear();
+        self.sensed.clear();
+    }
+
+    /// The next recorded `samples`, minus our own transmission if echo
+    /// cancellation is on
+    fn cancel_echo(&mut self, samples: &[f32]) -> Vec<f32> {
+        match &mut self.echo {
+            Some(echo) => echo.cancel(samples),
+            None => samples.to_vec(),
+        }
+    }
+
+    /// Decode the next recorded `samples`. Frames overheard for other
+    /// nodes are not returned, they only set the NAV.
+    fn feed_samples(&mut self, samples: &[f32]) -> Vec<Frame> {
+        let samples = self.cancel_echo(samples);
+        let frames = self.decode_samples(&samples);
+        self.publish_status();
+        frames
+    }
+
+    /// feed_samples on samples already echo cancelled
+    fn decode_samples(&mut self, samples: &[f32]) -> Vec<Frame> {
+        let now = std::time::Instant::now();
+        let mut frames = self
+            .backend
+            .feed_samples(samples);
+        frames.retain_mut(|frame| {
+            // Our own transmission, recorded while playing it
+            if self.full_duplex && frame.src == self.local_addr {
+                self.stats.own_frames_heard += 1;
+                return false;
+            }
+            // Beacons only feed the neighbor table
+            if self
+                .discovery
+                .observe(frame, now)
+            {
+                debug!("Beacon from {}", frame.src);
+                return false;
+            }
+            let foreign = frame.dst != self.local_addr && frame.dst != BROADCAST;
+            if !foreign
+                && let Some(cipher) = &self.cipher
+                && let Err(e) = cipher.open(frame)
+            {
+                warn!(
+                    "Dropping {:?} seq {} from {}: {}",
+                    frame.frame_type, frame.sequence, frame.src, e
+                );
+                self.stats.auth_failures += 1;
+                return false;
+            }
+            if !foreign && let Some(stamp) = frame.timestamp {
+                self.stats
+                    .delays
+                    .record(stamp, delay::now_ms());
+            }
+            if foreign
+                && self
+                    .nav
+                    .observe(frame, self.local_addr, now)
+            {
+                debug!(
+                    "NAV set by {:?} from {} to {} ({} ms)",
+                    frame.frame_type,
+                    frame.src,
+                    frame.dst,
+                    frame.nav_ms.unwrap_or(0)
+                );
+                self.stats.nav_reservations += 1;
+            }
+            !foreign
+        });
+        frames
+    }
+
+    fn airtime(&self, frame: &Frame) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(
+            self.backend.airtime(frame) as f64 / self.sample_rate as f64,
+        )
+    }
+
+    /// Copies of `burst` whose data frames announce the rest of the
+    /// exchange in their NAV: the frames after them and the ACK.
+    /// Aggregates are answered by a bitmap ACK, longer than the ack_airtime
+    /// a NAV counts, and broadcasts are not ACKed, neither gets one.
+    fn stamp_nav(&self, burst: &[Frame]) -> Vec<Frame> {
+        let mut stamped = burst.to_vec();
+        for frame in stamped
+            .iter_mut()
+            .filter(|frame| {
+                frame.frame_type == FrameType::Data && frame.dst != BROADCAST
+            })
+        {
+            // Placeholder so the airtime below counts the NAV bytes
+            frame.nav_ms = Some(0);
+        }
+
+        let gap = std::time::Duration::from_millis(INTER_FRAME_GAP_MS as u64);
+        let mut remaining = self.timing.sifs + self.ack_airtime;
+        for frame in stamped.iter_mut().rev() {
+            if frame.nav_ms.is_some() {
+                frame.nav_ms = Some(nav::duration_ms(remaining));
+            }
+            remaining += self.airtime(frame) + gap;
+        }
+        stamped
+    }
+
+    /// How long an RTS for `burst` reserves the channel once it ends:
+    /// CTS, the burst and the ACK, with a turnaround before each
+    fn reservation(&self, burst: &[Frame]) -> std::time::Duration {
+        let data: std::time::Duration = burst
+            .iter()
+            .map(|frame| self.airtime(frame))
+            .sum();
+        data + 2 * self.ack_airtime + 3 * self.timing.sifs
+    }
+
+    /// With RTS/CTS on and `burst` over the threshold, send an RTS and wait
+    /// for the CTS. Returns false if no CTS came back, the caller backs off.
+    fn reserve_channel(&mut self, burst: &[Frame]) -> bool {
+        let Some(threshold) = self.rts_threshold else {
+            return true;
+        };
+        let dst = burst[0].dst;
+        let bytes: usize = burst
+            .iter()
+            .map(|frame| frame.data.len())
+            .sum();
+        if dst == BROADCAST || bytes <= threshold {
+            return true;
+        }
+
+        let rts = Frame::new_rts(
+            burst[0].sequence,
+            self.local_addr,
+            dst,
+            nav::duration_ms(self.reservation(burst)),
+        );
+        let mut state = mac::CSMAState::SendingRts;
+        let mut processed_samples_len = 0;
+        let mut cts_wait_start = std::time::Instant::now();
+        loop {
+            self.publish_csma(&state);
+            match state {
+                mac::CSMAState::SendingRts => {
+                    debug!("RTS for seq {} ({} bytes)", rts.sequence, bytes);
+                    self.stats.rts_sent += 1;
+                    self.play_frames(std::slice::from_ref(&rts));
+                    self.shared
+                        .app_state
+                        .set(recorder::AppState::Recording);
+                    cts_wait_start = std::time::Instant::now();
+                    state = mac::CSMAState::WaitingForCts;
+                }
+                mac::CSMAState::WaitingForCts => {
+                    if cts_wait_start.elapsed() > self.ack_timeout() {
+                        warn!("CTS timeout for seq: {}", rts.sequence);
+                        self.stats.cts_timeouts += 1;
+                        return false;
+                    }
+                    for reply in self.poll_frames(&mut processed_samples_len) {
+                        if reply.frame_type == FrameType::Cts
+                            && reply.src == dst
+                            && reply.sequence == rts.sequence
+                        {
+                            debug!("CTS received for seq: {}", rts.sequence);
+                            return true;
+                        }
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Put `frames` on the air back-to-back and wait until playback is done
+    fn play_frames(&mut self, frames: &[Frame]) {
+        // Sent now, again for retransmissions
+        let sent_ms = delay::now_ms();
+        let frames: Vec<Frame> = frames
+            .iter()
+            .cloned()
+            .map(|mut frame| {
+                if let Some(stamp) = &mut frame.timestamp {
+                    stamp.sent_ms = sent_ms;
+                }
+                frame
+            })
+            .collect();
+        metrics::global()
+            .frames_tx
+            .add(frames.len() as u64);
+        self.publish_csma(&mac::CSMAState::Transmitting);
+        let mut samples = Vec::new();
+        for chunk in self
+            .backend
+            .encode_frames_iter(&frames, self.inter_frame_gap)
+        {
+            self.stats.tx_airtime_samples += chunk.len() as u64;
+            samples.extend(chunk);
+        }
+        // Clear previous recordings before listening for ACK
+        self.shared
+            .record_buffer
+            .clear();
+        self.play(&samples);
+    }
+
+    /// Play `samples` and wait until they are out, recording all along in
+    /// full duplex. The echo canceller expects them in what is recorded next.
+    fn play(&mut self, samples: &[f32]) {
+        if let Some(echo) = &mut self.echo {
+            let lead = if self.full_duplex { 0 } else { samples.len() };
+            echo.start(samples, lead);
+        }
+        if self.full_duplex {
+            self.shared
+                .play_recording(samples);
+        } else {
+            self.shared.play(samples);
+        }
+    }
+
+    /// Sleep briefly, then decode what was recorded past `processed` samples
+    fn poll_frames(&mut self, processed: &mut usize) -> Vec<Frame> {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        if self
+            .shared
+            .record_buffer
+            .len()
+            < *processed
+        {
+            // The buffer was cleared by one of our transmissions
+            *processed = 0;
+        }
+        let new_samples = self
+            .shared
+            .record_buffer
+            .peek_from(*processed);
+        if new_samples.is_empty() {
+            return Vec::new();
+        }
+        *processed += new_samples.len();
+        self.feed_samples(&new_samples)
+    }
+
+    /// How long to wait for an ACK after our transmission ended
+    fn ack_timeout(&self) -> std::time::Duration {
+        self.mac_params.ack_timeout() + self.ack_airtime
+    }
+
+    /// Contend for the channel, transmit `frame` and wait for its ACK,
+    /// retransmitting after every ACK timeout until `deadline`.
+    /// Broadcast frames are sent once and return None, nobody ACKs them.
+    /// Gives up after `max_retries` failed exchanges.
+    /// Other frames decoded while waiting are appended to `others`.
+    fn transmit_until_acked(
+        &mut self,
+        frame: &Frame,
+        deadline: std::time::Instant,
+        others: &mut Vec<Frame>,
+    ) -> Result<Option<Frame>, TxError> {
+        let mut state = mac::CSMAState::Sensing;
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+        let mut stage = 0;
+        let mut attempts = 0;
+        let mut retries = 0;
+        let mut sent_at = std::time::Instant::now();
+
+        loop {
+            if retries > self.max_retries {
+                warn!(
+                    "Dropping seq {} after {} failed attempts",
+                    frame.sequence, retries
+                );
+                self.stats.frames_dropped += 1;
+                return Err(TxError::RetriesExceeded {
+                    sequence: frame.sequence,
+                    attempts: retries,
+                });
+            }
+
+            if let mac::CSMAState::WaitingForAck = state {
+                self.publish_csma(&state);
+                // 3. ACK waiting loop
+                let mut processed_samples_len = 0;
+                let ack_wait_start = std::time::Instant::now();
+                while ack_wait_start.elapsed() <= self.ack_timeout() {
+                    for ack_frame in self.poll_frames(&mut processed_samples_len)
+                    {
+                        // Standalone, or piggybacked on the peer's data
+                        let acked = ack_frame.src == frame.dst
+                            && (ack_frame.frame_type == FrameType::Ack
+                                && ack_frame.sequence == frame.sequence
+                                || ack_frame.piggyback_ack
+                                    == Some(frame.sequence));
+                        let consumed = self.accept_duplex_data(&ack_frame);
+
+                        if acked {
+                            debug!("ACK received for seq: {}", frame.sequence);
+                            self.stats.acks_received += 1;
+                            self.stats
+                                .record_rtt(sent_at.elapsed());
+                            metrics::global().record_rtt(sent_at.elapsed());
+                            return Ok(Some(ack_frame)); // ACK OK
+                        } else if !consumed {
+                            warn!(
+                                "Received unexpected frame while waiting for ACK {}: type={:?}, seq={}",
+                                frame.sequence,
+                                ack_frame.frame_type,
+                                ack_frame.sequence
+                            );
+                            others.push(ack_frame);
+                        }
+                    }
+
+                    // The peer may be waiting for our ACK just as we wait
+                    // for theirs
+                    if self.flush_pending_ack() {
+                        processed_samples_len = 0;
+                    }
+                }
+
+                // Timed out, retransmit
+                warn!(
+                    "ACK timeout for seq: {}, stage {}",
+                    frame.sequence, stage
+                );
+                self.stats.ack_timeouts += 1;
+                retries += 1;
+                stage = (stage + 1).min(20);
+                state = self.backoff_state(stage);
+                continue;
+            }
+
+            self.acquire_channel(state, stage, deadline)
+                .map_err(|_| TxError::Deadline {
+                    sequence: frame.sequence,
+                })?;
+            if !self.reserve_channel(std::slice::from_ref(frame)) {
+                retries += 1;
+                stage = (stage + 1).min(20);
+                state = self.backoff_state(stage);
+                continue;
+            }
+            trace!(
+                "Channel idle, proceeding to transmit frame seq: {}",
+                frame.sequence
+            );
+            // 1. Encode and send the frame
+            self.stats.frames_sent += 1;
+            if attempts > 0 {
+                self.stats.retransmissions += 1;
+                self.note_retransmissions(1, frame.sequence);
+            }
+            attempts += 1;
+            sent_at = std::time::Instant::now();
+            self.send_burst(&self.stamp_nav(std::slice::from_ref(frame)));
+            if frame.dst == BROADCAST {
+                debug!("Broadcast frame {} sent", frame.sequence);
+                return Ok(None);
+            }
+            debug!("Frame {} sent, waiting for ACK...", frame.sequence);
+
+            // 2. Switch to recording to wait for ACK
+            self.shared
+                .app_state
+                .set(recorder::AppState::Recording);
+            state = mac::CSMAState::WaitingForAck;
+        }
+    }
+
+    /// In a duplex transfer, take in a data frame from the peer: deliver it
+    /// once and owe an ACK for it. Returns false for any other frame.
+    fn accept_duplex_data(&mut self, frame: &Frame) -> bool {
+        let Some(duplex) = self.duplex.as_mut() else {
+            return false;
+        };
+        if frame.frame_type != FrameType::Data
+            || frame.src != self.remote_addr
+            || frame.dst != self.local_addr
+        {
+            return false;
+        }
+
+        self.stats.frames_received += 1;
+        if duplex
+            .received
+            .insert(frame.sequence)
+        {
+            debug!("Received new DATA frame with seq: {}", frame.sequence);
+            duplex
+                .delivered
+                .send(frame.data.clone())
+                .unwrap_or_else(|err| {
+                    error!("Error while sending received frame: {:?}", err)
+                });
+        } else {
+            debug!("Received duplicate DATA frame with seq: {}", frame.sequence);
+            self.stats.duplicate_frames += 1;
+        }
+        duplex
+            .pending
+            .defer(frame.sequence, std::time::Instant::now());
+        true
+    }
+
+    /// Send the owed duplex ACK on its own once it has waited long enough
+    /// for data to carry it. Returns true if an ACK was played.
+    fn flush_pending_ack(&mut self) -> bool {
+        let Some(seq) = self
+            .duplex
+            .as_mut()
+            .and_then(|duplex| {
+                duplex
+                    .pending
+                    .take_expired(std::time::Instant::now())
+            })
+        else {
+            return false;
+        };
+        debug!("No data to carry ACK {}, sending it standalone", seq);
+        let ack_frame = Frame::new_ack(seq, self.local_addr, self.remote_addr);
+        self.send_ack(&ack_frame);
+        true
+    }
+
+    /// Play an ACK right away (no contention), then go back to recording
+    fn send_ack(&mut self, ack_frame: &Frame) {
+        let ack_track = self
+            .backend
+            .encode_frame(ack_frame);
+        match ack_frame.frame_type {
+            FrameType::Nack => self.stats.nacks_sent += 1,
+            FrameType::Cts => self.stats.cts_sent += 1,
+            _ => self.stats.acks_sent += 1,
+        }
+        self.stats.tx_airtime_samples += ack_track.len() as u64;
+        metrics::global()
+            .frames_tx
+            .inc();
+        self.publish_csma(&mac::CSMAState::Transmitting);
+
+        // Play the ACK and wait for it to complete. In full duplex what was
+        // heard meanwhile is kept, older samples are dropped either way.
+        if self.full_duplex {
+            self.shared
+                .record_buffer
+                .clear();
+            self.play(&ack_track);
+        } else {
+            self.play(&ack_track);
+            self.shared
+                .record_buffer
+                .clear();
+        }
+
+        // After sending ACK, switch back to recording for the next frame
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+        self.publish_csma(&mac::CSMAState::Idle);
+        debug!("Switched back to recording mode.");
+    }
+
+    /// Probe faster body rates with the peer until one is unreliable, see
+    /// mac::autorate. Returns the fastest reliable rate, 0 if none is.
+    fn negotiate_rate(&mut self, deadline: std::time::Instant) -> RateCode {
+        let mut prober = RateProber::new();
+        while let Some(rate) = prober.current() {
+            let probes =
+                autorate::probe_burst(self.local_addr, self.remote_addr, rate);
+            if self
+                .acquire_channel(mac::CSMAState::Sensing, 0, deadline)
+                .is_err()
+            {
+                break;
+            }
+            debug!("Probing rate {} with {} frames", rate, probes.len());
+            self.stats.frames_sent += probes.len() as u64;
+            self.play_frames(&probes);
+            self.shared
+                .app_state
+                .set(recorder::AppState::Recording);
+
+            let mut report = None;
+            let mut processed_samples_len = 0;
+            let wait_start = std::time::Instant::now();
+            while report.is_none() && wait_start.elapsed() <= self.ack_timeout()
+            {
+                report = self
+                    .poll_frames(&mut processed_samples_len)
+                    .into_iter()
+                    .filter(|reply| {
+                        reply.frame_type == FrameType::Ack
+                            && reply.src == self.remote_addr
+                    })
+                    .find_map(|reply| ProbeReport::from_bytes(&reply.data).ok());
+            }
+            if report.is_none() {
+                warn!("No probe report for rate {}", rate);
+            }
+            prober.on_report(report);
+        }
+
+        let rate = prober.chosen();
+        info!(
+            "Sending at rate {} ({} samples per level)",
+            rate,
+            samples_per_level(
+                rate,
+                self.backend
+                    .samples_per_symbol()
+            )
+            .unwrap_or(0)
+        );
+        rate
+    }
+
+    /// Send everything from `queue`, one frame at a time.
+    /// Frames dropped after `max_retries` are reported on `failures`.
+    /// Returns Err if the transfer did not finish within `tx_timeout` seconds.
+    pub fn run_sender_loop(
+        &mut self,
+        tx_timeout: u64,
+        queue: crossbeam_channel::Receiver<Vec<u8>>,
+        failures: crossbeam_channel::Sender<TxError>,
+    ) -> Result<(), String> {
+        let overall_start_time = std::time::Instant::now();
+        let deadline =
+            overall_start_time + std::time::Duration::from_secs(tx_timeout);
+        let mut seq: SeqType = 0;
+        let mut aggregator = self.aggregation.then(|| {
+            Aggregator::new(AGGREGATE_MAX_BYTES - self.seal_overhead())
+        });
+        let mut result = Ok(());
+
+        self.rate = 0;
+        if self.auto_rate && self.remote_addr != BROADCAST {
+            self.rate = self.negotiate_rate(deadline);
+        }
+
+        // Aggregates keep their bitmap ACKs and broadcasts are never ACKed,
+        // both stay stop-and-wait
+        let windowed = self.window > 1
+            && aggregator.is_none()
+            && self.remote_addr != BROADCAST;
+        if windowed {
+            info!(
+                "{} with a window of {} frames",
+                self.arq_mode.name(),
+                self.window
+            );
+            if let Err(e) =
+                self.run_sliding_window(&queue, deadline, &mut seq, &failures)
+            {
+                error!("Transmit timeout ({}s): {}", tx_timeout, e);
+                result = Err(format!(
+                    "Transmit timeout after {}s, {}",
+                    tx_timeout, e
+                ));
+            }
+        }
+
+        while !windowed
+            && let Some((frame, pieces)) =
+                self.next_frame(seq, &queue, aggregator.as_mut())
+        {
+            seq = seq.wrapping_add(1);
+
+            let ack_frame = match self.transmit_until_acked(
+                &frame,
+                deadline,
+                &mut Vec::new(),
+            ) {
+                Ok(ack_frame) => ack_frame,
+                Err(e @ TxError::RetriesExceeded { .. }) => {
+                    error!("{}, moving on", e);
+                    failures
+                        .send(e)
+                        .unwrap_or_else(|err| {
+                            debug!("Failure not reported: {:?}", err)
+                        });
+                    continue;
+                }
+                Err(e) => {
+                    error!("Transmit timeout ({}s): {}", tx_timeout, e);
+                    result = Err(format!(
+                        "Transmit timeout after {}s, seq {} not acknowledged",
+                        tx_timeout, frame.sequence
+                    ));
+                    break;
+                }
+            };
+
+            let delivered = match aggregator.as_mut() {
+                Some(aggregator) => {
+                    // A broadcast aggregate counts as delivered in full
+                    let status = match &ack_frame {
+                        Some(ack_frame) => aggregation::decode_ack_bitmap(
+                            &ack_frame.data,
+                            pieces.len(),
+                        )
+                        .unwrap_or_default(),
+                        None => vec![true; pieces.len()],
+                    };
+                    aggregator.on_ack(&pieces, &status)
+                }
+                None => 1,
+            };
+            self.progress_manager
+                .lock()
+                .unwrap()
+                .inc("sender", delivered as u64)
+                .unwrap();
+        } // end for frame_to_send
 
-        self.progress_manager
-            .lock()
-            .unwrap()
-            .finish("sender", "All frames acknowledged")
-            .unwrap();
         let total_duration = overall_start_time
             .elapsed()
             .as_secs_f32();
+        if result.is_err() {
+            self.progress_manager
+                .lock()
+                .unwrap()
+                .finish("sender", "Timed out")
+                .unwrap();
+            // Explain why the channel never came free
+            if self.noise.is_jammed() {
+                return result.map_err(|e| format!("{}, channel jammed", e));
+            }
+            return result;
+        }
+
+        self.progress_manager
+            .lock()
+            .unwrap()
+            .finish("sender", "All frames acknowledged")
+            .unwrap();
         info!(
             "🎉 All {} frames transmitted and acknowledged in {:.2} seconds.",
             seq, total_duration
         );
+        result
     }
 
+    /// Sliding window sender: keep up to `window` data frames in flight,
+    /// send new ones in a single burst per channel access and recover
+    /// losses per the ARQ mode (see mac::arq).
+    /// `seq` ends up as the number of frames sent.
+    /// Frames cannot be skipped within a window, so the whole transfer is
+    /// given up once `max_retries` timeouts pass without progress.
+    fn run_sliding_window(
+        &mut self,
+        queue: &crossbeam_channel::Receiver<Vec<u8>>,
+        deadline: std::time::Instant,
+        seq: &mut SeqType,
+        failures: &crossbeam_channel::Sender<TxError>,
+    ) -> Result<(), String> {
+        let mut arq = ArqSender::new(self.arq_mode, self.window);
+        let mut closed = false;
+        let mut stage = 0;
+        let mut retries = 0;
+        let mut processed_samples_len = 0;
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+
+        loop {
+            // Refill the window, only block while nothing is in flight
+            while arq.has_room()
+                && let Some(mut frame) = self.queue.pop_data()
+            {
+                frame.sequence = *seq;
+                arq.push(frame);
+                *seq = seq.wrapping_add(1);
+            }
+            while !closed && arq.has_room() {
+                let chunk = if arq.is_empty() {
+                    queue.recv().ok()
+                } else {
+                    match queue.try_recv() {
+                        Ok(chunk) => Some(chunk),
+                        Err(crossbeam_channel::TryRecvError::Empty) => break,
+                        Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                            None
+                        }
+                    }
+                };
+                let Some(chunk) = chunk else {
+                    closed = true;
+                    break;
+                };
+                arq.push(self.data_frame(*seq, chunk));
+                *seq = seq.wrapping_add(1);
+            }
+            if arq.is_empty() {
+                return Ok(());
+            }
+
+            let burst = arq.unsent();
+            if retries > self.max_retries {
+                let e = TxError::RetriesExceeded {
+                    sequence: burst[0].sequence,
+                    attempts: retries,
+                };
+                self.stats.frames_dropped += 1;
+                failures
+                    .send(e.clone())
+                    .unwrap_or_else(|err| {
+                        debug!("Failure not reported: {:?}", err)
+                    });
+                return Err(e.to_string());
+            }
+            if !burst.is_empty() {
+                let state = if stage == 0 {
+                    mac::CSMAState::Sensing
+                } else {
+                    self.backoff_state(stage)
+                };
+                self.acquire_channel(state, stage, deadline)
+                    .map_err(|_| {
+                        format!("seq {} not acknowledged", burst[0].sequence)
+                    })?;
+                if !self.reserve_channel(&burst) {
+                    retries += 1;
+                    stage = (stage + 1).min(20);
+                    continue;
+                }
+                debug!(
+                    "Sending seq {:?} ({} frames)",
+                    burst
+                        .iter()
+                        .map(|f| f.sequence)
+                        .collect::<Vec<_>>(),
+                    burst.len()
+                );
+                self.stats.frames_sent += burst.len() as u64;
+                self.send_burst(&self.stamp_nav(&burst));
+                processed_samples_len = 0;
+                // Resent frames come first in a burst
+                let resent = arq.mark_sent(std::time::Instant::now()) as u64;
+                self.stats.retransmissions += resent;
+                if resent > 0 {
+                    self.note_retransmissions(resent, burst[0].sequence);
+                }
+                self.publish_csma(&mac::CSMAState::WaitingForAck);
+                self.shared
+                    .app_state
+                    .set(recorder::AppState::Recording);
+            }
+
+            // Wait for an ACK / NACK or the oldest frame's timeout; the
+            // deadline is checked on the next channel access. Samples
+            // decoded here stay decoded across bursts, or a replayed ACK
+            // would count as a duplicate.
+            loop {
+                let requeued = arq
+                    .on_timeout(std::time::Instant::now(), self.ack_timeout());
+                if requeued > 0 {
+                    self.stats.ack_timeouts += 1;
+                    retries += 1;
+                    stage = (stage + 1).min(20);
+                    warn!(
+                        "ACK timeout, resending {} frames, stage {}",
+                        requeued, stage
+                    );
+                    break;
+                }
+
+                let mut released = 0;
+                let duplicate_acks = arq.duplicate_acks();
+                for reply in self.poll_frames(&mut processed_samples_len) {
+                    if reply.src != self.remote_addr {
+                        continue;
+                    }
+                    match reply.frame_type {
+                        // ACKs carrying data are replies, see await_reply
+                        FrameType::Ack if reply.data.is_empty() => {
+                            self.stats.acks_received += 1;
+                            released += arq.on_ack(reply.sequence);
+                        }
+                        FrameType::Nack => {
+                            self.stats.nacks_received += 1;
+                            let missing = arq::decode_nack_bitmap(&reply.data)
+                                .unwrap_or_default();
+                            debug!(
+                                "NACK from seq {}: {:?}",
+                                reply.sequence, missing
+                            );
+                            released += arq.on_nack(reply.sequence, &missing);
+                        }
+                        _ => {}
+                    }
+                }
+                if released > 0 {
+                    debug!("ACK released {} frames", released);
+                    stage = 0;
+                    retries = 0;
+                    self.progress_manager
+                        .lock()
+                        .unwrap()
+                        .inc("sender", released as u64)
+                        .unwrap();
+                }
+                let fast = arq.fast_retransmit();
+                if fast > 0 {
+                    self.stats.fast_retransmits += 1;
+                    warn!(
+                        "{} duplicate ACKs, fast retransmit of {} frames, window {}",
+                        FAST_RETRANSMIT_DUP_ACKS,
+                        fast,
+                        arq.window()
+                    );
+                }
+                // A new duplicate ACK makes room for one more frame
+                let room_for_new = !closed
+                    && arq.duplicate_acks() > duplicate_acks
+                    && arq.has_room();
+                if released > 0 || room_for_new || !arq.unsent().is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Listen up to `timeout` for the peer's reply after a transfer, an ACK
+    /// carrying data queued by its receiver (see tx_queue). Returns the data.
+    pub fn await_reply(
+        &mut self,
+        timeout: std::time::Duration,
+    ) -> Option<Vec<u8>> {
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+        let start = std::time::Instant::now();
+        let mut processed_samples_len = 0;
+        while start.elapsed() < timeout {
+            for frame in self.poll_frames(&mut processed_samples_len) {
+                if frame.src == self.remote_addr
+                    && frame.frame_type == FrameType::Ack
+                    && !frame.data.is_empty()
+                {
+                    return Some(frame.data);
+                }
+            }
+        }
+        None
+    }
+
+    /// Receive until `rx_duration` seconds elapsed or recording stops.
+    /// Returns Err if the user interrupted the session.
     pub fn run_receiver_loop(
         &mut self,
         max_recording_duration_samples: u32,
         rx_duration: u64,
         tx: crossbeam_channel::Sender<Vec<u8>>,
-    ) {
+    ) -> Result<(), String> {
         info!("=== Receiver Mode ===");
 
-        let mut received_sequences = std::collections::HashSet::new();
-        let mut processed_samples_len = 0;
+        let mut received_sequences = SequenceWindow::new(RX_SEQUENCE_WINDOW);
+        let mut unique_frames = 0usize;
+        let mut deaggregator = Deaggregator::new();
+        let mut ack_suppressor = AckSuppressor::new(self.dup_ack_suppression);
+        let windowed = self.window > 1;
+        let mut arq = ArqReceiver::new(self.arq_mode);
+        let mut pending_ack: Option<Frame> = None;
+        let mut probes = ProbeCollector::new();
+        // Decoder and xrun counters cover this session only
+        self.backend
+            .reset_decode_stats();
+        self.shared.reset_xruns();
 
-        *self
-            .shared
+        self.shared
             .app_state
-            .lock()
-            .unwrap() = recorder::AppState::Recording;
+            .set(recorder::AppState::Recording);
 
         let start_time = std::time::Instant::now();
         let recording_timeout = std::time::Duration::from_secs(rx_duration);
+        let stats_log_interval = std::time::Duration::from_secs(5);
+        let mut last_stats_log = start_time;
 
         let running = Arc::new(AtomicBool::new(true));
         let r = running.clone();
 
         // Ctrl+C 设置标志
-        ctrlc::set_handler(move || {
+        if let Err(e) = ctrlc::set_handler(move || {
             r.store(false, Ordering::SeqCst);
-        })
-        .expect("Error setting Ctrl-C handler");
+        }) {
+            warn!("Ctrl+C won't stop cleanly: {}", e);
+        }
+
+        let mut result = Ok(());
 
         'main_loop: loop {
             if !running.load(Ordering::SeqCst) {
+                result = Err("Interrupted by user".to_string());
                 break;
             }
 
@@ -448,118 +1782,240 @@ impl CsmaNode {
             if self
                 .shared
                 .record_buffer
-                .lock()
-                .unwrap()
                 .len()
                 > 50
             {
                 let new_samples = &self
                     .shared
                     .record_buffer
-                    .lock()
-                    .unwrap()
-                    .drain(..)
-                    .collect::<Vec<_>>()[..];
-                let decoded_frames = self
-                    .decoder
-                    .process_samples(new_samples);
-                processed_samples_len += new_samples.len();
+                    .pop_all()[..];
+                let decoded_frames = self.feed_samples(new_samples);
+                if decoded_frames.is_empty() {
+                    self.noise
+                        .observe(new_samples, std::time::Instant::now());
+                }
+                let burst_over = decoded_frames.is_empty()
+                    && self
+                        .noise
+                        .is_channel_busy(new_samples)
+                        == Some(false);
 
+                // Probes of a batch share the decoder's latest estimate
+                let snr_db = self
+                    .backend
+                    .decode_stats()
+                    .map(|stats| stats.last_snr_db);
                 for frame in decoded_frames {
-                    if frame.frame_type == FrameType::Data {
-                        if !received_sequences.contains(&frame.sequence) {
-                            debug!(
-                                "Received new DATA frame with seq: {}",
-                                frame.sequence
-                            );
-                            // Store data and mark sequence as received
-                            tx.send(frame.data).unwrap_or_else(|err| {
-                                error!("Error while sending received frame: {:?}", err)
-                            });
-                            received_sequences.insert(frame.sequence);
-                        } else {
-                            info!(
-                                "Received duplicate DATA frame with seq: {}, re-sending ACK.",
-                                frame.sequence
-                            );
+                    let ack_frame = match frame.frame_type {
+                        FrameType::Probe => {
+                            if self.auto_rate {
+                                probes.observe(&frame, snr_db);
+                            }
+                            continue;
                         }
-
-                        // Always send an ACK for a data frame
-                        debug!("Sending ACK for seq: {}", frame.sequence);
-                        let ack_frame = Frame::new_ack(
-                            frame.sequence,
-                            self.local_addr,
-                            self.remote_addr,
-                        );
-                        let ack_track = self
-                            .encoder
-                            .encode_frames(&[ack_frame], 0);
-
-                        // Put ACK in playback buffer
+                        FrameType::Data
+                            if windowed && frame.dst != BROADCAST =>
                         {
-                            let mut playback = self
-                                .shared
-                                .playback_buffer
-                                .lock()
-                                .unwrap();
-                            playback.clear();
-                            playback.extend(ack_track);
+                            self.stats.frames_received += 1;
+                            let seq = frame.sequence;
+                            match arq.receive(seq, frame.data) {
+                                Arrival::Delivered(ready) => {
+                                    unique_frames += ready.len();
+                                    debug!(
+                                        "Received new DATA frame with seq: {}, delivering {}",
+                                        seq,
+                                        ready.len()
+                                    );
+                                    for data in ready {
+                                        tx.send(data).unwrap_or_else(|err| {
+                                            error!("Error while sending received frame: {:?}", err)
+                                        });
+                                    }
+                                }
+                                Arrival::Buffered => {
+                                    debug!(
+                                        "Buffering out-of-order DATA frame with seq: {}",
+                                        seq
+                                    );
+                                    self.stats.out_of_order_frames += 1;
+                                }
+                                Arrival::Duplicate => {
+                                    debug!(
+                                        "Received duplicate DATA frame with seq: {}",
+                                        seq
+                                    );
+                                    self.stats.duplicate_frames += 1;
+                                }
+                                Arrival::Dropped => {
+                                    debug!(
+                                        "Dropping out-of-order DATA frame with seq: {}",
+                                        seq
+                                    );
+                                    self.stats.out_of_order_frames += 1;
+                                }
+                            }
+
+                            // One ACK / NACK once the sender's burst is over
+                            pending_ack =
+                                arq.feedback(self.local_addr, self.remote_addr);
+                            continue;
                         }
+                        FrameType::Data => {
+                            self.stats.frames_received += 1;
+                            let duplicate =
+                                !received_sequences.insert(frame.sequence);
+                            if !duplicate {
+                                unique_frames += 1;
+                                debug!(
+                                    "Received new DATA frame with seq: {}",
+                                    frame.sequence
+                                );
+                                // Store data
+                                tx.send(frame.data).unwrap_or_else(|err| {
+                                    error!("Error while sending received frame: {:?}", err)
+                                });
+                            } else {
+                                info!(
+                                    "Received duplicate DATA frame with seq: {}, re-sending ACK.",
+                                    frame.sequence
+                                );
+                                self.stats.duplicate_frames += 1;
+                            }
+                            if frame.dst == BROADCAST {
+                                continue;
+                            }
 
-                        // Switch to playing state
-                        *self
-                            .shared
-                            .app_state
-                            .lock()
-                            .unwrap() = recorder::AppState::Playing;
-
-                        // Wait for ACK playback to complete
-                        while let recorder::AppState::Playing = {
-                            self.shared
-                                .app_state
-                                .lock()
-                                .unwrap()
-                                .clone()
-                        } {
-                            std::thread::sleep(
-                                std::time::Duration::from_millis(1),
-                            );
+                            // ACK every data frame, except duplicates that
+                            // were just re-ACKed
+                            if !ack_suppressor.should_ack(
+                                frame.sequence,
+                                duplicate,
+                                std::time::Instant::now(),
+                            ) {
+                                debug!(
+                                    "Suppressing duplicate ACK for seq: {}",
+                                    frame.sequence
+                                );
+                                self.stats.acks_suppressed += 1;
+                                continue;
+                            }
+                            Frame::new_ack(
+                                frame.sequence,
+                                self.local_addr,
+                                self.remote_addr,
+                            )
                         }
-                        debug!("ACK sent for seq: {}", frame.sequence);
+                        FrameType::Aggregate => {
+                            self.stats.frames_received += 1;
+                            let (pieces, status) =
+                                aggregation::decode_aggregate(&frame.data);
+                            debug!(
+                                "Received AGGREGATE seq: {}, sub-packet status {:?}",
+                                frame.sequence, status
+                            );
+                            for data in deaggregator.accept(pieces) {
+                                tx.send(data).unwrap_or_else(|err| {
+                                    error!("Error while sending received frame: {:?}", err)
+                                });
+                            }
+                            if received_sequences.insert(frame.sequence) {
+                                unique_frames += 1;
+                            }
+                            if frame.dst == BROADCAST {
+                                continue;
+                            }
 
-                        {
-                            self.shared
-                                .record_buffer
-                                .lock()
-                                .unwrap()
-                                .clear();
+                            // One ACK for the whole aggregate
+                            Frame::new_ack_mix(
+                                frame.sequence,
+                                self.local_addr,
+                                self.remote_addr,
+                                aggregation::encode_ack_bitmap(&status),
+                            )
+                        }
+                        FrameType::Rts if self.rts_threshold.is_some() => {
+                            if self
+                                .nav
+                                .is_active(std::time::Instant::now())
+                            {
+                                debug!(
+                                    "NAV set, not answering RTS seq: {}",
+                                    frame.sequence
+                                );
+                                continue;
+                            }
+                            // What is left once this CTS is over
+                            let remaining = std::time::Duration::from_millis(
+                                frame.nav_ms.unwrap_or(0) as u64,
+                            )
+                            .saturating_sub(self.ack_airtime + self.timing.sifs);
+                            Frame::new_cts(
+                                frame.sequence,
+                                self.local_addr,
+                                frame.src,
+                                nav::duration_ms(remaining),
+                            )
                         }
+                        FrameType::Ack
+                        | FrameType::Nack
+                        | FrameType::Rts
+                        | FrameType::Cts
+                        | FrameType::Beacon => continue,
+                    };
 
-                        // After sending ACK, switch back to recording for the next frame
-                        *self
-                            .shared
-                            .app_state
-                            .lock()
-                            .unwrap() = recorder::AppState::Recording;
-                        debug!("Switched back to recording mode.");
-                    }
+                    debug!("Sending ACK for seq: {}", frame.sequence);
+                    self.send_ack(&ack_frame);
+                    debug!("ACK sent for seq: {}", frame.sequence);
                 } // end for frame
+
+                if burst_over && let Some(ack_frame) = pending_ack.take() {
+                    debug!(
+                        "Sending {:?} for seq: {}",
+                        ack_frame.frame_type, ack_frame.sequence
+                    );
+                    self.send_ack(&ack_frame);
+                }
+                if burst_over {
+                    // Replies queued from other threads, e.g. a file
+                    // transfer verdict
+                    for reply in self.queue.drain_control() {
+                        self.send_ack(&reply);
+                    }
+                }
+                if burst_over && let Some((peer, report)) = probes.take_report()
+                {
+                    info!("Probe report for {}: {:?}", peer, report);
+                    self.send_ack(&Frame::new_ack_mix(
+                        0,
+                        self.local_addr,
+                        peer,
+                        report.to_bytes(),
+                    ));
+                }
             } // end if new samples
 
             self.progress_manager
                 .lock()
                 .unwrap()
-                .set_position("recording", processed_samples_len as u64)
+                .set_position("receiver", unique_frames as u64)
                 .unwrap();
 
+            if last_stats_log.elapsed() >= stats_log_interval {
+                if let Some(stats) = self.backend.decode_stats() {
+                    info!("Decoder: {}", stats);
+                    debug!(
+                        "Decoder buffer: {}/{} samples (peak {}, discarded {})",
+                        stats.buffered_samples,
+                        stats.max_buffered_samples,
+                        stats.peak_buffered_samples,
+                        stats.discarded_samples
+                    );
+                }
+                last_stats_log = std::time::Instant::now();
+            }
+
             // Check if user manually stopped
-            let state = {
-                self.shared
-                    .app_state
-                    .lock()
-                    .unwrap()
-                    .clone()
-            };
+            let state = self.shared.app_state.get();
             if let recorder::AppState::Idle = state {
                 info!("Recording finished by user or duration limit.");
                 break 'main_loop;
@@ -575,7 +2031,7 @@ impl CsmaNode {
         self.progress_manager
             .lock()
             .unwrap()
-            .finish("recording", "Finished")
+            .finish("receiver", "Finished")
             .unwrap();
 
         // // Final processing for any remaining samples
@@ -608,9 +2064,1218 @@ impl CsmaNode {
         //     }
         // }
 
+        info!("Total unique data frames received: {}", unique_frames);
+        if let Some(stats) = self.backend.decode_stats() {
+            info!(
+                "Decoder buffer peak: {}/{} samples, {} discarded",
+                stats.peak_buffered_samples,
+                stats.max_buffered_samples,
+                stats.discarded_samples
+            );
+        }
+        result
+    }
+
+    /// Forward frames between the two peers of `relay` until `duration`
+    /// seconds elapsed. Returns Err if the user interrupted the relay.
+    pub fn run_relay_loop(
+        &mut self,
+        relay: &mut RelayCore,
+        duration: u64,
+    ) -> Result<(), String> {
+        info!("=== Relay Mode ===");
+
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+
+        let start_time = std::time::Instant::now();
+        let deadline = start_time + std::time::Duration::from_secs(duration);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let r = running.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            r.store(false, Ordering::SeqCst);
+        }) {
+            warn!("Ctrl+C won't stop cleanly: {}", e);
+        }
+
+        let mut inbox: Vec<Frame> = Vec::new();
+        let mut forward_queue: VecDeque<Frame> = VecDeque::new();
+
+        while std::time::Instant::now() < deadline {
+            if !running.load(Ordering::SeqCst) {
+                return Err("Interrupted by user".to_string());
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(25));
+
+            let new_samples: Vec<f32> = self
+                .shared
+                .record_buffer
+                .pop_all();
+            inbox.extend(self.feed_samples(&new_samples));
+
+            for frame in inbox.drain(..) {
+                if frame.frame_type != FrameType::Ack {
+                    self.stats.frames_received += 1;
+                }
+                for action in relay.handle_frame(&frame) {
+                    match action {
+                        RelayAction::Ack(ack_frame) => self.send_ack(&ack_frame),
+                        RelayAction::Forward(forward) => {
+                            forward_queue.push_back(forward)
+                        }
+                    }
+                }
+            }
+
+            // Hop-by-hop: a frame is done once the next hop has ACKed it
+            while let Some(frame) = forward_queue.pop_front() {
+                debug!(
+                    "Forwarding seq {} from {} to {} (hops {})",
+                    frame.sequence, frame.src, frame.dst, frame.hops
+                );
+                let sealed = self.sealed(frame.clone());
+                match self.transmit_until_acked(&sealed, deadline, &mut inbox) {
+                    Ok(Some(ack_frame)) => {
+                        if let Some(rest) =
+                            relay::unacked_remainder(&frame, &ack_frame)
+                        {
+                            forward_queue.push_front(rest);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Giving up on seq {}: {}", frame.sequence, e);
+                    }
+                }
+            }
+        }
+
+        info!(
+            "Relay loop finished in {:.2} seconds",
+            start_time
+                .elapsed()
+                .as_secs_f32()
+        );
+        Ok(())
+    }
+
+    /// Broadcast beacons and listen to others' for `duration` seconds. No
+    /// data flows here, so beacons never collide with a transfer.
+    pub fn run_discovery_loop(&mut self, duration: u64) -> Result<(), String> {
+        info!("=== Discovery Mode ===");
+
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+
+        let deadline =
+            std::time::Instant::now() + std::time::Duration::from_secs(duration);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let r = running.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            r.store(false, Ordering::SeqCst);
+        }) {
+            warn!("Ctrl+C won't stop cleanly: {}", e);
+        }
+
+        let mut processed_samples_len = 0;
+        while std::time::Instant::now() < deadline {
+            if !running.load(Ordering::SeqCst) {
+                return Err("Interrupted by user".to_string());
+            }
+
+            if let Some(beacon) = self
+                .discovery
+                .poll_beacon(std::time::Instant::now())
+            {
+                debug!("Sending beacon {}", beacon.sequence);
+                self.enqueue(beacon, Priority::Control);
+            }
+            match self.flush_control(deadline) {
+                Ok(true) => processed_samples_len = 0,
+                Ok(false) => {}
+                Err(_) => break,
+            }
+
+            // Beacons are taken in by feed_samples, anything else is not
+            // for this loop
+            self.poll_frames(&mut processed_samples_len);
+        }
+        Ok(())
+    }
+
+    /// Send everything from `queue` to the peer while delivering the peer's
+    /// data to `tx`, until `duration` seconds elapsed. Both ends are
+    /// stop-and-wait; with piggybacking on, the ACK we owe rides on our next
+    /// data frame and only goes out standalone after PIGGYBACK_DELAY_MS.
+    pub fn run_duplex_loop(
+        &mut self,
+        duration: u64,
+        queue: crossbeam_channel::Receiver<Vec<u8>>,
+        tx: crossbeam_channel::Sender<Vec<u8>>,
+    ) -> Result<(), String> {
+        info!(
+            "=== Duplex Mode (piggyback {}) ===",
+            if self.piggyback { "on" } else { "off" }
+        );
+
+        let delay = if self.piggyback {
+            std::time::Duration::from_millis(PIGGYBACK_DELAY_MS)
+        } else {
+            std::time::Duration::ZERO
+        };
+        self.duplex = Some(DuplexInbound {
+            pending: PendingAck::new(delay),
+            received: SequenceWindow::new(RX_SEQUENCE_WINDOW),
+            delivered: tx,
+        });
+
+        self.shared
+            .app_state
+            .set(recorder::AppState::Recording);
+
+        let start_time = std::time::Instant::now();
+        let deadline = start_time + std::time::Duration::from_secs(duration);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let r = running.clone();
+        if let Err(e) = ctrlc::set_handler(move || {
+            r.store(false, Ordering::SeqCst);
+        }) {
+            warn!("Ctrl+C won't stop cleanly: {}", e);
+        }
+
+        let mut result = Ok(());
+        let mut processed_samples_len = 0;
+        let mut seq: SeqType = 0;
+        let mut sent = 0usize;
+
+        while std::time::Instant::now() < deadline {
+            if !running.load(Ordering::SeqCst) {
+                result = Err("Interrupted by user".to_string());
+                break;
+            }
+
+            for frame in self.poll_frames(&mut processed_samples_len) {
+                if !self.accept_duplex_data(&frame) {
+                    trace!(
+                        "Ignoring frame type={:?}, seq={} from {}",
+                        frame.frame_type, frame.sequence, frame.src
+                    );
+                }
+            }
+            if self.flush_pending_ack() {
+                processed_samples_len = 0;
+            }
+
+            let Ok(chunk) = queue.try_recv() else {
+                continue;
+            };
+            let mut frame = self.data_frame(seq, chunk);
+            if self.piggyback {
+                frame.piggyback_ack = self
+                    .duplex
+                    .as_mut()
+                    .and_then(|duplex| duplex.pending.take());
+            }
+            seq = seq.wrapping_add(1);
+
+            let mut others = Vec::new();
+            let outcome =
+                self.transmit_until_acked(&frame, deadline, &mut others);
+            for other in others {
+                self.accept_duplex_data(&other);
+            }
+            // Everything recorded so far went through the ACK wait's decoder
+            processed_samples_len = self
+                .shared
+                .record_buffer
+                .len();
+            match outcome {
+                Ok(_) => sent += 1,
+                Err(e @ TxError::RetriesExceeded { .. }) => {
+                    warn!("{}, moving on", e);
+                    continue;
+                }
+                Err(e) => {
+                    result = Err(e.to_string());
+                    break;
+                }
+            }
+            self.progress_manager
+                .lock()
+                .unwrap()
+                .inc("sender", 1)
+                .unwrap();
+        }
+
+        // Do not leave the peer's last frame unacknowledged
+        if let Some(seq) = self
+            .duplex
+            .take()
+            .and_then(|mut duplex| duplex.pending.take())
+        {
+            let ack_frame =
+                Frame::new_ack(seq, self.local_addr, self.remote_addr);
+            self.send_ack(&ack_frame);
+        }
+
         info!(
-            "Total unique data frames received: {}",
-            received_sequences.len()
+            "Duplex loop finished in {:.2} seconds: {} frames sent, {} received",
+            start_time
+                .elapsed()
+                .as_secs_f32(),
+            sent,
+            self.stats.frames_received - self.stats.duplicate_frames
+        );
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_first_copy_and_first_duplicate_are_acked() {
+        let mut suppressor = AckSuppressor::new(Duration::from_millis(50));
+        let t0 = Instant::now();
+        assert!(suppressor.should_ack(3, false, t0));
+        assert!(suppressor.should_ack(3, true, t0));
+        assert!(!suppressor.should_ack(3, true, t0 + Duration::from_millis(20)));
+        // Other sequences are unaffected
+        assert!(suppressor.should_ack(4, true, t0 + Duration::from_millis(20)));
+        // A duplicate after the interval is ACKed again
+        assert!(suppressor.should_ack(3, true, t0 + Duration::from_millis(60)));
+    }
+
+    #[test]
+    fn test_suppression_reduces_ack_airtime() {
+        // Lossy reverse channel: the sender keeps retransmitting seq 0 while
+        // the receiver hears every copy, some of them in quick succession
+        // (echoes / back-to-back retries).
+        let arrivals_ms = [0, 5, 10, 210, 215, 220, 420, 425, 630, 640, 645];
+        let t0 = Instant::now();
+
+        let count_acks = |interval: Duration| {
+            let mut suppressor = AckSuppressor::new(interval);
+            arrivals_ms
+                .iter()
+                .enumerate()
+                .filter(|&(i, &ms)| {
+                    suppressor.should_ack(
+                        0,
+                        i > 0,
+                        t0 + Duration::from_millis(ms),
+                    )
+                })
+                .count()
+        };
+
+        let without = count_acks(Duration::ZERO);
+        let with = count_acks(Duration::from_millis(DUP_ACK_SUPPRESSION_MS));
+        assert_eq!(without, arrivals_ms.len());
+        // Still at least one ACK per retransmission round
+        assert_eq!(with, 5);
+        assert!(with * 2 <= without);
+    }
+
+    #[test]
+    fn test_audio_latency_stretches_ack_timeout_and_nav() {
+        let mut node = CsmaNode::new(
+            recorder::AppShared::new(SAMPLE_RATE as usize),
+            Arc::new(Mutex::new(ProgressManager::new())),
+            SAMPLE_RATE,
+            LineCodingKind::FourBFiveB,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        let burst = [Frame::new_data(0, 1, 2, vec![0x5A; 32])];
+        let (timeout, nav) =
+            (node.ack_timeout(), node.stamp_nav(&burst)[0].nav_ms);
+
+        node.set_audio_latency(Duration::from_millis(40));
+        // Set again, not added up
+        node.set_audio_latency(Duration::from_millis(30));
+        assert_eq!(node.ack_timeout(), timeout + Duration::from_millis(60));
+        assert_eq!(node.stamp_nav(&burst)[0].nav_ms, nav.map(|ms| ms + 60));
+    }
+
+    #[test]
+    fn test_timestamped_frames_record_delays() {
+        let node = |local, remote| {
+            CsmaNode::new(
+                recorder::AppShared::new(SAMPLE_RATE as usize),
+                Arc::new(Mutex::new(ProgressManager::new())),
+                SAMPLE_RATE,
+                LineCodingKind::FourBFiveB,
+                local,
+                remote,
+                &PhyParams::default(),
+            )
+        };
+        let mut a = node(1, 2);
+        let mut b = node(2, 1);
+        assert!(
+            a.data_frame(0, vec![1; 16])
+                .timestamp
+                .is_none()
+        );
+        a.set_timestamps(true);
+        let frame = a.data_frame(0, vec![1; 16]);
+        assert!(frame.timestamp.is_some());
+
+        let mut samples = a.backend.encode_frame(&frame);
+        // Past what the AGC holds back
+        samples.extend(vec![0.0; b.backend.rx_latency() + 100]);
+        assert_eq!(
+            b.decode_samples(&samples)
+                .len(),
+            1
+        );
+
+        let delays = b.stats().delays;
+        assert_eq!(delays.queueing.samples, 1);
+        assert_eq!(delays.one_way.samples, 1);
+        assert_eq!(delays.clock_skewed, 0);
+    }
+
+    #[test]
+    fn test_tampered_sealed_frame_is_dropped() {
+        let node = |local, remote| {
+            let mut node = CsmaNode::new(
+                recorder::AppShared::new(SAMPLE_RATE as usize),
+                Arc::new(Mutex::new(ProgressManager::new())),
+                SAMPLE_RATE,
+                LineCodingKind::FourBFiveB,
+                local,
+                remote,
+                &PhyParams::default(),
+            );
+            node.set_key(Some([0x42; crypto::KEY_BYTES]));
+            node
+        };
+        let a = node(1, 2);
+        let mut b = node(2, 1);
+        let data = vec![0x5A; 32];
+        let sealed = a.data_frame(3, data.clone());
+        assert_ne!(sealed.data, data);
+        // Valid checksums, so only the tag can catch it
+        let mut tampered = sealed.clone();
+        tampered.data[12] ^= 0x80;
+
+        let mut samples = Vec::new();
+        for frame in [&tampered, &sealed] {
+            samples.extend(a.backend.encode_frame(frame));
+            samples.extend(vec![0.0; b.backend.rx_latency() + 100]);
+        }
+        let frames = b.decode_samples(&samples);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data, data);
+        assert_eq!(b.stats().auth_failures, 1);
+        assert_eq!(b.stats().crc_failures, 0);
+    }
+
+    #[test]
+    fn test_sequence_window_forgets_oldest() {
+        let mut window = SequenceWindow::new(4);
+        for seq in 0..4 {
+            assert!(window.insert(seq));
+        }
+        assert!(!window.insert(2));
+        assert!(window.insert(4));
+        // 0 fell out of the window
+        assert!(window.insert(0));
+        assert!(!window.insert(4));
+    }
+
+    #[test]
+    fn test_loopback_transfer_past_256_frames() {
+        use crate::phy::{PhyDecoder, PhyEncoder};
+
+        let kind = LineCodingKind::FourBFiveB;
+        let params = PhyParams::default();
+        let encoder = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut decoder = PhyDecoder::new(&params, kind, FecKind::None, 1);
+        let mut window = SequenceWindow::new(RX_SEQUENCE_WINDOW);
+
+        let message: Vec<u8> = (0..320 * 8)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let frames: Vec<_> = message
+            .chunks(8)
+            .enumerate()
+            .map(|(seq, chunk)| {
+                Frame::new_data(seq as SeqType, 0, 1, chunk.to_vec())
+            })
+            .collect();
+        assert!(frames.len() > 300);
+
+        let mut received = Vec::new();
+        for chunk in encoder.encode_frames_iter(&frames, INTER_FRAME_GAP_SAMPLES)
+        {
+            for frame in decoder.process_samples(&chunk) {
+                if window.insert(frame.sequence) {
+                    received.extend(frame.data);
+                }
+            }
+        }
+        received.extend(
+            decoder
+                .process_samples(&[0.0; 200])
+                .into_iter()
+                .filter(|f| window.insert(f.sequence))
+                .flat_map(|f| f.data),
+        );
+        assert_eq!(received, message);
+    }
+
+    #[test]
+    fn test_pending_ack_keeps_first_deadline() {
+        let mut pending = PendingAck::new(Duration::from_millis(30));
+        let t0 = Instant::now();
+        pending.defer(7, t0);
+        pending.defer(7, t0 + Duration::from_millis(20));
+        assert_eq!(pending.take_expired(t0 + Duration::from_millis(25)), None);
+        assert_eq!(
+            pending.take_expired(t0 + Duration::from_millis(30)),
+            Some(7)
+        );
+        assert_eq!(pending.take(), None);
+    }
+
+    /// One end of a simulated duplex link, stop-and-wait with no losses
+    struct DuplexEnd {
+        addr: mac::types::MacAddr,
+        peer: mac::types::MacAddr,
+        encoder: crate::phy::PhyEncoder,
+        decoder: crate::phy::PhyDecoder,
+        queue: VecDeque<Vec<u8>>,
+        outstanding: Option<SeqType>,
+        next_seq: SeqType,
+        pending: PendingAck,
+        received_seqs: SequenceWindow,
+        received: Vec<u8>,
+    }
+
+    impl DuplexEnd {
+        fn new(
+            addr: mac::types::MacAddr,
+            peer: mac::types::MacAddr,
+            message: &[u8],
+            delay: Duration,
+        ) -> Self {
+            let kind = LineCodingKind::FourBFiveB;
+            Self {
+                addr,
+                peer,
+                encoder: crate::phy::PhyEncoder::new(
+                    &PhyParams::default(),
+                    kind,
+                    FecKind::None,
+                ),
+                decoder: crate::phy::PhyDecoder::new(
+                    &PhyParams::default(),
+                    kind,
+                    FecKind::None,
+                    addr,
+                ),
+                queue: message
+                    .chunks(32)
+                    .map(|chunk| chunk.to_vec())
+                    .collect(),
+                outstanding: None,
+                next_seq: 0,
+                pending: PendingAck::new(delay),
+                received_seqs: SequenceWindow::new(RX_SEQUENCE_WINDOW),
+                received: Vec::new(),
+            }
+        }
+
+        fn done(&self) -> bool {
+            self.queue.is_empty()
+                && self.outstanding.is_none()
+                && self.pending.pending.is_none()
+        }
+
+        /// Frames this end puts on the air during its turn
+        fn turn(&mut self, now: Instant, piggyback: bool) -> Vec<Frame> {
+            let mut frames = Vec::new();
+            if let Some(seq) = self.pending.take_expired(now) {
+                frames.push(Frame::new_ack(seq, self.addr, self.peer));
+            }
+            if self.outstanding.is_none()
+                && let Some(chunk) = self.queue.pop_front()
+            {
+                let mut frame =
+                    Frame::new_data(self.next_seq, self.addr, self.peer, chunk);
+                if piggyback {
+                    frame.piggyback_ack = self.pending.take();
+                }
+                self.outstanding = Some(self.next_seq);
+                self.next_seq = self.next_seq.wrapping_add(1);
+                frames.push(frame);
+            }
+            frames
+        }
+
+        fn hear(&mut self, samples: &[f32], now: Instant) {
+            for frame in self
+                .decoder
+                .process_samples(samples)
+            {
+                let acked = match frame.frame_type {
+                    FrameType::Ack => Some(frame.sequence),
+                    _ => frame.piggyback_ack,
+                };
+                if acked.is_some() && acked == self.outstanding {
+                    self.outstanding = None;
+                }
+                if frame.frame_type == FrameType::Data {
+                    if self
+                        .received_seqs
+                        .insert(frame.sequence)
+                    {
+                        self.received
+                            .extend(&frame.data);
+                    }
+                    self.pending
+                        .defer(frame.sequence, now);
+                }
+            }
+        }
+    }
+
+    /// Frames on the wire and standalone ACKs of a bidirectional transfer
+    fn simulate_duplex(
+        a_message: &[u8],
+        b_message: &[u8],
+        piggyback: bool,
+    ) -> (usize, usize) {
+        let delay = if piggyback {
+            Duration::from_millis(PIGGYBACK_DELAY_MS)
+        } else {
+            Duration::ZERO
+        };
+        let mut a = DuplexEnd::new(1, 2, a_message, delay);
+        let mut b = DuplexEnd::new(2, 1, b_message, delay);
+        let t0 = Instant::now();
+        let (mut frames, mut acks) = (0, 0);
+
+        // Turns alternate every 10 ms of simulated time
+        for step in 0..1000u64 {
+            if a.done() && b.done() {
+                break;
+            }
+            let now = t0 + Duration::from_millis(step * 10);
+            let (speaker, listener) = if step % 2 == 0 {
+                (&mut a, &mut b)
+            } else {
+                (&mut b, &mut a)
+            };
+            for frame in speaker.turn(now, piggyback) {
+                frames += 1;
+                if frame.frame_type == FrameType::Ack {
+                    acks += 1;
+                }
+                let mut samples = speaker
+                    .encoder
+                    .encode_frame(&frame);
+                samples.extend(vec![0.0; 100]);
+                listener.hear(&samples, now);
+            }
+        }
+
+        assert!(a.done() && b.done(), "transfer did not finish");
+        assert_eq!(b.received, a_message);
+        assert_eq!(a.received, b_message);
+        (frames, acks)
+    }
+
+    #[test]
+    fn test_piggybacked_acks_save_frames_on_the_wire() {
+        let a_message: Vec<u8> = (0..640)
+            .map(|i| (i * 13 % 256) as u8)
+            .collect();
+        let b_message: Vec<u8> = (0..480)
+            .map(|i| (i * 29 % 256) as u8)
+            .collect();
+
+        let (frames_off, acks_off) =
+            simulate_duplex(&a_message, &b_message, false);
+        let (frames_on, acks_on) = simulate_duplex(&a_message, &b_message, true);
+
+        // 20 + 15 data frames, each ACKed on its own without piggybacking
+        assert_eq!(acks_off, 35);
+        assert_eq!(frames_off, 70);
+        // Only the tail of the longer transfer needs standalone ACKs
+        assert!(acks_on <= 6, "{} standalone ACKs", acks_on);
+        assert!(frames_on < frames_off);
+    }
+
+    #[test]
+    fn test_contention_window_doubles_up_to_cw_max() {
+        let params = MacParams::default();
+        let windows: Vec<_> = (0..10)
+            .map(|stage| params.contention_window(stage))
+            .collect();
+        assert_eq!(windows, vec![1, 2, 4, 8, 16, 32, 64, 100, 100, 100]);
+        assert_eq!(params.contention_window(u16::MAX), CW_MAX as usize);
+    }
+
+    #[test]
+    fn test_node_follows_mac_params() {
+        let mac_params = MacParams {
+            slot_ms: 12,
+            difs_ms: 40,
+            ack_timeout_ms: 500,
+            ..MacParams::default()
+        };
+        let node = |mac_params: &MacParams| {
+            let mut node = CsmaNode::new(
+                recorder::AppShared::new(SAMPLE_RATE as usize),
+                Arc::new(Mutex::new(ProgressManager::new())),
+                SAMPLE_RATE,
+                LineCodingKind::FourBFiveB,
+                1,
+                2,
+                &PhyParams::default(),
+            );
+            node.set_mac_params(mac_params);
+            node
+        };
+        let tuned = node(&mac_params);
+        let default = node(&MacParams::default());
+        assert_eq!(tuned.timing.slot, Duration::from_millis(12));
+        assert_eq!(tuned.timing.difs, Duration::from_millis(40));
+        assert_eq!(
+            tuned.ack_timeout(),
+            default.ack_timeout() + Duration::from_millis(300)
+        );
+    }
+
+    #[test]
+    fn test_transfer_over_lossy_loopback() {
+        use crate::audio::loopback::{
+            LinkParams, LoopbackConfig, LoopbackMedium,
+        };
+
+        // A third of the transmissions either way never arrive
+        let medium = LoopbackMedium::new(LoopbackConfig {
+            seed: 2,
+            link: LinkParams {
+                loss: 0.3,
+                noise: 0.01,
+                ..LinkParams::default()
+            },
+            ..LoopbackConfig::default()
+        });
+        let mut nodes = medium.nodes(2, SAMPLE_RATE as usize * 30);
+        let (b, _b_audio) = nodes.pop().unwrap();
+        let (a, _a_audio) = nodes.pop().unwrap();
+
+        let progress = ProgressManager::new();
+        progress
+            .add_frames_bar("sender", 4)
+            .unwrap();
+        progress
+            .add_frames_bar("receiver", 0)
+            .unwrap();
+        let progress = Arc::new(Mutex::new(progress));
+        let kind = LineCodingKind::FourBFiveB;
+        let mut sender = CsmaNode::new(
+            a,
+            progress.clone(),
+            SAMPLE_RATE,
+            kind,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        let mut receiver = CsmaNode::new(
+            b,
+            progress,
+            SAMPLE_RATE,
+            kind,
+            2,
+            1,
+            &PhyParams::default(),
+        );
+
+        let (delivered_tx, delivered_rx) = crossbeam_channel::unbounded();
+        let receiving = std::thread::spawn(move || {
+            let result =
+                receiver.run_receiver_loop(SAMPLE_RATE * 30, 12, delivered_tx);
+            (result, receiver.stats())
+        });
+
+        let payloads: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| vec![i; 24])
+            .collect();
+        let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+        for payload in &payloads {
+            queue_tx
+                .send(payload.clone())
+                .unwrap();
+        }
+        drop(queue_tx);
+        let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+        let result = sender.run_sender_loop(10, queue_rx, failures_tx);
+        let (rx_result, rx_stats) = receiving.join().unwrap();
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(rx_result.is_ok(), "{:?}", rx_result);
+        assert_eq!(failures_rx.try_iter().count(), 0);
+        // Losses cost retransmissions, never data
+        assert_eq!(
+            delivered_rx
+                .try_iter()
+                .collect::<Vec<_>>(),
+            payloads
+        );
+        assert!(sender.stats().retransmissions > 0);
+        assert!(rx_stats.frames_received >= 4);
+    }
+
+    /// Send four frames from 1 to 2 with half of 2's transmissions lost
+    /// and the sender retrying fast, so the receiver gets copies in quick
+    /// succession. Returns the receiver's stats.
+    fn transfer_dropping_acks(dup_ack_suppression: Duration) -> LinkStats {
+        use crate::audio::loopback::{
+            LinkParams, LoopbackConfig, LoopbackMedium,
+        };
+
+        let medium = LoopbackMedium::new(LoopbackConfig {
+            seed: 3,
+            ..LoopbackConfig::default()
+        });
+        medium.set_link(
+            1,
+            0,
+            LinkParams {
+                loss: 0.75,
+                ..LinkParams::default()
+            },
+        );
+        let mut nodes = medium.nodes(2, SAMPLE_RATE as usize * 30);
+        let (b, _b_audio) = nodes.pop().unwrap();
+        let (a, _a_audio) = nodes.pop().unwrap();
+
+        let progress = ProgressManager::new();
+        progress
+            .add_frames_bar("sender", 4)
+            .unwrap();
+        progress
+            .add_frames_bar("receiver", 0)
+            .unwrap();
+        let progress = Arc::new(Mutex::new(progress));
+        let kind = LineCodingKind::FourBFiveB;
+        let mut sender = CsmaNode::new(
+            a,
+            progress.clone(),
+            SAMPLE_RATE,
+            kind,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        sender.set_mac_params(&MacParams {
+            ack_timeout_ms: 20,
+            ..MacParams::default()
+        });
+        // Stop-and-wait, windowed ARQ answers a burst with one ACK
+        sender.set_window(1);
+        sender.set_max_retries(64);
+        let mut receiver = CsmaNode::new(
+            b.clone(),
+            progress,
+            SAMPLE_RATE,
+            kind,
+            2,
+            1,
+            &PhyParams::default(),
+        );
+        // Suppression is capped at half of it
+        receiver.set_mac_params(&MacParams {
+            ack_timeout_ms: 400,
+            ..MacParams::default()
+        });
+        receiver.set_window(1);
+        receiver.set_dup_ack_suppression(dup_ack_suppression);
+
+        let (delivered_tx, delivered_rx) = crossbeam_channel::unbounded();
+        let receiving = std::thread::spawn(move || {
+            let result =
+                receiver.run_receiver_loop(SAMPLE_RATE * 30, 30, delivered_tx);
+            (result, receiver.stats())
+        });
+
+        let payloads: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| vec![i; 24])
+            .collect();
+        let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+        for payload in &payloads {
+            queue_tx
+                .send(payload.clone())
+                .unwrap();
+        }
+        drop(queue_tx);
+        let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+        // Slow on a loaded machine, the receiver stops as soon as it is done
+        let result = sender.run_sender_loop(25, queue_rx, failures_tx);
+        // Over once every frame is ACKed
+        b.app_state
+            .set(recorder::AppState::Idle);
+        let (rx_result, rx_stats) = receiving.join().unwrap();
+
+        assert!(result.is_ok(), "{:?}", result);
+        assert!(rx_result.is_ok(), "{:?}", rx_result);
+        assert_eq!(failures_rx.try_iter().count(), 0);
+        assert_eq!(
+            delivered_rx
+                .try_iter()
+                .collect::<Vec<_>>(),
+            payloads
+        );
+        assert!(rx_stats.duplicate_frames > 0, "{:?}", rx_stats);
+        rx_stats
+    }
+
+    #[test]
+    fn test_ack_suppression_over_loopback_dropping_acks() {
+        // Every copy ACKed
+        let without = transfer_dropping_acks(Duration::ZERO);
+        assert_eq!(without.acks_suppressed, 0);
+        assert_eq!(without.acks_sent, without.frames_received);
+
+        // Copies right after a re-ACKed one aren't, the transfer still
+        // completes
+        let with = transfer_dropping_acks(Duration::from_millis(200));
+        assert!(with.acks_suppressed > 0, "{:?}", with);
+        assert_eq!(with.acks_sent + with.acks_suppressed, with.frames_received);
+        let duplicate_acks = |stats: &LinkStats| stats.acks_sent - 4;
+        assert!(duplicate_acks(&with) < with.duplicate_frames);
+        assert_eq!(duplicate_acks(&without), without.duplicate_frames);
+    }
+
+    /// Frames `a` heard while sending a burst as `b` sent a shorter frame
+    /// over its start
+    fn hear_overlapping_frame(full_duplex: bool) -> Vec<Frame> {
+        use crate::audio::loopback::{LoopbackConfig, LoopbackMedium};
+
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        let mut nodes = medium.nodes(2, SAMPLE_RATE as usize * 5);
+        let (b, _b_audio) = nodes.pop().unwrap();
+        let (a, _a_audio) = nodes.pop().unwrap();
+        let progress = Arc::new(Mutex::new(ProgressManager::new()));
+        let kind = LineCodingKind::FourBFiveB;
+        let mut node = CsmaNode::new(
+            a,
+            progress,
+            SAMPLE_RATE,
+            kind,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        node.set_full_duplex(full_duplex);
+
+        let burst: Vec<Frame> = (0..4u8)
+            .map(|i| Frame::new_data(i as SeqType, 1, 2, vec![i; 24]))
+            .collect();
+        let overlap = Frame::new_data(7, 2, 1, vec![0xA5; 16]);
+        b.queue_playback(
+            &node
+                .backend
+                .encode_frame(&overlap),
+        );
+        // Shortly after our burst started
+        let other = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            b.app_state
+                .set(recorder::AppState::Playing);
+        });
+        node.play_frames(&burst);
+        other.join().unwrap();
+
+        let mut processed = 0;
+        let mut heard = Vec::new();
+        let deadline =
+            std::time::Instant::now() + std::time::Duration::from_secs(1);
+        while heard.is_empty() && std::time::Instant::now() < deadline {
+            heard.extend(node.poll_frames(&mut processed));
+        }
+        heard
+    }
+
+    #[test]
+    fn test_full_duplex_hears_frame_overlapping_its_own() {
+        let heard = hear_overlapping_frame(true);
+        // Only the other side's frame, not our burst heard back
+        assert_eq!(heard.len(), 1, "{:?}", heard);
+        assert_eq!(heard[0].src, 2);
+        assert_eq!(heard[0].sequence, 7);
+
+        // Half duplex was deaf while it played
+        assert!(hear_overlapping_frame(false).is_empty());
+    }
+
+    /// Our frames a full duplex node decoded while its own echo came back
+    /// 5 ms late at -6 dB
+    fn own_frames_heard(echo_cancel: bool) -> u64 {
+        use crate::audio::loopback::{
+            LinkParams, LoopbackConfig, LoopbackMedium,
+        };
+
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        medium.set_link(
+            0,
+            0,
+            LinkParams {
+                latency: std::time::Duration::from_millis(5),
+                gain: 0.5,
+                ..LinkParams::default()
+            },
         );
+        let (a, _a_audio) = medium
+            .nodes(1, SAMPLE_RATE as usize * 5)
+            .pop()
+            .unwrap();
+        let progress = Arc::new(Mutex::new(ProgressManager::new()));
+        let kind = LineCodingKind::FourBFiveB;
+        let mut node = CsmaNode::new(
+            a,
+            progress,
+            SAMPLE_RATE,
+            kind,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        node.set_full_duplex(true);
+        node.set_echo_cancel(echo_cancel);
+
+        // Broadcasts, the decoder drops unicasts for others by itself
+        let burst: Vec<Frame> = (0..4u8)
+            .map(|i| Frame::new_data(i as SeqType, 1, BROADCAST, vec![i; 24]))
+            .collect();
+        node.play_frames(&burst);
+        let mut processed = 0;
+        for _ in 0..20 {
+            assert!(
+                node.poll_frames(&mut processed)
+                    .is_empty()
+            );
+        }
+        node.stats().own_frames_heard
+    }
+
+    #[test]
+    fn test_echo_cancel_stops_decoding_own_frames() {
+        assert!(own_frames_heard(false) > 0);
+        assert_eq!(own_frames_heard(true), 0);
+    }
+
+    /// Stand-in for the sound card: runs the process callback on silence
+    /// every millisecond, nobody else is on the air
+    fn spawn_silent_audio(
+        shared: recorder::AppShared,
+        running: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<()> {
+        spawn_silent_audio_into(shared, running, Default::default())
+    }
+
+    /// Like spawn_silent_audio, keeping everything played in `played`
+    fn spawn_silent_audio_into(
+        shared: recorder::AppShared,
+        running: Arc<AtomicBool>,
+        played: Arc<Mutex<Vec<f32>>>,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let period = SAMPLE_RATE as usize / 1000;
+            let mut process = recorder::build_process_closure(shared.clone());
+            let silence = vec![0.0; period];
+            let mut output = vec![0.0; period];
+            while running.load(Ordering::SeqCst) {
+                let playing =
+                    shared.app_state.get() == recorder::AppState::Playing;
+                process(&silence, &mut output);
+                if playing {
+                    played
+                        .lock()
+                        .unwrap()
+                        .extend_from_slice(&output);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        })
+    }
+
+    #[test]
+    fn test_sender_gives_up_without_acks() {
+        let shared = recorder::AppShared::new(SAMPLE_RATE as usize * 30);
+        let running = Arc::new(AtomicBool::new(true));
+        let audio = spawn_silent_audio(shared.clone(), running.clone());
+
+        let progress = ProgressManager::new();
+        progress
+            .add_frames_bar("sender", 1)
+            .unwrap();
+        let mut node = CsmaNode::new(
+            shared,
+            Arc::new(Mutex::new(progress)),
+            SAMPLE_RATE,
+            LineCodingKind::FourBFiveB,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        node.set_window(1);
+        node.set_max_retries(2);
+
+        let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+        let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+        queue_tx
+            .send(vec![0x55; 16])
+            .unwrap();
+        drop(queue_tx);
+
+        // The receiver is gone: the frame is dropped well before the
+        // transfer timeout and the loop returns
+        let start = Instant::now();
+        let result = node.run_sender_loop(30, queue_rx, failures_tx);
+        running.store(false, Ordering::SeqCst);
+        audio.join().unwrap();
+
+        assert!(result.is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            failures_rx
+                .try_iter()
+                .collect::<Vec<_>>(),
+            vec![TxError::RetriesExceeded {
+                sequence: 0,
+                attempts: 3
+            }]
+        );
+        let stats = node.stats();
+        assert_eq!(stats.frames_sent, 3);
+        assert_eq!(stats.ack_timeouts, 3);
+        assert_eq!(stats.frames_dropped, 1);
+    }
+
+    #[test]
+    fn test_control_frame_jumps_data_backlog() {
+        let shared = recorder::AppShared::new(SAMPLE_RATE as usize * 30);
+        let running = Arc::new(AtomicBool::new(true));
+        let played = Arc::new(Mutex::new(Vec::new()));
+        let audio = spawn_silent_audio_into(
+            shared.clone(),
+            running.clone(),
+            played.clone(),
+        );
+
+        let progress = ProgressManager::new();
+        progress
+            .add_frames_bar("sender", 20)
+            .unwrap();
+        let mut node = CsmaNode::new(
+            shared,
+            Arc::new(Mutex::new(progress)),
+            SAMPLE_RATE,
+            LineCodingKind::FourBFiveB,
+            1,
+            2,
+            &PhyParams::default(),
+        );
+        node.set_window(1);
+        for seq in 0..20 {
+            node.enqueue(
+                Frame::new_data(seq, 1, 2, vec![0x55; 32]),
+                Priority::Data,
+            );
+        }
+        node.enqueue(Frame::new_nack(3, 1, 2, vec![0x01]), Priority::Control);
+
+        // Nobody ACKs, the transfer times out after the first frames
+        let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+        drop(queue_tx);
+        let (failures_tx, _failures_rx) = crossbeam_channel::unbounded();
+        let result = node.run_sender_loop(1, queue_rx, failures_tx);
+        running.store(false, Ordering::SeqCst);
+        audio.join().unwrap();
+        assert!(result.is_err());
+
+        let mut decoder = crate::phy::PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+            2,
+        );
+        let heard = decoder.process_samples(&played.lock().unwrap());
+        assert!(heard.len() >= 2, "{} frames", heard.len());
+        assert_eq!(heard[0].frame_type, FrameType::Nack);
+        assert_eq!(heard[1].frame_type, FrameType::Data);
+        assert_eq!(heard[1].sequence, 0);
+    }
+
+    #[test]
+    fn test_sender_defers_for_overheard_nav() {
+        let defer = |nav_ms: Option<u16>| {
+            let shared = recorder::AppShared::new(SAMPLE_RATE as usize * 30);
+            let running = Arc::new(AtomicBool::new(true));
+            let audio = spawn_silent_audio(shared.clone(), running.clone());
+            let mut node = CsmaNode::new(
+                shared.clone(),
+                Arc::new(Mutex::new(ProgressManager::new())),
+                SAMPLE_RATE,
+                LineCodingKind::FourBFiveB,
+                1,
+                2,
+                &PhyParams::default(),
+            );
+
+            // Nodes 2 and 3 are mid-exchange, their frame ends right now
+            let mut frame = Frame::new_data(0, 2, 3, vec![0x5A; 32]);
+            frame.nav_ms = nav_ms;
+            let mut samples = node
+                .backend
+                .encode_frame(&frame);
+            samples.extend(vec![0.0; 100]);
+            // Queued before recording starts, the audio thread is the
+            // ring's only producer from then on
+            shared
+                .record_buffer
+                .extend(samples);
+            shared
+                .app_state
+                .set(recorder::AppState::Recording);
+
+            let start = Instant::now();
+            node.acquire_channel(
+                mac::CSMAState::Sensing,
+                0,
+                start + Duration::from_secs(5),
+            )
+            .unwrap();
+            running.store(false, Ordering::SeqCst);
+            audio.join().unwrap();
+            (start.elapsed(), node.stats().nav_reservations)
+        };
+
+        // Energy detection alone frees the channel as soon as the frame ends
+        let (elapsed, reservations) = defer(None);
+        assert_eq!(reservations, 0);
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+
+        let (elapsed, reservations) = defer(Some(400));
+        assert_eq!(reservations, 1);
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
     }
 }
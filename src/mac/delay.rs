@@ -0,0 +1,201 @@
+// Per-frame delays
+//
+// With timestamps on (`--timestamps`), data frames carry the sender's wall
+// clock in milliseconds when they were queued and when their playback
+// started (see phy::frame). The receiver takes the difference as the
+// queueing delay, and its own clock on decoding minus the second as the
+// one-way delay. The latter only means something with both clocks
+// synchronized (NTP or the like); a negative one is counted as clock skew.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::phy::frame::FrameTimestamp;
+use crate::utils::consts::DELAY_BUCKETS_MS;
+
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Timestamp the data frames of every node created from now on
+pub fn select_timestamps() {
+    TIMESTAMPS.store(true, Ordering::Relaxed);
+}
+
+pub fn timestamps_selected() -> bool {
+    TIMESTAMPS.load(Ordering::Relaxed)
+}
+
+/// Wall clock in milliseconds, wrapping every 49 days
+pub fn now_ms() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u32)
+}
+
+/// Delays counted into DELAY_BUCKETS_MS, the last bucket holding what is
+/// beyond them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DelayHistogram {
+    pub buckets: [u64; DELAY_BUCKETS_MS.len() + 1],
+    pub samples: u64,
+    pub total_ms: u64,
+    pub max_ms: u32,
+}
+
+impl DelayHistogram {
+    pub fn record(&mut self, delay_ms: u32) {
+        let bucket = DELAY_BUCKETS_MS
+            .iter()
+            .position(|&bound| delay_ms < bound)
+            .unwrap_or(DELAY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.samples += 1;
+        self.total_ms += delay_ms as u64;
+        self.max_ms = self.max_ms.max(delay_ms);
+    }
+
+    pub fn mean_ms(&self) -> Option<f64> {
+        (self.samples > 0).then(|| self.total_ms as f64 / self.samples as f64)
+    }
+}
+
+/// `n=3 mean 4.0 ms max 7 ms [<5:2 <10:1]`, empty buckets left out
+impl fmt::Display for DelayHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mean) = self.mean_ms() else {
+            return write!(f, "n=0");
+        };
+        write!(
+            f,
+            "n={} mean {:.1} ms max {} ms [",
+            self.samples, mean, self.max_ms
+        )?;
+        let mut first = true;
+        for (i, &count) in self
+            .buckets
+            .iter()
+            .enumerate()
+        {
+            if count == 0 {
+                continue;
+            }
+            if !first {
+                write!(f, " ")?;
+            }
+            first = false;
+            match DELAY_BUCKETS_MS.get(i) {
+                Some(bound) => write!(f, "<{}:{}", bound, count)?,
+                None => write!(
+                    f,
+                    ">={}:{}",
+                    DELAY_BUCKETS_MS[DELAY_BUCKETS_MS.len() - 1],
+                    count
+                )?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// Delays of the timestamped frames received
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FrameDelays {
+    /// Queued to sent, on the sender's clock
+    pub queueing: DelayHistogram,
+    /// Sent to received, across both clocks
+    pub one_way: DelayHistogram,
+    /// Frames received before they were sent, by our clock
+    pub clock_skewed: u64,
+}
+
+impl FrameDelays {
+    /// Count a frame stamped `stamp` that arrived at `received_ms`
+    pub fn record(&mut self, stamp: FrameTimestamp, received_ms: u32) {
+        // Wrapping differences, any sane delay is far below 2^31 ms
+        let queued = stamp
+            .sent_ms
+            .wrapping_sub(stamp.queued_ms) as i32;
+        if queued >= 0 {
+            self.queueing
+                .record(queued as u32);
+        }
+        let one_way = received_ms.wrapping_sub(stamp.sent_ms) as i32;
+        if one_way >= 0 {
+            self.one_way
+                .record(one_way as u32);
+        } else {
+            self.clock_skewed += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queueing.samples == 0 && self.one_way.samples == 0
+    }
+
+    /// Log both histograms, if any timestamped frame came in
+    pub fn log_summary(&self) {
+        if self.is_empty() && self.clock_skewed == 0 {
+            return;
+        }
+        info!("queueing delay: {}", self.queueing);
+        info!(
+            "one-way delay: {} ({} frames clock skewed)",
+            self.one_way, self.clock_skewed
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(queued_ms: u32, sent_ms: u32) -> FrameTimestamp {
+        FrameTimestamp { queued_ms, sent_ms }
+    }
+
+    #[test]
+    fn test_delays_from_synthetic_timestamps() {
+        let mut delays = FrameDelays::default();
+        delays.record(stamp(1000, 1003), 1010);
+        delays.record(stamp(2000, 2040), 2047);
+        // Across the 32-bit wrap
+        delays.record(stamp(u32::MAX - 1, 2), 9);
+        // The receiver's clock runs behind the sender's
+        delays.record(stamp(5000, 5000), 4990);
+
+        assert_eq!(delays.queueing.samples, 4);
+        assert_eq!(delays.queueing.total_ms, 3 + 40 + 4);
+        assert_eq!(delays.queueing.max_ms, 40);
+        assert_eq!(delays.one_way.samples, 3);
+        assert_eq!(delays.one_way.mean_ms(), Some(7.0));
+        assert_eq!(delays.clock_skewed, 1);
+    }
+
+    #[test]
+    fn test_histogram_buckets_and_display() {
+        let mut histogram = DelayHistogram::default();
+        assert_eq!(histogram.to_string(), "n=0");
+        for delay_ms in [0, 4, 5, 9, 1_000_000] {
+            histogram.record(delay_ms);
+        }
+        let last = DELAY_BUCKETS_MS.len();
+        assert_eq!(histogram.buckets[last], 1);
+        assert_eq!(
+            histogram
+                .buckets
+                .iter()
+                .sum::<u64>(),
+            5
+        );
+        assert_eq!(
+            histogram.to_string(),
+            format!(
+                "n=5 mean 200003.6 ms max 1000000 ms [<1:1 <5:1 <10:2 >={}:1]",
+                DELAY_BUCKETS_MS[last - 1]
+            )
+        );
+    }
+}
@@ -0,0 +1,403 @@
+// Neighbor discovery
+//
+// Every node broadcasts a Beacon frame each BEACON_INTERVAL_MS announcing
+// its MAC address and, if it has one, its IP address:
+//
+//   Beacon payload: [Mac:1] [Ip:0/4]
+//
+// Nodes that hear a beacon add the sender to their neighbor table. Any
+// later frame from a known neighbor refreshes it; neighbors silent for
+// NEIGHBOR_EXPIRY_MS are dropped. Beacons are held back while data is
+// flowing, the data frames themselves keep the tables fresh.
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use crate::audio::{backend, recorder};
+use crate::mac::csma::CsmaNode;
+use crate::mac::params::MacParams;
+use crate::mac::types::{BROADCAST, MacAddr};
+use crate::phy::frame::SeqType;
+use crate::phy::params::PhyParams;
+use crate::phy::{Frame, FrameType, LineCodingKind};
+use crate::ui::progress::ProgressManager;
+use crate::utils::consts::*;
+
+/// Contents of a beacon frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Beacon {
+    pub mac: MacAddr,
+    pub ip: Option<Ipv4Addr>,
+}
+
+impl Beacon {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![self.mac];
+        if let Some(ip) = self.ip {
+            bytes.extend_from_slice(&ip.octets());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        match bytes {
+            [mac] => Ok(Self {
+                mac: *mac,
+                ip: None,
+            }),
+            [mac, a, b, c, d] => Ok(Self {
+                mac: *mac,
+                ip: Some(Ipv4Addr::new(*a, *b, *c, *d)),
+            }),
+            _ => Err(format!("Invalid beacon payload of {} bytes", bytes.len())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Neighbor {
+    pub mac: MacAddr,
+    pub ip: Option<Ipv4Addr>,
+    pub last_seen: Instant,
+    /// Beacons heard from this neighbor
+    pub beacons: u64,
+}
+
+/// Nodes heard recently, keyed by MAC address
+#[derive(Debug, Clone)]
+pub struct NeighborTable {
+    entries: BTreeMap<MacAddr, Neighbor>,
+    expiry: Duration,
+}
+
+impl Default for NeighborTable {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(NEIGHBOR_EXPIRY_MS))
+    }
+}
+
+impl NeighborTable {
+    pub fn new(expiry: Duration) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            expiry,
+        }
+    }
+
+    /// Learn from a received frame: beacons add or update their sender,
+    /// other frames only refresh a known neighbor. Returns true for beacons.
+    pub fn observe(&mut self, frame: &Frame, now: Instant) -> bool {
+        if frame.frame_type != FrameType::Beacon {
+            if let Some(neighbor) = self
+                .entries
+                .get_mut(&frame.src)
+            {
+                neighbor.last_seen = now;
+            }
+            return false;
+        }
+        let beacon = match Beacon::from_bytes(&frame.data) {
+            Ok(beacon) => beacon,
+            Err(e) => {
+                warn!("Ignoring beacon from {}: {}", frame.src, e);
+                return false;
+            }
+        };
+        let neighbor = self
+            .entries
+            .entry(beacon.mac)
+            .or_insert(Neighbor {
+                mac: beacon.mac,
+                ip: None,
+                last_seen: now,
+                beacons: 0,
+            });
+        neighbor.ip = beacon.ip;
+        neighbor.last_seen = now;
+        neighbor.beacons += 1;
+        true
+    }
+
+    /// Drop neighbors not heard from within the expiry time
+    pub fn expire(&mut self, now: Instant) {
+        let expiry = self.expiry;
+        self.entries
+            .retain(|_, neighbor| {
+                now.saturating_duration_since(neighbor.last_seen) < expiry
+            });
+    }
+
+    /// Live neighbors, by MAC address
+    pub fn neighbors(&self, now: Instant) -> Vec<Neighbor> {
+        self.entries
+            .values()
+            .filter(|neighbor| {
+                now.saturating_duration_since(neighbor.last_seen) < self.expiry
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Beacon schedule and neighbor table of one node
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    local: MacAddr,
+    ip: Option<Ipv4Addr>,
+    interval: Duration,
+    sequence: SeqType,
+    last_beacon: Option<Instant>,
+    last_traffic: Option<Instant>,
+    table: NeighborTable,
+}
+
+impl Discovery {
+    pub fn new(local: MacAddr, ip: Option<Ipv4Addr>) -> Self {
+        Self {
+            local,
+            ip,
+            interval: Duration::from_millis(BEACON_INTERVAL_MS),
+            sequence: 0,
+            last_beacon: None,
+            last_traffic: None,
+            table: NeighborTable::default(),
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn set_ip(&mut self, ip: Option<Ipv4Addr>) {
+        self.ip = ip;
+    }
+
+    /// Data was sent or received: beacons wait until the link has been
+    /// quiet for a full interval
+    pub fn note_traffic(&mut self, now: Instant) {
+        self.last_traffic = Some(now);
+    }
+
+    /// The beacon to broadcast now, if one is due
+    pub fn poll_beacon(&mut self, now: Instant) -> Option<Frame> {
+        let quiet = |last: Option<Instant>| {
+            last.is_none_or(|t| {
+                now.saturating_duration_since(t) >= self.interval
+            })
+        };
+        if !quiet(self.last_beacon) || !quiet(self.last_traffic) {
+            return None;
+        }
+        self.last_beacon = Some(now);
+        let beacon = Beacon {
+            mac: self.local,
+            ip: self.ip,
+        };
+        let frame =
+            Frame::new_beacon(self.sequence, self.local, beacon.to_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(frame)
+    }
+
+    /// See NeighborTable::observe
+    pub fn observe(&mut self, frame: &Frame, now: Instant) -> bool {
+        if frame.src == self.local {
+            return false;
+        }
+        self.table.expire(now);
+        self.table.observe(frame, now)
+    }
+
+    pub fn neighbors(&self, now: Instant) -> Vec<Neighbor> {
+        self.table.neighbors(now)
+    }
+}
+
+/// Print a neighbor table the way the Discover command shows it
+pub fn format_neighbors(neighbors: &[Neighbor], now: Instant) -> String {
+    if neighbors.is_empty() {
+        return "No neighbors heard".to_string();
+    }
+    let mut out =
+        format!("{:<6}{:<18}{:<12}{}\n", "MAC", "IP", "Last seen", "Beacons");
+    for neighbor in neighbors {
+        out.push_str(&format!(
+            "{:<6}{:<18}{:<12}{}\n",
+            neighbor.mac,
+            neighbor
+                .ip
+                .map_or("-".to_string(), |ip| ip.to_string()),
+            format!(
+                "{:.1}s ago",
+                now.saturating_duration_since(neighbor.last_seen)
+                    .as_secs_f32()
+            ),
+            neighbor.beacons
+        ));
+    }
+    out
+}
+
+/// Beacon and listen for `duration` seconds, then print who was heard
+pub fn run_discover(
+    local: MacAddr,
+    ip: Option<Ipv4Addr>,
+    interval: Duration,
+    line_coding: LineCodingKind,
+    duration: u64,
+    phy_params: &PhyParams,
+    mac_params: &MacParams,
+) {
+    info!(
+        "Discovering neighbors of {} for {}s, beacon every {:?} ({})",
+        local,
+        duration,
+        interval,
+        line_coding.name()
+    );
+
+    // Setup audio
+    let client = backend::open(&format!(
+        "{}_discover_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))
+    .unwrap();
+
+    let sample_rate = client.sample_rate() as u32;
+    let max_samples = sample_rate as usize * duration as usize;
+    let shared = recorder::AppShared::new(max_samples);
+    let shared_cb = shared.clone();
+
+    let active_client = client
+        .activate(recorder::build_process_closure(shared_cb))
+        .unwrap();
+
+    let mut node = CsmaNode::new(
+        shared,
+        Arc::new(Mutex::new(ProgressManager::new())),
+        sample_rate,
+        line_coding,
+        local,
+        BROADCAST,
+        phy_params,
+    );
+    node.set_mac_params(mac_params);
+    node.set_discovery(interval, ip);
+
+    if let Err(e) = node.run_discovery_loop(duration) {
+        warn!("Discovery stopped: {}", e);
+    }
+
+    println!("{}", format_neighbors(&node.neighbors(), Instant::now()));
+
+    if let Err(err) = active_client.deactivate() {
+        error!("Error deactivating client: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::params::PhyParams;
+    use crate::phy::{FecKind, LineCodingKind, PhyDecoder, PhyEncoder};
+
+    #[test]
+    fn test_beacon_roundtrip() {
+        let kind = LineCodingKind::FourBFiveB;
+        let params = PhyParams::default();
+        let encoder = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut decoder = PhyDecoder::new(&params, kind, FecKind::None, 2);
+
+        let mut discovery =
+            Discovery::new(1, Some(Ipv4Addr::new(192, 168, 1, 1)));
+        let frame = discovery
+            .poll_beacon(Instant::now())
+            .unwrap();
+        let mut samples = encoder.encode_frame(&frame);
+        samples.extend(vec![0.0; 100]);
+        let heard = decoder.process_samples(&samples);
+
+        assert_eq!(heard.len(), 1);
+        assert_eq!(heard[0].frame_type, FrameType::Beacon);
+        assert_eq!(heard[0].dst, BROADCAST);
+        assert_eq!(
+            Beacon::from_bytes(&heard[0].data),
+            Ok(Beacon {
+                mac: 1,
+                ip: Some(Ipv4Addr::new(192, 168, 1, 1)),
+            })
+        );
+        assert_eq!(
+            Beacon::from_bytes(&Beacon { mac: 7, ip: None }.to_bytes()),
+            Ok(Beacon { mac: 7, ip: None })
+        );
+        assert!(Beacon::from_bytes(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_neighbors_age_out() {
+        let t0 = Instant::now();
+        let mut table = NeighborTable::new(Duration::from_secs(15));
+        let beacon =
+            |mac| Frame::new_beacon(0, mac, Beacon { mac, ip: None }.to_bytes());
+
+        assert!(table.observe(&beacon(1), t0));
+        assert!(table.observe(&beacon(2), t0 + Duration::from_secs(10)));
+        // Data from a known neighbor keeps it alive, strangers are not added
+        table.observe(
+            &Frame::new_data(0, 1, 3, vec![1]),
+            t0 + Duration::from_secs(12),
+        );
+        table.observe(
+            &Frame::new_data(0, 4, 3, vec![1]),
+            t0 + Duration::from_secs(12),
+        );
+
+        let macs = |now| {
+            table
+                .neighbors(now)
+                .iter()
+                .map(|n| n.mac)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(macs(t0 + Duration::from_secs(20)), vec![1, 2]);
+        assert_eq!(macs(t0 + Duration::from_secs(26)), vec![1]);
+
+        table.expire(t0 + Duration::from_secs(30));
+        assert!(table.neighbors(t0).is_empty());
+    }
+
+    #[test]
+    fn test_beacons_wait_for_quiet_link() {
+        let t0 = Instant::now();
+        let mut discovery = Discovery::new(1, None);
+        discovery.set_interval(Duration::from_secs(5));
+
+        assert!(
+            discovery
+                .poll_beacon(t0)
+                .is_some()
+        );
+        assert!(
+            discovery
+                .poll_beacon(t0 + Duration::from_secs(4))
+                .is_none()
+        );
+        discovery.note_traffic(t0 + Duration::from_secs(4));
+        assert!(
+            discovery
+                .poll_beacon(t0 + Duration::from_secs(6))
+                .is_none()
+        );
+        assert!(
+            discovery
+                .poll_beacon(t0 + Duration::from_secs(9))
+                .is_some()
+        );
+    }
+}
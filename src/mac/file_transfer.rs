@@ -0,0 +1,662 @@
+// File transfer protocol
+//
+// Tx/Rx move one file as a series of messages, one per data frame (or
+// sub-packet when aggregating):
+//
+// Header:  [Tag:'H'] [Size:4] [Mode:4] [Compression:1] [SHA-256:32]
+//          [NameLen:1] [Name]
+// Chunk:   [Tag:'D'] [Offset:4] [Data]
+// Trailer: [Tag:'T'] [StreamSize:4] [SHA-256:32]
+// Verdict: [Tag:'V'] [Passed:1] [Received:4]
+//
+// Integers are big-endian. Chunks carry the stream: the file itself, or
+// deflated with --compress (0 = none, 1 = deflate), whichever is smaller.
+// Size and hash are the file's, StreamSize what the chunks add up to.
+// The receiver puts chunks where their offsets say, so the order they
+// arrive in doesn't matter. Once the trailer and every byte it announces
+// are in, it inflates the stream, checks the size and hash and answers
+// with a verdict, an ACK carrying it (see CsmaNode::await_reply).
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::utils::consts::DEFLATE_LEVEL;
+
+/// Tag + Size + Mode + Compression + SHA-256 + NameLen
+pub const HEADER_FIXED_BYTES: usize = 1 + 4 + 4 + 1 + 32 + 1;
+/// Tag + Offset
+pub const CHUNK_HEADER_BYTES: usize = 1 + 4;
+
+const HEADER_TAG: u8 = b'H';
+const CHUNK_TAG: u8 = b'D';
+const TRAILER_TAG: u8 = b'T';
+const VERDICT_TAG: u8 = b'V';
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Permission bits of the file at `path`, 0o644 where there are none
+pub fn file_mode(path: &Path) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            return metadata.permissions().mode() & 0o777;
+        }
+    }
+    let _ = path;
+    0o644
+}
+
+/// Give the file at `path` the permission bits `mode`, where there are any
+pub fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(
+            path,
+            std::fs::Permissions::from_mode(mode & 0o777),
+        )?;
+    }
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// The last component of a received `name`, so a file can't be written
+/// outside the output directory. None if there is nothing left.
+pub fn safe_file_name(name: &str) -> Option<&str> {
+    Path::new(name)
+        .file_name()?
+        .to_str()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, String> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            _ => Err(format!("Unknown compression {}", code)),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Deflate => {
+                miniz_oxide::deflate::compress_to_vec(data, DEFLATE_LEVEL)
+            }
+        }
+    }
+
+    /// `stream` unpacked, failing past `size` bytes
+    pub fn decompress(
+        self,
+        stream: &[u8],
+        size: usize,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            Compression::None => Ok(stream.to_vec()),
+            Compression::Deflate => {
+                miniz_oxide::inflate::decompress_to_vec_with_limit(stream, size)
+                    .map_err(|e| format!("Inflating failed: {:?}", e.status))
+            }
+        }
+    }
+}
+
+/// What the header announces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub name: String,
+    pub size: u32,
+    /// Unix permission bits
+    pub mode: u32,
+    /// How the chunks carry the file
+    pub compression: Compression,
+    pub sha256: [u8; 32],
+}
+
+/// The receiver's answer to a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub passed: bool,
+    /// Distinct stream bytes received
+    pub received: u32,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes received)",
+            if self.passed { "PASS" } else { "FAIL" },
+            self.received
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Header(FileHeader),
+    Chunk { offset: u32, data: Vec<u8> },
+    Trailer { stream_size: u32, sha256: [u8; 32] },
+    Verdict(Verdict),
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Message::Header(header) => {
+                bytes.push(HEADER_TAG);
+                bytes.extend_from_slice(&header.size.to_be_bytes());
+                bytes.extend_from_slice(&header.mode.to_be_bytes());
+                bytes.push(header.compression.code());
+                bytes.extend_from_slice(&header.sha256);
+                bytes.push(header.name.len() as u8);
+                bytes.extend_from_slice(header.name.as_bytes());
+            }
+            Message::Chunk { offset, data } => {
+                bytes.push(CHUNK_TAG);
+                bytes.extend_from_slice(&offset.to_be_bytes());
+                bytes.extend_from_slice(data);
+            }
+            Message::Trailer {
+                stream_size,
+                sha256,
+            } => {
+                bytes.push(TRAILER_TAG);
+                bytes.extend_from_slice(&stream_size.to_be_bytes());
+                bytes.extend_from_slice(sha256);
+            }
+            Message::Verdict(verdict) => {
+                bytes.push(VERDICT_TAG);
+                bytes.push(verdict.passed as u8);
+                bytes.extend_from_slice(&verdict.received.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let u32_at = |at: usize| -> Result<u32, String> {
+            bytes
+                .get(at..at + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(|| "Message truncated".to_string())
+        };
+        let sha_at = |at: usize| -> Result<[u8; 32], String> {
+            bytes
+                .get(at..at + 32)
+                .map(|b| b.try_into().unwrap())
+                .ok_or_else(|| "Message truncated".to_string())
+        };
+        match bytes.first() {
+            Some(&HEADER_TAG) => {
+                let name_len = *bytes
+                    .get(HEADER_FIXED_BYTES - 1)
+                    .ok_or("Message truncated")?
+                    as usize;
+                let name = bytes
+                    .get(HEADER_FIXED_BYTES..HEADER_FIXED_BYTES + name_len)
+                    .ok_or("Message truncated")?;
+                Ok(Message::Header(FileHeader {
+                    name: String::from_utf8_lossy(name).into_owned(),
+                    size: u32_at(1)?,
+                    mode: u32_at(5)?,
+                    compression: Compression::from_code(bytes[9])?,
+                    sha256: sha_at(10)?,
+                }))
+            }
+            Some(&CHUNK_TAG) => Ok(Message::Chunk {
+                offset: u32_at(1)?,
+                data: bytes[CHUNK_HEADER_BYTES..].to_vec(),
+            }),
+            Some(&TRAILER_TAG) => Ok(Message::Trailer {
+                stream_size: u32_at(1)?,
+                sha256: sha_at(5)?,
+            }),
+            Some(&VERDICT_TAG) => Ok(Message::Verdict(Verdict {
+                passed: *bytes
+                    .get(1)
+                    .ok_or("Message truncated")?
+                    != 0,
+                received: u32_at(2)?,
+            })),
+            Some(tag) => Err(format!("Unknown message tag {:#04x}", tag)),
+            None => Err("Empty message".to_string()),
+        }
+    }
+}
+
+/// `name` cut to what a header of `max_bytes` holds, on a char boundary
+fn truncated_name(name: &str, max_bytes: usize) -> &str {
+    let max = max_bytes
+        .saturating_sub(HEADER_FIXED_BYTES)
+        .min(u8::MAX as usize);
+    let mut end = name.len().min(max);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// A file ready to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutgoingFile {
+    pub header: FileHeader,
+    /// What the chunks carry
+    pub stream: Vec<u8>,
+}
+
+impl OutgoingFile {
+    /// Send `data` as `name`, packed with `compression` unless that doesn't
+    /// make it smaller. The name is cut short to fit a header of
+    /// `header_bytes`.
+    pub fn new(
+        name: &str,
+        mode: u32,
+        data: &[u8],
+        compression: Compression,
+        header_bytes: usize,
+    ) -> Self {
+        let mut stream = compression.compress(data);
+        let compression = if stream.len() < data.len() {
+            compression
+        } else {
+            stream = data.to_vec();
+            Compression::None
+        };
+        Self {
+            header: FileHeader {
+                name: truncated_name(name, header_bytes).to_string(),
+                size: data.len() as u32,
+                mode,
+                compression,
+                sha256: sha256(data),
+            },
+            stream,
+        }
+    }
+
+    /// Header, chunks of up to `chunk_bytes` (message included), trailer
+    pub fn messages(&self, chunk_bytes: usize) -> Vec<Vec<u8>> {
+        let per_chunk = chunk_bytes - CHUNK_HEADER_BYTES;
+        let mut messages = vec![Message::Header(self.header.clone()).to_bytes()];
+        messages.extend(
+            self.stream
+                .chunks(per_chunk)
+                .enumerate()
+                .map(|(i, chunk)| {
+                    Message::Chunk {
+                        offset: (i * per_chunk) as u32,
+                        data: chunk.to_vec(),
+                    }
+                    .to_bytes()
+                }),
+        );
+        messages.push(
+            Message::Trailer {
+                stream_size: self.stream.len() as u32,
+                sha256: self.header.sha256,
+            }
+            .to_bytes(),
+        );
+        messages
+    }
+}
+
+/// Puts a received file back together from its messages
+#[derive(Debug, Default)]
+pub struct FileAssembler {
+    header: Option<FileHeader>,
+    /// Stream size and file hash the trailer announced
+    trailer: Option<(u32, [u8; 32])>,
+    data: Vec<u8>,
+    offsets: HashSet<u32>,
+    received: u32,
+    answered: bool,
+}
+
+impl FileAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take in the next message. Returns the verdict to send back, once,
+    /// when the trailer and every byte it announces are in.
+    pub fn accept(&mut self, bytes: &[u8]) -> Result<Option<Verdict>, String> {
+        match Message::from_bytes(bytes)? {
+            Message::Header(header) => self.header = Some(header),
+            Message::Chunk { offset, data } => {
+                // Duplicates of a chunk carry the same bytes
+                if self.offsets.insert(offset) {
+                    let end = offset as usize + data.len();
+                    if self.data.len() < end {
+                        self.data.resize(end, 0);
+                    }
+                    self.data[offset as usize..end].copy_from_slice(&data);
+                    self.received += data.len() as u32;
+                }
+            }
+            Message::Trailer {
+                stream_size,
+                sha256,
+            } => self.trailer = Some((stream_size, sha256)),
+            Message::Verdict(_) => {
+                return Err("Unexpected verdict from the sender".to_string());
+            }
+        }
+        let complete = self
+            .trailer
+            .is_some_and(|(size, _)| self.received >= size);
+        if complete && !self.answered {
+            self.answered = true;
+            return Ok(Some(self.verdict()));
+        }
+        Ok(None)
+    }
+
+    pub fn header(&self) -> Option<&FileHeader> {
+        self.header.as_ref()
+    }
+
+    /// Distinct stream bytes received so far
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    /// Stream size, once the trailer (or the header of an uncompressed
+    /// file) announced it
+    pub fn expected_size(&self) -> Option<u32> {
+        match (self.trailer, &self.header) {
+            (Some((size, _)), _) => Some(size),
+            (None, Some(header)) if header.compression == Compression::None => {
+                Some(header.size)
+            }
+            _ => None,
+        }
+    }
+
+    /// The stream as received so far, compressed if it was sent so
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The file, if it came through whole, as the sender hashed it
+    pub fn file(&self) -> Result<Vec<u8>, String> {
+        let (stream_size, expected) = match (self.trailer, &self.header) {
+            (Some(trailer), _) => trailer,
+            (None, Some(header)) if header.compression == Compression::None => {
+                (header.size, header.sha256)
+            }
+            (None, Some(_)) => return Err("Trailer not received".to_string()),
+            (None, None) => {
+                return Err("Neither header nor trailer received".to_string());
+            }
+        };
+        if self.received != stream_size
+            || self.data.len() != stream_size as usize
+        {
+            return Err(format!(
+                "{} of {} bytes received",
+                self.received, stream_size
+            ));
+        }
+        let file = match &self.header {
+            Some(header) => {
+                let file = header
+                    .compression
+                    .decompress(&self.data, header.size as usize)?;
+                if file.len() != header.size as usize {
+                    return Err(format!(
+                        "{} bytes instead of {}",
+                        file.len(),
+                        header.size
+                    ));
+                }
+                file
+            }
+            None => self.data.clone(),
+        };
+        if sha256(&file) != expected {
+            return Err("SHA-256 mismatch".to_string());
+        }
+        Ok(file)
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        self.file().map(|_| ())
+    }
+
+    pub fn verdict(&self) -> Verdict {
+        Verdict {
+            passed: self.verify().is_ok(),
+            received: self.received,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::consts::MAX_FRAME_DATA_SIZE;
+
+    fn binary_file() -> Vec<u8> {
+        // Not UTF-8, every byte value, a short last chunk
+        (0..1000u32)
+            .map(|i| (i * 167 % 256) as u8)
+            .chain([0xFF, 0xFE, 0xC0, 0x80])
+            .collect()
+    }
+
+    #[test]
+    fn test_messages_roundtrip() {
+        let messages = [
+            Message::Header(FileHeader {
+                name: "ünïcode.bin".to_string(),
+                size: 70000,
+                mode: 0o755,
+                compression: Compression::Deflate,
+                sha256: sha256(b"abc"),
+            }),
+            Message::Chunk {
+                offset: 123456,
+                data: vec![0xFF, 0x00, 0x80],
+            },
+            Message::Trailer {
+                stream_size: 70000,
+                sha256: [7; 32],
+            },
+            Message::Verdict(Verdict {
+                passed: true,
+                received: 70000,
+            }),
+        ];
+        for message in messages {
+            assert_eq!(Message::from_bytes(&message.to_bytes()), Ok(message));
+        }
+        assert!(Message::from_bytes(&[HEADER_TAG, 0, 0]).is_err());
+        assert!(Message::from_bytes(b"X").is_err());
+        // Known answer for "abc"
+        assert_eq!(sha256(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf]);
+    }
+
+    #[test]
+    fn test_long_name_cut_to_fit() {
+        let name = "é".repeat(100);
+        let messages = OutgoingFile::new(
+            &name,
+            0o644,
+            b"x",
+            Compression::None,
+            MAX_FRAME_DATA_SIZE,
+        )
+        .messages(48);
+        assert!(messages[0].len() <= MAX_FRAME_DATA_SIZE);
+        let Ok(Message::Header(header)) = Message::from_bytes(&messages[0])
+        else {
+            panic!("not a header");
+        };
+        assert!(name.starts_with(&header.name));
+        assert_eq!(header.name.len() % 2, 0);
+    }
+
+    #[test]
+    fn test_out_of_order_and_duplicate_chunks_assemble() {
+        let file = binary_file();
+        let mut messages =
+            OutgoingFile::new("data.bin", 0o600, &file, Compression::None, 128)
+                .messages(48);
+        assert!(
+            messages
+                .iter()
+                .all(|m| m.len() <= 128)
+        );
+        // Trailer ahead of the last chunks, one chunk twice
+        let last = messages.len() - 1;
+        messages.swap(last - 2, last);
+        messages.insert(3, messages[2].clone());
+
+        let mut assembler = FileAssembler::new();
+        let mut verdicts = Vec::new();
+        for message in &messages {
+            verdicts.extend(
+                assembler
+                    .accept(message)
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            verdicts,
+            vec![Verdict {
+                passed: true,
+                received: file.len() as u32
+            }]
+        );
+        assert_eq!(assembler.data(), file);
+        assert_eq!(
+            assembler
+                .header()
+                .unwrap()
+                .mode,
+            0o600
+        );
+    }
+
+    #[test]
+    fn test_missing_or_corrupt_chunks_fail() {
+        let file = binary_file();
+        let messages =
+            OutgoingFile::new("data.bin", 0o644, &file, Compression::None, 128)
+                .messages(128);
+
+        let mut missing = FileAssembler::new();
+        for message in messages
+            .iter()
+            .filter(|m| m[..] != messages[2][..])
+        {
+            assert_eq!(missing.accept(message), Ok(None));
+        }
+        assert!(!missing.verdict().passed);
+        assert!(missing.verify().is_err());
+
+        let mut corrupt = FileAssembler::new();
+        let mut verdict = None;
+        for message in &messages {
+            let mut message = message.clone();
+            if message[0] == CHUNK_TAG {
+                message[CHUNK_HEADER_BYTES] ^= 1;
+            }
+            verdict = verdict.or(corrupt
+                .accept(&message)
+                .unwrap());
+        }
+        assert_eq!(
+            verdict,
+            Some(Verdict {
+                passed: false,
+                received: file.len() as u32
+            })
+        );
+        assert_eq!(corrupt.verify(), Err("SHA-256 mismatch".to_string()));
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let text = "The quick brown fox jumps over the lazy dog. "
+            .repeat(40)
+            .into_bytes();
+        // binary_file() repeats every 256 bytes, this doesn't
+        let mut state = 0x12345678u32;
+        let noise: Vec<u8> = (0..1500)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+
+        for (file, compressed) in [(text, true), (noise, false)] {
+            let outgoing = OutgoingFile::new(
+                "data.bin",
+                0o644,
+                &file,
+                Compression::Deflate,
+                128,
+            );
+            assert_eq!(
+                outgoing.header.compression,
+                if compressed {
+                    Compression::Deflate
+                } else {
+                    Compression::None
+                }
+            );
+            assert_eq!(outgoing.stream.len() < file.len(), compressed);
+
+            let mut assembler = FileAssembler::new();
+            let mut verdict = None;
+            for message in outgoing.messages(64) {
+                verdict = verdict.or(assembler
+                    .accept(&message)
+                    .unwrap());
+            }
+            assert_eq!(
+                verdict,
+                Some(Verdict {
+                    passed: true,
+                    received: outgoing.stream.len() as u32
+                })
+            );
+            assert_eq!(assembler.data(), outgoing.stream);
+            assert_eq!(assembler.file(), Ok(file));
+        }
+    }
+}
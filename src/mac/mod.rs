@@ -1,5 +1,20 @@
 pub mod acoustic_interface;
+pub mod aggregation;
+pub mod arq;
+pub mod autorate;
+pub mod bench;
+pub mod crypto;
 pub mod csma;
+pub mod delay;
+pub mod discovery;
+pub mod file_transfer;
+pub mod nav;
+pub mod noise;
+pub mod params;
+pub mod queue;
+pub mod relay;
+pub mod stats;
+pub mod timing;
 pub mod transfer;
 pub mod types;
 
@@ -11,17 +26,23 @@ pub enum CSMAState {
     Transmitting,         // Transmitting Frame
     WaitingForDIFS,       // Waiting for DIFS
     WaitingForAck,        // Waiting for ACK
+    SendingRts,           // Reserving the channel, see mac::nav
+    WaitingForCts,        // Waiting for CTS
 }
 
-use crate::utils::consts::{ENERGY_DETECTION_SAMPLES, ENERGY_THRESHOLD};
-
-pub fn is_channel_busy(samples: &[f32]) -> Option<bool> {
-    if samples.len() < ENERGY_DETECTION_SAMPLES {
-        return None;
+impl CSMAState {
+    /// Without the backoff counter, e.g. for the dashboard
+    pub fn name(&self) -> &'static str {
+        match self {
+            CSMAState::Idle => "Idle",
+            CSMAState::Sensing => "Sensing",
+            CSMAState::Backoff(_) => "Backoff",
+            CSMAState::BackoffPaused(_) => "BackoffPaused",
+            CSMAState::Transmitting => "Transmitting",
+            CSMAState::WaitingForDIFS => "WaitingForDIFS",
+            CSMAState::WaitingForAck => "WaitingForAck",
+            CSMAState::SendingRts => "SendingRts",
+            CSMAState::WaitingForCts => "WaitingForCts",
+        }
     }
-    Some(
-        samples
-            .iter()
-            .any(|&s| s.abs() > ENERGY_THRESHOLD),
-    )
 }
@@ -0,0 +1,159 @@
+// Virtual carrier sense for the RTS/CTS handshake
+//
+//   A --RTS(d)--> B          C hears B only
+//   A <--CTS(d')- B   ==>    C sets its NAV to d' and stays quiet
+//   A --DATA----> B
+//   A <--ACK----- B
+//
+// A node that overhears an RTS or CTS addressed to someone else treats the
+// channel as busy for the announced duration, whatever its energy detector
+// says. This covers hidden terminals that plain CSMA cannot see.
+//
+// Unicast data frames carry a NAV too (the rest of their exchange: later
+// frames of the burst and the ACK), so neighbours that decode them stay
+// off the channel even while their energy detector misses the ACK.
+
+use std::time::{Duration, Instant};
+
+use crate::mac::noise::NoiseFloor;
+use crate::mac::types::{BROADCAST, MacAddr};
+use crate::phy::Frame;
+
+/// Network allocation vector: until when the channel is reserved by others
+#[derive(Debug, Clone, Default)]
+pub struct Nav {
+    until: Option<Instant>,
+}
+
+impl Nav {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reserve the channel for `duration` from `now`; never shortens an
+    /// earlier reservation
+    pub fn set(&mut self, now: Instant, duration: Duration) {
+        let until = now + duration;
+        if self
+            .until
+            .is_none_or(|current| until > current)
+        {
+            self.until = Some(until);
+        }
+    }
+
+    pub fn is_active(&self, now: Instant) -> bool {
+        self.until
+            .is_some_and(|until| now < until)
+    }
+
+    /// Set the NAV from an overheard frame meant for another node.
+    /// Returns true if `frame` reserved the channel.
+    pub fn observe(
+        &mut self,
+        frame: &Frame,
+        local: MacAddr,
+        now: Instant,
+    ) -> bool {
+        if frame.dst == local || frame.dst == BROADCAST {
+            return false;
+        }
+        let Some(ms) = frame.nav_ms else {
+            return false;
+        };
+        self.set(now, Duration::from_millis(ms as u64));
+        true
+    }
+}
+
+/// Channel state seen through the NAV: busy while it is active, otherwise
+/// what the energy detector says against the noise floor's threshold (see
+/// NoiseFloor::is_channel_busy)
+pub fn is_channel_busy(
+    samples: &[f32],
+    noise: &NoiseFloor,
+    nav: &Nav,
+    now: Instant,
+) -> Option<bool> {
+    if nav.is_active(now) {
+        return Some(true);
+    }
+    noise.is_channel_busy(samples)
+}
+
+/// Milliseconds for the reservation field, saturating
+pub fn duration_ms(duration: Duration) -> u16 {
+    duration
+        .as_millis()
+        .min(u16::MAX as u128) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::params::PhyParams;
+    use crate::phy::{FecKind, LineCodingKind, PhyDecoder, PhyEncoder};
+    use crate::utils::consts::ENERGY_DETECTION_SAMPLES;
+
+    const A: MacAddr = 1;
+    const B: MacAddr = 2;
+    const C: MacAddr = 3;
+
+    #[test]
+    fn test_overheard_cts_suppresses_hidden_node() {
+        // C cannot hear A's RTS, only B's CTS
+        let kind = LineCodingKind::FourBFiveB;
+        let params = PhyParams::default();
+        let enc_b = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut dec_c = PhyDecoder::new(&params, kind, FecKind::None, C);
+
+        let mut samples = enc_b.encode_frame(&Frame::new_cts(9, B, A, 400));
+        samples.extend(vec![0.0; 100]);
+        let heard = dec_c.process_samples(&samples);
+        assert_eq!(heard.len(), 1);
+
+        let mut nav = Nav::new();
+        let t0 = Instant::now();
+        assert!(nav.observe(&heard[0], C, t0));
+
+        // Silence on the air, yet the channel counts as busy
+        let quiet = vec![0.0; ENERGY_DETECTION_SAMPLES];
+        assert_eq!(
+            is_channel_busy(
+                &quiet,
+                &NoiseFloor::default(),
+                &nav,
+                t0 + Duration::from_millis(399)
+            ),
+            Some(true)
+        );
+        assert_eq!(
+            is_channel_busy(
+                &quiet,
+                &NoiseFloor::default(),
+                &nav,
+                t0 + Duration::from_millis(400)
+            ),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_own_handshake_does_not_set_nav() {
+        let mut nav = Nav::new();
+        let t0 = Instant::now();
+        assert!(!nav.observe(&Frame::new_cts(1, B, A, 400), A, t0));
+        assert!(!nav.observe(&Frame::new_data(1, B, C, vec![1]), A, t0));
+        assert!(!nav.is_active(t0));
+    }
+
+    #[test]
+    fn test_nav_is_never_shortened() {
+        let mut nav = Nav::new();
+        let t0 = Instant::now();
+        nav.set(t0, Duration::from_millis(300));
+        nav.set(t0, Duration::from_millis(100));
+        assert!(nav.is_active(t0 + Duration::from_millis(200)));
+        assert_eq!(duration_ms(Duration::from_secs(100)), u16::MAX);
+    }
+}
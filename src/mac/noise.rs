@@ -0,0 +1,292 @@
+// Noise floor tracking and jamming detection
+//
+// The floor is the quietest chunk peak heard over the last
+// NOISE_FLOOR_WINDOW_MS (minimum statistics). Frames leave gaps between
+// them, so on a working channel the minimum lands on an idle stretch; only
+// noise that never lets up (a fan, music) lifts it.
+//
+// The busy threshold follows the floor at +6 dB (NOISE_FLOOR_MARGIN)
+// instead of the fixed energy threshold (ENERGY_THRESHOLD unless set in
+// PhyParams). A floor above the fixed threshold for JAM_DETECT_MS means
+// the band is jammed: a warning goes out through tracing and to the alert
+// channel, once when it starts and once when it clears.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::utils::consts::{
+    ENERGY_DETECTION_SAMPLES, ENERGY_MIN_THRESHOLD, ENERGY_THRESHOLD,
+    JAM_DETECT_MS, NOISE_FLOOR_MARGIN, NOISE_FLOOR_WINDOW_MS,
+};
+
+/// Change in the jamming condition
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JamEvent {
+    /// The floor has stayed above the energy threshold for the detection
+    /// time
+    Jammed { floor: f32 },
+    /// The floor dropped back below it
+    Cleared { floor: f32 },
+}
+
+impl std::fmt::Display for JamEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JamEvent::Jammed { floor } => {
+                write!(f, "channel jammed (noise floor {:.3})", floor)
+            }
+            JamEvent::Cleared { floor } => {
+                write!(f, "channel clear (noise floor {:.3})", floor)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NoiseFloor {
+    /// Busy threshold while the floor is unknown, a floor above it is
+    /// jamming
+    energy_threshold: f32,
+    window: Duration,
+    jam_after: Duration,
+    /// Peak of each observed chunk, oldest first
+    peaks: VecDeque<(Instant, f32)>,
+    above_since: Option<Instant>,
+    jammed: bool,
+    alerts: Option<crossbeam_channel::Sender<JamEvent>>,
+}
+
+impl Default for NoiseFloor {
+    fn default() -> Self {
+        Self::new(ENERGY_THRESHOLD)
+    }
+}
+
+impl NoiseFloor {
+    pub fn new(energy_threshold: f32) -> Self {
+        Self {
+            energy_threshold,
+            window: Duration::from_millis(NOISE_FLOOR_WINDOW_MS),
+            jam_after: Duration::from_millis(JAM_DETECT_MS),
+            peaks: VecDeque::new(),
+            above_since: None,
+            jammed: false,
+            alerts: None,
+        }
+    }
+
+    /// How long the floor must stay above the energy threshold before the
+    /// channel counts as jammed
+    pub fn set_jam_after(&mut self, jam_after: Duration) {
+        self.jam_after = jam_after;
+    }
+
+    /// Also send jam events to `alerts`, e.g. for the UI
+    pub fn set_alerts(&mut self, alerts: crossbeam_channel::Sender<JamEvent>) {
+        self.alerts = Some(alerts);
+    }
+
+    /// Take in samples heard while nobody we know was sending. Returns the
+    /// jam event this caused, if any.
+    pub fn observe(
+        &mut self,
+        samples: &[f32],
+        now: Instant,
+    ) -> Option<JamEvent> {
+        if samples.is_empty() {
+            return None;
+        }
+        let peak = samples
+            .iter()
+            .fold(0.0f32, |peak, s| peak.max(s.abs()));
+        self.peaks
+            .push_back((now, peak));
+        while let Some(&(at, _)) = self.peaks.front()
+            && now.duration_since(at) > self.window
+        {
+            self.peaks.pop_front();
+        }
+
+        let floor = self.floor()?;
+        let event = if floor > self.energy_threshold {
+            let since = *self
+                .above_since
+                .get_or_insert(now);
+            (!self.jammed && now.duration_since(since) >= self.jam_after)
+                .then_some(JamEvent::Jammed { floor })
+        } else {
+            self.above_since = None;
+            self.jammed
+                .then_some(JamEvent::Cleared { floor })
+        };
+
+        if let Some(event) = event {
+            self.jammed = matches!(event, JamEvent::Jammed { .. });
+            if self.jammed {
+                warn!(floor, threshold = self.energy_threshold, "{}", event);
+            } else {
+                info!(floor, "{}", event);
+            }
+            if let Some(alerts) = &self.alerts {
+                // Nobody listening is fine, the log has it
+                let _ = alerts.send(event);
+            }
+        }
+        event
+    }
+
+    /// Quietest peak level in the window, None before any samples
+    pub fn floor(&self) -> Option<f32> {
+        self.peaks
+            .iter()
+            .map(|&(_, peak)| peak)
+            .reduce(f32::min)
+    }
+
+    /// Level above which the channel counts as busy: the floor plus the
+    /// margin, the energy threshold while the floor is unknown
+    pub fn threshold(&self) -> f32 {
+        self.floor()
+            .map_or(self.energy_threshold, |floor| {
+                (floor * NOISE_FLOOR_MARGIN).max(ENERGY_MIN_THRESHOLD)
+            })
+    }
+
+    /// Energy detection against `threshold`, None until there are enough
+    /// samples to tell
+    pub fn is_channel_busy(&self, samples: &[f32]) -> Option<bool> {
+        if samples.len() < ENERGY_DETECTION_SAMPLES {
+            return None;
+        }
+        let threshold = self.threshold();
+        Some(
+            samples
+                .iter()
+                .any(|&s| s.abs() > threshold),
+        )
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random noise peaking at `level`
+    fn noise(level: f32, len: usize, seed: u32) -> Vec<f32> {
+        let mut x = seed | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                level * ((x % 2001) as f32 / 1000.0 - 1.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_threshold_follows_floor() {
+        let mut tracker = NoiseFloor::default();
+        let t0 = Instant::now();
+        assert_eq!(tracker.threshold(), ENERGY_THRESHOLD);
+
+        // Quiet room: the threshold drops to its minimum
+        tracker.observe(&noise(0.01, 480, 1), t0);
+        assert_eq!(tracker.threshold(), ENERGY_MIN_THRESHOLD);
+
+        // A fan at 0.3 would read as a busy channel with the old threshold
+        let fan = noise(0.3, ENERGY_DETECTION_SAMPLES * 10, 2);
+        for i in 1..=20 {
+            tracker.observe(&fan, t0 + Duration::from_millis(100 * i));
+        }
+        let threshold = tracker.threshold();
+        assert!((0.55..=0.6).contains(&threshold), "{}", threshold);
+        assert_eq!(tracker.is_channel_busy(&fan), Some(false));
+        // A frame on top still gets through
+        let frame: Vec<f32> = fan
+            .iter()
+            .map(|s| s + 1.0)
+            .collect();
+        assert_eq!(tracker.is_channel_busy(&frame), Some(true));
+        assert!(!tracker.is_jammed());
+    }
+
+    #[test]
+    fn test_energy_threshold_from_params() {
+        // A quieter setup senses at 0.2 until the floor is known
+        let mut tracker = NoiseFloor::new(0.2);
+        let t0 = Instant::now();
+        let hum = noise(0.3, ENERGY_DETECTION_SAMPLES, 5);
+        assert_eq!(tracker.threshold(), 0.2);
+        assert_eq!(tracker.is_channel_busy(&hum[..1]), None);
+        assert_eq!(NoiseFloor::default().is_channel_busy(&hum), Some(false));
+        assert_eq!(tracker.is_channel_busy(&hum), Some(true));
+
+        // ... and the same level that long is jamming
+        tracker.set_jam_after(Duration::from_millis(200));
+        let event = (0..=3)
+            .filter_map(|i| {
+                tracker.observe(&hum, t0 + Duration::from_millis(100 * i))
+            })
+            .next();
+        assert!(matches!(event, Some(JamEvent::Jammed { .. })));
+    }
+
+    #[test]
+    fn test_jam_triggers_and_clears() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut tracker = NoiseFloor::default();
+        tracker.set_jam_after(Duration::from_millis(500));
+        tracker.set_alerts(tx);
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+
+        // Loud music, but not yet for long enough
+        let music = noise(0.8, 480, 3);
+        for ms in (0..=400).step_by(100) {
+            assert_eq!(tracker.observe(&music, at(ms)), None);
+        }
+        let event = tracker.observe(&music, at(500));
+        assert!(
+            matches!(event, Some(JamEvent::Jammed { floor }) if floor > 0.5)
+        );
+        assert!(tracker.is_jammed());
+        // Reported once
+        assert_eq!(tracker.observe(&music, at(600)), None);
+
+        // Music stops: once the window holds a quiet chunk it clears
+        let quiet = noise(0.01, 480, 4);
+        let event = tracker.observe(&quiet, at(700));
+        assert!(matches!(event, Some(JamEvent::Cleared { .. })));
+        assert!(!tracker.is_jammed());
+
+        let alerts: Vec<_> = rx.try_iter().collect();
+        assert_eq!(alerts.len(), 2);
+        assert!(matches!(alerts[0], JamEvent::Jammed { .. }));
+        assert_eq!(Some(alerts[1]), event);
+    }
+
+    #[test]
+    fn test_short_bursts_do_not_jam() {
+        // Loud frames with idle gaps between them keep the floor down
+        let mut tracker = NoiseFloor::default();
+        tracker.set_jam_after(Duration::from_millis(300));
+        let t0 = Instant::now();
+        for i in 0..50u64 {
+            let chunk = if i % 4 == 3 {
+                noise(0.02, 480, i as u32)
+            } else {
+                noise(1.0, 480, i as u32)
+            };
+            tracker.observe(&chunk, t0 + Duration::from_millis(50 * i));
+        }
+        assert!(!tracker.is_jammed());
+        assert!(tracker.floor().unwrap() < 0.05);
+    }
+}
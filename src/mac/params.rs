@@ -0,0 +1,126 @@
+// CSMA/CA parameters that can be tuned without recompiling
+//
+// Defaults are the constants in utils::consts; main builds one MacParams
+// from the command line and the config file ([phy] section) next to the
+// PhyParams (see phy::params).
+
+use std::time::Duration;
+
+use super::timing::CsmaTiming;
+use crate::utils::consts::{
+    ACK_TIMEOUT_MS, CW_MAX, CW_MIN, DIFS_DURATION_MS, SLOT_TIME_MS,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacParams {
+    /// Lower bound of the DIFS, the idle time before contending
+    pub difs_ms: u64,
+    /// Lower bound of a backoff slot
+    pub slot_ms: u64,
+    /// Contention window at the first backoff stage (in slots)
+    pub cw_min: u32,
+    /// Largest contention window (in slots)
+    pub cw_max: u32,
+    /// How long a sender waits for an ACK, before the airtime of the ACK
+    /// and the audio latency are added
+    pub ack_timeout_ms: u64,
+}
+
+impl Default for MacParams {
+    fn default() -> Self {
+        Self {
+            difs_ms: DIFS_DURATION_MS,
+            slot_ms: SLOT_TIME_MS,
+            cw_min: CW_MIN,
+            cw_max: CW_MAX,
+            ack_timeout_ms: ACK_TIMEOUT_MS,
+        }
+    }
+}
+
+impl MacParams {
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout_ms)
+    }
+
+    /// Timing for the given PHY, floored by the slot and DIFS set here
+    pub fn timing(
+        &self,
+        sample_rate: u32,
+        samples_per_level: usize,
+    ) -> CsmaTiming {
+        CsmaTiming::with_floors(
+            sample_rate,
+            samples_per_level,
+            Duration::from_millis(self.slot_ms),
+            Duration::from_millis(self.difs_ms),
+        )
+    }
+
+    /// Contention window in slots at backoff stage `stage`: cw_min doubled
+    /// on every stage (binary exponential backoff), capped at cw_max
+    pub fn contention_window(&self, stage: u16) -> usize {
+        (self.cw_min as usize)
+            .saturating_mul(1 << stage.min(31))
+            .min(self.cw_max as usize)
+    }
+
+    /// Reject windows the backoff can't draw from
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cw_min == 0 || self.cw_max < self.cw_min {
+            return Err(format!(
+                "contention window {}..{} slots is empty",
+                self.cw_min, self.cw_max
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contention_window_follows_params() {
+        let params = MacParams {
+            cw_min: 4,
+            cw_max: 20,
+            ..MacParams::default()
+        };
+        let windows: Vec<usize> = (0..4)
+            .map(|stage| params.contention_window(stage))
+            .collect();
+        assert_eq!(windows, vec![4, 8, 16, 20]);
+        assert!(params.validate().is_ok());
+        assert!(
+            MacParams {
+                cw_max: 2,
+                ..params
+            }
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_timing_uses_floors() {
+        let params = MacParams {
+            slot_ms: 10,
+            difs_ms: 50,
+            ..MacParams::default()
+        };
+        let timing = params.timing(48000, 3);
+        assert_eq!(timing.slot, Duration::from_millis(10));
+        assert_eq!(timing.difs, Duration::from_millis(50));
+        assert_eq!(
+            MacParams::default().timing(48000, 3),
+            CsmaTiming::with_floors(
+                48000,
+                3,
+                Duration::from_millis(SLOT_TIME_MS),
+                Duration::from_millis(DIFS_DURATION_MS),
+            )
+        );
+    }
+}
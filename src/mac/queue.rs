@@ -0,0 +1,132 @@
+// Transmit queues
+//
+// Two FIFOs in front of the channel. Control frames (ACK, NACK, beacons,
+// RTS/CTS) go out before any queued data, so a long data backlog can't
+// hold up the exchanges that keep the link going. Senders drain the
+// control queue in one burst at every channel access, frames spaced by
+// INTER_FRAME_GAP_SAMPLES, then carry on with data.
+//
+// A TxQueue is a handle: clones share the same queues, so other threads
+// can enqueue while a node transmits.
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::phy::{Frame, FrameType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Link upkeep, sent ahead of all data
+    Control,
+    Data,
+}
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Control => "control",
+            Priority::Data => "data",
+        }
+    }
+
+    /// Default priority of a frame type
+    pub fn for_frame(frame_type: FrameType) -> Self {
+        match frame_type {
+            FrameType::Data | FrameType::Aggregate => Priority::Data,
+            FrameType::Ack
+            | FrameType::Nack
+            | FrameType::Rts
+            | FrameType::Cts
+            | FrameType::Beacon
+            | FrameType::Probe => Priority::Control,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxQueue {
+    control: (Sender<Frame>, Receiver<Frame>),
+    data: (Sender<Frame>, Receiver<Frame>),
+}
+
+impl Default for TxQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxQueue {
+    pub fn new() -> Self {
+        Self {
+            control: crossbeam_channel::unbounded(),
+            data: crossbeam_channel::unbounded(),
+        }
+    }
+
+    pub fn enqueue(&self, frame: Frame, priority: Priority) {
+        let queue = match priority {
+            Priority::Control => &self.control.0,
+            Priority::Data => &self.data.0,
+        };
+        // Both ends live in self, the send cannot fail
+        let _ = queue.send(frame);
+    }
+
+    /// Every queued control frame, oldest first
+    pub fn drain_control(&self) -> Vec<Frame> {
+        self.control
+            .1
+            .try_iter()
+            .collect()
+    }
+
+    /// Next frame to send: control frames before any data
+    pub fn pop(&self) -> Option<(Frame, Priority)> {
+        if let Ok(frame) = self.control.1.try_recv() {
+            return Some((frame, Priority::Control));
+        }
+        self.data
+            .1
+            .try_recv()
+            .ok()
+            .map(|frame| (frame, Priority::Data))
+    }
+
+    /// Next queued data frame, leaving control frames queued
+    pub fn pop_data(&self) -> Option<Frame> {
+        self.data.1.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_jumps_data_backlog() {
+        let queue = TxQueue::new();
+        let producer = queue.clone();
+        for seq in 0..100 {
+            producer.enqueue(
+                Frame::new_data(seq, 1, 2, vec![0; 64]),
+                Priority::Data,
+            );
+        }
+        producer.enqueue(Frame::new_ack(7, 1, 2), Priority::Control);
+
+        let (first, priority) = queue.pop().unwrap();
+        assert_eq!(priority, Priority::Control);
+        assert_eq!(first.frame_type, FrameType::Ack);
+        // Data keeps its order behind it
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|(frame, _)| frame.sequence)
+            .collect();
+        assert_eq!(order, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_priority_by_frame_type() {
+        assert_eq!(Priority::for_frame(FrameType::Data), Priority::Data);
+        assert_eq!(Priority::for_frame(FrameType::Nack), Priority::Control);
+        assert_eq!(Priority::for_frame(FrameType::Beacon), Priority::Control);
+    }
+}
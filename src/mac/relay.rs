@@ -0,0 +1,467 @@
+// Decode-and-forward relay for two peers that cannot hear each other
+//
+//   A ---> R ---> B   (and the other way round)
+//
+// Peers address their frames to the relay. The relay ACKs each frame
+// hop-by-hop, re-addresses it to the other peer with hops + 1 and sends it
+// with the usual CSMA procedure, retransmitting until the far peer ACKs.
+//
+// The ACK is hop-by-hop only: it goes out as soon as the relay has decoded
+// the frame, before forwarding even starts. A sender's ACK says the relay
+// has the frame, not that the far peer does; if the relay gives up on the
+// far peer, the frame is lost and the sender is never told. End-to-end
+// delivery is left to the layer above (the SHA-256 check of
+// mac::file_transfer, TCP).
+//
+// Both hops are stop-and-wait, one ACK per frame, so the peers must run
+// with a window of 1: windowed ARQ takes the relay's per-frame ACKs for
+// cumulative ones. The Hops byte of the frame header came with the relay,
+// see phy::frame.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, error, info, warn};
+
+use crate::audio::{backend, recorder};
+use crate::mac::aggregation;
+use crate::mac::csma::CsmaNode;
+use crate::mac::params::MacParams;
+use crate::mac::types::MacAddr;
+use crate::phy::frame::SeqType;
+use crate::phy::params::PhyParams;
+use crate::phy::{Frame, FrameType, LineCodingKind};
+use crate::ui::progress::ProgressManager;
+use crate::utils::consts::*;
+
+/// How many recent (src, seq) pairs are remembered for duplicate suppression
+const DUPLICATE_WINDOW: usize = 64;
+
+#[derive(Debug, Clone)]
+pub enum RelayAction {
+    /// ACK the previous hop right away
+    Ack(Frame),
+    /// Send towards the other peer, expecting its ACK
+    Forward(Frame),
+}
+
+/// Forwarding decisions of a relay, independent of the audio I/O
+pub struct RelayCore {
+    local: MacAddr,
+    peer_a: MacAddr,
+    peer_b: MacAddr,
+    recent: VecDeque<(MacAddr, SeqType)>,
+}
+
+impl RelayCore {
+    pub fn new(local: MacAddr, peer_a: MacAddr, peer_b: MacAddr) -> Self {
+        Self {
+            local,
+            peer_a,
+            peer_b,
+            recent: VecDeque::with_capacity(DUPLICATE_WINDOW),
+        }
+    }
+
+    /// Decide what to do with a frame addressed to the relay
+    pub fn handle_frame(&mut self, frame: &Frame) -> Vec<RelayAction> {
+        if frame.dst != self.local
+            || !matches!(
+                frame.frame_type,
+                FrameType::Data | FrameType::Aggregate
+            )
+        {
+            return Vec::new();
+        }
+
+        let next_hop = if frame.src == self.peer_a {
+            self.peer_b
+        } else if frame.src == self.peer_b {
+            self.peer_a
+        } else {
+            debug!("Relay ignoring frame from unknown node {}", frame.src);
+            return Vec::new();
+        };
+
+        let ack = match frame.frame_type {
+            FrameType::Aggregate => {
+                let (_, status) = aggregation::decode_aggregate(&frame.data);
+                Frame::new_ack_mix(
+                    frame.sequence,
+                    self.local,
+                    frame.src,
+                    aggregation::encode_ack_bitmap(&status),
+                )
+            }
+            _ => Frame::new_ack(frame.sequence, self.local, frame.src),
+        };
+        let mut actions = vec![RelayAction::Ack(ack)];
+
+        if frame.hops >= RELAY_MAX_HOPS {
+            warn!(
+                "Dropping seq {} from {}: hop limit {} reached",
+                frame.sequence, frame.src, RELAY_MAX_HOPS
+            );
+            return actions;
+        }
+
+        let key = (frame.src, frame.sequence);
+        if self.recent.contains(&key) {
+            debug!(
+                "Duplicate seq {} from {}, re-ACK only",
+                frame.sequence, frame.src
+            );
+            return actions;
+        }
+        if self.recent.len() == DUPLICATE_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(key);
+
+        let data = match frame.frame_type {
+            // Only pass on the sub-packets that survived this hop
+            FrameType::Aggregate => {
+                let (pieces, _) = aggregation::decode_aggregate(&frame.data);
+                if pieces.is_empty() {
+                    return actions;
+                }
+                aggregation::encode_aggregate(&pieces)
+            }
+            _ => frame.data.clone(),
+        };
+
+        let mut forward = Frame::new(
+            frame.frame_type,
+            frame.sequence,
+            self.local,
+            next_hop,
+            data,
+        );
+        forward.hops = frame.hops + 1;
+        actions.push(RelayAction::Forward(forward));
+        actions
+    }
+}
+
+/// For a forwarded aggregate that was only partly ACKed, the frame carrying
+/// the missing sub-packets
+pub fn unacked_remainder(forwarded: &Frame, ack: &Frame) -> Option<Frame> {
+    if forwarded.frame_type != FrameType::Aggregate {
+        return None;
+    }
+    let (pieces, _) = aggregation::decode_aggregate(&forwarded.data);
+    let status = aggregation::decode_ack_bitmap(&ack.data, pieces.len())
+        .unwrap_or_default();
+    let missing: Vec<_> = pieces
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            !status
+                .get(*i)
+                .copied()
+                .unwrap_or(false)
+        })
+        .map(|(_, piece)| piece)
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+
+    let mut frame = forwarded.clone();
+    frame.data = aggregation::encode_aggregate(&missing);
+    Some(frame)
+}
+
+pub fn run_relay(
+    local: MacAddr,
+    peer_a: MacAddr,
+    peer_b: MacAddr,
+    line_coding: LineCodingKind,
+    duration: u64,
+    phy_params: &PhyParams,
+    mac_params: &MacParams,
+) {
+    info!(
+        "Relay {} between peers {} and {} ({})",
+        local,
+        peer_a,
+        peer_b,
+        line_coding.name()
+    );
+
+    // Setup audio
+    let client = backend::open(&format!(
+        "{}_relay_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))
+    .unwrap();
+
+    let sample_rate = client.sample_rate() as u32;
+    let max_samples = sample_rate as usize * duration as usize;
+    let shared = recorder::AppShared::new(max_samples);
+    let shared_cb = shared.clone();
+
+    let active_client = client
+        .activate(recorder::build_process_closure(shared_cb))
+        .unwrap();
+
+    let mut node = CsmaNode::new(
+        shared,
+        Arc::new(Mutex::new(ProgressManager::new())),
+        sample_rate,
+        line_coding,
+        local,
+        peer_a,
+        phy_params,
+    );
+    node.set_mac_params(mac_params);
+    let mut relay = RelayCore::new(local, peer_a, peer_b);
+
+    if let Err(e) = node.run_relay_loop(&mut relay, duration) {
+        warn!("Relay stopped: {}", e);
+    }
+
+    let stats = node.stats();
+    info!(
+        "Relay finished: {} frames received, {} forwarded ({} retransmissions), {} ACKs sent",
+        stats.frames_received,
+        stats.frames_sent,
+        stats.retransmissions,
+        stats.acks_sent
+    );
+
+    if let Err(err) = active_client.deactivate() {
+        error!("Error deactivating client: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::params::PhyParams;
+    use crate::phy::{FecKind, PhyDecoder, PhyEncoder};
+
+    const A: MacAddr = 1;
+    const B: MacAddr = 2;
+    const R: MacAddr = 3;
+
+    fn air(encoder: &PhyEncoder, frame: &Frame) -> Vec<f32> {
+        let mut samples = encoder.encode_frame(frame);
+        samples.extend(vec![0.0; 100]);
+        samples
+    }
+
+    #[test]
+    fn test_three_node_transfer_through_relay() {
+        let kind = LineCodingKind::FourBFiveB;
+        let params = PhyParams::default();
+        let enc_a = PhyEncoder::new(&params, kind, FecKind::None);
+        let enc_b = PhyEncoder::new(&params, kind, FecKind::None);
+        let enc_r = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut dec_a = PhyDecoder::new(&params, kind, FecKind::None, A);
+        let mut dec_b = PhyDecoder::new(&params, kind, FecKind::None, B);
+        let mut dec_r = PhyDecoder::new(&params, kind, FecKind::None, R);
+        let mut relay = RelayCore::new(R, A, B);
+
+        let message: Vec<u8> = (0..=255u8)
+            .cycle()
+            .take(600)
+            .collect();
+        let mut received = Vec::new();
+
+        for (seq, chunk) in message
+            .chunks(MAX_FRAME_DATA_SIZE)
+            .enumerate()
+        {
+            // A -> R only, B cannot hear A
+            let frame = Frame::new_data(seq as SeqType, A, R, chunk.to_vec());
+            let at_relay = dec_r.process_samples(&air(&enc_a, &frame));
+            assert_eq!(at_relay.len(), 1);
+
+            let mut forwards = Vec::new();
+            for action in relay.handle_frame(&at_relay[0]) {
+                match action {
+                    RelayAction::Ack(ack) => {
+                        let acks = dec_a.process_samples(&air(&enc_r, &ack));
+                        assert_eq!(acks[0].sequence, seq as SeqType);
+                    }
+                    RelayAction::Forward(f) => forwards.push(f),
+                }
+            }
+            assert_eq!(forwards.len(), 1);
+
+            // R -> B, then B's ACK back to R
+            let at_b = dec_b.process_samples(&air(&enc_r, &forwards[0]));
+            assert_eq!(at_b.len(), 1);
+            assert_eq!(at_b[0].src, R);
+            assert_eq!(at_b[0].hops, 1);
+            received.extend_from_slice(&at_b[0].data);
+
+            let ack = Frame::new_ack(at_b[0].sequence, B, R);
+            let acks = dec_r.process_samples(&air(&enc_b, &ack));
+            assert_eq!(acks[0].frame_type, FrameType::Ack);
+            assert_eq!(acks[0].src, B);
+        }
+
+        assert_eq!(received, message);
+    }
+
+    /// A (node 0) sends `payloads` to B (node 1) through R (node 2) over a
+    /// loopback medium where A and B can't hear each other, and R can't
+    /// reach B either if `b_reachable` is false. Returns A's result and
+    /// what B delivered.
+    fn send_through_relay(
+        payloads: &[Vec<u8>],
+        b_reachable: bool,
+    ) -> (Result<(), String>, Vec<Vec<u8>>) {
+        use crate::audio::loopback::{
+            LinkParams, LoopbackConfig, LoopbackMedium,
+        };
+
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        let cut = LinkParams {
+            loss: 1.0,
+            ..LinkParams::default()
+        };
+        medium.set_link(0, 1, cut);
+        medium.set_link(1, 0, cut);
+        if !b_reachable {
+            medium.set_link(2, 1, cut);
+        }
+        let mut nodes = medium.nodes(3, SAMPLE_RATE as usize * 30);
+        let (r, _r_audio) = nodes.pop().unwrap();
+        let (b, _b_audio) = nodes.pop().unwrap();
+        let (a, _a_audio) = nodes.pop().unwrap();
+
+        let progress = ProgressManager::new();
+        progress
+            .add_frames_bar("sender", payloads.len() as u64)
+            .unwrap();
+        progress
+            .add_frames_bar("receiver", 0)
+            .unwrap();
+        let progress = Arc::new(Mutex::new(progress));
+        let kind = LineCodingKind::FourBFiveB;
+        let node = |shared, local, remote| {
+            CsmaNode::new(
+                shared,
+                progress.clone(),
+                SAMPLE_RATE,
+                kind,
+                local,
+                remote,
+                &PhyParams::default(),
+            )
+        };
+        let mut sender = node(a, A, R);
+        let mut receiver = node(b.clone(), B, R);
+        // The relay ACKs and forwards frame by frame
+        sender.set_window(1);
+        receiver.set_window(1);
+        let mut relay_node = node(r, R, A);
+
+        let relaying = std::thread::spawn(move || {
+            relay_node.run_relay_loop(&mut RelayCore::new(R, A, B), 4)
+        });
+        let (delivered_tx, delivered_rx) = crossbeam_channel::unbounded();
+        let receiving = std::thread::spawn(move || {
+            receiver.run_receiver_loop(SAMPLE_RATE * 30, 10, delivered_tx)
+        });
+
+        let (queue_tx, queue_rx) = crossbeam_channel::unbounded();
+        for payload in payloads {
+            queue_tx
+                .send(payload.clone())
+                .unwrap();
+        }
+        drop(queue_tx);
+        let (failures_tx, _failures_rx) = crossbeam_channel::unbounded();
+        let result = sender.run_sender_loop(10, queue_rx, failures_tx);
+
+        // R is done by its deadline, B once R is
+        assert_eq!(relaying.join().unwrap(), Ok(()));
+        b.app_state
+            .set(recorder::AppState::Idle);
+        assert_eq!(receiving.join().unwrap(), Ok(()));
+        (
+            result,
+            delivered_rx
+                .try_iter()
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_three_nodes_over_loopback_through_relay() {
+        let payloads: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| vec![i; 24])
+            .collect();
+        let (result, delivered) = send_through_relay(&payloads, true);
+        assert_eq!(result, Ok(()));
+        assert_eq!(delivered, payloads);
+    }
+
+    #[test]
+    fn test_relay_acks_before_forwarding() {
+        // A's frames are ACKed by R whether B ever gets them or not
+        let payloads = vec![vec![7; 24]];
+        let (result, delivered) = send_through_relay(&payloads, false);
+        assert_eq!(result, Ok(()));
+        assert!(delivered.is_empty());
+    }
+
+    #[test]
+    fn test_duplicates_are_acked_not_forwarded() {
+        let mut relay = RelayCore::new(R, A, B);
+        let frame = Frame::new_data(5, A, R, vec![1, 2, 3]);
+        assert_eq!(
+            relay
+                .handle_frame(&frame)
+                .len(),
+            2
+        );
+
+        // Lost ACK: A retransmits the same frame
+        let actions = relay.handle_frame(&frame);
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(actions[0], RelayAction::Ack(_)));
+    }
+
+    #[test]
+    fn test_hop_limit_stops_loops() {
+        let mut relay = RelayCore::new(R, A, B);
+        let mut frame = Frame::new_data(1, B, R, vec![9]);
+        frame.hops = RELAY_MAX_HOPS;
+        let actions = relay.handle_frame(&frame);
+        assert_eq!(actions.len(), 1);
+        assert!(matches!(actions[0], RelayAction::Ack(_)));
+    }
+
+    #[test]
+    fn test_unacked_remainder_of_aggregate() {
+        let pieces: Vec<_> = (0..3u8)
+            .map(|id| aggregation::SubPacket {
+                id,
+                data: vec![id; 4],
+            })
+            .collect();
+        let forwarded = Frame::new(
+            FrameType::Aggregate,
+            0,
+            R,
+            B,
+            aggregation::encode_aggregate(&pieces),
+        );
+        let ack = Frame::new_ack_mix(
+            0,
+            B,
+            R,
+            aggregation::encode_ack_bitmap(&[true, false, true]),
+        );
+
+        let rest = unacked_remainder(&forwarded, &ack).unwrap();
+        let (left, _) = aggregation::decode_aggregate(&rest.data);
+        assert_eq!(left, vec![pieces[1].clone()]);
+    }
+}
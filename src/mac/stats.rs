@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mac::delay::FrameDelays;
+
+/// Counters describing the health of one acoustic link
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkStats {
+    /// Data/aggregate frames put on the air, including retransmissions
+    pub frames_sent: u64,
+    /// Frames sent again after an ACK timeout
+    pub retransmissions: u64,
+    pub ack_timeouts: u64,
+    /// Lost frames resent after duplicate ACKs, ahead of their timeout
+    pub fast_retransmits: u64,
+    /// Frames given up on after MAX_RETRIES failed exchanges
+    pub frames_dropped: u64,
+    pub acks_received: u64,
+    pub nacks_received: u64,
+    /// Data/aggregate frames decoded for us, including duplicates
+    pub frames_received: u64,
+    pub duplicate_frames: u64,
+    /// Frames whose header or payload checksum did not match
+    pub crc_failures: u64,
+    /// Data/aggregate frames for us that failed to authenticate, dropped
+    #[serde(default)]
+    pub auth_failures: u64,
+    /// Frames that arrived after a lost one (dropped by Go-Back-N, buffered
+    /// by selective repeat)
+    pub out_of_order_frames: u64,
+    pub acks_sent: u64,
+    pub nacks_sent: u64,
+    pub rts_sent: u64,
+    /// RTS that went unanswered, the burst was not sent
+    pub cts_timeouts: u64,
+    pub cts_sent: u64,
+    /// Overheard frames whose NAV reserved the channel for other nodes
+    pub nav_reservations: u64,
+    /// ACKs withheld for duplicates that had just been re-ACKed
+    pub acks_suppressed: u64,
+    /// Samples this node has played (frames and ACKs)
+    pub tx_airtime_samples: u64,
+    /// Our own frames decoded from the recording in full duplex, dropped
+    pub own_frames_heard: u64,
+    /// Recorded samples dropped because the MAC fell behind reading them
+    pub rx_overflows: u64,
+    /// Samples the sound card wanted to play before they were queued
+    pub tx_underflows: u64,
+    /// Frames acknowledged with a measured round trip
+    pub rtt_samples: u64,
+    /// Sum of the measured round trips, in microseconds
+    pub rtt_total_us: u64,
+    /// Queueing and one-way delays of timestamped frames received
+    #[serde(default)]
+    pub delays: FrameDelays,
+}
+
+impl LinkStats {
+    /// Record the time from starting a transmission to its ACK
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt_samples += 1;
+        self.rtt_total_us += rtt.as_micros() as u64;
+    }
+
+    pub fn average_rtt(&self) -> Option<Duration> {
+        if self.rtt_samples == 0 {
+            return None;
+        }
+        Some(Duration::from_micros(self.rtt_total_us / self.rtt_samples))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average_rtt() {
+        let mut stats = LinkStats::default();
+        assert_eq!(stats.average_rtt(), None);
+        stats.record_rtt(Duration::from_millis(100));
+        stats.record_rtt(Duration::from_millis(300));
+        assert_eq!(stats.average_rtt(), Some(Duration::from_millis(200)));
+    }
+}
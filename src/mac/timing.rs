@@ -0,0 +1,99 @@
+// CSMA/CA timing derived from PHY parameters
+//
+// Propagation delay is assumed to be zero (a few metres of air), so:
+//   sense window = max(ENERGY_DETECTION_SAMPLES, SENSE_WINDOW_LEVELS levels)
+//   slot         = sense window + turnaround
+//   SIFS         = turnaround
+//   DIFS         = SIFS + 2 * slot
+// The slot and DIFS of MacParams (SLOT_TIME_MS / DIFS_DURATION_MS unless
+// configured) act as lower bounds, see MacParams::timing.
+
+use std::time::Duration;
+
+use crate::utils::consts::ENERGY_DETECTION_SAMPLES;
+
+/// Signal levels a sense window must span to see a transmission reliably
+pub const SENSE_WINDOW_LEVELS: usize = 8;
+
+/// Rx/Tx turnaround of the audio path (state switch + one callback period)
+pub const TURNAROUND_US: u64 = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsmaTiming {
+    /// How long to listen before deciding the channel is idle
+    pub sense: Duration,
+    pub slot: Duration,
+    pub sifs: Duration,
+    pub difs: Duration,
+}
+
+impl CsmaTiming {
+    /// Derive timing for the given PHY with explicit slot / DIFS floors
+    pub fn with_floors(
+        sample_rate: u32,
+        samples_per_level: usize,
+        slot_floor: Duration,
+        difs_floor: Duration,
+    ) -> Self {
+        let sense_samples = ENERGY_DETECTION_SAMPLES
+            .max(SENSE_WINDOW_LEVELS * samples_per_level);
+        let sense = samples_to_duration(sense_samples, sample_rate);
+        let sifs = Duration::from_micros(TURNAROUND_US);
+
+        let slot = (sense + sifs).max(slot_floor);
+        let difs = (sifs + 2 * slot).max(difs_floor);
+
+        Self {
+            sense,
+            slot,
+            sifs,
+            difs,
+        }
+    }
+}
+
+fn samples_to_duration(samples: usize, sample_rate: u32) -> Duration {
+    Duration::from_micros(samples as u64 * 1_000_000 / sample_rate as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::consts::{DIFS_DURATION_MS, SLOT_TIME_MS};
+
+    fn default_floors(sample_rate: u32, samples_per_level: usize) -> CsmaTiming {
+        CsmaTiming::with_floors(
+            sample_rate,
+            samples_per_level,
+            Duration::from_millis(SLOT_TIME_MS),
+            Duration::from_millis(DIFS_DURATION_MS),
+        )
+    }
+
+    #[test]
+    fn test_default_phy_uses_floors() {
+        // 3 samples per level at 48 kHz: derived slot is well below 5 ms
+        let timing = default_floors(48000, 3);
+        assert_eq!(timing.sense, Duration::from_micros(500));
+        assert_eq!(timing.slot, Duration::from_millis(SLOT_TIME_MS));
+        assert_eq!(timing.difs, Duration::from_millis(DIFS_DURATION_MS));
+    }
+
+    #[test]
+    fn test_slow_phy_scales_up() {
+        // 480 samples per level: 8 levels = 80 ms of sensing
+        let timing = default_floors(48000, 480);
+        assert_eq!(timing.sense, Duration::from_millis(80));
+        assert_eq!(timing.slot, Duration::from_millis(82));
+        assert_eq!(timing.sifs, Duration::from_millis(2));
+        assert_eq!(timing.difs, Duration::from_millis(166));
+    }
+
+    #[test]
+    fn test_floors_can_be_lowered() {
+        let timing =
+            CsmaTiming::with_floors(48000, 3, Duration::ZERO, Duration::ZERO);
+        assert_eq!(timing.slot, Duration::from_micros(2500));
+        assert_eq!(timing.difs, Duration::from_micros(7000));
+    }
+}
@@ -1,15 +1,175 @@
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-use tracing::{debug, error, info};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
 use crate::audio::recorder;
 use crate::mac;
+use crate::mac::arq::ArqMode;
+use crate::mac::crypto;
 use crate::mac::csma::CsmaNode;
-use crate::phy::LineCodingKind;
-use crate::ui::progress::{ProgressManager, templates};
+use crate::mac::file_transfer::{
+    self, Compression, FileAssembler, Message, OutgoingFile,
+};
+use crate::mac::noise::JamEvent;
+use crate::mac::params::MacParams;
+use crate::mac::queue::Priority;
+use crate::mac::stats::LinkStats;
+use crate::phy::backend::ModulationKind;
+use crate::phy::interleaver::Interleaver;
+use crate::phy::params::PhyParams;
+use crate::phy::{FecKind, Frame, LineCodingKind};
+use crate::ui::dashboard::DashboardEvent;
+use crate::ui::progress::ProgressManager;
 use crate::utils::consts::*;
+use crate::utils::report::{
+    self, FileReport, SessionConfig, SessionReport, SessionStatus, TimingReport,
+};
+
+/// Per-session options of a file transfer
+#[derive(Debug, Clone, Default)]
+pub struct TransferOptions {
+    /// Pack several sub-packets per frame (sender only)
+    pub aggregate: bool,
+    /// Where report.json goes, defaults to ./tmp/sessions/<role>-<time>
+    pub session_dir: Option<PathBuf>,
+    /// Also print the final report to stdout as JSON
+    pub json: bool,
+    /// Duplicate ACK suppression window (receiver only), None = default
+    pub dup_ack_suppression: Option<Duration>,
+    /// Signal path, must match on both ends
+    pub modulation: ModulationKind,
+    /// Forward error correction, must match on both ends
+    pub fec: FecKind,
+    /// Convolutionally code data frame bodies (sender only)
+    pub conv: bool,
+    /// Interleave data frame bodies (sender only)
+    pub interleave: Option<Interleaver>,
+    /// Sliding window size, must match on both ends, None = ARQ_WINDOW
+    pub window: Option<usize>,
+    /// Loss recovery within the window, must match on both ends
+    pub arq: ArqMode,
+    /// Carry ACKs on data frames (duplex only)
+    pub piggyback: bool,
+    /// Keep recording while transmitting (duplex only)
+    pub full_duplex: bool,
+    /// Subtract our own transmissions from the recording (duplex only)
+    pub echo_cancel: bool,
+    /// RTS/CTS before bursts longer than this many bytes, must be set on
+    /// both ends, None = off
+    pub rts_threshold: Option<usize>,
+    /// Failed exchanges before a frame is dropped (sender only), None =
+    /// MAX_RETRIES
+    pub max_retries: Option<u32>,
+    /// Negotiate the body rate with probes, must be set on both ends, off
+    /// keeps SAMPLES_PER_LEVEL
+    pub auto_rate: bool,
+    /// Noise floor above ENERGY_THRESHOLD this long counts as jamming
+    /// (sender only), None = JAM_DETECT_MS
+    pub jam_after: Option<Duration>,
+    /// Measured round trip through the sound card (see calibrate), None =
+    /// negligible
+    pub audio_latency: Option<Duration>,
+    /// File to send (sender only), None = INPUT<local>to<remote>.bin
+    pub file: Option<PathBuf>,
+    /// Write the received file here under the name it was sent with
+    /// (receiver only), None = OUTPUT<remote>to<local>.bin
+    pub output_dir: Option<PathBuf>,
+    /// Deflate the file when that makes it smaller (sender only)
+    pub compress: bool,
+    /// Pre-shared key sealing data frames, must match on both ends, None =
+    /// in the clear
+    pub key: Option<[u8; crypto::KEY_BYTES]>,
+    /// Samples per level, preamble and energy threshold, see PhyParams
+    pub phy: PhyParams,
+    /// CSMA timing, contention window and ACK timeout, see MacParams
+    pub mac: MacParams,
+    /// Where the node publishes its link status for --tui, None = no
+    /// dashboard
+    pub dashboard: Option<crossbeam_channel::Sender<DashboardEvent>>,
+    /// Seconds before the session gives up, None = DEFAULT_TIMEOUT
+    pub duration: Option<u64>,
+}
+
+impl TransferOptions {
+    fn duration_secs(&self) -> u64 {
+        self.duration
+            .unwrap_or(DEFAULT_TIMEOUT as u64)
+    }
+
+    /// Data bytes per frame left after sealing
+    fn frame_data_size(&self) -> usize {
+        match self.key {
+            Some(_) => MAX_FRAME_DATA_SIZE - crypto::SEAL_OVERHEAD,
+            None => MAX_FRAME_DATA_SIZE,
+        }
+    }
+}
+
+fn session_config(
+    role: &str,
+    sample_rate: u32,
+    line_coding: LineCodingKind,
+    local_addr: mac::types::MacAddr,
+    remote_addr: mac::types::MacAddr,
+    options: &TransferOptions,
+) -> SessionConfig {
+    SessionConfig {
+        role: role.to_string(),
+        encoding: line_coding.name().to_string(),
+        modulation: options
+            .modulation
+            .name()
+            .to_string(),
+        sample_rate,
+        samples_per_level: options.phy.samples_per_level,
+        fec: options.fec.name().to_string(),
+        local_addr,
+        remote_addr,
+        aggregate: options.aggregate,
+    }
+}
+
+fn finish_report(report: &SessionReport, options: &TransferOptions) {
+    report
+        .link
+        .delays
+        .log_summary();
+
+    let session_dir = options
+        .session_dir
+        .clone()
+        .unwrap_or_else(|| {
+            report::default_session_dir(
+                &report.config.role,
+                report
+                    .timing
+                    .started_unix_secs,
+            )
+        });
+    match report.write(&session_dir) {
+        Ok(path) => info!("Session report written to {}", path.display()),
+        Err(e) => error!("Failed to write session report: {}", e),
+    }
+
+    if options.json {
+        match report.to_json() {
+            Ok(json) => println!("{}", json),
+            Err(e) => error!("Failed to serialize session report: {}", e),
+        }
+    }
+}
+
+/// INPUT<local>to<peer>.bin, what run_sender sends without a file
+pub fn default_input_path(
+    local: mac::types::MacAddr,
+    peer: mac::types::MacAddr,
+) -> PathBuf {
+    PathBuf::from(format!("INPUT{}to{}.bin", local, peer))
+}
 
 pub fn run_sender(
     shared: recorder::AppShared,
@@ -18,52 +178,206 @@ pub fn run_sender(
     line_coding: LineCodingKind,
     sender_mac: mac::types::MacAddr,
     receiver_mac: mac::types::MacAddr,
-    tx_timeout: u64,
+    options: TransferOptions,
 ) {
-    info!("=== Sender Mode (with Stop-and-Wait) ===");
+    info!(
+        "=== Sender Mode (ARQ window {}) ===",
+        options
+            .window
+            .unwrap_or(ARQ_WINDOW)
+    );
     info!("Using line coding: {}", line_coding.name());
 
+    let start_time = Instant::now();
+    let mut report = SessionReport {
+        status: SessionStatus::Aborted,
+        config: session_config(
+            "tx",
+            sample_rate,
+            line_coding,
+            sender_mac,
+            receiver_mac,
+            &options,
+        ),
+        link: LinkStats::default(),
+        file: None,
+        timing: TimingReport {
+            started_unix_secs: report::unix_now_secs(),
+            total_secs: 0.0,
+            airtime_secs: 0.0,
+        },
+        errors: Vec::new(),
+    };
+
     // Read input file
-    let input_path = format!("INPUT{}to{}.bin", &sender_mac, &receiver_mac);
+    let input_path = options
+        .file
+        .clone()
+        .unwrap_or_else(|| default_input_path(sender_mac, receiver_mac));
     let file_data = match fs::read(&input_path) {
         Ok(data) => {
-            info!("Read {} bytes from {}", data.len(), input_path);
+            info!("Read {} bytes from {}", data.len(), input_path.display());
             data
         }
         Err(e) => {
-            error!("Failed to read {}: {}", input_path, e);
+            error!("Failed to read {}: {}", input_path.display(), e);
+            report.errors.push(format!(
+                "Failed to read {}: {}",
+                input_path.display(),
+                e
+            ));
+            finish_report(&report, &options);
             return;
         }
     };
+    if file_data.len() > u32::MAX as usize {
+        error!("{} is too large to send", input_path.display());
+        report
+            .errors
+            .push(format!("{} is too large to send", input_path.display()));
+        finish_report(&report, &options);
+        return;
+    }
+    let mut file_report = FileReport::new(
+        &input_path
+            .display()
+            .to_string(),
+        &file_data,
+    );
 
-    info!("=== Sender Mode (with Stop-and-Wait) ===");
+    info!("=== Sender Mode ===");
 
     let progress_manager = Arc::new(Mutex::new(progress_manager));
 
     let _sender_progress = progress_manager
         .lock()
         .unwrap()
-        .create_bar("sender", 0u64, templates::SENDER, "sender")
+        .add_frames_bar("sender", 0)
         .unwrap();
 
     let (tx, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+    let (failures_tx, failures_rx) = crossbeam_channel::unbounded();
+
+    // Show jamming on the progress bar while it lasts
+    let (jam_tx, jam_rx) = crossbeam_channel::unbounded();
+    let jam_progress_manager = progress_manager.clone();
+    let jam_handle = thread::spawn(move || {
+        for event in jam_rx {
+            let message = match event {
+                JamEvent::Jammed { .. } => event.to_string(),
+                JamEvent::Cleared { .. } => String::new(),
+            };
+            jam_progress_manager
+                .lock()
+                .unwrap()
+                .set_message("sender", &message)
+                .unwrap_or_else(|err| {
+                    debug!("Error while updating sender: {:?}", err)
+                });
+        }
+    });
 
     let sub_progress_manager = progress_manager.clone();
+    let aggregate = options.aggregate;
+    let conv = options.conv;
+    let interleave = options.interleave;
+    let window = options.window;
+    let arq = options.arq;
+    let rts_threshold = options.rts_threshold;
+    let max_retries = options.max_retries;
+    let auto_rate = options.auto_rate;
+    let jam_after = options.jam_after;
+    let audio_latency = options.audio_latency;
+    let fec = options.fec;
+    let modulation = options.modulation;
+    let key = options.key;
+    let (phy, mac) = (options.phy, options.mac);
+    let dashboard = options.dashboard.clone();
+    let duration = options.duration_secs();
     let handle = thread::spawn(move || {
-        let mut node = CsmaNode::new(
+        let mut node = CsmaNode::with_backend(
             shared,
             sub_progress_manager,
             sample_rate,
-            line_coding,
+            modulation.create(&phy, line_coding, fec, sender_mac),
             sender_mac,
             receiver_mac,
+            &phy,
         );
+        node.set_mac_params(&mac);
+        node.set_aggregation(aggregate);
+        node.set_conv_coding(conv);
+        node.set_interleaver(interleave);
+        if let Some(window) = window {
+            node.set_window(window);
+        }
+        node.set_arq_mode(arq);
+        node.set_rts_cts(rts_threshold);
+        if let Some(max_retries) = max_retries {
+            node.set_max_retries(max_retries);
+        }
+        node.set_auto_rate(auto_rate);
+        if let Some(jam_after) = jam_after {
+            node.set_jam_detection(jam_after);
+        }
+        if let Some(latency) = audio_latency {
+            node.set_audio_latency(latency);
+        }
+        node.set_key(key);
+        node.set_jam_alerts(jam_tx);
+        if let Some(dashboard) = dashboard {
+            node.set_dashboard(dashboard);
+        }
 
-        node.run_sender_loop(tx_timeout, rx);
+        let result = node.run_sender_loop(duration, rx, failures_tx);
+        // Broadcasts go unanswered
+        let reply = if result.is_ok() && receiver_mac != mac::types::BROADCAST {
+            node.await_reply(Duration::from_millis(FILE_VERDICT_TIMEOUT_MS))
+        } else {
+            None
+        };
+        (result, node.stats(), reply)
     });
 
-    // Split data into frames and push to queue
-    for chunk in file_data.chunks(MAX_FRAME_DATA_SIZE) {
+    // Split the file into protocol messages, one per frame (or sub-packet)
+    let chunk_size = if aggregate {
+        AGGREGATE_SUBPACKET_SIZE
+    } else {
+        options.frame_data_size()
+    };
+    let name = input_path
+        .file_name()
+        .map(|name| {
+            name.to_string_lossy()
+                .into_owned()
+        })
+        .unwrap_or_default();
+    let outgoing = OutgoingFile::new(
+        &name,
+        file_transfer::file_mode(&input_path),
+        &file_data,
+        if options.compress {
+            Compression::Deflate
+        } else {
+            Compression::None
+        },
+        options.frame_data_size(),
+    );
+    if options.compress {
+        info!(
+            "{} bytes, {} on the link ({})",
+            file_data.len(),
+            outgoing.stream.len(),
+            outgoing
+                .header
+                .compression
+                .name()
+        );
+    }
+    if outgoing.header.compression != Compression::None {
+        file_report.compressed_bytes = Some(outgoing.stream.len() as u64);
+    }
+    for message in outgoing.messages(chunk_size) {
         progress_manager
             .lock()
             .unwrap()
@@ -71,7 +385,7 @@ pub fn run_sender(
             .unwrap_or_else(|err| {
                 debug!("Error while updating sender: {:?}", err)
             });
-        tx.send(chunk.to_vec())
+        tx.send(message)
             .unwrap_or_else(|e| {
                 error!("Failed to send data chunk to sender thread: {}", e);
             });
@@ -79,7 +393,51 @@ pub fn run_sender(
 
     drop(tx); // Close the channel
 
-    handle.join().unwrap();
+    match handle.join() {
+        Ok((result, stats, reply)) => {
+            report.link = stats;
+            match result {
+                Ok(()) => report.status = SessionStatus::Completed,
+                Err(e) => report.errors.push(e),
+            }
+            match reply.map(|reply| Message::from_bytes(&reply)) {
+                Some(Ok(Message::Verdict(verdict))) => {
+                    info!("Receiver verdict: {}", verdict);
+                    if !verdict.passed {
+                        report
+                            .errors
+                            .push(format!("Receiver verdict: {}", verdict));
+                    }
+                    file_report.verified = Some(verdict.passed);
+                }
+                Some(_) => warn!("Unexpected reply from the receiver"),
+                None if report.status == SessionStatus::Completed
+                    && receiver_mac != mac::types::BROADCAST =>
+                {
+                    warn!("No verdict from the receiver");
+                }
+                None => {}
+            }
+        }
+        Err(_) => report
+            .errors
+            .push("Sender thread panicked".to_string()),
+    }
+    report.file = Some(file_report);
+    // Ends once the node and its alert sender are gone
+    let _ = jam_handle.join();
+    for failure in failures_rx.try_iter() {
+        report
+            .errors
+            .push(failure.to_string());
+    }
+
+    report.timing.total_secs = start_time
+        .elapsed()
+        .as_secs_f64();
+    report.timing.airtime_secs =
+        report.link.tx_airtime_samples as f64 / sample_rate as f64;
+    finish_report(&report, &options);
 }
 
 pub fn run_receiver(
@@ -89,55 +447,624 @@ pub fn run_receiver(
     line_coding: LineCodingKind,
     receiver_addr: mac::types::MacAddr,
     sender_addr: mac::types::MacAddr,
-    rx_duration: u64,
+    options: TransferOptions,
 ) {
     info!("=== Receiver Mode ===");
     info!("Using line coding: {}", line_coding.name());
 
+    let start_time = Instant::now();
+    let mut report = SessionReport {
+        status: SessionStatus::Aborted,
+        config: session_config(
+            "rx",
+            SAMPLE_RATE,
+            line_coding,
+            receiver_addr,
+            sender_addr,
+            &options,
+        ),
+        link: LinkStats::default(),
+        file: None,
+        timing: TimingReport {
+            started_unix_secs: report::unix_now_secs(),
+            total_secs: 0.0,
+            airtime_secs: 0.0,
+        },
+        errors: Vec::new(),
+    };
+
     let (tx, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
 
     let progress_manager = Arc::new(Mutex::new(progress_manager));
 
-    let _progress_bar = progress_manager
+    // Frames as the node delivers them, the file once its size is known
+    {
+        let progress_manager = progress_manager
+            .lock()
+            .unwrap();
+        progress_manager
+            .add_frames_bar("receiver", 0)
+            .unwrap();
+        progress_manager
+            .add_bytes_bar("file", 0)
+            .unwrap();
+    }
+
+    let mut node = CsmaNode::with_backend(
+        shared,
+        progress_manager.clone(),
+        SAMPLE_RATE,
+        options.modulation.create(
+            &options.phy,
+            line_coding,
+            options.fec,
+            receiver_addr,
+        ),
+        receiver_addr,
+        sender_addr,
+        &options.phy,
+    );
+    node.set_mac_params(&options.mac);
+    if let Some(interval) = options.dup_ack_suppression {
+        node.set_dup_ack_suppression(interval);
+    }
+    if let Some(window) = options.window {
+        node.set_window(window);
+    }
+    node.set_arq_mode(options.arq);
+    node.set_rts_cts(options.rts_threshold);
+    node.set_auto_rate(options.auto_rate);
+    if let Some(latency) = options.audio_latency {
+        node.set_audio_latency(latency);
+    }
+    node.set_key(options.key);
+    if let Some(dashboard) = options.dashboard.clone() {
+        node.set_dashboard(dashboard);
+    }
+    // The verdict goes back through the node while it receives
+    let replies = node.tx_queue();
+    let duration = options.duration_secs();
+    let handle = thread::spawn(move || {
+        let result =
+            node.run_receiver_loop(max_recording_duration_samples, duration, tx);
+        (result, node.stats(), node.decode_stats())
+    });
+
+    let mut assembler = FileAssembler::new();
+    let mut first_at = None;
+    let mut verified_at = None;
+    while let Ok(data) = rx.recv() {
+        first_at.get_or_insert_with(Instant::now);
+        match assembler.accept(&data) {
+            Ok(Some(verdict)) => {
+                info!("Transfer complete, answering {}", verdict);
+                verified_at = Some(Instant::now());
+                replies.enqueue(
+                    Frame::new_ack_mix(
+                        0,
+                        receiver_addr,
+                        sender_addr,
+                        Message::Verdict(verdict).to_bytes(),
+                    ),
+                    Priority::Control,
+                );
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Dropping message: {}", e),
+        }
+        let progress = progress_manager
+            .lock()
+            .unwrap();
+        if let Some(size) = assembler.expected_size() {
+            let _ = progress.set_length("file", size as u64);
+        }
+        let _ = progress.set_position("file", assembler.received() as u64);
+    }
+    let _ = progress_manager
         .lock()
         .unwrap()
-        .create_bar(
-            "recording",
-            max_recording_duration_samples as u64,
-            templates::RECEIVER,
-            "receiver",
-        )
+        .finish(
+            "file",
+            if verified_at.is_some() {
+                "Complete"
+            } else {
+                "Incomplete"
+            },
+        );
+
+    match handle.join() {
+        Ok((result, stats, decode_stats)) => {
+            report.link = stats;
+            if let Some(decode_stats) = decode_stats {
+                info!("Receiver decoder: {}", decode_stats);
+            }
+            match result {
+                Ok(()) => report.status = SessionStatus::Completed,
+                Err(e) => report.errors.push(e),
+            }
+        }
+        Err(_) => report
+            .errors
+            .push("Receiver thread panicked".to_string()),
+    }
+
+    let default_name = format!("OUTPUT{}to{}.bin", &sender_addr, &receiver_addr);
+    let output_path = match &options.output_dir {
+        Some(dir) => dir.join(
+            assembler
+                .header()
+                .and_then(|header| file_transfer::safe_file_name(&header.name))
+                .unwrap_or(&default_name),
+        ),
+        None => PathBuf::from(&default_name),
+    };
+    // Whatever arrived when the file didn't come through, to look into
+    let verified = assembler.file();
+    let output_data = verified
+        .as_deref()
+        .unwrap_or(assembler.data());
+    if let Err(e) = write_received(&output_path, output_data, &assembler) {
+        error!("Failed to write {}: {}", output_path.display(), e);
+        report.errors.push(format!(
+            "Failed to write {}: {}",
+            output_path.display(),
+            e
+        ));
+    } else {
+        debug!("Written to {}", output_path.display());
+    }
+
+    let mut file_report = FileReport::new(
+        &output_path
+            .display()
+            .to_string(),
+        output_data,
+    );
+    let on_link = assembler.data().len();
+    if assembler
+        .header()
+        .is_some_and(|header| header.compression != Compression::None)
+    {
+        file_report.compressed_bytes = Some(on_link as u64);
+    }
+    match &verified {
+        Ok(file) => {
+            let secs = match (first_at, verified_at) {
+                (Some(first), Some(last)) => last
+                    .duration_since(first)
+                    .as_secs_f64(),
+                _ => 0.0,
+            };
+            // Effective rate, counting the file as written
+            println!(
+                "PASS: {} ({} bytes, {} on the link) in {:.2} s, {:.1} B/s",
+                output_path.display(),
+                file.len(),
+                on_link,
+                secs,
+                file.len() as f64 / secs.max(f64::EPSILON)
+            );
+        }
+        Err(e) => {
+            println!("FAIL: {}: {}", output_path.display(), e);
+            report
+                .errors
+                .push(format!("File verification failed: {}", e));
+        }
+    }
+    file_report.verified = Some(verified.is_ok());
+    report.file = Some(file_report);
+
+    report.timing.total_secs = start_time
+        .elapsed()
+        .as_secs_f64();
+    report.timing.airtime_secs =
+        report.link.tx_airtime_samples as f64 / SAMPLE_RATE as f64;
+    finish_report(&report, &options);
+}
+
+/// Write `data` to `path`, with the permissions `assembler` says it was
+/// sent with
+fn write_received(
+    path: &Path,
+    data: &[u8],
+    assembler: &FileAssembler,
+) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, data)?;
+    if let Some(header) = assembler.header() {
+        file_transfer::set_file_mode(path, header.mode)?;
+    }
+    Ok(())
+}
+
+/// Send INPUT<local>to<remote>.bin and receive OUTPUT<remote>to<local>.bin
+/// over the same link at the same time
+pub fn run_duplex(
+    shared: recorder::AppShared,
+    progress_manager: ProgressManager,
+    sample_rate: u32,
+    line_coding: LineCodingKind,
+    local_addr: mac::types::MacAddr,
+    remote_addr: mac::types::MacAddr,
+    options: TransferOptions,
+) {
+    info!(
+        "=== Duplex Mode ({} <-> {}, piggyback {}, full duplex {}) ===",
+        local_addr,
+        remote_addr,
+        if options.piggyback { "on" } else { "off" },
+        if options.full_duplex { "on" } else { "off" }
+    );
+    info!("Using line coding: {}", line_coding.name());
+
+    let start_time = Instant::now();
+    let mut report = SessionReport {
+        status: SessionStatus::Aborted,
+        config: session_config(
+            "duplex",
+            sample_rate,
+            line_coding,
+            local_addr,
+            remote_addr,
+            &options,
+        ),
+        link: LinkStats::default(),
+        file: None,
+        timing: TimingReport {
+            started_unix_secs: report::unix_now_secs(),
+            total_secs: 0.0,
+            airtime_secs: 0.0,
+        },
+        errors: Vec::new(),
+    };
+
+    // Nothing to send is fine, the node still receives
+    let input_path = format!("INPUT{}to{}.bin", &local_addr, &remote_addr);
+    let file_data = match fs::read(&input_path) {
+        Ok(data) => {
+            info!("Read {} bytes from {}", data.len(), input_path);
+            data
+        }
+        Err(e) => {
+            info!("Nothing to send, failed to read {}: {}", input_path, e);
+            Vec::new()
+        }
+    };
+
+    let progress_manager = Arc::new(Mutex::new(progress_manager));
+    progress_manager
+        .lock()
+        .unwrap()
+        .add_frames_bar("sender", 0)
         .unwrap();
 
+    let (out_tx, out_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+    let (in_tx, in_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+
+    for chunk in file_data.chunks(options.frame_data_size()) {
+        progress_manager
+            .lock()
+            .unwrap()
+            .increasae_length("sender", 1)
+            .unwrap_or_else(|err| {
+                debug!("Error while updating sender: {:?}", err)
+            });
+        out_tx
+            .send(chunk.to_vec())
+            .unwrap_or_else(|e| {
+                error!("Failed to queue data chunk: {}", e);
+            });
+    }
+    drop(out_tx);
+
     let sub_progress_manager = progress_manager.clone();
+    let piggyback = options.piggyback;
+    let full_duplex = options.full_duplex;
+    let echo_cancel = options.echo_cancel;
+    let audio_latency = options.audio_latency;
+    let fec = options.fec;
+    let modulation = options.modulation;
+    let key = options.key;
+    let (phy, mac) = (options.phy, options.mac);
+    let dashboard = options.dashboard.clone();
+    let duration = options.duration_secs();
     let handle = thread::spawn(move || {
-        let mut node = CsmaNode::new(
+        let mut node = CsmaNode::with_backend(
             shared,
             sub_progress_manager,
-            SAMPLE_RATE,
-            line_coding,
-            receiver_addr,
-            sender_addr,
+            sample_rate,
+            modulation.create(&phy, line_coding, fec, local_addr),
+            local_addr,
+            remote_addr,
+            &phy,
         );
+        node.set_mac_params(&mac);
+        node.set_piggyback(piggyback);
+        node.set_full_duplex(full_duplex);
+        node.set_echo_cancel(echo_cancel);
+        if let Some(latency) = audio_latency {
+            node.set_audio_latency(latency);
+        }
+        node.set_key(key);
+        if let Some(dashboard) = dashboard {
+            node.set_dashboard(dashboard);
+        }
 
-        node.run_receiver_loop(max_recording_duration_samples, rx_duration, tx);
+        let result = node.run_duplex_loop(duration, out_rx, in_tx);
+        (result, node.stats())
     });
 
     let mut all_data = Vec::new();
-    while let Ok(data) = rx.recv() {
+    while let Ok(data) = in_rx.recv() {
         all_data.push(data);
     }
 
-    handle.join().unwrap();
+    match handle.join() {
+        Ok((result, stats)) => {
+            report.link = stats;
+            match result {
+                Ok(()) => report.status = SessionStatus::Completed,
+                Err(e) => report.errors.push(e),
+            }
+        }
+        Err(_) => report
+            .errors
+            .push("Duplex thread panicked".to_string()),
+    }
 
     let output_data: Vec<u8> = all_data
         .into_iter()
         .flatten()
         .collect();
-
-    let output_path = format!("OUTPUT{}to{}.bin", &sender_addr, &receiver_addr);
+    let output_path = format!("OUTPUT{}to{}.bin", &remote_addr, &local_addr);
     match fs::write(&output_path, &output_data) {
         Ok(_) => debug!("Written to {}", &output_path),
-        Err(e) => error!("Failed to write {}: {}", output_path, e),
+        Err(e) => {
+            error!("Failed to write {}: {}", output_path, e);
+            report
+                .errors
+                .push(format!("Failed to write {}: {}", output_path, e));
+        }
+    }
+    report.file = Some(FileReport::new(&output_path, &output_data));
+
+    report.timing.total_secs = start_time
+        .elapsed()
+        .as_secs_f64();
+    report.timing.airtime_secs =
+        report.link.tx_airtime_samples as f64 / sample_rate as f64;
+    finish_report(&report, &options);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::loopback::{LoopbackConfig, LoopbackMedium};
+    use crate::utils::report::REPORT_FILE_NAME;
+
+    fn read_report(session_dir: &Path) -> SessionReport {
+        let json =
+            fs::read_to_string(session_dir.join(REPORT_FILE_NAME)).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    /// Send `file` from node 1 to node 2 with `options` on both ends, check
+    /// it arrived intact and return the tx and rx reports
+    fn sessions_over_loopback(
+        name: &str,
+        file: &[u8],
+        options: TransferOptions,
+    ) -> [SessionReport; 2] {
+        let dir = std::env::temp_dir().join(format!(
+            "trackmaker-transfer-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("payload.bin");
+        fs::write(&input, file).unwrap();
+
+        let medium = LoopbackMedium::new(LoopbackConfig::default());
+        let mut nodes = medium.nodes(2, SAMPLE_RATE as usize * 30);
+        let (b, _b_audio) = nodes.pop().unwrap();
+        let (a, _a_audio) = nodes.pop().unwrap();
+        let kind = LineCodingKind::FourBFiveB;
+
+        let receiver = {
+            let b = b.clone();
+            let options = TransferOptions {
+                output_dir: Some(dir.join("out")),
+                session_dir: Some(dir.join("rx")),
+                duration: Some(30),
+                ..options.clone()
+            };
+            thread::spawn(move || {
+                run_receiver(
+                    b,
+                    ProgressManager::new(),
+                    SAMPLE_RATE * 30,
+                    kind,
+                    2,
+                    1,
+                    options,
+                )
+            })
+        };
+        run_sender(
+            a,
+            ProgressManager::new(),
+            SAMPLE_RATE,
+            kind,
+            1,
+            2,
+            TransferOptions {
+                file: Some(input),
+                session_dir: Some(dir.join("tx")),
+                duration: Some(20),
+                ..options
+            },
+        );
+        // Over once the sender has its verdict
+        b.app_state
+            .set(recorder::AppState::Idle);
+        receiver.join().unwrap();
+
+        assert_eq!(
+            fs::read(
+                dir.join("out")
+                    .join("payload.bin")
+            )
+            .unwrap(),
+            file
+        );
+        let reports = ["tx", "rx"].map(|role| {
+            let report = read_report(&dir.join(role));
+            assert_eq!(
+                report.status,
+                SessionStatus::Completed,
+                "{}: {:?}",
+                role,
+                report.errors
+            );
+            assert!(report.errors.is_empty(), "{}: {:?}", role, report.errors);
+            assert_eq!(
+                report
+                    .file
+                    .as_ref()
+                    .unwrap()
+                    .verified,
+                Some(true),
+                "{}",
+                role
+            );
+            report
+        });
+        fs::remove_dir_all(&dir).unwrap();
+        reports
+    }
+
+    /// sessions_over_loopback, returning the receiver's file report
+    fn transfer_over_loopback(
+        name: &str,
+        file: &[u8],
+        options: TransferOptions,
+    ) -> FileReport {
+        let reports = sessions_over_loopback(name, file, options);
+        // Both ends agree on what went over the link
+        let [tx, rx] = reports.map(|report| report.file.unwrap());
+        assert_eq!(tx.digest, rx.digest);
+        assert_eq!(tx.compressed_bytes, rx.compressed_bytes);
+        rx
+    }
+
+    #[test]
+    fn test_session_reports_over_loopback() {
+        let file: Vec<u8> = (0..300u32)
+            .map(|i| (i * 31 % 256) as u8)
+            .collect();
+        let started = report::unix_now_secs();
+        let [tx, rx] =
+            sessions_over_loopback("report", &file, TransferOptions::default());
+        let finished = report::unix_now_secs();
+
+        assert_eq!(tx.config.role, "tx");
+        assert_eq!((tx.config.local_addr, tx.config.remote_addr), (1, 2));
+        assert_eq!(rx.config.role, "rx");
+        assert_eq!((rx.config.local_addr, rx.config.remote_addr), (2, 1));
+
+        // Every frame the receiver counted was sent, every ACK the sender
+        // counted was sent back, nothing given up on
+        assert!(tx.link.frames_sent > 0);
+        assert_eq!(tx.link.frames_dropped, 0);
+        assert!(rx.link.frames_received > 0);
+        assert!(rx.link.frames_received <= tx.link.frames_sent);
+        assert!(tx.link.acks_received > 0);
+        assert!(tx.link.acks_received <= rx.link.acks_sent);
+
+        for report in [&tx, &rx] {
+            let file_report = report.file.as_ref().unwrap();
+            assert_eq!(file_report.digest, report::file_digest(&file));
+            assert_eq!(file_report.size_bytes, file.len() as u64);
+
+            let timing = &report.timing;
+            assert!((started..=finished).contains(&timing.started_unix_secs));
+            assert!(timing.airtime_secs > 0.0);
+            assert!(timing.airtime_secs <= timing.total_secs, "{:?}", timing);
+            assert_eq!(
+                timing.airtime_secs,
+                report.link.tx_airtime_samples as f64 / SAMPLE_RATE as f64
+            );
+        }
+    }
+
+    #[test]
+    fn test_binary_file_over_loopback() {
+        // Not UTF-8, a few frames' worth
+        let file: Vec<u8> = (0..600u32)
+            .map(|i| (i * 151 % 256) as u8)
+            .chain([0xFF, 0xFE, 0xC3])
+            .collect();
+        assert!(String::from_utf8(file.clone()).is_err());
+        let report =
+            transfer_over_loopback("plain", &file, TransferOptions::default());
+        assert_eq!(report.compressed_bytes, None);
+    }
+
+    #[test]
+    fn test_psk_file_over_loopback() {
+        let file: Vec<u8> = (0..500u32)
+            .map(|i| (i * 37 % 256) as u8)
+            .collect();
+        let [tx, rx] = sessions_over_loopback(
+            "psk",
+            &file,
+            TransferOptions {
+                modulation: ModulationKind::Psk,
+                ..Default::default()
+            },
+        );
+        assert_eq!(tx.config.modulation, "psk");
+        assert_eq!(rx.config.modulation, "psk");
+        assert!(tx.link.acks_received > 0);
+        assert!(rx.link.frames_received > 0);
+    }
+
+    #[test]
+    fn test_compressed_file_over_loopback() {
+        let file = "All work and no play makes Jack a dull boy.\n"
+            .repeat(50)
+            .into_bytes();
+        let report = transfer_over_loopback(
+            "compressed",
+            &file,
+            TransferOptions {
+                compress: true,
+                ..Default::default()
+            },
+        );
+        let compressed = report
+            .compressed_bytes
+            .unwrap();
+        assert!(compressed < file.len() as u64 / 4, "{}", compressed);
+    }
+
+    #[test]
+    fn test_encrypted_file_over_loopback() {
+        let file: Vec<u8> = (0..400u32)
+            .map(|i| (i * 97 % 251) as u8)
+            .collect();
+        let report = transfer_over_loopback(
+            "encrypted",
+            &file,
+            TransferOptions {
+                key: Some([0x17; crypto::KEY_BYTES]),
+                ..Default::default()
+            },
+        );
+        assert_eq!(report.size_bytes, file.len() as u64);
     }
 }
@@ -1 +1,4 @@
 pub type MacAddr = u8;
+
+/// Destination of frames meant for every node; never ACKed
+pub const BROADCAST: MacAddr = 0xFF;
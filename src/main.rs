@@ -1,7 +1,9 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use dialoguer::{Input, Select, theme::ColorfulTheme};
-use jack;
 use rand::Rng;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing::{debug, error, info, warn};
 
 mod audio;
@@ -12,15 +14,32 @@ mod phy;
 mod ui;
 mod utils;
 
+use audio::backend::{self, BackendKind, PortSelection};
 use audio::recorder;
-use device::jack::{connect_system_ports, print_jack_info};
-use mac::transfer::{run_receiver, run_sender};
-use net::tool::{run_ip_host, run_ping, run_router};
-use phy::{Frame, LineCodingKind, PhyDecoder, PhyEncoder};
+use mac::arq::ArqMode;
+use mac::params::MacParams;
+use mac::transfer::{
+    TransferOptions, default_input_path, run_duplex, run_receiver, run_sender,
+};
+use net::firewall::FirewallRule;
+use net::router::StaticRoute;
+use net::tool::{
+    HostOptions, PingOptions, PingSweep, RouterOptions, run_ip_host, run_ping,
+    run_router, run_tcp_client, run_tcp_server, run_udp_echo, run_udp_send,
+};
+use phy::backend::ModulationKind;
+use phy::interleaver::Interleaver;
+use phy::params::PhyParams;
+use phy::{
+    FecKind, Frame, LineCodingKind, PhyDecoder, PhyEncoder, PreambleKind,
+};
+use ui::dashboard::Dashboard;
 use ui::print_banner;
 use ui::progress::ProgressManager;
 use utils::consts::*;
-use utils::logging::init_logging;
+use utils::logging::{
+    LogOptions, init_logging_to, init_logging_with, with_startup_logging,
+};
 
 #[derive(Parser)]
 #[command(name = "trackmaker-rs")]
@@ -32,6 +51,197 @@ struct Cli {
     /// Enable interactive mode (dialoguer) instead of CLI args
     #[arg(long)]
     interactive: bool,
+
+    /// Audio backend (jack or cpal), default JACK falling back to cpal
+    #[arg(long, global = true)]
+    backend: Option<BackendKind>,
+
+    /// Physical capture port (JACK) or input device (cpal) to record from,
+    /// a unique part of its name will do (see list-devices)
+    #[arg(long, global = true)]
+    input_port: Option<String>,
+
+    /// Physical playback port (JACK) or output device (cpal) to play to,
+    /// a unique part of its name will do (see list-devices)
+    #[arg(long, global = true)]
+    output_port: Option<String>,
+
+    /// Share of full scale to play at, soft clipped near full scale
+    #[arg(long, global = true, default_value_t = TX_GAIN)]
+    tx_gain: f32,
+
+    /// Round trip through the sound card in ms (see calibrate), allowed
+    /// for in ACK timeouts and NAVs
+    #[arg(long, global = true)]
+    audio_latency_ms: Option<f64>,
+
+    /// Timestamp data frames when queued and sent, the receiver logs
+    /// queueing and one-way delays (the latter needs synchronized clocks)
+    #[arg(long, global = true)]
+    timestamps: bool,
+
+    /// Pre-shared key (32 bytes, raw or hex) to encrypt and authenticate
+    /// data frames with in tx, rx and duplex, the same on both ends
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+
+    /// TOML file with option values, [global], [phy] and a section per
+    /// subcommand; the command line overrides it (see dump-config)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    link: LinkArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    /// Serve Prometheus metrics over HTTP at this address, e.g.
+    /// 127.0.0.1:9095 (GET /metrics)
+    #[arg(long, global = true)]
+    metrics_addr: Option<std::net::SocketAddr>,
+}
+
+/// Where logging goes and how much of it
+#[derive(clap::Args)]
+struct LogArgs {
+    /// Level for every module (error, warn, info, debug, trace or off),
+    /// default RUST_LOG or info
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Per-module levels on top of --log-level, e.g.
+    /// trackmaker_rs::phy=warn,trackmaker_rs::net::router=debug
+    #[arg(long, global = true)]
+    log_filter: Option<String>,
+
+    /// One JSON object per event on stdout (or in --log-file)
+    #[arg(long, global = true)]
+    log_json: bool,
+
+    /// Log to this file instead, a new one started every --log-rotate-mb
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// MiB per log file before the next one is started
+    #[arg(long, global = true, default_value_t = LOG_ROTATE_BYTES / (1024 * 1024))]
+    log_rotate_mb: u64,
+}
+
+impl LogArgs {
+    fn options(&self) -> LogOptions {
+        LogOptions {
+            level: self.log_level.clone(),
+            filter: self.log_filter.clone(),
+            json: self.log_json,
+            file: self.log_file.clone(),
+            rotate_bytes: self.log_rotate_mb * 1024 * 1024,
+        }
+    }
+}
+
+/// PHY and MAC parameters, for tuning to a speaker / microphone pair
+/// without recompiling (see phy::params, mac::params)
+#[derive(clap::Args)]
+struct LinkArgs {
+    /// Samples per level (Manchester level or 4B5B bit), the same on both
+    /// ends
+    #[arg(long, global = true, default_value_t = SAMPLES_PER_LEVEL)]
+    samples_per_level: usize,
+
+    /// 0xAA bytes in the preamble, the same on both ends
+    #[arg(long, global = true, default_value_t = PREAMBLE_PATTERN_BYTES)]
+    preamble_bytes: usize,
+
+    /// Samples of silence between frames sent back to back
+    #[arg(long, global = true, default_value_t = INTER_FRAME_GAP_SAMPLES)]
+    inter_frame_gap: usize,
+
+    /// Level above which the channel is busy until the noise floor is known
+    #[arg(long, global = true, default_value_t = ENERGY_THRESHOLD)]
+    energy_threshold: f32,
+
+    /// Lower bound of the DIFS in ms
+    #[arg(long, global = true, default_value_t = DIFS_DURATION_MS)]
+    difs_ms: u64,
+
+    /// Lower bound of a backoff slot in ms
+    #[arg(long, global = true, default_value_t = SLOT_TIME_MS)]
+    slot_ms: u64,
+
+    /// Contention window at the first backoff stage, in slots
+    #[arg(long, global = true, default_value_t = CW_MIN)]
+    cw_min: u32,
+
+    /// Largest contention window, in slots
+    #[arg(long, global = true, default_value_t = CW_MAX)]
+    cw_max: u32,
+
+    /// How long to wait for an ACK in ms, on top of its airtime
+    #[arg(long, global = true, default_value_t = ACK_TIMEOUT_MS)]
+    ack_timeout_ms: u64,
+}
+
+impl LinkArgs {
+    fn params(&self) -> Result<(PhyParams, MacParams), String> {
+        let phy = PhyParams {
+            samples_per_level: self.samples_per_level,
+            preamble_pattern_bytes: self.preamble_bytes,
+            inter_frame_gap_samples: self.inter_frame_gap,
+            energy_threshold: self.energy_threshold,
+        };
+        let mac = MacParams {
+            difs_ms: self.difs_ms,
+            slot_ms: self.slot_ms,
+            cw_min: self.cw_min,
+            cw_max: self.cw_max,
+            ack_timeout_ms: self.ack_timeout_ms,
+        };
+        phy.validate()?;
+        mac.validate()?;
+        Ok((phy, mac))
+    }
+}
+
+/// Options of the modes whose audio can be dumped
+#[derive(clap::Args)]
+struct DumpArgs {
+    /// Keep the raw RX and TX audio as WAV files in this directory, and a
+    /// capture of every frame the decoder gives up on under failures/
+    #[arg(long)]
+    dump_audio: Option<PathBuf>,
+
+    /// Minutes per dump file before a new one is started
+    #[arg(long, default_value_t = DUMP_ROTATE_MINUTES, requires = "dump_audio")]
+    dump_rotate_min: u64,
+
+    /// Write spectrograms of the frames the decoder gives up on into this
+    /// directory
+    #[arg(long)]
+    debug_dumps: Option<PathBuf>,
+}
+
+impl DumpArgs {
+    /// Dump the audio of every client opened from now on, if asked to
+    fn select(self) {
+        let failures = utils::dump::DebugDumpOptions {
+            plots: self.debug_dumps,
+            // Captures of failed frames sit next to the audio dumps
+            captures: self
+                .dump_audio
+                .as_ref()
+                .map(|dir| dir.join("failures")),
+        };
+        if let Some(dir) = self.dump_audio {
+            backend::select_dump(audio::dump::DumpOptions {
+                dir,
+                rotate: Duration::from_secs(self.dump_rotate_min * 60),
+            });
+        }
+        if failures != utils::dump::DebugDumpOptions::default() {
+            select_debug_dumps(failures);
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -46,13 +256,89 @@ enum Commands {
         #[arg(short = 'r', long, default_value = "2")]
         remote: u8,
 
-        /// Line coding scheme (4b5b or manchester)
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
         #[arg(long, default_value = "4b5b")]
         encoding: String,
 
         /// Transmit Timeout in seconds
         #[arg(short = 'd', long, default_value_t = DEFAULT_TIMEOUT as u64)]
         duration: u64,
+
+        /// Pack several sub-packets per frame, acknowledged by one bitmap ACK
+        #[arg(long)]
+        aggregate: bool,
+
+        /// Protect frame bodies with the rate-1/2 convolutional code
+        #[arg(long)]
+        conv: bool,
+
+        /// Interleave frame bodies, ROWSxCOLS (e.g. 8x16)
+        #[arg(long)]
+        interleave: Option<String>,
+
+        /// Forward error correction (none or hamming), must match the peer
+        #[arg(long, default_value = "none")]
+        fec: String,
+
+        /// Signal path (baseband or psk), must match the peer
+        #[arg(long, default_value = "baseband")]
+        modulation: String,
+
+        /// Send to every node (ignores --remote); frames are not ACKed
+        #[arg(long)]
+        broadcast: bool,
+
+        /// Sliding window in frames (1 = stop-and-wait), must match the peer
+        #[arg(long, default_value_t = ARQ_WINDOW)]
+        window: usize,
+
+        /// Loss recovery within the window (gbn or sr), must match the peer
+        #[arg(long, default_value = "gbn")]
+        arq: String,
+
+        /// Reserve the channel with RTS/CTS first, must match the peer
+        #[arg(long)]
+        rts_cts: bool,
+
+        /// Data bytes per burst above which --rts-cts sends an RTS
+        #[arg(long, default_value_t = RTS_THRESHOLD_BYTES)]
+        rts_threshold: usize,
+
+        /// Failed exchanges after which a frame is dropped
+        #[arg(long, default_value_t = MAX_RETRIES)]
+        max_retries: u32,
+
+        /// Probe for the fastest reliable rate first, must match the peer
+        #[arg(long)]
+        auto_rate: bool,
+
+        /// Warn that the channel is jammed after this many ms of noise
+        #[arg(long, default_value_t = JAM_DETECT_MS)]
+        jam_ms: u64,
+
+        /// File to send (default: INPUT<local>to<remote>.bin)
+        #[arg(long)]
+        file: Option<String>,
+
+        /// Deflate the file on the link when that makes it smaller
+        #[arg(long)]
+        compress: bool,
+
+        /// Directory for report.json (default: ./tmp/sessions/tx-<time>)
+        #[arg(long)]
+        session_dir: Option<String>,
+
+        /// Print the final session report to stdout as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Show a live dashboard instead of the progress bars (needs a
+        /// terminal)
+        #[arg(long)]
+        tui: bool,
+
+        #[command(flatten)]
+        dump: DumpArgs,
     },
 
     /// Receive a file
@@ -65,20 +351,305 @@ enum Commands {
         #[arg(short = 'r', long, default_value = "1")]
         remote: u8,
 
-        /// Line coding scheme (4b5b or manchester)
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
         #[arg(long, default_value = "4b5b")]
         encoding: String,
 
         /// Recording duration in seconds
         #[arg(short = 'd', long, default_value_t = DEFAULT_TIMEOUT as u64)]
         duration: u64,
+
+        /// Write the received file here under the name it was sent with
+        /// (default: OUTPUT<remote>to<local>.bin in the current directory)
+        #[arg(long)]
+        output_dir: Option<String>,
+
+        /// Directory for report.json (default: ./tmp/sessions/rx-<time>)
+        #[arg(long)]
+        session_dir: Option<String>,
+
+        /// Window in ms for suppressing repeated ACKs of duplicate frames
+        #[arg(long, default_value_t = DUP_ACK_SUPPRESSION_MS)]
+        dup_ack_window: u64,
+
+        /// Sliding window in frames (1 = stop-and-wait), must match the peer
+        #[arg(long, default_value_t = ARQ_WINDOW)]
+        window: usize,
+
+        /// Loss recovery within the window (gbn or sr), must match the peer
+        #[arg(long, default_value = "gbn")]
+        arq: String,
+
+        /// Answer RTS with CTS and honour overheard reservations
+        #[arg(long)]
+        rts_cts: bool,
+
+        /// Answer the sender's rate probes, must match the peer
+        #[arg(long)]
+        auto_rate: bool,
+
+        /// Forward error correction (none or hamming), must match the peer
+        #[arg(long, default_value = "none")]
+        fec: String,
+
+        /// Signal path (baseband or psk), must match the peer
+        #[arg(long, default_value = "baseband")]
+        modulation: String,
+
+        /// Print the final session report to stdout as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Show a live dashboard instead of the progress bars (needs a
+        /// terminal)
+        #[arg(long)]
+        tui: bool,
+
+        #[command(flatten)]
+        dump: DumpArgs,
+    },
+
+    /// Send and receive a file at the same time (stop-and-wait both ways)
+    Duplex {
+        /// Local address
+        #[arg(short = 'l', long, default_value = "1")]
+        local: u8,
+
+        /// Peer address
+        #[arg(short = 'r', long, default_value = "2")]
+        remote: u8,
+
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
+        #[arg(long, default_value = "4b5b")]
+        encoding: String,
+
+        /// Signal path (baseband or psk), must match the peer
+        #[arg(long, default_value = "baseband")]
+        modulation: String,
+
+        /// Session duration in seconds
+        #[arg(short = 'd', long, default_value_t = DEFAULT_TIMEOUT as u64)]
+        duration: u64,
+
+        /// Always send standalone ACKs instead of riding on data frames
+        #[arg(long)]
+        no_piggyback: bool,
+
+        /// Keep recording while transmitting, to hear frames that overlap
+        /// ours (needs a sound card that doesn't hear itself)
+        #[arg(long)]
+        full_duplex: bool,
+
+        /// Subtract the echo of our own transmissions from the recording
+        #[arg(long)]
+        echo_cancel: bool,
+
+        /// Directory for report.json (default: ./tmp/sessions/duplex-<time>)
+        #[arg(long)]
+        session_dir: Option<String>,
+
+        /// Print the final session report to stdout as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Relay frames between two peers that cannot hear each other. ACKs
+    /// are hop-by-hop and the peers must use --window 1.
+    Relay {
+        /// Local relay address
+        #[arg(short = 'l', long, default_value = "3")]
+        local: u8,
+
+        /// First peer address
+        #[arg(long, default_value = "1")]
+        peer_a: u8,
+
+        /// Second peer address
+        #[arg(long, default_value = "2")]
+        peer_b: u8,
+
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
+        #[arg(long, default_value = "4b5b")]
+        encoding: String,
+
+        /// Relay duration in seconds
+        #[arg(short = 'd', long, default_value_t = DEFAULT_TIMEOUT as u64)]
+        duration: u64,
+    },
+
+    /// List the physical audio ports and devices --input-port and
+    /// --output-port pick from
+    ListDevices,
+
+    /// Beacon and listen for neighbors, then print the neighbor table
+    Discover {
+        /// Local address
+        #[arg(short = 'l', long, default_value = "1")]
+        local: u8,
+
+        /// IP address to announce in beacons
+        #[arg(long)]
+        ip: Option<String>,
+
+        /// Milliseconds between beacons
+        #[arg(long, default_value_t = BEACON_INTERVAL_MS)]
+        interval: u64,
+
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
+        #[arg(long, default_value = "4b5b")]
+        encoding: String,
+
+        /// Listening time in seconds
+        #[arg(short = 'd', long, default_value = "15")]
+        duration: u64,
     },
 
     /// Test mode (loopback without JACK)
     Test {
-        /// Line coding scheme (4b5b or manchester)
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
+        #[arg(long, default_value = "4b5b")]
+        encoding: String,
+
+        /// Forward error correction (none or hamming)
+        #[arg(long, default_value = "none")]
+        fec: String,
+
+        /// Synchronize on a chirp instead of the byte pattern preamble
+        #[arg(long)]
+        chirp: bool,
+
+        /// Normalized correlation needed to detect the preamble
+        #[arg(long)]
+        sync_threshold: Option<f32>,
+
+        /// Receiver DC blocker cutoff in Hz, 0 to disable
+        #[arg(long, default_value_t = DC_BLOCK_CUTOFF_HZ)]
+        dc_cutoff: f32,
+    },
+
+    /// Measure the audio round trip with chirps played back into the input
+    Calibrate {
+        /// Chirps to average over
+        #[arg(long, default_value_t = CALIBRATE_ROUNDS)]
+        rounds: usize,
+    },
+
+    /// Decode a WAV file (e.g. a --dump-audio dump) or a capture file
+    /// (.tmk) and print its frames and the decoder's counters
+    Replay {
+        /// WAV or capture file to decode
+        file: PathBuf,
+
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi), a capture
+        /// file brings its own
+        #[arg(long, default_value = "4b5b")]
+        encoding: String,
+
+        /// Forward error correction (none or hamming)
+        #[arg(long, default_value = "none")]
+        fec: String,
+
+        /// Synchronize on a chirp instead of the byte pattern preamble
+        #[arg(long)]
+        chirp: bool,
+
+        /// Write spectrograms of the frames the decoder gives up on into
+        /// this directory
+        #[arg(long)]
+        debug_dumps: Option<PathBuf>,
+    },
+
+    /// Record the input into a capture file (.tmk) to replay later
+    Capture {
+        /// Capture file to write
+        output: PathBuf,
+
+        /// Seconds to record
+        #[arg(short = 'd', long, default_value_t = CAPTURE_SECONDS)]
+        duration: u64,
+
+        /// Line coding scheme the sender uses, kept for replay
         #[arg(long, default_value = "4b5b")]
         encoding: String,
+
+        /// Forward error correction the sender uses, kept for replay
+        #[arg(long, default_value = "none")]
+        fec: String,
+
+        /// The sender synchronizes on a chirp instead of the byte pattern
+        #[arg(long)]
+        chirp: bool,
+    },
+
+    /// Cut a capture file down and blank its payloads before it goes into
+    /// tests/corpus
+    Trim {
+        /// Capture file to read
+        input: PathBuf,
+
+        /// Capture file to write
+        output: PathBuf,
+
+        /// Keep from this many seconds in
+        #[arg(long, default_value_t = 0.0)]
+        from: f64,
+
+        /// Keep up to this many seconds in
+        #[arg(long)]
+        to: Option<f64>,
+
+        /// Replace the payload of every frame that decodes with zeros
+        #[arg(long)]
+        anonymize: bool,
+
+        /// Also write <output>.json with the number of frames it decodes
+        /// to, for tests/corpus
+        #[arg(long)]
+        sidecar: bool,
+    },
+
+    /// Measure goodput, frame loss and retransmissions over a simulated
+    /// link for every combination of the given settings
+    Bench {
+        /// Four short cells instead of the full matrix
+        #[arg(long)]
+        quick: bool,
+
+        /// Line codings to compare, comma separated
+        #[arg(long, value_delimiter = ',')]
+        encodings: Vec<String>,
+
+        /// Payload bytes per frame, comma separated
+        #[arg(long, value_delimiter = ',')]
+        frame_sizes: Vec<usize>,
+
+        /// FEC schemes to compare (none or hamming), comma separated
+        #[arg(long, value_delimiter = ',')]
+        fec: Vec<String>,
+
+        /// Noise standard deviations on the link, comma separated
+        #[arg(long, value_delimiter = ',')]
+        noise: Vec<f32>,
+
+        /// Bytes to send in every cell
+        #[arg(long)]
+        bytes: Option<usize>,
+
+        /// Results file, CSV if it ends in .csv and JSON otherwise
+        /// (default: ./tmp/bench/bench-<time>.json)
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the effective configuration, defaults with --config and the
+    /// global options merged in, as a config file
+    DumpConfig {
+        /// Only this section (global or a subcommand)
+        section: Option<String>,
+
+        /// Write it to this file instead
+        #[arg(short = 'o', long)]
+        output: Option<PathBuf>,
     },
 
     /// Ping a remote host
@@ -96,8 +667,44 @@ enum Commands {
 
         /// Payload size in bytes
         /// Default is 32 bytes
-        #[arg(long, default_value_t = PING_PAYLOAD_SIZE)]
+        #[arg(long, visible_alias = "size", default_value_t = PING_PAYLOAD_SIZE)]
         payload_size: usize,
+
+        /// Sweep payload sizes for MTU probing, min,max,step
+        #[arg(long, conflicts_with = "payload_size")]
+        sweep: Option<PingSweep>,
+
+        /// Echo requests to send (per size when sweeping)
+        #[arg(short = 'c', long, default_value_t = PING_PACKET_COUNT)]
+        count: u16,
+
+        /// Time between requests in milliseconds
+        #[arg(long, default_value_t = PING_INTERVAL_MS)]
+        interval_ms: u64,
+
+        /// How long to wait for each reply in milliseconds
+        #[arg(long, default_value_t = PING_TIMEOUT_MS)]
+        timeout_ms: u64,
+
+        /// Only print the summary
+        #[arg(short = 'q', long)]
+        quiet: bool,
+
+        /// Lifetime of learned ARP entries in milliseconds
+        #[arg(long, default_value_t = ARP_ENTRY_TTL_MS)]
+        arp_ttl_ms: u64,
+
+        /// Record the acoustic link's IP traffic to this pcap file
+        #[arg(long)]
+        capture: Option<String>,
+
+        /// Ping an IPv6 link-local target (e.g. fe80::200:ff:fe00:2) with
+        /// ICMPv6; --local-ip still picks our MAC
+        #[arg(long)]
+        ipv6: bool,
+
+        #[command(flatten)]
+        dump: DumpArgs,
     },
 
     /// Run as an IP Host (respond to pings)
@@ -105,6 +712,107 @@ enum Commands {
         /// Local IP address
         #[arg(long, default_value = "192.168.1.2")]
         local_ip: String,
+
+        /// Lifetime of learned ARP entries in milliseconds
+        #[arg(long, default_value_t = ARP_ENTRY_TTL_MS)]
+        arp_ttl_ms: u64,
+
+        /// Record the acoustic link's IP traffic to this pcap file
+        #[arg(long)]
+        capture: Option<String>,
+
+        /// Also answer ICMPv6 echo and NDP at our link-local address
+        #[arg(long)]
+        ipv6: bool,
+    },
+
+    /// Echo back UDP datagrams sent to a port (and respond to pings)
+    UdpEcho {
+        /// Local IP address
+        #[arg(long, default_value = "192.168.1.2")]
+        local_ip: String,
+
+        /// UDP port to echo on
+        #[arg(long, default_value_t = UDP_ECHO_PORT)]
+        port: u16,
+
+        /// Lifetime of learned ARP entries in milliseconds
+        #[arg(long, default_value_t = ARP_ENTRY_TTL_MS)]
+        arp_ttl_ms: u64,
+    },
+
+    /// Send a UDP datagram and wait for its echo
+    UdpSend {
+        /// Target IP address
+        target: String,
+
+        /// Target UDP port
+        #[arg(long, default_value_t = UDP_ECHO_PORT)]
+        port: u16,
+
+        /// Local IP address
+        #[arg(long, default_value = "192.168.1.1")]
+        local_ip: String,
+
+        /// Gateway IP address
+        #[arg(long)]
+        gateway: Option<String>,
+
+        /// Payload text
+        #[arg(long, default_value = "hello", conflicts_with = "file")]
+        payload: String,
+
+        /// Send the contents of this file instead
+        #[arg(long)]
+        file: Option<String>,
+
+        /// Lifetime of learned ARP entries in milliseconds
+        #[arg(long, default_value_t = ARP_ENTRY_TTL_MS)]
+        arp_ttl_ms: u64,
+    },
+
+    /// Accept one TCP connection and receive a file over it
+    TcpServer {
+        /// Local IP address
+        #[arg(long, default_value = "192.168.1.2")]
+        local_ip: String,
+
+        /// TCP port to listen on
+        #[arg(long, default_value_t = TCP_DEFAULT_PORT)]
+        port: u16,
+
+        /// Save what was received to this file
+        #[arg(long)]
+        output: Option<String>,
+
+        /// Lifetime of learned ARP entries in milliseconds
+        #[arg(long, default_value_t = ARP_ENTRY_TTL_MS)]
+        arp_ttl_ms: u64,
+    },
+
+    /// Send a file over a TCP connection
+    TcpClient {
+        /// Target IP address
+        target: String,
+
+        /// File to send
+        file: String,
+
+        /// Target TCP port
+        #[arg(long, default_value_t = TCP_DEFAULT_PORT)]
+        port: u16,
+
+        /// Local IP address
+        #[arg(long, default_value = "192.168.1.1")]
+        local_ip: String,
+
+        /// Gateway IP address
+        #[arg(long)]
+        gateway: Option<String>,
+
+        /// Lifetime of learned ARP entries in milliseconds
+        #[arg(long, default_value_t = ARP_ENTRY_TTL_MS)]
+        arp_ttl_ms: u64,
     },
 
     /// Run as a Router (forward packets between acoustic and WiFi interfaces)
@@ -173,9 +881,36 @@ enum Commands {
         #[arg(long, default_value = "255.255.255.0")]
         tun_netmask: String,
 
-        /// Line coding scheme (4b5b or manchester)
+        /// Static route, net/prefix:nexthop:iface (iface: acoustic, wifi,
+        /// eth or tun), repeatable
+        #[arg(long = "route")]
+        routes: Vec<StaticRoute>,
+
+        /// Default route, nexthop:iface (default: the gateway on eth)
+        #[arg(long, value_parser = StaticRoute::parse_default)]
+        default_route: Option<StaticRoute>,
+
+        /// Firewall rule, e.g. "deny src=192.168.1.0/24 proto=tcp port=22
+        /// out=eth" (allow, deny or reject; src, dst, proto, port, in,
+        /// out), first match wins, repeatable
+        #[arg(long = "fw-rule")]
+        fw_rules: Vec<FirewallRule>,
+
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
         #[arg(long, default_value = "4b5b")]
         encoding: String,
+
+        /// Record the acoustic link's IP traffic to this pcap file
+        #[arg(long)]
+        capture: Option<String>,
+
+        /// Show a live dashboard instead of the progress bars (needs a
+        /// terminal)
+        #[arg(long)]
+        tui: bool,
+
+        #[command(flatten)]
+        dump: DumpArgs,
     },
 
     /// Run as a TUN Adapter (expose acoustic interface as a network interface)
@@ -196,38 +931,241 @@ enum Commands {
         #[arg(long)]
         gateway: Option<String>,
 
-        /// Line coding scheme (4b5b or manchester)
+        /// Line coding scheme (4b5b, manchester, 8b10b or nrzi)
         #[arg(long, default_value = "4b5b")]
         encoding: String,
+
+        /// Record the acoustic link's IP traffic to this pcap file
+        #[arg(long)]
+        capture: Option<String>,
+
+        /// MTU advertised to the kernel (default: one frame, or several
+        /// with --fragment)
+        #[arg(long)]
+        mtu: Option<usize>,
+
+        /// Fragment packets larger than a frame instead of dropping them
+        #[arg(long)]
+        fragment: bool,
+
+        /// Create a TAP device and bridge Ethernet frames instead of IP
+        /// packets
+        #[arg(long)]
+        tap: bool,
     },
 }
 
 fn parse_line_coding(encoding: &str) -> LineCodingKind {
-    match encoding
-        .to_lowercase()
-        .as_str()
-    {
-        "manchester" | "manchester-biphase" => LineCodingKind::Manchester,
-        "4b5b" | "4b5b-nrz" => LineCodingKind::FourBFiveB,
+    LineCodingKind::from_name(encoding).unwrap_or_else(|| {
+        warn!("Unknown encoding '{}', defaulting to 4B5B", encoding);
+        LineCodingKind::FourBFiveB
+    })
+}
+
+fn parse_fec(fec: &str) -> FecKind {
+    FecKind::from_name(fec).unwrap_or_else(|| {
+        warn!("Unknown FEC '{}', defaulting to none", fec);
+        FecKind::None
+    })
+}
+
+fn parse_modulation(modulation: &str) -> ModulationKind {
+    ModulationKind::from_name(modulation).unwrap_or_else(|| {
+        warn!(
+            "Unknown modulation '{}', defaulting to baseband",
+            modulation
+        );
+        ModulationKind::Baseband
+    })
+}
+
+fn parse_arq(arq: &str) -> ArqMode {
+    match arq.to_lowercase().as_str() {
+        "gbn" | "go-back-n" => ArqMode::GoBackN,
+        "sr" | "selective" => ArqMode::SelectiveRepeat,
         _ => {
-            warn!("Unknown encoding '{}', defaulting to 4B5B", encoding);
-            LineCodingKind::FourBFiveB
+            warn!("Unknown ARQ mode '{}', defaulting to Go-Back-N", arq);
+            ArqMode::GoBackN
+        }
+    }
+}
+
+fn parse_interleaver(spec: &str) -> Interleaver {
+    Interleaver::parse(spec).unwrap_or_else(|e| {
+        warn!("{}, using the default", e);
+        Interleaver::default()
+    })
+}
+
+/// Address and file for `tx`: a broadcast goes to BROADCAST but still
+/// sends the file named after --remote unless --file says otherwise
+fn tx_destination(
+    local: u8,
+    remote: u8,
+    broadcast: bool,
+    file: Option<String>,
+) -> (u8, Option<PathBuf>) {
+    let file = file.map(PathBuf::from);
+    if broadcast {
+        let file = file.unwrap_or_else(|| default_input_path(local, remote));
+        (mac::types::BROADCAST, Some(file))
+    } else {
+        (remote, file)
+    }
+}
+
+/// The `trim` command: cut `input` to `from`..`to` seconds, blank its
+/// payloads if asked to and write it (and its sidecar) to `output`
+fn trim_capture(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    from: f64,
+    to: Option<f64>,
+    anonymize: bool,
+    sidecar: bool,
+) -> Result<(), String> {
+    use phy::capture::{Capture, Sidecar};
+
+    let mut capture = Capture::read(input)
+        .map_err(|e| format!("Cannot read {}: {}", input.display(), e))?;
+    let rate = capture.header.sample_rate as f64;
+    let end = to.map_or(capture.samples.len(), |to| (to * rate) as usize);
+    capture.truncate((from * rate) as usize, end);
+    if anonymize {
+        info!("Blanked {} frames", capture.anonymize()?);
+    }
+    capture
+        .write(output)
+        .map_err(|e| format!("Cannot write {}: {}", output.display(), e))?;
+    info!(
+        "Wrote {} samples to {}",
+        capture.samples.len(),
+        output.display()
+    );
+    if sidecar {
+        let (frames, _, _) = capture.decode()?;
+        Sidecar {
+            frames: frames.len(),
+            note: String::new(),
         }
+        .write(output)
+        .map_err(|e| format!("Cannot write sidecar: {}", e))?;
+        info!("{} frames expected from {}", frames.len(), output.display());
+    }
+    Ok(())
+}
+
+/// Dump failed frames as `options` say, exiting if a directory can't
+/// be created
+fn select_debug_dumps(options: utils::dump::DebugDumpOptions) {
+    if let Err(e) = utils::dump::select_debug_dumps(options) {
+        error!("Cannot write debug dumps: {}", e);
+        std::process::exit(1);
     }
 }
 
 fn main() {
-    init_logging();
-    print_banner();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let config = match with_startup_logging(|| {
+        utils::config::from_args(&Cli::command(), &args).inspect_err(|e| {
+            error!("{}", e);
+        })
+    }) {
+        Ok(config) => config.unwrap_or_default(),
+        Err(_) => return,
+    };
+    let cli = Cli::parse_from(utils::config::merge_args(
+        &Cli::command(),
+        &args,
+        &config,
+    ));
+    // --tui takes the terminal over when there is one, logging included
+    let tui = match &cli.command {
+        Some(Commands::Tx { tui, .. }) => tui.then_some("tx"),
+        Some(Commands::Rx { tui, .. }) => tui.then_some("rx"),
+        Some(Commands::Router { tui, .. }) => tui.then_some("router"),
+        _ => None,
+    };
+    let dashboard = tui.and_then(Dashboard::start);
+    let logging = match &dashboard {
+        Some(dashboard) if cli.log.log_file.is_none() => {
+            init_logging_to(&cli.log.options(), dashboard.log_writer())
+        }
+        _ => init_logging_with(&cli.log.options()),
+    };
+    if let Err(e) = logging {
+        eprintln!("{}", e);
+        return;
+    }
+    if dashboard.is_none() {
+        print_banner();
+    }
+    if tui.is_some() && dashboard.is_none() {
+        warn!("Not a terminal, showing progress bars instead of --tui");
+    }
+    if let Some(addr) = cli.metrics_addr {
+        match std::net::TcpListener::bind(addr) {
+            Ok(listener) => {
+                info!("Metrics on http://{}/metrics", addr);
+                std::thread::spawn(move || {
+                    utils::metrics::serve(
+                        listener,
+                        utils::metrics::global(),
+                        || true,
+                    )
+                });
+            }
+            Err(e) => warn!("No metrics endpoint on {}: {}", addr, e),
+        }
+    }
+    if let Some(kind) = cli.backend {
+        backend::select(kind);
+    }
+    backend::select_tx_gain(cli.tx_gain);
+    if cli.timestamps {
+        mac::delay::select_timestamps();
+    }
+    if cli.input_port.is_some() || cli.output_port.is_some() {
+        backend::select_ports(PortSelection {
+            input: cli.input_port,
+            output: cli.output_port,
+        });
+    }
+
+    let (phy_params, mac_params) = match cli.link.params() {
+        Ok(params) => params,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if (phy_params, mac_params) != (PhyParams::default(), MacParams::default()) {
+        info!("PHY {:?}, MAC {:?}", phy_params, mac_params);
+    }
 
-    let cli = Cli::parse();
+    let audio_latency = cli
+        .audio_latency_ms
+        .map(|ms| Duration::from_secs_f64(ms / 1000.0));
+    let key = match cli
+        .key_file
+        .as_deref()
+        .map(mac::crypto::load_key)
+        .transpose()
+    {
+        Ok(key) => key,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
     // Determine mode and parameters
-    let (selection, line_coding, tx_addr, rx_addr, timeout) = if cli.interactive
+    let (selection, line_coding, tx_addr, rx_addr, timeout, mut options) = if cli
+        .interactive
         || cli.command.is_none()
     {
         // Interactive mode (original dialoguer behavior)
-        interactive_mode()
+        interactive_mode(&phy_params)
     } else {
         // Command-line mode
         match cli.command.unwrap() {
@@ -236,24 +1174,369 @@ fn main() {
                 remote,
                 encoding,
                 duration,
+                aggregate,
+                conv,
+                interleave,
+                fec,
+                modulation,
+                broadcast,
+                window,
+                arq,
+                rts_cts,
+                rts_threshold,
+                max_retries,
+                auto_rate,
+                jam_ms,
+                file,
+                compress,
+                session_dir,
+                json,
+                tui: _,
+                dump,
             } => {
+                dump.select();
                 let line_coding = parse_line_coding(&encoding);
+                if broadcast {
+                    info!("Broadcasting, frames will not be acknowledged");
+                }
+                let (remote, file) =
+                    tx_destination(local, remote, broadcast, file);
                 info!("Using line coding: {}", line_coding.name());
-                (0, line_coding, local, remote, duration)
+                let options = TransferOptions {
+                    aggregate,
+                    session_dir: session_dir.map(PathBuf::from),
+                    json,
+                    fec: parse_fec(&fec),
+                    modulation: parse_modulation(&modulation),
+                    conv,
+                    interleave: interleave
+                        .as_deref()
+                        .map(parse_interleaver),
+                    window: Some(window),
+                    arq: parse_arq(&arq),
+                    rts_threshold: rts_cts.then_some(rts_threshold),
+                    max_retries: Some(max_retries),
+                    auto_rate,
+                    jam_after: Some(Duration::from_millis(jam_ms)),
+                    file,
+                    compress,
+                    ..Default::default()
+                };
+                (0, line_coding, local, remote, duration, options)
             }
             Commands::Rx {
                 local,
                 remote,
                 encoding,
                 duration,
+                output_dir,
+                session_dir,
+                dup_ack_window,
+                window,
+                arq,
+                rts_cts,
+                auto_rate,
+                fec,
+                modulation,
+                json,
+                tui: _,
+                dump,
             } => {
+                dump.select();
                 let line_coding = parse_line_coding(&encoding);
                 info!("Using line coding: {}", line_coding.name());
-                (1, line_coding, local, remote, duration)
+                let options = TransferOptions {
+                    session_dir: session_dir.map(PathBuf::from),
+                    json,
+                    dup_ack_suppression: Some(Duration::from_millis(
+                        dup_ack_window,
+                    )),
+                    fec: parse_fec(&fec),
+                    modulation: parse_modulation(&modulation),
+                    window: Some(window),
+                    arq: parse_arq(&arq),
+                    rts_threshold: rts_cts.then_some(RTS_THRESHOLD_BYTES),
+                    auto_rate,
+                    output_dir: output_dir.map(PathBuf::from),
+                    ..Default::default()
+                };
+                (1, line_coding, local, remote, duration, options)
             }
-            Commands::Test { encoding } => {
+            Commands::Duplex {
+                local,
+                remote,
+                encoding,
+                modulation,
+                duration,
+                no_piggyback,
+                full_duplex,
+                echo_cancel,
+                session_dir,
+                json,
+            } => {
                 let line_coding = parse_line_coding(&encoding);
-                test_transmission(line_coding);
+                let options = TransferOptions {
+                    session_dir: session_dir.map(PathBuf::from),
+                    json,
+                    modulation: parse_modulation(&modulation),
+                    piggyback: !no_piggyback,
+                    full_duplex,
+                    echo_cancel,
+                    ..Default::default()
+                };
+                (2, line_coding, local, remote, duration, options)
+            }
+            Commands::Relay {
+                local,
+                peer_a,
+                peer_b,
+                encoding,
+                duration,
+            } => {
+                let line_coding = parse_line_coding(&encoding);
+                mac::relay::run_relay(
+                    local,
+                    peer_a,
+                    peer_b,
+                    line_coding,
+                    duration,
+                    &phy_params,
+                    &mac_params,
+                );
+                return;
+            }
+            Commands::ListDevices => {
+                backend::list_devices(&format!("{}_list", JACK_CLIENT_NAME));
+                return;
+            }
+            Commands::Discover {
+                local,
+                ip,
+                interval,
+                encoding,
+                duration,
+            } => {
+                let ip = ip.map(|ip| {
+                    ip.parse()
+                        .expect("Invalid IP address")
+                });
+                mac::discovery::run_discover(
+                    local,
+                    ip,
+                    Duration::from_millis(interval),
+                    parse_line_coding(&encoding),
+                    duration,
+                    &phy_params,
+                    &mac_params,
+                );
+                return;
+            }
+            Commands::Test {
+                encoding,
+                fec,
+                chirp,
+                sync_threshold,
+                dc_cutoff,
+            } => {
+                let line_coding = parse_line_coding(&encoding);
+                let preamble = if chirp {
+                    PreambleKind::default_chirp()
+                } else {
+                    PreambleKind::BytePattern
+                };
+                test_transmission(
+                    line_coding,
+                    parse_fec(&fec),
+                    preamble,
+                    sync_threshold,
+                    (dc_cutoff > 0.0).then_some(dc_cutoff),
+                    &phy_params,
+                );
+                return;
+            }
+            Commands::Calibrate { rounds } => {
+                audio::latency::run_calibration(rounds);
+                return;
+            }
+            Commands::DumpConfig { section, output } => {
+                let effective = utils::config::effective(
+                    &Cli::command(),
+                    &args,
+                    &config,
+                    section.as_deref(),
+                );
+                let mut text = toml::to_string(&effective)
+                    .expect("a TOML table serializes");
+                if let Some(path) = &cli.config {
+                    text.insert_str(
+                        0,
+                        &format!("# Merged from {}\n\n", path.display()),
+                    );
+                }
+                match output {
+                    Some(path) => match std::fs::write(&path, text) {
+                        Ok(()) => {
+                            info!("Configuration written to {}", path.display())
+                        }
+                        Err(e) => {
+                            error!("Failed to write {}: {}", path.display(), e)
+                        }
+                    },
+                    None => print!("{}", text),
+                }
+                return;
+            }
+            Commands::Bench {
+                quick,
+                encodings,
+                frame_sizes,
+                fec,
+                noise,
+                bytes,
+                output,
+            } => {
+                // Given settings replace the preset's
+                let mut matrix = if quick {
+                    mac::bench::BenchMatrix::quick()
+                } else {
+                    mac::bench::BenchMatrix::full()
+                };
+                if !encodings.is_empty() {
+                    matrix.encodings = encodings
+                        .iter()
+                        .map(|e| parse_line_coding(e))
+                        .collect();
+                }
+                if !frame_sizes.is_empty() {
+                    matrix.frame_sizes = frame_sizes;
+                }
+                if !fec.is_empty() {
+                    matrix.fecs = fec
+                        .iter()
+                        .map(|f| parse_fec(f))
+                        .collect();
+                }
+                if !noise.is_empty() {
+                    matrix.noise = noise;
+                }
+                if let Some(bytes) = bytes {
+                    matrix.payload_bytes = bytes;
+                }
+                matrix.phy = phy_params;
+                matrix.mac = mac_params;
+                mac::bench::run_bench(matrix, output);
+                return;
+            }
+            Commands::Replay {
+                file,
+                encoding,
+                fec,
+                chirp,
+                debug_dumps,
+            } => {
+                if debug_dumps.is_some() {
+                    select_debug_dumps(utils::dump::DebugDumpOptions {
+                        plots: debug_dumps,
+                        captures: None,
+                    });
+                }
+                let replayed = if file
+                    .extension()
+                    .is_some_and(|ext| ext == "tmk")
+                {
+                    audio::dump::replay_capture(&file)
+                } else {
+                    let preamble = if chirp {
+                        PreambleKind::default_chirp()
+                    } else {
+                        PreambleKind::BytePattern
+                    };
+                    let mut decoder = PhyDecoder::new(
+                        &phy_params,
+                        parse_line_coding(&encoding),
+                        parse_fec(&fec),
+                        mac::types::BROADCAST,
+                    )
+                    .with_preamble(preamble)
+                    .promiscuous();
+                    audio::dump::replay(&file, &mut decoder)
+                        .map(|frames| (frames, decoder.stats()))
+                };
+                match replayed {
+                    Ok((frames, stats)) => {
+                        for frame in &frames {
+                            println!(
+                                "{:?} seq {} {} -> {}, {} bytes",
+                                frame.frame_type,
+                                frame.sequence,
+                                frame.src,
+                                frame.dst,
+                                frame.data.len()
+                            );
+                        }
+                        println!("{} frames decoded", frames.len());
+                        println!("{}", stats);
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                utils::dump::flush_debug_dumps(Duration::from_millis(
+                    DEBUG_DUMP_FLUSH_MS,
+                ));
+                return;
+            }
+            Commands::Capture {
+                output,
+                duration,
+                encoding,
+                fec,
+                chirp,
+            } => {
+                let preamble = if chirp {
+                    PreambleKind::default_chirp()
+                } else {
+                    PreambleKind::BytePattern
+                };
+                let header = phy::capture::CaptureHeader::new(
+                    &phy_params,
+                    parse_line_coding(&encoding),
+                    parse_fec(&fec),
+                    preamble,
+                    "capture",
+                );
+                match audio::dump::record_capture(
+                    &output,
+                    Duration::from_secs(duration),
+                    header,
+                ) {
+                    Ok(samples) => info!(
+                        "Captured {} samples to {}",
+                        samples,
+                        output.display()
+                    ),
+                    Err(e) => {
+                        error!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            Commands::Trim {
+                input,
+                output,
+                from,
+                to,
+                anonymize,
+                sidecar,
+            } => {
+                if let Err(e) =
+                    trim_capture(&input, &output, from, to, anonymize, sidecar)
+                {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
                 return;
             }
             Commands::Ping {
@@ -261,14 +1544,116 @@ fn main() {
                 local_ip,
                 gateway,
                 payload_size,
+                sweep,
+                count,
+                interval_ms,
+                timeout_ms,
+                quiet,
+                arp_ttl_ms,
+                capture,
+                ipv6,
+                dump,
             } => {
+                dump.select();
                 // Ping Mode
-                run_ping(target, local_ip, gateway, payload_size);
-                return;
+                let options = PingOptions {
+                    count,
+                    interval: Duration::from_millis(interval_ms),
+                    timeout: Duration::from_millis(timeout_ms),
+                    payload_size,
+                    sweep,
+                    quiet,
+                    arp_ttl: Duration::from_millis(arp_ttl_ms),
+                    capture,
+                    ipv6,
+                    phy: phy_params,
+                    mac: mac_params,
+                };
+                std::process::exit(run_ping(target, local_ip, gateway, options));
             }
-            Commands::IpHost { local_ip } => {
+            Commands::IpHost {
+                local_ip,
+                arp_ttl_ms,
+                capture,
+                ipv6,
+            } => {
                 // IP Host Mode
-                run_ip_host(local_ip);
+                run_ip_host(
+                    local_ip,
+                    Duration::from_millis(arp_ttl_ms),
+                    capture,
+                    ipv6,
+                    &phy_params,
+                    &mac_params,
+                );
+                return;
+            }
+            Commands::UdpEcho {
+                local_ip,
+                port,
+                arp_ttl_ms,
+            } => {
+                let options = HostOptions {
+                    arp_ttl: Duration::from_millis(arp_ttl_ms),
+                    phy: phy_params,
+                    mac: mac_params,
+                };
+                run_udp_echo(local_ip, port, options);
+                return;
+            }
+            Commands::UdpSend {
+                target,
+                port,
+                local_ip,
+                gateway,
+                payload,
+                file,
+                arp_ttl_ms,
+            } => {
+                let payload = match file {
+                    Some(path) => std::fs::read(&path).unwrap_or_else(|e| {
+                        panic!("Cannot read {}: {}", path, e)
+                    }),
+                    None => payload.into_bytes(),
+                };
+                let options = HostOptions {
+                    arp_ttl: Duration::from_millis(arp_ttl_ms),
+                    phy: phy_params,
+                    mac: mac_params,
+                };
+                run_udp_send(target, port, local_ip, gateway, payload, options);
+                return;
+            }
+            Commands::TcpServer {
+                local_ip,
+                port,
+                output,
+                arp_ttl_ms,
+            } => {
+                let options = HostOptions {
+                    arp_ttl: Duration::from_millis(arp_ttl_ms),
+                    phy: phy_params,
+                    mac: mac_params,
+                };
+                run_tcp_server(local_ip, port, output, options);
+                return;
+            }
+            Commands::TcpClient {
+                target,
+                file,
+                port,
+                local_ip,
+                gateway,
+                arp_ttl_ms,
+            } => {
+                let data = std::fs::read(&file)
+                    .unwrap_or_else(|e| panic!("Cannot read {}: {}", file, e));
+                let options = HostOptions {
+                    arp_ttl: Duration::from_millis(arp_ttl_ms),
+                    phy: phy_params,
+                    mac: mac_params,
+                };
+                run_tcp_client(target, port, local_ip, gateway, data, options);
                 return;
             }
             Commands::Router {
@@ -288,10 +1673,28 @@ fn main() {
                 tun_name,
                 tun_ip,
                 tun_netmask,
+                mut routes,
+                default_route,
+                fw_rules,
                 encoding,
+                capture,
+                tui: _,
+                dump,
             } => {
+                dump.select();
                 // Router Mode
                 let line_coding = parse_line_coding(&encoding);
+                routes.extend(default_route);
+                let options = RouterOptions {
+                    routes,
+                    capture,
+                    firewall: fw_rules,
+                    phy: phy_params,
+                    mac: mac_params,
+                    dashboard: dashboard
+                        .as_ref()
+                        .map(Dashboard::sender),
+                };
                 run_router(
                     acoustic_ip,
                     acoustic_mac,
@@ -310,7 +1713,11 @@ fn main() {
                     tun_ip,
                     tun_netmask,
                     line_coding,
+                    options,
                 );
+                utils::dump::flush_debug_dumps(Duration::from_millis(
+                    DEBUG_DUMP_FLUSH_MS,
+                ));
                 return;
             }
             Commands::Tun {
@@ -319,34 +1726,47 @@ fn main() {
                 tun_name,
                 gateway,
                 encoding,
+                capture,
+                mtu,
+                fragment,
+                tap,
             } => {
                 let line_coding = parse_line_coding(&encoding);
-                net::tun::run_tun(ip, netmask, tun_name, gateway, line_coding);
+                net::tun::run_tun(
+                    ip,
+                    netmask,
+                    tun_name,
+                    gateway,
+                    line_coding,
+                    capture,
+                    net::tun::TunOptions {
+                        mtu,
+                        fragment,
+                        tap,
+                        phy: phy_params,
+                        mac: mac_params,
+                    },
+                );
                 return;
             }
         }
     };
-
-    let (client, status) = jack::Client::new(
-        format!(
-            "{}_{:04}",
-            JACK_CLIENT_NAME,
-            rand::rng().random_range(0..10000)
-        )
-        .as_str(),
-        jack::ClientOptions::NO_START_SERVER,
-    )
+    options.duration = Some(timeout);
+    options.audio_latency = audio_latency;
+    options.key = key;
+    options.phy = phy_params;
+    options.mac = mac_params;
+    options.dashboard = dashboard
+        .as_ref()
+        .map(Dashboard::sender);
+
+    let client = backend::open(&format!(
+        "{}_{:04}",
+        JACK_CLIENT_NAME,
+        rand::rng().random_range(0..10000)
+    ))
     .unwrap();
-    tracing::info!("JACK client status: {:?}", status);
-    let (sample_rate, _buffer_size) = print_jack_info(&client);
-
-    if sample_rate as u32 != SAMPLE_RATE {
-        warn!(
-            "Sample rate mismatch! Expected {}, got {}",
-            SAMPLE_RATE, sample_rate
-        );
-        warn!("Physical layer is designed for {} Hz", SAMPLE_RATE);
-    }
+    let sample_rate = client.sample_rate();
 
     let max_duration_samples = sample_rate * timeout as usize;
 
@@ -354,44 +1774,20 @@ fn main() {
     let shared = recorder::AppShared::new(max_duration_samples);
     let shared_cb = shared.clone();
 
-    let in_port = client
-        .register_port(INPUT_PORT_NAME, jack::AudioIn::default())
-        .unwrap();
-    let out_port = client
-        .register_port(OUTPUT_PORT_NAME, jack::AudioOut::default())
-        .unwrap();
-
-    let in_port_name = in_port.name().unwrap();
-    let out_port_name = out_port.name().unwrap();
-
     // Process Callback
-    let process_cb = recorder::build_process_closure(
-        in_port,
-        out_port,
-        shared_cb,
-        max_duration_samples,
-    );
-    let process = jack::contrib::ClosureProcessHandler::new(process_cb);
-
+    let process_cb = recorder::build_process_closure(shared_cb);
     let active_client = client
-        .activate_async((), process)
+        .activate(process_cb)
         .unwrap();
 
-    let progress_manager = ProgressManager::new();
-
-    connect_system_ports(
-        active_client.as_client(),
-        in_port_name.as_str(),
-        out_port_name.as_str(),
-    );
+    // The dashboard shows what the bars would
+    let progress_manager = if dashboard.is_some() {
+        ProgressManager::hidden()
+    } else {
+        ProgressManager::new()
+    };
 
-    {
-        shared
-            .record_buffer
-            .lock()
-            .unwrap()
-            .clear();
-    }
+    shared.record_buffer.clear();
 
     if selection == 0 {
         // Sender
@@ -402,7 +1798,7 @@ fn main() {
             line_coding,
             tx_addr,
             rx_addr,
-            timeout,
+            options,
         );
     } else if selection == 1 {
         // Receiver
@@ -413,19 +1809,32 @@ fn main() {
             line_coding,
             tx_addr,
             rx_addr,
-            timeout,
+            options,
+        );
+    } else if selection == 2 {
+        run_duplex(
+            shared,
+            progress_manager,
+            sample_rate as u32,
+            line_coding,
+            tx_addr,
+            rx_addr,
+            options,
         );
     } else {
         unreachable!();
     }
 
     info!("Exiting gracefully...");
+    utils::dump::flush_debug_dumps(Duration::from_millis(DEBUG_DUMP_FLUSH_MS));
     if let Err(err) = active_client.deactivate() {
         error!("Error deactivating client: {}", err);
     }
 }
 
-fn interactive_mode() -> (usize, LineCodingKind, u8, u8, u64) {
+fn interactive_mode(
+    phy_params: &PhyParams,
+) -> (usize, LineCodingKind, u8, u8, u64, TransferOptions) {
     let selections = &["Send File", "Receive File", "Test (No JACK - Loopback)"];
     let selection = Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Select mode")
@@ -436,9 +1845,14 @@ fn interactive_mode() -> (usize, LineCodingKind, u8, u8, u64) {
 
     if selection == 2 {
         // Test mode - return dummy values that won't be used
-        let line_coding_options =
-            [LineCodingKind::FourBFiveB, LineCodingKind::Manchester];
-        let line_coding_labels = ["4B5B (NRZ)", "Manchester (Bi-phase)"];
+        let line_coding_options = [
+            LineCodingKind::FourBFiveB,
+            LineCodingKind::Manchester,
+            LineCodingKind::EightBTenB,
+            LineCodingKind::Nrzi,
+        ];
+        let line_coding_labels =
+            ["4B5B (NRZ)", "Manchester (Bi-phase)", "8B10B (NRZ)", "NRZI"];
         let line_coding_idx = Select::with_theme(&ColorfulTheme::default())
             .with_prompt("Select line coding scheme")
             .default(0)
@@ -446,13 +1860,24 @@ fn interactive_mode() -> (usize, LineCodingKind, u8, u8, u64) {
             .interact()
             .unwrap();
         let line_coding = line_coding_options[line_coding_idx];
-        test_transmission(line_coding);
+        test_transmission(
+            line_coding,
+            FecKind::None,
+            PreambleKind::BytePattern,
+            None,
+            Some(DC_BLOCK_CUTOFF_HZ),
+            phy_params,
+        );
         std::process::exit(0);
     }
 
-    let line_coding_options =
-        [LineCodingKind::FourBFiveB, LineCodingKind::Manchester];
-    let line_coding_labels = ["4B5B (NRZ)", "Manchester (Bi-phase)"];
+    let line_coding_options = [
+        LineCodingKind::FourBFiveB,
+        LineCodingKind::Manchester,
+        LineCodingKind::EightBTenB,
+    ];
+    let line_coding_labels =
+        ["4B5B (NRZ)", "Manchester (Bi-phase)", "8B10B (NRZ)", "NRZI"];
     let line_coding_idx = Select::with_theme(&ColorfulTheme::default())
         .with_prompt("Select line coding scheme")
         .default(0)
@@ -473,11 +1898,67 @@ fn interactive_mode() -> (usize, LineCodingKind, u8, u8, u64) {
             .default(2)
             .interact()
             .unwrap();
+    pick_ports();
+
+    (
+        selection,
+        line_coding,
+        tx_addr,
+        rx_addr,
+        60u64,
+        TransferOptions::default(),
+    )
+}
 
-    (selection, line_coding, tx_addr, rx_addr, 60u64)
+/// Let the user pick among the physical JACK ports, unless --input-port /
+/// --output-port did or there is nothing to choose
+fn pick_ports() {
+    if backend::selected_ports() != PortSelection::default() {
+        return;
+    }
+    let ports = match device::jack::query_physical_ports(&format!(
+        "{}_ports",
+        JACK_CLIENT_NAME
+    )) {
+        Ok(ports) => ports,
+        Err(e) => {
+            debug!("{}, using the default ports", e);
+            return;
+        }
+    };
+    let pick = |prompt: &str, capture: bool| {
+        let mut items = vec!["First physical port".to_string()];
+        items.extend(
+            ports
+                .iter()
+                .filter(|port| port.capture == capture)
+                .map(|port| port.name.clone()),
+        );
+        if items.len() <= 2 {
+            return None;
+        }
+        let index = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(prompt)
+            .default(0)
+            .items(&items)
+            .interact()
+            .unwrap();
+        (index > 0).then(|| items[index].clone())
+    };
+    backend::select_ports(PortSelection {
+        input: pick("Select capture port to record from", true),
+        output: pick("Select playback port to play to", false),
+    });
 }
 
-fn test_transmission(line_coding: LineCodingKind) {
+fn test_transmission(
+    line_coding: LineCodingKind,
+    fec: FecKind,
+    preamble: PreambleKind,
+    sync_threshold: Option<f32>,
+    dc_cutoff: Option<f32>,
+    params: &PhyParams,
+) {
     info!("=== Test Mode (Loopback without JACK) ===");
     info!("Using line coding: {}", line_coding.name());
 
@@ -492,17 +1973,17 @@ fn test_transmission(line_coding: LineCodingKind) {
 
     // Create encoder and decoder
     let encoder =
-        PhyEncoder::new(SAMPLES_PER_LEVEL, PREAMBLE_PATTERN_BYTES, line_coding);
-    let mut decoder = PhyDecoder::new(
-        SAMPLES_PER_LEVEL,
-        PREAMBLE_PATTERN_BYTES,
-        line_coding,
-        2,
-    );
+        PhyEncoder::new(params, line_coding, fec).with_preamble(preamble);
+    let mut decoder = PhyDecoder::new(params, line_coding, fec, 2)
+        .with_preamble(preamble)
+        .with_dc_cutoff(dc_cutoff);
+    if let Some(threshold) = sync_threshold {
+        decoder = decoder.with_correlation_threshold(threshold);
+    }
 
     // Create frames
     let mut frames = Vec::new();
-    let mut seq = 0u8;
+    let mut seq = 0u16;
 
     for chunk in test_data.chunks(MAX_FRAME_DATA_SIZE) {
         let frame = Frame::new_data(seq, 0, 1, chunk.to_vec());
@@ -513,7 +1994,7 @@ fn test_transmission(line_coding: LineCodingKind) {
     info!("Created {} frames", frames.len());
 
     // Encode
-    let samples = encoder.encode_frames(&frames, INTER_FRAME_GAP_SAMPLES);
+    let samples = encoder.encode_frames(&frames, params.inter_frame_gap_samples);
     info!(
         "Encoded to {} samples ({:.2} seconds at {} Hz)",
         samples.len(),
@@ -587,3 +2068,219 @@ fn test_transmission(line_coding: LineCodingKind) {
         (1.0 - effective_bitrate / BIT_RATE as f32) * 100.0
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(args: &[&str]) -> Result<Commands, clap::Error> {
+        let argv = ["trackmaker-rs", "ping", "192.168.1.2"];
+        Cli::try_parse_from(argv.iter().chain(args))
+            .map(|cli| cli.command.unwrap())
+    }
+
+    #[test]
+    fn test_ping_arguments() {
+        let Ok(Commands::Ping {
+            count,
+            interval_ms,
+            timeout_ms,
+            quiet,
+            sweep,
+            payload_size,
+            ..
+        }) = ping(&[])
+        else {
+            panic!("not a ping");
+        };
+        assert_eq!(count, PING_PACKET_COUNT);
+        assert_eq!(interval_ms, PING_INTERVAL_MS);
+        assert_eq!(timeout_ms, PING_TIMEOUT_MS);
+        assert!(!quiet && sweep.is_none());
+        assert_eq!(payload_size, PING_PAYLOAD_SIZE);
+
+        let Ok(Commands::Ping {
+            count,
+            interval_ms,
+            timeout_ms,
+            quiet,
+            sweep,
+            ..
+        }) = ping(&[
+            "-c",
+            "3",
+            "--interval-ms",
+            "200",
+            "--timeout-ms",
+            "500",
+            "-q",
+            "--sweep",
+            "32,128,32",
+        ])
+        else {
+            panic!("not a ping");
+        };
+        assert_eq!((count, interval_ms, timeout_ms, quiet), (3, 200, 500, true));
+        assert_eq!(sweep.unwrap().sizes(), vec![32, 64, 96, 128]);
+
+        let Ok(Commands::Ping { payload_size, .. }) = ping(&["--size", "100"])
+        else {
+            panic!("not a ping");
+        };
+        assert_eq!(payload_size, 100);
+        // One size or a sweep, not both
+        assert!(ping(&["--size", "100", "--sweep", "32,64,8"]).is_err());
+        assert!(ping(&["--sweep", "64,32,8"]).is_err());
+    }
+
+    #[test]
+    fn test_broadcast_sends_the_file_named_for_the_peer() {
+        let tx = |args: &[&str]| {
+            let argv = ["trackmaker-rs", "tx", "-l", "1", "-r", "3"];
+            let Some(Commands::Tx {
+                local,
+                remote,
+                broadcast,
+                file,
+                ..
+            }) = Cli::try_parse_from(argv.iter().chain(args))
+                .unwrap()
+                .command
+            else {
+                panic!("not a tx");
+            };
+            tx_destination(local, remote, broadcast, file)
+        };
+        assert_eq!(tx(&[]), (3, None));
+        assert_eq!(
+            tx(&["--broadcast"]),
+            (mac::types::BROADCAST, Some(PathBuf::from("INPUT1to3.bin")))
+        );
+        assert_eq!(
+            tx(&["--broadcast", "--file", "notes.txt"]),
+            (mac::types::BROADCAST, Some(PathBuf::from("notes.txt")))
+        );
+    }
+
+    fn with_config(args: &[&str], config: &str) -> Cli {
+        let args: Vec<OsString> = args
+            .iter()
+            .map(OsString::from)
+            .collect();
+        let config: toml::Table = config.parse().unwrap();
+        Cli::try_parse_from(utils::config::merge_args(
+            &Cli::command(),
+            &args,
+            &config,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_config_file_precedence() {
+        let config = r#"
+            [global]
+            tx-gain = 0.5
+            [phy]
+            encoding = "nrzi"
+            fec = "hamming"
+            [tx]
+            encoding = "manchester"
+            window = 4
+            rts_cts = true
+        "#;
+        let tx = |cli: Cli| match cli.command {
+            Some(Commands::Tx {
+                encoding,
+                fec,
+                window,
+                rts_cts,
+                ..
+            }) => (cli.tx_gain, encoding, fec, window, rts_cts),
+            _ => panic!("not a tx"),
+        };
+
+        // Defaults
+        assert_eq!(
+            tx(with_config(&["trackmaker-rs", "tx"], "")),
+            (TX_GAIN, "4b5b".into(), "none".into(), ARQ_WINDOW, false)
+        );
+        // The file over the defaults, the subcommand's section over [phy]
+        assert_eq!(
+            tx(with_config(&["trackmaker-rs", "tx"], config)),
+            (0.5, "manchester".into(), "hamming".into(), 4, true)
+        );
+        // The command line over the file, before or after the subcommand
+        assert_eq!(
+            tx(with_config(
+                &[
+                    "trackmaker-rs",
+                    "--tx-gain",
+                    "0.8",
+                    "tx",
+                    "--encoding",
+                    "8b10b",
+                    "--window",
+                    "2"
+                ],
+                config
+            )),
+            (0.8, "8b10b".into(), "hamming".into(), 2, true)
+        );
+        assert_eq!(
+            tx(with_config(
+                &["trackmaker-rs", "tx", "--tx-gain", "0.8"],
+                config
+            ))
+            .0,
+            0.8
+        );
+    }
+
+    #[test]
+    fn test_dump_config_round_trip() {
+        let config: toml::Table = r#"
+            [phy]
+            encoding = "nrzi"
+            [router]
+            gateway-interface = "eth0"
+            eth-ip = "10.1.0.2"
+            route = ["10.9.0.0/16:192.168.1.2:acoustic"]
+            [ping]
+            target = "192.168.1.2"
+            count = 3
+        "#
+        .parse()
+        .unwrap();
+        let args = [OsString::from("trackmaker-rs"), "dump-config".into()];
+        let dumped =
+            utils::config::effective(&Cli::command(), &args, &config, None);
+        assert_eq!(dumped["ping"]["target"].as_str(), Some("192.168.1.2"));
+        assert_eq!(dumped["ping"]["count"].as_integer(), Some(3));
+        assert_eq!(dumped["relay"]["encoding"].as_str(), Some("nrzi"));
+        assert!(
+            utils::config::unknown_keys(&Cli::command(), &dumped).is_empty()
+        );
+
+        // Read back in, the same configuration
+        let text = toml::to_string(&dumped).unwrap();
+        let reread: toml::Table = text.parse().unwrap();
+        assert_eq!(
+            utils::config::effective(&Cli::command(), &args, &reread, None),
+            dumped
+        );
+        // And enough of it for the router's required options
+        let Some(Commands::Router {
+            eth_ip,
+            routes,
+            encoding,
+            ..
+        }) = with_config(&["trackmaker-rs", "router"], &text).command
+        else {
+            panic!("not a router");
+        };
+        assert_eq!(eth_ip, "10.1.0.2");
+        assert_eq!(routes.len(), 1);
+        assert_eq!(encoding, "nrzi");
+    }
+}
@@ -1,31 +1,393 @@
+// ARP over the acoustic link
+//
+// Requests and replies travel as packets of their own in Frame::data, next
+// to IPv4 (whose first nibble is always 4, so the two can't be confused):
+//
+//   [Magic:2 = 0x0806] [Op:1] [SenderMac:1] [SenderIp:4] [TargetMac:1] [TargetIp:4]
+//
+// Requests are broadcast with TargetMac 0; the owner of TargetIp answers
+// directly. A gratuitous ARP (a request for the sender's own address)
+// announces a mapping and gets no answer. Every packet received teaches
+// the table the sender's mapping, and learned entries age out after a TTL.
+// The 192.168.1.x seeds are static and never expire.
+
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info};
+
+use crate::mac::acoustic_interface::AcousticInterface;
+use crate::mac::types::BROADCAST;
+use crate::phy::FrameType;
+use crate::utils::consts::ARP_ENTRY_TTL_MS;
+
+const ARP_MAGIC: [u8; 2] = [0x08, 0x06];
+const ARP_PACKET_LEN: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpOp {
+    Request = 1,
+    Reply = 2,
+}
+
+impl ArpOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            ArpOp::Request => "request",
+            ArpOp::Reply => "reply",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    pub op: ArpOp,
+    pub sender_mac: u8,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: u8,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Who has `target_ip`? Tell `sender_ip`
+    pub fn request(
+        sender_mac: u8,
+        sender_ip: Ipv4Addr,
+        target_ip: Ipv4Addr,
+    ) -> Self {
+        Self {
+            op: ArpOp::Request,
+            sender_mac,
+            sender_ip,
+            target_mac: 0,
+            target_ip,
+        }
+    }
+
+    /// Announcement of our own mapping: a request for `ip` from `ip`
+    pub fn gratuitous(mac: u8, ip: Ipv4Addr) -> Self {
+        Self::request(mac, ip, ip)
+    }
+
+    pub fn is_gratuitous(&self) -> bool {
+        self.sender_ip == self.target_ip
+    }
+
+    /// Answer to a request for our address
+    pub fn reply_to(request: &ArpPacket, local_mac: u8) -> Self {
+        Self {
+            op: ArpOp::Reply,
+            sender_mac: local_mac,
+            sender_ip: request.target_ip,
+            target_mac: request.sender_mac,
+            target_ip: request.sender_ip,
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(ARP_PACKET_LEN);
+        bytes.extend_from_slice(&ARP_MAGIC);
+        bytes.push(self.op as u8);
+        bytes.push(self.sender_mac);
+        bytes.extend_from_slice(&self.sender_ip.octets());
+        bytes.push(self.target_mac);
+        bytes.extend_from_slice(&self.target_ip.octets());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != ARP_PACKET_LEN || bytes[..2] != ARP_MAGIC {
+            return Err("Not an ARP packet".to_string());
+        }
+        let op = match bytes[2] {
+            1 => ArpOp::Request,
+            2 => ArpOp::Reply,
+            op => return Err(format!("Unknown ARP operation {}", op)),
+        };
+        let ip = |at: usize| {
+            Ipv4Addr::new(bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3])
+        };
+        Ok(Self {
+            op,
+            sender_mac: bytes[3],
+            sender_ip: ip(4),
+            target_mac: bytes[8],
+            target_ip: ip(9),
+        })
+    }
+
+    /// Quick check before parsing, IPv4 packets never match
+    pub fn is_arp(bytes: &[u8]) -> bool {
+        bytes.starts_with(&ARP_MAGIC)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ArpEntry {
+    mac: u8,
+    /// When it was last learned, None for static entries
+    learned: Option<Instant>,
+}
 
 pub struct ArpTable {
-    table: HashMap<Ipv4Addr, u8>,
+    table: HashMap<Ipv4Addr, ArpEntry>,
+    ttl: Duration,
+}
+
+impl Default for ArpTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // FIXME: deprecate this in favor of Router's ARP table
 impl ArpTable {
     pub fn new() -> Self {
-        let mut table = HashMap::new();
+        let mut table = Self {
+            table: HashMap::new(),
+            ttl: Duration::from_millis(ARP_ENTRY_TTL_MS),
+        };
         // Static mapping the IP addresses to MAC addresses
-        table.insert("192.168.1.1".parse().unwrap(), 1);
-        table.insert("192.168.1.2".parse().unwrap(), 2);
-        table.insert("192.168.1.3".parse().unwrap(), 3);
-        Self { table }
+        table.add_static("192.168.1.1".parse().unwrap(), 1);
+        table.add_static("192.168.1.2".parse().unwrap(), 2);
+        table.add_static("192.168.1.3".parse().unwrap(), 3);
+        table
+    }
+
+    /// Lifetime of learned entries
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Add a mapping that never expires
+    pub fn add_static(&mut self, ip: Ipv4Addr, mac: u8) {
+        self.table
+            .insert(ip, ArpEntry { mac, learned: None });
+    }
+
+    /// Learn or refresh a mapping. Static entries are left alone.
+    pub fn learn(&mut self, ip: Ipv4Addr, mac: u8, now: Instant) {
+        if ip.is_unspecified() || ip.is_broadcast() || mac == BROADCAST {
+            return;
+        }
+        let entry = self
+            .table
+            .entry(ip)
+            .or_insert(ArpEntry {
+                mac,
+                learned: Some(now),
+            });
+        if entry.learned.is_some() {
+            if entry.mac != mac {
+                info!("ARP: {} moved from {} to {}", ip, entry.mac, mac);
+            }
+            *entry = ArpEntry {
+                mac,
+                learned: Some(now),
+            };
+        }
+    }
+
+    /// Drop learned entries older than the TTL
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.table
+            .retain(|ip, entry| {
+                let alive = !Self::is_expired(entry, ttl, now);
+                if !alive {
+                    debug!("ARP: {} ({}) expired", ip, entry.mac);
+                }
+                alive
+            });
+    }
+
+    fn is_expired(entry: &ArpEntry, ttl: Duration, now: Instant) -> bool {
+        entry
+            .learned
+            .is_some_and(|at| now.saturating_duration_since(at) > ttl)
+    }
+
+    /// MAC address of `ip` as of `now`
+    pub fn lookup(&self, ip: &Ipv4Addr, now: Instant) -> Option<u8> {
+        self.table
+            .get(ip)
+            .filter(|entry| !Self::is_expired(entry, self.ttl, now))
+            .map(|entry| entry.mac)
     }
 
     pub fn get_mac(&self, ip: &Ipv4Addr) -> Option<u8> {
-        self.table.get(ip).cloned()
+        self.lookup(ip, Instant::now())
     }
 
     pub fn get_ip(&self, mac: u8) -> Option<Ipv4Addr> {
-        for (ip, m) in &self.table {
-            if *m == mac {
+        let now = Instant::now();
+        for (ip, entry) in &self.table {
+            if entry.mac == mac && !Self::is_expired(entry, self.ttl, now) {
                 return Some(*ip);
             }
         }
         None
     }
+
+    /// Learn the sender of a packet received from `src_mac`: the sender
+    /// fields of an ARP packet, the source address of an IPv4 one. Returns
+    /// the ARP packet, if it was one.
+    pub fn observe(
+        &mut self,
+        packet: &[u8],
+        src_mac: u8,
+        now: Instant,
+    ) -> Option<ArpPacket> {
+        if ArpPacket::is_arp(packet) {
+            let arp = ArpPacket::from_bytes(packet).ok()?;
+            self.learn(arp.sender_ip, arp.sender_mac, now);
+            return Some(arp);
+        }
+        if let Ok(ip) = etherparse::Ipv4HeaderSlice::from_slice(packet) {
+            self.learn(ip.source_addr(), src_mac, now);
+        }
+        None
+    }
+
+    /// MAC address of `ip`, broadcasting a request from `local_ip` if it
+    /// isn't known. Other packets received while waiting are dropped.
+    pub fn resolve(
+        &mut self,
+        interface: &mut AcousticInterface,
+        local_ip: Ipv4Addr,
+        ip: Ipv4Addr,
+        timeout: Duration,
+    ) -> Result<u8, String> {
+        if let Some(mac) = self.get_mac(&ip) {
+            return Ok(mac);
+        }
+        let request = ArpPacket::request(interface.local_mac(), local_ip, ip);
+        info!("ARP: who has {}? tell {}", ip, local_ip);
+        interface.send_packet(
+            &request.to_bytes(),
+            BROADCAST,
+            FrameType::Data,
+        )?;
+
+        let start = Instant::now();
+        while let Some(left) = timeout.checked_sub(start.elapsed()) {
+            let Ok((packet, src)) = interface.receive_packet_from(Some(left))
+            else {
+                break;
+            };
+            match self.observe(&packet, src, Instant::now()) {
+                Some(reply)
+                    if reply.op == ArpOp::Reply && reply.sender_ip == ip =>
+                {
+                    info!("ARP: {} is at {}", ip, reply.sender_mac);
+                    return Ok(reply.sender_mac);
+                }
+                Some(arp) => {
+                    debug!("ARP: ignoring {} while resolving", arp.op.name())
+                }
+                None => debug!("ARP: dropping packet while resolving {}", ip),
+            }
+        }
+        Err(format!("ARP: no reply from {}", ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, last)
+    }
+
+    #[test]
+    fn test_request_reply_round_trip() {
+        let request = ArpPacket::request(4, ip(4), ip(5));
+        let bytes = request.to_bytes();
+        assert_eq!(bytes.len(), ARP_PACKET_LEN);
+        assert_eq!(ArpPacket::from_bytes(&bytes), Ok(request));
+
+        let reply = ArpPacket::reply_to(&request, 5);
+        assert_eq!(reply.op, ArpOp::Reply);
+        assert_eq!((reply.sender_ip, reply.sender_mac), (ip(5), 5));
+        assert_eq!((reply.target_ip, reply.target_mac), (ip(4), 4));
+        assert_eq!(ArpPacket::from_bytes(&reply.to_bytes()), Ok(reply));
+
+        // IPv4 packets and damaged ARP ones are rejected
+        assert!(!ArpPacket::is_arp(&[0x45, 0, 0, 20]));
+        assert!(ArpPacket::from_bytes(&bytes[..12]).is_err());
+        let mut bad_op = bytes.clone();
+        bad_op[2] = 9;
+        assert!(ArpPacket::from_bytes(&bad_op).is_err());
+    }
+
+    #[test]
+    fn test_gratuitous_arp() {
+        let announce = ArpPacket::gratuitous(7, ip(7));
+        assert!(announce.is_gratuitous());
+        assert!(!ArpPacket::request(4, ip(4), ip(7)).is_gratuitous());
+        assert_eq!(
+            crate::net::PayloadKind::of(&announce.to_bytes()),
+            crate::net::PayloadKind::Arp
+        );
+
+        // Announcements refresh what the table knows
+        let mut arp = ArpTable::new();
+        let now = Instant::now();
+        arp.learn(ip(7), 3, now);
+        arp.observe(&announce.to_bytes(), 7, now);
+        assert_eq!(arp.lookup(&ip(7), now), Some(7));
+    }
+
+    #[test]
+    fn test_learns_from_received_packets() {
+        let mut arp = ArpTable::new();
+        let now = Instant::now();
+
+        let request = ArpPacket::request(4, ip(4), ip(5));
+        assert_eq!(arp.observe(&request.to_bytes(), 4, now), Some(request));
+        assert_eq!(arp.lookup(&ip(4), now), Some(4));
+
+        // Plain IPv4: the source address maps to the frame's sender
+        let mut packet = Vec::new();
+        etherparse::Ipv4Header::new(
+            0,
+            64,
+            etherparse::IpNumber::ICMP,
+            ip(6).octets(),
+            ip(1).octets(),
+        )
+        .unwrap()
+        .write(&mut packet)
+        .unwrap();
+        assert_eq!(arp.observe(&packet, 6, now), None);
+        assert_eq!(arp.lookup(&ip(6), now), Some(6));
+
+        // Static entries can't be overridden
+        let seeded: Ipv4Addr = "192.168.1.1".parse().unwrap();
+        arp.learn(seeded, 9, now);
+        assert_eq!(arp.lookup(&seeded, now), Some(1));
+    }
+
+    #[test]
+    fn test_learned_entries_expire() {
+        let mut arp = ArpTable::new();
+        arp.set_ttl(Duration::from_secs(10));
+        let now = Instant::now();
+        arp.learn(ip(4), 4, now);
+
+        let later = now + Duration::from_secs(6);
+        assert_eq!(arp.lookup(&ip(4), later), Some(4));
+        // A refresh restarts the clock
+        arp.learn(ip(4), 4, later);
+        assert_eq!(arp.lookup(&ip(4), now + Duration::from_secs(12)), Some(4));
+
+        let gone = later + Duration::from_secs(11);
+        assert_eq!(arp.lookup(&ip(4), gone), None);
+        arp.expire(gone);
+        assert!(!arp.table.contains_key(&ip(4)));
+        // Static entries stay
+        assert_eq!(arp.lookup(&"192.168.1.2".parse().unwrap(), gone), Some(2));
+    }
 }
@@ -0,0 +1,388 @@
+// Router traffic counters
+//
+// Lock-free per-interface counters bumped from the router's packet path,
+// and a small table of the busiest flows by (source, destination,
+// protocol). Packets are counted on the interface they came in on (rx,
+// drops, NAT) or go out of (tx); a packet sent out the acoustic link
+// counts once, however many fragments it takes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::net::router::InterfaceType;
+use crate::utils::metrics::Exposition;
+
+/// Why the router dropped a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    /// Unparsable or inconsistent IP packet
+    Invalid,
+    /// Fragment that couldn't be reassembled
+    Reassembly,
+    TtlExpired,
+    NoRoute,
+    /// ARP for the next hop never got an answer
+    HostUnreachable,
+    /// Over the MTU with Don't Fragment set
+    TooBig,
+    Firewall,
+    /// No NAT session or port for it
+    Nat,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 8] = [
+        DropReason::Invalid,
+        DropReason::Reassembly,
+        DropReason::TtlExpired,
+        DropReason::NoRoute,
+        DropReason::HostUnreachable,
+        DropReason::TooBig,
+        DropReason::Firewall,
+        DropReason::Nat,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DropReason::Invalid => "invalid",
+            DropReason::Reassembly => "reassembly",
+            DropReason::TtlExpired => "ttl_expired",
+            DropReason::NoRoute => "no_route",
+            DropReason::HostUnreachable => "host_unreachable",
+            DropReason::TooBig => "too_big",
+            DropReason::Firewall => "firewall",
+            DropReason::Nat => "nat",
+        }
+    }
+
+    fn slot(self) -> usize {
+        self as usize
+    }
+}
+
+/// Counters of one interface
+#[derive(Debug, Default)]
+pub struct InterfaceCounters {
+    rx_packets: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_packets: AtomicU64,
+    tx_bytes: AtomicU64,
+    /// Packets in on this interface that NAT rewrote
+    nat_packets: AtomicU64,
+    drops: [AtomicU64; DropReason::ALL.len()],
+}
+
+/// Plain copy of an interface's counters, drops by reason name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InterfaceSnapshot {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub nat_packets: u64,
+    pub drops: BTreeMap<&'static str, u64>,
+}
+
+impl InterfaceSnapshot {
+    pub fn dropped(&self) -> u64 {
+        self.drops.values().sum()
+    }
+}
+
+impl InterfaceCounters {
+    pub fn snapshot(&self) -> InterfaceSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        InterfaceSnapshot {
+            rx_packets: load(&self.rx_packets),
+            rx_bytes: load(&self.rx_bytes),
+            tx_packets: load(&self.tx_packets),
+            tx_bytes: load(&self.tx_bytes),
+            nat_packets: load(&self.nat_packets),
+            drops: DropReason::ALL
+                .iter()
+                .map(|reason| (reason.name(), load(&self.drops[reason.slot()])))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+        }
+    }
+}
+
+/// Traffic of one (source, destination, protocol) seen coming in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FlowCount {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+type FlowKey = (Ipv4Addr, Ipv4Addr, u8);
+
+/// All the router's counters
+#[derive(Debug)]
+pub struct RouterCounters {
+    interfaces: [InterfaceCounters; 4],
+    flows: Mutex<HashMap<FlowKey, FlowCount>>,
+    max_flows: usize,
+}
+
+impl RouterCounters {
+    /// Counters tracking at most `max_flows` flows; once full, a new flow
+    /// pushes out the one with the fewest bytes
+    pub fn new(max_flows: usize) -> Self {
+        Self {
+            interfaces: Default::default(),
+            flows: Mutex::new(HashMap::new()),
+            max_flows,
+        }
+    }
+
+    pub fn interface(&self, iface: InterfaceType) -> &InterfaceCounters {
+        let slot = match iface {
+            InterfaceType::Acoustic => 0,
+            InterfaceType::WiFi => 1,
+            InterfaceType::Ethernet => 2,
+            InterfaceType::Tun => 3,
+        };
+        &self.interfaces[slot]
+    }
+
+    pub fn record_rx(&self, iface: InterfaceType, bytes: usize) {
+        let counters = self.interface(iface);
+        counters
+            .rx_packets
+            .fetch_add(1, Ordering::Relaxed);
+        counters
+            .rx_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_tx(&self, iface: InterfaceType, bytes: usize) {
+        let counters = self.interface(iface);
+        counters
+            .tx_packets
+            .fetch_add(1, Ordering::Relaxed);
+        counters
+            .tx_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_nat(&self, iface: InterfaceType) {
+        self.interface(iface)
+            .nat_packets
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_drop(&self, iface: InterfaceType, reason: DropReason) {
+        self.interface(iface).drops[reason.slot()]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_flow(
+        &self,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        protocol: u8,
+        bytes: usize,
+    ) {
+        let Ok(mut flows) = self.flows.lock() else {
+            return;
+        };
+        let key = (source, destination, protocol);
+        if !flows.contains_key(&key) && flows.len() >= self.max_flows {
+            let quietest = flows
+                .iter()
+                .min_by_key(|(_, flow)| flow.bytes)
+                .map(|(key, _)| *key);
+            if let Some(quietest) = quietest {
+                flows.remove(&quietest);
+            }
+        }
+        let flow = flows
+            .entry(key)
+            .or_insert(FlowCount {
+                source,
+                destination,
+                protocol,
+                packets: 0,
+                bytes: 0,
+            });
+        flow.packets += 1;
+        flow.bytes += bytes as u64;
+    }
+
+    /// Log each interface that saw traffic and the `top` busiest flows
+    pub fn log_summary(&self, top: usize) {
+        for iface in [
+            InterfaceType::Acoustic,
+            InterfaceType::WiFi,
+            InterfaceType::Ethernet,
+            InterfaceType::Tun,
+        ] {
+            let counters = self
+                .interface(iface)
+                .snapshot();
+            if counters == InterfaceSnapshot::default() {
+                continue;
+            }
+            info!(
+                "{}: rx {} packets/{} bytes, tx {} packets/{} bytes, nat {}, dropped {} {:?}",
+                iface.name(),
+                counters.rx_packets,
+                counters.rx_bytes,
+                counters.tx_packets,
+                counters.tx_bytes,
+                counters.nat_packets,
+                counters.dropped(),
+                counters.drops
+            );
+        }
+        for flow in self.top_flows(top) {
+            info!(
+                "Flow {} -> {} proto {}: {} packets/{} bytes",
+                flow.source,
+                flow.destination,
+                flow.protocol,
+                flow.packets,
+                flow.bytes
+            );
+        }
+    }
+
+    /// Add the per-interface counters to a metrics scrape
+    pub fn export(&self, out: &mut Exposition) {
+        let mut packets = Vec::new();
+        let mut bytes = Vec::new();
+        let mut nat = Vec::new();
+        let mut drops = Vec::new();
+        for iface in [
+            InterfaceType::Acoustic,
+            InterfaceType::WiFi,
+            InterfaceType::Ethernet,
+            InterfaceType::Tun,
+        ] {
+            let counters = self
+                .interface(iface)
+                .snapshot();
+            let name = iface.name();
+            packets.push((
+                vec![("interface", name), ("direction", "rx")],
+                counters.rx_packets,
+            ));
+            packets.push((
+                vec![("interface", name), ("direction", "tx")],
+                counters.tx_packets,
+            ));
+            bytes.push((
+                vec![("interface", name), ("direction", "rx")],
+                counters.rx_bytes,
+            ));
+            bytes.push((
+                vec![("interface", name), ("direction", "tx")],
+                counters.tx_bytes,
+            ));
+            nat.push((vec![("interface", name)], counters.nat_packets));
+            for (reason, count) in counters.drops {
+                drops.push((
+                    vec![("interface", name), ("reason", reason)],
+                    count,
+                ));
+            }
+        }
+        out.counter_family(
+            "router_packets_total",
+            "Packets in or out of an interface",
+            &packets,
+        );
+        out.counter_family(
+            "router_bytes_total",
+            "Bytes in or out of an interface",
+            &bytes,
+        );
+        out.counter_family(
+            "router_nat_packets_total",
+            "Packets in on an interface that NAT rewrote",
+            &nat,
+        );
+        if !drops.is_empty() {
+            out.counter_family(
+                "router_drops_total",
+                "Packets dropped, by the interface they came in on",
+                &drops,
+            );
+        }
+    }
+
+    /// The `n` flows with the most bytes, busiest first
+    pub fn top_flows(&self, n: usize) -> Vec<FlowCount> {
+        let Ok(flows) = self.flows.lock() else {
+            return Vec::new();
+        };
+        let mut top: Vec<_> = flows
+            .values()
+            .copied()
+            .collect();
+        top.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then(b.packets.cmp(&a.packets))
+        });
+        top.truncate(n);
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interface_counters() {
+        let counters = RouterCounters::new(8);
+        counters.record_rx(InterfaceType::Acoustic, 100);
+        counters.record_rx(InterfaceType::Acoustic, 50);
+        counters.record_tx(InterfaceType::Ethernet, 150);
+        counters.record_drop(InterfaceType::Acoustic, DropReason::NoRoute);
+        counters.record_drop(InterfaceType::Acoustic, DropReason::NoRoute);
+        counters.record_drop(InterfaceType::Acoustic, DropReason::Firewall);
+
+        let acoustic = counters
+            .interface(InterfaceType::Acoustic)
+            .snapshot();
+        assert_eq!((acoustic.rx_packets, acoustic.rx_bytes), (2, 150));
+        assert_eq!(acoustic.drops["no_route"], 2);
+        assert_eq!(acoustic.drops["firewall"], 1);
+        assert_eq!(acoustic.dropped(), 3);
+        let eth = counters
+            .interface(InterfaceType::Ethernet)
+            .snapshot();
+        assert_eq!((eth.tx_packets, eth.tx_bytes), (1, 150));
+        assert!(eth.drops.is_empty());
+    }
+
+    #[test]
+    fn test_top_flows_bounded() {
+        let counters = RouterCounters::new(2);
+        let ip = |last: u8| Ipv4Addr::new(192, 168, 1, last);
+        for _ in 0..3 {
+            counters.record_flow(ip(2), ip(9), 17, 100);
+        }
+        counters.record_flow(ip(3), ip(9), 6, 1000);
+        assert_eq!(counters.top_flows(5)[1].packets, 3);
+        // Table full: the new flow replaces the quietest one
+        counters.record_flow(ip(4), ip(9), 1, 10);
+
+        let top = counters.top_flows(5);
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].source, top[0].protocol), (ip(3), 6));
+        assert_eq!((top[1].source, top[1].packets), (ip(4), 1));
+        assert_eq!(top[1].bytes, 10);
+        assert_eq!(counters.top_flows(1).len(), 1);
+    }
+}
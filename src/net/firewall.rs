@@ -0,0 +1,411 @@
+// Stateless packet filter for the router
+//
+// Every IPv4 packet the router takes in is checked against an ordered rule
+// list before it is routed; the first rule that matches decides, packets
+// no rule matches are allowed. Rules are written as an action followed by
+// any of the match fields, e.g.
+//
+//   deny src=192.168.1.0/24 out=eth proto=tcp port=80-443
+//   reject proto=icmp in=acoustic
+//
+// `port` is the destination port (TCP and UDP only), `out` the interface
+// the routing table picks, none for packets addressed to the router.
+// `reject` denies and answers with ICMP Communication Administratively
+// Prohibited.
+
+use std::net::Ipv4Addr;
+
+use crate::net::router::InterfaceType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallAction {
+    Allow,
+    /// Drop silently
+    Deny,
+    /// Drop and tell the sender
+    Reject,
+}
+
+impl FirewallAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            FirewallAction::Allow => "allow",
+            FirewallAction::Deny => "deny",
+            FirewallAction::Reject => "reject",
+        }
+    }
+}
+
+/// `net/len`, a bare address being a /32
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Prefix {
+    pub network: Ipv4Addr,
+    pub len: u8,
+}
+
+impl Ipv4Prefix {
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::MAX
+            .checked_shl(32 - self.len as u32)
+            .unwrap_or(0);
+        u32::from(ip) & mask == u32::from(self.network) & mask
+    }
+}
+
+impl std::str::FromStr for Ipv4Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, len) = s
+            .split_once('/')
+            .unwrap_or((s, "32"));
+        let network = network
+            .parse()
+            .map_err(|_| format!("Invalid network '{}'", network))?;
+        let len = len
+            .parse::<u8>()
+            .ok()
+            .filter(|len| *len <= 32)
+            .ok_or_else(|| format!("Invalid prefix length '{}'", len))?;
+        Ok(Self { network, len })
+    }
+}
+
+impl std::fmt::Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.len)
+    }
+}
+
+/// What a rule looks at in a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketSummary {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    /// Destination port, TCP and UDP only
+    pub port: Option<u16>,
+    pub in_interface: InterfaceType,
+    /// None if the packet is for the router itself
+    pub out_interface: Option<InterfaceType>,
+}
+
+/// One rule; fields left out match anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirewallRule {
+    pub action: FirewallAction,
+    pub source: Option<Ipv4Prefix>,
+    pub destination: Option<Ipv4Prefix>,
+    pub protocol: Option<u8>,
+    /// Destination port range, inclusive
+    pub ports: Option<(u16, u16)>,
+    pub in_interface: Option<InterfaceType>,
+    pub out_interface: Option<InterfaceType>,
+}
+
+impl FirewallRule {
+    pub fn new(action: FirewallAction) -> Self {
+        Self {
+            action,
+            source: None,
+            destination: None,
+            protocol: None,
+            ports: None,
+            in_interface: None,
+            out_interface: None,
+        }
+    }
+
+    pub fn matches(&self, packet: &PacketSummary) -> bool {
+        self.source
+            .is_none_or(|prefix| prefix.contains(packet.source))
+            && self
+                .destination
+                .is_none_or(|prefix| prefix.contains(packet.destination))
+            && self
+                .protocol
+                .is_none_or(|protocol| protocol == packet.protocol)
+            && self
+                .ports
+                .is_none_or(|(low, high)| {
+                    packet
+                        .port
+                        .is_some_and(|port| (low..=high).contains(&port))
+                })
+            && self
+                .in_interface
+                .is_none_or(|iface| iface == packet.in_interface)
+            && self
+                .out_interface
+                .is_none_or(|iface| Some(iface) == packet.out_interface)
+    }
+}
+
+fn parse_protocol(s: &str) -> Result<u8, String> {
+    match s
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "icmp" => Ok(1),
+        "tcp" => Ok(6),
+        "udp" => Ok(17),
+        other => other
+            .parse()
+            .map_err(|_| format!("Unknown protocol '{}'", s)),
+    }
+}
+
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 => "icmp".to_string(),
+        6 => "tcp".to_string(),
+        17 => "udp".to_string(),
+        other => other.to_string(),
+    }
+}
+
+impl std::str::FromStr for FirewallRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = s.split_whitespace();
+        let action = match tokens.next() {
+            Some("allow") => FirewallAction::Allow,
+            Some("deny") => FirewallAction::Deny,
+            Some("reject") => FirewallAction::Reject,
+            _ => {
+                return Err(format!(
+                    "Invalid rule '{}', expected allow, deny or reject first",
+                    s
+                ));
+            }
+        };
+        let mut rule = Self::new(action);
+        for token in tokens {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or_else(|| {
+                    format!("Invalid match '{}', expected key=value", token)
+                })?;
+            match key {
+                "src" => rule.source = Some(value.parse()?),
+                "dst" => rule.destination = Some(value.parse()?),
+                "proto" => rule.protocol = Some(parse_protocol(value)?),
+                "port" => {
+                    let (low, high) = value
+                        .split_once('-')
+                        .unwrap_or((value, value));
+                    let port = |p: &str| {
+                        p.parse::<u16>()
+                            .map_err(|_| format!("Invalid port '{}'", p))
+                    };
+                    let (low, high) = (port(low)?, port(high)?);
+                    if low > high {
+                        return Err(format!("Empty port range '{}'", value));
+                    }
+                    rule.ports = Some((low, high));
+                }
+                "in" => rule.in_interface = Some(value.parse()?),
+                "out" => rule.out_interface = Some(value.parse()?),
+                _ => return Err(format!("Unknown match '{}'", key)),
+            }
+        }
+        if rule.ports.is_some() && !matches!(rule.protocol, Some(6 | 17)) {
+            return Err(format!(
+                "Rule '{}' has a port but no proto=tcp or udp",
+                s
+            ));
+        }
+        Ok(rule)
+    }
+}
+
+impl std::fmt::Display for FirewallRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.action.name())?;
+        if let Some(prefix) = self.source {
+            write!(f, " src={}", prefix)?;
+        }
+        if let Some(prefix) = self.destination {
+            write!(f, " dst={}", prefix)?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, " proto={}", protocol_name(protocol))?;
+        }
+        match self.ports {
+            Some((low, high)) if low == high => write!(f, " port={}", low)?,
+            Some((low, high)) => write!(f, " port={}-{}", low, high)?,
+            None => {}
+        }
+        if let Some(iface) = self.in_interface {
+            write!(f, " in={}", iface.name())?;
+        }
+        if let Some(iface) = self.out_interface {
+            write!(f, " out={}", iface.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// The rule list and how many packets each rule caught
+#[derive(Debug, Clone, Default)]
+pub struct Firewall {
+    rules: Vec<FirewallRule>,
+    hits: Vec<u64>,
+}
+
+impl Firewall {
+    pub fn new(rules: Vec<FirewallRule>) -> Self {
+        let hits = vec![0; rules.len()];
+        Self { rules, hits }
+    }
+
+    /// Rules in evaluation order, with their hit counts
+    pub fn rules(&self) -> impl Iterator<Item = (&FirewallRule, u64)> {
+        self.rules
+            .iter()
+            .zip(self.hits.iter().copied())
+    }
+
+    /// Append a rule, evaluated after all the others
+    pub fn push(&mut self, rule: FirewallRule) {
+        self.rules.push(rule);
+        self.hits.push(0);
+    }
+
+    /// Remove the rule at `index`, later ones move up
+    pub fn remove(&mut self, index: usize) -> Option<FirewallRule> {
+        if index >= self.rules.len() {
+            return None;
+        }
+        self.hits.remove(index);
+        Some(self.rules.remove(index))
+    }
+
+    /// Action for `packet` and the index of the rule that decided it,
+    /// None if no rule matched (allowed)
+    pub fn check(
+        &mut self,
+        packet: &PacketSummary,
+    ) -> Option<(usize, FirewallAction)> {
+        let index = self
+            .rules
+            .iter()
+            .position(|rule| rule.matches(packet))?;
+        self.hits[index] += 1;
+        Some((index, self.rules[index].action))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(
+        source: &str,
+        destination: &str,
+        port: Option<u16>,
+    ) -> PacketSummary {
+        PacketSummary {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            protocol: 17,
+            port,
+            in_interface: InterfaceType::Acoustic,
+            out_interface: Some(InterfaceType::Ethernet),
+        }
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rule: FirewallRule =
+            "deny src=192.168.1.0/24 dst=10.0.0.7 proto=tcp port=80-443 in=acoustic out=eth"
+                .parse()
+                .unwrap();
+        assert_eq!(rule.action, FirewallAction::Deny);
+        assert_eq!(
+            rule.source,
+            Some(Ipv4Prefix {
+                network: "192.168.1.0".parse().unwrap(),
+                len: 24
+            })
+        );
+        assert_eq!(rule.destination.unwrap().len, 32);
+        assert_eq!(rule.protocol, Some(6));
+        assert_eq!(rule.ports, Some((80, 443)));
+        assert_eq!(rule.in_interface, Some(InterfaceType::Acoustic));
+        assert_eq!(rule.out_interface, Some(InterfaceType::Ethernet));
+        assert_eq!(
+            rule.to_string(),
+            "deny src=192.168.1.0/24 dst=10.0.0.7/32 proto=tcp port=80-443 in=acoustic out=eth"
+        );
+
+        let rule: FirewallRule = "reject proto=icmp"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.action, FirewallAction::Reject);
+        assert_eq!(
+            rule,
+            rule.to_string()
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(
+            "allow".parse::<FirewallRule>(),
+            Ok(FirewallRule::new(FirewallAction::Allow))
+        );
+
+        for bad in [
+            "",
+            "drop proto=tcp",
+            "deny src=192.168.1.0/33",
+            "deny src",
+            "deny proto=sctp",
+            "deny proto=udp port=90-80",
+            "deny port=53",
+            "deny in=radio",
+            "deny ttl=3",
+        ] {
+            assert!(
+                bad.parse::<FirewallRule>()
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_first_match_decides_and_counts() {
+        let mut firewall = Firewall::new(vec![
+            "allow src=192.168.1.5"
+                .parse()
+                .unwrap(),
+            "deny src=192.168.1.0/24 proto=udp port=53"
+                .parse()
+                .unwrap(),
+            "reject out=eth"
+                .parse()
+                .unwrap(),
+        ]);
+
+        let allowed = summary("192.168.1.5", "8.8.8.8", Some(53));
+        assert_eq!(firewall.check(&allowed), Some((0, FirewallAction::Allow)));
+        let dns = summary("192.168.1.2", "8.8.8.8", Some(53));
+        assert_eq!(firewall.check(&dns), Some((1, FirewallAction::Deny)));
+        let other = summary("192.168.1.2", "8.8.8.8", Some(123));
+        assert_eq!(firewall.check(&other), Some((2, FirewallAction::Reject)));
+        // For the router itself, no out interface to match
+        let local = PacketSummary {
+            out_interface: None,
+            ..other
+        };
+        assert_eq!(firewall.check(&local), None);
+
+        let hits: Vec<_> = firewall
+            .rules()
+            .map(|(_, hits)| hits)
+            .collect();
+        assert_eq!(hits, vec![1, 1, 1]);
+    }
+}
@@ -1,6 +1,9 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use tracing::{debug, info};
 
+use crate::utils::consts::{REASSEMBLY_MEMORY_CAP, REASSEMBLY_TIMEOUT_MS};
+
 /// Structure to hold fragmentation information
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct FragmentationInfo {
@@ -84,7 +87,6 @@ impl IpFragmenter {
     ///
     /// # Arguments
     /// * `packet` - Complete IP packet (including header)
-    /// * `mtu` - Maximum Transmission Unit
     ///
     /// # Returns
     /// Vector of fragments, each <= MTU in size
@@ -101,7 +103,32 @@ impl IpFragmenter {
             );
             return Ok(vec![packet.to_vec()]);
         }
+        let identification = self.next_identification();
+        self.split(packet, identification)
+    }
+
+    /// Fragment a packet passing through, as a router does: it keeps its
+    /// identification, and a fragment splits into smaller fragments of the
+    /// same datagram
+    pub fn fragment_forwarded(
+        &self,
+        packet: &[u8],
+    ) -> Result<Vec<Vec<u8>>, String> {
+        if packet.len() <= self.mtu {
+            return Ok(vec![packet.to_vec()]);
+        }
+        if packet.len() < 20 {
+            return Err("Invalid IP packet: too small for header".to_string());
+        }
+        let identification = u16::from_be_bytes([packet[4], packet[5]]);
+        self.split(packet, identification)
+    }
 
+    fn split(
+        &self,
+        packet: &[u8],
+        identification: u16,
+    ) -> Result<Vec<Vec<u8>>, String> {
         debug!(
             "Fragmenting packet of size {} (MTU={})",
             packet.len(),
@@ -113,11 +140,8 @@ impl IpFragmenter {
             return Err("Invalid IP packet: too small for header".to_string());
         }
 
-        let ip_header = &packet[..20];
-        let data = &packet[20..];
-
         // Get version and IHL
-        let version_ihl = ip_header[0];
+        let version_ihl = packet[0];
         let ihl = (version_ihl & 0x0F) as usize * 4;
 
         if ihl < 20 || ihl > packet.len() {
@@ -125,9 +149,15 @@ impl IpFragmenter {
         }
 
         let header_with_options = &packet[..ihl];
+        let data = &packet[ihl..];
+
+        // A fragment being split again keeps its place in the datagram
+        let original = FragmentationInfo::from_u16(u16::from_be_bytes([
+            packet[6], packet[7],
+        ]));
 
         // Calculate maximum data per fragment (must be multiple of 8 bytes)
-        let max_data_per_fragment = ((self.mtu - ihl) / 8) * 8;
+        let max_data_per_fragment = (self.mtu.saturating_sub(ihl) / 8) * 8;
 
         if max_data_per_fragment == 0 {
             return Err(
@@ -136,7 +166,6 @@ impl IpFragmenter {
             );
         }
 
-        let identification = self.next_identification();
         let mut fragments = Vec::new();
         let mut offset = 0;
 
@@ -146,24 +175,21 @@ impl IpFragmenter {
             let chunk = &data[offset..offset + chunk_size];
 
             // More fragments flag is set if there's more data after this fragment
-            let more_fragments = offset + chunk_size < data.len();
-            let fragment_offset = (offset / 8) as u16;
+            let more_fragments =
+                original.more_fragments || offset + chunk_size < data.len();
+            let fragment_offset = original.fragment_offset + (offset / 8) as u16;
 
-            // Build fragment header
-            let mut fragment = Vec::new();
-            fragment.extend_from_slice(&ip_header[..20]);
+            // Build fragment header, options included
+            let mut fragment = header_with_options.to_vec();
 
-            // Update flags_fragment_offset field
-            let mut flags_offset_bytes = [0u8; 2];
+            // Update flags_fragment_offset field, DF cleared
             let flags_offset_value = FragmentationInfo::new(
                 identification,
                 more_fragments,
                 fragment_offset,
             )
             .to_u16();
-            flags_offset_bytes[0] = (flags_offset_value >> 8) as u8;
-            flags_offset_bytes[1] = flags_offset_value as u8;
-            fragment[6..8].copy_from_slice(&flags_offset_bytes);
+            fragment[6..8].copy_from_slice(&flags_offset_value.to_be_bytes());
 
             // Update total length
             let fragment_total_length = ihl + chunk_size;
@@ -173,18 +199,9 @@ impl IpFragmenter {
             // Update identification
             fragment[4..6].copy_from_slice(&identification.to_be_bytes());
 
-            // Zero the checksum field for recalculation
-            fragment[10..12].copy_from_slice(&[0u8; 2]);
-
-            // Recalculate checksum (simplified: just copy header)
-            // In a real implementation, we'd compute the checksum properly
-            // For now, we copy the original checksum (sender should recalculate)
-            fragment[10..12].copy_from_slice(&ip_header[10..12]);
-
-            // Add header options if present
-            if ihl > 20 {
-                fragment.extend_from_slice(&header_with_options[20..ihl]);
-            }
+            // Checksum over the fragment's own header
+            let checksum = ipv4_header_checksum(&fragment);
+            fragment[10..12].copy_from_slice(&checksum.to_be_bytes());
 
             // Add data chunk
             fragment.extend_from_slice(chunk);
@@ -203,24 +220,121 @@ impl IpFragmenter {
     }
 }
 
+/// Header checksum of an IPv4 header, its checksum field taken as zero
+pub fn ipv4_header_checksum(header: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for (i, word) in header.chunks(2).enumerate() {
+        if i == 5 {
+            continue;
+        }
+        let hi = word[0] as u32;
+        let lo = word
+            .get(1)
+            .copied()
+            .unwrap_or(0) as u32;
+        sum += (hi << 8) | lo;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Fragments of one datagram are told apart by source, destination,
+/// protocol and identification (RFC 791)
+type DatagramKey = ([u8; 4], [u8; 4], u8, u16);
+
+/// A datagram being put back together
+struct PartialDatagram {
+    /// Header of the fragment at offset 0, once it arrived
+    header: Option<Vec<u8>>,
+    /// Payload bytes, valid where `received` says so
+    data: Vec<u8>,
+    /// Byte ranges of the payload received so far, sorted and merged
+    received: Vec<(usize, usize)>,
+    /// Payload length, known once the last fragment (MF=0) arrived
+    total_len: Option<usize>,
+    first_seen: Instant,
+}
+
+impl PartialDatagram {
+    fn new(now: Instant) -> Self {
+        Self {
+            header: None,
+            data: Vec::new(),
+            received: Vec::new(),
+            total_len: None,
+            first_seen: now,
+        }
+    }
+
+    /// Copy `payload` in at byte `start`. Where it overlaps what already
+    /// arrived the first copy is kept.
+    fn insert(&mut self, start: usize, payload: &[u8]) {
+        let end = start + payload.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        let mut pos = start;
+        for &(from, to) in &self.received {
+            if to <= pos || from >= end {
+                continue;
+            }
+            if from > pos {
+                self.data[pos..from]
+                    .copy_from_slice(&payload[pos - start..from - start]);
+            }
+            pos = pos.max(to);
+        }
+        if pos < end {
+            self.data[pos..end].copy_from_slice(&payload[pos - start..]);
+        }
+
+        self.received
+            .push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for &(from, to) in &self.received {
+            match merged.last_mut() {
+                Some(last) if from <= last.1 => last.1 = last.1.max(to),
+                _ => merged.push((from, to)),
+            }
+        }
+        self.received = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self
+                .total_len
+                .is_some_and(|len| self.received == [(0, len)])
+    }
+}
+
 /// Reassembler for combining IP fragments back into a complete packet
+///
+/// Fragments may come in any order and overlap. A datagram still missing
+/// pieces after REASSEMBLY_TIMEOUT_MS is dropped, and so are the oldest
+/// ones when the buffered payload would exceed REASSEMBLY_MEMORY_CAP.
 pub struct IpReassembler {
-    /// Map from (identification, source_ip) to fragments sorted by offset
-    /// Each entry: (offset_in_8byte_units, payload_data)
-    fragments: HashMap<(u16, [u8; 4]), Vec<(u16, Vec<u8>)>>,
-    /// Map to track if we've seen the last fragment
-    last_fragment_seen: HashMap<(u16, [u8; 4]), bool>,
-    /// Store header for first fragment to use in reassembled packet
-    headers: HashMap<(u16, [u8; 4]), Vec<u8>>,
+    datagrams: HashMap<DatagramKey, PartialDatagram>,
+    timeout: Duration,
+    memory_cap: usize,
+}
+
+impl Default for IpReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IpReassembler {
     /// Create a new reassembler
     pub fn new() -> Self {
         Self {
-            fragments: HashMap::new(),
-            last_fragment_seen: HashMap::new(),
-            headers: HashMap::new(),
+            datagrams: HashMap::new(),
+            timeout: Duration::from_millis(REASSEMBLY_TIMEOUT_MS),
+            memory_cap: REASSEMBLY_MEMORY_CAP,
         }
     }
 
@@ -234,6 +348,15 @@ impl IpReassembler {
     pub fn process_fragment(
         &mut self,
         packet: &[u8],
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.process_fragment_at(packet, Instant::now())
+    }
+
+    /// `process_fragment` with the fragment arriving at `now`
+    pub fn process_fragment_at(
+        &mut self,
+        packet: &[u8],
+        now: Instant,
     ) -> Result<Option<Vec<u8>>, String> {
         if packet.len() < 20 {
             return Err(
@@ -248,162 +371,186 @@ impl IpReassembler {
         if ihl < 20 || ihl > packet.len() {
             return Err("Invalid IP header length in fragment".to_string());
         }
+        // Link layer padding doesn't belong to the fragment
+        let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if total_length < ihl || total_length > packet.len() {
+            return Err("Invalid IP total length in fragment".to_string());
+        }
+        let packet = &packet[..total_length];
 
         // Extract fragmentation info
         let flags_offset = u16::from_be_bytes([packet[6], packet[7]]);
         let frag_info = FragmentationInfo::from_u16(flags_offset);
 
-        let identification = u16::from_be_bytes([packet[4], packet[5]]);
-        let source_ip = [packet[12], packet[13], packet[14], packet[15]];
-
-        let key = (identification, source_ip);
-
         // Check if this is a non-fragmented packet
         if !frag_info.more_fragments && frag_info.fragment_offset == 0 {
             // Single fragment packet (not fragmented)
             return Ok(Some(packet.to_vec()));
         }
 
-        // Store header from first fragment (or any fragment)
-        if !self
-            .headers
-            .contains_key(&key)
-        {
-            self.headers
-                .insert(key, packet[..ihl].to_vec());
-        }
+        self.expire(now);
 
-        // Initialize fragment storage if needed
-        if !self
-            .fragments
-            .contains_key(&key)
+        let identification = u16::from_be_bytes([packet[4], packet[5]]);
+        let key: DatagramKey = (
+            [packet[12], packet[13], packet[14], packet[15]],
+            [packet[16], packet[17], packet[18], packet[19]],
+            packet[9],
+            identification,
+        );
+        let payload = &packet[ihl..];
+        let start = frag_info.fragment_offset as usize * 8;
+        let end = start + payload.len();
+
+        if frag_info.more_fragments
+            && !payload
+                .len()
+                .is_multiple_of(8)
         {
-            self.fragments
-                .insert(key, Vec::new());
+            return Err(format!(
+                "Fragment {} of id={} is not a multiple of 8 bytes",
+                start, identification
+            ));
         }
-
-        // Store fragment with its offset
-        let payload = packet[ihl..].to_vec();
-        let fragment_list = self
-            .fragments
-            .get_mut(&key)
-            .unwrap();
-        fragment_list.push((frag_info.fragment_offset, payload.clone()));
+        if ihl + end > u16::MAX as usize {
+            self.datagrams.remove(&key);
+            return Err(format!("Datagram id={} too long", identification));
+        }
+        self.make_room(&key, end)?;
+
+        let datagram = self
+            .datagrams
+            .entry(key)
+            .or_insert_with(|| PartialDatagram::new(now));
+        let conflicting = match datagram.total_len {
+            Some(len) => end > len || (!frag_info.more_fragments && end != len),
+            None => {
+                !frag_info.more_fragments
+                    && datagram
+                        .received
+                        .last()
+                        .is_some_and(|&(_, to)| to > end)
+            }
+        };
+        if conflicting {
+            self.datagrams.remove(&key);
+            return Err(format!(
+                "Fragment {}..{} of id={} past the end of the datagram",
+                start, end, identification
+            ));
+        }
+        if !frag_info.more_fragments {
+            datagram.total_len = Some(end);
+        }
+        if start == 0 && datagram.header.is_none() {
+            datagram.header = Some(packet[..ihl].to_vec());
+        }
+        datagram.insert(start, payload);
 
         debug!(
-            "Fragment received: id={}, offset={}, more={}, payload_len={}, total_fragments={}",
+            "Fragment received: id={}, offset={}, more={}, payload_len={}, holes_left={}",
             identification,
             frag_info.fragment_offset,
             frag_info.more_fragments,
             payload.len(),
-            fragment_list.len()
+            !datagram.is_complete()
         );
 
-        // Mark if this is the last fragment
-        if !frag_info.more_fragments {
-            self.last_fragment_seen
-                .insert(key, true);
-            debug!("Last fragment marked for id={}", identification);
+        if !datagram.is_complete() {
+            return Ok(None);
         }
+        let datagram = self
+            .datagrams
+            .remove(&key)
+            .unwrap();
+        let mut reassembled = datagram.header.unwrap();
+        // The first fragment's header, options and all
+        let header_len = reassembled.len();
+        reassembled.extend_from_slice(&datagram.data);
+
+        // Update IP header total_length
+        let total_length = reassembled.len() as u16;
+        reassembled[2..4].copy_from_slice(&total_length.to_be_bytes());
+        // Clear MF and the offset, keep DF
+        reassembled[6] &= 0x40;
+        reassembled[7] = 0;
+        let checksum = ipv4_header_checksum(&reassembled[..header_len]);
+        reassembled[10..12].copy_from_slice(&checksum.to_be_bytes());
 
-        // Check if we can reassemble
-        if self
-            .last_fragment_seen
-            .get(&key)
-            .copied()
-            .unwrap_or(false)
-        {
-            let fragment_list = &self.fragments[&key];
-
-            // Sort fragments by offset
-            let mut sorted_fragments = fragment_list.clone();
-            sorted_fragments.sort_by_key(|(offset, _)| *offset);
-
-            debug!(
-                "Checking reassembly: id={}, fragments={:?}",
-                identification,
-                sorted_fragments
-                    .iter()
-                    .map(|(o, _)| o)
-                    .collect::<Vec<_>>()
-            );
-
-            // Check if we have all fragments (no gaps in offsets)
-            let mut expected_offset = 0u16;
-            let mut complete = true;
-
-            for (offset, payload) in &sorted_fragments {
-                if *offset != expected_offset {
-                    complete = false;
-                    debug!(
-                        "Gap detected in fragments: expected offset {}, got offset {}",
-                        expected_offset, offset
-                    );
-                    break;
-                }
-                // Next expected offset is current offset + number of 8-byte units in this payload
-                let payload_units = ((payload.len() + 7) / 8) as u16;
-                expected_offset = offset + payload_units;
-
-                debug!(
-                    "Fragment ok: offset={}, payload_len={}, units={}, next_expected={}",
-                    offset,
-                    payload.len(),
-                    payload_units,
-                    expected_offset
-                );
-            }
-
-            if complete {
-                // All fragments received and in order
-                let mut reassembled = Vec::new();
+        debug!(
+            "Reassembled datagram (identification: {}, total_size: {})",
+            identification,
+            reassembled.len()
+        );
 
-                // Add header
-                if let Some(header) = self.headers.get(&key) {
-                    reassembled.extend_from_slice(header);
-                }
+        Ok(Some(reassembled))
+    }
 
-                // Add all payloads in order
-                for (_, payload) in &sorted_fragments {
-                    reassembled.extend_from_slice(payload);
+    /// Drop datagrams that have waited longer than the timeout for their
+    /// missing fragments. Returns how many went.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let timeout = self.timeout;
+        let before = self.datagrams.len();
+        self.datagrams
+            .retain(|key, datagram| {
+                let alive = now.saturating_duration_since(datagram.first_seen)
+                    <= timeout;
+                if !alive {
+                    debug!("Reassembly of id={} timed out", key.3);
                 }
+                alive
+            });
+        before - self.datagrams.len()
+    }
 
-                // Update IP header total_length
-                let total_length = reassembled.len() as u16;
-                if reassembled.len() >= 4 {
-                    reassembled[2..4]
-                        .copy_from_slice(&total_length.to_be_bytes());
-                }
+    /// Payload bytes buffered across all datagrams
+    fn buffered(&self) -> usize {
+        self.datagrams
+            .values()
+            .map(|datagram| datagram.data.len())
+            .sum()
+    }
 
-                // Clear fragmentation flags in reassembled packet
-                if reassembled.len() >= 8 {
-                    reassembled[6..8].copy_from_slice(&[0u8; 2]);
+    /// Evict the oldest other datagrams until `key` can grow to `end`
+    /// bytes within the memory cap
+    fn make_room(
+        &mut self,
+        key: &DatagramKey,
+        end: usize,
+    ) -> Result<(), String> {
+        let current = self
+            .datagrams
+            .get(key)
+            .map_or(0, |datagram| datagram.data.len());
+        if end > self.memory_cap {
+            self.datagrams.remove(key);
+            return Err(format!(
+                "Datagram of {} bytes exceeds the reassembly buffer",
+                end
+            ));
+        }
+        while self.buffered() - current + end.max(current) > self.memory_cap {
+            let oldest = self
+                .datagrams
+                .iter()
+                .filter(|(k, _)| *k != key)
+                .min_by_key(|(_, datagram)| datagram.first_seen)
+                .map(|(k, _)| *k);
+            match oldest {
+                Some(oldest) => {
+                    debug!("Reassembly buffer full, dropping id={}", oldest.3);
+                    self.datagrams.remove(&oldest);
                 }
-
-                // Clean up stored fragments
-                self.fragments.remove(&key);
-                self.last_fragment_seen
-                    .remove(&key);
-                self.headers.remove(&key);
-
-                debug!(
-                    "Reassembled {} fragments (identification: {}, total_size: {})",
-                    sorted_fragments.len(),
-                    identification,
-                    reassembled.len()
-                );
-
-                return Ok(Some(reassembled));
+                None => break,
             }
         }
-
-        Ok(None)
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::consts::DEFAULT_MTU;
 
     #[test]
     fn test_fragmentation_info_encode_decode() {
@@ -512,4 +659,249 @@ mod tests {
             "Reassembled payload should match original"
         );
     }
+
+    /// UDP datagram of `len` payload bytes with a recognisable pattern
+    fn datagram(len: usize) -> Vec<u8> {
+        let payload: Vec<u8> = (0..len)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut packet = Vec::new();
+        etherparse::PacketBuilder::ipv4([192, 168, 2, 5], [192, 168, 1, 1], 64)
+            .udp(4000, 5000)
+            .write(&mut packet, &payload)
+            .unwrap();
+        // Clear DF, these get fragmented
+        packet[6] &= !0x40;
+        let checksum = ipv4_header_checksum(&packet[..20]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_reassemble_shuffled_fragments() {
+        use rand::SeedableRng;
+        use rand::seq::SliceRandom;
+
+        let packet = datagram(3000);
+        let mut fragments = IpFragmenter::new(DEFAULT_MTU)
+            .fragment_packet(&packet)
+            .unwrap();
+        assert!(fragments.len() > 10);
+        // Out of order, with a duplicate thrown in
+        fragments.shuffle(&mut rand::rngs::StdRng::seed_from_u64(7));
+        fragments.insert(3, fragments[5].clone());
+
+        let mut reassembler = IpReassembler::new();
+        let (last, rest) = fragments
+            .split_last()
+            .unwrap();
+        for fragment in rest {
+            assert_eq!(
+                reassembler
+                    .process_fragment(fragment)
+                    .unwrap(),
+                None
+            );
+        }
+        let reassembled = reassembler
+            .process_fragment(last)
+            .unwrap()
+            .unwrap();
+        assert!(reassembled == packet, "reassembly is not byte-exact");
+        assert!(
+            reassembler
+                .datagrams
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_overlapping_fragments_keep_first_copy() {
+        let packet = datagram(40);
+        // Payload starts after the 20 byte header: 8 UDP + 40 data bytes
+        let piece = |start: usize, end: usize, more: bool, fill: Option<u8>| {
+            let mut fragment = packet[..20].to_vec();
+            match fill {
+                Some(b) => fragment.extend(vec![b; end - start]),
+                None => {
+                    fragment.extend_from_slice(&packet[20 + start..20 + end])
+                }
+            }
+            let total = fragment.len() as u16;
+            fragment[2..4].copy_from_slice(&total.to_be_bytes());
+            let info = FragmentationInfo::new(0, more, (start / 8) as u16);
+            fragment[6..8].copy_from_slice(&info.to_u16().to_be_bytes());
+            fragment
+        };
+
+        let mut reassembler = IpReassembler::new();
+        assert_eq!(
+            reassembler
+                .process_fragment(&piece(16, 48, false, None))
+                .unwrap(),
+            None
+        );
+        // Overlaps the tail that already arrived, which wins
+        assert_eq!(
+            reassembler
+                .process_fragment(&piece(8, 32, true, Some(0xee)))
+                .unwrap(),
+            None
+        );
+        let reassembled = reassembler
+            .process_fragment(&piece(0, 8, true, None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reassembled[28..36], [0xee; 8]);
+        assert_eq!(reassembled[36..], packet[36..]);
+
+        // A last fragment ending before data already received is bogus
+        reassembler
+            .process_fragment(&piece(16, 48, true, None))
+            .unwrap();
+        assert!(
+            reassembler
+                .process_fragment(&piece(8, 16, false, None))
+                .is_err()
+        );
+        assert!(
+            reassembler
+                .datagrams
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_first_fragment_options_kept() {
+        let packet = datagram(40);
+        let with_header = |header: &[u8], start: usize, end: usize| {
+            let mut fragment = header.to_vec();
+            fragment.extend_from_slice(&packet[20 + start..20 + end]);
+            let total = fragment.len() as u16;
+            fragment[2..4].copy_from_slice(&total.to_be_bytes());
+            let info = FragmentationInfo::new(0, end < 48, (start / 8) as u16);
+            fragment[6..8].copy_from_slice(&info.to_u16().to_be_bytes());
+            fragment
+        };
+        // Only the first fragment carries the options: NOP, NOP, NOP, EOL
+        let mut options_header = packet[..20].to_vec();
+        options_header[0] = 0x46;
+        options_header.extend_from_slice(&[1, 1, 1, 0]);
+
+        let mut reassembler = IpReassembler::new();
+        assert_eq!(
+            reassembler
+                .process_fragment(&with_header(&options_header, 0, 24))
+                .unwrap(),
+            None
+        );
+        let reassembled = reassembler
+            .process_fragment(&with_header(&packet[..20], 24, 48))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(reassembled.len(), 24 + 48);
+        assert_eq!(reassembled[20..24], [1, 1, 1, 0]);
+        assert_eq!(reassembled[24..], packet[20..]);
+        assert_eq!(
+            reassembled[10..12],
+            ipv4_header_checksum(&reassembled[..24]).to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn test_incomplete_datagrams_time_out() {
+        let start = Instant::now();
+        let mut fragments = IpFragmenter::new(DEFAULT_MTU)
+            .fragment_packet(&datagram(3000))
+            .unwrap();
+        let missing = fragments.pop().unwrap();
+
+        let mut reassembler = IpReassembler::new();
+        for fragment in &fragments {
+            reassembler
+                .process_fragment_at(fragment, start)
+                .unwrap();
+        }
+        let timeout = Duration::from_millis(REASSEMBLY_TIMEOUT_MS);
+        assert_eq!(reassembler.expire(start + timeout), 0);
+        assert_eq!(reassembler.expire(start + timeout * 2), 1);
+        // Too late: the straggler starts over on its own
+        let late = reassembler
+            .process_fragment_at(&missing, start + timeout * 2)
+            .unwrap();
+        assert_eq!(late, None);
+    }
+
+    #[test]
+    fn test_memory_cap_evicts_oldest() {
+        let start = Instant::now();
+        let mut fragmenter = IpFragmenter::new(DEFAULT_MTU);
+        let mut reassembler = IpReassembler::new();
+        reassembler.memory_cap = 2 * DEFAULT_MTU;
+
+        // First fragments of three datagrams, one after the other
+        let firsts: Vec<_> = (0..3)
+            .map(|_| {
+                fragmenter
+                    .fragment_packet(&datagram(3000))
+                    .unwrap()
+                    .swap_remove(0)
+            })
+            .collect();
+        for (i, first) in firsts.iter().enumerate() {
+            let at = start + Duration::from_millis(i as u64);
+            reassembler
+                .process_fragment_at(first, at)
+                .unwrap();
+        }
+        assert_eq!(reassembler.datagrams.len(), 2);
+        assert!(reassembler.buffered() <= reassembler.memory_cap);
+        let oldest = u16::from_be_bytes([firsts[0][4], firsts[0][5]]);
+        assert!(
+            reassembler
+                .datagrams
+                .keys()
+                .all(|key| key.3 != oldest)
+        );
+    }
+
+    #[test]
+    fn test_refragmenting_keeps_datagram() {
+        let packet = datagram(3000);
+        let mut host = IpFragmenter::new(1500);
+        let router = IpFragmenter::new(DEFAULT_MTU);
+
+        // A router splits the host's fragments further
+        let mut pieces = Vec::new();
+        for fragment in host
+            .fragment_packet(&packet)
+            .unwrap()
+        {
+            pieces.extend(
+                router
+                    .fragment_forwarded(&fragment)
+                    .unwrap(),
+            );
+        }
+        let id = &pieces[0][4..6];
+        for piece in &pieces {
+            assert!(piece.len() <= DEFAULT_MTU);
+            assert_eq!(&piece[4..6], id);
+            let checksum = u16::from_be_bytes([piece[10], piece[11]]);
+            assert_eq!(checksum, ipv4_header_checksum(&piece[..20]));
+        }
+
+        let mut reassembler = IpReassembler::new();
+        let reassembled = pieces
+            .iter()
+            .rev()
+            .find_map(|piece| {
+                reassembler
+                    .process_fragment(piece)
+                    .unwrap()
+            })
+            .unwrap();
+        assert!(reassembled == packet, "reassembly is not byte-exact");
+    }
 }
@@ -1,5 +1,8 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Cursor, Read, Write};
+use std::time::Instant;
+
+use crate::utils::consts::{ICMP_ERROR_BURST, ICMP_ERROR_RATE_PER_SEC};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
@@ -122,6 +125,50 @@ impl IcmpPacket {
     }
 }
 
+/// Token bucket in front of ICMP error generation, so a flood of expiring
+/// packets doesn't turn into a flood of replies
+#[derive(Debug, Clone)]
+pub struct IcmpRateLimiter {
+    rate: f32,
+    burst: f32,
+    tokens: f32,
+    last: Option<Instant>,
+}
+
+impl Default for IcmpRateLimiter {
+    fn default() -> Self {
+        Self::new(ICMP_ERROR_RATE_PER_SEC, ICMP_ERROR_BURST)
+    }
+}
+
+impl IcmpRateLimiter {
+    pub fn new(rate: f32, burst: f32) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: None,
+        }
+    }
+
+    /// Whether one more message may go out at `now`
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if let Some(last) = self.last {
+            let elapsed = now
+                .saturating_duration_since(last)
+                .as_secs_f32();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        }
+        self.last = Some(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,4 +189,20 @@ mod tests {
         assert_eq!(packet.payload, deserialized.payload);
         assert_eq!(packet.checksum, deserialized.checksum);
     }
+
+    #[test]
+    fn test_rate_limiter_refills() {
+        let mut limiter = IcmpRateLimiter::new(10.0, 3.0);
+        let now = Instant::now();
+        assert_eq!(
+            (0..5)
+                .filter(|_| limiter.allow(now))
+                .count(),
+            3
+        );
+        // One token every 100 ms
+        let later = now + std::time::Duration::from_millis(150);
+        assert!(limiter.allow(later));
+        assert!(!limiter.allow(later));
+    }
 }
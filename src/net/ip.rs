@@ -111,6 +111,33 @@ impl Ipv4Header {
     }
 }
 
+/// TCP/UDP checksum of `segment` (header and payload) carried from `src`
+/// to `dst`, pseudo-header included. The segment's checksum field must be
+/// zero.
+pub fn l4_checksum(
+    src: [u8; 4],
+    dst: [u8; 4],
+    protocol: u8,
+    segment: &[u8],
+) -> u16 {
+    // Pseudo-header: addresses, protocol and segment length
+    let mut sum = protocol as u32 + segment.len() as u32;
+    for word in src
+        .chunks(2)
+        .chain(dst.chunks(2))
+    {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    for word in segment.chunks(2) {
+        // Odd length: pad with a zero byte
+        sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
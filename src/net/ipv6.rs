@@ -0,0 +1,690 @@
+// IPv6 on the acoustic link
+//
+// Just enough for host-mode ping6. IPv6 packets travel in Frame::data like
+// IPv4 ones (told apart by the version nibble, 6), but are never
+// fragmented. Every node has the link-local address its MAC maps to,
+// fe80::200:ff:fe00:MM, the EUI-64 of 00:00:00:00:00:MM.
+//
+// Neighbors are found with a minimal NDP in place of ARP: a Neighbor
+// Solicitation to the target's solicited-node group, broadcast on the
+// link, answered by a unicast Neighbor Advertisement. Link-layer address
+// options carry the one byte MAC followed by five zero bytes.
+
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+use etherparse::{IpNumber, Ipv6FlowLabel, Ipv6Header};
+use tracing::{debug, info, warn};
+
+use crate::mac::acoustic_interface::AcousticInterface;
+use crate::mac::types::BROADCAST;
+use crate::phy::FrameType;
+use crate::utils::consts::{ARP_ENTRY_TTL_MS, IP_TTL};
+
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const OPTION_SOURCE_LINK_ADDR: u8 = 1;
+const OPTION_TARGET_LINK_ADDR: u8 = 2;
+/// NDP messages are only accepted with the hop limit they were sent with
+const NDP_HOP_LIMIT: u8 = 255;
+/// Solicited and Override flags of a Neighbor Advertisement
+const NA_SOLICITED_OVERRIDE: u8 = 0x60;
+
+/// Link-local address of the node with acoustic MAC `mac`
+pub fn link_local(mac: u8) -> Ipv6Addr {
+    Ipv6Addr::new(0xfe80, 0, 0, 0, 0x0200, 0x00ff, 0xfe00, mac as u16)
+}
+
+/// Solicited-node multicast group of `addr`, ff02::1:ffXX:XXXX
+pub fn solicited_node(addr: Ipv6Addr) -> Ipv6Addr {
+    let octets = addr.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | octets[13] as u16,
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// ICMPv6 checksum of `message` sent from `src` to `dst`, pseudo-header
+/// included. The message's checksum field must be zero.
+pub fn icmpv6_checksum(src: Ipv6Addr, dst: Ipv6Addr, message: &[u8]) -> u16 {
+    // Pseudo-header: addresses, upper-layer length and next header
+    let length = message.len() as u32;
+    let mut sum = (length >> 16) + (length & 0xffff) + 58;
+    for word in src
+        .octets()
+        .chunks(2)
+        .chain(dst.octets().chunks(2))
+    {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    for word in message.chunks(2) {
+        // Odd length: pad with a zero byte
+        sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+    }
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// IPv6 packet carrying an ICMPv6 message of `icmp_type` with `body`
+/// after the checksum
+fn icmpv6_packet(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    hop_limit: u8,
+    icmp_type: u8,
+    body: &[u8],
+) -> Vec<u8> {
+    let mut message = vec![icmp_type, 0, 0, 0];
+    message.extend_from_slice(body);
+    let checksum = icmpv6_checksum(src, dst, &message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let header = Ipv6Header {
+        traffic_class: 0,
+        flow_label: Ipv6FlowLabel::ZERO,
+        payload_length: message.len() as u16,
+        next_header: IpNumber::IPV6_ICMP,
+        hop_limit,
+        source: src.octets(),
+        destination: dst.octets(),
+    };
+    let mut packet = Vec::with_capacity(Ipv6Header::LEN + message.len());
+    header
+        .write(&mut packet)
+        .expect("Failed to write IPv6 header");
+    packet.extend_from_slice(&message);
+    packet
+}
+
+fn echo(
+    icmp_type: u8,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    identifier: u16,
+    seq: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + payload.len());
+    body.extend_from_slice(&identifier.to_be_bytes());
+    body.extend_from_slice(&seq.to_be_bytes());
+    body.extend_from_slice(payload);
+    icmpv6_packet(src, dst, IP_TTL, icmp_type, &body)
+}
+
+pub fn echo_request(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    identifier: u16,
+    seq: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    echo(ICMPV6_ECHO_REQUEST, src, dst, identifier, seq, payload)
+}
+
+pub fn echo_reply(
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    identifier: u16,
+    seq: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    echo(ICMPV6_ECHO_REPLY, src, dst, identifier, seq, payload)
+}
+
+/// NDP body: `first` (reserved or flags), the target, one link-layer
+/// address option
+fn ndp_body(first: u8, target: Ipv6Addr, option: u8, mac: u8) -> Vec<u8> {
+    let mut body = vec![first, 0, 0, 0];
+    body.extend_from_slice(&target.octets());
+    body.extend_from_slice(&[option, 1, mac, 0, 0, 0, 0, 0]);
+    body
+}
+
+/// Who has `target`? Tell `src` at `src_mac`
+pub fn neighbor_solicitation(
+    src: Ipv6Addr,
+    src_mac: u8,
+    target: Ipv6Addr,
+) -> Vec<u8> {
+    icmpv6_packet(
+        src,
+        solicited_node(target),
+        NDP_HOP_LIMIT,
+        ICMPV6_NEIGHBOR_SOLICITATION,
+        &ndp_body(0, target, OPTION_SOURCE_LINK_ADDR, src_mac),
+    )
+}
+
+/// `src` is at `mac`, told to `dst` that asked
+pub fn neighbor_advertisement(src: Ipv6Addr, mac: u8, dst: Ipv6Addr) -> Vec<u8> {
+    icmpv6_packet(
+        src,
+        dst,
+        NDP_HOP_LIMIT,
+        ICMPV6_NEIGHBOR_ADVERTISEMENT,
+        &ndp_body(NA_SOLICITED_OVERRIDE, src, OPTION_TARGET_LINK_ADDR, mac),
+    )
+}
+
+/// The ICMPv6 messages this module knows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Icmpv6Message {
+    EchoRequest {
+        identifier: u16,
+        seq: u16,
+        payload: Vec<u8>,
+    },
+    EchoReply {
+        identifier: u16,
+        seq: u16,
+    },
+    NeighborSolicitation {
+        target: Ipv6Addr,
+        source_mac: Option<u8>,
+    },
+    NeighborAdvertisement {
+        target: Ipv6Addr,
+        target_mac: Option<u8>,
+    },
+    /// Any other type
+    Other(u8),
+}
+
+/// A received IPv6 packet carrying ICMPv6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6Packet {
+    pub source: Ipv6Addr,
+    pub destination: Ipv6Addr,
+    pub hop_limit: u8,
+    pub message: Icmpv6Message,
+}
+
+/// The MAC in the first link-layer address option of `kind`
+fn link_addr_option(mut options: &[u8], kind: u8) -> Option<u8> {
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        if options[0] == kind {
+            return Some(options[2]);
+        }
+        options = &options[len..];
+    }
+    None
+}
+
+impl Ipv6Packet {
+    /// Parse an IPv6 packet, checking the ICMPv6 checksum. Anything but
+    /// ICMPv6 without extension headers is an error.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let (header, rest) = Ipv6Header::from_slice(data)
+            .map_err(|e| format!("Failed to parse IPv6 header: {}", e))?;
+        if header.next_header != IpNumber::IPV6_ICMP {
+            return Err(format!(
+                "Unsupported IPv6 next header {}",
+                header.next_header.0
+            ));
+        }
+        let message = rest
+            .get(..header.payload_length as usize)
+            .filter(|message| message.len() >= 8)
+            .ok_or("Truncated ICMPv6 message")?;
+        let source = header.source_addr();
+        let destination = header.destination_addr();
+
+        let mut zeroed = message.to_vec();
+        zeroed[2..4].fill(0);
+        let checksum = u16::from_be_bytes([message[2], message[3]]);
+        if icmpv6_checksum(source, destination, &zeroed) != checksum {
+            return Err("Bad ICMPv6 checksum".to_string());
+        }
+
+        let identifier = u16::from_be_bytes([message[4], message[5]]);
+        let seq = u16::from_be_bytes([message[6], message[7]]);
+        let ndp = move || {
+            if header.hop_limit != NDP_HOP_LIMIT || message.len() < 24 {
+                return Err("Invalid NDP message".to_string());
+            }
+            let target: [u8; 16] = message[8..24]
+                .try_into()
+                .unwrap();
+            Ok((Ipv6Addr::from(target), &message[24..]))
+        };
+        let message = match message[0] {
+            ICMPV6_ECHO_REQUEST => Icmpv6Message::EchoRequest {
+                identifier,
+                seq,
+                payload: message[8..].to_vec(),
+            },
+            ICMPV6_ECHO_REPLY => Icmpv6Message::EchoReply { identifier, seq },
+            ICMPV6_NEIGHBOR_SOLICITATION => {
+                let (target, options) = ndp()?;
+                Icmpv6Message::NeighborSolicitation {
+                    target,
+                    source_mac: link_addr_option(
+                        options,
+                        OPTION_SOURCE_LINK_ADDR,
+                    ),
+                }
+            }
+            ICMPV6_NEIGHBOR_ADVERTISEMENT => {
+                let (target, options) = ndp()?;
+                Icmpv6Message::NeighborAdvertisement {
+                    target,
+                    target_mac: link_addr_option(
+                        options,
+                        OPTION_TARGET_LINK_ADDR,
+                    ),
+                }
+            }
+            other => Icmpv6Message::Other(other),
+        };
+        Ok(Self {
+            source,
+            destination,
+            hop_limit: header.hop_limit,
+            message,
+        })
+    }
+}
+
+/// What a host at `local` with MAC `local_mac` answers to `packet`: a
+/// Neighbor Advertisement for a solicitation of its address, an echo
+/// reply for an echo request to it
+pub fn answer(
+    packet: &Ipv6Packet,
+    local: Ipv6Addr,
+    local_mac: u8,
+) -> Option<Vec<u8>> {
+    match &packet.message {
+        // From the unspecified address it's duplicate address detection,
+        // which nobody here does
+        Icmpv6Message::NeighborSolicitation { target, .. }
+            if *target == local && !packet.source.is_unspecified() =>
+        {
+            Some(neighbor_advertisement(local, local_mac, packet.source))
+        }
+        Icmpv6Message::EchoRequest {
+            identifier,
+            seq,
+            payload,
+        } if packet.destination == local => {
+            Some(echo_reply(local, packet.source, *identifier, *seq, payload))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Neighbor {
+    mac: u8,
+    learned: Instant,
+}
+
+/// IPv6 addresses of the nodes on the link, ArpTable's counterpart. All
+/// entries are learned and age out after a TTL.
+pub struct NeighborTable {
+    table: HashMap<Ipv6Addr, Neighbor>,
+    ttl: Duration,
+}
+
+impl Default for NeighborTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NeighborTable {
+    pub fn new() -> Self {
+        Self {
+            table: HashMap::new(),
+            ttl: Duration::from_millis(ARP_ENTRY_TTL_MS),
+        }
+    }
+
+    /// Lifetime of learned entries
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Learn or refresh a mapping
+    pub fn learn(&mut self, ip: Ipv6Addr, mac: u8, now: Instant) {
+        if ip.is_unspecified() || ip.is_multicast() || mac == BROADCAST {
+            return;
+        }
+        if let Some(old) = self
+            .table
+            .insert(ip, Neighbor { mac, learned: now })
+            && old.mac != mac
+        {
+            info!("NDP: {} moved from {} to {}", ip, old.mac, mac);
+        }
+    }
+
+    /// Drop entries older than the TTL
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.table
+            .retain(|ip, entry| {
+                let alive = now.saturating_duration_since(entry.learned) <= ttl;
+                if !alive {
+                    debug!("NDP: {} ({}) expired", ip, entry.mac);
+                }
+                alive
+            });
+    }
+
+    /// MAC address of `ip` as of `now`
+    pub fn lookup(&self, ip: &Ipv6Addr, now: Instant) -> Option<u8> {
+        self.table
+            .get(ip)
+            .filter(|entry| {
+                now.saturating_duration_since(entry.learned) <= self.ttl
+            })
+            .map(|entry| entry.mac)
+    }
+
+    /// Parse an IPv6 packet received from `src_mac` and learn from it: the
+    /// source address maps to the frame's sender, an advertisement's
+    /// target to the MAC it carries
+    pub fn observe(
+        &mut self,
+        data: &[u8],
+        src_mac: u8,
+        now: Instant,
+    ) -> Option<Ipv6Packet> {
+        let packet = match Ipv6Packet::parse(data) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("NDP: ignoring packet from {}: {}", src_mac, e);
+                return None;
+            }
+        };
+        self.learn(packet.source, src_mac, now);
+        if let Icmpv6Message::NeighborAdvertisement {
+            target,
+            target_mac: Some(mac),
+        } = packet.message
+        {
+            self.learn(target, mac, now);
+        }
+        Some(packet)
+    }
+
+    /// MAC address of `ip`, soliciting it from `local` if it isn't known.
+    /// Other packets received while waiting are dropped.
+    pub fn resolve(
+        &mut self,
+        interface: &mut AcousticInterface,
+        local: Ipv6Addr,
+        ip: Ipv6Addr,
+        timeout: Duration,
+    ) -> Result<u8, String> {
+        if let Some(mac) = self.lookup(&ip, Instant::now()) {
+            return Ok(mac);
+        }
+        let solicitation =
+            neighbor_solicitation(local, interface.local_mac(), ip);
+        info!("NDP: who has {}? tell {}", ip, local);
+        interface.send_packet(&solicitation, BROADCAST, FrameType::Data)?;
+
+        let start = Instant::now();
+        while let Some(left) = timeout.checked_sub(start.elapsed()) {
+            let Ok((data, src)) = interface.receive_packet_from(Some(left))
+            else {
+                break;
+            };
+            let now = Instant::now();
+            if let Some(Ipv6Packet {
+                message:
+                    Icmpv6Message::NeighborAdvertisement {
+                        target,
+                        target_mac: Some(mac),
+                    },
+                ..
+            }) = self.observe(&data, src, now)
+                && target == ip
+            {
+                info!("NDP: {} is at {}", ip, mac);
+                return Ok(mac);
+            }
+            debug!("NDP: dropping packet while resolving {}", ip);
+        }
+        Err(format!("NDP: no advertisement from {}", ip))
+    }
+}
+
+/// What an IPv6 host at `local` does with one received packet: learn the
+/// sender, answer solicitations for `local` and echo requests to it
+pub fn serve_packet(
+    interface: &mut AcousticInterface,
+    neighbors: &mut NeighborTable,
+    local: Ipv6Addr,
+    data: &[u8],
+    src_mac: u8,
+) {
+    let now = Instant::now();
+    let Some(packet) = neighbors.observe(data, src_mac, now) else {
+        return;
+    };
+    let Some(reply) = answer(&packet, local, interface.local_mac()) else {
+        return;
+    };
+    let Some(dest_mac) = neighbors.lookup(&packet.source, now) else {
+        warn!("Unknown source {}, cannot reply", packet.source);
+        return;
+    };
+    let frame_type = match packet.message {
+        Icmpv6Message::EchoRequest { seq, .. } => {
+            info!("Echo Request seq={} from {}, replying", seq, packet.source);
+            FrameType::Ack
+        }
+        _ => {
+            debug!(
+                "NDP: telling {} that {} is at {}",
+                packet.source,
+                local,
+                interface.local_mac()
+            );
+            FrameType::Data
+        }
+    };
+    if let Err(e) = interface.send_packet(&reply, dest_mac, frame_type) {
+        warn!("Failed to send IPv6 reply: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::recorder::{AppShared, AppState};
+    use crate::mac::acoustic_interface::tests::{
+        SAMPLE_RATE, spawn_mock_channel, test_interface,
+    };
+    use crate::phy::LineCodingKind;
+    use etherparse::{IcmpEchoHeader, Icmpv6Header, Icmpv6Type};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_addresses_from_mac() {
+        assert_eq!(
+            link_local(2),
+            "fe80::200:ff:fe00:2"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+        assert_eq!(
+            solicited_node(link_local(0x2a)),
+            "ff02::1:ff00:2a"
+                .parse::<Ipv6Addr>()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_echo_checksum_matches_etherparse() {
+        let (src, dst) = (link_local(1), link_local(2));
+        // Odd payload length exercises the padding
+        let payload = [0x5a; 33];
+        let packet = echo_request(src, dst, 0x1234, 7, &payload);
+        assert_eq!(packet.len(), 40 + 8 + payload.len());
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(packet[6], 58);
+        assert_eq!(
+            crate::net::PayloadKind::of(&packet),
+            crate::net::PayloadKind::Ipv6
+        );
+
+        let expected = Icmpv6Header::with_checksum(
+            Icmpv6Type::EchoRequest(IcmpEchoHeader { id: 0x1234, seq: 7 }),
+            src.octets(),
+            dst.octets(),
+            &payload,
+        )
+        .unwrap();
+        assert_eq!(packet[40..48], expected.to_bytes()[..]);
+
+        let parsed = Ipv6Packet::parse(&packet).unwrap();
+        assert_eq!((parsed.source, parsed.destination), (src, dst));
+        assert_eq!(
+            parsed.message,
+            Icmpv6Message::EchoRequest {
+                identifier: 0x1234,
+                seq: 7,
+                payload: payload.to_vec(),
+            }
+        );
+
+        // A flipped bit fails the checksum
+        let mut damaged = packet.clone();
+        damaged[60] ^= 1;
+        assert!(Ipv6Packet::parse(&damaged).is_err());
+    }
+
+    #[test]
+    fn test_neighbor_discovery_messages() {
+        let (asker, owner) = (link_local(1), link_local(2));
+        let solicitation = neighbor_solicitation(asker, 1, owner);
+        let parsed = Ipv6Packet::parse(&solicitation).unwrap();
+        assert_eq!(parsed.destination, solicited_node(owner));
+        assert_eq!(parsed.hop_limit, 255);
+        assert_eq!(
+            parsed.message,
+            Icmpv6Message::NeighborSolicitation {
+                target: owner,
+                source_mac: Some(1),
+            }
+        );
+
+        // Only the owner answers
+        assert_eq!(answer(&parsed, link_local(3), 3), None);
+        let advertisement = answer(&parsed, owner, 2).unwrap();
+        let mut neighbors = NeighborTable::new();
+        let now = Instant::now();
+        let parsed = neighbors
+            .observe(&advertisement, 2, now)
+            .unwrap();
+        assert_eq!(parsed.destination, asker);
+        assert_eq!(advertisement[40 + 4], 0x60);
+        assert_eq!(
+            parsed.message,
+            Icmpv6Message::NeighborAdvertisement {
+                target: owner,
+                target_mac: Some(2),
+            }
+        );
+        assert_eq!(neighbors.lookup(&owner, now), Some(2));
+
+        // Entries age out
+        neighbors.set_ttl(Duration::from_secs(10));
+        let later = now + Duration::from_secs(11);
+        assert_eq!(neighbors.lookup(&owner, later), None);
+        neighbors.expire(later);
+        assert!(neighbors.table.is_empty());
+
+        // NDP that went through a router is refused
+        let mut forwarded = solicitation.clone();
+        forwarded[7] = 64;
+        assert!(Ipv6Packet::parse(&forwarded).is_err());
+    }
+
+    #[test]
+    fn test_ping6_over_loopback() {
+        let a = AppShared::new(SAMPLE_RATE as usize);
+        let b = AppShared::new(SAMPLE_RATE as usize);
+        let running = Arc::new(AtomicBool::new(true));
+        let channel =
+            spawn_mock_channel(vec![a.clone(), b.clone()], running.clone());
+
+        let kind = LineCodingKind::FourBFiveB;
+        let mut pinger = test_interface(a, kind, 1);
+        let mut host = test_interface(b.clone(), kind, 2);
+        b.app_state
+            .set(AppState::Recording);
+
+        // The host answers the solicitation, then the echo request
+        let server = std::thread::spawn(move || {
+            let mut neighbors = NeighborTable::new();
+            for _ in 0..2 {
+                let Ok((data, src)) =
+                    host.receive_packet_from(Some(Duration::from_secs(10)))
+                else {
+                    break;
+                };
+                serve_packet(
+                    &mut host,
+                    &mut neighbors,
+                    link_local(2),
+                    &data,
+                    src,
+                );
+            }
+        });
+
+        let mut neighbors = NeighborTable::new();
+        let mac = neighbors
+            .resolve(
+                &mut pinger,
+                link_local(1),
+                link_local(2),
+                Duration::from_secs(10),
+            )
+            .unwrap();
+        assert_eq!(mac, 2);
+        let request =
+            echo_request(link_local(1), link_local(2), 99, 1, b"ping6");
+        pinger
+            .send_packet(&request, mac, FrameType::Data)
+            .unwrap();
+        let (reply, src) = pinger
+            .receive_packet_from(Some(Duration::from_secs(10)))
+            .unwrap();
+        server.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+
+        assert_eq!(src, 2);
+        let reply = Ipv6Packet::parse(&reply).unwrap();
+        assert_eq!(reply.source, link_local(2));
+        assert_eq!(
+            reply.message,
+            Icmpv6Message::EchoReply {
+                identifier: 99,
+                seq: 1,
+            }
+        );
+    }
+}
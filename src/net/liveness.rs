@@ -0,0 +1,222 @@
+// Liveness of the router's next hops on the acoustic link
+//
+// The router counts failed sends to each acoustic node in a row. After
+// ROUTER_HOP_MAX_FAILURES of them the node is down: routes through it are
+// passed over for the next best one, or the packet is answered with Host
+// Unreachable. A down node gets a small unicast probe every
+// ROUTER_HOP_PROBE_INTERVAL_MS; a probe that goes out, or any frame heard
+// from the node, brings it back up.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::mac::types::{BROADCAST, MacAddr};
+use crate::utils::consts::{
+    ROUTER_HOP_MAX_FAILURES, ROUTER_HOP_PROBE_INTERVAL_MS,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopState {
+    Up,
+    Down,
+}
+
+impl HopState {
+    pub fn name(self) -> &'static str {
+        match self {
+            HopState::Up => "up",
+            HopState::Down => "down",
+        }
+    }
+}
+
+/// What is known about one next hop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hop {
+    pub state: HopState,
+    /// Failed sends since the last one that went out
+    pub failures: u32,
+    /// When it last went up or down
+    pub since: Instant,
+    last_probe: Option<Instant>,
+}
+
+/// Up/down state of each acoustic node the router sent to
+#[derive(Debug, Clone)]
+pub struct HopLiveness {
+    hops: HashMap<MacAddr, Hop>,
+    max_failures: u32,
+    probe_interval: Duration,
+}
+
+impl Default for HopLiveness {
+    fn default() -> Self {
+        Self::new(
+            ROUTER_HOP_MAX_FAILURES,
+            Duration::from_millis(ROUTER_HOP_PROBE_INTERVAL_MS),
+        )
+    }
+}
+
+impl HopLiveness {
+    pub fn new(max_failures: u32, probe_interval: Duration) -> Self {
+        Self {
+            hops: HashMap::new(),
+            max_failures,
+            probe_interval,
+        }
+    }
+
+    fn hop(&mut self, mac: MacAddr, now: Instant) -> &mut Hop {
+        self.hops
+            .entry(mac)
+            .or_insert(Hop {
+                state: HopState::Up,
+                failures: 0,
+                since: now,
+                last_probe: None,
+            })
+    }
+
+    /// Note how a send to `mac` went. Returns the new state if this changed
+    /// it.
+    pub fn record_send(
+        &mut self,
+        mac: MacAddr,
+        sent: bool,
+        now: Instant,
+    ) -> Option<HopState> {
+        if mac == BROADCAST {
+            return None;
+        }
+        let max_failures = self.max_failures;
+        let hop = self.hop(mac, now);
+        if sent {
+            hop.failures = 0;
+            return Self::set(hop, HopState::Up, now);
+        }
+        hop.failures += 1;
+        if hop.failures >= max_failures {
+            return Self::set(hop, HopState::Down, now);
+        }
+        None
+    }
+
+    /// A frame came in from `mac`, so it is alive. Returns the new state if
+    /// this changed it.
+    pub fn heard_from(
+        &mut self,
+        mac: MacAddr,
+        now: Instant,
+    ) -> Option<HopState> {
+        let hop = self.hops.get_mut(&mac)?;
+        hop.failures = 0;
+        Self::set(hop, HopState::Up, now)
+    }
+
+    fn set(hop: &mut Hop, state: HopState, now: Instant) -> Option<HopState> {
+        if hop.state == state {
+            return None;
+        }
+        hop.state = state;
+        hop.since = now;
+        hop.last_probe = None;
+        Some(state)
+    }
+
+    pub fn is_up(&self, mac: MacAddr) -> bool {
+        self.hops
+            .get(&mac)
+            .is_none_or(|hop| hop.state == HopState::Up)
+    }
+
+    pub fn all_up(&self) -> bool {
+        self.hops
+            .values()
+            .all(|hop| hop.state == HopState::Up)
+    }
+
+    /// Down nodes due a probe at `now`, marked as probed
+    pub fn due_probes(&mut self, now: Instant) -> Vec<MacAddr> {
+        let interval = self.probe_interval;
+        let mut due: Vec<_> = self
+            .hops
+            .iter_mut()
+            .filter(|(_, hop)| {
+                hop.state == HopState::Down
+                    && hop
+                        .last_probe
+                        .is_none_or(|at| {
+                            now.saturating_duration_since(at) >= interval
+                        })
+            })
+            .map(|(mac, hop)| {
+                hop.last_probe = Some(now);
+                *mac
+            })
+            .collect();
+        due.sort();
+        due
+    }
+
+    /// Every node sent to, lowest address first
+    pub fn hops(&self) -> Vec<(MacAddr, Hop)> {
+        let mut hops: Vec<_> = self
+            .hops
+            .iter()
+            .map(|(mac, hop)| (*mac, *hop))
+            .collect();
+        hops.sort_by_key(|(mac, _)| *mac);
+        hops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_down_after_failures_and_back_up() {
+        let start = Instant::now();
+        let mut hops = HopLiveness::new(3, Duration::from_secs(2));
+        assert!(hops.is_up(2));
+        assert_eq!(hops.record_send(2, false, start), None);
+        assert_eq!(hops.record_send(2, false, start), None);
+        // A send that goes out breaks the run
+        assert_eq!(hops.record_send(2, true, start), None);
+        for _ in 0..2 {
+            assert_eq!(hops.record_send(2, false, start), None);
+        }
+        assert_eq!(hops.record_send(2, false, start), Some(HopState::Down));
+        assert_eq!(hops.record_send(2, false, start), None);
+        assert!(!hops.is_up(2));
+        assert_eq!(hops.hops()[0].1.failures, 4);
+
+        // Probed once per interval while down
+        assert_eq!(hops.due_probes(start), vec![2]);
+        assert!(
+            hops.due_probes(start + Duration::from_secs(1))
+                .is_empty()
+        );
+        let later = start + Duration::from_secs(2);
+        assert_eq!(hops.due_probes(later), vec![2]);
+        assert_eq!(hops.record_send(2, true, later), Some(HopState::Up));
+        assert!(hops.is_up(2));
+        assert!(
+            hops.due_probes(later)
+                .is_empty()
+        );
+
+        // Hearing from a node also brings it back
+        for _ in 0..3 {
+            hops.record_send(3, false, later);
+        }
+        assert_eq!(hops.heard_from(3, later), Some(HopState::Up));
+        assert_eq!(hops.heard_from(4, later), None);
+        // Broadcasts say nothing about a node
+        for _ in 0..5 {
+            assert_eq!(hops.record_send(BROADCAST, false, later), None);
+        }
+        assert!(hops.all_up());
+    }
+}
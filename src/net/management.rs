@@ -0,0 +1,584 @@
+// Router management socket
+//
+// A localhost TCP server that lets an operator look into a running router
+// and change it without a restart. One JSON object per line each way:
+//
+//   -> {"command": "show arp"}
+//   <- {"ok": true, "result": [{"ip": "192.168.1.2", ...}, ...]}
+//   -> {"command": "add route", "arg": "10.5.0.0/16:192.168.2.2:wifi"}
+//   <- {"ok": false, "error": "Invalid next hop '192.168.2'"}
+//
+// Commands: show arp | routes | nat | stats | fw | gateway | hops, add route
+// <route>, add fw <rule>, del fw <index>, flush arp, resolve gateway. The
+// server works on the same Arc<RwLock<...>> tables as the router threads.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+use tracing::{debug, info, warn};
+
+use crate::mac::stats::LinkStats;
+use crate::net::counters::RouterCounters;
+use crate::net::firewall::{Firewall, FirewallRule};
+use crate::net::liveness::HopLiveness;
+use crate::net::nat::{NaptTable, NatTable};
+use crate::net::router::{
+    ArpTable, GatewayState, InterfaceType, RoutingTable, StaticRoute,
+};
+use crate::utils::consts::{ROUTER_STATS_INTERVAL_SECS, ROUTER_TOP_FLOWS};
+
+/// The router state the management socket reads and changes
+#[derive(Clone)]
+pub struct ManagedTables {
+    pub routes: Arc<RwLock<RoutingTable>>,
+    pub arp: Arc<RwLock<ArpTable>>,
+    pub nat: Arc<RwLock<NatTable>>,
+    pub napt: Arc<RwLock<NaptTable>>,
+    pub firewall: Arc<RwLock<Firewall>>,
+    /// Acoustic link counters, as published by the acoustic thread
+    pub link_stats: Arc<Mutex<LinkStats>>,
+    pub counters: Arc<RouterCounters>,
+    pub gateway_ip: Ipv4Addr,
+    pub gateway: Arc<Mutex<GatewayState>>,
+    /// Up/down state of the acoustic next hops
+    pub hops: Arc<Mutex<HopLiveness>>,
+}
+
+fn format_mac(mac: [u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn poisoned() -> String {
+    "Router table poisoned".to_string()
+}
+
+impl ManagedTables {
+    /// Answer one request line with one response line (no newline)
+    pub fn execute(&self, request: &str) -> String {
+        let response = match self.dispatch(request) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(error) => json!({ "ok": false, "error": error }),
+        };
+        response.to_string()
+    }
+
+    fn dispatch(&self, request: &str) -> Result<Value, String> {
+        let request: Value = serde_json::from_str(request)
+            .map_err(|e| format!("Invalid request: {}", e))?;
+        let command = request["command"]
+            .as_str()
+            .ok_or("Request without a command")?;
+        let arg = request["arg"].as_str();
+        let need_arg = || arg.ok_or(format!("'{}' needs an arg", command));
+
+        match command {
+            "show arp" => self.show_arp(),
+            "show routes" => self.show_routes(),
+            "show nat" => self.show_nat(),
+            "show stats" => self.show_stats(),
+            "show fw" => self.show_firewall(),
+            "show gateway" => self.show_gateway(),
+            "show hops" => self.show_hops(),
+            "add route" => {
+                let route: StaticRoute = need_arg()?.parse()?;
+                self.routes
+                    .write()
+                    .map_err(|_| poisoned())?
+                    .add_network(
+                        route.network,
+                        route.mask(),
+                        route.interface,
+                        route.next_hop,
+                    );
+                info!("Management: added route {}", route);
+                Ok(json!(route.to_string()))
+            }
+            "add fw" => {
+                let rule: FirewallRule = need_arg()?.parse()?;
+                let mut firewall = self
+                    .firewall
+                    .write()
+                    .map_err(|_| poisoned())?;
+                firewall.push(rule.clone());
+                info!("Management: added firewall rule {}", rule);
+                Ok(json!(firewall.rules().count() - 1))
+            }
+            "del fw" => {
+                let index = need_arg()?;
+                let rule = index
+                    .parse()
+                    .ok()
+                    .and_then(|index| {
+                        self.firewall
+                            .write()
+                            .ok()?
+                            .remove(index)
+                    })
+                    .ok_or(format!("No firewall rule '{}'", index))?;
+                info!("Management: removed firewall rule {}", rule);
+                Ok(json!(rule.to_string()))
+            }
+            "flush arp" => {
+                let flushed = self
+                    .arp
+                    .write()
+                    .map_err(|_| poisoned())?
+                    .flush();
+                info!("Management: flushed {} ARP entries", flushed);
+                Ok(json!(flushed))
+            }
+            "resolve gateway" => {
+                let mut gateway = self
+                    .gateway
+                    .lock()
+                    .map_err(|_| poisoned())?;
+                if let GatewayState::Static(_) = *gateway {
+                    return Err("The gateway MAC is static".to_string());
+                }
+                *gateway = GatewayState::RESOLVE;
+                info!("Management: resolving gateway {}", self.gateway_ip);
+                Ok(json!(gateway.name()))
+            }
+            _ => Err(format!("Unknown command '{}'", command)),
+        }
+    }
+
+    fn show_arp(&self) -> Result<Value, String> {
+        let now = Instant::now();
+        let arp = self
+            .arp
+            .read()
+            .map_err(|_| poisoned())?;
+        Ok(arp
+            .entries()
+            .into_iter()
+            .map(|(iface, ip, mac, learned)| {
+                json!({
+                    "interface": iface.name(),
+                    "ip": ip.to_string(),
+                    "mac": format_mac(mac),
+                    "age_secs": learned.map(|at| {
+                        now.saturating_duration_since(at).as_secs()
+                    }),
+                })
+            })
+            .collect())
+    }
+
+    fn show_routes(&self) -> Result<Value, String> {
+        let routes = self
+            .routes
+            .read()
+            .map_err(|_| poisoned())?;
+        Ok(routes
+            .list_routes()
+            .iter()
+            .map(|route| {
+                json!({
+                    "network": format!(
+                        "{}/{}",
+                        route.network.network, route.prefix_len
+                    ),
+                    "next_hop": route.next_hop.map(|ip| ip.to_string()),
+                    "interface": route.network.interface.name(),
+                })
+            })
+            .collect())
+    }
+
+    fn show_nat(&self) -> Result<Value, String> {
+        let now = Instant::now();
+        let echo: Vec<_> = self
+            .nat
+            .read()
+            .map_err(|_| poisoned())?
+            .echo_sessions()
+            .into_iter()
+            .map(|(id, ip)| {
+                json!({
+                    "identifier": id,
+                    "inside": ip.to_string(),
+                })
+            })
+            .collect();
+        let flows: Vec<_> = self
+            .napt
+            .read()
+            .map_err(|_| poisoned())?
+            .flows()
+            .into_iter()
+            .map(|(flow, port, tcp, last_used)| {
+                json!({
+                    "protocol": flow.protocol.0,
+                    "inside": flow.internal.to_string(),
+                    "remote": flow.remote.to_string(),
+                    "external_port": port,
+                    "state": tcp.map(|state| state.name()),
+                    "idle_secs": now
+                        .saturating_duration_since(last_used)
+                        .as_secs(),
+                })
+            })
+            .collect();
+        Ok(json!({ "echo": echo, "flows": flows }))
+    }
+
+    fn show_stats(&self) -> Result<Value, String> {
+        let stats = self
+            .link_stats
+            .lock()
+            .map_err(|_| poisoned())?
+            .clone();
+        let arp_entries = self
+            .arp
+            .read()
+            .map_err(|_| poisoned())?
+            .entries()
+            .len();
+        let flows = self
+            .napt
+            .read()
+            .map_err(|_| poisoned())?
+            .flows()
+            .len();
+        let interfaces: serde_json::Map<_, _> = [
+            InterfaceType::Acoustic,
+            InterfaceType::WiFi,
+            InterfaceType::Ethernet,
+            InterfaceType::Tun,
+        ]
+        .into_iter()
+        .map(|iface| {
+            let counters = self
+                .counters
+                .interface(iface)
+                .snapshot();
+            (iface.name().to_string(), json!(counters))
+        })
+        .collect();
+        Ok(json!({
+            "interfaces": interfaces,
+            "top_flows": self.counters.top_flows(ROUTER_TOP_FLOWS),
+            "acoustic": stats,
+            "acoustic_reset_every_secs": ROUTER_STATS_INTERVAL_SECS,
+            "arp_entries": arp_entries,
+            "napt_flows": flows,
+        }))
+    }
+
+    fn show_gateway(&self) -> Result<Value, String> {
+        let gateway = *self
+            .gateway
+            .lock()
+            .map_err(|_| poisoned())?;
+        Ok(json!({
+            "ip": self.gateway_ip.to_string(),
+            "state": gateway.name(),
+            "mac": gateway.mac().map(format_mac),
+        }))
+    }
+
+    fn show_hops(&self) -> Result<Value, String> {
+        let hops = self
+            .hops
+            .lock()
+            .map_err(|_| poisoned())?
+            .hops();
+        let arp = self
+            .arp
+            .read()
+            .map_err(|_| poisoned())?
+            .entries();
+        Ok(hops
+            .into_iter()
+            .map(|(mac, hop)| {
+                let ip = arp
+                    .iter()
+                    .find(|(iface, _, entry_mac, _)| {
+                        *iface == InterfaceType::Acoustic && entry_mac[5] == mac
+                    })
+                    .map(|(_, ip, _, _)| ip.to_string());
+                json!({
+                    "mac": mac,
+                    "ip": ip,
+                    "state": hop.state.name(),
+                    "failures": hop.failures,
+                    "since_secs": hop.since.elapsed().as_secs(),
+                })
+            })
+            .collect())
+    }
+
+    fn show_firewall(&self) -> Result<Value, String> {
+        let firewall = self
+            .firewall
+            .read()
+            .map_err(|_| poisoned())?;
+        Ok(firewall
+            .rules()
+            .enumerate()
+            .map(|(index, (rule, hits))| {
+                json!({
+                    "index": index,
+                    "rule": rule.to_string(),
+                    "hits": hits,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Serve management connections on `listener` as long as `running`
+/// says so, each on its own thread
+pub fn serve(
+    listener: TcpListener,
+    tables: ManagedTables,
+    running: impl Fn() -> bool,
+) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        warn!("Management socket unusable: {}", e);
+        return;
+    }
+    while running() {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                debug!("Management connection from {}", peer);
+                let tables = tables.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &tables) {
+                        debug!("Management connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => warn!("Management accept failed: {}", e),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    tables: &ManagedTables,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(writer, "{}", tables.execute(&line))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn tables() -> ManagedTables {
+        let mut routes = RoutingTable::new();
+        routes.add_direct_network(
+            "192.168.1.0".parse().unwrap(),
+            "255.255.255.0"
+                .parse()
+                .unwrap(),
+            InterfaceType::Acoustic,
+        );
+        let mut arp = ArpTable::new();
+        arp.update(
+            "192.168.2.2".parse().unwrap(),
+            [0x02, 0, 0, 0, 0, 0x22],
+            InterfaceType::WiFi,
+            Instant::now(),
+        );
+        let nat = NatTable::new();
+        nat.register_echo_request(77, "192.168.1.2".parse().unwrap());
+        let mut hops = HopLiveness::new(1, Duration::from_secs(2));
+        hops.record_send(2, false, Instant::now());
+        ManagedTables {
+            routes: Arc::new(RwLock::new(routes)),
+            arp: Arc::new(RwLock::new(arp)),
+            nat: Arc::new(RwLock::new(nat)),
+            napt: Arc::new(RwLock::new(NaptTable::new())),
+            firewall: Arc::new(RwLock::new(Firewall::default())),
+            link_stats: Arc::new(Mutex::new(LinkStats {
+                frames_sent: 12,
+                ..LinkStats::default()
+            })),
+            counters: Arc::new(RouterCounters::new(8)),
+            gateway_ip: "10.20.0.254".parse().unwrap(),
+            gateway: Arc::new(Mutex::new(GatewayState::Failed)),
+            hops: Arc::new(Mutex::new(hops)),
+        }
+    }
+
+    /// Sends each request over one connection, returns the parsed answers
+    fn ask(tables: ManagedTables, requests: &[Value]) -> Vec<Value> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+        let server = {
+            let running = running.clone();
+            thread::spawn(move || {
+                serve(listener, tables, || running.load(Ordering::SeqCst))
+            })
+        };
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let answers = requests
+            .iter()
+            .map(|request| {
+                writeln!(writer, "{}", request).unwrap();
+                let mut line = String::new();
+                reader
+                    .read_line(&mut line)
+                    .unwrap();
+                serde_json::from_str(&line).unwrap()
+            })
+            .collect();
+
+        running.store(false, Ordering::SeqCst);
+        server.join().unwrap();
+        answers
+    }
+
+    #[test]
+    fn test_show_commands() {
+        let answers = ask(
+            tables(),
+            &[
+                json!({ "command": "show arp" }),
+                json!({ "command": "show routes" }),
+                json!({ "command": "show nat" }),
+                json!({ "command": "show stats" }),
+                json!({ "command": "show gateway" }),
+                json!({ "command": "show hops" }),
+            ],
+        );
+        assert!(
+            answers
+                .iter()
+                .all(|a| a["ok"] == true),
+            "{:?}",
+            answers
+        );
+
+        let arp = answers[0]["result"]
+            .as_array()
+            .unwrap();
+        // Three static acoustic hosts, one learned WiFi one
+        assert_eq!(arp.len(), 4);
+        assert_eq!(arp[0]["interface"], "acoustic");
+        assert_eq!(arp[0]["age_secs"], Value::Null);
+        assert_eq!(arp[3]["ip"], "192.168.2.2");
+        assert_eq!(arp[3]["mac"], "02:00:00:00:00:22");
+        assert_eq!(arp[3]["age_secs"], 0);
+
+        assert_eq!(
+            answers[1]["result"],
+            json!([{
+                "network": "192.168.1.0/24",
+                "next_hop": null,
+                "interface": "acoustic",
+            }])
+        );
+        assert_eq!(
+            answers[2]["result"]["echo"],
+            json!([{ "identifier": 77, "inside": "192.168.1.2" }])
+        );
+        assert_eq!(answers[3]["result"]["acoustic"]["frames_sent"], 12);
+        assert_eq!(answers[3]["result"]["interfaces"]["eth"]["rx_packets"], 0);
+        assert_eq!(answers[3]["result"]["arp_entries"], 4);
+        assert_eq!(
+            answers[4]["result"],
+            json!({ "ip": "10.20.0.254", "state": "failed", "mac": null })
+        );
+        let hops = &answers[5]["result"][0];
+        assert_eq!(
+            (&hops["mac"], &hops["ip"]),
+            (&json!(2), &json!("192.168.1.2"))
+        );
+        assert_eq!(
+            (&hops["state"], &hops["failures"]),
+            (&json!("down"), &json!(1))
+        );
+    }
+
+    #[test]
+    fn test_changes_go_to_the_shared_tables() {
+        let tables = tables();
+        let answers = ask(
+            tables.clone(),
+            &[
+                json!({
+                    "command": "add route",
+                    "arg": "10.5.0.0/16:192.168.2.2:wifi",
+                }),
+                json!({ "command": "add fw", "arg": "deny proto=udp port=53" }),
+                json!({ "command": "show fw" }),
+                json!({ "command": "del fw", "arg": "3" }),
+                json!({ "command": "flush arp" }),
+                json!({ "command": "resolve gateway" }),
+                json!({ "command": "add route", "arg": "10.5.0.0/16" }),
+                json!({ "command": "show everything" }),
+                json!("show arp"),
+            ],
+        );
+        assert_eq!(answers[0]["result"], "10.5.0.0/16:192.168.2.2:wifi");
+        assert_eq!(answers[1]["result"], 0);
+        assert_eq!(
+            answers[2]["result"],
+            json!([{ "index": 0, "rule": "deny proto=udp port=53", "hits": 0 }])
+        );
+        assert_eq!(answers[3]["ok"], false);
+        assert_eq!(answers[4]["result"], 1);
+        assert_eq!(answers[5]["result"], "resolving");
+        for answer in &answers[6..] {
+            assert_eq!(answer["ok"], false);
+            assert!(answer["error"].is_string());
+        }
+
+        assert_eq!(*tables.gateway.lock().unwrap(), GatewayState::RESOLVE);
+        let target: Ipv4Addr = "10.5.3.4".parse().unwrap();
+        assert_eq!(
+            tables
+                .routes
+                .read()
+                .unwrap()
+                .lookup(&target),
+            Some((Some("192.168.2.2".parse().unwrap()), InterfaceType::WiFi))
+        );
+        assert_eq!(
+            tables
+                .firewall
+                .read()
+                .unwrap()
+                .rules()
+                .count(),
+            1
+        );
+        // Static entries survive a flush
+        assert_eq!(
+            tables
+                .arp
+                .read()
+                .unwrap()
+                .entries()
+                .len(),
+            3
+        );
+    }
+}
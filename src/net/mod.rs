@@ -1,12 +1,50 @@
 pub mod arp;
+pub mod counters;
+pub mod firewall;
 pub mod fragmentation;
 pub mod icmp;
 pub mod ip;
+pub mod ipv6;
+pub mod liveness;
+pub mod management;
 pub mod nat;
 pub mod pcap_utils;
 pub mod router;
+pub mod tap;
+pub mod tcp;
 pub mod tool;
 pub mod tun;
+pub mod udp;
+
+/// What a packet received over the acoustic link carries, told apart by
+/// its first bytes like an ethertype
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    Arp,
+    Ipv4,
+    Ipv6,
+    Unknown,
+}
+
+impl PayloadKind {
+    pub fn of(packet: &[u8]) -> Self {
+        if arp::ArpPacket::is_arp(packet) {
+            PayloadKind::Arp
+        } else if packet
+            .first()
+            .is_some_and(|b| b >> 4 == 4)
+        {
+            PayloadKind::Ipv4
+        } else if packet
+            .first()
+            .is_some_and(|b| b >> 4 == 6)
+        {
+            PayloadKind::Ipv6
+        } else {
+            PayloadKind::Unknown
+        }
+    }
+}
 
 pub enum Protocol {
     Icmp = 1,
@@ -1,6 +1,14 @@
+use etherparse::{IpNumber, TcpHeaderSlice};
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::utils::consts::{
+    NAPT_PORT_FIRST, NAPT_PORT_LAST, NAPT_TCP_IDLE_SECS,
+    NAPT_TCP_TRANSITORY_SECS, NAPT_UDP_IDLE_SECS,
+};
 
 /// NAT Table for tracking ICMP Echo requests
 #[derive(Debug, Clone)]
@@ -44,4 +52,473 @@ impl NatTable {
         let set = self.dnat_ids.lock().unwrap();
         set.contains(&identifier)
     }
+
+    /// Echo sessions as (identifier, inside host), by identifier
+    pub fn echo_sessions(&self) -> Vec<(u16, Ipv4Addr)> {
+        let map = self.icmp_map.lock().unwrap();
+        let mut sessions: Vec<_> = map
+            .iter()
+            .map(|(id, ip)| (*id, *ip))
+            .collect();
+        sessions.sort();
+        sessions
+    }
+}
+
+/// One TCP/UDP flow through the NAPT, as seen from the inside
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flow {
+    pub protocol: IpNumber,
+    pub internal: SocketAddrV4,
+    pub remote: SocketAddrV4,
+}
+
+/// The TCP flags conntrack cares about
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpFlags {
+    pub syn: bool,
+    pub ack: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+impl TcpFlags {
+    /// Flags of the TCP segment `l4`, None for other protocols or a
+    /// truncated header
+    pub fn of(protocol: IpNumber, l4: &[u8]) -> Option<Self> {
+        if protocol != IpNumber::TCP {
+            return None;
+        }
+        TcpHeaderSlice::from_slice(l4)
+            .ok()
+            .map(|tcp| Self {
+                syn: tcp.syn(),
+                ack: tcp.ack(),
+                fin: tcp.fin(),
+                rst: tcp.rst(),
+            })
+    }
+
+    /// First segment of a handshake
+    fn is_syn(self) -> bool {
+        self.syn && !self.ack && !self.rst
+    }
+}
+
+/// Where a translated TCP connection is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    /// SYN sent, no answer yet
+    New,
+    Established,
+    /// FIN seen, from the inside and/or the outside
+    FinWait {
+        fin_out: bool,
+        fin_in: bool,
+    },
+    /// Reset, or both FINs acknowledged: dropped right away
+    Closed,
+}
+
+impl TcpState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TcpState::New => "NEW",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait { .. } => "FIN_WAIT",
+            TcpState::Closed => "CLOSED",
+        }
+    }
+
+    /// State after a segment with `flags`, `outbound` if it left the inside
+    fn next(self, flags: TcpFlags, outbound: bool) -> Self {
+        if flags.rst {
+            return TcpState::Closed;
+        }
+        match self {
+            TcpState::New if !outbound && flags.syn && flags.ack => {
+                TcpState::Established
+            }
+            TcpState::New | TcpState::Established if flags.fin => {
+                TcpState::FinWait {
+                    fin_out: outbound,
+                    fin_in: !outbound,
+                }
+            }
+            TcpState::FinWait { fin_out, fin_in } => {
+                if fin_out && fin_in && flags.ack && !flags.fin {
+                    // The last FIN got its ACK
+                    TcpState::Closed
+                } else {
+                    TcpState::FinWait {
+                        fin_out: fin_out || (outbound && flags.fin),
+                        fin_in: fin_in || (!outbound && flags.fin),
+                    }
+                }
+            }
+            state => state,
+        }
+    }
+
+    /// Idle time after which a connection in this state is forgotten
+    fn idle_timeout(self) -> Duration {
+        match self {
+            TcpState::Established => Duration::from_secs(NAPT_TCP_IDLE_SECS),
+            TcpState::New | TcpState::FinWait { .. } => {
+                Duration::from_secs(NAPT_TCP_TRANSITORY_SECS)
+            }
+            TcpState::Closed => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FlowEntry {
+    last_used: Instant,
+    /// Connection state, None for UDP
+    tcp: Option<TcpState>,
+}
+
+impl FlowEntry {
+    fn idle_timeout(&self) -> Duration {
+        match self.tcp {
+            Some(state) => state.idle_timeout(),
+            None => Duration::from_secs(NAPT_UDP_IDLE_SECS),
+        }
+    }
+}
+
+/// Port-translating NAT for TCP and UDP
+///
+/// The first flow out of an internal (IP, port) gets an external port from
+/// the NAPT_PORT_FIRST..=NAPT_PORT_LAST pool; later flows from the same
+/// endpoint reuse it, so two hosts behind the router can pick the same
+/// source port without stealing each other's traffic. Replies are only let
+/// in for flows the inside opened. Idle flows expire, and a port goes back
+/// to the pool with the last flow using it.
+///
+/// TCP flows are tracked: only an outbound SYN opens one, and a reset or a
+/// completed FIN exchange removes it at once. Half-open and closing
+/// connections time out sooner than established ones.
+#[derive(Debug, Clone)]
+pub struct NaptTable {
+    flows: HashMap<Flow, FlowEntry>,
+    /// (protocol, internal endpoint) -> external port
+    mappings: HashMap<(IpNumber, SocketAddrV4), u16>,
+    /// (protocol, external port) -> internal endpoint
+    ports: HashMap<(IpNumber, u16), SocketAddrV4>,
+    next_port: u16,
+}
+
+impl Default for NaptTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NaptTable {
+    pub fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+            next_port: NAPT_PORT_FIRST,
+        }
+    }
+
+    /// External port for a packet of `flow` leaving at `now`, allocating
+    /// one for a new internal endpoint. `tcp` carries the segment's flags
+    /// for TCP flows, which only a SYN can open.
+    pub fn outbound(
+        &mut self,
+        flow: Flow,
+        tcp: Option<TcpFlags>,
+        now: Instant,
+    ) -> Result<u16, String> {
+        if tcp.is_some_and(|flags| !flags.is_syn())
+            && !self.flows.contains_key(&flow)
+        {
+            return Err(format!(
+                "No TCP session for {} -> {}",
+                flow.internal, flow.remote
+            ));
+        }
+        self.flows
+            .entry(flow)
+            .or_insert_with(|| FlowEntry {
+                last_used: now,
+                tcp: tcp.map(|_| TcpState::New),
+            });
+
+        let key = (flow.protocol, flow.internal);
+        let port = match self.mappings.get(&key) {
+            Some(&port) => port,
+            None => {
+                let Some(port) = self.allocate(flow.protocol) else {
+                    self.flows.remove(&flow);
+                    return Err(format!(
+                        "No external port left for {}",
+                        flow.internal
+                    ));
+                };
+                self.mappings
+                    .insert(key, port);
+                self.ports
+                    .insert((flow.protocol, port), flow.internal);
+                port
+            }
+        };
+        self.track(flow, tcp, true, now);
+        Ok(port)
+    }
+
+    /// Internal endpoint for a packet from `remote` to external `port`,
+    /// if the inside opened that flow
+    pub fn inbound(
+        &mut self,
+        protocol: IpNumber,
+        port: u16,
+        remote: SocketAddrV4,
+        tcp: Option<TcpFlags>,
+        now: Instant,
+    ) -> Option<SocketAddrV4> {
+        let internal = *self
+            .ports
+            .get(&(protocol, port))?;
+        let flow = Flow {
+            protocol,
+            internal,
+            remote,
+        };
+        if !self.flows.contains_key(&flow) {
+            return None;
+        }
+        self.track(flow, tcp, false, now);
+        Some(internal)
+    }
+
+    /// Forget flows idle for longer than their timeout, and the ports
+    /// nothing uses any more. Returns how many flows went.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.flows.len();
+        self.flows.retain(|_, entry| {
+            now.saturating_duration_since(entry.last_used)
+                <= entry.idle_timeout()
+        });
+        self.release_ports();
+        before - self.flows.len()
+    }
+
+    /// Every flow with its external port, TCP state (None for UDP) and
+    /// when it was last used
+    pub fn flows(&self) -> Vec<(Flow, u16, Option<TcpState>, Instant)> {
+        let mut flows: Vec<_> = self
+            .flows
+            .iter()
+            .filter_map(|(flow, entry)| {
+                let port = self
+                    .mappings
+                    .get(&(flow.protocol, flow.internal))?;
+                Some((*flow, *port, entry.tcp, entry.last_used))
+            })
+            .collect();
+        flows.sort_by_key(|(flow, port, _, _)| (flow.protocol.0, *port));
+        flows
+    }
+
+    /// Connection state of a TCP flow, None if it isn't tracked
+    #[cfg(test)]
+    pub(crate) fn tcp_state(&self, flow: &Flow) -> Option<TcpState> {
+        self.flows
+            .get(flow)
+            .and_then(|entry| entry.tcp)
+    }
+
+    /// Note a packet of a known `flow`, dropping it once the connection
+    /// is closed
+    fn track(
+        &mut self,
+        flow: Flow,
+        tcp: Option<TcpFlags>,
+        outbound: bool,
+        now: Instant,
+    ) {
+        let Some(entry) = self.flows.get_mut(&flow) else {
+            return;
+        };
+        entry.last_used = now;
+        if let (Some(state), Some(flags)) = (entry.tcp, tcp) {
+            let next = state.next(flags, outbound);
+            if next != state {
+                debug!(
+                    "NAPT: {} -> {} {} -> {}",
+                    flow.internal,
+                    flow.remote,
+                    state.name(),
+                    next.name()
+                );
+            }
+            entry.tcp = Some(next);
+            if next == TcpState::Closed {
+                self.flows.remove(&flow);
+                self.release_ports();
+            }
+        }
+    }
+
+    /// Give back the external ports of endpoints without flows
+    fn release_ports(&mut self) {
+        let flows = &self.flows;
+        let ports = &mut self.ports;
+        self.mappings
+            .retain(|&(protocol, internal), port| {
+                let used = flows
+                    .keys()
+                    .any(|f| f.protocol == protocol && f.internal == internal);
+                if !used {
+                    ports.remove(&(protocol, *port));
+                }
+                used
+            });
+    }
+
+    /// Next free port of the pool for `protocol`, round robin
+    fn allocate(&mut self, protocol: IpNumber) -> Option<u16> {
+        let pool = (NAPT_PORT_LAST - NAPT_PORT_FIRST) as u32 + 1;
+        for _ in 0..pool {
+            let port = self.next_port;
+            self.next_port = if port == NAPT_PORT_LAST {
+                NAPT_PORT_FIRST
+            } else {
+                port + 1
+            };
+            if !self
+                .ports
+                .contains_key(&(protocol, port))
+            {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(internal: &str, remote: &str) -> Flow {
+        Flow {
+            protocol: IpNumber::UDP,
+            internal: internal.parse().unwrap(),
+            remote: remote.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_same_source_port_gets_own_external_port() {
+        let mut napt = NaptTable::new();
+        let now = Instant::now();
+        let server: SocketAddrV4 = "10.0.0.5:9000"
+            .parse()
+            .unwrap();
+        let a = napt
+            .outbound(flow("192.168.1.2:5000", "10.0.0.5:9000"), None, now)
+            .unwrap();
+        let b = napt
+            .outbound(flow("192.168.1.3:5000", "10.0.0.5:9000"), None, now)
+            .unwrap();
+        assert_ne!(a, b);
+        // Same endpoint, another server: the mapping is reused
+        let a2 = napt
+            .outbound(flow("192.168.1.2:5000", "10.0.0.6:80"), None, now)
+            .unwrap();
+        assert_eq!(a, a2);
+
+        assert_eq!(
+            napt.inbound(IpNumber::UDP, a, server, None, now),
+            Some(
+                "192.168.1.2:5000"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            napt.inbound(IpNumber::UDP, b, server, None, now),
+            Some(
+                "192.168.1.3:5000"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        // Nobody inside talked to this one, or over TCP
+        assert_eq!(
+            napt.inbound(
+                IpNumber::UDP,
+                a,
+                "10.0.0.7:9000"
+                    .parse()
+                    .unwrap(),
+                None,
+                now
+            ),
+            None
+        );
+        assert_eq!(napt.inbound(IpNumber::TCP, a, server, None, now), None);
+    }
+
+    #[test]
+    fn test_idle_flows_expire() {
+        let mut napt = NaptTable::new();
+        let start = Instant::now();
+        let server: SocketAddrV4 = "10.0.0.5:9000"
+            .parse()
+            .unwrap();
+        let port = napt
+            .outbound(flow("192.168.1.2:5000", "10.0.0.5:9000"), None, start)
+            .unwrap();
+        let idle = Duration::from_secs(NAPT_UDP_IDLE_SECS);
+
+        // Traffic in either direction keeps the flow alive
+        let later = start + idle / 2;
+        assert!(
+            napt.inbound(IpNumber::UDP, port, server, None, later)
+                .is_some()
+        );
+        assert_eq!(napt.expire(start + idle + Duration::from_secs(1)), 0);
+        assert_eq!(napt.expire(later + idle + Duration::from_secs(1)), 1);
+        assert_eq!(napt.inbound(IpNumber::UDP, port, server, None, later), None);
+        // The endpoint's port went back to the pool
+        assert!(napt.mappings.is_empty() && napt.ports.is_empty());
+    }
+
+    #[test]
+    fn test_half_open_tcp_expires_early() {
+        let mut napt = NaptTable::new();
+        let start = Instant::now();
+        let flow = Flow {
+            protocol: IpNumber::TCP,
+            ..flow("192.168.1.2:40000", "10.0.0.5:80")
+        };
+        let syn = TcpFlags {
+            syn: true,
+            ..TcpFlags::default()
+        };
+        let ack = TcpFlags {
+            ack: true,
+            ..TcpFlags::default()
+        };
+        // Only a SYN opens a connection
+        assert!(
+            napt.outbound(flow, Some(ack), start)
+                .is_err()
+        );
+        napt.outbound(flow, Some(syn), start)
+            .unwrap();
+        assert_eq!(napt.tcp_state(&flow), Some(TcpState::New));
+
+        let transitory = Duration::from_secs(NAPT_TCP_TRANSITORY_SECS);
+        assert_eq!(napt.expire(start + transitory * 2), 1);
+        assert!(napt.flows.is_empty() && napt.ports.is_empty());
+    }
 }
@@ -1,5 +1,9 @@
 use pcap::{Active, Capture, Device};
 use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::info;
 
 pub fn list_devices() -> Result<Vec<Device>, Box<dyn Error>> {
@@ -62,3 +66,201 @@ pub fn next_packet<'a>(
         Err(e) => Err(Box::new(e)),
     }
 }
+
+// Classic pcap file writer
+//
+// Records raw IPv4 packets (LINKTYPE_RAW) so Wireshark or tcpdump can read
+// what went over the acoustic link. Little-endian, microsecond timestamps:
+//
+//   File header:   [Magic:4] [Major:2] [Minor:2] [ThisZone:4] [SigFigs:4]
+//                  [SnapLen:4] [LinkType:4]
+//   Record header: [TsSec:4] [TsUsec:4] [InclLen:4] [OrigLen:4] [Packet]
+//
+// With a rotation size, a file that would grow past it is closed and the
+// next one started: capture.pcap, capture.1.pcap, capture.2.pcap, ...
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+/// Packets start straight at the IP header
+pub const LINKTYPE_RAW: u32 = 101;
+pub const PCAP_HEADER_BYTES: usize = 24;
+pub const PCAP_RECORD_HEADER_BYTES: usize = 16;
+
+pub struct PcapWriter {
+    base: PathBuf,
+    rotate_bytes: Option<u64>,
+    index: u32,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl PcapWriter {
+    /// Start a capture at `path`, rotating files at `rotate_bytes` if set
+    pub fn create(
+        path: impl AsRef<Path>,
+        rotate_bytes: Option<u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let base = path.as_ref().to_path_buf();
+        let file = Self::open(&base)?;
+        info!("Capturing packets to {}", base.display());
+        Ok(Self {
+            base,
+            rotate_bytes,
+            index: 0,
+            file,
+            written: PCAP_HEADER_BYTES as u64,
+        })
+    }
+
+    fn open(path: &Path) -> Result<BufWriter<File>, Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        // Timestamps are UTC, no accuracy claimed
+        file.write_all(&0i32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        file.flush()?;
+        Ok(file)
+    }
+
+    /// File the next packet goes to
+    pub fn path(&self) -> PathBuf {
+        rotated_path(&self.base, self.index)
+    }
+
+    /// Append one packet, captured at `timestamp`. Flushed right away so
+    /// the file stays readable while the capture runs.
+    pub fn write_packet(
+        &mut self,
+        timestamp: SystemTime,
+        packet: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let incl_len = packet
+            .len()
+            .min(PCAP_SNAPLEN as usize);
+        let record_len = (PCAP_RECORD_HEADER_BYTES + incl_len) as u64;
+        if let Some(limit) = self.rotate_bytes
+            && self.written > PCAP_HEADER_BYTES as u64
+            && self.written + record_len > limit
+        {
+            self.index += 1;
+            self.file = Self::open(&self.path())?;
+            self.written = PCAP_HEADER_BYTES as u64;
+            info!("Capture rotated to {}", self.path().display());
+        }
+
+        let since_epoch = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.file
+            .write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(
+            &since_epoch
+                .subsec_micros()
+                .to_le_bytes(),
+        )?;
+        self.file
+            .write_all(&(incl_len as u32).to_le_bytes())?;
+        self.file
+            .write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.file
+            .write_all(&packet[..incl_len])?;
+        self.file.flush()?;
+        self.written += record_len;
+        Ok(())
+    }
+}
+
+/// Name of the `index`-th file of a rotated capture, `base` itself first
+pub fn rotated_path(base: &Path, index: u32) -> PathBuf {
+    if index == 0 {
+        return base.to_path_buf();
+    }
+    let stem = base
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let name = match base.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, index, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, index),
+    };
+    base.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "trackmaker-{}-{}.pcap",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_header_and_records_byte_for_byte() {
+        let path = temp_path("capture");
+        let mut writer = PcapWriter::create(&path, None).unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        writer
+            .write_packet(t0, &[0x45, 0, 0, 20])
+            .unwrap();
+        writer
+            .write_packet(t0 + Duration::from_micros(900_000), &[1, 2])
+            .unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected: Vec<u8> = [
+            // File header
+            &[0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0][..],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[0xff, 0xff, 0, 0, 101, 0, 0, 0],
+            // 1700000000 s, 123456 us, 4 of 4 bytes
+            &[0x00, 0xf1, 0x53, 0x65, 0x40, 0xe2, 0x01, 0x00],
+            &[4, 0, 0, 0, 4, 0, 0, 0],
+            &[0x45, 0, 0, 20],
+            // 1700000001 s, 23456 us, 2 of 2 bytes
+            &[0x01, 0xf1, 0x53, 0x65, 0xa0, 0x5b, 0x00, 0x00],
+            &[2, 0, 0, 0, 2, 0, 0, 0],
+            &[1, 2],
+        ]
+        .concat();
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_rotation_by_size() {
+        let path = temp_path("rotate");
+        // Header plus two 40-byte records per file
+        let limit = (PCAP_HEADER_BYTES + 2 * 40) as u64;
+        let mut writer = PcapWriter::create(&path, Some(limit)).unwrap();
+        for i in 0..5u8 {
+            writer
+                .write_packet(SystemTime::now(), &[i; 24])
+                .unwrap();
+        }
+        assert_eq!(writer.path(), rotated_path(&path, 2));
+
+        let sizes: Vec<_> = (0..3)
+            .map(|i| {
+                let file = rotated_path(&path, i);
+                let bytes = std::fs::read(&file).unwrap();
+                std::fs::remove_file(&file).unwrap();
+                assert_eq!(bytes[..4], PCAP_MAGIC.to_le_bytes());
+                bytes.len()
+            })
+            .collect();
+        assert_eq!(sizes, vec![104, 104, 64]);
+        assert_eq!(
+            rotated_path(Path::new("/tmp/run.pcap"), 3),
+            Path::new("/tmp/run.3.pcap")
+        );
+    }
+}
@@ -10,18 +10,30 @@ use etherparse::{
 };
 use pcap::{Active, Capture, Device, Linktype};
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock}; // Added RwLock for better read concurrency
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, trace, warn};
 
 use crate::audio::recorder::AppShared;
 use crate::mac::acoustic_interface::AcousticInterface;
+use crate::mac::params::MacParams;
+use crate::mac::stats::LinkStats;
+use crate::net::counters::{DropReason, RouterCounters};
+use crate::net::firewall::{
+    Firewall, FirewallAction, FirewallRule, PacketSummary,
+};
+use crate::net::fragmentation::{IpFragmenter, IpReassembler};
 use crate::net::icmp::{self, IcmpPacket, IcmpType};
-use crate::net::nat::NatTable;
+use crate::net::liveness::{HopLiveness, HopState};
+use crate::net::management::{self, ManagedTables};
+use crate::net::nat::{Flow, NaptTable, NatTable, TcpFlags};
+use crate::phy::params::PhyParams;
 use crate::phy::{FrameType, LineCodingKind};
+use crate::ui::dashboard::DashboardEvent;
+use crate::utils::metrics;
 
 /// Network interface type
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -36,12 +48,211 @@ pub enum InterfaceType {
     Tun,
 }
 
+impl InterfaceType {
+    pub fn name(self) -> &'static str {
+        match self {
+            InterfaceType::Acoustic => "acoustic",
+            InterfaceType::WiFi => "wifi",
+            InterfaceType::Ethernet => "eth",
+            InterfaceType::Tun => "tun",
+        }
+    }
+}
+
+impl std::str::FromStr for InterfaceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "acoustic" => Ok(InterfaceType::Acoustic),
+            "wifi" | "wlan" => Ok(InterfaceType::WiFi),
+            "eth" | "ethernet" => Ok(InterfaceType::Ethernet),
+            "tun" => Ok(InterfaceType::Tun),
+            _ => Err(format!(
+                "Unknown interface '{}' (acoustic, wifi, eth or tun)",
+                s
+            )),
+        }
+    }
+}
+
+/// Route given on the command line: `net/prefix:nexthop:iface`, e.g.
+/// `10.5.0.0/16:192.168.2.2:wifi`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticRoute {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    pub next_hop: Ipv4Addr,
+    pub interface: InterfaceType,
+}
+
+impl StaticRoute {
+    /// 0.0.0.0/0 via `next_hop`
+    pub fn default_via(next_hop: Ipv4Addr, interface: InterfaceType) -> Self {
+        Self {
+            network: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            next_hop,
+            interface,
+        }
+    }
+
+    /// Parse a default route, `nexthop:iface`
+    pub fn parse_default(s: &str) -> Result<Self, String> {
+        let (next_hop, iface) = s
+            .split_once(':')
+            .ok_or_else(|| {
+                format!("Invalid default route '{}', expected nexthop:iface", s)
+            })?;
+        let next_hop = next_hop
+            .parse()
+            .map_err(|_| format!("Invalid next hop '{}'", next_hop))?;
+        Ok(Self::default_via(next_hop, iface.parse()?))
+    }
+
+    pub fn mask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - self.prefix_len as u32)
+                .unwrap_or(0),
+        )
+    }
+}
+
+impl std::str::FromStr for StaticRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("Invalid route '{}', expected net/prefix:nexthop:iface", s)
+        };
+        let mut parts = s.split(':');
+        let (Some(prefix), Some(next_hop), Some(iface), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let (network, prefix_len) = prefix
+            .split_once('/')
+            .ok_or_else(invalid)?;
+        let network = network
+            .parse()
+            .map_err(|_| format!("Invalid network '{}'", network))?;
+        let prefix_len = prefix_len
+            .parse::<u8>()
+            .ok()
+            .filter(|len| *len <= 32)
+            .ok_or_else(|| format!("Invalid prefix length '{}'", prefix_len))?;
+        let next_hop = next_hop
+            .parse()
+            .map_err(|_| format!("Invalid next hop '{}'", next_hop))?;
+        Ok(Self {
+            network,
+            prefix_len,
+            next_hop,
+            interface: iface.parse()?,
+        })
+    }
+}
+
+impl std::fmt::Display for StaticRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}:{}:{}",
+            self.network,
+            self.prefix_len,
+            self.next_hop,
+            self.interface.name()
+        )
+    }
+}
+
 /// Packet waiting for ARP resolution
 #[derive(Debug, Clone)]
 struct PendingPacket {
     interface: InterfaceType,
     packet: Vec<u8>,
     src_mac: [u8; 6],
+    /// Where it came in, for the Host Unreachable if ARP never answers
+    ingress: InterfaceType,
+}
+
+/// ARP resolution in progress and the packets waiting on it
+#[derive(Debug, Clone)]
+struct PendingArp {
+    packets: Vec<PendingPacket>,
+    /// Requests sent after the first one
+    retries: u32,
+    last_request: Instant,
+}
+
+/// Where the router stands on the gateway's MAC address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayState {
+    /// Given with --gateway-mac
+    Static([u8; 6]),
+    /// ARP requests out, `tries` of them so far
+    Resolving {
+        tries: u32,
+        last_request: Option<Instant>,
+    },
+    Resolved([u8; 6]),
+    /// No answer to ARP_MAX_RETRIES retries; NATed traffic still asks
+    Failed,
+}
+
+impl GatewayState {
+    /// Start (over) with ARP
+    pub const RESOLVE: Self = GatewayState::Resolving {
+        tries: 0,
+        last_request: None,
+    };
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GatewayState::Static(_) => "static",
+            GatewayState::Resolving { .. } => "resolving",
+            GatewayState::Resolved(_) => "resolved",
+            GatewayState::Failed => "failed",
+        }
+    }
+
+    pub fn mac(&self) -> Option<[u8; 6]> {
+        match self {
+            GatewayState::Static(mac) | GatewayState::Resolved(mac) => {
+                Some(*mac)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads a packet or frame off a link, None if nothing came in time
+pub type LinkRx = Box<dyn FnMut() -> Result<Option<Vec<u8>>, String> + Send>;
+/// Writes a packet or frame to a link
+pub type LinkTx = Box<dyn FnMut(&[u8]) -> Result<(), String> + Send>;
+
+/// The devices the router's threads work on, `Router::run` opens the real
+/// ones
+pub struct RouterLinks {
+    pub acoustic: AcousticInterface,
+    pub wifi: (LinkRx, LinkTx),
+    /// None when the gateway is on the WiFi interface
+    pub eth: Option<(LinkRx, LinkTx)>,
+    pub tun: (LinkRx, LinkTx),
+}
+
+/// Queues towards the interface threads
+#[derive(Clone)]
+pub struct Egress {
+    pub acoustic: crossbeam_channel::Sender<(Vec<u8>, u8)>,
+    pub wifi: crossbeam_channel::Sender<Vec<u8>>,
+    pub eth: crossbeam_channel::Sender<Vec<u8>>,
+    pub tun: crossbeam_channel::Sender<Vec<u8>>,
 }
 
 /// A directly connected network
@@ -68,6 +279,11 @@ impl DirectNetwork {
         }
     }
 
+    /// Number of leading ones in the mask (24 for 255.255.255.0)
+    pub fn prefix_len(&self) -> u8 {
+        u32::from(self.mask).leading_ones() as u8
+    }
+
     /// Check if an IP address belongs to this network
     pub fn contains(&self, ip: &Ipv4Addr) -> bool {
         let net_octets = self.network.octets();
@@ -90,11 +306,45 @@ impl DirectNetwork {
 pub struct RouteEntry {
     /// Destination network
     pub network: DirectNetwork,
+    /// Prefix length of the destination network, 0 for the default route
+    pub prefix_len: u8,
     /// Next hop (None for directly connected)
     pub next_hop: Option<Ipv4Addr>,
 }
 
-/// Static routing table
+impl RouteEntry {
+    fn new(network: DirectNetwork, next_hop: Option<Ipv4Addr>) -> Self {
+        Self {
+            prefix_len: network.prefix_len(),
+            network,
+            next_hop,
+        }
+    }
+
+    /// Same destination prefix, whatever the host bits say
+    fn is_prefix(&self, network: Ipv4Addr, mask: Ipv4Addr) -> bool {
+        let mask_bits = u32::from(mask);
+        mask_bits == u32::from(self.network.mask)
+            && u32::from(network) & mask_bits
+                == u32::from(self.network.network) & mask_bits
+    }
+}
+
+impl std::fmt::Display for RouteEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} dev {:?}",
+            self.network.network, self.prefix_len, self.network.interface
+        )?;
+        if let Some(next_hop) = self.next_hop {
+            write!(f, " via {}", next_hop)?;
+        }
+        Ok(())
+    }
+}
+
+/// Static routing table, longest prefix match
 #[derive(Clone)]
 pub struct RoutingTable {
     routes: Vec<RouteEntry>,
@@ -112,10 +362,10 @@ impl RoutingTable {
         mask: Ipv4Addr,
         interface: InterfaceType,
     ) {
-        self.routes.push(RouteEntry {
-            network: DirectNetwork::new(network, mask, interface),
-            next_hop: None,
-        });
+        self.add_route(RouteEntry::new(
+            DirectNetwork::new(network, mask, interface),
+            None,
+        ));
     }
 
     pub fn add_network(
@@ -125,45 +375,145 @@ impl RoutingTable {
         interface: InterfaceType,
         next_hop: Ipv4Addr,
     ) {
-        self.routes.push(RouteEntry {
-            network: DirectNetwork::new(network, mask, interface),
-            next_hop: Some(next_hop),
-        });
+        self.add_route(RouteEntry::new(
+            DirectNetwork::new(network, mask, interface),
+            Some(next_hop),
+        ));
+    }
+
+    /// A route to a prefix already in the table replaces it
+    fn add_route(&mut self, route: RouteEntry) {
+        self.remove_route(route.network.network, route.network.mask);
+        self.routes.push(route);
+    }
+
+    /// Remove the route to `network`/`mask`, returns whether there was one
+    pub fn remove_route(&mut self, network: Ipv4Addr, mask: Ipv4Addr) -> bool {
+        let before = self.routes.len();
+        self.routes
+            .retain(|route| !route.is_prefix(network, mask));
+        self.routes.len() != before
+    }
+
+    /// All routes, in insertion order
+    pub fn list_routes(&self) -> &[RouteEntry] {
+        &self.routes
     }
 
-    /// Lookup the interface for a destination IP
+    /// Lookup the interface for a destination IP: the matching route with
+    /// the longest prefix, the earliest one on a tie
     pub fn lookup(
         &self,
         dest_ip: &Ipv4Addr,
     ) -> Option<(Option<Ipv4Addr>, InterfaceType)> {
+        self.lookup_where(dest_ip, |_| true)
+    }
+
+    /// Longest prefix match among the routes `keep` accepts
+    pub fn lookup_where(
+        &self,
+        dest_ip: &Ipv4Addr,
+        keep: impl Fn(&RouteEntry) -> bool,
+    ) -> Option<(Option<Ipv4Addr>, InterfaceType)> {
+        let mut best: Option<&RouteEntry> = None;
         for route in &self.routes {
             if route
                 .network
                 .contains(dest_ip)
+                && keep(route)
+                && best.is_none_or(|b| route.prefix_len > b.prefix_len)
             {
-                return Some((route.next_hop, route.network.interface));
+                best = Some(route);
             }
         }
-        None
+        best.map(|route| (route.next_hop, route.network.interface))
+    }
+}
+
+/// Time source of the router, frozen and moved by hand in tests
+#[derive(Clone, Default)]
+struct Clock {
+    mock: Option<Arc<Mutex<Instant>>>,
+}
+
+impl Clock {
+    fn now(&self) -> Instant {
+        match &self.mock {
+            Some(mock) => *mock.lock().unwrap(),
+            None => Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    fn mock() -> Self {
+        Self {
+            mock: Some(Arc::new(Mutex::new(Instant::now()))),
+        }
+    }
+
+    #[cfg(test)]
+    fn advance(&self, by: Duration) {
+        if let Some(mock) = &self.mock {
+            *mock.lock().unwrap() += by;
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct ArpEntry {
+    mac: [u8; 6],
+    /// When it was last confirmed, None for static entries
+    learned: Option<Instant>,
+    /// Last refresh request sent for it while stale
+    refresh_sent: Option<Instant>,
+}
+
 /// ARP table for Network interface (maps IP to MAC address)
+///
+/// Learned entries go stale after the TTL. A stale entry is still used
+/// (soft expiry) but each use asks for a refresh, at most once per
+/// ARP_RETRY_INTERVAL_MS; if no answer comes within ARP_MAX_RETRIES + 1
+/// intervals the entry is dropped. Static entries never age, though ARP
+/// traffic can still change their MAC.
 #[derive(Clone)]
 pub struct ArpTable {
-    table: HashMap<InterfaceType, HashMap<Ipv4Addr, [u8; 6]>>,
+    table: HashMap<InterfaceType, HashMap<Ipv4Addr, ArpEntry>>,
+    ttl: Duration,
 }
 
 impl ArpTable {
     pub fn new() -> Self {
-        let mut ac_table = HashMap::new();
-        ac_table.insert("192.168.1.1".parse().unwrap(), [0, 0, 0, 0, 0, 1]);
-        ac_table.insert("192.168.1.2".parse().unwrap(), [0, 0, 0, 0, 0, 2]);
-        ac_table.insert("192.168.1.3".parse().unwrap(), [0, 0, 0, 0, 0, 3]);
+        let mut table = Self {
+            table: HashMap::new(),
+            ttl: Duration::from_secs(crate::utils::consts::ROUTER_ARP_TTL_SECS),
+        };
+        table.add_entry(
+            "192.168.1.1".parse().unwrap(),
+            [0, 0, 0, 0, 0, 1],
+            InterfaceType::Acoustic,
+        );
+        table.add_entry(
+            "192.168.1.2".parse().unwrap(),
+            [0, 0, 0, 0, 0, 2],
+            InterfaceType::Acoustic,
+        );
+        table.add_entry(
+            "192.168.1.3".parse().unwrap(),
+            [0, 0, 0, 0, 0, 3],
+            InterfaceType::Acoustic,
+        );
+        table
+    }
 
-        Self {
-            table: HashMap::from([(InterfaceType::Acoustic, ac_table)]),
-        }
+    /// Lifetime of learned entries before they go stale
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// How long a stale entry is kept while refreshes go unanswered
+    fn grace() -> Duration {
+        use crate::utils::consts::{ARP_MAX_RETRIES, ARP_RETRY_INTERVAL_MS};
+        Duration::from_millis(ARP_RETRY_INTERVAL_MS) * (ARP_MAX_RETRIES + 1)
     }
 
     /// Add a static ARP entry
@@ -176,32 +526,115 @@ impl ArpTable {
         self.table
             .entry(iface)
             .or_insert_with(HashMap::new)
-            .insert(ip, mac);
+            .insert(
+                ip,
+                ArpEntry {
+                    mac,
+                    learned: None,
+                    refresh_sent: None,
+                },
+            );
     }
 
-    /// Get MAC address for an IP
-    pub fn get_mac(
-        &self,
+    /// MAC address to send to `ip` at `now`, and whether a refresh request
+    /// should go out first because the entry is stale
+    pub fn lookup_for_send(
+        &mut self,
         ip: &Ipv4Addr,
         iface: InterfaceType,
-    ) -> Option<[u8; 6]> {
-        // Borrow the interface key for lookup, then copy the MAC out of the inner map
-        self.table
-            .get(&iface)
-            .and_then(|m| m.get(ip).copied())
+        now: Instant,
+    ) -> Option<([u8; 6], bool)> {
+        let ttl = self.ttl;
+        let entry = self
+            .table
+            .get_mut(&iface)?
+            .get_mut(ip)?;
+        let stale = entry
+            .learned
+            .is_some_and(|at| now.saturating_duration_since(at) > ttl);
+        let interval =
+            Duration::from_millis(crate::utils::consts::ARP_RETRY_INTERVAL_MS);
+        let refresh = stale
+            && entry
+                .refresh_sent
+                .is_none_or(|at| now.saturating_duration_since(at) >= interval);
+        if refresh {
+            entry.refresh_sent = Some(now);
+        }
+        Some((entry.mac, refresh))
     }
 
-    /// Update or add an ARP entry (for learning)
+    /// Update or add an ARP entry (for learning), confirmed at `now`
     pub fn update(
         &mut self,
         ip: Ipv4Addr,
         mac: [u8; 6],
         interface: InterfaceType,
+        now: Instant,
     ) {
-        self.table
+        let entry = self
+            .table
             .entry(interface)
             .or_insert_with(HashMap::new)
-            .insert(ip, mac);
+            .entry(ip)
+            .or_insert(ArpEntry {
+                mac,
+                learned: Some(now),
+                refresh_sent: None,
+            });
+        if entry.mac != mac {
+            info!("ARP: {} moved to a new MAC on {:?}", ip, interface);
+        }
+        entry.mac = mac;
+        entry.refresh_sent = None;
+        if entry.learned.is_some() {
+            entry.learned = Some(now);
+        }
+    }
+
+    /// Every entry as (interface, IP, MAC, when it was learned), static
+    /// ones without a time
+    pub fn entries(
+        &self,
+    ) -> Vec<(InterfaceType, Ipv4Addr, [u8; 6], Option<Instant>)> {
+        let mut entries: Vec<_> = self
+            .table
+            .iter()
+            .flat_map(|(iface, entries)| {
+                entries
+                    .iter()
+                    .map(|(ip, entry)| (*iface, *ip, entry.mac, entry.learned))
+            })
+            .collect();
+        entries.sort_by_key(|(iface, ip, _, _)| (iface.name(), *ip));
+        entries
+    }
+
+    /// Forget every learned entry, static ones stay. Returns how many went.
+    pub fn flush(&mut self) -> usize {
+        let mut flushed = 0;
+        for entries in self.table.values_mut() {
+            let before = entries.len();
+            entries.retain(|_, entry| entry.learned.is_none());
+            flushed += before - entries.len();
+        }
+        flushed
+    }
+
+    /// Drop learned entries whose refreshes went unanswered
+    pub fn expire(&mut self, now: Instant) {
+        let limit = self.ttl + Self::grace();
+        for (iface, entries) in self.table.iter_mut() {
+            entries.retain(|ip, entry| {
+                let alive = entry
+                    .learned
+                    .is_none_or(|at| now.saturating_duration_since(at) <= limit);
+                if !alive {
+                    debug!("ARP: {} on {:?} expired", ip, iface);
+                }
+                alive
+            });
+        }
     }
 }
 
@@ -219,11 +652,14 @@ impl DnsTable {
     }
 
     pub fn add_entry(&mut self, domain: &str, ip: Ipv4Addr) {
-        self.entries.insert(domain.to_lowercase(), ip);
+        self.entries
+            .insert(domain.to_lowercase(), ip);
     }
 
     pub fn lookup(&self, domain: &str) -> Option<Ipv4Addr> {
-        self.entries.get(&domain.to_lowercase()).copied()
+        self.entries
+            .get(&domain.to_lowercase())
+            .copied()
     }
 }
 
@@ -268,6 +704,25 @@ pub struct RouterConfig {
     pub node3_ip: Ipv4Addr,
     /// NODE1 IP (for Traversal)
     pub node1_ip: Ipv4Addr,
+    /// Static routes, the default route (prefix 0) among them
+    pub routes: Vec<StaticRoute>,
+    /// Learned ARP entries go stale after this long
+    pub arp_ttl: Duration,
+    /// Larger packets are fragmented before going out the acoustic link
+    pub acoustic_mtu: usize,
+    /// Record the acoustic link's IP traffic to this pcap file
+    pub capture: Option<String>,
+    /// Filter applied to incoming packets, first match wins
+    pub firewall: Vec<FirewallRule>,
+    /// Localhost address of the management socket, None for none
+    pub management: Option<std::net::SocketAddr>,
+    /// Physical layer of the acoustic link
+    pub phy: PhyParams,
+    /// CSMA/CA timing of the acoustic link
+    pub mac: MacParams,
+    /// Where the acoustic link publishes its status for --tui, None = no
+    /// dashboard
+    pub dashboard: Option<crossbeam_channel::Sender<DashboardEvent>>,
 }
 
 impl Default for RouterConfig {
@@ -303,6 +758,24 @@ impl Default for RouterConfig {
                 .unwrap(),
             node3_ip: "192.168.2.2".parse().unwrap(),
             node1_ip: "192.168.1.2".parse().unwrap(),
+            routes: vec![StaticRoute::default_via(
+                "192.168.2.254"
+                    .parse()
+                    .unwrap(),
+                InterfaceType::Ethernet,
+            )],
+            arp_ttl: Duration::from_secs(
+                crate::utils::consts::ROUTER_ARP_TTL_SECS,
+            ),
+            acoustic_mtu: crate::utils::consts::ROUTER_ACOUSTIC_MTU,
+            capture: None,
+            firewall: Vec::new(),
+            management: crate::utils::consts::ROUTER_MGMT_ADDR
+                .parse()
+                .ok(),
+            phy: PhyParams::default(),
+            mac: MacParams::default(),
+            dashboard: None,
         }
     }
 }
@@ -315,13 +788,25 @@ pub struct Router {
     routing_table: Arc<RwLock<RoutingTable>>,
     arp_table: Arc<RwLock<ArpTable>>,
     nat_table: Arc<RwLock<NatTable>>,
-    // Simple Session table for TCP/UDP NAT: Port -> Original IP
-    // Assumes simple Cone NAT where external port maps to internal IP 1:1 (no port translation unless collision, but keeping simple)
-    nat_sessions: Arc<RwLock<HashMap<u16, Ipv4Addr>>>,
+    // TCP/UDP port translation: flows and their external ports
+    napt: Arc<RwLock<NaptTable>>,
     // Local DNS Table
     dns_table: Arc<RwLock<DnsTable>>,
+    firewall: Arc<RwLock<Firewall>>,
+    // Acoustic link counters, published for the management socket
+    link_stats: Arc<Mutex<LinkStats>>,
+    counters: Arc<RouterCounters>,
+    // Gateway MAC, static or found by ARP
+    gateway: Arc<Mutex<GatewayState>>,
+    // Up/down state of the acoustic next hops
+    hops: Arc<Mutex<HopLiveness>>,
+    clock: Clock,
     // Buffer for packets awaiting ARP resolution
-    pending_packets: Arc<RwLock<HashMap<Ipv4Addr, Vec<PendingPacket>>>>,
+    pending_packets: Arc<RwLock<HashMap<Ipv4Addr, PendingArp>>>,
+    // Keeps Time Exceeded replies from turning into a storm
+    icmp_limiter: Arc<Mutex<icmp::IcmpRateLimiter>>,
+    // Fragments of datagrams addressed to the router itself
+    reassembler: Arc<Mutex<IpReassembler>>,
     running: Arc<Mutex<AtomicBool>>,
 }
 
@@ -348,7 +833,7 @@ pub enum PacketState {
         dst_mac: [u8; 6],
     },
     /// Dropped packet
-    Dropped { reason: String },
+    Dropped { reason: DropReason, detail: String },
 }
 
 impl Router {
@@ -393,6 +878,16 @@ impl Router {
             InterfaceType::Tun,
         );
 
+        // Static routes, everything else goes nowhere without a default one
+        for route in &config.routes {
+            routing_table.add_network(
+                route.network,
+                route.mask(),
+                route.interface,
+                route.next_hop,
+            );
+        }
+
         // Initialize DNS Table with hardcoded entries
         let mut dns_table = DnsTable::new();
         // Hardcoded Static Entries
@@ -400,23 +895,94 @@ impl Router {
         dns_table.add_entry("node1.lan", config.node1_ip);
         dns_table.add_entry("node3.lan", config.node3_ip);
         // Add a funny external one for testing
-        dns_table.add_entry("example.com", "104.18.27.120".parse().unwrap());
+        dns_table.add_entry(
+            "example.com",
+            "104.18.27.120"
+                .parse()
+                .unwrap(),
+        );
         dns_table.add_entry("google.com", "8.8.8.8".parse().unwrap());
-        dns_table.add_entry("help.3g.163.com", "111.124.202.255".parse().unwrap());
+        dns_table.add_entry(
+            "help.3g.163.com",
+            "111.124.202.255"
+                .parse()
+                .unwrap(),
+        );
         dns_table.add_entry("test.dns", "1.2.3.4".parse().unwrap());
 
+        let mut arp_table = ArpTable::new();
+        arp_table.set_ttl(config.arp_ttl);
+        let firewall = Firewall::new(config.firewall.clone());
+        let gateway = match config.gateway_mac {
+            Some(mac) => GatewayState::Static(mac),
+            None => GatewayState::RESOLVE,
+        };
+
         Self {
             config,
             routing_table: Arc::new(RwLock::new(routing_table)),
-            arp_table: Arc::new(RwLock::new(ArpTable::new())),
+            arp_table: Arc::new(RwLock::new(arp_table)),
             nat_table: Arc::new(RwLock::new(NatTable::new())),
-            nat_sessions: Arc::new(RwLock::new(HashMap::new())),
+            napt: Arc::new(RwLock::new(NaptTable::new())),
             dns_table: Arc::new(RwLock::new(dns_table)),
+            firewall: Arc::new(RwLock::new(firewall)),
+            link_stats: Arc::new(Mutex::new(LinkStats::default())),
+            counters: Arc::new(RouterCounters::new(
+                crate::utils::consts::ROUTER_FLOW_TABLE_SIZE,
+            )),
+            gateway: Arc::new(Mutex::new(gateway)),
+            hops: Arc::new(Mutex::new(HopLiveness::default())),
+            clock: Clock::default(),
             pending_packets: Arc::new(RwLock::new(HashMap::new())),
+            icmp_limiter: Arc::new(Mutex::new(icmp::IcmpRateLimiter::default())),
+            reassembler: Arc::new(Mutex::new(IpReassembler::new())),
             running: Arc::new(Mutex::new(AtomicBool::new(false))),
         }
     }
 
+    /// The tables the management socket works on
+    fn managed_tables(&self) -> ManagedTables {
+        ManagedTables {
+            routes: self.routing_table.clone(),
+            arp: self.arp_table.clone(),
+            nat: self.nat_table.clone(),
+            napt: self.napt.clone(),
+            firewall: self.firewall.clone(),
+            link_stats: self.link_stats.clone(),
+            counters: self.counters(),
+            gateway_ip: self.config.gateway_ip,
+            gateway: self.gateway.clone(),
+            hops: self.hops.clone(),
+        }
+    }
+
+    /// Packet and byte counters, per interface and per flow
+    pub fn counters(&self) -> Arc<RouterCounters> {
+        self.counters.clone()
+    }
+
+    /// Adds the counters and the NAT sessions to metrics scrapes
+    fn metrics_collector(&self) -> metrics::Collector {
+        let counters = self.counters();
+        let nat = self.nat_table.clone();
+        let napt = self.napt.clone();
+        Box::new(move |out| {
+            counters.export(out);
+            let echo = nat
+                .read()
+                .map_or(0, |nat| nat.echo_sessions().len());
+            let flows = napt
+                .read()
+                .map_or(0, |napt| napt.flows().len());
+            out.gauge(
+                "nat_sessions",
+                "NAT echo sessions and NAPT flows",
+                &[],
+                (echo + flows) as f64,
+            );
+        })
+    }
+
     /// Add a static ARP entry for Other(Gateway)
     pub fn add_arp_entry(
         &self,
@@ -515,112 +1081,623 @@ impl Router {
         Ok(())
     }
 
-    /// Recalculate IP header checksum
-    fn recalculate_ip_checksum(ip_packet: &mut [u8]) {
-        // Zero out checksum
-        ip_packet[10] = 0;
-        ip_packet[11] = 0;
-
-        let ihl = (ip_packet[0] & 0x0F) as usize;
-        let header_len = ihl * 4;
+    /// DF bit of an IPv4 packet
+    fn dont_fragment(packet: &[u8]) -> bool {
+        packet.len() >= 20 && packet[6] & 0x40 != 0
+    }
 
-        let mut sum: u32 = 0;
-        for i in (0..header_len).step_by(2) {
-            let word = u16::from_be_bytes([ip_packet[i], ip_packet[i + 1]]);
-            sum = sum.wrapping_add(word as u32);
-        }
+    /// Whether forwarding would take the TTL of this packet to 0
+    fn ttl_expired(ip_packet: &[u8]) -> bool {
+        ip_packet.len() >= 20 && ip_packet[8] <= 1
+    }
 
-        while (sum >> 16) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
+    /// Our IP on an interface
+    fn interface_ip(&self, iface: InterfaceType) -> Ipv4Addr {
+        match iface {
+            InterfaceType::Acoustic => self.config.acoustic_ip,
+            InterfaceType::WiFi => self.config.wifi_ip,
+            InterfaceType::Ethernet => self.config.eth_ip,
+            InterfaceType::Tun => self.config.tun_ip,
         }
-
-        let checksum = !(sum as u16);
-        ip_packet[10] = (checksum >> 8) as u8;
-        ip_packet[11] = (checksum & 0xFF) as u8;
     }
 
-    /// Recalculate TCP or UDP Checksum (Pseudo-Header + L4 Header + Payload)
-    /// This is required when IP addresses change (NAT).
-    fn recalculate_l4_checksum(
-        packet: &mut [u8],
-        src_ip: Ipv4Addr,
-        dst_ip: Ipv4Addr,
-        protocol: IpNumber,
+    /// Take an ARP answer for `sender_ip` on `iface` and send whatever
+    /// was waiting for it
+    fn learn_arp(
+        &self,
+        egress: &Egress,
+        sender_ip: Ipv4Addr,
+        sender_mac: [u8; 6],
+        iface: InterfaceType,
     ) {
-        let ihl = (packet[0] & 0x0F) as usize * 4;
-        if packet.len() < ihl {
-            return;
+        // Update ARP Table Thread-Safely
+        if let Ok(mut table) = self.arp_table.write() {
+            table.update(sender_ip, sender_mac, iface, self.clock.now());
+        }
+        if sender_ip == self.config.gateway_ip
+            && iface == InterfaceType::Ethernet
+            && let Ok(mut gateway) = self.gateway.lock()
+            && !matches!(*gateway, GatewayState::Static(_))
+            && gateway.mac() != Some(sender_mac)
+        {
+            info!(
+                "Gateway {} resolved to {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                sender_ip,
+                sender_mac[0],
+                sender_mac[1],
+                sender_mac[2],
+                sender_mac[3],
+                sender_mac[4],
+                sender_mac[5]
+            );
+            *gateway = GatewayState::Resolved(sender_mac);
         }
 
-        let l4_len = packet.len() - ihl;
-        let l4_bytes = &mut packet[ihl..];
+        // Check for pending packets (packets that were waiting for this ARP reply)
+        let buffered = if let Ok(mut pending) = self.pending_packets.write() {
+            pending.remove(&sender_ip)
+        } else {
+            None
+        };
 
-        // Reset Checksum Field to 0 before calculation
-        match protocol {
-            IpNumber::TCP => {
-                if l4_bytes.len() >= 18 {
-                    l4_bytes[16] = 0;
-                    l4_bytes[17] = 0;
-                } else {
-                    return;
-                }
-            }
-            IpNumber::UDP => {
-                if l4_bytes.len() >= 8 {
-                    l4_bytes[6] = 0;
-                    l4_bytes[7] = 0;
-                } else {
-                    return;
+        if let Some(PendingArp { packets, .. }) = buffered {
+            info!(
+                "ARP Resolved for {}. Sending {} buffered packets.",
+                sender_ip,
+                packets.len()
+            );
+            for pkt in packets {
+                match pkt.interface {
+                    InterfaceType::WiFi => {
+                        let frame = self.build_ethernet_frame(
+                            pkt.src_mac,
+                            sender_mac,
+                            &pkt.packet,
+                        );
+                        if let Err(e) = egress.wifi.send(frame) {
+                            warn!("Failed to send buffered WiFi packet: {}", e);
+                        }
+                    }
+                    InterfaceType::Ethernet => {
+                        let frame = self.build_ethernet_frame(
+                            pkt.src_mac,
+                            sender_mac,
+                            &pkt.packet,
+                        );
+                        if let Err(e) = egress.eth.send(frame) {
+                            warn!(
+                                "Failed to send buffered Ethernet packet: {}",
+                                e
+                            );
+                        }
+                    }
+                    InterfaceType::Acoustic => {
+                        if let Err(e) = egress
+                            .acoustic
+                            .send((pkt.packet, sender_mac[5]))
+                        {
+                            warn!(
+                                "Failed to send buffered Acoustic packet: {}",
+                                e
+                            );
+                        }
+                    }
+                    _ => {}
                 }
             }
-            _ => return,
         }
+    }
 
-        let mut sum: u32 = 0;
+    /// Whether the routing table has a way to `dest_ip`
+    fn has_route(&self, dest_ip: &Ipv4Addr) -> bool {
+        self.routing_table
+            .read()
+            .map(|table| {
+                table
+                    .lookup(dest_ip)
+                    .is_some()
+            })
+            .unwrap_or(false)
+    }
 
-        // 1. Pseudo Header Sum
-        let src_oct = src_ip.octets();
-        let dst_oct = dst_ip.octets();
-
-        sum += u16::from_be_bytes([src_oct[0], src_oct[1]]) as u32;
-        sum += u16::from_be_bytes([src_oct[2], src_oct[3]]) as u32;
-        sum += u16::from_be_bytes([dst_oct[0], dst_oct[1]]) as u32;
-        sum += u16::from_be_bytes([dst_oct[2], dst_oct[3]]) as u32;
-
-        sum += protocol.0 as u32;
-        sum += l4_len as u32;
-
-        // 2. L4 Header + Payload Sum
-        for i in (0..l4_bytes.len()).step_by(2) {
-            if i + 1 < l4_bytes.len() {
-                sum += u16::from_be_bytes([l4_bytes[i], l4_bytes[i + 1]]) as u32;
-            } else {
-                // Padding for odd length
-                sum += u16::from_be_bytes([l4_bytes[i], 0]) as u32;
+    /// ICMP error of `icmp_type` from `router_ip` back to the sender of
+    /// `original`, quoting its IP header and the first 8 bytes of its
+    /// payload. None for packets that must not get one: ICMP errors
+    /// themselves and non-first fragments.
+    fn build_icmp_error(
+        router_ip: Ipv4Addr,
+        original: &[u8],
+        icmp_type: Icmpv4Type,
+    ) -> Option<Vec<u8>> {
+        let header = Ipv4HeaderSlice::from_slice(original).ok()?;
+        if header
+            .fragments_offset()
+            .value()
+            != 0
+        {
+            return None;
+        }
+        let ihl = header.slice().len();
+        if header.protocol() == IpNumber::ICMP {
+            let is_echo = original
+                .get(ihl)
+                .is_some_and(|&t| t == 0 || t == 8);
+            if !is_echo {
+                return None;
             }
         }
 
-        // 3. Fold to 16 bits
-        while (sum >> 16) != 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16);
-        }
-        let checksum = !(sum as u16);
+        let quote = &original[..original.len().min(ihl + 8)];
+        let builder = PacketBuilder::ipv4(
+            router_ip.octets(),
+            header.source(),
+            crate::utils::consts::IP_TTL,
+        )
+        .icmpv4(icmp_type);
+        let mut result = Vec::with_capacity(builder.size(quote.len()));
+        builder
+            .write(&mut result, quote)
+            .ok()?;
+        Some(result)
+    }
 
-        // 4. Write Checksum
-        match protocol {
-            IpNumber::TCP => {
-                l4_bytes[16] = (checksum >> 8) as u8;
-                l4_bytes[17] = (checksum & 0xFF) as u8;
-            }
-            IpNumber::UDP => {
-                l4_bytes[6] = (checksum >> 8) as u8;
-                l4_bytes[7] = (checksum & 0xFF) as u8;
-            }
-            _ => {}
-        }
+    /// What to do with a packet whose TTL ran out on `iface`: answer with
+    /// Time Exceeded if allowed, drop it either way
+    fn expire_packet(
+        &self,
+        iface: InterfaceType,
+        original: &[u8],
+    ) -> PacketState {
+        self.icmp_error_state(
+            iface,
+            original,
+            Icmpv4Type::TimeExceeded(
+                etherparse::icmpv4::TimeExceededCode::TtlExceededInTransit,
+            ),
+            DropReason::TtlExpired,
+            "TTL expired",
+        )
     }
 
-    /// Process packet using etherparse (decrement TTL, rebuild checksums)
+    /// Drop `original`, which came in on `iface`, and answer with an ICMP
+    /// error sourced from our IP there, unless rate limited. The drop is
+    /// counted here if an answer goes out, in the Dropped state otherwise.
+    fn icmp_error_state(
+        &self,
+        iface: InterfaceType,
+        original: &[u8],
+        icmp_type: Icmpv4Type,
+        reason: DropReason,
+        detail: &str,
+    ) -> PacketState {
+        let dropped = PacketState::Dropped {
+            reason,
+            detail: detail.to_string(),
+        };
+        let router_ip = self.interface_ip(iface);
+        // Only packets that get an answer spend a token
+        let Some(packet) =
+            Self::build_icmp_error(router_ip, original, icmp_type)
+        else {
+            return dropped;
+        };
+        let allowed = self
+            .icmp_limiter
+            .lock()
+            .map(|mut limiter| limiter.allow(self.clock.now()))
+            .unwrap_or(false);
+        if !allowed {
+            debug!("ICMP rate limit hit, not answering: {}", detail);
+            return dropped;
+        }
+        let dst_ip = Ipv4Addr::new(
+            original[12],
+            original[13],
+            original[14],
+            original[15],
+        );
+        debug!("{}, sending ICMP error to {}", detail, dst_ip);
+        self.counters
+            .record_drop(iface, reason);
+        PacketState::Routing {
+            src_ip: router_ip,
+            dst_ip,
+            packet,
+        }
+    }
+
+    /// Next hop to `dst_ip` out of `iface` only: the gateway of the best
+    /// route there, else `dst_ip` itself
+    fn next_hop_on(&self, iface: InterfaceType, dst_ip: Ipv4Addr) -> Ipv4Addr {
+        self.routing_table
+            .read()
+            .ok()
+            .and_then(|table| {
+                table.lookup_where(&dst_ip, |route| {
+                    route.network.interface == iface
+                })
+            })
+            .and_then(|(next_hop, _)| next_hop)
+            .unwrap_or(dst_ip)
+    }
+
+    /// Source MAC and IP of our ARP requests on `iface`, zero if we don't
+    /// send any there
+    fn arp_source(&self, iface: InterfaceType) -> ([u8; 6], Ipv4Addr) {
+        match iface {
+            InterfaceType::WiFi => (self.config.wifi_mac, self.config.wifi_ip),
+            InterfaceType::Ethernet => (self.config.eth_mac, self.config.eth_ip),
+            InterfaceType::Acoustic => {
+                let mut mac = [0u8; 6];
+                mac[5] = self.config.acoustic_mac;
+                (mac, self.config.acoustic_ip)
+            }
+            _ => ([0u8; 6], Ipv4Addr::new(0, 0, 0, 0)),
+        }
+    }
+
+    /// Ask for the MAC of `target` on `iface`
+    fn send_arp_request(
+        &self,
+        egress: &Egress,
+        iface: InterfaceType,
+        target: Ipv4Addr,
+    ) {
+        let (src_mac, src_ip) = self.arp_source(iface);
+        let arp_req = self.prepare_arp_request(src_mac, src_ip, target);
+        match iface {
+            InterfaceType::WiFi => {
+                if let Err(e) = egress.wifi.send(arp_req) {
+                    warn!("Failed to send ARP request to WiFi: {}", e);
+                }
+            }
+            InterfaceType::Ethernet => {
+                if let Err(e) = egress.eth.send(arp_req) {
+                    warn!("Failed to send ARP request to Ethernet: {}", e);
+                }
+            }
+            InterfaceType::Acoustic => {
+                let request = crate::net::arp::ArpPacket::request(
+                    self.config.acoustic_mac,
+                    src_ip,
+                    target,
+                );
+                if let Err(e) = egress
+                    .acoustic
+                    .send((request.to_bytes(), crate::mac::types::BROADCAST))
+                {
+                    warn!("Failed to send ARP request to Acoustic: {}", e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The gateway's MAC if it is known
+    fn gateway_mac(&self) -> Option<[u8; 6]> {
+        self.gateway
+            .lock()
+            .ok()?
+            .mac()
+    }
+
+    /// Ask for the gateway's MAC until it answers, once per
+    /// ARP_RETRY_INTERVAL_MS, giving up after ARP_MAX_RETRIES retries
+    fn resolve_gateway(&self, egress: &Egress, now: Instant) {
+        use crate::utils::consts::{ARP_MAX_RETRIES, ARP_RETRY_INTERVAL_MS};
+
+        let Ok(mut gateway) = self.gateway.lock() else {
+            return;
+        };
+        let GatewayState::Resolving {
+            tries,
+            last_request,
+        } = *gateway
+        else {
+            return;
+        };
+        let interval = Duration::from_millis(ARP_RETRY_INTERVAL_MS);
+        if last_request
+            .is_some_and(|at| now.saturating_duration_since(at) < interval)
+        {
+            return;
+        }
+        if tries > ARP_MAX_RETRIES {
+            warn!(
+                "Gateway {} does not answer ARP, NAT out eth waits for it",
+                self.config.gateway_ip
+            );
+            *gateway = GatewayState::Failed;
+            return;
+        }
+        debug!(
+            "Resolving gateway {} (request {})",
+            self.config.gateway_ip,
+            tries + 1
+        );
+        *gateway = GatewayState::Resolving {
+            tries: tries + 1,
+            last_request: Some(now),
+        };
+        drop(gateway);
+        self.send_arp_request(
+            egress,
+            InterfaceType::Ethernet,
+            self.config.gateway_ip,
+        );
+    }
+
+    /// Note how a send to acoustic node `mac` went, logging when that
+    /// takes the node down or brings it back
+    fn record_acoustic_send(&self, mac: u8, sent: bool) {
+        let now = self.clock.now();
+        let changed = self
+            .hops
+            .lock()
+            .ok()
+            .and_then(|mut hops| hops.record_send(mac, sent, now));
+        match changed {
+            Some(HopState::Down) => warn!(
+                "Acoustic node {} is down after {} failed sends, routing around it",
+                mac,
+                crate::utils::consts::ROUTER_HOP_MAX_FAILURES
+            ),
+            Some(HopState::Up) => info!("Acoustic node {} is back up", mac),
+            None => {}
+        }
+    }
+
+    /// A frame came in from acoustic node `mac`
+    fn heard_on_acoustic(&self, mac: u8) {
+        let now = self.clock.now();
+        let changed = self
+            .hops
+            .lock()
+            .ok()
+            .and_then(|mut hops| hops.heard_from(mac, now));
+        if changed == Some(HopState::Up) {
+            info!("Heard from acoustic node {}, it is back up", mac);
+        }
+    }
+
+    /// Acoustic IPs whose node is down, by the ARP table
+    fn down_acoustic_hops(&self) -> Vec<Ipv4Addr> {
+        let Ok(hops) = self.hops.lock() else {
+            return Vec::new();
+        };
+        if hops.all_up() {
+            return Vec::new();
+        }
+        let Ok(table) = self.arp_table.read() else {
+            return Vec::new();
+        };
+        table
+            .entries()
+            .into_iter()
+            .filter(|(iface, _, mac, _)| {
+                *iface == InterfaceType::Acoustic && !hops.is_up(mac[5])
+            })
+            .map(|(_, ip, _, _)| ip)
+            .collect()
+    }
+
+    /// Route to `dst_ip` passing over routes whose acoustic next hop is
+    /// down. Err with that next hop if the best route goes through one and
+    /// no other route is left.
+    fn live_route(
+        &self,
+        dst_ip: Ipv4Addr,
+    ) -> Result<Option<(Option<Ipv4Addr>, InterfaceType)>, Ipv4Addr> {
+        let down = self.down_acoustic_hops();
+        let Ok(table) = self.routing_table.read() else {
+            return Ok(None);
+        };
+        let is_down = |next_hop: Option<Ipv4Addr>, iface: InterfaceType| {
+            iface == InterfaceType::Acoustic
+                && down.contains(&next_hop.unwrap_or(dst_ip))
+        };
+        let Some((next_hop, iface)) = table.lookup(&dst_ip) else {
+            return Ok(None);
+        };
+        if !is_down(next_hop, iface) {
+            return Ok(Some((next_hop, iface)));
+        }
+        let alternate = table.lookup_where(&dst_ip, |route| {
+            !is_down(route.next_hop, route.network.interface)
+        });
+        match alternate {
+            Some((alt_hop, alt_iface)) => {
+                debug!(
+                    "{}: next hop {} is down, using {:?} via {}",
+                    dst_ip,
+                    next_hop.unwrap_or(dst_ip),
+                    alt_hop,
+                    alt_iface.name()
+                );
+                Ok(Some((alt_hop, alt_iface)))
+            }
+            None => Err(next_hop.unwrap_or(dst_ip)),
+        }
+    }
+
+    /// Send a unicast ARP request to each down acoustic node due a probe;
+    /// if it goes out the node is up again
+    fn probe_down_hops(&self, egress: &Egress, now: Instant) {
+        let due = match self.hops.lock() {
+            Ok(mut hops) => hops.due_probes(now),
+            Err(_) => return,
+        };
+        if due.is_empty() {
+            return;
+        }
+        let Ok(entries) = self
+            .arp_table
+            .read()
+            .map(|table| table.entries())
+        else {
+            return;
+        };
+        for mac in due {
+            let Some((_, ip, _, _)) =
+                entries
+                    .iter()
+                    .find(|(iface, _, entry_mac, _)| {
+                        *iface == InterfaceType::Acoustic && entry_mac[5] == mac
+                    })
+            else {
+                continue;
+            };
+            debug!("Probing down acoustic node {} ({})", mac, ip);
+            let request = crate::net::arp::ArpPacket::request(
+                self.config.acoustic_mac,
+                self.config.acoustic_ip,
+                *ip,
+            );
+            if let Err(e) = egress
+                .acoustic
+                .send((request.to_bytes(), mac))
+            {
+                warn!("Failed to send probe to Acoustic: {}", e);
+            }
+        }
+    }
+
+    /// Retry ARP requests that went unanswered for ARP_RETRY_INTERVAL_MS.
+    /// Once ARP_MAX_RETRIES are used up the waiting packets are dropped,
+    /// each answered with a Host Unreachable out its ingress interface.
+    fn sweep_pending_arp(&mut self, egress: &Egress, now: Instant) {
+        use crate::utils::consts::{ARP_MAX_RETRIES, ARP_RETRY_INTERVAL_MS};
+
+        let interval = Duration::from_millis(ARP_RETRY_INTERVAL_MS);
+        let mut retry = Vec::new();
+        let mut failed = Vec::new();
+        if let Ok(mut pending) = self.pending_packets.write() {
+            pending.retain(|ip, entry| {
+                if now.saturating_duration_since(entry.last_request) < interval {
+                    return true;
+                }
+                if entry.retries < ARP_MAX_RETRIES {
+                    entry.retries += 1;
+                    entry.last_request = now;
+                    if let Some(pkt) = entry.packets.first() {
+                        retry.push((pkt.interface, *ip));
+                    }
+                    return true;
+                }
+                warn!(
+                    "ARP for {} failed, dropping {} packet(s)",
+                    ip,
+                    entry.packets.len()
+                );
+                failed.append(&mut entry.packets);
+                false
+            });
+        }
+
+        for (iface, ip) in retry {
+            debug!("Retrying ARP request for {}", ip);
+            self.send_arp_request(egress, iface, ip);
+        }
+        for pkt in failed {
+            let state = self.icmp_error_state(
+                pkt.ingress,
+                &pkt.packet,
+                Icmpv4Type::DestinationUnreachable(
+                    etherparse::icmpv4::DestUnreachableHeader::Host,
+                ),
+                DropReason::HostUnreachable,
+                "Host unreachable",
+            );
+            self.process(egress, state, pkt.ingress, Some(pkt.ingress));
+        }
+    }
+
+    /// Recalculate IP header checksum
+    fn recalculate_ip_checksum(ip_packet: &mut [u8]) {
+        // Zero out checksum
+        ip_packet[10] = 0;
+        ip_packet[11] = 0;
+
+        let ihl = (ip_packet[0] & 0x0F) as usize;
+        let header_len = ihl * 4;
+
+        let mut sum: u32 = 0;
+        for i in (0..header_len).step_by(2) {
+            let word = u16::from_be_bytes([ip_packet[i], ip_packet[i + 1]]);
+            sum = sum.wrapping_add(word as u32);
+        }
+
+        while (sum >> 16) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+
+        let checksum = !(sum as u16);
+        ip_packet[10] = (checksum >> 8) as u8;
+        ip_packet[11] = (checksum & 0xFF) as u8;
+    }
+
+    /// Recalculate TCP or UDP Checksum (Pseudo-Header + L4 Header + Payload)
+    /// This is required when IP addresses change (NAT).
+    fn recalculate_l4_checksum(
+        packet: &mut [u8],
+        src_ip: Ipv4Addr,
+        dst_ip: Ipv4Addr,
+        protocol: IpNumber,
+    ) {
+        let ihl = (packet[0] & 0x0F) as usize * 4;
+        if packet.len() < ihl {
+            return;
+        }
+
+        let l4_bytes = &mut packet[ihl..];
+
+        // Reset Checksum Field to 0 before calculation
+        match protocol {
+            IpNumber::TCP => {
+                if l4_bytes.len() >= 18 {
+                    l4_bytes[16] = 0;
+                    l4_bytes[17] = 0;
+                } else {
+                    return;
+                }
+            }
+            IpNumber::UDP => {
+                if l4_bytes.len() >= 8 {
+                    l4_bytes[6] = 0;
+                    l4_bytes[7] = 0;
+                } else {
+                    return;
+                }
+            }
+            _ => return,
+        }
+
+        let mut checksum = crate::net::ip::l4_checksum(
+            src_ip.octets(),
+            dst_ip.octets(),
+            protocol.0,
+            l4_bytes,
+        );
+        // A UDP checksum of zero means none was computed
+        if protocol == IpNumber::UDP && checksum == 0 {
+            checksum = 0xFFFF;
+        }
+
+        // Write Checksum
+        match protocol {
+            IpNumber::TCP => {
+                l4_bytes[16] = (checksum >> 8) as u8;
+                l4_bytes[17] = (checksum & 0xFF) as u8;
+            }
+            IpNumber::UDP => {
+                l4_bytes[6] = (checksum >> 8) as u8;
+                l4_bytes[7] = (checksum & 0xFF) as u8;
+            }
+            _ => {}
+        }
+    }
+
+    /// Process packet using etherparse (decrement TTL, rebuild checksums)
     fn process_packet_with_etherparse(
         packet_data: &[u8],
     ) -> Result<Vec<u8>, String> {
@@ -721,6 +1798,131 @@ impl Router {
         result
     }
 
+    /// Ethernet frame answering `requester_ip` at `requester_mac` that
+    /// `source_ip` is at `source_mac`
+    fn prepare_arp_reply(
+        &self,
+        source_mac: [u8; 6],
+        source_ip: Ipv4Addr,
+        requester_mac: [u8; 6],
+        requester_ip: Ipv4Addr,
+    ) -> Vec<u8> {
+        let builder = PacketBuilder::ethernet2(source_mac, requester_mac).arp(
+            ArpPacket::new(
+                ArpHardwareId::ETHERNET,
+                EtherType::IPV4,
+                ArpOperation::REPLY,
+                &source_mac,
+                &source_ip.octets(),
+                &requester_mac,
+                &requester_ip.octets(),
+            )
+            .unwrap(),
+        );
+
+        // get some memory to store the result
+        let mut result = Vec::<u8>::with_capacity(builder.size());
+
+        // serialize
+        builder
+            .write(&mut result)
+            .unwrap();
+
+        debug!("Built ARP reply, len = {}", result.len());
+
+        result
+    }
+
+    /// Check a packet that just came in on `iface` against the firewall.
+    /// None lets it through, otherwise what becomes of it.
+    fn filter(
+        &self,
+        iface: InterfaceType,
+        packet: &[u8],
+    ) -> Option<PacketState> {
+        let header = Ipv4HeaderSlice::from_slice(packet).ok()?;
+        let destination = header.destination_addr();
+        // Only the first fragment carries the ports
+        let port = if header
+            .fragments_offset()
+            .value()
+            == 0
+        {
+            Self::l4_ports(packet, header.slice().len(), header.protocol())
+                .map(|(_, dst_port)| dst_port)
+        } else {
+            None
+        };
+        let out_interface = if self.is_for_us(&destination) {
+            None
+        } else {
+            self.routing_table
+                .read()
+                .ok()
+                .and_then(|table| table.lookup(&destination))
+                .map(|(_, out)| out)
+        };
+        let summary = PacketSummary {
+            source: header.source_addr(),
+            destination,
+            protocol: header.protocol().0,
+            port,
+            in_interface: iface,
+            out_interface,
+        };
+
+        let (index, action) = self
+            .firewall
+            .write()
+            .ok()?
+            .check(&summary)?;
+        let detail = format!("Firewall rule {}", index);
+        match action {
+            FirewallAction::Allow => None,
+            FirewallAction::Deny => Some(PacketState::Dropped {
+                reason: DropReason::Firewall,
+                detail,
+            }),
+            FirewallAction::Reject => Some(self.icmp_error_state(
+                iface,
+                packet,
+                Icmpv4Type::DestinationUnreachable(
+                    etherparse::icmpv4::DestUnreachableHeader::FilterProhibited,
+                ),
+                DropReason::Firewall,
+                &detail,
+            )),
+        }
+    }
+
+    /// Source and destination port of a TCP or UDP packet
+    fn l4_ports(
+        ip_packet: &[u8],
+        ihl: usize,
+        protocol: IpNumber,
+    ) -> Option<(u16, u16)> {
+        let l4 = ip_packet.get(ihl..)?;
+        if protocol == IpNumber::TCP {
+            TcpHeaderSlice::from_slice(l4)
+                .ok()
+                .map(|tcp| (tcp.source_port(), tcp.destination_port()))
+        } else if protocol == IpNumber::UDP {
+            UdpHeaderSlice::from_slice(l4)
+                .ok()
+                .map(|udp| (udp.source_port(), udp.destination_port()))
+        } else {
+            None
+        }
+    }
+
+    fn l4_name(protocol: IpNumber) -> &'static str {
+        if protocol == IpNumber::TCP {
+            "TCP"
+        } else {
+            "UDP"
+        }
+    }
+
     /// Handle inbound NAT. If translated, modifies packet in-place and returns original destination IP.
     fn handle_inbound_nat(&self, ip_packet: &mut Vec<u8>) -> Option<Ipv4Addr> {
         let ip_header = match Ipv4HeaderSlice::from_slice(ip_packet) {
@@ -771,86 +1973,50 @@ impl Router {
                     }
                 }
             }
-        } else if protocol == etherparse::IpNumber::TCP {
-            if let Ok(tcp) = TcpHeaderSlice::from_slice(&ip_packet[ihl..]) {
-                let dst_port = tcp.destination_port();
-                // Lookup session
-                let original_ip_opt =
-                    if let Ok(sessions) = self.nat_sessions.read() {
-                        sessions
-                            .get(&dst_port)
-                            .copied()
-                    } else {
-                        None
-                    };
-
-                if let Some(original_ip) = original_ip_opt {
-                    debug!(
-                        "NAT: Translating TCP Port {} to {}",
-                        dst_port, original_ip
-                    );
-
-                    // Modify Destination IP in IP Header
-                    let original_ip_octets = original_ip.octets();
-                    ip_packet[16] = original_ip_octets[0];
-                    ip_packet[17] = original_ip_octets[1];
-                    ip_packet[18] = original_ip_octets[2];
-                    ip_packet[19] = original_ip_octets[3];
-
-                    // Recalculate IP Checksum
-                    Self::recalculate_ip_checksum(ip_packet);
-
-                    // Recalculate TCP Checksum (Critical!)
-                    // Note: We use the *NEW* destination IP (original_ip) for checksum calculation
-                    Self::recalculate_l4_checksum(
-                        ip_packet,
-                        src_ip,
-                        original_ip,
-                        protocol,
-                    );
-
-                    return Some(original_ip);
-                }
-            }
-        } else if protocol == etherparse::IpNumber::UDP {
-            if let Ok(udp) = UdpHeaderSlice::from_slice(&ip_packet[ihl..]) {
-                let dst_port = udp.destination_port();
-                // Lookup session
-                let original_ip_opt =
-                    if let Ok(sessions) = self.nat_sessions.read() {
-                        sessions
-                            .get(&dst_port)
-                            .copied()
-                    } else {
-                        None
-                    };
-
-                if let Some(original_ip) = original_ip_opt {
-                    debug!(
-                        "NAT: Translating UDP Port {} to {}",
-                        dst_port, original_ip
-                    );
-
-                    // Modify Destination IP in IP Header
-                    let original_ip_octets = original_ip.octets();
-                    ip_packet[16] = original_ip_octets[0];
-                    ip_packet[17] = original_ip_octets[1];
-                    ip_packet[18] = original_ip_octets[2];
-                    ip_packet[19] = original_ip_octets[3];
-
-                    // Recalculate IP Checksum
-                    Self::recalculate_ip_checksum(ip_packet);
-
-                    // Recalculate UDP Checksum
-                    Self::recalculate_l4_checksum(
-                        ip_packet,
-                        src_ip,
-                        original_ip,
-                        protocol,
-                    );
-
-                    return Some(original_ip);
-                }
+        } else if (protocol == etherparse::IpNumber::TCP
+            || protocol == etherparse::IpNumber::UDP)
+            && let Some((src_port, dst_port)) =
+                Self::l4_ports(ip_packet, ihl, protocol)
+        {
+            // Lookup the flow the inside opened
+            let remote = SocketAddrV4::new(src_ip, src_port);
+            let tcp = TcpFlags::of(protocol, &ip_packet[ihl..]);
+            let now = self.clock.now();
+            let internal_opt = self
+                .napt
+                .write()
+                .ok()
+                .and_then(|mut napt| {
+                    napt.inbound(protocol, dst_port, remote, tcp, now)
+                });
+
+            if let Some(internal) = internal_opt {
+                debug!(
+                    "NAPT: Translating {} Port {} to {}",
+                    Self::l4_name(protocol),
+                    dst_port,
+                    internal
+                );
+
+                // Modify Destination IP in IP Header and Port in L4 Header
+                let original_ip = *internal.ip();
+                ip_packet[16..20].copy_from_slice(&original_ip.octets());
+                ip_packet[ihl + 2..ihl + 4]
+                    .copy_from_slice(&internal.port().to_be_bytes());
+
+                // Recalculate IP Checksum
+                Self::recalculate_ip_checksum(ip_packet);
+
+                // Recalculate TCP/UDP Checksum (Critical!)
+                // Note: We use the *NEW* destination IP (original_ip) for checksum calculation
+                Self::recalculate_l4_checksum(
+                    ip_packet,
+                    src_ip,
+                    original_ip,
+                    protocol,
+                );
+
+                return Some(original_ip);
             }
         }
 
@@ -887,7 +2053,8 @@ impl Router {
             if *offset + len > payload.len() {
                 return None;
             }
-            let label = std::str::from_utf8(&payload[*offset..*offset + len]).ok()?;
+            let label =
+                std::str::from_utf8(&payload[*offset..*offset + len]).ok()?;
             labels.push(label.to_string());
             *offset += len;
         }
@@ -895,43 +2062,55 @@ impl Router {
     }
 
     fn build_dns_response(&self, payload: &[u8]) -> Option<Vec<u8>> {
-        if payload.len() < 12 { return None; }
-        
+        if payload.len() < 12 {
+            return None;
+        }
+
         // Transaction ID
         let id_bytes = [payload[0], payload[1]];
         let flags = u16::from_be_bytes([payload[2], payload[3]]);
         // Check if it is a query (QR bit = 0) and Opcode = 0 (Standard Query)
-        if (flags & 0x8000) != 0 { return None; } 
+        if (flags & 0x8000) != 0 {
+            return None;
+        }
 
         let qdcount = u16::from_be_bytes([payload[4], payload[5]]);
-        if qdcount != 1 { return None; } // Only handle single question
+        if qdcount != 1 {
+            return None;
+        } // Only handle single question
 
         let mut offset = 12;
         let domain_name = Self::parse_dns_name(payload, &mut offset)?;
 
         // Skip QTYPE and QCLASS
-        if offset + 4 > payload.len() { return None; }
-        let qtype = u16::from_be_bytes([payload[offset], payload[offset + 1]]);
-        let qclass = u16::from_be_bytes([payload[offset + 2], payload[offset + 3]]);
+        if offset + 4 > payload.len() {
+            return None;
+        }
+        let qtype = u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+        let qclass =
+            u16::from_be_bytes([payload[offset + 2], payload[offset + 3]]);
         offset += 4;
 
-        info!("DNS Query for: {} (Type: {}, Class: {})", domain_name, qtype, qclass);
+        info!(
+            "DNS Query for: {} (Type: {}, Class: {})",
+            domain_name, qtype, qclass
+        );
 
         // Only handle A records (Type 1) and IN class (1)
         if qtype != 1 || qclass != 1 {
-             // Return No Error but no answer? Or unimplemented?
-             // Let's just ignore for now or implement "Refused" later
-             // For now, construct response with 0 answers
-             let mut response = Vec::new();
-             response.extend_from_slice(&id_bytes);
-             response.extend_from_slice(&0x8180u16.to_be_bytes()); // Standard response, No error
-             response.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
-             response.extend_from_slice(&0u16.to_be_bytes()); // ANCOUNT
-             response.extend_from_slice(&0u16.to_be_bytes());
-             response.extend_from_slice(&0u16.to_be_bytes());
-             // Copy question
-             response.extend_from_slice(&payload[12..offset]);
-             return Some(response);
+            // Return No Error but no answer? Or unimplemented?
+            // Let's just ignore for now or implement "Refused" later
+            // For now, construct response with 0 answers
+            let mut response = Vec::new();
+            response.extend_from_slice(&id_bytes);
+            response.extend_from_slice(&0x8180u16.to_be_bytes()); // Standard response, No error
+            response.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+            response.extend_from_slice(&0u16.to_be_bytes()); // ANCOUNT
+            response.extend_from_slice(&0u16.to_be_bytes());
+            response.extend_from_slice(&0u16.to_be_bytes());
+            // Copy question
+            response.extend_from_slice(&payload[12..offset]);
+            return Some(response);
         }
 
         // Lookup
@@ -955,7 +2134,7 @@ impl Router {
             response.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
             response.extend_from_slice(&0u16.to_be_bytes()); // ANCOUNT
         }
-        
+
         response.extend_from_slice(&0u16.to_be_bytes()); // NSCOUNT
         response.extend_from_slice(&0u16.to_be_bytes()); // ARCOUNT
 
@@ -964,13 +2143,13 @@ impl Router {
 
         // Add Answer Section if resolved
         if let Some(ip) = ip_opt {
-             // Name ptr (0xC00C points to start of name in header)
-             response.extend_from_slice(&0xC00Cu16.to_be_bytes()); 
-             response.extend_from_slice(&1u16.to_be_bytes()); // TYPE A
-             response.extend_from_slice(&1u16.to_be_bytes()); // CLASS IN
-             response.extend_from_slice(&300u32.to_be_bytes()); // TTL 300s
-             response.extend_from_slice(&4u16.to_be_bytes()); // RDLENGTH 4
-             response.extend_from_slice(&ip.octets()); // RDATA
+            // Name ptr (0xC00C points to start of name in header)
+            response.extend_from_slice(&0xC00Cu16.to_be_bytes());
+            response.extend_from_slice(&1u16.to_be_bytes()); // TYPE A
+            response.extend_from_slice(&1u16.to_be_bytes()); // CLASS IN
+            response.extend_from_slice(&300u32.to_be_bytes()); // TTL 300s
+            response.extend_from_slice(&4u16.to_be_bytes()); // RDLENGTH 4
+            response.extend_from_slice(&ip.octets()); // RDATA
         }
 
         // FIXME: temp sleep to prevent AAAA record
@@ -979,7 +2158,7 @@ impl Router {
         Some(response)
     }
 
-    /// Run the router
+    /// Run the router until `stop` is called
     pub fn run(
         &mut self,
         shared: AppShared,
@@ -1004,6 +2183,22 @@ impl Router {
             "Traversal Targets: NODE3={}, NODE1={}",
             self.config.node3_ip, self.config.node1_ip
         );
+        if let Ok(table) = self.routing_table.read() {
+            for route in table.list_routes() {
+                info!("Route: {}", route);
+            }
+        }
+        if let Ok(firewall) = self.firewall.read() {
+            for (i, (rule, _)) in firewall.rules().enumerate() {
+                info!("Firewall rule {}: {}", i, rule);
+            }
+        }
+        if self.gateway_mac().is_none() {
+            info!(
+                "Gateway {}: MAC unknown, resolving it by ARP on eth",
+                self.config.gateway_ip
+            );
+        }
 
         // Open WiFi device
         let wifi_device = crate::net::pcap_utils::get_device_by_name(
@@ -1017,7 +2212,24 @@ impl Router {
             sample_rate,
             line_coding,
             self.config.acoustic_mac,
+            &self.config.phy,
+            &self.config.mac,
         );
+        acoustic_interface.set_discovery(
+            Duration::from_millis(crate::utils::consts::BEACON_INTERVAL_MS),
+            Some(self.config.acoustic_ip),
+        );
+        if let Some(path) = &self.config.capture {
+            let capture = crate::net::pcap_utils::PcapWriter::create(
+                path,
+                Some(crate::utils::consts::CAPTURE_ROTATE_BYTES),
+            )
+            .map_err(|e| format!("Failed to open capture {}: {}", path, e))?;
+            acoustic_interface.set_capture(capture);
+        }
+        if let Some(dashboard) = self.config.dashboard.clone() {
+            acoustic_interface.set_dashboard(dashboard);
+        }
 
         // Open Ethernet device
         let eth_device = if self.config.gateway_interface
@@ -1050,284 +2262,339 @@ impl Router {
 
         let tun_device = tun::create(&tun_config)
             .map_err(|e| format!("Failed to create TUN device: {}", e))?;
-
-        info!("Router is running. Press Ctrl+C to stop.");
-
-        // Channels for inter-thread communication
-        let (to_acoustic_tx, to_acoustic_rx) =
-            crossbeam_channel::unbounded::<(Vec<u8>, u8)>();
-        let (to_wifi_tx, to_wifi_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
-        let (to_eth_tx, to_eth_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
-        let (to_tun_tx, to_tun_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
-        let (to_router_tx, to_router_rx) =
-            crossbeam_channel::unbounded::<(Vec<u8>, InterfaceType)>();
-
-        // Spawn TUN Threads
-        let tun_to_router = to_router_tx.clone();
-        let running = self.running.clone();
-
-        // Split TUN device
         let (mut tun_reader, mut tun_writer) = tun_device.split();
-
-        // TUN RX (Read from TUN -> Send to Router)
-        let tun_rx_handle = thread::spawn(move || {
-            let mut buf = [0u8; 1504];
-            while running
-                .lock()
-                .unwrap()
-                .load(Ordering::SeqCst)
+        let tun_rx: LinkRx = Box::new(move || {
+            // Wait with a timeout, so the thread notices stop()
+            let poll = Duration::from_millis(100);
+            if !crate::net::tun::wait_readable(&tun_reader, poll)
+                .map_err(|e| e.to_string())?
             {
-                // Blocking read is fine here as long as tun_reader supports it properly
-                match std::io::Read::read(&mut tun_reader, &mut buf) {
-                    Ok(n) => {
-                        if n > 0 {
-                            let packet = buf[..n].to_vec();
-                            tun_to_router
-                                .send((packet, InterfaceType::Tun))
-                                .unwrap();
-                        }
-                    }
-                    Err(e) => {
-                        // If it is a temp error or timeout, we might continue
-                        // For a real error, we might log and break
-                        match e.kind() {
-                            std::io::ErrorKind::WouldBlock
-                            | std::io::ErrorKind::TimedOut => {
-                                // Short sleep to avoid tight loop on non-blocking error
-                                thread::sleep(Duration::from_millis(10));
-                            }
-                            _ => {
-                                warn!("TUN read error: {}", e);
-                                thread::sleep(Duration::from_millis(100));
-                            }
-                        }
-                    }
-                }
+                return Ok(None);
             }
-        });
-
-        // TUN TX (Read from Channel -> Write to TUN)
-        // Optimized: Use blocking iterator instead of busy waiting loop
-        let tun_tx_handle = thread::spawn(move || {
-            // This loop automatically terminates when to_tun_tx is dropped
-            for packet in to_tun_rx {
-                info!("Writing packet to TUN device (len={})", packet.len());
-                if let Err(e) =
-                    std::io::Write::write_all(&mut tun_writer, &packet)
+            let mut buf = [0u8; 1504];
+            match std::io::Read::read(&mut tun_reader, &mut buf) {
+                Ok(0) => Ok(None),
+                Ok(n) => Ok(Some(buf[..n].to_vec())),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock
+                            | std::io::ErrorKind::TimedOut
+                    ) =>
                 {
-                    warn!("Failed to write to TUN: {}", e);
+                    Ok(None)
                 }
+                Err(e) => Err(format!("TUN read error: {}", e)),
             }
-            debug!("TUN TX thread stopping");
+        });
+        let tun_tx: LinkTx = Box::new(move |packet| {
+            info!("Writing packet to TUN device (len={})", packet.len());
+            std::io::Write::write_all(&mut tun_writer, packet)
+                .map_err(|e| e.to_string())
         });
 
-        // WiFi Hotspot RX
-
-        // Spawn WiFi Thread
-        let router_wifi = self.clone(); // Clone is now cheap and safe (shares Arc<RwLock>)
-        let running = self.running.clone();
-        let wifi_to_router = to_router_tx.clone();
-
-        let mut wifi_capture =
+        // WiFi, only IP and ARP (including TCP, UDP)
+        let mut wifi_recv =
             crate::net::pcap_utils::open_capture(wifi_device.clone())
                 .map_err(|e| format!("Failed to open WiFi capture: {}", e))?;
-
-        // Set filter to only capture IP packets (including TCP, UDP)
-        wifi_capture
+        wifi_recv
             .filter("icmp or arp or tcp or udp", true)
             .map_err(|e| format!("Failed to set filter: {}", e))?;
+        let mut wifi_send = crate::net::pcap_utils::open_capture(wifi_device)
+            .map_err(|e| format!("Failed to open WiFi capture: {}", e))?;
+        let wifi_mac = self.config.wifi_mac;
+        let wifi: (LinkRx, LinkTx) = (
+            Box::new(move || match wifi_recv.next_packet() {
+                Ok(packet) => Ok(Self::frame_for(packet.data, wifi_mac)),
+                // Timeout allows checking `running` flag
+                Err(pcap::Error::TimeoutExpired) => Ok(None),
+                Err(e) => Err(format!("WiFi capture error: {}", e)),
+            }),
+            Box::new(move |frame| {
+                info!("WiFi sent");
+                wifi_send
+                    .sendpacket(frame)
+                    .map_err(|e| e.to_string())
+            }),
+        );
+
+        let mut eth = None;
+        if let Some(main_device) = eth_device
+            && main_device.name != self.config.wifi_interface
+        {
+            let mut gateway_send =
+                crate::net::pcap_utils::open_capture(main_device.clone())
+                    .map_err(|e| {
+                        format!("Failed to open Ethernet capture: {}", e)
+                    })?;
+            let mut gateway_recv = crate::net::pcap_utils::open_capture(
+                main_device,
+            )
+            .map_err(|e| format!("Failed to open Ethernet capture: {}", e))?;
+            gateway_recv
+                .filter("icmp or arp or tcp or udp", true)
+                .unwrap();
+            let eth_mac = self.config.eth_mac;
+            let links: (LinkRx, LinkTx) = (
+                Box::new(move || match gateway_recv.next_packet() {
+                    Ok(packet) => Ok(Self::frame_for(packet.data, eth_mac)),
+                    Err(pcap::Error::TimeoutExpired) => Ok(None),
+                    Err(e) => Err(format!("Ethernet capture error: {}", e)),
+                }),
+                Box::new(move |frame| {
+                    gateway_send
+                        .sendpacket(frame)
+                        .map_err(|e| e.to_string())
+                }),
+            );
+            eth = Some(links);
+        }
 
-        let wifi_rx_handle = thread::spawn(move || {
+        info!("Router is running. Press Ctrl+C to stop.");
+        let result = self.serve(RouterLinks {
+            acoustic: acoustic_interface,
+            wifi,
+            eth,
+            tun: (tun_rx, tun_tx),
+        });
+        // The device went with the last of its threads
+        info!("Removed TUN device {}", self.config.tun_name);
+        result
+    }
+
+    /// The payload of an Ethernet frame sent to `mac` or broadcast, None
+    /// for anything else, our own frames included
+    fn frame_for(frame: &[u8], mac: [u8; 6]) -> Option<Vec<u8>> {
+        let (payload, src_mac, dst_mac, _ethertype) =
+            Self::parse_ethernet_frame(frame)?;
+        if src_mac == mac || (dst_mac != mac && dst_mac != [0xff; 6]) {
+            return None;
+        }
+        trace!("RX Packet for {:02x?} from {:02x?}", mac, src_mac);
+        Some(payload)
+    }
+
+    /// Move packets between `links` and the routing logic until the
+    /// running flag `run` set is cleared, then wait for every thread
+    fn serve(&mut self, links: RouterLinks) -> Result<(), String> {
+        let RouterLinks {
+            acoustic: mut acoustic_interface,
+            wifi,
+            eth,
+            tun,
+        } = links;
+        let mut handles: Vec<(String, thread::JoinHandle<()>)> = Vec::new();
+
+        // Management socket, the router runs on without one
+        if let Some(addr) = self.config.management {
+            match std::net::TcpListener::bind(addr) {
+                Ok(listener) => {
+                    info!("Management socket on {}", addr);
+                    let tables = self.managed_tables();
+                    let running = self.running.clone();
+                    let handle = thread::spawn(move || {
+                        management::serve(listener, tables, || {
+                            running
+                                .lock()
+                                .unwrap()
+                                .load(Ordering::SeqCst)
+                        })
+                    });
+                    handles.push(("Management".to_string(), handle));
+                }
+                Err(e) => {
+                    warn!("No management socket on {}: {}", addr, e)
+                }
+            }
+        }
+
+        metrics::global().register("router", self.metrics_collector());
+
+        // Traffic summary every ROUTER_COUNTERS_INTERVAL_SECS
+        let counters = self.counters();
+        let running = self.running.clone();
+        let handle = thread::spawn(move || {
+            use crate::utils::consts::{
+                ROUTER_COUNTERS_INTERVAL_SECS, ROUTER_TOP_FLOWS,
+            };
+            let interval = Duration::from_secs(ROUTER_COUNTERS_INTERVAL_SECS);
+            let mut last = Instant::now();
             while running
                 .lock()
                 .unwrap()
                 .load(Ordering::SeqCst)
             {
-                // 1. Read from WiFi (with timeout)
-                // pcap `next_packet` is blocking or has timeout set in open_capture
-                match wifi_capture.next_packet() {
-                    Ok(packet) => {
-                        if let Some((ip_packet, src_mac, dst_mac, _ethertype)) =
-                            Self::parse_ethernet_frame(packet.data)
-                        {
-                            if dst_mac == router_wifi.config.wifi_mac
-                                || dst_mac == [0xff; 6]
+                thread::sleep(Duration::from_millis(500));
+                if last.elapsed() >= interval {
+                    counters.log_summary(ROUTER_TOP_FLOWS);
+                    last = Instant::now();
+                }
+            }
+        });
+        handles.push(("Counters".to_string(), handle));
+
+        // Channels for inter-thread communication
+        let (to_acoustic_tx, to_acoustic_rx) =
+            crossbeam_channel::unbounded::<(Vec<u8>, u8)>();
+        let (to_wifi_tx, to_wifi_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let (to_eth_tx, to_eth_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let (to_tun_tx, to_tun_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let (to_router_tx, to_router_rx) =
+            crossbeam_channel::unbounded::<(Vec<u8>, InterfaceType)>();
+
+        // An RX thread per wired link, reading until stop(), and a TX
+        // thread writing until the main loop drops the senders
+        let mut wired = vec![
+            (InterfaceType::Tun, tun, to_tun_rx),
+            (InterfaceType::WiFi, wifi, to_wifi_rx),
+        ];
+        match eth {
+            Some(eth) => wired.push((InterfaceType::Ethernet, eth, to_eth_rx)),
+            // Nothing goes out eth
+            None => drop(to_eth_rx),
+        }
+        for (iface, (mut rx, mut tx), from_router) in wired {
+            let running = self.running.clone();
+            let to_router = to_router_tx.clone();
+            let handle = thread::spawn(move || {
+                while running
+                    .lock()
+                    .unwrap()
+                    .load(Ordering::SeqCst)
+                {
+                    match rx() {
+                        Ok(Some(packet)) => {
+                            if to_router
+                                .send((packet, iface))
+                                .is_err()
                             {
-                                debug!(
-                                    "WiFi RX Packet for us from {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-                                    src_mac[0],
-                                    src_mac[1],
-                                    src_mac[2],
-                                    src_mac[3],
-                                    src_mac[4],
-                                    src_mac[5]
-                                );
-                                wifi_to_router
-                                    .send((
-                                        ip_packet.clone(),
-                                        InterfaceType::WiFi,
-                                    ))
-                                    .unwrap();
+                                break;
                             }
                         }
-                    }
-                    Err(pcap::Error::TimeoutExpired) => {
-                        // Timeout allows checking `running` flag
-                    }
-                    Err(e) => {
-                        warn!("WiFi capture error: {}", e);
-                        thread::sleep(Duration::from_millis(100)); // Prevent tight loop on error
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("{}", e);
+                            // Prevent tight loop on error
+                            thread::sleep(Duration::from_millis(100));
+                        }
                     }
                 }
-            }
-        });
-
-        // WiFi TX Thread
-        // Optimized: Use blocking iterator
-        let mut wifi_capture = crate::net::pcap_utils::open_capture(wifi_device)
-            .map_err(|e| format!("Failed to open WiFi capture: {}", e))?;
-        let wifi_tx_handle = thread::spawn(move || {
-            // Loop until channel is closed
-            for frame in to_wifi_rx {
-                info!("WiFi sent");
-                if let Err(e) = wifi_capture.sendpacket(frame) {
-                    warn!("Failed to send packet to WiFi: {}", e);
+                debug!("{} RX thread stopping", iface.name());
+            });
+            handles.push((format!("{} RX", iface.name()), handle));
+            let handle = thread::spawn(move || {
+                // Ends once the main loop drops the sender
+                for packet in from_router {
+                    if let Err(e) = tx(&packet) {
+                        warn!("Failed to send to {}: {}", iface.name(), e);
+                    }
                 }
-            }
-            debug!("WiFi TX thread stopping");
-        });
+                debug!("{} TX thread stopping", iface.name());
+            });
+            handles.push((format!("{} TX", iface.name()), handle));
+        }
 
         // Spawn Acoustic Thread
         let running = self.running.clone();
         let acoustic_to_router = to_router_tx.clone();
-        let acoustic_handle = thread::spawn(move || {
+        let link_stats = self.link_stats.clone();
+        let router_acoustic = self.clone();
+        let handle = thread::spawn(move || {
+            let stats_interval = Duration::from_secs(
+                crate::utils::consts::ROUTER_STATS_INTERVAL_SECS,
+            );
+            let mut last_stats = std::time::Instant::now();
             while running
                 .lock()
                 .unwrap()
                 .load(Ordering::SeqCst)
             {
+                if last_stats.elapsed() >= stats_interval {
+                    let stats = acoustic_interface.stats();
+                    info!(
+                        "Acoustic link (last {}s): sent={} received={} retransmissions={} ack_timeouts={} crc_failures={} avg_rtt={:?} neighbors={}",
+                        stats_interval.as_secs(),
+                        stats.frames_sent,
+                        stats.frames_received,
+                        stats.retransmissions,
+                        stats.ack_timeouts,
+                        stats.crc_failures,
+                        stats.average_rtt(),
+                        acoustic_interface
+                            .neighbors()
+                            .len()
+                    );
+                    acoustic_interface.reset_stats();
+                    last_stats = std::time::Instant::now();
+                }
+                if let Ok(mut shared) = link_stats.lock() {
+                    *shared = acoustic_interface.stats();
+                }
+
                 // 1. Read from Acoustic (non-blocking/timeout)
-                // Assuming receive_packet has internal timeout logic
-                match acoustic_interface
-                    .receive_packet(Some(Duration::from_millis(10)))
+                // receive_packet_from has internal timeout logic
+                if let Ok((ip_packet, src)) = acoustic_interface
+                    .receive_packet_from(Some(Duration::from_millis(10)))
                 {
-                    Ok(ip_packet) => {
-                        acoustic_to_router
-                            .send((ip_packet.clone(), InterfaceType::Acoustic))
-                            .unwrap();
-                    }
-                    Err(_) => {
-                        // Timeout or error, just continue
+                    router_acoustic.heard_on_acoustic(src);
+                    if acoustic_to_router
+                        .send((ip_packet, InterfaceType::Acoustic))
+                        .is_err()
+                    {
+                        break;
                     }
                 }
 
                 // 2. Send to Acoustic
                 // Use try_recv here because we are in a loop handling both RX and TX in one thread.
                 // This is a specific design for acoustic interface which might be half-duplex or single-threaded.
+                let mut queued_for = Vec::new();
                 while let Ok((ip_packet, dest_mac)) = to_acoustic_rx.try_recv() {
-                    // thread::sleep(Duration::from_millis(20));
-                    if let Err(e) = acoustic_interface.send_packet(
+                    match acoustic_interface.enqueue_packet(
                         &ip_packet,
                         dest_mac,
                         FrameType::Data,
                     ) {
-                        warn!("Failed to send packet to Acoustic: {}", e);
-                    }
-                }
-            }
-        });
-
-        let mut gateway_tx_handle: Option<thread::JoinHandle<()>> = None;
-        let mut gateway_rx_handle: Option<thread::JoinHandle<()>> = None;
-
-        if let Some(main_device) = eth_device {
-            if main_device.name != self.config.wifi_interface {
-                // Gateway TX
-                // Optimized: Use blocking iterator
-                let mut gateway_send =
-                    crate::net::pcap_utils::open_capture(main_device.clone())
-                        .map_err(|e| {
-                            format!("Failed to open Ethernet capture: {}", e)
-                        })?;
-
-                gateway_tx_handle = Some(thread::spawn(move || {
-                    for frame in to_eth_rx {
-                        // info!("Gateway sent");
-                        if let Err(e) = gateway_send.sendpacket(frame) {
-                            warn!("Failed to send packet to Ethernet: {}", e);
+                        Ok(()) if !queued_for.contains(&dest_mac) => {
+                            queued_for.push(dest_mac)
                         }
-                    }
-                    debug!("Ethernet TX thread stopping");
-                }));
-
-                // Gateway RX
-                let network_router = self.clone(); // Clone Safe Router
-                let eth_to_router = to_router_tx.clone();
-                let mut gateway_recv =
-                    crate::net::pcap_utils::open_capture(main_device).map_err(
-                        |e| format!("Failed to open Ethernet capture: {}", e),
-                    )?;
-                gateway_recv
-                    .filter("icmp or arp or tcp or udp", true)
-                    .unwrap();
-                let running = self.running.clone();
-                gateway_rx_handle = Some(thread::spawn(move || {
-                    while running
-                        .lock()
-                        .unwrap()
-                        .load(Ordering::SeqCst)
-                    {
-                        match gateway_recv.next_packet() {
-                            Ok(packet) => {
-                                if let Some((
-                                    ip_packet,
-                                    src_mac,
-                                    dst_mac,
-                                    _ethertype,
-                                )) = Self::parse_ethernet_frame(packet.data)
-                                {
-                                    if src_mac == network_router.config.eth_mac {
-                                        // Ignore packets sent by ourselves
-                                        continue;
-                                    }
-                                    if dst_mac == network_router.config.eth_mac
-                                        || dst_mac == [0xff; 6]
-                                    {
-                                        trace!(
-                                            "Ethernet RX Packet for us from {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-                                            src_mac[0],
-                                            src_mac[1],
-                                            src_mac[2],
-                                            src_mac[3],
-                                            src_mac[4],
-                                            src_mac[5]
-                                        );
-                                        eth_to_router
-                                            .send((
-                                                ip_packet.clone(),
-                                                InterfaceType::Ethernet,
-                                            ))
-                                            .unwrap();
-                                    }
-                                }
-                            }
-                            Err(pcap::Error::TimeoutExpired) => {
-                                // Timeout, check for outgoing packets
-                            }
-                            Err(e) => {
-                                warn!("Ethernet capture error: {}", e);
-                                thread::sleep(Duration::from_millis(100));
-                            }
+                        Ok(()) => {}
+                        Err(e) => {
+                            warn!("Failed to queue packet for Acoustic: {}", e);
+                            router_acoustic
+                                .record_acoustic_send(dest_mac, false);
                         }
                     }
-                }));
+                }
+                let sent = acoustic_interface.flush_queue();
+                if let Err(e) = &sent {
+                    warn!("Failed to send packet to Acoustic: {}", e);
+                }
+                for dest_mac in queued_for {
+                    router_acoustic.record_acoustic_send(dest_mac, sent.is_ok());
+                }
+
+                // 3. Announce ourselves while the link is quiet
+                if let Err(e) = acoustic_interface.send_beacon_if_due() {
+                    warn!("Failed to send beacon: {}", e);
+                }
             }
-        }
+            debug!("acoustic thread stopping");
+        });
+        handles.push(("Acoustic".to_string(), handle));
+        // Only the RX threads hold senders now
+        drop(to_router_tx);
 
         // Main Router Loop
         let mut router_main = self.clone();
         let running = self.running.clone();
-        let main_handle = thread::spawn(move || {
+        let egress = Egress {
+            acoustic: to_acoustic_tx,
+            wifi: to_wifi_tx,
+            eth: to_eth_tx,
+            tun: to_tun_tx,
+        };
+        let handle = thread::spawn(move || {
             while running
                 .lock()
                 .unwrap()
@@ -1335,14 +2602,10 @@ impl Router {
             {
                 // Receive packets from interfaces
                 // Uses recv_timeout so we can periodically check `running` flag
-                // Alternatively, we could just block on recv() if we dropping the sender was the only stop mechanism.
-                match to_router_rx.recv_timeout(Duration::from_secs(1)) {
+                match to_router_rx.recv_timeout(Duration::from_millis(100)) {
                     Ok((ip_packet, src_interface)) => {
                         router_main.handle_packet(
-                            &to_acoustic_tx,
-                            &to_wifi_tx,
-                            &to_eth_tx,
-                            &to_tun_tx,
+                            &egress,
                             ip_packet,
                             src_interface,
                         );
@@ -1355,42 +2618,35 @@ impl Router {
                         break; // Channel disconnected
                     }
                 }
+                let now = router_main.clock.now();
+                router_main.sweep_pending_arp(&egress, now);
+                router_main.resolve_gateway(&egress, now);
+                router_main.probe_down_hops(&egress, now);
+                if let Ok(mut table) = router_main.arp_table.write() {
+                    table.expire(now);
+                }
+                if let Ok(mut napt) = router_main.napt.write() {
+                    let expired = napt.expire(now);
+                    if expired > 0 {
+                        debug!("NAPT: {} idle flows expired", expired);
+                    }
+                }
+                if let Ok(mut reassembler) = router_main.reassembler.lock() {
+                    reassembler.expire(now);
+                }
             }
+            // Closes the TX channels, the TX threads finish what is queued
+            drop(egress);
             debug!("Main router loop stopping");
         });
+        handles.push(("Router main".to_string(), handle));
 
-        // Wait for threads to finish
-        // Note: RX threads typically need an external signal or loop check to stop.
-        // TX threads will stop when the main_handle drops the senders (which happens when main_handle finishes).
-
-        if let Err(e) = wifi_rx_handle.join() {
-            warn!("WiFi RX thread panicked: {:?}", e);
-        }
-        if let Err(e) = wifi_tx_handle.join() {
-            warn!("WiFi RX thread panicked: {:?}", e);
-        }
-        if let Some(handle) = gateway_tx_handle {
-            if let Err(e) = handle.join() {
-                warn!("Ethernet TX thread panicked: {:?}", e);
-            }
-        }
-        if let Some(handle) = gateway_rx_handle {
+        for (name, handle) in handles {
             if let Err(e) = handle.join() {
-                warn!("Ethernet RX thread panicked: {:?}", e);
+                warn!("{} thread panicked: {:?}", name, e);
             }
         }
-        if let Err(e) = tun_rx_handle.join() {
-            warn!("TUN RX thread panicked: {:?}", e);
-        }
-        if let Err(e) = tun_tx_handle.join() {
-            warn!("TUN TX thread panicked: {:?}", e);
-        }
-        if let Err(e) = acoustic_handle.join() {
-            warn!("Acoustic thread panicked: {:?}", e);
-        }
-        if let Err(e) = main_handle.join() {
-            warn!("Router main thread panicked: {:?}", e);
-        }
+        metrics::global().unregister("router");
 
         info!("Router stopped.");
         Ok(())
@@ -1415,143 +2671,97 @@ impl Router {
             return;
         }
 
-        // 2. Parse the IP header to get context
-        let (header, payload_offset) = match Ipv4HeaderSlice::from_slice(&packet)
+        // 2. Split, keeping the datagram's identification
+        let fragments = match IpFragmenter::new(mtu).fragment_forwarded(&packet)
         {
-            Ok(h) => (h.to_header(), h.slice().len()),
+            Ok(fragments) => fragments,
             Err(e) => {
-                warn!("Cannot fragment non-IPv4 packet: {}", e);
+                warn!("Cannot fragment packet: {}", e);
                 return;
             }
         };
-
-        // Don't fragment if DF (Don't Fragment) bit is set?
-        // For acoustic links, we usually ignore DF because we MUST fragment.
-
-        let full_payload = &packet[payload_offset..];
-        let header_len = payload_offset; // usually 20 bytes
-
-        // Calculate max payload size per fragment.
-        // MUST be a multiple of 8 (IP spec requirement), except for the last fragment.
-        // Formula: floor((MTU - HeaderLen) / 8) * 8
-        let max_frag_payload = ((mtu - header_len) / 8) * 8;
-
-        if max_frag_payload == 0 {
-            error!("MTU {} is too small for IP header overhead!", mtu);
-            return;
-        }
-
-        let total_len = full_payload.len();
-        let mut offset = 0;
-
         info!(
-            "Fragmenting {} bytes into chunks of {} bytes (MTU {})",
-            total_len, max_frag_payload, mtu
-        );
-
-        // 3. Loop to create fragments
-        // 3. Loop to create fragments
-        while offset < total_len {
-            // Determine size of this chunk
-            let remaining = total_len - offset;
-            let len = std::cmp::min(remaining, max_frag_payload);
-            let is_last_fragment = (offset + len) == total_len;
-
-            // Prepare new Header based on original
-            let mut frag_header = header.clone();
-
-            // --- FIX START ---
-            // 关键修改 1: 清除 DF 标志位
-            // 如果不清除，MF=1 + DF=1 组合会被内核视为非法包丢弃
-            frag_header.dont_fragment = false;
-            // --- FIX END ---
-
-            // Set Flags: Keep MF (More Fragments) if original had it, OR if this isn't the last chunk
-            let original_mf = header.more_fragments;
-            frag_header.more_fragments = original_mf || !is_last_fragment;
-
-            // Set Offset: Original Offset + Current Offset / 8
-            // 修复说明: 先取出 u16 值进行计算，再转换回 IpFragOffset
-            let current_offset_val = header.fragment_offset.value();
-            let added_offset = (offset as u16 / 8);
-
-            frag_header.fragment_offset = etherparse::IpFragOffset::try_new(
-                current_offset_val + added_offset,
-            )
-            .unwrap_or_else(|_| {
-                // 如果 offset 溢出 (超过 13 bit)，这是一个极端的异常情况
-                warn!("Fragment offset overflow!");
-                etherparse::IpFragOffset::try_new(0).unwrap()
-            });
-
-            // Set Total Length
-            frag_header.total_len = (header_len + len) as u16;
-
-            let mut frag_packet = Vec::with_capacity(header_len + len);
-
-            // Update checksum (etherparse write does NOT do this automatically)
-            frag_header.header_checksum = frag_header.calc_header_checksum();
-
-            // Write header
-            if let Err(e) = frag_header.write(&mut frag_packet) {
-                warn!("Failed to write frag header: {}", e);
-                return;
-            }
-
-            info!(
-                "frag: off={} mf={} len={} checksum=0x{:04x}",
-                frag_header
-                    .fragment_offset
-                    .value(),
-                frag_header.more_fragments,
-                frag_header.total_len,
-                frag_header.header_checksum
-            );
-
-            // Copy payload chunk
-            frag_packet.extend_from_slice(&full_payload[offset..offset + len]);
-
-            // --- FIX START ---
-            // 关键修改 2: 移除手动重算 Checksum
-            // Self::recalculate_ip_checksum(&mut frag_packet); // <--- 已移除，防止覆盖正确的 Checksum
-            // --- FIX END ---
+            "Fragmenting {} bytes into {} fragments (MTU {})",
+            packet.len(),
+            fragments.len(),
+            mtu
+        );
 
-            // 4.0. Monitor to TUN
+        for frag_packet in fragments {
+            // 3.0. Monitor to TUN
             if let Err(e) = to_tun.send(frag_packet.clone()) {
                 warn!("Failed to send packet to TUN thread: {}", e);
             }
 
-            // 4.1 Send fragment
+            // 3.1 Send fragment
             if let Err(e) = to_channel.send((frag_packet, dest_mac_byte)) {
                 warn!("Failed to send fragment: {}", e);
                 break;
             }
-
-            offset += len;
         }
     }
 
     fn handle_packet(
         &mut self,
-        to_acoustic: &crossbeam_channel::Sender<(Vec<u8>, u8)>,
-        to_wifi: &crossbeam_channel::Sender<Vec<u8>>,
-        to_eth: &crossbeam_channel::Sender<Vec<u8>>,
-        to_tun: &crossbeam_channel::Sender<Vec<u8>>,
+        egress: &Egress,
         ip_packet: Vec<u8>,
         src_interface: InterfaceType,
     ) {
-        let mut state = PacketState::Ingress {
+        let state = PacketState::Ingress {
             iface: src_interface,
             raw_data: ip_packet,
         };
+        self.process(egress, state, src_interface, None);
+    }
+
+    /// Run a packet through the state machine. `ingress` is where the
+    /// packet that started it all came in; `reply_via` pins the egress of
+    /// an ICMP error to it.
+    fn process(
+        &mut self,
+        egress: &Egress,
+        mut state: PacketState,
+        ingress: InterfaceType,
+        mut reply_via: Option<InterfaceType>,
+    ) {
+        let Egress {
+            acoustic: to_acoustic,
+            wifi: to_wifi,
+            eth: to_eth,
+            tun: to_tun,
+        } = egress;
         'router_loop: loop {
             match state {
-                PacketState::Ingress { iface, raw_data } => {
+                PacketState::Ingress {
+                    iface,
+                    mut raw_data,
+                } => {
+                    self.counters
+                        .record_rx(iface, raw_data.len());
                     if iface == InterfaceType::Acoustic {
                         to_tun
                             .send(raw_data.clone())
                             .unwrap();
                     }
+                    // Acoustic ARP (see net::arp), one byte MACs there
+                    if iface == InterfaceType::Acoustic
+                        && crate::net::arp::ArpPacket::is_arp(&raw_data)
+                    {
+                        use crate::net::arp::{ArpOp, ArpPacket as AcousticArp};
+                        if let Ok(arp) = AcousticArp::from_bytes(&raw_data)
+                            && (arp.op == ArpOp::Reply || arp.is_gratuitous())
+                        {
+                            let mut sender_mac = [0u8; 6];
+                            sender_mac[5] = arp.sender_mac;
+                            self.learn_arp(
+                                egress,
+                                arp.sender_ip,
+                                sender_mac,
+                                iface,
+                            );
+                        }
+                        return;
+                    }
                     // Check if it's ARP (starts with 0x0001 for Ethernet HW type)
                     if raw_data.len() >= 28
                         && raw_data[0] == 0x00
@@ -1572,19 +2782,32 @@ impl Router {
                             && hw_len == 6
                             && proto_len == 4
                         {
-                            if opcode == 2 {
-                                // Reply
-                                let mut sender_mac = [0u8; 6];
-                                sender_mac.copy_from_slice(&raw_data[8..14]);
-                                let sender_ip = Ipv4Addr::new(
-                                    raw_data[14],
-                                    raw_data[15],
-                                    raw_data[16],
-                                    raw_data[17],
-                                );
-
+                            let mut sender_mac = [0u8; 6];
+                            sender_mac.copy_from_slice(&raw_data[8..14]);
+                            let sender_ip = Ipv4Addr::new(
+                                raw_data[14],
+                                raw_data[15],
+                                raw_data[16],
+                                raw_data[17],
+                            );
+                            let target_ip = Ipv4Addr::new(
+                                raw_data[24],
+                                raw_data[25],
+                                raw_data[26],
+                                raw_data[27],
+                            );
+                            // Replies and gratuitous requests (a host
+                            // announcing its own address) refresh the table
+                            let gratuitous =
+                                opcode == 1 && sender_ip == target_ip;
+                            if opcode == 2 || gratuitous {
                                 info!(
-                                    "ARP Reply: {} is at {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                                    "ARP {}: {} is at {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                                    if gratuitous {
+                                        "Announce"
+                                    } else {
+                                        "Reply"
+                                    },
                                     sender_ip,
                                     sender_mac[0],
                                     sender_mac[1],
@@ -1593,88 +2816,59 @@ impl Router {
                                     sender_mac[4],
                                     sender_mac[5]
                                 );
-
-                                // Update ARP Table Thread-Safely
-                                if let Ok(mut table) = self.arp_table.write() {
-                                    table.update(sender_ip, sender_mac, iface);
+                                self.learn_arp(
+                                    egress, sender_ip, sender_mac, iface,
+                                );
+                            }
+                            // Answer requests for our own address on
+                            // WiFi/Ethernet, learning the asker on the way
+                            let (our_mac, our_ip) = self.arp_source(iface);
+                            if opcode == 1
+                                && !gratuitous
+                                && target_ip == our_ip
+                                && matches!(
+                                    iface,
+                                    InterfaceType::WiFi
+                                        | InterfaceType::Ethernet
+                                )
+                            {
+                                debug!(
+                                    "ARP Request from {} for our {}",
+                                    sender_ip, our_ip
+                                );
+                                if !sender_ip.is_unspecified() {
+                                    self.learn_arp(
+                                        egress, sender_ip, sender_mac, iface,
+                                    );
                                 }
-
-                                // Check for pending packets (packets that were waiting for this ARP reply)
-                                let buffered = if let Ok(mut pending) =
-                                    self.pending_packets.write()
-                                {
-                                    pending.remove(&sender_ip)
+                                let reply = self.prepare_arp_reply(
+                                    our_mac, our_ip, sender_mac, sender_ip,
+                                );
+                                let sent = if iface == InterfaceType::WiFi {
+                                    to_wifi.send(reply)
                                 } else {
-                                    None
+                                    to_eth.send(reply)
                                 };
-
-                                if let Some(packets) = buffered {
-                                    info!(
-                                        "ARP Resolved for {}. Sending {} buffered packets.",
-                                        sender_ip,
-                                        packets.len()
+                                if let Err(e) = sent {
+                                    warn!(
+                                        "Failed to send ARP reply to {:?}: {}",
+                                        iface, e
                                     );
-                                    for pkt in packets {
-                                        match pkt.interface {
-                                            InterfaceType::WiFi => {
-                                                let frame = self
-                                                    .build_ethernet_frame(
-                                                        pkt.src_mac,
-                                                        sender_mac,
-                                                        &pkt.packet,
-                                                    );
-                                                if let Err(e) =
-                                                    to_wifi.send(frame)
-                                                {
-                                                    warn!(
-                                                        "Failed to send buffered WiFi packet: {}",
-                                                        e
-                                                    );
-                                                }
-                                            }
-                                            InterfaceType::Ethernet => {
-                                                let frame = self
-                                                    .build_ethernet_frame(
-                                                        pkt.src_mac,
-                                                        sender_mac,
-                                                        &pkt.packet,
-                                                    );
-                                                if let Err(e) =
-                                                    to_eth.send(frame)
-                                                {
-                                                    warn!(
-                                                        "Failed to send buffered Ethernet packet: {}",
-                                                        e
-                                                    );
-                                                }
-                                            }
-                                            InterfaceType::Acoustic => {
-                                                if let Err(e) = to_acoustic.send(
-                                                    (pkt.packet, sender_mac[5]),
-                                                ) {
-                                                    warn!(
-                                                        "Failed to send buffered Acoustic packet: {}",
-                                                        e
-                                                    );
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
                                 }
                             }
                         }
                         return;
                     }
 
-                    let (src_ip, dest_ip, protocol) = {
+                    let (src_ip, dest_ip, protocol, is_fragment) = {
                         let ip_header =
                             match Ipv4HeaderSlice::from_slice(&raw_data) {
                                 Ok(h) => h,
                                 Err(e) => {
                                     debug!("Failed to parse IP header: {}", e);
                                     state = PacketState::Dropped {
-                                        reason: format!(
+                                        reason: DropReason::Invalid,
+                                        detail: format!(
                                             "Invalid IP header: {}",
                                             e
                                         ),
@@ -1686,6 +2880,11 @@ impl Router {
                             Ipv4Addr::from(ip_header.source()),
                             Ipv4Addr::from(ip_header.destination()),
                             ip_header.protocol(),
+                            ip_header.more_fragments()
+                                || ip_header
+                                    .fragments_offset()
+                                    .value()
+                                    != 0,
                         )
                     };
 
@@ -1693,9 +2892,42 @@ impl Router {
                         "{:?} packet: {} -> {} (proto: {:?})",
                         iface, src_ip, dest_ip, protocol
                     );
+                    self.counters.record_flow(
+                        src_ip,
+                        dest_ip,
+                        protocol.0,
+                        raw_data.len(),
+                    );
+
+                    if let Some(filtered) = self.filter(iface, &raw_data) {
+                        state = filtered;
+                        reply_via = Some(iface);
+                        continue 'router_loop;
+                    }
 
                     // Check if packet is for us (our IP / NAT response)
                     if self.is_for_us(&dest_ip) {
+                        // Only whole datagrams get past here, forwarded
+                        // fragments are left to the destination
+                        if is_fragment {
+                            let now = self.clock.now();
+                            let reassembled = match self.reassembler.lock() {
+                                Ok(mut reassembler) => reassembler
+                                    .process_fragment_at(&raw_data, now),
+                                Err(_) => Err("Reassembler poisoned".into()),
+                            };
+                            match reassembled {
+                                Ok(Some(packet)) => raw_data = packet,
+                                Ok(None) => return,
+                                Err(e) => {
+                                    state = PacketState::Dropped {
+                                        reason: DropReason::Reassembly,
+                                        detail: e,
+                                    };
+                                    continue 'router_loop;
+                                }
+                            }
+                        }
                         // Check for Traversal (DNAT)
                         if protocol == etherparse::IpNumber::ICMP {
                             // Parse ICMP
@@ -1723,6 +2955,8 @@ impl Router {
                                                 first_byte, new_dst
                                             );
 
+                                            self.counters
+                                                .record_nat(iface);
                                             // Register DNAT session (Thread-safe write)
                                             if let Ok(mut table) =
                                                 self.nat_table.write()
@@ -1751,6 +2985,13 @@ impl Router {
                                             );
 
                                             // Decrement TTL (since we are forwarding)
+                                            if Self::ttl_expired(&raw_data) {
+                                                state = self.expire_packet(
+                                                    iface, &raw_data,
+                                                );
+                                                reply_via = Some(iface);
+                                                continue 'router_loop;
+                                            }
                                             match Self::decrement_ttl(
                                                 &mut packet,
                                             ) {
@@ -1766,7 +3007,9 @@ impl Router {
                                                 Err(e) => {
                                                     state =
                                                         PacketState::Dropped {
-                                                            reason: e
+                                                            reason:
+                                                                DropReason::Invalid,
+                                                            detail: e
                                                                 .to_string(),
                                                         };
                                                     continue 'router_loop;
@@ -1784,6 +3027,23 @@ impl Router {
                             packet: raw_data,
                         };
                         continue 'router_loop;
+                    } else if Self::ttl_expired(&raw_data) {
+                        // traceroute lives off these
+                        state = self.expire_packet(iface, &raw_data);
+                        reply_via = Some(iface);
+                        continue 'router_loop;
+                    } else if !self.has_route(&dest_ip) {
+                        state = self.icmp_error_state(
+                            iface,
+                            &raw_data,
+                            Icmpv4Type::DestinationUnreachable(
+                                etherparse::icmpv4::DestUnreachableHeader::Network,
+                            ),
+                            DropReason::NoRoute,
+                            "No route",
+                        );
+                        reply_via = Some(iface);
+                        continue 'router_loop;
                     } else {
                         // Decrement TTL and rebuild packet
                         let packet = match Self::process_packet_with_etherparse(
@@ -1792,7 +3052,10 @@ impl Router {
                             Ok(p) => p,
                             Err(e) => {
                                 warn!("Failed to process packet: {}", e);
-                                state = PacketState::Dropped { reason: e };
+                                state = PacketState::Dropped {
+                                    reason: DropReason::Invalid,
+                                    detail: e,
+                                };
                                 continue 'router_loop;
                             }
                         };
@@ -1808,6 +3071,8 @@ impl Router {
                     if let Some(new_dest_ip) =
                         self.handle_inbound_nat(&mut packet)
                     {
+                        self.counters
+                            .record_nat(ingress);
                         state = PacketState::Routing {
                             src_ip,
                             dst_ip: new_dest_ip,
@@ -1820,38 +3085,52 @@ impl Router {
                     // Check if UDP port 53
                     if let Ok(h) = Ipv4HeaderSlice::from_slice(&packet) {
                         if h.protocol() == etherparse::IpNumber::UDP {
-                             let ihl = h.slice().len();
-                             let udp_slice = &packet[ihl..];
-                             if let Ok(udp) = UdpHeaderSlice::from_slice(udp_slice) {
-                                 if udp.destination_port() == 53 {
+                            let ihl = h.slice().len();
+                            let udp_slice = &packet[ihl..];
+                            if let Ok(udp) =
+                                UdpHeaderSlice::from_slice(udp_slice)
+                            {
+                                if udp.destination_port() == 53 {
                                     // It is a DNS Query!
                                     let dns_payload = &udp_slice[8..];
-                                    if let Some(response_payload) = self.build_dns_response(dns_payload) {
+                                    if let Some(response_payload) =
+                                        self.build_dns_response(dns_payload)
+                                    {
                                         // Build UDP Response
                                         // Swap Ports
                                         let src_port = 53;
                                         let dst_port = udp.source_port();
-                                        
+
                                         // Build UDP Packet
                                         let builder = PacketBuilder::ipv4(
                                             h.destination(), // Src IP (router)
-                                            h.source(),      // Dst IP (requester)
-                                            64,              // TTL
-                                        ).udp(src_port, dst_port);
+                                            h.source(), // Dst IP (requester)
+                                            64,         // TTL
+                                        )
+                                        .udp(src_port, dst_port);
 
-                                        let mut result = Vec::with_capacity(builder.size(response_payload.len()));
-                                        if let Ok(_) = builder.write(&mut result, &response_payload) {
+                                        let mut result = Vec::with_capacity(
+                                            builder.size(response_payload.len()),
+                                        );
+                                        if let Ok(_) = builder.write(
+                                            &mut result,
+                                            &response_payload,
+                                        ) {
                                             // Send back
                                             state = PacketState::Routing {
-                                                src_ip: Ipv4Addr::from(h.destination()),
-                                                dst_ip: Ipv4Addr::from(h.source()),
+                                                src_ip: Ipv4Addr::from(
+                                                    h.destination(),
+                                                ),
+                                                dst_ip: Ipv4Addr::from(
+                                                    h.source(),
+                                                ),
                                                 packet: result,
                                             };
                                             continue 'router_loop;
                                         }
                                     }
-                                 }
-                             }
+                                }
+                            }
                         }
                     }
                     // --- DNS SERVICE END ---
@@ -1892,7 +3171,8 @@ impl Router {
                             ),
                             Err(_) => {
                                 state = PacketState::Dropped {
-                                    reason: "Invalid IP header in Routing state"
+                                    reason: DropReason::Invalid,
+                                    detail: "Invalid IP header in Routing state"
                                         .to_string(),
                                 };
                                 continue 'router_loop;
@@ -1901,27 +3181,38 @@ impl Router {
 
                     // TODO: search DNAT table/rule (Pre-Routing)
 
-                    // Lookup routing table (Thread-safe read)
-                    let (new_dst_ip, new_iface) = if let Ok(table) =
-                        self.routing_table.read()
-                    {
-                        match table.lookup(&dst_ip) {
-                            Some((next_hop, iface)) => {
-                                if let Some(new_dst) = next_hop {
-                                    // redirect to some gateway
-                                    (new_dst, iface)
-                                } else {
-                                    (dst_ip, iface)
-                                }
+                    let (new_dst_ip, new_iface) = if let Some(via) = reply_via {
+                        // ICMP errors leave where the offending packet came in
+                        (self.next_hop_on(via, dst_ip), via)
+                    } else {
+                        // Lookup routing table, around acoustic hops that
+                        // are down
+                        match self.live_route(dst_ip) {
+                            // redirect to some gateway if there is a next hop
+                            Ok(Some((next_hop, iface))) => {
+                                (next_hop.unwrap_or(dst_ip), iface)
+                            }
+                            Ok(None) => {
+                                state = PacketState::Dropped {
+                                    reason: DropReason::NoRoute,
+                                    detail: format!("No route to {}", dst_ip),
+                                };
+                                continue 'router_loop;
                             }
-                            None => {
-                                // Maybe for 0.0.0.0/0?
-                                // Check for default gateway
-                                (self.config.gateway_ip, InterfaceType::Ethernet) // TODO: change to other interface
+                            Err(hop) => {
+                                state = self.icmp_error_state(
+                                    ingress,
+                                    &packet,
+                                    Icmpv4Type::DestinationUnreachable(
+                                        etherparse::icmpv4::DestUnreachableHeader::Host,
+                                    ),
+                                    DropReason::HostUnreachable,
+                                    &format!("acoustic next hop {} is down", hop),
+                                );
+                                reply_via = Some(ingress);
+                                continue 'router_loop;
                             }
                         }
-                    } else {
-                        (self.config.gateway_ip, InterfaceType::Ethernet)
                     };
 
                     // Post-Routing (SNAT/DNAT handling)
@@ -1945,18 +3236,6 @@ impl Router {
                         // SNAT Logic for Ethernet interface
                         let new_src_ip = self.config.eth_ip;
                         let new_src_mac = self.config.eth_mac;
-                        let gateway_mac = self
-                            .config
-                            .gateway_mac
-                            .unwrap_or([0xff; 6]); // Fallback if no gateway MAC, usually handled by ARP later but needed for build
-
-                        let can_snat = self
-                            .config
-                            .gateway_mac
-                            .is_some();
-                        if !can_snat {
-                            // Wait for ARP for gateway logic handled later in loop
-                        }
 
                         if protocol == etherparse::IpNumber::ICMP {
                             // ICMP
@@ -1984,14 +3263,33 @@ impl Router {
                                         src_ip_from_header,
                                     );
                                 }
+                                self.counters
+                                    .record_nat(ingress);
                                 debug!(
                                     "NAT: Registered Echo Request ID {} from {}",
                                     icmp_id, src_ip_from_header
                                 );
 
-                                if let Some(gateway_mac) =
-                                    self.config.gateway_mac
-                                {
+                                // Extract payload
+                                let payload = &packet[ihl + 8..];
+
+                                let builder = PacketBuilder::ipv4(
+                                    new_src_ip.octets(),
+                                    dst_ip.octets(),
+                                    60,
+                                )
+                                .icmpv4_echo_request(icmp_id, icmp_seq);
+                                let new_payload = {
+                                    let mut frame = Vec::<u8>::with_capacity(
+                                        builder.size(payload.len()),
+                                    );
+                                    builder
+                                        .write(&mut frame, payload)
+                                        .unwrap();
+                                    frame
+                                };
+
+                                if let Some(gateway_mac) = self.gateway_mac() {
                                     info!(
                                         "NAT Forwarding packet to Gateway: {} -> MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
                                         new_dst_ip,
@@ -2002,26 +3300,6 @@ impl Router {
                                         gateway_mac[4],
                                         gateway_mac[5]
                                     );
-
-                                    // Extract payload
-                                    let payload = &packet[ihl + 8..];
-
-                                    let builder = PacketBuilder::ipv4(
-                                        new_src_ip.octets(),
-                                        dst_ip.octets(),
-                                        60,
-                                    )
-                                    .icmpv4_echo_request(icmp_id, icmp_seq);
-                                    let new_payload = {
-                                        let mut frame = Vec::<u8>::with_capacity(
-                                            builder.size(payload.len()),
-                                        );
-                                        builder
-                                            .write(&mut frame, payload)
-                                            .unwrap();
-                                        frame
-                                    };
-
                                     state = PacketState::Send {
                                         out_interface: new_iface,
                                         payload: new_payload,
@@ -2030,6 +3308,9 @@ impl Router {
                                     };
                                     continue 'router_loop;
                                 }
+                                // Gateway not resolved yet: the ARP
+                                // lookup below queues it until it is
+                                packet = new_payload;
                             } else if icmp_type == IcmpType::EchoReply {
                                 // ... existing DNAT logic for traversal ...
                                 debug!(
@@ -2061,73 +3342,62 @@ impl Router {
                                     packet[15] = octets[3];
 
                                     Self::recalculate_ip_checksum(&mut packet);
+                                    self.counters
+                                        .record_nat(ingress);
                                 }
                             }
-                        } else if protocol == etherparse::IpNumber::TCP {
-                            // TCP SNAT
-                            if let Ok(tcp) =
-                                TcpHeaderSlice::from_slice(&packet[ihl..])
+                        } else if protocol == etherparse::IpNumber::TCP
+                            || protocol == etherparse::IpNumber::UDP
+                        {
+                            // TCP/UDP SNAT with port translation
+                            if let Some((src_port, dst_port)) =
+                                Self::l4_ports(&packet, ihl, protocol)
                             {
-                                let src_port = tcp.source_port();
-
-                                // Record session: External Port (same as src_port) -> Internal IP (src_ip_from_header)
-                                if let Ok(mut sessions) =
-                                    self.nat_sessions.write()
-                                {
-                                    sessions
-                                        .insert(src_port, src_ip_from_header);
-                                }
-
-                                // Perform Masquerade (SNAT)
-                                let new_src_ip = self.config.eth_ip;
-                                let octets = new_src_ip.octets();
-
-                                // Update IP Header Source
-                                packet[12] = octets[0];
-                                packet[13] = octets[1];
-                                packet[14] = octets[2];
-                                packet[15] = octets[3];
-
-                                // Recalculate IP Checksum
-                                Self::recalculate_ip_checksum(&mut packet);
-
-                                // Recalculate TCP Checksum using new Source IP
-                                Self::recalculate_l4_checksum(
-                                    &mut packet,
-                                    new_src_ip,
-                                    dst_ip,
+                                let flow = Flow {
                                     protocol,
-                                );
-                            }
-                        } else if protocol == etherparse::IpNumber::UDP {
-                            // UDP SNAT
-                            if let Ok(udp) =
-                                UdpHeaderSlice::from_slice(&packet[ihl..])
-                            {
-                                let src_port = udp.source_port();
+                                    internal: SocketAddrV4::new(
+                                        src_ip_from_header,
+                                        src_port,
+                                    ),
+                                    remote: SocketAddrV4::new(dst_ip, dst_port),
+                                };
 
-                                // Record session
-                                if let Ok(mut sessions) =
-                                    self.nat_sessions.write()
-                                {
-                                    sessions
-                                        .insert(src_port, src_ip_from_header);
-                                }
+                                // Record session, allocating an external port
+                                let tcp = TcpFlags::of(protocol, &packet[ihl..]);
+                                let now = self.clock.now();
+                                let external_port = match self.napt.write() {
+                                    Ok(mut napt) => {
+                                        napt.outbound(flow, tcp, now)
+                                    }
+                                    Err(_) => Err("NAPT table poisoned".into()),
+                                };
+                                let external_port = match external_port {
+                                    Ok(port) => port,
+                                    Err(e) => {
+                                        state = PacketState::Dropped {
+                                            reason: DropReason::Nat,
+                                            detail: format!("NAPT: {}", e),
+                                        };
+                                        continue 'router_loop;
+                                    }
+                                };
 
+                                self.counters
+                                    .record_nat(ingress);
                                 // Perform Masquerade (SNAT)
                                 let new_src_ip = self.config.eth_ip;
-                                let octets = new_src_ip.octets();
 
-                                // Update IP Header Source
-                                packet[12] = octets[0];
-                                packet[13] = octets[1];
-                                packet[14] = octets[2];
-                                packet[15] = octets[3];
+                                // Update IP Header Source and L4 Source Port
+                                packet[12..16]
+                                    .copy_from_slice(&new_src_ip.octets());
+                                packet[ihl..ihl + 2].copy_from_slice(
+                                    &external_port.to_be_bytes(),
+                                );
 
                                 // Recalculate IP Checksum
                                 Self::recalculate_ip_checksum(&mut packet);
 
-                                // Recalculate UDP Checksum
+                                // Recalculate TCP/UDP Checksum using new Source IP and Port
                                 Self::recalculate_l4_checksum(
                                     &mut packet,
                                     new_src_ip,
@@ -2138,33 +3408,38 @@ impl Router {
                         }
                     }
 
-                    // Thread-safe read for ARP
+                    // Thread-safe ARP lookup, a stale entry is still used
+                    // while a refresh goes out
                     let dst_mac_opt = if new_iface == InterfaceType::Tun {
                         Some([0u8; 6])
-                    } else if let Ok(table) = self.arp_table.read() {
-                        table.get_mac(&new_dst_ip, new_iface)
                     } else {
-                        None
+                        let now = self.clock.now();
+                        let found = self
+                            .arp_table
+                            .write()
+                            .ok()
+                            .and_then(|mut table| {
+                                table.lookup_for_send(
+                                    &new_dst_ip,
+                                    new_iface,
+                                    now,
+                                )
+                            });
+                        if let Some((_, true)) = found {
+                            debug!(
+                                "ARP entry for {} is stale, refreshing",
+                                new_dst_ip
+                            );
+                            self.send_arp_request(egress, new_iface, new_dst_ip);
+                        }
+                        found.map(|(mac, _)| mac)
                     };
 
                     let dst_mac = match dst_mac_opt {
                         Some(mac) => mac,
                         None => {
-                            // Determine Source MAC/IP for ARP request
-                            let (src_mac, src_ip) = match new_iface {
-                                InterfaceType::WiFi => {
-                                    (self.config.wifi_mac, self.config.wifi_ip)
-                                }
-                                InterfaceType::Ethernet => {
-                                    (self.config.eth_mac, self.config.eth_ip)
-                                }
-                                InterfaceType::Acoustic => {
-                                    let mut mac = [0u8; 6];
-                                    mac[5] = self.config.acoustic_mac;
-                                    (mac, self.config.acoustic_ip)
-                                }
-                                _ => ([0u8; 6], Ipv4Addr::new(0, 0, 0, 0)),
-                            };
+                            // Determine Source MAC for ARP request
+                            let (src_mac, _) = self.arp_source(new_iface);
 
                             if src_mac != [0u8; 6] {
                                 // 1. Buffer packet waiting for ARP
@@ -2175,46 +3450,32 @@ impl Router {
                                     interface: new_iface,
                                     packet: packet, // Move packet
                                     src_mac: src_mac,
+                                    ingress,
                                 };
 
                                 let should_send_arp = if let Ok(mut pending) =
                                     self.pending_packets.write()
                                 {
-                                    let queue = pending
+                                    let entry = pending
                                         .entry(new_dst_ip)
-                                        .or_default();
-                                    queue.push(pending_pkt);
-                                    queue.len() == 1 // Only send ARP if this is the first packet in queue to avoid ARP storm
+                                        .or_insert_with(|| PendingArp {
+                                            packets: Vec::new(),
+                                            retries: 0,
+                                            last_request: self.clock.now(),
+                                        });
+                                    entry
+                                        .packets
+                                        .push(pending_pkt);
+                                    entry.packets.len() == 1 // Only send ARP if this is the first packet in queue to avoid ARP storm
                                 } else {
                                     false
                                 };
 
                                 // 2. Send ARP Request if needed
                                 if should_send_arp {
-                                    let arp_req = self.prepare_arp_request(
-                                        src_mac, src_ip, new_dst_ip,
+                                    self.send_arp_request(
+                                        egress, new_iface, new_dst_ip,
                                     );
-                                    match new_iface {
-                                        InterfaceType::WiFi => {
-                                            if let Err(e) = to_wifi.send(arp_req)
-                                            {
-                                                warn!(
-                                                    "Failed to send ARP request to WiFi: {}",
-                                                    e
-                                                );
-                                            }
-                                        }
-                                        InterfaceType::Ethernet => {
-                                            if let Err(e) = to_eth.send(arp_req)
-                                            {
-                                                warn!(
-                                                    "Failed to send ARP request to Ethernet: {}",
-                                                    e
-                                                );
-                                            }
-                                        }
-                                        _ => {}
-                                    }
                                     info!(
                                         "Sent ARP Request for {} and buffered packet",
                                         new_dst_ip
@@ -2265,36 +3526,34 @@ impl Router {
                         out_interface,
                         payload.len()
                     );
+                    let bytes = payload.len();
                     match out_interface {
                         InterfaceType::Acoustic => {
-                            // -- 原来的直接发送逻辑 (已注释) --
-                            // if let Err(e) =
-                            //     to_acoustic.send((payload.clone(), dst_mac[5]))
-                            // {
-                            //     warn!(
-                            //         "Failed to send packet to Acoustic thread: {}",
-                            //         e
-                            //     );
-                            // }
-
-                            // 监控用途：同时转发给 TUN 供抓包查看
-                            // if let Err(e) = to_tun.send(payload.clone()) {
-                            //     warn!(
-                            //         "Failed to send packet to TUN thread: {}",
-                            //         e
-                            //     );
-                            // }
-
-                            // -- 使用修复后的分片发送逻辑 --
-                            // 定义声学 MTU (需根据你的物理层载荷能力调整)
-                            const ACOUSTIC_MTU: usize = 140;
+                            let mtu = self.config.acoustic_mtu;
+                            if payload.len() > mtu
+                                && Self::dont_fragment(&payload)
+                            {
+                                state = self.icmp_error_state(
+                                    ingress,
+                                    &payload,
+                                    Icmpv4Type::DestinationUnreachable(
+                                        etherparse::icmpv4::DestUnreachableHeader::FragmentationNeeded {
+                                            next_hop_mtu: mtu as u16,
+                                        },
+                                    ),
+                                    DropReason::TooBig,
+                                    "Fragmentation needed but DF set",
+                                );
+                                reply_via = Some(ingress);
+                                continue 'router_loop;
+                            }
 
                             self.fragment_and_send(
                                 to_acoustic,
                                 to_tun,
-                                payload.clone(),
+                                payload,
                                 dst_mac[5],
-                                ACOUSTIC_MTU,
+                                mtu,
                             );
                         }
                         InterfaceType::WiFi => {
@@ -2338,10 +3597,14 @@ impl Router {
                             }
                         }
                     }
+                    self.counters
+                        .record_tx(out_interface, bytes);
                     return;
                 }
-                PacketState::Dropped { reason } => {
-                    debug!("Packet dropped: {}", reason);
+                PacketState::Dropped { reason, detail } => {
+                    debug!("Packet dropped: {}", detail);
+                    self.counters
+                        .record_drop(ingress, reason);
                     return;
                 }
             }
@@ -2446,4 +3709,1448 @@ mod tests {
         assert!(Router::decrement_ttl(&mut packet).is_ok());
         assert_eq!(packet[8], 63); // TTL should be 63 now
     }
-}
\ No newline at end of file
+
+    /// Internet checksum over `bytes`, 0 when the embedded one is right
+    fn ones_complement_sum(bytes: &[u8]) -> u16 {
+        let mut sum: u32 = 0;
+        for chunk in bytes.chunks(2) {
+            let word =
+                u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]);
+            sum = sum.wrapping_add(word as u32);
+        }
+        while (sum >> 16) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn test_time_exceeded_for_ttl_1() {
+        // UDP packet with TTL 1, as the first traceroute probe
+        let builder = PacketBuilder::ipv4([192, 168, 1, 2], [8, 8, 8, 8], 1)
+            .udp(40000, 33434);
+        let mut original = Vec::new();
+        builder
+            .write(&mut original, &[0xAB; 24])
+            .unwrap();
+        assert!(Router::ttl_expired(&original));
+
+        let router = Router::new(RouterConfig::default());
+        let state = router.expire_packet(InterfaceType::Acoustic, &original);
+        let PacketState::Routing {
+            src_ip,
+            dst_ip,
+            packet,
+        } = state
+        else {
+            panic!("expected a Time Exceeded reply");
+        };
+        assert_eq!(src_ip, router.config.acoustic_ip);
+        assert_eq!(
+            dst_ip,
+            "192.168.1.2"
+                .parse::<Ipv4Addr>()
+                .unwrap()
+        );
+
+        let ip = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+        assert_eq!(ip.protocol(), IpNumber::ICMP);
+        assert_eq!(ip.source_addr(), router.config.acoustic_ip);
+        assert_eq!(ip.destination_addr(), dst_ip);
+        assert_eq!(ones_complement_sum(ip.slice()), 0);
+
+        let icmp = &packet[ip.slice().len()..];
+        assert_eq!(icmp[0], 11); // Time Exceeded
+        assert_eq!(icmp[1], 0); // TTL exceeded in transit
+        assert_eq!(ones_complement_sum(icmp), 0);
+        assert_eq!(&icmp[4..8], &[0; 4]);
+        // Original header plus 8 bytes of its payload (the UDP header)
+        assert_eq!(&icmp[8..], &original[..28]);
+    }
+
+    #[test]
+    fn test_fragmentation_needed_carries_mtu() {
+        let mut original = Vec::new();
+        PacketBuilder::ipv4([192, 168, 2, 5], [192, 168, 1, 2], 63)
+            .udp(4000, 5000)
+            .write(&mut original, &[0; 400])
+            .unwrap();
+        let reply = Router::build_icmp_error(
+            "192.168.1.1".parse().unwrap(),
+            &original,
+            Icmpv4Type::DestinationUnreachable(
+                etherparse::icmpv4::DestUnreachableHeader::FragmentationNeeded {
+                    next_hop_mtu: 140,
+                },
+            ),
+        )
+        .unwrap();
+
+        let ip = Ipv4HeaderSlice::from_slice(&reply).unwrap();
+        assert_eq!(ip.destination(), [192, 168, 2, 5]);
+        let icmp = &reply[20..];
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        // Unused word: 16 zero bits, then the next-hop MTU
+        assert_eq!(icmp[4..8], [0, 0, 0, 140]);
+        assert_eq!(icmp[8..], original[..28]);
+        assert_eq!(ones_complement_sum(icmp), 0);
+    }
+
+    #[test]
+    fn test_time_exceeded_not_for_icmp_errors() {
+        let router_ip: Ipv4Addr = "192.168.1.1".parse().unwrap();
+        let mut probe = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [8, 8, 8, 8], 1)
+            .icmpv4_echo_request(1, 1)
+            .write(&mut probe, &[0; 8])
+            .unwrap();
+        let time_exceeded = Icmpv4Type::TimeExceeded(
+            etherparse::icmpv4::TimeExceededCode::TtlExceededInTransit,
+        );
+        let error =
+            Router::build_icmp_error(router_ip, &probe, time_exceeded.clone())
+                .unwrap();
+        // Echo requests get one, the error itself doesn't
+        assert!(
+            Router::build_icmp_error(router_ip, &error, time_exceeded).is_none()
+        );
+    }
+
+    #[test]
+    fn test_time_exceeded_rate_limited() {
+        let mut original = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [8, 8, 8, 8], 1)
+            .udp(40000, 33434)
+            .write(&mut original, &[0; 8])
+            .unwrap();
+        let router = Router::new(RouterConfig::default());
+        let replies = (0..100)
+            .filter(|_| {
+                matches!(
+                    router.expire_packet(InterfaceType::WiFi, &original),
+                    PacketState::Routing { .. }
+                )
+            })
+            .count();
+        // A burst goes out, then about ICMP_ERROR_RATE_PER_SEC a second
+        let burst = crate::utils::consts::ICMP_ERROR_BURST as usize;
+        assert!(replies >= burst && replies < 2 * burst);
+    }
+
+    #[test]
+    fn test_ineligible_packets_spend_no_icmp_tokens() {
+        let time_exceeded = Icmpv4Type::TimeExceeded(
+            etherparse::icmpv4::TimeExceededCode::TtlExceededInTransit,
+        );
+        let mut error = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [8, 8, 8, 8], 1)
+            .icmpv4(time_exceeded)
+            .write(&mut error, &[0; 28])
+            .unwrap();
+        let mut udp = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [8, 8, 8, 8], 1)
+            .udp(40000, 33434)
+            .write(&mut udp, &[0; 8])
+            .unwrap();
+
+        let router = Router::new(RouterConfig::default());
+        for _ in 0..100 {
+            assert!(matches!(
+                router.expire_packet(InterfaceType::WiFi, &error),
+                PacketState::Dropped { .. }
+            ));
+        }
+        assert!(matches!(
+            router.expire_packet(InterfaceType::WiFi, &udp),
+            PacketState::Routing { .. }
+        ));
+    }
+
+    #[test]
+    fn test_icmp_error_leaves_by_ingress() {
+        // The best route back to the sender is over WiFi, but its packet
+        // came in over the acoustic link through NODE1
+        let config = RouterConfig {
+            routes: vec![
+                "10.5.0.0/16:192.168.2.7:wifi"
+                    .parse()
+                    .unwrap(),
+                "10.0.0.0/8:192.168.1.2:acoustic"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+
+        let mut probe = Vec::new();
+        PacketBuilder::ipv4([10, 5, 3, 4], [192, 168, 2, 9], 1)
+            .udp(40000, 33434)
+            .write(&mut probe, &[0; 8])
+            .unwrap();
+        router.handle_packet(&egress, probe, InterfaceType::Acoustic);
+
+        let (reply, mac) = taps
+            .acoustic
+            .try_recv()
+            .unwrap();
+        assert_eq!(mac, 2);
+        let ip = Ipv4HeaderSlice::from_slice(&reply).unwrap();
+        assert_eq!(ip.protocol(), IpNumber::ICMP);
+        assert_eq!(ip.destination(), [10, 5, 3, 4]);
+        assert_eq!(reply[ip.slice().len()], 11); // Time Exceeded
+        assert!(taps.wifi.try_recv().is_err());
+    }
+
+    /// The interface threads' ends of the egress queues
+    struct Taps {
+        acoustic: crossbeam_channel::Receiver<(Vec<u8>, u8)>,
+        wifi: crossbeam_channel::Receiver<Vec<u8>>,
+        eth: crossbeam_channel::Receiver<Vec<u8>>,
+        tun: crossbeam_channel::Receiver<Vec<u8>>,
+    }
+
+    fn router_with_egress(config: RouterConfig) -> (Router, Egress, Taps) {
+        let (acoustic, acoustic_rx) = crossbeam_channel::unbounded();
+        let (wifi, wifi_rx) = crossbeam_channel::unbounded();
+        let (eth, eth_rx) = crossbeam_channel::unbounded();
+        let (tun, tun_rx) = crossbeam_channel::unbounded();
+        let egress = Egress {
+            acoustic,
+            wifi,
+            eth,
+            tun,
+        };
+        let taps = Taps {
+            acoustic: acoustic_rx,
+            wifi: wifi_rx,
+            eth: eth_rx,
+            tun: tun_rx,
+        };
+        (Router::new(config), egress, taps)
+    }
+
+    /// Checks `reply` is an ICMP Destination Unreachable with `code` from
+    /// the router's acoustic IP, quoting the header and 8 bytes of `original`
+    /// (TTL and header checksum as it was being forwarded)
+    fn assert_unreachable(reply: &[u8], code: u8, original: &[u8]) {
+        let ip = Ipv4HeaderSlice::from_slice(reply).unwrap();
+        assert_eq!(ip.protocol(), IpNumber::ICMP);
+        assert_eq!(ip.source_addr(), RouterConfig::default().acoustic_ip);
+        assert_eq!(&ip.destination(), &original[12..16]);
+        let icmp = &reply[ip.slice().len()..];
+        assert_eq!(icmp[0], 3); // Destination Unreachable
+        assert_eq!(icmp[1], code);
+        assert_eq!(ones_complement_sum(icmp), 0);
+        let quote = &icmp[8..];
+        assert_eq!(quote.len(), 28);
+        assert_eq!(&quote[..8], &original[..8]);
+        assert_eq!(quote[9], original[9]);
+        assert_eq!(&quote[12..], &original[12..28]);
+        assert_eq!(ones_complement_sum(&quote[..20]), 0);
+    }
+
+    #[test]
+    fn test_network_unreachable_without_route() {
+        // No default route
+        let config = RouterConfig {
+            routes: Vec::new(),
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+
+        let mut original = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [172, 16, 0, 1], 64)
+            .udp(40000, 53)
+            .write(&mut original, &[0x11; 12])
+            .unwrap();
+        router.handle_packet(&egress, original.clone(), InterfaceType::Acoustic);
+
+        // Back out the acoustic side, to NODE1's MAC
+        let (reply, mac) = taps
+            .acoustic
+            .try_recv()
+            .unwrap();
+        assert_eq!(mac, 2);
+        assert_unreachable(&reply, 0, &original);
+    }
+
+    #[test]
+    fn test_host_unreachable_after_arp_retries() {
+        use crate::utils::consts::{ARP_MAX_RETRIES, ARP_RETRY_INTERVAL_MS};
+
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        // A WiFi host that never answers ARP
+        let mut original = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [192, 168, 2, 77], 64)
+            .icmpv4_echo_request(9, 1)
+            .write(&mut original, &[0x22; 16])
+            .unwrap();
+        router.handle_packet(&egress, original.clone(), InterfaceType::Acoustic);
+        assert_eq!(taps.wifi.try_iter().count(), 1);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+
+        let start = Instant::now();
+        let interval = Duration::from_millis(ARP_RETRY_INTERVAL_MS);
+        // Too early for a retry
+        router.sweep_pending_arp(&egress, start);
+        assert_eq!(taps.wifi.try_iter().count(), 0);
+        for retry in 1..=ARP_MAX_RETRIES {
+            router.sweep_pending_arp(&egress, start + interval * retry);
+            assert_eq!(taps.wifi.try_iter().count(), 1);
+            assert!(
+                taps.acoustic
+                    .try_recv()
+                    .is_err()
+            );
+        }
+        let give_up = start + interval * (ARP_MAX_RETRIES + 1);
+        router.sweep_pending_arp(&egress, give_up);
+
+        assert_eq!(taps.wifi.try_iter().count(), 0);
+        let (reply, mac) = taps
+            .acoustic
+            .try_recv()
+            .unwrap();
+        assert_eq!(mac, 2);
+        assert_unreachable(&reply, 1, &original);
+        assert!(
+            router
+                .pending_packets
+                .read()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// Raw Ethernet ARP body (as the WiFi thread hands it over) with
+    /// `sender` at `mac` asking for or announcing `target`
+    fn ethernet_arp(
+        op: u16,
+        mac: [u8; 6],
+        sender: [u8; 4],
+        target: [u8; 4],
+    ) -> Vec<u8> {
+        let mut arp = vec![0x00, 0x01, 0x08, 0x00, 6, 4];
+        arp.extend_from_slice(&op.to_be_bytes());
+        arp.extend_from_slice(&mac);
+        arp.extend_from_slice(&sender);
+        arp.extend_from_slice(&[0; 6]);
+        arp.extend_from_slice(&target);
+        arp
+    }
+
+    #[test]
+    fn test_answers_arp_for_own_addresses() {
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        let host_mac = [0x02, 0, 0, 0, 0, 0x77];
+        let host = [192, 168, 2, 77];
+
+        router.handle_packet(
+            &egress,
+            ethernet_arp(1, host_mac, host, [192, 168, 2, 1]),
+            InterfaceType::WiFi,
+        );
+        let frame = taps.wifi.try_recv().unwrap();
+        let wifi_mac = router.config.wifi_mac;
+        assert_eq!(frame.len(), 14 + 28);
+        assert_eq!(&frame[..6], &host_mac);
+        assert_eq!(&frame[6..12], &wifi_mac);
+        assert_eq!(&frame[12..14], &[0x08, 0x06]);
+        let arp = &frame[14..];
+        assert_eq!(&arp[..6], &[0x00, 0x01, 0x08, 0x00, 6, 4]);
+        assert_eq!(&arp[6..8], &[0x00, 0x02]);
+        assert_eq!(&arp[8..14], &wifi_mac);
+        assert_eq!(&arp[14..18], &[192, 168, 2, 1]);
+        assert_eq!(&arp[18..24], &host_mac);
+        assert_eq!(&arp[24..28], &host);
+        // The asker was learned
+        let now = router.clock.now();
+        assert_eq!(
+            router
+                .arp_table
+                .write()
+                .unwrap()
+                .lookup_for_send(&host.into(), InterfaceType::WiFi, now)
+                .map(|(mac, _)| mac),
+            Some(host_mac)
+        );
+
+        // Ethernet answers with its own address
+        router.handle_packet(
+            &egress,
+            ethernet_arp(1, host_mac, [10, 20, 0, 9], [10, 20, 0, 1]),
+            InterfaceType::Ethernet,
+        );
+        let frame = taps.eth.try_recv().unwrap();
+        assert_eq!(&frame[14 + 8..14 + 14], &router.config.eth_mac);
+        assert_eq!(&frame[14 + 14..14 + 18], &[10, 20, 0, 1]);
+
+        // Not for us, or the wrong interface: silence
+        router.handle_packet(
+            &egress,
+            ethernet_arp(1, host_mac, host, [192, 168, 2, 9]),
+            InterfaceType::WiFi,
+        );
+        router.handle_packet(
+            &egress,
+            ethernet_arp(1, host_mac, [10, 20, 0, 9], [192, 168, 2, 1]),
+            InterfaceType::Ethernet,
+        );
+        assert!(taps.wifi.try_recv().is_err());
+        assert!(taps.eth.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_arp_soft_expiry_and_refresh() {
+        use crate::utils::consts::{ARP_MAX_RETRIES, ARP_RETRY_INTERVAL_MS};
+
+        let config = RouterConfig {
+            arp_ttl: Duration::from_secs(60),
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+        router.clock = Clock::mock();
+        let host = [192, 168, 2, 77];
+        let old_mac = [0x02, 0, 0, 0, 0, 0x77];
+        let new_mac = [0x02, 0, 0, 0, 0, 0x78];
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], host, 64)
+            .icmpv4_echo_request(9, 1)
+            .write(&mut packet, &[0x22; 16])
+            .unwrap();
+        let is_arp = |frame: &Vec<u8>| frame[12..14] == [0x08, 0x06];
+        let sent = |taps: &Taps| {
+            taps.wifi
+                .try_iter()
+                .collect::<Vec<_>>()
+        };
+
+        router.handle_packet(
+            &egress,
+            ethernet_arp(2, old_mac, host, [192, 168, 2, 1]),
+            InterfaceType::WiFi,
+        );
+        router.handle_packet(&egress, packet.clone(), InterfaceType::Acoustic);
+        let frames = sent(&taps);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][..6], old_mac);
+
+        // Stale: still forwarded on the old MAC, one refresh goes out
+        router
+            .clock
+            .advance(Duration::from_secs(61));
+        router.handle_packet(&egress, packet.clone(), InterfaceType::Acoustic);
+        router.handle_packet(&egress, packet.clone(), InterfaceType::Acoustic);
+        let frames = sent(&taps);
+        assert_eq!(
+            frames
+                .iter()
+                .filter(|f| is_arp(f))
+                .count(),
+            1
+        );
+        let data: Vec<_> = frames
+            .iter()
+            .filter(|f| !is_arp(f))
+            .collect();
+        assert_eq!(data.len(), 2);
+        assert!(
+            data.iter()
+                .all(|f| f[..6] == old_mac)
+        );
+
+        // A gratuitous ARP refreshes the entry and moves it
+        router.handle_packet(
+            &egress,
+            ethernet_arp(1, new_mac, host, host),
+            InterfaceType::WiFi,
+        );
+        router.handle_packet(&egress, packet.clone(), InterfaceType::Acoustic);
+        let frames = sent(&taps);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][..6], new_mac);
+
+        // Unanswered refreshes: the entry is dropped once the grace is over
+        let grace =
+            Duration::from_millis(ARP_RETRY_INTERVAL_MS) * (ARP_MAX_RETRIES + 1);
+        router
+            .clock
+            .advance(Duration::from_secs(61) + grace);
+        let now = router.clock.now();
+        let mut table = router
+            .arp_table
+            .write()
+            .unwrap();
+        table.expire(now);
+        assert!(
+            table
+                .lookup_for_send(&host.into(), InterfaceType::WiFi, now)
+                .is_none()
+        );
+        drop(table);
+
+        // The next packet waits for re-resolution, then goes out
+        router.handle_packet(&egress, packet.clone(), InterfaceType::Acoustic);
+        let frames = sent(&taps);
+        assert_eq!(frames.len(), 1);
+        assert!(is_arp(&frames[0]));
+        router.handle_packet(
+            &egress,
+            ethernet_arp(2, old_mac, host, [192, 168, 2, 1]),
+            InterfaceType::WiFi,
+        );
+        let frames = sent(&taps);
+        assert_eq!(frames.len(), 1);
+        assert!(!is_arp(&frames[0]));
+        assert_eq!(frames[0][..6], old_mac);
+        assert!(
+            router
+                .pending_packets
+                .read()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_gateway_mac_discovered_by_arp() {
+        use crate::utils::consts::{ARP_MAX_RETRIES, ARP_RETRY_INTERVAL_MS};
+
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        router.clock = Clock::mock();
+        let gateway_ip = router.config.gateway_ip;
+        let gateway_mac = [0x02, 0, 0, 0, 0, 0xfe];
+        let eth_ip = router.config.eth_ip;
+        let is_arp = |frame: &Vec<u8>| frame[12..14] == [0x08, 0x06];
+        let asks_gateway = |frame: &Vec<u8>| {
+            is_arp(frame) && frame[38..42] == gateway_ip.octets()
+        };
+
+        // Startup: requests go out every interval, then it gives up
+        let interval = Duration::from_millis(ARP_RETRY_INTERVAL_MS);
+        for _ in 0..=ARP_MAX_RETRIES {
+            router.resolve_gateway(&egress, router.clock.now());
+            router.resolve_gateway(&egress, router.clock.now());
+            let frame = taps.eth.try_recv().unwrap();
+            assert!(asks_gateway(&frame));
+            assert!(taps.eth.try_recv().is_err());
+            router.clock.advance(interval);
+        }
+        router.resolve_gateway(&egress, router.clock.now());
+        assert!(taps.eth.try_recv().is_err());
+        assert_eq!(*router.gateway.lock().unwrap(), GatewayState::Failed);
+
+        // NATed traffic waits for the gateway, asking for it on its own
+        let mut ping = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [203, 0, 113, 5], 64)
+            .icmpv4_echo_request(7, 1)
+            .write(&mut ping, &[0x33; 8])
+            .unwrap();
+        let mut udp = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [203, 0, 113, 5], 64)
+            .udp(5000, 9000)
+            .write(&mut udp, b"hello")
+            .unwrap();
+        router.handle_packet(&egress, ping, InterfaceType::Acoustic);
+        router.handle_packet(&egress, udp, InterfaceType::Acoustic);
+        let frames: Vec<_> = taps.eth.try_iter().collect();
+        assert_eq!(frames.len(), 1);
+        assert!(asks_gateway(&frames[0]));
+
+        // The reply sends both out, NATed, to the learned MAC
+        router.handle_packet(
+            &egress,
+            ethernet_arp(2, gateway_mac, gateway_ip.octets(), eth_ip.octets()),
+            InterfaceType::Ethernet,
+        );
+        assert_eq!(
+            *router.gateway.lock().unwrap(),
+            GatewayState::Resolved(gateway_mac)
+        );
+        let frames: Vec<_> = taps.eth.try_iter().collect();
+        assert_eq!(frames.len(), 2);
+        for frame in &frames {
+            assert_eq!(frame[..6], gateway_mac);
+            assert_eq!(frame[14 + 12..14 + 16], eth_ip.octets());
+        }
+        assert_eq!(frames[0][14 + 9], 1);
+        assert_eq!(frames[1][14 + 9], 17);
+        assert!(
+            router
+                .pending_packets
+                .read()
+                .unwrap()
+                .is_empty()
+        );
+
+        // From now on pings go straight out
+        let mut ping = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [203, 0, 113, 5], 64)
+            .icmpv4_echo_request(7, 2)
+            .write(&mut ping, &[0x33; 8])
+            .unwrap();
+        router.handle_packet(&egress, ping, InterfaceType::Acoustic);
+        let frame = taps.eth.try_recv().unwrap();
+        assert_eq!(frame[..6], gateway_mac);
+    }
+
+    #[test]
+    fn test_napt_separates_hosts_on_same_port() {
+        use etherparse::{SlicedPacket, TransportSlice};
+
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        let gateway_mac = [0x02, 0, 0, 0, 0, 0xfe];
+        router
+            .arp_table
+            .write()
+            .unwrap()
+            .add_entry(
+                router.config.gateway_ip,
+                gateway_mac,
+                InterfaceType::Ethernet,
+            );
+        let eth_ip = router.config.eth_ip;
+        let server = [203, 0, 113, 5];
+        let hosts = [[192, 168, 1, 2], [192, 168, 1, 3]];
+        // Checks the UDP checksum and returns (src, src port, dst port)
+        let parse = |packet: &[u8]| {
+            let sliced = SlicedPacket::from_ip(packet).unwrap();
+            let Some(etherparse::NetSlice::Ipv4(ip)) = sliced.net else {
+                panic!("not IPv4");
+            };
+            let Some(TransportSlice::Udp(udp)) = sliced.transport else {
+                panic!("not UDP");
+            };
+            let header = ip.header();
+            let checksum = udp
+                .to_header()
+                .calc_checksum_ipv4_raw(
+                    header.source(),
+                    header.destination(),
+                    udp.payload(),
+                )
+                .unwrap();
+            assert_eq!(udp.checksum(), checksum);
+            (header.source(), udp.source_port(), udp.destination_port())
+        };
+
+        // Both hosts pick source port 5000 towards the same server
+        let mut external_ports = Vec::new();
+        for host in hosts {
+            let mut packet = Vec::new();
+            PacketBuilder::ipv4(host, server, 64)
+                .udp(5000, 9000)
+                .write(&mut packet, &host)
+                .unwrap();
+            router.handle_packet(&egress, packet, InterfaceType::Acoustic);
+            let frame = taps.eth.try_recv().unwrap();
+            assert_eq!(frame[..6], gateway_mac);
+            let (src, src_port, dst_port) = parse(&frame[14..]);
+            assert_eq!(src, eth_ip.octets());
+            assert_eq!(dst_port, 9000);
+            external_ports.push(src_port);
+        }
+        assert_ne!(external_ports[0], external_ports[1]);
+
+        // Each reply finds its way back to the host that opened the flow
+        for (host, port) in hosts
+            .iter()
+            .zip(&external_ports)
+            .rev()
+        {
+            let mut reply = Vec::new();
+            PacketBuilder::ipv4(server, eth_ip.octets(), 64)
+                .udp(9000, *port)
+                .write(&mut reply, b"pong")
+                .unwrap();
+            router.handle_packet(&egress, reply, InterfaceType::Ethernet);
+            let (packet, mac) = taps
+                .acoustic
+                .try_recv()
+                .unwrap();
+            assert_eq!(mac, host[3]);
+            assert_eq!(packet[16..20], *host);
+            assert_eq!(parse(&packet), (server, 9000, 5000));
+        }
+
+        // Unsolicited traffic to a mapped port stays out
+        let mut probe = Vec::new();
+        PacketBuilder::ipv4([203, 0, 113, 6], eth_ip.octets(), 64)
+            .udp(9000, external_ports[0])
+            .write(&mut probe, b"knock")
+            .unwrap();
+        router.handle_packet(&egress, probe, InterfaceType::Ethernet);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_napt_tracks_tcp_lifecycle() {
+        use crate::net::nat::TcpState;
+        use crate::utils::consts::NAPT_PORT_FIRST;
+
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        router
+            .arp_table
+            .write()
+            .unwrap()
+            .add_entry(
+                router.config.gateway_ip,
+                [0x02, 0, 0, 0, 0, 0xfe],
+                InterfaceType::Ethernet,
+            );
+        let eth_ip = router.config.eth_ip.octets();
+        let host = [192, 168, 1, 2];
+        let server = [203, 0, 113, 5];
+        let flow = Flow {
+            protocol: IpNumber::TCP,
+            internal: SocketAddrV4::new(host.into(), 40000),
+            remote: SocketAddrV4::new(server.into(), 80),
+        };
+        let state = |router: &Router| {
+            router
+                .napt
+                .read()
+                .unwrap()
+                .tcp_state(&flow)
+        };
+        // Segments with the flags `set` (s = SYN, a = ACK, f = FIN, r = RST)
+        let segment = |src, dst, sport, dport, set: &str| {
+            let mut tcp =
+                PacketBuilder::ipv4(src, dst, 64).tcp(sport, dport, 1, 1024);
+            for flag in set.chars() {
+                tcp = match flag {
+                    's' => tcp.syn(),
+                    'a' => tcp.ack(1),
+                    'f' => tcp.fin(),
+                    'r' => tcp.rst(),
+                    _ => unreachable!(),
+                };
+            }
+            let mut packet = Vec::new();
+            tcp.write(&mut packet, &[])
+                .unwrap();
+            packet
+        };
+        let send_out = |router: &mut Router, set: &str| {
+            let packet = segment(host, server, 40000, 80, set);
+            router.handle_packet(&egress, packet, InterfaceType::Acoustic);
+            taps.eth
+                .try_recv()
+                .ok()
+                .map(|frame| u16::from_be_bytes([frame[34], frame[35]]))
+        };
+        let send_in = |router: &mut Router, port, set: &str| {
+            let packet = segment(server, eth_ip, 80, port, set);
+            router.handle_packet(&egress, packet, InterfaceType::Ethernet);
+            taps.acoustic
+                .try_recv()
+                .is_ok()
+        };
+
+        // Nothing opens a connection but an outbound SYN
+        assert!(!send_in(&mut router, NAPT_PORT_FIRST, "s"));
+        assert_eq!(send_out(&mut router, "a"), None);
+        assert_eq!(state(&router), None);
+
+        let port = send_out(&mut router, "s").unwrap();
+        assert_eq!(state(&router), Some(TcpState::New));
+        assert!(send_in(&mut router, port, "sa"));
+        assert_eq!(state(&router), Some(TcpState::Established));
+        assert_eq!(send_out(&mut router, "a"), Some(port));
+
+        // Teardown: the final ACK still goes out, then the session is gone
+        assert_eq!(send_out(&mut router, "fa"), Some(port));
+        assert!(matches!(state(&router), Some(TcpState::FinWait { .. })));
+        assert!(send_in(&mut router, port, "fa"));
+        assert_eq!(send_out(&mut router, "a"), Some(port));
+        assert_eq!(state(&router), None);
+        assert!(!send_in(&mut router, port, "a"));
+
+        // A reset from either side closes at once
+        let port = send_out(&mut router, "s").unwrap();
+        assert!(send_in(&mut router, port, "r"));
+        assert_eq!(state(&router), None);
+        assert!(!send_in(&mut router, port, "sa"));
+    }
+
+    #[test]
+    fn test_reassembles_fragments_for_router() {
+        use crate::net::fragmentation::IpFragmenter;
+
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([192, 168, 2, 5], [192, 168, 1, 1], 64)
+            .icmpv4_echo_request(3, 1)
+            .write(&mut packet, &[0x5a; 2972])
+            .unwrap();
+        packet[6] &= !0x40;
+        Router::recalculate_ip_checksum(&mut packet);
+        let mut fragments = IpFragmenter::new(1500)
+            .fragment_packet(&packet)
+            .unwrap();
+        fragments.reverse();
+
+        for fragment in fragments {
+            assert!(taps.tun.try_recv().is_err());
+            router.handle_packet(&egress, fragment, InterfaceType::WiFi);
+        }
+        let delivered = taps.tun.try_recv().unwrap();
+        assert!(delivered == packet, "reassembly is not byte-exact");
+        assert!(taps.tun.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_fragments_oversized_packet_to_acoustic() {
+        use crate::net::fragmentation::{IpReassembler, ipv4_header_checksum};
+
+        let (mut router, egress, taps) =
+            router_with_egress(RouterConfig::default());
+        let mtu = router.config.acoustic_mtu;
+        let sender = [192, 168, 2, 5];
+        router
+            .arp_table
+            .write()
+            .unwrap()
+            .add_entry(
+                sender.into(),
+                [0x02, 0, 0, 0, 0, 0x05],
+                InterfaceType::WiFi,
+            );
+        let mut original = Vec::new();
+        PacketBuilder::ipv4(sender, [192, 168, 1, 2], 64)
+            .udp(4000, 5000)
+            .write(&mut original, &[0x3c; 1472])
+            .unwrap();
+        assert_eq!(original.len(), 1500);
+
+        // DF set: the sender hears the MTU instead
+        router.handle_packet(&egress, original.clone(), InterfaceType::WiFi);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+        let frame = taps.wifi.try_recv().unwrap();
+        let icmp = &frame[14 + 20..];
+        assert_eq!((icmp[0], icmp[1]), (3, 4));
+        assert_eq!(u16::from_be_bytes([icmp[6], icmp[7]]), mtu as u16);
+
+        original[6] &= !0x40;
+        Router::recalculate_ip_checksum(&mut original);
+        router.handle_packet(&egress, original.clone(), InterfaceType::WiFi);
+        let fragments: Vec<_> = taps
+            .acoustic
+            .try_iter()
+            .collect();
+        assert!(fragments.len() > 10);
+        let mut reassembler = IpReassembler::new();
+        let mut reassembled = None;
+        for (fragment, mac) in &fragments {
+            assert_eq!(*mac, 2);
+            assert!(fragment.len() <= mtu);
+            let checksum = u16::from_be_bytes([fragment[10], fragment[11]]);
+            assert_eq!(checksum, ipv4_header_checksum(&fragment[..20]));
+            reassembled = reassembler
+                .process_fragment(fragment)
+                .unwrap();
+        }
+
+        // The original, one hop further
+        let mut expected = original;
+        expected[8] -= 1;
+        Router::recalculate_ip_checksum(&mut expected);
+        assert!(reassembled.unwrap() == expected, "reassembly differs");
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let mask = |m: &str| m.parse::<Ipv4Addr>().unwrap();
+        let mut table = RoutingTable::new();
+        // Least specific first, so first-match would shadow the rest
+        table.add_network(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+            InterfaceType::Ethernet,
+            "10.20.0.254".parse().unwrap(),
+        );
+        table.add_network(
+            "172.16.0.0".parse().unwrap(),
+            mask("255.255.0.0"),
+            InterfaceType::WiFi,
+            "192.168.2.9".parse().unwrap(),
+        );
+        table.add_direct_network(
+            "172.16.5.0".parse().unwrap(),
+            mask("255.255.255.0"),
+            InterfaceType::Acoustic,
+        );
+
+        assert_eq!(
+            table.lookup(&"172.16.5.7".parse().unwrap()),
+            Some((None, InterfaceType::Acoustic))
+        );
+        assert_eq!(
+            table.lookup(&"172.16.9.7".parse().unwrap()),
+            Some((Some("192.168.2.9".parse().unwrap()), InterfaceType::WiFi))
+        );
+        assert_eq!(
+            table.lookup(&"8.8.8.8".parse().unwrap()),
+            Some((
+                Some("10.20.0.254".parse().unwrap()),
+                InterfaceType::Ethernet
+            ))
+        );
+        let lengths: Vec<u8> = table
+            .list_routes()
+            .iter()
+            .map(|route| route.prefix_len)
+            .collect();
+        assert_eq!(lengths, vec![0, 16, 24]);
+
+        // Without the /24 the /16 takes over
+        assert!(table.remove_route(
+            "172.16.5.99".parse().unwrap(),
+            mask("255.255.255.0")
+        ));
+        assert!(
+            !table.remove_route(
+                "172.16.5.0".parse().unwrap(),
+                mask("255.255.255.0")
+            )
+        );
+        assert_eq!(
+            table.lookup(&"172.16.5.7".parse().unwrap()),
+            Some((Some("192.168.2.9".parse().unwrap()), InterfaceType::WiFi))
+        );
+    }
+
+    #[test]
+    fn test_same_prefix_replaces_route() {
+        let mask: Ipv4Addr = "255.255.255.0"
+            .parse()
+            .unwrap();
+        let mut table = RoutingTable::new();
+        // Same /24 written two ways: the second replaces the first
+        table.add_direct_network(
+            "192.168.7.0".parse().unwrap(),
+            mask,
+            InterfaceType::WiFi,
+        );
+        table.add_direct_network(
+            "192.168.7.1".parse().unwrap(),
+            mask,
+            InterfaceType::Tun,
+        );
+        assert_eq!(table.list_routes().len(), 1);
+        assert_eq!(
+            table.lookup(&"192.168.7.5".parse().unwrap()),
+            Some((None, InterfaceType::Tun))
+        );
+        assert_eq!(table.list_routes()[0].to_string(), "192.168.7.1/24 dev Tun");
+    }
+
+    #[test]
+    fn test_parse_static_route() {
+        let route: StaticRoute = "10.5.0.0/16:192.168.2.2:wifi"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            route.network,
+            "10.5.0.0"
+                .parse::<Ipv4Addr>()
+                .unwrap()
+        );
+        assert_eq!(route.prefix_len, 16);
+        assert_eq!(
+            route.mask(),
+            "255.255.0.0"
+                .parse::<Ipv4Addr>()
+                .unwrap()
+        );
+        assert_eq!(
+            route.next_hop,
+            "192.168.2.2"
+                .parse::<Ipv4Addr>()
+                .unwrap()
+        );
+        assert_eq!(route.interface, InterfaceType::WiFi);
+        assert_eq!(route.to_string(), "10.5.0.0/16:192.168.2.2:wifi");
+
+        let default = StaticRoute::parse_default("10.20.0.254:eth").unwrap();
+        assert_eq!(default.prefix_len, 0);
+        assert_eq!(default.mask(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(default.interface, InterfaceType::Ethernet);
+
+        for bad in [
+            "10.5.0.0:192.168.2.2:wifi",
+            "10.5.0.0/33:192.168.2.2:wifi",
+            "10.5.0/16:192.168.2.2:wifi",
+            "10.5.0.0/16:192.168.2:wifi",
+            "10.5.0.0/16:192.168.2.2:radio",
+            "10.5.0.0/16:192.168.2.2",
+            "10.5.0.0/16:192.168.2.2:wifi:extra",
+        ] {
+            assert!(
+                bad.parse::<StaticRoute>()
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(StaticRoute::parse_default("10.20.0.254").is_err());
+    }
+
+    #[test]
+    fn test_static_route_egress() {
+        let wifi_mac = [0x02, 0, 0, 0, 0, 0x22];
+        let config = RouterConfig {
+            routes: vec![
+                "10.5.0.0/16:192.168.2.2:wifi"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+        router.add_arp_entry(
+            "192.168.2.2".parse().unwrap(),
+            wifi_mac,
+            InterfaceType::WiFi,
+        );
+
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([192, 168, 1, 2], [10, 5, 3, 4], 64)
+            .udp(40000, 9)
+            .write(&mut packet, &[0x33; 8])
+            .unwrap();
+        router.handle_packet(&egress, packet, InterfaceType::Acoustic);
+
+        // Out over WiFi to the next hop's MAC, still addressed to 10.5.3.4
+        let frame = taps.wifi.try_recv().unwrap();
+        assert_eq!(&frame[..6], &wifi_mac);
+        let ip = Ipv4HeaderSlice::from_slice(&frame[14..]).unwrap();
+        assert_eq!(ip.destination(), [10, 5, 3, 4]);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_fails_over_around_down_acoustic_hop() {
+        let config = RouterConfig {
+            routes: vec![
+                "10.5.0.0/16:192.168.1.3:acoustic"
+                    .parse()
+                    .unwrap(),
+                "10.0.0.0/8:192.168.2.2:wifi"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+        router.clock = Clock::mock();
+        for (ip, last) in [("192.168.2.2", 0x22), ("192.168.2.5", 0x05)] {
+            router.add_arp_entry(
+                ip.parse().unwrap(),
+                [0x02, 0, 0, 0, 0, last],
+                InterfaceType::WiFi,
+            );
+        }
+        let packet = |dst: [u8; 4]| {
+            let mut packet = Vec::new();
+            PacketBuilder::ipv4([192, 168, 2, 5], dst, 64)
+                .udp(40000, 9)
+                .write(&mut packet, &[0x44; 8])
+                .unwrap();
+            packet
+        };
+
+        let to_10_5 = packet([10, 5, 3, 4]);
+        router.handle_packet(&egress, to_10_5.clone(), InterfaceType::WiFi);
+        assert_eq!(
+            taps.acoustic
+                .try_recv()
+                .unwrap()
+                .1,
+            3
+        );
+
+        // Node 3 stops taking frames
+        for _ in 0..crate::utils::consts::ROUTER_HOP_MAX_FAILURES {
+            router.record_acoustic_send(3, false);
+        }
+        router.handle_packet(&egress, to_10_5.clone(), InterfaceType::WiFi);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+        let frame = taps.wifi.try_recv().unwrap();
+        assert_eq!(frame[5], 0x22);
+        assert_eq!(&frame[14 + 16..14 + 20], &[10, 5, 3, 4]);
+
+        // Nothing else reaches the node itself
+        let original = packet([192, 168, 1, 3]);
+        router.handle_packet(&egress, original.clone(), InterfaceType::WiFi);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+        let frame = taps.wifi.try_recv().unwrap();
+        assert_eq!(frame[5], 0x05);
+        let icmp = &frame[14 + 20..];
+        assert_eq!((icmp[0], icmp[1]), (3, 1));
+        assert_eq!(&icmp[8 + 12..8 + 20], &original[12..20]);
+
+        // Probed once per interval, a probe that goes out brings it back
+        let now = router.clock.now();
+        router.probe_down_hops(&egress, now);
+        let (probe, mac) = taps
+            .acoustic
+            .try_recv()
+            .unwrap();
+        assert_eq!(mac, 3);
+        assert!(crate::net::arp::ArpPacket::from_bytes(&probe).is_ok());
+        router.probe_down_hops(&egress, now);
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+        router.record_acoustic_send(3, true);
+        router.handle_packet(&egress, to_10_5, InterfaceType::WiFi);
+        assert_eq!(
+            taps.acoustic
+                .try_recv()
+                .unwrap()
+                .1,
+            3
+        );
+        assert!(taps.wifi.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_firewall_drops_matching_packets() {
+        let wifi_mac = [0x02, 0, 0, 0, 0, 0x22];
+        let config = RouterConfig {
+            routes: vec![
+                "10.5.0.0/16:192.168.2.2:wifi"
+                    .parse()
+                    .unwrap(),
+            ],
+            firewall: vec![
+                "deny proto=udp port=53 out=wifi"
+                    .parse()
+                    .unwrap(),
+                "reject dst=10.5.9.0/24 in=acoustic"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+        router.add_arp_entry(
+            "192.168.2.2".parse().unwrap(),
+            wifi_mac,
+            InterfaceType::WiFi,
+        );
+        let udp = |dst: [u8; 4], port: u16| {
+            let mut packet = Vec::new();
+            PacketBuilder::ipv4([192, 168, 1, 2], dst, 64)
+                .udp(40000, port)
+                .write(&mut packet, &[0x44; 8])
+                .unwrap();
+            packet
+        };
+
+        // DNS towards WiFi is dropped without a word
+        router.handle_packet(
+            &egress,
+            udp([10, 5, 3, 4], 53),
+            InterfaceType::Acoustic,
+        );
+        assert!(taps.wifi.try_recv().is_err());
+        assert!(
+            taps.acoustic
+                .try_recv()
+                .is_err()
+        );
+
+        // Other ports pass
+        router.handle_packet(
+            &egress,
+            udp([10, 5, 3, 4], 9),
+            InterfaceType::Acoustic,
+        );
+        let frame = taps.wifi.try_recv().unwrap();
+        assert_eq!(&frame[..6], &wifi_mac);
+
+        // Rejected: Communication Administratively Prohibited
+        let original = udp([10, 5, 9, 1], 9);
+        router.handle_packet(&egress, original.clone(), InterfaceType::Acoustic);
+        assert!(taps.wifi.try_recv().is_err());
+        let (reply, mac) = taps
+            .acoustic
+            .try_recv()
+            .unwrap();
+        assert_eq!(mac, 2);
+        assert_unreachable(&reply, 13, &original);
+
+        let hits: Vec<_> = router
+            .firewall
+            .read()
+            .unwrap()
+            .rules()
+            .map(|(_, hits)| hits)
+            .collect();
+        assert_eq!(hits, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_counters_follow_packets() {
+        let wifi_mac = [0x02, 0, 0, 0, 0, 0x22];
+        let config = RouterConfig {
+            routes: vec![
+                "10.5.0.0/16:192.168.2.2:wifi"
+                    .parse()
+                    .unwrap(),
+            ],
+            firewall: vec![
+                "deny proto=udp port=53"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..RouterConfig::default()
+        };
+        let (mut router, egress, taps) = router_with_egress(config);
+        router.add_arp_entry(
+            "192.168.2.2".parse().unwrap(),
+            wifi_mac,
+            InterfaceType::WiFi,
+        );
+        let udp = |dst: [u8; 4], port: u16| {
+            let mut packet = Vec::new();
+            PacketBuilder::ipv4([192, 168, 1, 2], dst, 64)
+                .udp(40000, port)
+                .write(&mut packet, &[0x44; 8])
+                .unwrap();
+            packet
+        };
+
+        // Forwarded twice, one no-route drop, one firewall drop
+        let forwarded = udp([10, 5, 3, 4], 9);
+        for _ in 0..2 {
+            router.handle_packet(
+                &egress,
+                forwarded.clone(),
+                InterfaceType::Acoustic,
+            );
+            taps.wifi.try_recv().unwrap();
+        }
+        router.handle_packet(
+            &egress,
+            udp([172, 16, 0, 1], 9),
+            InterfaceType::Acoustic,
+        );
+        router.handle_packet(
+            &egress,
+            udp([10, 5, 3, 4], 53),
+            InterfaceType::Acoustic,
+        );
+        assert!(taps.wifi.try_recv().is_err());
+
+        let counters = router.counters();
+        let acoustic = counters
+            .interface(InterfaceType::Acoustic)
+            .snapshot();
+        assert_eq!(acoustic.rx_packets, 4);
+        assert_eq!(acoustic.drops["no_route"], 1);
+        assert_eq!(acoustic.drops["firewall"], 1);
+        let wifi = counters
+            .interface(InterfaceType::WiFi)
+            .snapshot();
+        assert_eq!(wifi.tx_packets, 2);
+        assert_eq!(wifi.tx_bytes, 2 * forwarded.len() as u64);
+        assert_eq!(wifi.rx_packets, 0);
+
+        let top = counters.top_flows(1);
+        assert_eq!(top[0].destination, Ipv4Addr::new(10, 5, 3, 4));
+        assert_eq!((top[0].protocol, top[0].packets), (17, 3));
+    }
+
+    /// A wired link whose RX end takes packets from the returned sender
+    /// and whose TX end hands them to the returned receiver
+    fn mock_link() -> (
+        (LinkRx, LinkTx),
+        crossbeam_channel::Sender<Vec<u8>>,
+        crossbeam_channel::Receiver<Vec<u8>>,
+    ) {
+        let (rx_in, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let (tx, tx_out) = crossbeam_channel::unbounded::<Vec<u8>>();
+        let link: (LinkRx, LinkTx) = (
+            Box::new(move || {
+                Ok(rx
+                    .recv_timeout(Duration::from_millis(10))
+                    .ok())
+            }),
+            Box::new(move |data| {
+                tx.send(data.to_vec())
+                    .map_err(|e| e.to_string())
+            }),
+        );
+        (link, rx_in, tx_out)
+    }
+
+    #[test]
+    fn test_metrics_scrape() {
+        use crate::utils::metrics::Metrics;
+        use crate::utils::metrics::tests::{parse, scrape};
+
+        let router = Router::new(RouterConfig::default());
+        let counters = router.counters();
+        counters.record_rx(InterfaceType::Acoustic, 100);
+        counters.record_rx(InterfaceType::Acoustic, 60);
+        counters.record_tx(InterfaceType::Ethernet, 160);
+        counters.record_drop(InterfaceType::WiFi, DropReason::NoRoute);
+        router
+            .nat_table
+            .read()
+            .unwrap()
+            .register_echo_request(7, "192.168.1.2".parse().unwrap());
+        let flow = Flow {
+            protocol: IpNumber::UDP,
+            internal: SocketAddrV4::new([192, 168, 1, 2].into(), 5000),
+            remote: SocketAddrV4::new([203, 0, 113, 5].into(), 53),
+        };
+        router
+            .napt
+            .write()
+            .unwrap()
+            .outbound(flow, None, Instant::now())
+            .unwrap();
+
+        let metrics = Metrics::default();
+        metrics.register("router", router.metrics_collector());
+        let (status, body) = scrape(&metrics, "/metrics");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let samples = parse(&body);
+        let sample = |name: &str| samples[&format!("trackmaker_{}", name)];
+        assert_eq!(
+            sample(
+                r#"router_packets_total{interface="acoustic",direction="rx"}"#
+            ),
+            2.0
+        );
+        assert_eq!(
+            sample(r#"router_bytes_total{interface="acoustic",direction="rx"}"#),
+            160.0
+        );
+        assert_eq!(
+            sample(r#"router_packets_total{interface="eth",direction="tx"}"#),
+            1.0
+        );
+        assert_eq!(
+            sample(r#"router_drops_total{interface="wifi",reason="no_route"}"#),
+            1.0
+        );
+        assert_eq!(sample("nat_sessions"), 2.0);
+        assert!(samples.contains_key("trackmaker_frames_tx_total"));
+    }
+
+    #[test]
+    fn test_stop_ends_all_threads() {
+        use crate::mac::acoustic_interface::tests::{
+            SAMPLE_RATE, spawn_mock_channel, test_interface,
+        };
+
+        let shared = AppShared::new(SAMPLE_RATE as usize);
+        let air = Arc::new(AtomicBool::new(true));
+        let channel = spawn_mock_channel(vec![shared.clone()], air.clone());
+
+        let mut router = Router::new(RouterConfig {
+            management: None,
+            ..RouterConfig::default()
+        });
+        let acoustic = test_interface(
+            shared,
+            LineCodingKind::FourBFiveB,
+            router.config.acoustic_mac,
+        );
+        let (tun, tun_in, tun_out) = mock_link();
+        let (wifi, _wifi_in, wifi_out) = mock_link();
+        let (eth, _eth_in, eth_out) = mock_link();
+        router
+            .running
+            .lock()
+            .unwrap()
+            .store(true, Ordering::SeqCst);
+        let stopper = router.clone();
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        let serving = thread::spawn(move || {
+            let result = router.serve(RouterLinks {
+                acoustic,
+                wifi,
+                eth: Some(eth),
+                tun,
+            });
+            done_tx.send(result).unwrap();
+        });
+
+        // Packets still get taken in while running
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 2], [192, 168, 2, 99], 64)
+            .udp(40000, 9)
+            .write(&mut packet, &[0x44; 8])
+            .unwrap();
+        tun_in.send(packet).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        stopper.stop();
+
+        let result = done_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("router threads still running after stop");
+        assert_eq!(result, Ok(()));
+        serving.join().unwrap();
+        air.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+
+        // The TX threads ended and let go of their links
+        for out in [tun_out, wifi_out, eth_out] {
+            let mut sent = 0;
+            loop {
+                match out.recv_timeout(Duration::from_secs(1)) {
+                    Ok(_) => sent += 1,
+                    Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                        break;
+                    }
+                    Err(e) => panic!("TX link still open: {}", e),
+                }
+            }
+            assert!(sent < 10);
+        }
+        let tun = stopper
+            .counters()
+            .interface(InterfaceType::Tun)
+            .snapshot();
+        assert_eq!(tun.rx_packets, 1);
+    }
+}
@@ -0,0 +1,462 @@
+// Ethernet over the acoustic link, for the TUN adapter's TAP mode
+//
+// A TAP device hands over whole Ethernet frames, most of them larger than
+// an acoustic frame's payload. Each is split into pieces that travel in one
+// acoustic frame each, behind a 4-byte header:
+//
+//   0xE7 | frame id | piece index | piece count | up to 124 bytes of frame
+//
+// The first byte keeps the pieces apart from IPv4, IPv6 and ARP payloads.
+// The receiver puts a frame back together per sender and id, and learns
+// which node each Ethernet source address sits behind, so unicast frames
+// go to that node; broadcast, multicast and unknown destinations go to
+// the acoustic broadcast address.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::mac::types::{BROADCAST, MacAddr};
+use crate::utils::consts::{
+    MAX_FRAME_DATA_SIZE, REASSEMBLY_TIMEOUT_MS, TAP_STATION_TTL_SECS,
+};
+
+/// First byte of every TAP piece
+pub const TAP_MARKER: u8 = 0xE7;
+pub const TAP_HEADER_LEN: usize = 4;
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
+/// Split `frame` into pieces of at most `piece_len` bytes, header included
+pub fn encapsulate(
+    frame: &[u8],
+    id: u8,
+    piece_len: usize,
+) -> Result<Vec<Vec<u8>>, String> {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return Err(format!("Ethernet frame of {} bytes", frame.len()));
+    }
+    let chunk = piece_len.saturating_sub(TAP_HEADER_LEN);
+    if chunk == 0 {
+        return Err(format!("No room in a {} byte piece", piece_len));
+    }
+    let count = frame.len().div_ceil(chunk);
+    if count > u8::MAX as usize {
+        return Err(format!(
+            "Ethernet frame of {} bytes needs {} pieces",
+            frame.len(),
+            count
+        ));
+    }
+    Ok(frame
+        .chunks(chunk)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut piece = vec![TAP_MARKER, id, index as u8, count as u8];
+            piece.extend_from_slice(data);
+            piece
+        })
+        .collect())
+}
+
+pub fn is_tap_piece(payload: &[u8]) -> bool {
+    payload.len() > TAP_HEADER_LEN && payload[0] == TAP_MARKER
+}
+
+struct Partial {
+    pieces: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+/// Puts frames back together from their pieces
+pub struct TapReassembler {
+    partial: HashMap<(MacAddr, u8), Partial>,
+    timeout: Duration,
+}
+
+impl Default for TapReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TapReassembler {
+    pub fn new() -> Self {
+        Self {
+            partial: HashMap::new(),
+            timeout: Duration::from_millis(REASSEMBLY_TIMEOUT_MS),
+        }
+    }
+
+    /// Take a piece from node `src`, returns the frame once it is complete
+    pub fn process(
+        &mut self,
+        src: MacAddr,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let timeout = self.timeout;
+        self.partial
+            .retain(|_, partial| now.duration_since(partial.started) < timeout);
+
+        if !is_tap_piece(payload) {
+            return Err("Not a TAP piece".to_string());
+        }
+        let (id, index, count) =
+            (payload[1], payload[2] as usize, payload[3] as usize);
+        if index >= count {
+            return Err(format!("Piece {} of {}", index, count));
+        }
+        let partial = self
+            .partial
+            .entry((src, id))
+            .or_insert_with(|| Partial {
+                pieces: vec![None; count],
+                started: now,
+            });
+        // A reused id: the old frame is lost
+        if partial.pieces.len() != count {
+            *partial = Partial {
+                pieces: vec![None; count],
+                started: now,
+            };
+        }
+        partial.pieces[index] = Some(payload[TAP_HEADER_LEN..].to_vec());
+        if partial
+            .pieces
+            .iter()
+            .any(Option::is_none)
+        {
+            return Ok(None);
+        }
+        let partial = self
+            .partial
+            .remove(&(src, id))
+            .unwrap();
+        Ok(Some(
+            partial
+                .pieces
+                .into_iter()
+                .flatten()
+                .flatten()
+                .collect(),
+        ))
+    }
+}
+
+/// A learning bridge between the TAP device and the acoustic link
+pub struct TapBridge {
+    stations: HashMap<[u8; 6], (MacAddr, Instant)>,
+    ttl: Duration,
+    reassembler: TapReassembler,
+    next_id: u8,
+}
+
+impl Default for TapBridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TapBridge {
+    pub fn new() -> Self {
+        Self {
+            stations: HashMap::new(),
+            ttl: Duration::from_secs(TAP_STATION_TTL_SECS),
+            reassembler: TapReassembler::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Node `mac` is behind, None if not heard from lately
+    pub fn station(&self, mac: [u8; 6], now: Instant) -> Option<MacAddr> {
+        self.stations
+            .get(&mac)
+            .filter(|(_, seen)| now.duration_since(*seen) < self.ttl)
+            .map(|(node, _)| *node)
+    }
+
+    /// The node to send an Ethernet frame from the TAP device to, and the
+    /// pieces to send it in
+    pub fn outgoing(
+        &mut self,
+        frame: &[u8],
+        now: Instant,
+    ) -> Result<(MacAddr, Vec<Vec<u8>>), String> {
+        let pieces = encapsulate(frame, self.next_id, MAX_FRAME_DATA_SIZE)?;
+        self.next_id = self.next_id.wrapping_add(1);
+        let destination: [u8; 6] = frame[..6].try_into().unwrap();
+        // Group bit: broadcast or multicast
+        let node = if destination[0] & 1 != 0 {
+            BROADCAST
+        } else {
+            self.station(destination, now)
+                .unwrap_or(BROADCAST)
+        };
+        Ok((node, pieces))
+    }
+
+    /// Take a payload from node `src`, returns the Ethernet frame for the
+    /// TAP device once all its pieces are in
+    pub fn incoming(
+        &mut self,
+        src: MacAddr,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let Some(frame) = self
+            .reassembler
+            .process(src, payload, now)?
+        else {
+            return Ok(None);
+        };
+        if frame.len() < ETHERNET_HEADER_LEN {
+            return Err(format!("Ethernet frame of {} bytes", frame.len()));
+        }
+        let source: [u8; 6] = frame[6..12]
+            .try_into()
+            .unwrap();
+        if source[0] & 1 == 0 {
+            if self.station(source, now) != Some(src) {
+                debug!("TAP: {:02x?} is behind node {}", source, src);
+            }
+            self.stations
+                .insert(source, (src, now));
+        }
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use etherparse::{
+        ArpHardwareId, ArpOperation, ArpPacket, ArpPacketSlice, EtherType,
+        PacketBuilder,
+    };
+
+    use crate::audio::recorder::{AppShared, AppState};
+    use crate::mac::acoustic_interface::tests::{
+        SAMPLE_RATE, spawn_mock_channel, test_interface,
+    };
+    use crate::phy::{FrameType, LineCodingKind};
+
+    const MAC_A: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0a];
+    const MAC_B: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0b];
+
+    fn arp_frame(
+        operation: ArpOperation,
+        from: ([u8; 6], [u8; 4]),
+        to: ([u8; 6], [u8; 4]),
+        destination: [u8; 6],
+    ) -> Vec<u8> {
+        let builder = PacketBuilder::ethernet2(from.0, destination).arp(
+            ArpPacket::new(
+                ArpHardwareId::ETHERNET,
+                EtherType::IPV4,
+                operation,
+                &from.0,
+                &from.1,
+                &to.0,
+                &to.1,
+            )
+            .unwrap(),
+        );
+        let mut frame = Vec::with_capacity(builder.size());
+        builder
+            .write(&mut frame)
+            .unwrap();
+        frame
+    }
+
+    #[test]
+    fn test_encapsulation_format() {
+        let frame: Vec<u8> = (0..300u16)
+            .map(|i| i as u8)
+            .collect();
+        let pieces = encapsulate(&frame, 9, 128).unwrap();
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(&pieces[0][..4], &[TAP_MARKER, 9, 0, 3]);
+        assert_eq!(&pieces[2][..4], &[TAP_MARKER, 9, 2, 3]);
+        assert_eq!(pieces[0].len(), 128);
+        assert_eq!(pieces[2].len(), 4 + 300 - 2 * 124);
+        assert_eq!(&pieces[1][4..6], &[124, 125]);
+        assert!(!crate::net::arp::ArpPacket::is_arp(&pieces[0]));
+        assert_eq!(
+            crate::net::PayloadKind::of(&pieces[0]),
+            crate::net::PayloadKind::Unknown
+        );
+
+        // Out of order, and interleaved with another sender's frame
+        let now = Instant::now();
+        let mut reassembler = TapReassembler::new();
+        assert_eq!(reassembler.process(1, &pieces[2], now), Ok(None));
+        assert_eq!(reassembler.process(2, &pieces[0], now), Ok(None));
+        assert_eq!(reassembler.process(1, &pieces[0], now), Ok(None));
+        assert_eq!(reassembler.process(1, &pieces[1], now), Ok(Some(frame)));
+        // Node 2's frame never completes
+        let later = now + Duration::from_millis(REASSEMBLY_TIMEOUT_MS);
+        assert_eq!(reassembler.process(2, &pieces[1], later), Ok(None));
+        assert_eq!(reassembler.partial.len(), 1);
+
+        assert!(encapsulate(&[0; 10], 0, 128).is_err());
+        assert!(encapsulate(&[0; 2000], 0, 10).is_err());
+        assert!(
+            reassembler
+                .process(1, &[0x45, 0, 0, 1, 0], now)
+                .is_err()
+        );
+        assert!(
+            reassembler
+                .process(1, &[TAP_MARKER, 0, 3, 3, 0], now)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_bridge_learns_stations() {
+        let now = Instant::now();
+        let mut bridge = TapBridge::new();
+        let request = arp_frame(
+            ArpOperation::REQUEST,
+            (MAC_A, [10, 0, 0, 1]),
+            ([0; 6], [10, 0, 0, 2]),
+            [0xff; 6],
+        );
+        let (node, pieces) = bridge
+            .outgoing(&request, now)
+            .unwrap();
+        assert_eq!((node, pieces.len()), (BROADCAST, 1));
+
+        // Unknown unicast floods until the station is heard from
+        let reply = arp_frame(
+            ArpOperation::REPLY,
+            (MAC_B, [10, 0, 0, 2]),
+            (MAC_A, [10, 0, 0, 1]),
+            MAC_A,
+        );
+        let to_b = arp_frame(
+            ArpOperation::REPLY,
+            (MAC_A, [10, 0, 0, 1]),
+            (MAC_B, [10, 0, 0, 2]),
+            MAC_B,
+        );
+        assert_eq!(
+            bridge
+                .outgoing(&to_b, now)
+                .unwrap()
+                .0,
+            BROADCAST
+        );
+        let (_, pieces) = TapBridge::new()
+            .outgoing(&reply, now)
+            .unwrap();
+        assert_eq!(bridge.incoming(7, &pieces[0], now), Ok(Some(reply)));
+        assert_eq!(
+            bridge
+                .outgoing(&to_b, now)
+                .unwrap()
+                .0,
+            7
+        );
+
+        let expired = now + Duration::from_secs(TAP_STATION_TTL_SECS);
+        assert_eq!(
+            bridge
+                .outgoing(&to_b, expired)
+                .unwrap()
+                .0,
+            BROADCAST
+        );
+    }
+
+    #[test]
+    fn test_arp_over_tap_loopback() {
+        let a = AppShared::new(SAMPLE_RATE as usize);
+        let b = AppShared::new(SAMPLE_RATE as usize);
+        let running = Arc::new(AtomicBool::new(true));
+        let channel =
+            spawn_mock_channel(vec![a.clone(), b.clone()], running.clone());
+
+        let kind = LineCodingKind::FourBFiveB;
+        let mut node_a = test_interface(a, kind, 1);
+        let mut node_b = test_interface(b.clone(), kind, 2);
+        b.app_state
+            .set(AppState::Recording);
+
+        // Node B's TAP side: answers the ARP request it gets, unicast
+        let peer = std::thread::spawn(move || {
+            let mut bridge = TapBridge::new();
+            let timeout = Some(Duration::from_secs(10));
+            let request = loop {
+                let (payload, src) = node_b
+                    .receive_packet_from(timeout)
+                    .unwrap();
+                if let Some(frame) = bridge
+                    .incoming(src, &payload, Instant::now())
+                    .unwrap()
+                {
+                    break frame;
+                }
+            };
+            let arp = ArpPacketSlice::from_slice(&request[14..]).unwrap();
+            assert_eq!(arp.operation(), ArpOperation::REQUEST);
+            let reply = arp_frame(
+                ArpOperation::REPLY,
+                (MAC_B, [10, 0, 0, 2]),
+                (MAC_A, [10, 0, 0, 1]),
+                MAC_A,
+            );
+            let (node, pieces) = bridge
+                .outgoing(&reply, Instant::now())
+                .unwrap();
+            for piece in pieces {
+                node_b
+                    .send_packet(&piece, node, FrameType::Data)
+                    .unwrap();
+            }
+            node
+        });
+
+        let mut bridge = TapBridge::new();
+        let request = arp_frame(
+            ArpOperation::REQUEST,
+            (MAC_A, [10, 0, 0, 1]),
+            ([0; 6], [10, 0, 0, 2]),
+            [0xff; 6],
+        );
+        let (node, pieces) = bridge
+            .outgoing(&request, Instant::now())
+            .unwrap();
+        for piece in pieces {
+            node_a
+                .send_packet(&piece, node, FrameType::Data)
+                .unwrap();
+        }
+        let reply = loop {
+            let (payload, src) = node_a
+                .receive_packet_from(Some(Duration::from_secs(10)))
+                .unwrap();
+            if let Some(frame) = bridge
+                .incoming(src, &payload, Instant::now())
+                .unwrap()
+            {
+                break frame;
+            }
+        };
+        let reply_node = peer.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+
+        // B learned A from the broadcast request and answered it directly
+        assert_eq!(reply_node, 1);
+        assert_eq!(&reply[..6], &MAC_A);
+        let arp = ArpPacketSlice::from_slice(&reply[14..]).unwrap();
+        assert_eq!(arp.operation(), ArpOperation::REPLY);
+        assert_eq!(arp.sender_hw_addr(), &MAC_B);
+        assert_eq!(bridge.station(MAC_B, Instant::now()), Some(2));
+    }
+}
@@ -0,0 +1,1054 @@
+// Minimal user-space TCP
+//
+// Enough TCP to carry a byte stream between two acoustic hosts: the
+// three-way handshake, cumulative ACKs with retransmission on an RTO
+// (RFC 6298 estimate, Karn's rule, exponential backoff) and fast
+// retransmit after three duplicate ACKs, in-order delivery with
+// out-of-order segments held within the window, and FIN teardown with
+// TIME-WAIT. The window is fixed (TcpConfig::window) and there is no
+// congestion control beyond it (over the half-duplex acoustic link it
+// holds one segment: stop-and-wait). No options are sent or understood.
+//
+// Segments travel as whole IPv4 packets over a PacketLink, so the same
+// code runs over the acoustic interface and over an in-memory link in
+// tests. A connection is driven from the calls on it: every read, write
+// or close sends what the window allows and handles what arrives, so
+// nothing happens between calls.
+//
+//   Segment: [IPv4 header:20] [TCP header:20] [Payload]
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::net::ip::{IP_HEADER_BYTES, Ipv4Header, l4_checksum};
+use crate::net::nat::TcpFlags;
+use crate::utils::consts::{
+    IP_TTL, TCP_FIN_TIMEOUT_MS, TCP_MAX_RETRIES, TCP_MSS, TCP_POLL_MS,
+    TCP_RTO_INITIAL_MS, TCP_RTO_MAX_MS, TCP_RTO_MIN_MS, TCP_TIME_WAIT_MS,
+    TCP_WINDOW,
+};
+
+pub const TCP_HEADER_BYTES: usize = 20;
+const TCP_PROTOCOL: u8 = 6;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/// Carries whole IPv4 packets to and from the peer
+pub trait PacketLink {
+    fn send(&mut self, packet: &[u8]) -> Result<(), String>;
+
+    /// Next packet, None if nothing arrived within `timeout`
+    fn recv(&mut self, timeout: Duration) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    /// Largest payload per segment
+    pub mss: usize,
+    /// Receive window we advertise, and the most we keep in flight
+    pub window: usize,
+    pub rto_initial: Duration,
+    pub rto_min: Duration,
+    pub rto_max: Duration,
+    /// Retransmissions of one segment before giving up
+    pub max_retries: u32,
+    pub time_wait: Duration,
+    pub fin_timeout: Duration,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            mss: TCP_MSS,
+            window: TCP_WINDOW,
+            rto_initial: Duration::from_millis(TCP_RTO_INITIAL_MS),
+            rto_min: Duration::from_millis(TCP_RTO_MIN_MS),
+            rto_max: Duration::from_millis(TCP_RTO_MAX_MS),
+            max_retries: TCP_MAX_RETRIES,
+            time_wait: Duration::from_millis(TCP_TIME_WAIT_MS),
+            fin_timeout: Duration::from_millis(TCP_FIN_TIMEOUT_MS),
+        }
+    }
+}
+
+/// Connection states of RFC 793, LISTEN aside (that's a TcpListener)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    /// Our FIN sent, not acknowledged yet
+    FinWait1,
+    /// Our FIN acknowledged, the peer's still to come
+    FinWait2,
+    /// The peer closed, we haven't
+    CloseWait,
+    /// Both FINs sent at once, ours not acknowledged yet
+    Closing,
+    /// The peer closed first, our FIN not acknowledged yet
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl TcpState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TcpState::SynSent => "SYN-SENT",
+            TcpState::SynReceived => "SYN-RECEIVED",
+            TcpState::Established => "ESTABLISHED",
+            TcpState::FinWait1 => "FIN-WAIT-1",
+            TcpState::FinWait2 => "FIN-WAIT-2",
+            TcpState::CloseWait => "CLOSE-WAIT",
+            TcpState::Closing => "CLOSING",
+            TcpState::LastAck => "LAST-ACK",
+            TcpState::TimeWait => "TIME-WAIT",
+            TcpState::Closed => "CLOSED",
+        }
+    }
+
+    /// New data may still be sent
+    fn can_send(self) -> bool {
+        matches!(self, TcpState::Established | TcpState::CloseWait)
+    }
+
+    /// Data from the peer is still expected
+    fn can_receive(self) -> bool {
+        matches!(
+            self,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        )
+    }
+}
+
+/// One TCP segment with the addresses of the packet carrying it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+    pub seq: u32,
+    /// Only meaningful with the ACK flag
+    pub ack: u32,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    /// The whole IPv4 packet
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let total_len = IP_HEADER_BYTES + TCP_HEADER_BYTES + self.payload.len();
+        if total_len > u16::MAX as usize {
+            return Err(format!(
+                "TCP payload of {} bytes too large",
+                self.payload.len()
+            ));
+        }
+        let src = self.source.ip().octets();
+        let dst = self.destination.ip().octets();
+        let mut packet = Ipv4Header::new(
+            total_len as u16,
+            rand::random(),
+            IP_TTL,
+            TCP_PROTOCOL,
+            src,
+            dst,
+        )
+        .to_bytes()
+        .map_err(|e| e.to_string())?;
+
+        let flags = (self.flags.fin as u8 * FIN)
+            | (self.flags.syn as u8 * SYN)
+            | (self.flags.rst as u8 * RST)
+            | (self.flags.ack as u8 * ACK);
+        packet.extend_from_slice(
+            &self
+                .source
+                .port()
+                .to_be_bytes(),
+        );
+        packet.extend_from_slice(
+            &self
+                .destination
+                .port()
+                .to_be_bytes(),
+        );
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&self.ack.to_be_bytes());
+        // Data offset in words, no options
+        packet.extend_from_slice(&[(TCP_HEADER_BYTES as u8 / 4) << 4, flags]);
+        packet.extend_from_slice(&self.window.to_be_bytes());
+        // Checksum, urgent pointer
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(&self.payload);
+
+        let checksum =
+            l4_checksum(src, dst, TCP_PROTOCOL, &packet[IP_HEADER_BYTES..]);
+        packet[IP_HEADER_BYTES + 16..IP_HEADER_BYTES + 18]
+            .copy_from_slice(&checksum.to_be_bytes());
+        Ok(packet)
+    }
+
+    /// Parse an IPv4 packet carrying TCP, rejecting it if the checksum
+    /// doesn't add up
+    pub fn from_bytes(packet: &[u8]) -> Result<Self, String> {
+        let ip = etherparse::Ipv4HeaderSlice::from_slice(packet)
+            .map_err(|e| format!("Invalid IPv4 header: {}", e))?;
+        if ip.protocol().0 != TCP_PROTOCOL {
+            return Err(format!("Not TCP but protocol {}", ip.protocol().0));
+        }
+        if ip.is_fragmenting_payload() {
+            return Err("TCP segment is a fragment".to_string());
+        }
+        let segment = packet
+            .get(ip.slice().len()..ip.total_len() as usize)
+            .ok_or("Packet shorter than its IPv4 total length")?;
+        let data_offset = segment
+            .get(12)
+            .map(|b| (b >> 4) as usize * 4)
+            .unwrap_or(0);
+        if data_offset < TCP_HEADER_BYTES || data_offset > segment.len() {
+            return Err(format!("Bad TCP header in {} bytes", segment.len()));
+        }
+
+        let mut zeroed = segment.to_vec();
+        zeroed[16..18].fill(0);
+        let expected =
+            l4_checksum(ip.source(), ip.destination(), TCP_PROTOCOL, &zeroed);
+        let checksum = u16::from_be_bytes([segment[16], segment[17]]);
+        if checksum != expected {
+            return Err(format!(
+                "Bad TCP checksum {:#06x}, expected {:#06x}",
+                checksum, expected
+            ));
+        }
+
+        let u16_at = |i: usize| u16::from_be_bytes([segment[i], segment[i + 1]]);
+        let u32_at = |i: usize| {
+            u32::from_be_bytes([
+                segment[i],
+                segment[i + 1],
+                segment[i + 2],
+                segment[i + 3],
+            ])
+        };
+        let flags = segment[13];
+        Ok(Self {
+            source: SocketAddrV4::new(ip.source_addr(), u16_at(0)),
+            destination: SocketAddrV4::new(ip.destination_addr(), u16_at(2)),
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: TcpFlags {
+                syn: flags & SYN != 0,
+                ack: flags & ACK != 0,
+                fin: flags & FIN != 0,
+                rst: flags & RST != 0,
+            },
+            window: u16_at(14),
+            payload: segment[data_offset..].to_vec(),
+        })
+    }
+}
+
+/// `a` comes before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// A segment sent and not acknowledged yet
+#[derive(Debug, Clone)]
+struct InFlight {
+    seq: u32,
+    syn: bool,
+    fin: bool,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    retransmitted: bool,
+}
+
+impl InFlight {
+    fn end(&self) -> u32 {
+        self.seq
+            .wrapping_add(self.payload.len() as u32)
+            .wrapping_add(self.syn as u32 + self.fin as u32)
+    }
+}
+
+/// Waits for one connection on a local address
+pub struct TcpListener<L: PacketLink> {
+    link: L,
+    local: SocketAddrV4,
+    config: TcpConfig,
+}
+
+impl<L: PacketLink> TcpListener<L> {
+    pub fn bind(link: L, local: SocketAddrV4, config: TcpConfig) -> Self {
+        Self {
+            link,
+            local,
+            config,
+        }
+    }
+
+    /// Block until a peer connects, completing the handshake. The link
+    /// moves to the connection, so a listener accepts once.
+    pub fn accept(mut self) -> Result<TcpStream<L>, String> {
+        let syn = loop {
+            let Some(packet) = self
+                .link
+                .recv(Duration::from_millis(TCP_POLL_MS))
+            else {
+                continue;
+            };
+            match Segment::from_bytes(&packet) {
+                Ok(segment)
+                    if segment.destination == self.local
+                        && segment.flags.syn
+                        && !segment.flags.ack
+                        && !segment.flags.rst =>
+                {
+                    break segment;
+                }
+                Ok(_) => {}
+                Err(e) => debug!("TCP listener dropping packet: {}", e),
+            }
+        };
+        debug!("TCP: connection request from {}", syn.source);
+
+        let mut stream = TcpStream::new(
+            self.link,
+            self.local,
+            syn.source,
+            self.config,
+            TcpState::SynReceived,
+        );
+        stream.rcv_nxt = syn.seq.wrapping_add(1);
+        stream.peer_window = syn.window;
+        stream.send_syn()?;
+        stream.wait_while(|s| s.state == TcpState::SynReceived)?;
+        Ok(stream)
+    }
+}
+
+/// One TCP connection
+pub struct TcpStream<L: PacketLink> {
+    link: L,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    config: TcpConfig,
+    state: TcpState,
+
+    // Send side
+    snd_una: u32,
+    snd_nxt: u32,
+    peer_window: u16,
+    /// Written by the application, not sent yet
+    unsent: VecDeque<u8>,
+    in_flight: VecDeque<InFlight>,
+    /// close() was called, a FIN goes out once everything is sent
+    closing: bool,
+    fin_sent: bool,
+    rto: Duration,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto_deadline: Option<Instant>,
+    retries: u32,
+    dup_acks: u32,
+
+    // Receive side
+    rcv_nxt: u32,
+    /// In order, not read yet
+    received: VecDeque<u8>,
+    /// Ahead of rcv_nxt, by sequence number, with their FIN flag
+    out_of_order: HashMap<u32, (Vec<u8>, bool)>,
+    fin_received: bool,
+}
+
+impl<L: PacketLink> TcpStream<L> {
+    fn new(
+        link: L,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        config: TcpConfig,
+        state: TcpState,
+    ) -> Self {
+        let iss = rand::random::<u32>();
+        Self {
+            link,
+            local,
+            remote,
+            rto: config.rto_initial,
+            config,
+            state,
+            snd_una: iss,
+            snd_nxt: iss,
+            peer_window: 0,
+            unsent: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto_deadline: None,
+            retries: 0,
+            dup_acks: 0,
+            rcv_nxt: 0,
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_received: false,
+        }
+    }
+
+    /// Open a connection to `remote`, blocking until it is established
+    pub fn connect(
+        link: L,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        config: TcpConfig,
+    ) -> Result<Self, String> {
+        let mut stream =
+            Self::new(link, local, remote, config, TcpState::SynSent);
+        stream.send_syn()?;
+        stream.wait_while(|s| s.state == TcpState::SynSent)?;
+        Ok(stream)
+    }
+
+    pub fn remote(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    /// Read what has arrived, blocking until something has. Returns 0
+    /// once the peer has closed and everything was read.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+        self.wait_while(|s| {
+            s.received.is_empty() && !s.fin_received && !buf.is_empty()
+        })?;
+        let n = buf
+            .len()
+            .min(self.received.len());
+        for (dst, src) in buf
+            .iter_mut()
+            .zip(self.received.drain(..n))
+        {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    /// Queue `data` and block until all of it has been sent. Some of it
+    /// may still be unacknowledged; close() waits for that.
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), String> {
+        if !self.state.can_send() || self.closing {
+            return Err(format!("Cannot write in state {}", self.state.name()));
+        }
+        self.unsent.extend(data);
+        self.wait_while(|s| !s.unsent.is_empty())
+    }
+
+    /// Send our FIN once everything written is out, and wait for the
+    /// connection to close on both sides
+    pub fn close(mut self) -> Result<(), String> {
+        self.closing = true;
+        let started = Instant::now();
+        while self.state != TcpState::TimeWait {
+            match self.state {
+                TcpState::Closed => return Ok(()),
+                TcpState::FinWait2
+                    if started.elapsed() > self.config.fin_timeout =>
+                {
+                    warn!("TCP: no FIN from {}, closing anyway", self.remote);
+                    self.set_state(TcpState::Closed);
+                    return Ok(());
+                }
+                _ => self.poll(Duration::from_millis(TCP_POLL_MS))?,
+            }
+        }
+
+        // Stay around to ACK the peer's FIN again if our ACK got lost
+        let until = Instant::now() + self.config.time_wait;
+        while let Some(left) = until.checked_duration_since(Instant::now()) {
+            self.poll(left)?;
+        }
+        self.set_state(TcpState::Closed);
+        Ok(())
+    }
+
+    /// Poll until `busy` is false
+    fn wait_while(
+        &mut self,
+        busy: impl Fn(&Self) -> bool,
+    ) -> Result<(), String> {
+        loop {
+            self.transmit()?;
+            if !busy(self) {
+                return Ok(());
+            }
+            if self.state == TcpState::Closed {
+                return Err(format!("Connection to {} closed", self.remote));
+            }
+            self.poll(Duration::from_millis(TCP_POLL_MS))?;
+        }
+    }
+
+    /// Send what the window allows, then wait up to `max_wait` (or the
+    /// retransmission timer) for one segment and handle it
+    fn poll(&mut self, max_wait: Duration) -> Result<(), String> {
+        self.transmit()?;
+        let wait = match self.rto_deadline {
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .min(max_wait),
+            None => max_wait,
+        };
+        if let Some(packet) = self.link.recv(wait) {
+            match Segment::from_bytes(&packet) {
+                Ok(segment)
+                    if segment.source == self.remote
+                        && segment.destination == self.local =>
+                {
+                    self.on_segment(segment)?;
+                }
+                Ok(segment) => {
+                    debug!("TCP: ignoring segment from {}", segment.source)
+                }
+                Err(e) => debug!("TCP: dropping packet: {}", e),
+            }
+        }
+        if self
+            .rto_deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.on_timeout()?;
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: TcpState) {
+        if state != self.state {
+            debug!(
+                "TCP {} -> {}: {} -> {}",
+                self.local,
+                self.remote,
+                self.state.name(),
+                state.name()
+            );
+            self.state = state;
+        }
+    }
+
+    fn send_segment(
+        &mut self,
+        seq: u32,
+        syn: bool,
+        fin: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
+        let segment = Segment {
+            source: self.local,
+            destination: self.remote,
+            seq,
+            ack: self.rcv_nxt,
+            // Everything but our first SYN acknowledges the peer
+            flags: TcpFlags {
+                syn,
+                ack: self.state != TcpState::SynSent,
+                fin,
+                rst: false,
+            },
+            window: self
+                .config
+                .window
+                .min(u16::MAX as usize) as u16,
+            payload,
+        };
+        self.link
+            .send(&segment.to_bytes()?)
+    }
+
+    fn send_ack(&mut self) -> Result<(), String> {
+        self.send_segment(self.snd_nxt, false, false, Vec::new())
+    }
+
+    /// Send a new segment and keep it for retransmission
+    fn send_new(
+        &mut self,
+        syn: bool,
+        fin: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), String> {
+        let segment = InFlight {
+            seq: self.snd_nxt,
+            syn,
+            fin,
+            payload,
+            sent_at: Instant::now(),
+            retransmitted: false,
+        };
+        self.snd_nxt = segment.end();
+        self.send_segment(segment.seq, syn, fin, segment.payload.clone())?;
+        self.in_flight
+            .push_back(segment);
+        if self.rto_deadline.is_none() {
+            self.rto_deadline = Some(Instant::now() + self.rto);
+        }
+        Ok(())
+    }
+
+    fn send_syn(&mut self) -> Result<(), String> {
+        self.send_new(true, false, Vec::new())
+    }
+
+    /// Send unsent data as far as the window goes, then the FIN if due
+    fn transmit(&mut self) -> Result<(), String> {
+        if !self.state.can_send() {
+            return Ok(());
+        }
+        let window = self
+            .config
+            .window
+            .min(self.peer_window as usize);
+        loop {
+            let in_flight = self
+                .snd_nxt
+                .wrapping_sub(self.snd_una) as usize;
+            let n = self
+                .config
+                .mss
+                .min(self.unsent.len())
+                .min(window.saturating_sub(in_flight));
+            if n == 0 {
+                break;
+            }
+            let payload = self
+                .unsent
+                .drain(..n)
+                .collect();
+            self.send_new(false, false, payload)?;
+        }
+
+        if self.closing && self.unsent.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.send_new(false, true, Vec::new())?;
+            self.set_state(match self.state {
+                TcpState::CloseWait => TcpState::LastAck,
+                _ => TcpState::FinWait1,
+            });
+        }
+        Ok(())
+    }
+
+    fn retransmit_first(&mut self) -> Result<(), String> {
+        let Some(first) = self.in_flight.front_mut() else {
+            return Ok(());
+        };
+        first.retransmitted = true;
+        let (seq, syn, fin, payload) =
+            (first.seq, first.syn, first.fin, first.payload.clone());
+        self.send_segment(seq, syn, fin, payload)
+    }
+
+    fn on_timeout(&mut self) -> Result<(), String> {
+        if self.in_flight.is_empty() {
+            self.rto_deadline = None;
+            return Ok(());
+        }
+        self.retries += 1;
+        if self.retries > self.config.max_retries {
+            self.set_state(TcpState::Closed);
+            return Err(format!("Connection to {} timed out", self.remote));
+        }
+        self.rto = (self.rto * 2).min(self.config.rto_max);
+        debug!(
+            "TCP: RTO, retransmitting {} (retry {}, next RTO {:?})",
+            self.snd_una, self.retries, self.rto
+        );
+        self.retransmit_first()?;
+        self.rto_deadline = Some(Instant::now() + self.rto);
+        Ok(())
+    }
+
+    /// RFC 6298 estimate from one round trip
+    fn on_rtt_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap() + 4 * self.rttvar)
+            .clamp(self.config.rto_min, self.config.rto_max);
+    }
+
+    fn on_segment(&mut self, segment: Segment) -> Result<(), String> {
+        if segment.flags.rst {
+            self.set_state(TcpState::Closed);
+            return Err(format!("Connection reset by {}", self.remote));
+        }
+
+        if self.state == TcpState::SynSent {
+            // Only the answer to our SYN moves us on
+            if segment.flags.syn
+                && segment.flags.ack
+                && segment.ack == self.snd_nxt
+            {
+                self.rcv_nxt = segment.seq.wrapping_add(1);
+                self.on_ack(&segment);
+                self.set_state(TcpState::Established);
+                self.send_ack()?;
+            }
+            return Ok(());
+        }
+
+        if segment.flags.syn {
+            // The peer missed our SYN-ACK, or our ACK of its SYN-ACK
+            if self.state == TcpState::SynReceived {
+                self.retransmit_first()?;
+            } else {
+                self.send_ack()?;
+            }
+            return Ok(());
+        }
+
+        if segment.flags.ack {
+            self.on_ack(&segment);
+        }
+        if self.state == TcpState::SynReceived {
+            // Data can't come before the handshake completes
+            return Ok(());
+        }
+        if !segment.payload.is_empty() || segment.flags.fin {
+            self.on_data(segment);
+            self.send_ack()?;
+        }
+        Ok(())
+    }
+
+    fn on_ack(&mut self, segment: &Segment) {
+        let ack = segment.ack;
+        self.peer_window = segment.window;
+        if !seq_lt(self.snd_una, ack) || seq_lt(self.snd_nxt, ack) {
+            // Nothing new, or acknowledging what we never sent
+            let duplicate = ack == self.snd_una
+                && !self.in_flight.is_empty()
+                && segment.payload.is_empty()
+                && !segment.flags.fin;
+            if duplicate {
+                self.dup_acks += 1;
+                if self.dup_acks == 3 {
+                    debug!("TCP: fast retransmit of {}", self.snd_una);
+                    let _ = self.retransmit_first();
+                }
+            }
+            return;
+        }
+
+        self.snd_una = ack;
+        self.dup_acks = 0;
+        self.retries = 0;
+        let now = Instant::now();
+        let mut sample = None;
+        let mut fin_acked = false;
+        while let Some(first) = self.in_flight.front() {
+            if seq_lt(ack, first.end()) {
+                break;
+            }
+            // Karn: a retransmitted segment says nothing about the RTT
+            if !first.retransmitted {
+                sample = Some(now - first.sent_at);
+            }
+            fin_acked |= first.fin;
+            if first.syn && self.state == TcpState::SynReceived {
+                self.set_state(TcpState::Established);
+            }
+            self.in_flight.pop_front();
+        }
+        if let Some(rtt) = sample {
+            self.on_rtt_sample(rtt);
+        }
+        self.rto_deadline = (!self.in_flight.is_empty()).then(|| now + self.rto);
+
+        if fin_acked {
+            self.set_state(match self.state {
+                TcpState::FinWait1 => TcpState::FinWait2,
+                TcpState::Closing => TcpState::TimeWait,
+                TcpState::LastAck => TcpState::Closed,
+                state => state,
+            });
+        }
+    }
+
+    /// Take in the payload and FIN of `segment`, in order
+    fn on_data(&mut self, segment: Segment) {
+        if !self.state.can_receive() {
+            // Retransmitted FIN after ours: just ACK it again
+            return;
+        }
+        let mut seq = segment.seq;
+        let mut payload = segment.payload;
+        let fin = segment.flags.fin;
+
+        let ahead = seq.wrapping_sub(self.rcv_nxt) as i32;
+        if ahead > 0 {
+            if (ahead as usize) < self.config.window {
+                self.out_of_order
+                    .insert(seq, (payload, fin));
+            }
+            return;
+        }
+        // Starts at or before rcv_nxt: drop what we already have
+        let seen = ahead.unsigned_abs() as usize;
+        if seen > payload.len() || seen == payload.len() && !fin {
+            return;
+        }
+        payload.drain(..seen);
+        seq = seq.wrapping_add(seen as u32);
+
+        let (mut payload, mut fin) = (payload, fin);
+        loop {
+            self.received.extend(&payload);
+            self.rcv_nxt = seq.wrapping_add(payload.len() as u32);
+            if fin {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.on_fin();
+                break;
+            }
+            match self
+                .out_of_order
+                .remove(&self.rcv_nxt)
+            {
+                Some(next) => {
+                    seq = self.rcv_nxt;
+                    (payload, fin) = next;
+                }
+                None => break,
+            }
+        }
+        let rcv_nxt = self.rcv_nxt;
+        let window = self.config.window;
+        self.out_of_order
+            .retain(|&seq, _| {
+                let ahead = seq.wrapping_sub(rcv_nxt) as i32;
+                ahead > 0 && (ahead as usize) < window
+            });
+    }
+
+    fn on_fin(&mut self) {
+        self.fin_received = true;
+        self.out_of_order.clear();
+        self.set_state(match self.state {
+            TcpState::Established => TcpState::CloseWait,
+            // Our FIN still unacknowledged
+            TcpState::FinWait1 => TcpState::Closing,
+            TcpState::FinWait2 => TcpState::TimeWait,
+            state => state,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::{Receiver, Sender};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// One end of an in-memory link that loses `loss` of the packets it
+    /// sends and corrupts as many again
+    struct MemoryLink {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+        loss: f64,
+        rng: StdRng,
+    }
+
+    impl PacketLink for MemoryLink {
+        fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+            if self
+                .rng
+                .random_bool(self.loss)
+            {
+                return Ok(());
+            }
+            let mut packet = packet.to_vec();
+            if self
+                .rng
+                .random_bool(self.loss)
+            {
+                let i = self
+                    .rng
+                    .random_range(20..packet.len());
+                packet[i] ^= 0x10;
+            }
+            let _ = self.tx.send(packet);
+            Ok(())
+        }
+
+        fn recv(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+            self.rx
+                .recv_timeout(timeout)
+                .ok()
+        }
+    }
+
+    fn link_pair(loss: f64, seed: u64) -> (MemoryLink, MemoryLink) {
+        let (a_tx, b_rx) = crossbeam_channel::unbounded();
+        let (b_tx, a_rx) = crossbeam_channel::unbounded();
+        let link = |tx, rx, seed| MemoryLink {
+            tx,
+            rx,
+            loss,
+            rng: StdRng::seed_from_u64(seed),
+        };
+        (link(a_tx, a_rx, seed), link(b_tx, b_rx, seed + 1))
+    }
+
+    fn fast_config() -> TcpConfig {
+        TcpConfig {
+            // Several segments in flight, unlike over the acoustic link
+            window: 4 * TCP_MSS,
+            rto_initial: Duration::from_millis(50),
+            rto_min: Duration::from_millis(20),
+            rto_max: Duration::from_millis(400),
+            max_retries: 20,
+            time_wait: Duration::from_millis(200),
+            fin_timeout: Duration::from_secs(5),
+            ..TcpConfig::default()
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddrV4 {
+        s.parse().unwrap()
+    }
+
+    /// Send `data` from a client to a server over links losing `loss`,
+    /// returning what the server read
+    fn transfer(data: &[u8], loss: f64, seed: u64) -> Vec<u8> {
+        let (client_link, server_link) = link_pair(loss, seed);
+        let server = std::thread::spawn(move || {
+            let listener = TcpListener::bind(
+                server_link,
+                addr("192.168.1.9:8080"),
+                fast_config(),
+            );
+            let mut stream = listener.accept().unwrap();
+            assert_eq!(stream.remote(), addr("192.168.1.2:50000"));
+            let mut received = Vec::new();
+            let mut buf = [0; 1000];
+            loop {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(stream.state, TcpState::CloseWait);
+            stream.close().unwrap();
+            received
+        });
+
+        let mut client = TcpStream::connect(
+            client_link,
+            addr("192.168.1.2:50000"),
+            addr("192.168.1.9:8080"),
+            fast_config(),
+        )
+        .unwrap();
+        assert_eq!(client.state, TcpState::Established);
+        for chunk in data.chunks(4096) {
+            client
+                .write_all(chunk)
+                .unwrap();
+        }
+        client.close().unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_segment_matches_etherparse() {
+        let segment = Segment {
+            source: addr("192.168.1.2:50000"),
+            destination: addr("192.168.1.9:80"),
+            seq: 0xDEADBEEF,
+            ack: 12345,
+            flags: TcpFlags {
+                ack: true,
+                fin: true,
+                ..TcpFlags::default()
+            },
+            window: 640,
+            payload: b"GET / HTTP/1.0\r\n\r\n".to_vec(),
+        };
+        let bytes = segment.to_bytes().unwrap();
+
+        let ip = etherparse::Ipv4HeaderSlice::from_slice(&bytes).unwrap();
+        assert_eq!(
+            ip.header_checksum(),
+            ip.to_header()
+                .calc_header_checksum()
+        );
+        let tcp = etherparse::TcpHeaderSlice::from_slice(&bytes[20..]).unwrap();
+        let expected = tcp
+            .to_header()
+            .calc_checksum_ipv4(&ip.to_header(), &segment.payload)
+            .unwrap();
+        assert_eq!(tcp.checksum(), expected);
+        assert!(tcp.fin() && tcp.ack() && !tcp.syn());
+        assert_eq!(tcp.sequence_number(), 0xDEADBEEF);
+
+        assert_eq!(Segment::from_bytes(&bytes), Ok(segment));
+        let mut corrupt = bytes.clone();
+        corrupt[45] ^= 1;
+        assert!(Segment::from_bytes(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_sequence_numbers_wrap() {
+        assert!(seq_lt(u32::MAX - 10, 5));
+        assert!(!seq_lt(5, u32::MAX - 10));
+        assert!(!seq_lt(7, 7));
+    }
+
+    #[test]
+    fn test_transfer_over_clean_link() {
+        let data: Vec<u8> = (0..100 * 1024)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let received = transfer(&data, 0.0, 1);
+        assert!(received == data, "received {} bytes", received.len());
+    }
+
+    #[test]
+    fn test_transfer_over_lossy_link() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let data: Vec<u8> = (0..100 * 1024)
+            .map(|_| rng.random())
+            .collect();
+        // 5% lost and 5% corrupted, both ways, handshake and FINs included
+        let received = transfer(&data, 0.05, 7);
+        assert!(received == data, "received {} bytes", received.len());
+    }
+
+    #[test]
+    fn test_connect_gives_up_without_peer() {
+        let (client_link, _server_link) = link_pair(0.0, 3);
+        let config = TcpConfig {
+            max_retries: 2,
+            ..fast_config()
+        };
+        let result = TcpStream::connect(
+            client_link,
+            addr("192.168.1.2:50000"),
+            addr("192.168.1.9:8080"),
+            config,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -1,429 +1,1650 @@
-use crate::audio::recorder;
+use crate::audio::{backend, recorder};
+use crate::mac::params::MacParams;
 use crate::net::router::InterfaceType;
+use crate::phy::params::PhyParams;
 use crate::phy::{FrameType, LineCodingKind};
+use crate::ui::dashboard::DashboardEvent;
 use crate::utils::consts::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tracing::{debug, error, info, warn};
 
-use crate::device::jack::connect_system_ports;
+/// Flag Ctrl+C clears, so a tool can wind down (summary, audio client)
+/// instead of being killed
+fn stop_on_ctrlc() -> Arc<AtomicBool> {
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    }) {
+        warn!("Ctrl+C won't stop cleanly: {}", e);
+    }
+    running
+}
+
+/// Payload sizes a ping sweep goes through, `min,max,step` on the command
+/// line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingSweep {
+    pub min: usize,
+    pub max: usize,
+    pub step: usize,
+}
+
+impl PingSweep {
+    pub fn sizes(&self) -> Vec<usize> {
+        (self.min..=self.max)
+            .step_by(self.step)
+            .collect()
+    }
+}
+
+impl std::str::FromStr for PingSweep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid sweep '{}', expected min,max,step", s);
+        let parts: Vec<usize> = s
+            .split(',')
+            .map(|part| part.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        match parts[..] {
+            [min, max, step] if min <= max && step > 0 => {
+                Ok(Self { min, max, step })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How run_ping pings
+#[derive(Debug, Clone)]
+pub struct PingOptions {
+    /// Echo requests per payload size
+    pub count: u16,
+    pub interval: std::time::Duration,
+    /// How long to wait for each reply
+    pub timeout: std::time::Duration,
+    pub payload_size: usize,
+    /// Go through these payload sizes instead of `payload_size`
+    pub sweep: Option<PingSweep>,
+    /// Only the summary, no line per packet
+    pub quiet: bool,
+    pub arp_ttl: std::time::Duration,
+    /// Record the IP traffic to this pcap file
+    pub capture: Option<String>,
+    /// Ping an IPv6 address with ICMPv6, see net::ipv6
+    pub ipv6: bool,
+    pub phy: PhyParams,
+    pub mac: MacParams,
+}
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        Self {
+            count: PING_PACKET_COUNT,
+            interval: std::time::Duration::from_millis(PING_INTERVAL_MS),
+            timeout: std::time::Duration::from_millis(PING_TIMEOUT_MS),
+            payload_size: PING_PAYLOAD_SIZE,
+            sweep: None,
+            quiet: false,
+            arp_ttl: std::time::Duration::from_millis(ARP_ENTRY_TTL_MS),
+            capture: None,
+            ipv6: false,
+            phy: PhyParams::default(),
+            mac: MacParams::default(),
+        }
+    }
+}
+
+/// How the UDP and TCP tools run as a host on the acoustic link
+#[derive(Debug, Clone)]
+pub struct HostOptions {
+    pub arp_ttl: std::time::Duration,
+    pub phy: PhyParams,
+    pub mac: MacParams,
+}
+
+impl Default for HostOptions {
+    fn default() -> Self {
+        Self {
+            arp_ttl: std::time::Duration::from_millis(ARP_ENTRY_TTL_MS),
+            phy: PhyParams::default(),
+            mac: MacParams::default(),
+        }
+    }
+}
+
+/// How an echo reply relates to the requests sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReplyMatch {
+    /// First reply to its request; `late` if a later request had been
+    /// sent by then
+    First { rtt_ms: f32, late: bool },
+    /// Another reply to an answered request
+    Duplicate { rtt_ms: f32 },
+    /// For a request we never sent
+    Unknown,
+}
+
+/// What a ping run saw
+#[derive(Debug, Clone, Default)]
+struct PingStats {
+    transmitted: u32,
+    /// Requests answered, duplicates not included
+    received: u32,
+    duplicates: u32,
+    /// Replies that came after a later request had gone out
+    out_of_order: u32,
+    rtts_ms: Vec<f32>,
+    /// Send time of every request, by sequence number
+    sent: HashMap<u16, std::time::Instant>,
+    answered: HashSet<u16>,
+    newest: Option<u16>,
+}
+
+impl PingStats {
+    /// Note request `seq` going out at `at`. Sending the same sequence
+    /// again (a retry) restarts its clock but counts once.
+    fn on_sent(&mut self, seq: u16, at: std::time::Instant) {
+        if self
+            .sent
+            .insert(seq, at)
+            .is_none()
+        {
+            self.transmitted += 1;
+        }
+        self.newest = Some(seq);
+    }
+
+    /// Match a reply to `seq` received at `at` to its request
+    fn on_reply(&mut self, seq: u16, at: std::time::Instant) -> ReplyMatch {
+        let Some(sent_at) = self.sent.get(&seq) else {
+            return ReplyMatch::Unknown;
+        };
+        let rtt_ms = at
+            .saturating_duration_since(*sent_at)
+            .as_secs_f32()
+            * 1000.0;
+        if !self.answered.insert(seq) {
+            self.duplicates += 1;
+            return ReplyMatch::Duplicate { rtt_ms };
+        }
+        self.received += 1;
+        self.rtts_ms.push(rtt_ms);
+        let late = self.newest != Some(seq);
+        if late {
+            self.out_of_order += 1;
+        }
+        ReplyMatch::First { rtt_ms, late }
+    }
+
+    fn is_answered(&self, seq: u16) -> bool {
+        self.answered.contains(&seq)
+    }
+
+    fn loss_percent(&self) -> f32 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+        (self.transmitted - self.received) as f32 / self.transmitted as f32
+            * 100.0
+    }
+
+    /// min/avg/max/mdev in milliseconds, None without replies
+    fn rtt_summary(&self) -> Option<(f32, f32, f32, f32)> {
+        if self.rtts_ms.is_empty() {
+            return None;
+        }
+        let n = self.rtts_ms.len() as f32;
+        let min = self
+            .rtts_ms
+            .iter()
+            .cloned()
+            .fold(f32::INFINITY, f32::min);
+        let max = self
+            .rtts_ms
+            .iter()
+            .cloned()
+            .fold(f32::NEG_INFINITY, f32::max);
+        let avg = self
+            .rtts_ms
+            .iter()
+            .sum::<f32>()
+            / n;
+        // Like iputils: sqrt(mean of squares - square of mean)
+        let mean_sq = self
+            .rtts_ms
+            .iter()
+            .map(|rtt| rtt * rtt)
+            .sum::<f32>()
+            / n;
+        let mdev = (mean_sq - avg * avg)
+            .max(0.0)
+            .sqrt();
+        Some((min, avg, max, mdev))
+    }
+
+    /// Log the statistics lines closing a run to `target`
+    fn log_summary(
+        &self,
+        target: impl std::fmt::Display,
+        elapsed: std::time::Duration,
+        link: &crate::mac::stats::LinkStats,
+    ) {
+        info!("\n--- {} ping statistics ---", target);
+        info!(
+            "{} packets transmitted, {} received, {} duplicates, {} out of order, {:.1}% packet loss, time {:.2}s",
+            self.transmitted,
+            self.received,
+            self.duplicates,
+            self.out_of_order,
+            self.loss_percent(),
+            elapsed.as_secs_f32()
+        );
+        info!(
+            "link: {} frames sent, {} retransmissions, {} ACK timeouts, {} CRC failures",
+            link.frames_sent,
+            link.retransmissions,
+            link.ack_timeouts,
+            link.crc_failures
+        );
+        if let Some((min, avg, max, mdev)) = self.rtt_summary() {
+            info!(
+                "rtt min/avg/max/mdev = {:.2}/{:.2}/{:.2}/{:.2} ms",
+                min, avg, max, mdev
+            );
+        }
+        link.delays.log_summary();
+    }
 
+    /// Process exit status, as with iputils: 1 when nothing came back
+    fn exit_code(&self) -> i32 {
+        if self.received == 0 { 1 } else { 0 }
+    }
+}
+
+/// Ping `target`, returning the exit status for the process: 0 if any
+/// reply came back, 1 if none did, 2 if the target couldn't be reached
+/// at all
 pub fn run_ping(
     target: String,
     local_ip_str: String,
     gateway: Option<String>,
+    options: PingOptions,
+) -> i32 {
+    use crate::net::arp::ArpTable;
+    use std::net::Ipv4Addr;
+
+    if options.ipv6 {
+        return run_ping6(target, local_ip_str, gateway, options);
+    }
+
+    // Parse IP addresses
+    let target_ip: Ipv4Addr = target
+        .parse()
+        .expect("Invalid target IP");
+    let local_ip: Ipv4Addr = local_ip_str
+        .parse()
+        .expect("Invalid local IP");
+
+    let mut arp = ArpTable::new();
+    arp.set_ttl(options.arp_ttl);
+    let local_mac = arp
+        .get_mac(&local_ip)
+        .expect("Local IP not in ARP table");
+
+    let (_jack, mut interface) =
+        open_acoustic_interface("ping", local_mac, &options.phy, &options.mac);
+    start_capture(&mut interface, options.capture.as_deref());
+
+    // Known hosts go direct, anything else through the gateway if there is
+    // one; resolve whichever isn't in the table yet
+    let next_hop = match gateway {
+        Some(gateway_str)
+            if arp
+                .get_mac(&target_ip)
+                .is_none() =>
+        {
+            gateway_str
+                .parse()
+                .expect("Invalid gateway IP")
+        }
+        _ => target_ip,
+    };
+    let dest_mac = match arp.resolve(
+        &mut interface,
+        local_ip,
+        next_hop,
+        std::time::Duration::from_millis(ARP_RESOLVE_TIMEOUT_MS),
+    ) {
+        Ok(mac) => mac,
+        Err(e) => {
+            error!("Cannot reach {}: {}", next_hop, e);
+            return 2;
+        }
+    };
+
+    info!(
+        "PING {} ({}) from {} ({})",
+        target_ip, dest_mac, local_ip, local_mac
+    );
+
+    let running = stop_on_ctrlc();
+    let mut stats = PingStats::default();
+    let ping_start = std::time::Instant::now();
+
+    // A Modern taste to use a random identifier for ICMP
+    let identifier = rand::random::<u16>();
+    let mut seq = 0u16;
+
+    // Smallest MTU reported along the path, if any
+    let mut path_mtu: Option<u16> = None;
+    // Sweeps probe sizes as they are, single size pings shrink to fit
+    let sweeping = options.sweep.is_some();
+    let sizes = match options.sweep {
+        Some(sweep) => sweep.sizes(),
+        None => vec![options.payload_size],
+    };
+    let mut largest_answered: Option<usize> = None;
+
+    // Payload size of each request, for replies that come in late
+    let mut sent_sizes: HashMap<u16, usize> = HashMap::new();
+
+    'sizes: for (i, &size) in sizes.iter().enumerate() {
+        let mut payload_size = size;
+        for n in 0..options.count {
+            if i + n as usize > 0 {
+                std::thread::sleep(options.interval);
+            }
+            if !running.load(Ordering::SeqCst) {
+                break 'sizes;
+            }
+            seq = seq.wrapping_add(1);
+
+            // Retried with a smaller payload while the path asks for it
+            'retry: loop {
+                let ip_bytes = build_echo_request(
+                    local_ip,
+                    target_ip,
+                    identifier,
+                    seq,
+                    payload_size,
+                );
+
+                if !options.quiet {
+                    info!(
+                        "Sending ICMP Echo Request seq={} ({} bytes)...",
+                        seq, payload_size
+                    );
+                }
+
+                // Send IP Packet
+                if let Err(e) =
+                    interface.send_packet(&ip_bytes, dest_mac, FrameType::Data)
+                {
+                    error!("Failed to send packet: {}", e);
+                    break;
+                }
+                let deadline = std::time::Instant::now() + options.timeout;
+                stats.on_sent(seq, std::time::Instant::now());
+                sent_sizes.insert(seq, payload_size);
+
+                // Take whatever comes until this request is answered,
+                // replies to earlier ones included
+                while !stats.is_answered(seq) && running.load(Ordering::SeqCst) {
+                    let received = deadline
+                        .checked_duration_since(std::time::Instant::now())
+                        .ok_or_else(|| "Timeout".to_string())
+                        .and_then(|left| {
+                            interface.receive_packet_from(Some(left))
+                        });
+                    let (data, src_mac) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            if !options.quiet {
+                                warn!("Request seq={} timed out: {}", seq, e);
+                            }
+                            break;
+                        }
+                    };
+                    let now = std::time::Instant::now();
+                    // Answer ARP requests for us, learn from everything
+                    if let Some(request) = arp.observe(&data, src_mac, now) {
+                        answer_arp(&mut interface, &request, local_ip);
+                        continue;
+                    }
+
+                    match parse_ping_reply(&data, identifier) {
+                        Ok(PingReply::Echo {
+                            seq: reply_seq,
+                            ttl,
+                        }) => {
+                            let (rtt_ms, note) = match stats
+                                .on_reply(reply_seq, now)
+                            {
+                                ReplyMatch::First { rtt_ms, late } => {
+                                    largest_answered = largest_answered.max(
+                                        sent_sizes
+                                            .get(&reply_seq)
+                                            .copied(),
+                                    );
+                                    (rtt_ms, if late { " (late)" } else { "" })
+                                }
+                                ReplyMatch::Duplicate { rtt_ms } => {
+                                    (rtt_ms, " DUP!")
+                                }
+                                ReplyMatch::Unknown => continue,
+                            };
+                            if !options.quiet {
+                                info!(
+                                    "Reply from {}: bytes={} seq={} time={:.2}ms TTL={}{}",
+                                    target_ip,
+                                    data.len(),
+                                    reply_seq,
+                                    rtt_ms,
+                                    ttl,
+                                    note
+                                );
+                            }
+                        }
+                        Ok(PingReply::FragmentationNeeded {
+                            seq: quoted_seq,
+                            mtu,
+                        }) if quoted_seq == seq => {
+                            path_mtu = Some(
+                                path_mtu.map_or(mtu, |known| known.min(mtu)),
+                            );
+                            match payload_for_mtu(mtu, payload_size) {
+                                Some(smaller) if !sweeping => {
+                                    if !options.quiet {
+                                        info!(
+                                            "Frag needed: path MTU {}, retrying with {} bytes",
+                                            mtu, smaller
+                                        );
+                                    }
+                                    payload_size = smaller;
+                                    continue 'retry;
+                                }
+                                _ if !options.quiet => {
+                                    warn!(
+                                        "Frag needed: {} bytes don't fit MTU {}",
+                                        payload_size, mtu
+                                    );
+                                }
+                                _ => {}
+                            }
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("{}", e);
+                        }
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    // Print statistics
+    stats.log_summary(target_ip, ping_start.elapsed(), &interface.stats());
+    if sweeping {
+        match largest_answered {
+            Some(size) => info!("largest payload answered: {} bytes", size),
+            None => info!("no payload size was answered"),
+        }
+    }
+    if let Some(mtu) = path_mtu {
+        info!("path MTU to {} is {} bytes", target_ip, mtu);
+    }
+    stats.exit_code()
+}
+
+/// run_ping for an IPv6 target, reached over the link by its link-local
+/// address. There is no IPv6 routing, so no gateway.
+fn run_ping6(
+    target: String,
+    local_ip_str: String,
+    gateway: Option<String>,
+    options: PingOptions,
+) -> i32 {
+    use crate::net::arp::ArpTable;
+    use crate::net::ipv6::{self, Icmpv6Message, NeighborTable};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let target_ip: Ipv6Addr = target
+        .parse()
+        .expect("Invalid target IPv6 address");
+    let local_ip: Ipv4Addr = local_ip_str
+        .parse()
+        .expect("Invalid local IP");
+    let local_mac = ArpTable::new()
+        .get_mac(&local_ip)
+        .expect("Local IP not in ARP table");
+    let local = ipv6::link_local(local_mac);
+    if gateway.is_some() {
+        warn!("No IPv6 routing, ignoring the gateway");
+    }
+
+    let sizes = match options.sweep {
+        Some(sweep) => sweep.sizes(),
+        None => vec![options.payload_size],
+    };
+    // IPv6 is never fragmented: IPv6 and ICMPv6 headers, then the payload
+    let largest = DEFAULT_MTU - 48;
+    if let Some(size) = sizes
+        .iter()
+        .find(|size| **size > largest)
+    {
+        error!(
+            "{} byte payload doesn't fit the link, at most {} over IPv6",
+            size, largest
+        );
+        return 2;
+    }
+
+    let (_jack, mut interface) =
+        open_acoustic_interface("ping6", local_mac, &options.phy, &options.mac);
+    start_capture(&mut interface, options.capture.as_deref());
+
+    let mut neighbors = NeighborTable::new();
+    neighbors.set_ttl(options.arp_ttl);
+    let dest_mac = match neighbors.resolve(
+        &mut interface,
+        local,
+        target_ip,
+        std::time::Duration::from_millis(ARP_RESOLVE_TIMEOUT_MS),
+    ) {
+        Ok(mac) => mac,
+        Err(e) => {
+            error!("Cannot reach {}: {}", target_ip, e);
+            return 2;
+        }
+    };
+
+    info!(
+        "PING {} ({}) from {} ({})",
+        target_ip, dest_mac, local, local_mac
+    );
+
+    let running = stop_on_ctrlc();
+    let mut stats = PingStats::default();
+    let ping_start = std::time::Instant::now();
+    let identifier = rand::random::<u16>();
+    let mut seq = 0u16;
+    let mut largest_answered: Option<usize> = None;
+    let mut sent_sizes: HashMap<u16, usize> = HashMap::new();
+
+    'sizes: for (i, &payload_size) in sizes.iter().enumerate() {
+        for n in 0..options.count {
+            if i + n as usize > 0 {
+                std::thread::sleep(options.interval);
+            }
+            if !running.load(Ordering::SeqCst) {
+                break 'sizes;
+            }
+            seq = seq.wrapping_add(1);
+            let packet = ipv6::echo_request(
+                local,
+                target_ip,
+                identifier,
+                seq,
+                &vec![0u8; payload_size],
+            );
+            if !options.quiet {
+                info!(
+                    "Sending ICMPv6 Echo Request seq={} ({} bytes)...",
+                    seq, payload_size
+                );
+            }
+            if let Err(e) =
+                interface.send_packet(&packet, dest_mac, FrameType::Data)
+            {
+                error!("Failed to send packet: {}", e);
+                continue;
+            }
+            let deadline = std::time::Instant::now() + options.timeout;
+            stats.on_sent(seq, std::time::Instant::now());
+            sent_sizes.insert(seq, payload_size);
+
+            while !stats.is_answered(seq) && running.load(Ordering::SeqCst) {
+                let received = deadline
+                    .checked_duration_since(std::time::Instant::now())
+                    .ok_or_else(|| "Timeout".to_string())
+                    .and_then(|left| interface.receive_packet_from(Some(left)));
+                let (data, src_mac) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        if !options.quiet {
+                            warn!("Request seq={} timed out: {}", seq, e);
+                        }
+                        break;
+                    }
+                };
+                let now = std::time::Instant::now();
+                let Some(reply) = neighbors.observe(&data, src_mac, now) else {
+                    continue;
+                };
+                // Answer solicitations for us while waiting
+                if let Some(answer) = ipv6::answer(&reply, local, local_mac)
+                    && let Err(e) =
+                        interface.send_packet(&answer, src_mac, FrameType::Data)
+                {
+                    warn!("Failed to answer {}: {}", reply.source, e);
+                }
+                let Icmpv6Message::EchoReply {
+                    identifier: reply_id,
+                    seq: reply_seq,
+                } = reply.message
+                else {
+                    continue;
+                };
+                if reply_id != identifier {
+                    continue;
+                }
+                let (rtt_ms, note) = match stats.on_reply(reply_seq, now) {
+                    ReplyMatch::First { rtt_ms, late } => {
+                        largest_answered = largest_answered.max(
+                            sent_sizes
+                                .get(&reply_seq)
+                                .copied(),
+                        );
+                        (rtt_ms, if late { " (late)" } else { "" })
+                    }
+                    ReplyMatch::Duplicate { rtt_ms } => (rtt_ms, " DUP!"),
+                    ReplyMatch::Unknown => continue,
+                };
+                if !options.quiet {
+                    info!(
+                        "Reply from {}: bytes={} seq={} time={:.2}ms hlim={}{}",
+                        reply.source,
+                        data.len(),
+                        reply_seq,
+                        rtt_ms,
+                        reply.hop_limit,
+                        note
+                    );
+                }
+            }
+        }
+    }
+
+    stats.log_summary(target_ip, ping_start.elapsed(), &interface.stats());
+    if options.sweep.is_some() {
+        match largest_answered {
+            Some(size) => info!("largest payload answered: {} bytes", size),
+            None => info!("no payload size was answered"),
+        }
+    }
+    stats.exit_code()
+}
+
+/// What came back for an echo request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PingReply {
+    Echo {
+        seq: u16,
+        ttl: u8,
+    },
+    /// Request `seq` was too big for a hop, whose MTU this is
+    FragmentationNeeded {
+        seq: u16,
+        mtu: u16,
+    },
+    /// Something not about our requests
+    Unrelated,
+}
+
+/// Echo request with `payload_size` zero bytes. DF is set whenever the
+/// packet fits the acoustic MTU, so a smaller MTU further along the path
+/// gets reported back instead of fragmenting.
+fn build_echo_request(
+    local_ip: std::net::Ipv4Addr,
+    target_ip: std::net::Ipv4Addr,
+    identifier: u16,
+    seq: u16,
     payload_size: usize,
+) -> Vec<u8> {
+    use etherparse::{
+        Icmpv4Header, Icmpv4Type, IpNumber, Ipv4Header as EtherIpv4Header,
+    };
+
+    // Build ICMP Echo Request using etherparse
+    // payload --> icmp header --> ip header
+    let payload = vec![0u8; payload_size];
+
+    let icmp_header = Icmpv4Header::with_checksum(
+        Icmpv4Type::EchoRequest(etherparse::IcmpEchoHeader {
+            id: identifier,
+            seq,
+        }),
+        &payload,
+    );
+    let icmp_bytes = {
+        let mut buf = Vec::new();
+        icmp_header
+            .write(&mut buf)
+            .expect("Failed to write ICMP header");
+        buf.extend_from_slice(&payload);
+        buf
+    };
+
+    let total_len = 20 + icmp_bytes.len();
+    let mut ip_header = EtherIpv4Header {
+        dscp: Default::default(),
+        ecn: Default::default(),
+        total_len: total_len as u16,
+        identification: seq,
+        dont_fragment: total_len <= DEFAULT_MTU,
+        more_fragments: false,
+        fragment_offset: Default::default(),
+        time_to_live: IP_TTL,
+        protocol: IpNumber::ICMP,
+        header_checksum: 0,
+        source: local_ip.octets(),
+        destination: target_ip.octets(),
+        options: Default::default(),
+    };
+    ip_header.header_checksum = ip_header.calc_header_checksum();
+
+    let mut buf = Vec::new();
+    ip_header
+        .write(&mut buf)
+        .expect("Failed to write IP header");
+    buf.extend_from_slice(&icmp_bytes);
+    buf
+}
+
+/// Make sense of a packet received while pinging as `identifier`
+fn parse_ping_reply(data: &[u8], identifier: u16) -> Result<PingReply, String> {
+    use etherparse::icmpv4::DestUnreachableHeader;
+    use etherparse::{Icmpv4Slice, Icmpv4Type, Ipv4HeaderSlice};
+
+    // Parse IPv4
+    let ip_slice = Ipv4HeaderSlice::from_slice(data)
+        .map_err(|e| format!("Failed to parse IP header: {:?}", e))?;
+
+    // Parse ICMP
+    let icmp_slice = Icmpv4Slice::from_slice(&data[ip_slice.slice().len()..])
+        .map_err(|e| format!("Failed to parse ICMP: {:?}", e))?;
+
+    match icmp_slice.header().icmp_type {
+        // Replies to any of our requests, late ones included
+        Icmpv4Type::EchoReply(echo) if echo.id == identifier => {
+            Ok(PingReply::Echo {
+                seq: echo.seq,
+                ttl: ip_slice.ttl(),
+            })
+        }
+        Icmpv4Type::DestinationUnreachable(
+            DestUnreachableHeader::FragmentationNeeded { next_hop_mtu },
+        ) => {
+            // The error quotes our header and the first 8 bytes after it
+            let quoted = icmp_slice.payload();
+            let request = Ipv4HeaderSlice::from_slice(quoted)
+                .ok()
+                .and_then(|ip| quoted.get(ip.slice().len()..))
+                .and_then(|icmp| Icmpv4Slice::from_slice(icmp).ok())
+                .map(|icmp| icmp.icmp_type());
+            match request {
+                Some(Icmpv4Type::EchoRequest(echo)) if echo.id == identifier => {
+                    Ok(PingReply::FragmentationNeeded {
+                        seq: echo.seq,
+                        mtu: next_hop_mtu,
+                    })
+                }
+                _ => Ok(PingReply::Unrelated),
+            }
+        }
+        _ => Ok(PingReply::Unrelated),
+    }
+}
+
+/// Payload for the next try after a hop reported `mtu`, None if going
+/// smaller wouldn't help
+fn payload_for_mtu(mtu: u16, payload_size: usize) -> Option<usize> {
+    // IPv4 and ICMP headers
+    let payload = (mtu as usize).checked_sub(28)?;
+    (payload < payload_size).then_some(payload)
+}
+
+pub fn run_ip_host(
+    local_ip_str: String,
+    arp_ttl: std::time::Duration,
+    capture: Option<String>,
+    ipv6: bool,
+    phy_params: &PhyParams,
+    mac_params: &MacParams,
+) {
+    run_ip_host_until(
+        local_ip_str,
+        arp_ttl,
+        capture,
+        ipv6,
+        phy_params,
+        mac_params,
+        stop_on_ctrlc(),
+    );
+}
+
+/// run_ip_host, serving until `running` is cleared
+pub(crate) fn run_ip_host_until(
+    local_ip_str: String,
+    arp_ttl: std::time::Duration,
+    capture: Option<String>,
+    ipv6: bool,
+    phy_params: &PhyParams,
+    mac_params: &MacParams,
+    running: Arc<AtomicBool>,
 ) {
     use crate::mac::acoustic_interface::AcousticInterface;
+    use crate::net::PayloadKind;
     use crate::net::arp::ArpTable;
+    use crate::net::ipv6::{self as v6, NeighborTable};
+    use std::net::Ipv4Addr;
+
+    let local_ip: Ipv4Addr = local_ip_str
+        .parse()
+        .expect("Invalid local IP");
+    let mut arp = ArpTable::new();
+    arp.set_ttl(arp_ttl);
+    let local_mac = arp
+        .get_mac(&local_ip)
+        .expect("Local IP not in ARP table");
+
+    info!("Starting IP Host on {} ({})", local_ip, local_mac);
+    // Answered at the MAC's link-local address, see net::ipv6
+    let mut neighbors = ipv6.then(|| {
+        let mut neighbors = NeighborTable::new();
+        neighbors.set_ttl(arp_ttl);
+        neighbors
+    });
+    let local_v6 = v6::link_local(local_mac);
+    if ipv6 {
+        info!("IPv6 on {}", local_v6);
+    }
+
+    // Setup audio
+    let client = backend::open(&format!(
+        "{}_host_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))
+    .unwrap();
+
+    let sample_rate = client.sample_rate() as u32;
+    let shared = recorder::AppShared::new(sample_rate as usize * 10);
+    let shared_cb = shared.clone();
+
+    let active_client = client
+        .activate(recorder::build_process_closure(shared_cb))
+        .unwrap();
+
+    // Setup IP Interface
+    let mut interface = AcousticInterface::new(
+        shared.clone(),
+        sample_rate,
+        LineCodingKind::FourBFiveB,
+        local_mac,
+        phy_params,
+        mac_params,
+    );
+    start_capture(&mut interface, capture.as_deref());
+
+    // Announce ourselves so neighbors can skip resolving us
+    let announce = crate::net::arp::ArpPacket::gratuitous(local_mac, local_ip);
+    if let Err(e) = interface.send_packet(
+        &announce.to_bytes(),
+        crate::mac::types::BROADCAST,
+        FrameType::Data,
+    ) {
+        warn!("Failed to announce {}: {}", local_ip, e);
+    }
+
+    // Listen for packets until stopped
+    while running.load(Ordering::SeqCst) {
+        // Get a packet from interface
+        let (data, src_mac) = match interface
+            .receive_packet_from(Some(std::time::Duration::from_millis(100)))
+        {
+            Ok(received) => received,
+            Err(e) => {
+                if e != "Timeout" {
+                    warn!("Failed to receive packet: {:?}", e);
+                }
+                continue;
+            }
+        };
+
+        let now = std::time::Instant::now();
+        arp.expire(now);
+        match neighbors.as_mut() {
+            Some(neighbors) if PayloadKind::of(&data) == PayloadKind::Ipv6 => {
+                neighbors.expire(now);
+                v6::serve_packet(
+                    &mut interface,
+                    neighbors,
+                    local_v6,
+                    &data,
+                    src_mac,
+                );
+            }
+            _ => serve_host_packet(
+                &mut interface,
+                &mut arp,
+                local_ip,
+                &data,
+                src_mac,
+            ),
+        }
+    }
+
+    info!("Stopping IP Host...");
+    if let Err(e) = active_client.deactivate() {
+        error!("Error deactivating client: {}", e);
+    }
+}
+
+/// What an IP host does with one received packet: answer ARP requests for
+/// `local_ip` and ICMP echo requests, learning the sender either way
+fn serve_host_packet(
+    interface: &mut crate::mac::acoustic_interface::AcousticInterface,
+    arp: &mut crate::net::arp::ArpTable,
+    local_ip: std::net::Ipv4Addr,
+    data: &[u8],
+    src_mac: u8,
+) {
+    use crate::net::PayloadKind;
     use etherparse::{
         Icmpv4Header, Icmpv4Type, IpNumber, Ipv4Header as EtherIpv4Header,
     };
     use std::net::Ipv4Addr;
 
-    // Parse IP addresses
-    let target_ip: Ipv4Addr = target
-        .parse()
-        .expect("Invalid target IP");
+    match PayloadKind::of(data) {
+        PayloadKind::Arp => {
+            if let Some(request) =
+                arp.observe(data, src_mac, std::time::Instant::now())
+            {
+                answer_arp(interface, &request, local_ip);
+            }
+            return;
+        }
+        PayloadKind::Ipv4 => {
+            arp.observe(data, src_mac, std::time::Instant::now());
+        }
+        PayloadKind::Ipv6 | PayloadKind::Unknown => {
+            debug!("Dropping {} byte packet of unknown kind", data.len());
+            return;
+        }
+    }
+
+    // Parse IPv4
+    let ip_slice = match etherparse::Ipv4HeaderSlice::from_slice(data) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to parse IP header: {:?}", e);
+            return;
+        }
+    };
+
+    // Check if it's for us
+    if ip_slice.destination() != local_ip.octets() {
+        return; // Not for us
+    }
+
+    // Ensure packet is large enough for claimed IP header length
+    let ip_header_len = ip_slice.ihl() as usize * 4;
+    if data.len() < ip_header_len {
+        warn!("Received packet too short for IP header length");
+        return;
+    }
+
+    // Parse ICMP
+    let icmp_data = &data[ip_header_len..];
+    let icmp_slice = match etherparse::Icmpv4Slice::from_slice(icmp_data) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to parse ICMP: {:?}", e);
+            return;
+        }
+    };
+
+    // Only handle Echo Requests
+    let echo = match icmp_slice.header().icmp_type {
+        Icmpv4Type::EchoRequest(echo) => echo,
+        _ => return,
+    };
+
+    info!("Received ICMP Echo Request from {:?}", ip_slice.source());
+
+    // Build Echo Reply using etherparse
+    let payload = icmp_slice.payload().to_vec();
+    let reply_icmp_header =
+        Icmpv4Header::new(Icmpv4Type::EchoReply(etherparse::IcmpEchoHeader {
+            id: echo.id,
+            seq: echo.seq,
+        }));
+    let reply_icmp_bytes = {
+        let mut buf = Vec::new();
+        reply_icmp_header
+            .write(&mut buf)
+            .expect("Failed to write ICMP header");
+        buf.extend_from_slice(&payload);
+        buf
+    };
+
+    // Build IPv4 reply header
+    let reply_ip_header = EtherIpv4Header {
+        dscp: Default::default(),
+        ecn: Default::default(),
+        total_len: (20 + reply_icmp_bytes.len()) as u16,
+        identification: 0,
+        dont_fragment: false,
+        more_fragments: false,
+        fragment_offset: Default::default(),
+        time_to_live: IP_TTL,
+        protocol: IpNumber::ICMP,
+        header_checksum: 0,
+        source: local_ip.octets(),
+        destination: ip_slice.source(),
+        options: Default::default(),
+    };
+
+    let reply_bytes = {
+        let mut buf = Vec::new();
+        reply_ip_header
+            .write(&mut buf)
+            .expect("Failed to write IP header");
+        buf.extend_from_slice(&reply_icmp_bytes);
+        buf
+    };
+
+    // Find dest MAC
+    let src_ip = Ipv4Addr::from(ip_slice.source());
+    let dest_mac = match arp.get_mac(&src_ip) {
+        Some(m) => m,
+        None => {
+            warn!("Unknown source IP {}, cannot reply", src_ip);
+            return;
+        }
+    };
+
+    info!("Sending Echo Reply to {} ({})", src_ip, dest_mac);
+
+    if let Err(e) = interface.send_packet(&reply_bytes, dest_mac, FrameType::Ack)
+    {
+        error!("Failed to send reply: {}", e);
+    }
+}
+
+/// Reply to an ARP request if it asks for `local_ip`
+fn answer_arp(
+    interface: &mut crate::mac::acoustic_interface::AcousticInterface,
+    request: &crate::net::arp::ArpPacket,
+    local_ip: std::net::Ipv4Addr,
+) {
+    use crate::net::arp::{ArpOp, ArpPacket};
+
+    // Gratuitous ARPs only announce, nobody answers them
+    if request.op != ArpOp::Request
+        || request.target_ip != local_ip
+        || request.is_gratuitous()
+    {
+        return;
+    }
+    let reply = ArpPacket::reply_to(request, interface.local_mac());
+    debug!(
+        "ARP: telling {} that {} is at {}",
+        request.sender_ip, local_ip, reply.sender_mac
+    );
+    if let Err(e) = interface.send_packet(
+        &reply.to_bytes(),
+        request.sender_mac,
+        FrameType::Data,
+    ) {
+        error!("Failed to send ARP reply: {}", e);
+    }
+}
+
+pub fn run_udp_echo(local_ip_str: String, port: u16, options: HostOptions) {
+    use crate::net::arp::ArpTable;
+    use std::net::Ipv4Addr;
+
     let local_ip: Ipv4Addr = local_ip_str
         .parse()
         .expect("Invalid local IP");
+    let mut arp = ArpTable::new();
+    arp.set_ttl(options.arp_ttl);
+    let local_mac = arp
+        .get_mac(&local_ip)
+        .expect("Local IP not in ARP table");
 
-    // Check static ARP table
-    let arp = ArpTable::new();
-    let dest_mac = if let Some(mac) = arp.get_mac(&target_ip) {
-        mac
-    } else if let Some(gateway_str) = gateway {
-        let gateway_ip: Ipv4Addr = gateway_str
-            .parse()
-            .expect("Invalid gateway IP");
-        arp.get_mac(&gateway_ip)
-            .expect("Gateway IP not in ARP table")
-    } else {
-        panic!("Target IP not in ARP table and no gateway specified");
+    info!(
+        "Starting UDP echo server on {}:{} ({})",
+        local_ip, port, local_mac
+    );
+
+    let (_jack, mut interface) = open_acoustic_interface(
+        "udp_echo",
+        local_mac,
+        &options.phy,
+        &options.mac,
+    );
+
+    // Announce ourselves so neighbors can skip resolving us
+    let announce = crate::net::arp::ArpPacket::gratuitous(local_mac, local_ip);
+    if let Err(e) = interface.send_packet(
+        &announce.to_bytes(),
+        crate::mac::types::BROADCAST,
+        FrameType::Data,
+    ) {
+        warn!("Failed to announce {}: {}", local_ip, e);
+    }
+
+    loop {
+        let (data, src_mac) = match interface.receive_packet_from(None) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive packet: {:?}", e);
+                continue;
+            }
+        };
+
+        arp.expire(std::time::Instant::now());
+        serve_udp_echo_packet(
+            &mut interface,
+            &mut arp,
+            local_ip,
+            port,
+            &data,
+            src_mac,
+        );
+    }
+}
+
+/// What the UDP echo server does with one received packet: echo UDP sent
+/// to `local_ip:port`, and otherwise behave as an IP host
+fn serve_udp_echo_packet(
+    interface: &mut crate::mac::acoustic_interface::AcousticInterface,
+    arp: &mut crate::net::arp::ArpTable,
+    local_ip: std::net::Ipv4Addr,
+    port: u16,
+    data: &[u8],
+    src_mac: u8,
+) {
+    use crate::net::udp::UdpDatagram;
+    use std::net::SocketAddrV4;
+
+    // Anything but UDP (ARP, pings) is served as by any host
+    if !etherparse::Ipv4HeaderSlice::from_slice(data)
+        .is_ok_and(|ip| ip.protocol() == etherparse::IpNumber::UDP)
+    {
+        serve_host_packet(interface, arp, local_ip, data, src_mac);
+        return;
+    }
+    let request = match UdpDatagram::from_bytes(data) {
+        Ok(datagram) => datagram,
+        Err(e) => {
+            warn!("Dropping UDP packet: {}", e);
+            return;
+        }
     };
+    if request.destination != SocketAddrV4::new(local_ip, port) {
+        return;
+    }
+    arp.observe(data, src_mac, std::time::Instant::now());
+
+    info!(
+        "Echoing {} bytes of UDP to {}",
+        request.payload.len(),
+        request.source
+    );
+    let reply = match request.echo().to_bytes() {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("Cannot echo: {}", e);
+            return;
+        }
+    };
+    let dest_mac = match arp.get_mac(request.source.ip()) {
+        Some(mac) => mac,
+        None => {
+            warn!("Unknown source IP {}, cannot reply", request.source.ip());
+            return;
+        }
+    };
+    if let Err(e) = interface.send_packet(&reply, dest_mac, FrameType::Data) {
+        error!("Failed to send UDP echo: {}", e);
+    }
+}
+
+pub fn run_udp_send(
+    target: String,
+    port: u16,
+    local_ip_str: String,
+    gateway: Option<String>,
+    payload: Vec<u8>,
+    options: HostOptions,
+) {
+    use crate::net::arp::ArpTable;
+    use crate::net::udp::UdpDatagram;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    let target_ip: Ipv4Addr = target
+        .parse()
+        .expect("Invalid target IP");
+    let local_ip: Ipv4Addr = local_ip_str
+        .parse()
+        .expect("Invalid local IP");
 
+    let mut arp = ArpTable::new();
+    arp.set_ttl(options.arp_ttl);
     let local_mac = arp
         .get_mac(&local_ip)
         .expect("Local IP not in ARP table");
 
+    let (_jack, mut interface) = open_acoustic_interface(
+        "udp_send",
+        local_mac,
+        &options.phy,
+        &options.mac,
+    );
+
+    // Same next hop choice as ping
+    let next_hop = match gateway {
+        Some(gateway_str)
+            if arp
+                .get_mac(&target_ip)
+                .is_none() =>
+        {
+            gateway_str
+                .parse()
+                .expect("Invalid gateway IP")
+        }
+        _ => target_ip,
+    };
+    let dest_mac = match arp.resolve(
+        &mut interface,
+        local_ip,
+        next_hop,
+        std::time::Duration::from_millis(ARP_RESOLVE_TIMEOUT_MS),
+    ) {
+        Ok(mac) => mac,
+        Err(e) => {
+            error!("Cannot reach {}: {}", next_hop, e);
+            return;
+        }
+    };
+
+    // Ephemeral source port
+    let source_port = rand::random_range(NAPT_PORT_FIRST..=NAPT_PORT_LAST);
+    let request = UdpDatagram::new(
+        SocketAddrV4::new(local_ip, source_port),
+        SocketAddrV4::new(target_ip, port),
+        payload,
+    );
     info!(
-        "PING {} ({}) from {} ({})",
-        target_ip, dest_mac, local_ip, local_mac
+        "UDP {} -> {} ({}), {} bytes",
+        request.source,
+        request.destination,
+        dest_mac,
+        request.payload.len()
     );
 
-    // Setup JACK
-    let (client, _status) = jack::Client::new(
-        &format!("{}_ping_{}", JACK_CLIENT_NAME, rand::random::<u16>()),
-        jack::ClientOptions::NO_START_SERVER,
-    )
+    match send_udp_and_wait(
+        &mut interface,
+        &mut arp,
+        &request,
+        dest_mac,
+        std::time::Duration::from_millis(UDP_ECHO_TIMEOUT_MS),
+    ) {
+        Ok(rtt) => info!(
+            "Echo from {}: bytes={} time={:.2}ms",
+            request.destination,
+            request.payload.len(),
+            rtt.as_secs_f32() * 1000.0
+        ),
+        Err(e) => error!("No echo from {}: {}", request.destination, e),
+    }
+}
+
+/// Send `request` and wait up to `timeout` for its echo, answering ARP
+/// requests meanwhile. Returns the round trip time.
+fn send_udp_and_wait(
+    interface: &mut crate::mac::acoustic_interface::AcousticInterface,
+    arp: &mut crate::net::arp::ArpTable,
+    request: &crate::net::udp::UdpDatagram,
+    dest_mac: u8,
+    timeout: std::time::Duration,
+) -> Result<std::time::Duration, String> {
+    use crate::net::udp::UdpDatagram;
+
+    let packet = request.to_bytes()?;
+    let start = std::time::Instant::now();
+    interface.send_packet(&packet, dest_mac, FrameType::Data)?;
+
+    let expected = request.echo();
+    loop {
+        let left = timeout
+            .checked_sub(start.elapsed())
+            .ok_or("timed out")?;
+        let (data, src_mac) = interface
+            .receive_packet_from(Some(left))
+            .map_err(|e| e.to_string())?;
+        let now = std::time::Instant::now();
+        if let Some(arp_request) = arp.observe(&data, src_mac, now) {
+            answer_arp(interface, &arp_request, *request.source.ip());
+            continue;
+        }
+        match UdpDatagram::from_bytes(&data) {
+            Ok(reply) if reply == expected => return Ok(start.elapsed()),
+            Ok(reply) if reply.source == expected.source => {
+                warn!("Echo from {} doesn't match what we sent", reply.source);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Audio client and acoustic interface for a net tool named `role`. The
+/// client must be kept alive as long as the interface is used.
+fn open_acoustic_interface(
+    role: &str,
+    local_mac: u8,
+    phy_params: &PhyParams,
+    mac_params: &MacParams,
+) -> (
+    impl Sized,
+    crate::mac::acoustic_interface::AcousticInterface,
+) {
+    use crate::mac::acoustic_interface::AcousticInterface;
+
+    let client = backend::open(&format!(
+        "{}_{}_{}",
+        JACK_CLIENT_NAME,
+        role,
+        rand::random::<u16>()
+    ))
     .unwrap();
 
     let sample_rate = client.sample_rate() as u32;
-    let shared = recorder::AppShared::new(sample_rate as usize * 10); // 10s buffer
+    let shared = recorder::AppShared::new(sample_rate as usize * 10);
     let shared_cb = shared.clone();
 
-    let in_port = client
-        .register_port(INPUT_PORT_NAME, jack::AudioIn::default())
-        .unwrap();
-    let out_port = client
-        .register_port(OUTPUT_PORT_NAME, jack::AudioOut::default())
-        .unwrap();
-    let in_name = in_port.name().unwrap();
-    let out_name = out_port.name().unwrap();
-
-    let process = jack::contrib::ClosureProcessHandler::new(
-        recorder::build_process_closure(
-            in_port,
-            out_port,
-            shared_cb,
-            sample_rate as usize * 10,
-        ),
-    );
     let active_client = client
-        .activate_async((), process)
+        .activate(recorder::build_process_closure(shared_cb))
         .unwrap();
-    connect_system_ports(active_client.as_client(), &in_name, &out_name);
 
-    let mut interface = AcousticInterface::new(
-        shared.clone(),
+    let interface = AcousticInterface::new(
+        shared,
         sample_rate,
         LineCodingKind::FourBFiveB,
         local_mac,
+        phy_params,
+        mac_params,
     );
+    (active_client, interface)
+}
 
-    // Statistics
-    let mut packets_sent = 0u32;
-    let mut packets_received = 0u32;
-    let mut rtt_times: Vec<f32> = Vec::new();
-    let ping_start = std::time::Instant::now();
+/// Record the interface's IP traffic to `path`, if one is given
+pub(crate) fn start_capture(
+    interface: &mut crate::mac::acoustic_interface::AcousticInterface,
+    path: Option<&str>,
+) {
+    if let Some(path) = path {
+        let capture = crate::net::pcap_utils::PcapWriter::create(
+            path,
+            Some(CAPTURE_ROTATE_BYTES),
+        )
+        .unwrap_or_else(|e| panic!("Cannot open capture {}: {}", path, e));
+        interface.set_capture(capture);
+    }
+}
 
-    // A Modern taste to use a random identifier for ICMP
-    let identifier = rand::random::<u16>();
+/// TCP's view of the acoustic interface: packets go to the MAC the ARP
+/// table has for their destination, else to `next_hop`. ARP requests for
+/// us are answered on the way.
+struct AcousticLink {
+    interface: crate::mac::acoustic_interface::AcousticInterface,
+    arp: crate::net::arp::ArpTable,
+    local_ip: std::net::Ipv4Addr,
+    next_hop: Option<u8>,
+}
 
-    for seq in 0..PING_PACKET_COUNT {
-        // Build ICMP Echo Request using etherparse
-        // payload --> icmp header --> ip header
-        let payload = vec![0u8; payload_size];
+impl crate::net::tcp::PacketLink for AcousticLink {
+    fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+        let destination = etherparse::Ipv4HeaderSlice::from_slice(packet)
+            .map_err(|e| format!("Invalid IPv4 header: {}", e))?
+            .destination_addr();
+        let mac = self
+            .arp
+            .get_mac(&destination)
+            .or(self.next_hop)
+            .ok_or_else(|| format!("No MAC address for {}", destination))?;
+        self.interface
+            .send_packet(packet, mac, FrameType::Data)
+    }
 
-        let icmp_header = Icmpv4Header::new(Icmpv4Type::EchoRequest(
-            etherparse::IcmpEchoHeader {
-                id: identifier,
-                seq,
-            },
-        ));
-        let icmp_bytes = {
-            let mut buf = Vec::new();
-            icmp_header
-                .write(&mut buf)
-                .expect("Failed to write ICMP header");
-            buf.extend_from_slice(&payload);
-            buf
-        };
+    fn recv(&mut self, timeout: std::time::Duration) -> Option<Vec<u8>> {
+        use crate::net::PayloadKind;
+
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let left =
+                deadline.checked_duration_since(std::time::Instant::now())?;
+            let (data, src_mac) = self
+                .interface
+                .receive_packet_from(Some(left))
+                .ok()?;
+            let now = std::time::Instant::now();
+            if let Some(request) = self
+                .arp
+                .observe(&data, src_mac, now)
+            {
+                answer_arp(&mut self.interface, &request, self.local_ip);
+            } else if PayloadKind::of(&data) == PayloadKind::Ipv4 {
+                return Some(data);
+            }
+        }
+    }
+}
 
-        let ip_header = EtherIpv4Header {
-            dscp: Default::default(),
-            ecn: Default::default(),
-            total_len: (20 + icmp_bytes.len()) as u16,
-            identification: seq,
-            dont_fragment: false,
-            more_fragments: false,
-            fragment_offset: Default::default(),
-            time_to_live: IP_TTL,
-            protocol: IpNumber::ICMP,
-            header_checksum: 0, // Will be calculated
-            source: local_ip.octets(),
-            destination: target_ip.octets(),
-            options: Default::default(),
-        };
+pub fn run_tcp_server(
+    local_ip_str: String,
+    port: u16,
+    output: Option<String>,
+    options: HostOptions,
+) {
+    use crate::net::arp::ArpTable;
+    use crate::net::tcp::{TcpConfig, TcpListener};
+    use std::net::{Ipv4Addr, SocketAddrV4};
 
-        let ip_bytes = {
-            let mut buf = Vec::new();
-            ip_header
-                .write(&mut buf)
-                .expect("Failed to write IP header");
-            buf.extend_from_slice(&icmp_bytes);
-            buf
-        };
+    let local_ip: Ipv4Addr = local_ip_str
+        .parse()
+        .expect("Invalid local IP");
+    let mut arp = ArpTable::new();
+    arp.set_ttl(options.arp_ttl);
+    let local_mac = arp
+        .get_mac(&local_ip)
+        .expect("Local IP not in ARP table");
 
-        info!("Sending ICMP Echo Request seq={}...", seq);
-        let start = std::time::Instant::now();
+    let (_jack, mut interface) = open_acoustic_interface(
+        "tcp_server",
+        local_mac,
+        &options.phy,
+        &options.mac,
+    );
+    let announce = crate::net::arp::ArpPacket::gratuitous(local_mac, local_ip);
+    if let Err(e) = interface.send_packet(
+        &announce.to_bytes(),
+        crate::mac::types::BROADCAST,
+        FrameType::Data,
+    ) {
+        warn!("Failed to announce {}: {}", local_ip, e);
+    }
 
-        // Send IP Packet
-        if let Err(e) =
-            interface.send_packet(&ip_bytes, dest_mac, FrameType::Data)
-        {
-            error!("Failed to send packet: {}", e);
-            continue;
+    let link = AcousticLink {
+        interface,
+        arp,
+        local_ip,
+        next_hop: None,
+    };
+    let local = SocketAddrV4::new(local_ip, port);
+    info!("TCP server listening on {} ({})", local, local_mac);
+    let listener = TcpListener::bind(link, local, TcpConfig::default());
+    let mut stream = match listener.accept() {
+        Ok(stream) => stream,
+        Err(e) => {
+            error!("Accept failed: {}", e);
+            return;
         }
-        packets_sent += 1;
-        // Wait for reply
-        match interface.receive_packet(Some(std::time::Duration::from_millis(
-            PING_TIMEOUT_MS,
-        ))) {
-            Ok(data) => {
-                let rtt = start.elapsed();
-                let rtt_ms = rtt.as_secs_f32() * 1000.0;
-
-                // Parse IPv4
-                let ip_slice =
-                    match etherparse::Ipv4HeaderSlice::from_slice(&data) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            warn!("Failed to parse IP header: {:?}", e);
-                            continue;
-                        }
-                    };
-
-                // Ensure packet is large enough for claimed IP header length
-                let ip_header_len = ip_slice.ihl() as usize * 4;
-                if data.len() < ip_header_len {
-                    warn!("Received packet too short for IP header length");
-                    continue;
-                }
-
-                // Parse ICMP
-                let icmp_data = &data[ip_header_len..];
-                let icmp_slice =
-                    match etherparse::Icmpv4Slice::from_slice(icmp_data) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            warn!("Failed to parse ICMP: {:?}", e);
-                            continue;
-                        }
-                    };
+    };
+    info!("Connection from {}", stream.remote());
 
-                // Only handle Echo Replies that match our sequence
-                if let Icmpv4Type::EchoReply(echo) =
-                    icmp_slice.header().icmp_type
-                {
-                    if echo.seq == seq {
-                        packets_received += 1;
-                        rtt_times.push(rtt_ms);
-
-                        info!(
-                            "Reply from {}: bytes={} time={:.2}ms TTL={}",
-                            target_ip,
-                            data.len(),
-                            rtt_ms,
-                            ip_slice.ttl()
-                        );
-                    }
-                }
+    let start = std::time::Instant::now();
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                data.extend_from_slice(&buf[..n]);
+                debug!("{} bytes received", data.len());
             }
             Err(e) => {
-                warn!("Request timed out: {}", e);
+                error!("Transfer from {} failed: {}", stream.remote(), e);
+                return;
             }
         }
-
-        std::thread::sleep(std::time::Duration::from_millis(PING_INTERVAL_MS));
     }
-
-    // Print statistics
-    let total_time = ping_start.elapsed();
-    info!("\n--- {} ping statistics ---", target_ip);
+    let elapsed = start.elapsed().as_secs_f32();
     info!(
-        "{} packets transmitted, {} received, {:.1}% packet loss, time {:.2}s",
-        packets_sent,
-        packets_received,
-        if packets_sent > 0 {
-            ((packets_sent - packets_received) as f32 / packets_sent as f32)
-                * 100.0
-        } else {
-            0.0
-        },
-        total_time.as_secs_f32()
+        "Received {} bytes in {:.2}s ({:.0} bit/s)",
+        data.len(),
+        elapsed,
+        data.len() as f32 * 8.0 / elapsed
     );
-
-    if !rtt_times.is_empty() {
-        let min_rtt = rtt_times
-            .iter()
-            .cloned()
-            .fold(f32::INFINITY, f32::min);
-        let max_rtt = rtt_times
-            .iter()
-            .cloned()
-            .fold(f32::NEG_INFINITY, f32::max);
-        let avg_rtt = rtt_times.iter().sum::<f32>() / rtt_times.len() as f32;
-
-        info!(
-            "rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
-            min_rtt, avg_rtt, max_rtt
-        );
+    if let Err(e) = stream.close() {
+        warn!("Close failed: {}", e);
+    }
+    if let Some(path) = output {
+        match std::fs::write(&path, &data) {
+            Ok(()) => info!("Saved to {}", path),
+            Err(e) => error!("Cannot write {}: {}", path, e),
+        }
     }
 }
 
-pub fn run_ip_host(local_ip_str: String) {
-    use crate::mac::acoustic_interface::AcousticInterface;
+pub fn run_tcp_client(
+    target: String,
+    port: u16,
+    local_ip_str: String,
+    gateway: Option<String>,
+    data: Vec<u8>,
+    options: HostOptions,
+) {
     use crate::net::arp::ArpTable;
-    use etherparse::{
-        Icmpv4Header, Icmpv4Type, IpNumber, Ipv4Header as EtherIpv4Header,
-    };
-    use std::net::Ipv4Addr;
+    use crate::net::tcp::{TcpConfig, TcpStream};
+    use std::net::{Ipv4Addr, SocketAddrV4};
 
+    let target_ip: Ipv4Addr = target
+        .parse()
+        .expect("Invalid target IP");
     let local_ip: Ipv4Addr = local_ip_str
         .parse()
         .expect("Invalid local IP");
-    let arp = ArpTable::new();
+    let mut arp = ArpTable::new();
+    arp.set_ttl(options.arp_ttl);
     let local_mac = arp
         .get_mac(&local_ip)
         .expect("Local IP not in ARP table");
 
-    info!("Starting IP Host on {} ({})", local_ip, local_mac);
-
-    // Setup JACK
-    let (client, _status) = jack::Client::new(
-        &format!("{}_host_{}", JACK_CLIENT_NAME, rand::random::<u16>()),
-        jack::ClientOptions::NO_START_SERVER,
-    )
-    .unwrap();
-
-    let sample_rate = client.sample_rate() as u32;
-    let shared = recorder::AppShared::new(sample_rate as usize * 10);
-    let shared_cb = shared.clone();
-
-    let in_port = client
-        .register_port(INPUT_PORT_NAME, jack::AudioIn::default())
-        .unwrap();
-    let out_port = client
-        .register_port(OUTPUT_PORT_NAME, jack::AudioOut::default())
-        .unwrap();
-    let in_name = in_port.name().unwrap();
-    let out_name = out_port.name().unwrap();
-
-    let process = jack::contrib::ClosureProcessHandler::new(
-        recorder::build_process_closure(
-            in_port,
-            out_port,
-            shared_cb,
-            sample_rate as usize * 10,
-        ),
-    );
-    let active_client = client
-        .activate_async((), process)
-        .unwrap();
-    connect_system_ports(active_client.as_client(), &in_name, &out_name);
-
-    // Setup IP Interface
-    let mut interface = AcousticInterface::new(
-        shared.clone(),
-        sample_rate,
-        LineCodingKind::FourBFiveB,
+    let (_jack, mut interface) = open_acoustic_interface(
+        "tcp_client",
         local_mac,
+        &options.phy,
+        &options.mac,
     );
 
-    // Listen for packets
-    loop {
-        // Get a packet from interface
-        let data = match interface.receive_packet(None) {
-            Ok(data) => data,
-            Err(e) => {
-                warn!("Failed to receive packet: {:?}", e);
-                continue;
-            }
-        };
-
-        // Parse IPv4
-        let ip_slice = match etherparse::Ipv4HeaderSlice::from_slice(&data) {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("Failed to parse IP header: {:?}", e);
-                continue;
-            }
-        };
-
-        // Check if it's for us
-        if ip_slice.destination() != local_ip.octets() {
-            continue; // Continue if not
+    // Same next hop choice as ping
+    let next_hop = match gateway {
+        Some(gateway_str)
+            if arp
+                .get_mac(&target_ip)
+                .is_none() =>
+        {
+            gateway_str
+                .parse()
+                .expect("Invalid gateway IP")
         }
-
-        // Ensure packet is large enough for claimed IP header length
-        let ip_header_len = ip_slice.ihl() as usize * 4;
-        if data.len() < ip_header_len {
-            warn!("Received packet too short for IP header length");
-            continue;
+        _ => target_ip,
+    };
+    let next_hop_mac = match arp.resolve(
+        &mut interface,
+        local_ip,
+        next_hop,
+        std::time::Duration::from_millis(ARP_RESOLVE_TIMEOUT_MS),
+    ) {
+        Ok(mac) => mac,
+        Err(e) => {
+            error!("Cannot reach {}: {}", next_hop, e);
+            return;
         }
+    };
 
-        // Parse ICMP
-        let icmp_data = &data[ip_header_len..];
-        let icmp_slice = match etherparse::Icmpv4Slice::from_slice(icmp_data) {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("Failed to parse ICMP: {:?}", e);
-                continue;
-            }
-        };
-
-        // Only handle Echo Requests
-        let echo = match icmp_slice.header().icmp_type {
-            Icmpv4Type::EchoRequest(echo) => echo,
-            _ => continue,
-        };
-
-        info!("Received ICMP Echo Request from {:?}", ip_slice.source());
-
-        // Build Echo Reply using etherparse
-        let payload = icmp_slice.payload().to_vec();
-        let reply_icmp_header = Icmpv4Header::new(Icmpv4Type::EchoReply(
-            etherparse::IcmpEchoHeader {
-                id: echo.id,
-                seq: echo.seq,
-            },
-        ));
-        let reply_icmp_bytes = {
-            let mut buf = Vec::new();
-            reply_icmp_header
-                .write(&mut buf)
-                .expect("Failed to write ICMP header");
-            buf.extend_from_slice(&payload);
-            buf
-        };
-
-        // Build IPv4 reply header
-        let reply_ip_header = EtherIpv4Header {
-            dscp: Default::default(),
-            ecn: Default::default(),
-            total_len: (20 + reply_icmp_bytes.len()) as u16,
-            identification: 0,
-            dont_fragment: false,
-            more_fragments: false,
-            fragment_offset: Default::default(),
-            time_to_live: IP_TTL,
-            protocol: IpNumber::ICMP,
-            header_checksum: 0,
-            source: local_ip.octets(),
-            destination: ip_slice.source(),
-            options: Default::default(),
-        };
-
-        let reply_bytes = {
-            let mut buf = Vec::new();
-            reply_ip_header
-                .write(&mut buf)
-                .expect("Failed to write IP header");
-            buf.extend_from_slice(&reply_icmp_bytes);
-            buf
-        };
-
-        // Find dest MAC
-        let src_ip = Ipv4Addr::from(ip_slice.source());
-        let dest_mac = match arp.get_mac(&src_ip) {
-            Some(m) => m,
-            None => {
-                // warn!("Unknown source IP {}, cannot reply", src_ip);
-                // continue;
-                1
-            }
-        };
-
-        info!("Sending Echo Reply to {} ({})", src_ip, dest_mac);
-
-        if let Err(e) =
-            interface.send_packet(&reply_bytes, dest_mac, FrameType::Ack)
-        {
-            error!("Failed to send reply: {}", e);
+    let link = AcousticLink {
+        interface,
+        arp,
+        local_ip,
+        next_hop: Some(next_hop_mac),
+    };
+    let local = SocketAddrV4::new(
+        local_ip,
+        rand::random_range(NAPT_PORT_FIRST..=NAPT_PORT_LAST),
+    );
+    let remote = SocketAddrV4::new(target_ip, port);
+    info!("TCP {} -> {}, {} bytes", local, remote, data.len());
+
+    let start = std::time::Instant::now();
+    let result = TcpStream::connect(link, local, remote, TcpConfig::default())
+        .and_then(|mut stream| {
+            info!("Connected to {}", remote);
+            stream.write_all(&data)?;
+            stream.close()
+        });
+    match result {
+        Ok(()) => {
+            let elapsed = start.elapsed().as_secs_f32();
+            info!(
+                "Sent {} bytes in {:.2}s ({:.0} bit/s)",
+                data.len(),
+                elapsed,
+                data.len() as f32 * 8.0 / elapsed
+            );
         }
+        Err(e) => error!("Transfer to {} failed: {}", remote, e),
     }
 }
 
+/// What run_router is given beyond its addresses and interfaces
+#[derive(Debug, Clone, Default)]
+pub struct RouterOptions {
+    /// Static routes; without a default one, everything else goes to the
+    /// gateway on eth
+    pub routes: Vec<crate::net::router::StaticRoute>,
+    /// Record the acoustic IP traffic to this pcap file
+    pub capture: Option<String>,
+    /// Rules for incoming packets, first match wins; see net::firewall
+    pub firewall: Vec<crate::net::firewall::FirewallRule>,
+    pub phy: PhyParams,
+    pub mac: MacParams,
+    /// Where the router publishes its status for --tui
+    pub dashboard: Option<crossbeam_channel::Sender<DashboardEvent>>,
+}
+
 pub fn run_router(
     acoustic_ip_str: String,
     acoustic_mac: u8,
@@ -442,8 +1663,9 @@ pub fn run_router(
     tun_ip_str: String,
     tun_netmask_str: String,
     line_coding: LineCodingKind,
+    options: RouterOptions,
 ) {
-    use crate::net::router::{Router, RouterConfig};
+    use crate::net::router::{Router, RouterConfig, StaticRoute};
     use std::net::Ipv4Addr;
 
     // === Router Preparation ===
@@ -570,38 +1792,34 @@ pub fn run_router(
         .parse()
         .unwrap();
 
-    // Setup JACK
-    let (client, _status) = jack::Client::new(
-        &format!("{}_router_{}", JACK_CLIENT_NAME, rand::random::<u16>()),
-        jack::ClientOptions::NO_START_SERVER,
-    )
+    // Setup audio
+    let client = backend::open(&format!(
+        "{}_router_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))
     .unwrap();
 
     let sample_rate = client.sample_rate() as u32;
     let shared = recorder::AppShared::new(sample_rate as usize * 60); // 60s buffer
     let shared_cb = shared.clone();
 
-    let in_port = client
-        .register_port(INPUT_PORT_NAME, jack::AudioIn::default())
-        .unwrap();
-    let out_port = client
-        .register_port(OUTPUT_PORT_NAME, jack::AudioOut::default())
-        .unwrap();
-    let in_name = in_port.name().unwrap();
-    let out_name = out_port.name().unwrap();
-
-    let process = jack::contrib::ClosureProcessHandler::new(
-        recorder::build_process_closure(
-            in_port,
-            out_port,
-            shared_cb,
-            sample_rate as usize * 60,
-        ),
-    );
     let active_client = client
-        .activate_async((), process)
+        .activate(recorder::build_process_closure(shared_cb))
         .unwrap();
-    connect_system_ports(active_client.as_client(), &in_name, &out_name);
+
+    // Without an explicit default route, everything else goes to the gateway
+    let mut routes = options.routes;
+    if !routes
+        .iter()
+        .any(|route| route.prefix_len == 0)
+    {
+        info!("No default route given, using {} on eth", gateway_ip);
+        routes.push(StaticRoute::default_via(
+            gateway_ip,
+            InterfaceType::Ethernet,
+        ));
+    }
 
     // Create router config
     let config = RouterConfig {
@@ -631,6 +1849,15 @@ pub fn run_router(
         tun_netmask,
         node3_ip,
         node1_ip: "192.168.1.2".parse().unwrap(),
+        routes,
+        arp_ttl: std::time::Duration::from_secs(ROUTER_ARP_TTL_SECS),
+        acoustic_mtu: ROUTER_ACOUSTIC_MTU,
+        capture: options.capture,
+        firewall: options.firewall,
+        management: ROUTER_MGMT_ADDR.parse().ok(),
+        phy: options.phy,
+        mac: options.mac,
+        dashboard: options.dashboard,
     };
 
     let mut router = Router::new(config);
@@ -658,7 +1885,19 @@ pub fn run_router(
             gateway_ip, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
         );
     } else {
-        warn!("No Gateway Provided. NAT will be failed.");
+        info!(
+            "No gateway MAC given, NAT out eth waits until {} answers ARP",
+            gateway_ip
+        );
+    }
+
+    // Ctrl+C stops the router's threads, run returns once they are done
+    let stopper = router.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        info!("Stopping router...");
+        stopper.stop();
+    }) {
+        warn!("Ctrl+C won't stop the router cleanly: {}", e);
     }
 
     // Run router
@@ -671,3 +1910,485 @@ pub fn run_router(
         error!("Error deactivating client: {}", err);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mac::acoustic_interface::tests::{
+        SAMPLE_RATE, spawn_mock_channel, test_interface,
+    };
+    use crate::net::PayloadKind;
+    use crate::net::arp::{ArpPacket, ArpTable};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_payload_kind() {
+        assert_eq!(PayloadKind::of(&[0x45, 0, 0, 20]), PayloadKind::Ipv4);
+        assert_eq!(
+            PayloadKind::of(
+                &ArpPacket::gratuitous(1, Ipv4Addr::LOCALHOST).to_bytes()
+            ),
+            PayloadKind::Arp
+        );
+        assert_eq!(PayloadKind::of(&[0x60, 0]), PayloadKind::Ipv6);
+        assert_eq!(PayloadKind::of(&[0x10, 0]), PayloadKind::Unknown);
+        assert_eq!(PayloadKind::of(&[]), PayloadKind::Unknown);
+    }
+
+    #[test]
+    fn test_ping_sweep_parsing() {
+        let sweep: PingSweep = "100, 140, 8".parse().unwrap();
+        assert_eq!(sweep.sizes(), vec![100, 108, 116, 124, 132, 140]);
+        // The last step may fall short of max
+        let sweep: PingSweep = "32,100,32".parse().unwrap();
+        assert_eq!(sweep.sizes(), vec![32, 64, 96]);
+        for bad in ["140,100,8", "32,64,0", "32,64", "a,b,c", ""] {
+            assert!(
+                bad.parse::<PingSweep>()
+                    .is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_ping_exit_code_and_stats() {
+        use std::time::Instant;
+
+        let t0 = Instant::now();
+        let ms = |n: u64| t0 + Duration::from_millis(n);
+
+        // Everything lost: a failure, like iputils
+        let mut lost = PingStats::default();
+        for seq in 1..=4 {
+            lost.on_sent(seq, ms(seq as u64 * 1000));
+        }
+        assert_eq!(lost.exit_code(), 1);
+        assert_eq!(lost.loss_percent(), 100.0);
+        assert_eq!(lost.rtt_summary(), None);
+        assert_eq!(PingStats::default().exit_code(), 1);
+
+        // A single reply is enough
+        let mut stats = PingStats::default();
+        for seq in 1..=4 {
+            stats.on_sent(seq, ms(seq as u64 * 1000));
+        }
+        for (seq, at) in [(1, 1010), (2, 2020), (3, 3030)] {
+            stats.on_reply(seq, ms(at));
+        }
+        assert_eq!(stats.exit_code(), 0);
+        assert_eq!(stats.loss_percent(), 25.0);
+        let (min, avg, max, mdev) = stats.rtt_summary().unwrap();
+        assert_eq!((min, avg, max), (10.0, 20.0, 30.0));
+        assert!((mdev - 8.165).abs() < 0.01, "mdev {}", mdev);
+    }
+
+    #[test]
+    fn test_ping_matches_late_and_duplicate_replies() {
+        use std::time::Instant;
+
+        let t0 = Instant::now();
+        let ms = |n: u64| t0 + Duration::from_millis(n);
+        let mut stats = PingStats::default();
+
+        stats.on_sent(1, ms(0));
+        assert_eq!(
+            stats.on_reply(1, ms(100)),
+            ReplyMatch::First {
+                rtt_ms: 100.0,
+                late: false
+            }
+        );
+        // The link delivered it twice
+        assert_eq!(
+            stats.on_reply(1, ms(150)),
+            ReplyMatch::Duplicate { rtt_ms: 150.0 }
+        );
+
+        // Reply to 2 only shows up while waiting for 3, timed from 2
+        stats.on_sent(2, ms(1000));
+        stats.on_sent(3, ms(2000));
+        assert_eq!(
+            stats.on_reply(2, ms(2500)),
+            ReplyMatch::First {
+                rtt_ms: 1500.0,
+                late: true
+            }
+        );
+        assert_eq!(
+            stats.on_reply(3, ms(2100)),
+            ReplyMatch::First {
+                rtt_ms: 100.0,
+                late: false
+            }
+        );
+        // Never sent
+        assert_eq!(stats.on_reply(9, ms(3000)), ReplyMatch::Unknown);
+
+        // A retry of 4 counts once and is timed from the resend
+        stats.on_sent(4, ms(3000));
+        stats.on_sent(4, ms(3500));
+        assert_eq!(
+            stats.on_reply(4, ms(3600)),
+            ReplyMatch::First {
+                rtt_ms: 100.0,
+                late: false
+            }
+        );
+
+        assert_eq!(stats.transmitted, 4);
+        assert_eq!(stats.received, 4);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.out_of_order, 1);
+        assert_eq!(stats.loss_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_ping_retries_below_path_mtu() {
+        use etherparse::icmpv4::DestUnreachableHeader;
+        use etherparse::{Icmpv4Type, PacketBuilder};
+
+        let local: Ipv4Addr = "192.168.1.2".parse().unwrap();
+        let target: Ipv4Addr = "192.168.2.5".parse().unwrap();
+        let request = build_echo_request(local, target, 77, 3, 150);
+        let header = etherparse::Ipv4HeaderSlice::from_slice(&request).unwrap();
+        assert!(header.dont_fragment());
+        assert_eq!(request.len(), 178);
+        // Too big for the acoustic link itself: no DF, it gets fragmented
+        let big = build_echo_request(local, target, 77, 3, 1000);
+        assert!(
+            !etherparse::Ipv4HeaderSlice::from_slice(&big)
+                .unwrap()
+                .dont_fragment()
+        );
+
+        // The router quotes the header and 8 bytes of the request
+        let frag_needed = |quote: &[u8]| {
+            let mut packet = Vec::new();
+            PacketBuilder::ipv4([192, 168, 1, 1], local.octets(), 64)
+                .icmpv4(Icmpv4Type::DestinationUnreachable(
+                    DestUnreachableHeader::FragmentationNeeded {
+                        next_hop_mtu: 140,
+                    },
+                ))
+                .write(&mut packet, quote)
+                .unwrap();
+            packet
+        };
+        assert_eq!(
+            parse_ping_reply(&frag_needed(&request[..28]), 77),
+            Ok(PingReply::FragmentationNeeded { seq: 3, mtu: 140 })
+        );
+        // About someone else's request
+        let other = build_echo_request(local, target, 78, 3, 150);
+        assert_eq!(
+            parse_ping_reply(&frag_needed(&other[..28]), 77),
+            Ok(PingReply::Unrelated)
+        );
+
+        let retry = payload_for_mtu(140, 150).unwrap();
+        assert_eq!(retry, 112);
+        assert_eq!(build_echo_request(local, target, 77, 3, retry).len(), 140);
+        // Nothing to gain, or no room at all
+        assert_eq!(payload_for_mtu(140, 100), None);
+        assert_eq!(payload_for_mtu(20, 100), None);
+
+        let mut reply = Vec::new();
+        PacketBuilder::ipv4(target.octets(), local.octets(), 61)
+            .icmpv4_echo_reply(77, 3)
+            .write(&mut reply, &[0; 112])
+            .unwrap();
+        assert_eq!(
+            parse_ping_reply(&reply, 77),
+            Ok(PingReply::Echo { seq: 3, ttl: 61 })
+        );
+        assert_eq!(parse_ping_reply(&reply, 78), Ok(PingReply::Unrelated));
+        assert!(parse_ping_reply(&[0x45, 0], 77).is_err());
+    }
+
+    #[test]
+    fn test_udp_echo_over_loopback() {
+        use crate::net::udp::UdpDatagram;
+        use std::net::SocketAddrV4;
+
+        let client_ip: Ipv4Addr = "10.0.0.5".parse().unwrap();
+        let server_ip: Ipv4Addr = "192.168.1.9".parse().unwrap();
+        let a = recorder::AppShared::new(SAMPLE_RATE as usize);
+        let b = recorder::AppShared::new(SAMPLE_RATE as usize);
+        let running = Arc::new(AtomicBool::new(true));
+        let channel =
+            spawn_mock_channel(vec![a.clone(), b.clone()], running.clone());
+
+        let kind = LineCodingKind::FourBFiveB;
+        let mut client = test_interface(a, kind, 5);
+        let mut server = test_interface(b.clone(), kind, 9);
+        b.app_state
+            .set(recorder::AppState::Recording);
+
+        // Something for another port first, then the request
+        let server_loop = std::thread::spawn(move || {
+            let mut arp = ArpTable::new();
+            for _ in 0..2 {
+                let (data, src) = server
+                    .receive_packet_from(Some(Duration::from_secs(10)))
+                    .unwrap();
+                serve_udp_echo_packet(
+                    &mut server,
+                    &mut arp,
+                    server_ip,
+                    UDP_ECHO_PORT,
+                    &data,
+                    src,
+                );
+            }
+        });
+
+        let source = SocketAddrV4::new(client_ip, 50000);
+        let stray = UdpDatagram::new(
+            source,
+            SocketAddrV4::new(server_ip, 9),
+            vec![0; 4],
+        );
+        client
+            .send_packet(&stray.to_bytes().unwrap(), 9, FrameType::Data)
+            .unwrap();
+        let request = UdpDatagram::new(
+            source,
+            SocketAddrV4::new(server_ip, UDP_ECHO_PORT),
+            b"over the air and back".to_vec(),
+        );
+        let rtt = send_udp_and_wait(
+            &mut client,
+            &mut ArpTable::new(),
+            &request,
+            9,
+            Duration::from_secs(10),
+        );
+
+        server_loop.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+
+        assert!(rtt.unwrap() > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_tcp_over_acoustic_loopback() {
+        use crate::net::tcp::{TcpConfig, TcpListener, TcpStream};
+        use std::net::SocketAddrV4;
+
+        let client_ip: Ipv4Addr = "192.168.1.5".parse().unwrap();
+        let server_ip: Ipv4Addr = "192.168.1.9".parse().unwrap();
+        let a = recorder::AppShared::new(SAMPLE_RATE as usize);
+        let b = recorder::AppShared::new(SAMPLE_RATE as usize);
+        let running = Arc::new(AtomicBool::new(true));
+        let channel =
+            spawn_mock_channel(vec![a.clone(), b.clone()], running.clone());
+
+        let kind = LineCodingKind::FourBFiveB;
+        let client = test_interface(a, kind, 5);
+        let server = test_interface(b.clone(), kind, 9);
+        b.app_state
+            .set(recorder::AppState::Recording);
+
+        let data: Vec<u8> = (0..600)
+            .map(|i| (i % 256) as u8)
+            .collect();
+        let server_loop = std::thread::spawn(move || {
+            let link = AcousticLink {
+                interface: server,
+                arp: ArpTable::new(),
+                local_ip: server_ip,
+                next_hop: None,
+            };
+            let local = SocketAddrV4::new(server_ip, TCP_DEFAULT_PORT);
+            let mut stream =
+                TcpListener::bind(link, local, TcpConfig::default())
+                    .accept()
+                    .unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 256];
+            loop {
+                match stream.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+            stream.close().unwrap();
+            received
+        });
+
+        let link = AcousticLink {
+            interface: client,
+            arp: ArpTable::new(),
+            local_ip: client_ip,
+            next_hop: Some(9),
+        };
+        let mut stream = TcpStream::connect(
+            link,
+            SocketAddrV4::new(client_ip, 50000),
+            SocketAddrV4::new(server_ip, TCP_DEFAULT_PORT),
+            TcpConfig::default(),
+        )
+        .unwrap();
+        stream
+            .write_all(&data)
+            .unwrap();
+        stream.close().unwrap();
+
+        let received = server_loop.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_host_answers_arp_and_ping() {
+        let client_ip: Ipv4Addr = "10.0.0.5".parse().unwrap();
+        let host_ip: Ipv4Addr = "192.168.1.9".parse().unwrap();
+        let a = recorder::AppShared::new(SAMPLE_RATE as usize);
+        let b = recorder::AppShared::new(SAMPLE_RATE as usize);
+        let running = Arc::new(AtomicBool::new(true));
+        let channel =
+            spawn_mock_channel(vec![a.clone(), b.clone()], running.clone());
+
+        let kind = LineCodingKind::FourBFiveB;
+        let mut client = test_interface(a, kind, 5);
+        let mut host = test_interface(b.clone(), kind, 9);
+        // Listen before anything is sent
+        b.app_state
+            .set(recorder::AppState::Recording);
+
+        // Gratuitous ARP, ARP request, echo request
+        let host_loop = std::thread::spawn(move || {
+            let mut arp = ArpTable::new();
+            for _ in 0..3 {
+                let (data, src) = host
+                    .receive_packet_from(Some(Duration::from_secs(10)))
+                    .unwrap();
+                serve_host_packet(&mut host, &mut arp, host_ip, &data, src);
+            }
+            arp
+        });
+
+        client
+            .send_packet(
+                &ArpPacket::gratuitous(5, client_ip).to_bytes(),
+                crate::mac::types::BROADCAST,
+                FrameType::Data,
+            )
+            .unwrap();
+        let mut arp = ArpTable::new();
+        let host_mac = arp
+            .resolve(&mut client, client_ip, host_ip, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(host_mac, 9);
+        assert_eq!(arp.get_mac(&host_ip), Some(9));
+
+        let builder = etherparse::PacketBuilder::ipv4(
+            client_ip.octets(),
+            host_ip.octets(),
+            IP_TTL,
+        )
+        .icmpv4_echo_request(42, 1);
+        let mut request = Vec::new();
+        builder
+            .write(&mut request, &[7; 16])
+            .unwrap();
+        client
+            .send_packet(&request, host_mac, FrameType::Data)
+            .unwrap();
+        let (reply, src) = client
+            .receive_packet_from(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        let host_arp = host_loop.join().unwrap();
+        running.store(false, Ordering::SeqCst);
+        channel.join().unwrap();
+
+        assert_eq!(src, 9);
+        let ip = etherparse::Ipv4HeaderSlice::from_slice(&reply).unwrap();
+        assert_eq!(ip.source_addr(), host_ip);
+        let icmp = etherparse::Icmpv4Slice::from_slice(&reply[20..]).unwrap();
+        assert!(matches!(
+            icmp.header().icmp_type,
+            etherparse::Icmpv4Type::EchoReply(echo) if echo.id == 42 && echo.seq == 1
+        ));
+        assert_eq!(icmp.payload(), &[7; 16]);
+        // The host learned the client from its announcement
+        assert_eq!(host_arp.get_mac(&client_ip), Some(5));
+    }
+
+    #[test]
+    fn test_ping_host_over_lossy_loopback() {
+        use crate::audio::loopback::{
+            LinkParams, LoopbackConfig, LoopbackMedium,
+        };
+
+        // Both tools open their audio on the medium; the only test that
+        // takes the opens, as they are routed process-wide
+        let lossy = LinkParams {
+            loss: 0.2,
+            ..LinkParams::default()
+        };
+        let medium = LoopbackMedium::new(LoopbackConfig {
+            seed: 11,
+            link: lossy,
+            ..LoopbackConfig::default()
+        });
+        medium.take_opens();
+
+        let running = Arc::new(AtomicBool::new(true));
+        let host = {
+            let running = running.clone();
+            std::thread::spawn(move || {
+                run_ip_host_until(
+                    "192.168.1.2".to_string(),
+                    Duration::from_secs(60),
+                    None,
+                    false,
+                    &PhyParams::default(),
+                    &MacParams::default(),
+                    running,
+                )
+            })
+        };
+        // Past the host's announcement
+        std::thread::sleep(Duration::from_secs(2));
+
+        let ping = |count| {
+            run_ping(
+                "192.168.1.2".to_string(),
+                "192.168.1.3".to_string(),
+                None,
+                PingOptions {
+                    count,
+                    interval: Duration::from_millis(500),
+                    timeout: Duration::from_secs(2),
+                    quiet: true,
+                    ..PingOptions::default()
+                },
+            )
+        };
+        // Some of five get through
+        let answered = ping(5);
+
+        // The host (node 0) and the first ping (node 1) can't hear each
+        // other any more, nor the next ping (node 2)
+        let cut = LinkParams { loss: 1.0, ..lossy };
+        for (from, to) in [(0, 1), (1, 0), (0, 2), (2, 0)] {
+            medium.set_link(from, to, cut);
+        }
+        let unanswered = ping(2);
+
+        running.store(false, Ordering::SeqCst);
+        host.join().unwrap();
+
+        assert_eq!(answered, 0);
+        assert_eq!(unanswered, 1);
+    }
+}
@@ -1,27 +1,123 @@
 use std::io::{Read, Write};
 use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::os::fd::AsRawFd;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel;
 use etherparse::Ipv4HeaderSlice;
 use tracing::{debug, error, info, trace, warn};
-use tun::Configuration;
+use tun::{AbstractDevice, Configuration, Layer};
 
-use crate::audio::recorder::{self};
-use crate::device::jack::connect_system_ports;
+use crate::audio::{backend, recorder};
 use crate::mac::acoustic_interface::AcousticInterface;
+use crate::mac::params::MacParams;
+use crate::net::fragmentation::IpFragmenter;
+use crate::net::tap::{ETHERNET_HEADER_LEN, TapBridge};
+use crate::phy::params::PhyParams;
 use crate::phy::{FrameType, LineCodingKind};
 use crate::utils::consts::*;
 
+/// Smallest MTU an IPv4 link may have
+const MIN_IPV4_MTU: usize = 68;
+/// Largest packet the reader thread takes from the device
+const TUN_READ_BUFFER: usize = 1500;
+
+/// How the TUN adapter sizes the packets it takes from the kernel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TunOptions {
+    /// MTU advertised to the kernel, None for the default
+    pub mtu: Option<usize>,
+    /// Split packets that don't fit a frame instead of dropping them
+    pub fragment: bool,
+    /// Create a TAP device and bridge whole Ethernet frames, always split
+    /// into frame-sized pieces
+    pub tap: bool,
+    /// Acoustic link PHY and MAC parameters
+    pub phy: PhyParams,
+    pub mac: MacParams,
+}
+
+impl TunOptions {
+    /// The MTU to configure: one frame's payload, or TUN_MAX_FRAGMENTS of
+    /// them when packets get fragmented
+    pub fn mtu(&self) -> Result<usize, String> {
+        let default = if self.fragment || self.tap {
+            MAX_FRAME_DATA_SIZE * TUN_MAX_FRAGMENTS
+        } else {
+            MAX_FRAME_DATA_SIZE
+        };
+        let mtu = self.mtu.unwrap_or(default);
+        if !(MIN_IPV4_MTU..=TUN_READ_BUFFER).contains(&mtu) {
+            return Err(format!(
+                "MTU {} out of range {}-{}",
+                mtu, MIN_IPV4_MTU, TUN_READ_BUFFER
+            ));
+        }
+        Ok(mtu)
+    }
+
+    /// The frames-sized packets to send for `packet`, or why it is dropped
+    fn fit(&self, packet: &[u8], mtu: usize) -> Result<Vec<Vec<u8>>, String> {
+        if !self.fragment {
+            if packet.len() > mtu {
+                return Err(format!(
+                    "{} bytes over the {} byte MTU, fragmentation is off",
+                    packet.len(),
+                    mtu
+                ));
+            }
+            return Ok(vec![packet.to_vec()]);
+        }
+        let dont_fragment = packet.len() > 6 && packet[6] & 0x40 != 0;
+        if packet.len() > mtu && dont_fragment {
+            return Err(format!(
+                "{} bytes over the {} byte MTU with Don't Fragment set",
+                packet.len(),
+                mtu
+            ));
+        }
+        IpFragmenter::new(MAX_FRAME_DATA_SIZE).fragment_forwarded(packet)
+    }
+}
+
+/// Wait up to `timeout` for `fd` to have something to read, so a reading
+/// thread can look at its stop flag now and then
+pub(crate) fn wait_readable(
+    fd: &impl AsRawFd,
+    timeout: Duration,
+) -> std::io::Result<bool> {
+    let mut poll = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: one valid pollfd, for the duration of the call
+    let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) };
+    match ready {
+        -1 => {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
 pub fn run_tun(
     ip_str: String,
     netmask_str: String,
     tun_name: String,
     gateway_str: Option<String>,
     line_coding: LineCodingKind,
+    capture: Option<String>,
+    options: TunOptions,
 ) {
     // Parse IPs
     let ip: Ipv4Addr = ip_str
@@ -34,6 +130,9 @@ pub fn run_tun(
         s.parse()
             .expect("Invalid gateway IP")
     });
+    let mtu = options
+        .mtu()
+        .expect("Invalid MTU");
 
     info!("Starting TUN Adapter...");
     info!("  IP: {}", ip);
@@ -42,54 +141,54 @@ pub fn run_tun(
         info!("  Gateway: {}", gw);
     }
     info!("  Device: {}", tun_name);
-    info!("  MTU: {}", MAX_FRAME_DATA_SIZE);
 
     // Setup TUN
     let mut config = Configuration::default();
     config
         .address(ip)
         .netmask(netmask)
-        .mtu(MAX_FRAME_DATA_SIZE as u16)
+        .mtu(mtu as u16)
         .up();
 
+    if options.tap {
+        config.layer(Layer::L2);
+    }
+
     #[cfg(target_os = "linux")]
     config.tun_name(&tun_name);
 
     let dev = tun::create(&config).expect("Failed to create TUN device");
+    match dev.mtu() {
+        Ok(negotiated) => info!(
+            "  MTU: {} ({})",
+            negotiated,
+            if options.tap {
+                "TAP, Ethernet frames in pieces"
+            } else if options.fragment {
+                "fragmented to frames"
+            } else {
+                "one frame per packet"
+            }
+        ),
+        Err(e) => warn!("  MTU: {} requested, cannot read it back: {}", mtu, e),
+    }
     let (mut tun_reader, mut tun_writer) = dev.split();
 
-    // Setup JACK
-    let (client, _status) = jack::Client::new(
-        &format!("{}_tun_{}", JACK_CLIENT_NAME, rand::random::<u16>()),
-        jack::ClientOptions::NO_START_SERVER,
-    )
+    // Setup audio
+    let client = backend::open(&format!(
+        "{}_tun_{}",
+        JACK_CLIENT_NAME,
+        rand::random::<u16>()
+    ))
     .unwrap();
 
     let sample_rate = client.sample_rate() as u32;
     let shared = recorder::AppShared::new(sample_rate as usize * 60); // 60s buffer
     let shared_cb = shared.clone();
 
-    let in_port = client
-        .register_port(INPUT_PORT_NAME, jack::AudioIn::default())
-        .unwrap();
-    let out_port = client
-        .register_port(OUTPUT_PORT_NAME, jack::AudioOut::default())
-        .unwrap();
-    let in_name = in_port.name().unwrap();
-    let out_name = out_port.name().unwrap();
-
-    let process = jack::contrib::ClosureProcessHandler::new(
-        recorder::build_process_closure(
-            in_port,
-            out_port,
-            shared_cb,
-            sample_rate as usize * 60,
-        ),
-    );
     let active_client = client
-        .activate_async((), process)
+        .activate(recorder::build_process_closure(shared_cb))
         .unwrap();
-    connect_system_ports(active_client.as_client(), &in_name, &out_name);
 
     // Setup Acoustic Interface
     let local_mac = ip.octets()[3];
@@ -100,7 +199,10 @@ pub fn run_tun(
         sample_rate,
         line_coding,
         local_mac,
+        &options.phy,
+        &options.mac,
     );
+    crate::net::tool::start_capture(&mut interface, capture.as_deref());
 
     // Channels for inter-thread communication
     let (to_acoustic_tx, to_acoustic_rx) =
@@ -108,6 +210,9 @@ pub fn run_tun(
     let (to_tun_tx, to_tun_rx) = crossbeam_channel::unbounded::<Vec<u8>>();
 
     let running = Arc::new(AtomicBool::new(true));
+    let bridge = options
+        .tap
+        .then(|| Arc::new(Mutex::new(TapBridge::new())));
 
     // Handle Ctrl+C
     let r = running.clone();
@@ -121,14 +226,55 @@ pub fn run_tun(
     let local_ip = ip;
     let local_netmask = netmask;
     let local_gateway = gateway;
+    let reader_bridge = bridge.clone();
 
-    thread::spawn(move || {
-        let mut buf = [0u8; 1500];
+    let reader_handle = thread::spawn(move || {
+        let mut buf = [0u8; TUN_READ_BUFFER + ETHERNET_HEADER_LEN];
         while r_reader.load(Ordering::SeqCst) {
+            match wait_readable(&tun_reader, Duration::from_millis(100)) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("TUN poll error: {}", e);
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            }
             match tun_reader.read(&mut buf) {
                 Ok(len) => {
                     if len > 0 {
                         let packet = &buf[..len];
+                        if let Some(bridge) = &reader_bridge {
+                            let outgoing = bridge
+                                .lock()
+                                .unwrap()
+                                .outgoing(packet, Instant::now());
+                            let (node, pieces) = match outgoing {
+                                Ok(outgoing) => outgoing,
+                                Err(reason) => {
+                                    warn!("Dropping TAP frame: {}", reason);
+                                    continue;
+                                }
+                            };
+                            debug!(
+                                "TAP -> Channel: {} bytes to MAC {} in {} piece(s)",
+                                len,
+                                node,
+                                pieces.len()
+                            );
+                            for piece in pieces {
+                                if let Err(e) =
+                                    to_acoustic_tx.send((piece, node))
+                                {
+                                    error!(
+                                        "Failed to send to acoustic channel: {}",
+                                        e
+                                    );
+                                    return;
+                                }
+                            }
+                            continue;
+                        }
                         if let Ok(ip_header) =
                             Ipv4HeaderSlice::from_slice(packet)
                         {
@@ -173,18 +319,33 @@ pub fn run_tun(
                                 dest_ip.octets()[3]
                             };
 
+                            let pieces = match options.fit(packet, mtu) {
+                                Ok(pieces) => pieces,
+                                Err(reason) => {
+                                    warn!(
+                                        "Dropping packet to {}: {}",
+                                        dest_ip, reason
+                                    );
+                                    continue;
+                                }
+                            };
                             debug!(
-                                "TUN -> Channel: {} bytes to {} (MAC {})",
-                                len, dest_ip, target_mac
+                                "TUN -> Channel: {} bytes to {} (MAC {}) in {} piece(s)",
+                                len,
+                                dest_ip,
+                                target_mac,
+                                pieces.len()
                             );
-                            if let Err(e) = to_acoustic_tx
-                                .send((packet.to_vec(), target_mac))
-                            {
-                                error!(
-                                    "Failed to send to acoustic channel: {}",
-                                    e
-                                );
-                                break;
+                            for piece in pieces {
+                                if let Err(e) =
+                                    to_acoustic_tx.send((piece, target_mac))
+                                {
+                                    error!(
+                                        "Failed to send to acoustic channel: {}",
+                                        e
+                                    );
+                                    return;
+                                }
                             }
                         }
                     }
@@ -202,7 +363,7 @@ pub fn run_tun(
 
     // 2. TUN Writer Thread (TUN Channel -> TUN Device)
     let r_writer = running.clone();
-    thread::spawn(move || {
+    let writer_handle = thread::spawn(move || {
         while r_writer.load(Ordering::SeqCst) {
             match to_tun_rx.recv_timeout(Duration::from_millis(100)) {
                 Ok(packet) => {
@@ -222,8 +383,30 @@ pub fn run_tun(
 
     while running.load(Ordering::SeqCst) {
         // A. Try receive from air
-        match interface.receive_packet(Some(Duration::from_millis(10))) {
-            Ok(mut packet) => {
+        match interface.receive_packet_from(Some(Duration::from_millis(10))) {
+            Ok((payload, src)) if bridge.is_some() => {
+                let incoming = bridge
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .incoming(src, &payload, Instant::now());
+                match incoming {
+                    Ok(Some(frame)) => {
+                        debug!(
+                            "Acoustic -> Channel: {} byte frame",
+                            frame.len()
+                        );
+                        if let Err(e) = to_tun_tx.send(frame) {
+                            error!("Failed to send to TUN channel: {}", e);
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => trace!("Ignoring payload from {}: {}", src, e),
+                }
+            }
+            Ok((mut packet, _)) => {
                 // FIXME: Patch: Ensure IPv4 checksum is correct
                 if let Ok((mut header, payload)) =
                     etherparse::Ipv4Header::from_slice(&packet)
@@ -269,5 +452,101 @@ pub fn run_tun(
     }
 
     info!("Stopping TUN Adapter...");
-    let _ = active_client.deactivate();
+    running.store(false, Ordering::SeqCst);
+    drop(to_tun_tx);
+    for handle in [reader_handle, writer_handle] {
+        if let Err(e) = handle.join() {
+            warn!("TUN thread panicked: {:?}", e);
+        }
+    }
+    // Its last descriptor went with the threads
+    info!("Removed TUN device {}", tun_name);
+    if let Err(e) = active_client.deactivate() {
+        error!("Error deactivating client: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(len: usize, dont_fragment: bool) -> Vec<u8> {
+        let header = etherparse::Ipv4Header {
+            total_len: len as u16,
+            dont_fragment,
+            time_to_live: IP_TTL,
+            protocol: etherparse::IpNumber::UDP,
+            source: [192, 168, 1, 1],
+            destination: [192, 168, 1, 2],
+            ..Default::default()
+        };
+        let mut packet = header.to_bytes().to_vec();
+        packet.resize(len, 0xab);
+        packet
+    }
+
+    #[test]
+    fn test_mtu() {
+        let options = TunOptions::default();
+        assert_eq!(options.mtu(), Ok(MAX_FRAME_DATA_SIZE));
+        let fragmented = TunOptions {
+            fragment: true,
+            ..options
+        };
+        assert_eq!(
+            fragmented.mtu(),
+            Ok(MAX_FRAME_DATA_SIZE * TUN_MAX_FRAGMENTS)
+        );
+        let requested = TunOptions {
+            mtu: Some(96),
+            ..fragmented
+        };
+        assert_eq!(requested.mtu(), Ok(96));
+        for bad in [0, 40, 9000] {
+            let options = TunOptions {
+                mtu: Some(bad),
+                ..options
+            };
+            assert!(options.mtu().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_oversize_packets() {
+        let options = TunOptions::default();
+        let small = packet(MAX_FRAME_DATA_SIZE, true);
+        assert_eq!(options.fit(&small, MAX_FRAME_DATA_SIZE), Ok(vec![small]));
+        let big = packet(300, false);
+        assert!(
+            options
+                .fit(&big, MAX_FRAME_DATA_SIZE)
+                .is_err()
+        );
+
+        let options = TunOptions {
+            fragment: true,
+            ..options
+        };
+        let pieces = options
+            .fit(&big, 256)
+            .unwrap();
+        assert!(pieces.len() > 1);
+        assert!(
+            pieces
+                .iter()
+                .all(|piece| piece.len() <= MAX_FRAME_DATA_SIZE)
+        );
+        // Within the MTU Don't Fragment only concerns the IP path, the
+        // link still splits it; over the MTU it can't be sent
+        assert!(
+            options
+                .fit(&packet(200, true), 256)
+                .is_ok()
+        );
+        assert!(
+            options
+                .fit(&packet(300, true), 256)
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,233 @@
+// UDP over IPv4
+//
+// Just enough UDP for the net tools: a datagram is built straight into an
+// IPv4 packet with both checksums filled in, and parsed back with the UDP
+// checksum verified (pseudo-header included, see ip::l4_checksum).
+//
+//   [IPv4 header:20] [SrcPort:2] [DstPort:2] [Length:2] [Checksum:2] [Payload]
+
+use std::net::SocketAddrV4;
+
+use crate::net::fragmentation::ipv4_header_checksum;
+use crate::net::ip::{IP_HEADER_BYTES, l4_checksum};
+use crate::utils::consts::IP_TTL;
+
+pub const UDP_HEADER_BYTES: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpDatagram {
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+    pub payload: Vec<u8>,
+}
+
+impl UdpDatagram {
+    pub fn new(
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        payload: Vec<u8>,
+    ) -> Self {
+        Self {
+            source,
+            destination,
+            payload,
+        }
+    }
+
+    /// The answer an echo server sends: same payload, endpoints swapped
+    pub fn echo(&self) -> Self {
+        Self::new(self.destination, self.source, self.payload.clone())
+    }
+
+    /// The whole IPv4 packet
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let udp_len = UDP_HEADER_BYTES + self.payload.len();
+        let total_len = IP_HEADER_BYTES + udp_len;
+        if total_len > u16::MAX as usize {
+            return Err(format!("UDP payload of {} bytes too large", udp_len));
+        }
+        let src = self.source.ip().octets();
+        let dst = self.destination.ip().octets();
+
+        let mut packet = Vec::with_capacity(total_len);
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+        // Identification, flags and offset: never fragmented by us
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(&[IP_TTL, UDP_PROTOCOL, 0, 0]);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        let checksum = ipv4_header_checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        packet.extend_from_slice(
+            &self
+                .source
+                .port()
+                .to_be_bytes(),
+        );
+        packet.extend_from_slice(
+            &self
+                .destination
+                .port()
+                .to_be_bytes(),
+        );
+        packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(&self.payload);
+        let checksum = match l4_checksum(
+            src,
+            dst,
+            UDP_PROTOCOL,
+            &packet[IP_HEADER_BYTES..],
+        ) {
+            // Zero on the wire means "no checksum"
+            0 => 0xFFFF,
+            sum => sum,
+        };
+        packet[IP_HEADER_BYTES + 6..IP_HEADER_BYTES + 8]
+            .copy_from_slice(&checksum.to_be_bytes());
+        Ok(packet)
+    }
+
+    /// Parse an IPv4 packet carrying UDP. Datagrams whose checksum doesn't
+    /// add up are rejected; a zero checksum means the sender skipped it.
+    pub fn from_bytes(packet: &[u8]) -> Result<Self, String> {
+        let ip = etherparse::Ipv4HeaderSlice::from_slice(packet)
+            .map_err(|e| format!("Invalid IPv4 header: {}", e))?;
+        if ip.protocol().0 != UDP_PROTOCOL {
+            return Err(format!("Not UDP but protocol {}", ip.protocol().0));
+        }
+        if ip.is_fragmenting_payload() {
+            return Err("UDP datagram is a fragment".to_string());
+        }
+        let ihl = ip.slice().len();
+        let total_len = ip.total_len() as usize;
+        let segment = packet
+            .get(ihl..total_len)
+            .ok_or("Packet shorter than its IPv4 total length")?;
+        if segment.len() < UDP_HEADER_BYTES {
+            return Err(format!("UDP segment of {} bytes", segment.len()));
+        }
+
+        let port = |i: usize| u16::from_be_bytes([segment[i], segment[i + 1]]);
+        let udp_len = port(4) as usize;
+        if udp_len < UDP_HEADER_BYTES || udp_len > segment.len() {
+            return Err(format!(
+                "UDP length {} doesn't fit {} bytes",
+                udp_len,
+                segment.len()
+            ));
+        }
+        let segment = &segment[..udp_len];
+        if port(6) != 0 {
+            let mut zeroed = segment.to_vec();
+            zeroed[6..8].fill(0);
+            let expected = match l4_checksum(
+                ip.source(),
+                ip.destination(),
+                UDP_PROTOCOL,
+                &zeroed,
+            ) {
+                0 => 0xFFFF,
+                sum => sum,
+            };
+            if port(6) != expected {
+                return Err(format!(
+                    "Bad UDP checksum {:#06x}, expected {:#06x}",
+                    port(6),
+                    expected
+                ));
+            }
+        }
+
+        Ok(Self::new(
+            SocketAddrV4::new(ip.source_addr(), port(0)),
+            SocketAddrV4::new(ip.destination_addr(), port(2)),
+            segment[UDP_HEADER_BYTES..].to_vec(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddrV4 {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_checksum_matches_etherparse() {
+        let datagram = UdpDatagram::new(
+            addr("192.168.1.2:40000"),
+            addr("192.168.1.9:7"),
+            b"hello, acoustic world".to_vec(),
+        );
+        let bytes = datagram.to_bytes().unwrap();
+
+        let mut expected = Vec::new();
+        etherparse::PacketBuilder::ipv4(
+            [192, 168, 1, 2],
+            [192, 168, 1, 9],
+            IP_TTL,
+        )
+        .udp(40000, 7)
+        .write(&mut expected, &datagram.payload)
+        .unwrap();
+        // etherparse sets DF, we don't
+        expected[6] = 0;
+        let checksum = ipv4_header_checksum(&expected[..20]);
+        expected[10..12].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(bytes, expected);
+
+        assert_eq!(UdpDatagram::from_bytes(&bytes), Ok(datagram));
+    }
+
+    #[test]
+    fn test_echo_swaps_endpoints() {
+        let request = UdpDatagram::new(
+            addr("10.0.0.5:51000"),
+            addr("192.168.1.9:7"),
+            vec![1, 2, 3],
+        );
+        let reply = UdpDatagram::from_bytes(
+            &request
+                .echo()
+                .to_bytes()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(reply.source, addr("192.168.1.9:7"));
+        assert_eq!(reply.destination, addr("10.0.0.5:51000"));
+        assert_eq!(reply.payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_datagrams() {
+        let mut bytes = UdpDatagram::new(
+            addr("192.168.1.2:1000"),
+            addr("192.168.1.9:2000"),
+            vec![0xAB; 9],
+        )
+        .to_bytes()
+        .unwrap();
+        // Trailing link padding is ignored
+        bytes.push(0);
+        assert!(UdpDatagram::from_bytes(&bytes).is_ok());
+
+        bytes[30] ^= 0x40;
+        assert!(UdpDatagram::from_bytes(&bytes).is_err());
+        // Without a checksum the payload is taken as is
+        bytes[26..28].fill(0);
+        assert_eq!(
+            UdpDatagram::from_bytes(&bytes)
+                .unwrap()
+                .payload[2],
+            0xEB
+        );
+
+        assert!(UdpDatagram::from_bytes(&bytes[..24]).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// Automatic gain control for the receive path
+//
+// Tracks the RMS of the input over a sliding window, smooths it with
+// separate attack / decay time constants and scales samples so the signal
+// reaches the slicer at a fixed level whatever the speaker volume or mic
+// gain. Input whose level sits below the noise floor is muted, so silence
+// is never amplified into garbage frames.
+//
+// The output lags the input by half a window: the gain applied to a sample
+// comes from the window centred on it, so a frame that starts after silence
+// already sees a settled gain across its preamble.
+
+use std::collections::VecDeque;
+
+use crate::utils::consts::{
+    AGC_ATTACK_MS, AGC_DECAY_MS, AGC_NOISE_FLOOR, AGC_TARGET_LEVEL,
+    AGC_WINDOW_SAMPLES, SAMPLE_RATE,
+};
+
+pub struct Agc {
+    target: f32,
+    attack: f32,
+    decay: f32,
+    noise_floor: f32,
+    window: VecDeque<f32>,
+    window_len: usize,
+    lookahead: usize,
+    sum_squares: f64,
+    level: f32,
+}
+
+impl Default for Agc {
+    fn default() -> Self {
+        Self::new(AGC_TARGET_LEVEL, AGC_ATTACK_MS, AGC_DECAY_MS)
+    }
+}
+
+impl Agc {
+    /// `attack_ms` / `decay_ms` are the time constants of the level
+    /// estimate when the signal gets louder / quieter
+    pub fn new(target: f32, attack_ms: f32, decay_ms: f32) -> Self {
+        Self {
+            target,
+            attack: smoothing_coefficient(attack_ms),
+            decay: smoothing_coefficient(decay_ms),
+            noise_floor: AGC_NOISE_FLOOR,
+            window: VecDeque::with_capacity(AGC_WINDOW_SAMPLES),
+            window_len: AGC_WINDOW_SAMPLES,
+            lookahead: AGC_WINDOW_SAMPLES / 2,
+            sum_squares: 0.0,
+            level: 0.0,
+        }
+    }
+
+    /// Samples by which the output lags the input
+    pub fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    /// Normalize `samples`, delayed by `latency()`; muted while the level is
+    /// below the noise floor
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        samples
+            .iter()
+            .map(|&sample| {
+                self.window.push_back(sample);
+                self.sum_squares += (sample * sample) as f64;
+                if self.window.len() > self.window_len {
+                    let old = self
+                        .window
+                        .pop_front()
+                        .unwrap();
+                    self.sum_squares -= (old * old) as f64;
+                }
+                let rms = (self.sum_squares.max(0.0) / self.window.len() as f64)
+                    .sqrt() as f32;
+
+                let coefficient = if rms > self.level {
+                    self.attack
+                } else {
+                    self.decay
+                };
+                self.level += coefficient * (rms - self.level);
+
+                let delayed = match self
+                    .window
+                    .len()
+                    .checked_sub(self.lookahead + 1)
+                {
+                    Some(index) => self.window[index],
+                    None => 0.0,
+                };
+                if self.level < self.noise_floor {
+                    0.0
+                } else {
+                    delayed * self.target / self.level
+                }
+            })
+            .collect()
+    }
+}
+
+/// Per-sample smoothing factor of a one-pole filter with time constant `ms`
+fn smoothing_coefficient(ms: f32) -> f32 {
+    let samples = ms / 1000.0 * SAMPLE_RATE as f32;
+    if samples <= 1.0 {
+        1.0
+    } else {
+        1.0 - (-1.0 / samples).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_converges_to_target() {
+        for scale in [0.05, 3.0] {
+            let mut agc = Agc::default();
+            let input: Vec<f32> = (0..4800)
+                .map(|n| if n % 6 < 3 { scale } else { -scale })
+                .collect();
+            let output = agc.process(&input);
+            assert_eq!(output.len(), input.len());
+            for sample in &output[2400..] {
+                assert!((sample.abs() - AGC_TARGET_LEVEL).abs() < 0.02);
+            }
+        }
+    }
+
+    #[test]
+    fn test_silence_below_noise_floor_is_muted() {
+        let mut agc = Agc::default();
+        let hiss: Vec<f32> = (0..4800)
+            .map(|n| 0.001 * ((n * 7919) % 13) as f32 / 13.0 - 0.0005)
+            .collect();
+        assert!(
+            agc.process(&hiss)
+                .iter()
+                .all(|s| *s == 0.0)
+        );
+    }
+}
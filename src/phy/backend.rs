@@ -0,0 +1,276 @@
+// Modulation backends: everything the MAC layer needs from a signal path.
+//
+// A backend turns whole frames into samples and samples back into frames,
+// so schemes with their own internal framing (e.g. OFDM blocks) fit behind
+// the same interface as long as they deliver complete MAC frames. The
+// node's backend is picked with --modulation, see ModulationKind.
+
+use super::agc::Agc;
+use super::decoder::DecodeStats;
+use super::fec::FecKind;
+use super::frame::Frame;
+use super::line_coding::LineCodingKind;
+use super::params::PhyParams;
+use super::psk::PskBackend;
+use super::{PhyDecoder, PhyEncoder};
+use crate::mac::types::MacAddr;
+use crate::utils::consts::MAX_FRAME_DATA_SIZE;
+
+pub trait ModulationBackend: Send {
+    fn name(&self) -> &'static str;
+
+    /// Encode frames back to back, separated by `gap_samples` of silence
+    fn encode_frames(&self, frames: &[Frame], gap_samples: usize) -> Vec<f32>;
+
+    fn encode_frame(&self, frame: &Frame) -> Vec<f32> {
+        self.encode_frames(std::slice::from_ref(frame), 0)
+    }
+
+    /// `encode_frames` in chunks, for feeding playback incrementally
+    fn encode_frames_iter<'a>(
+        &'a self,
+        frames: &'a [Frame],
+        gap_samples: usize,
+    ) -> Box<dyn Iterator<Item = Vec<f32>> + 'a> {
+        Box::new(std::iter::once(self.encode_frames(frames, gap_samples)))
+    }
+
+    /// Feed received samples, returns the frames completed by them
+    fn feed_samples(&mut self, samples: &[f32]) -> Vec<Frame>;
+
+    /// Samples needed to send `frame`
+    fn airtime(&self, frame: &Frame) -> usize;
+
+    /// Largest frame payload in bytes
+    fn effective_payload_limit(&self) -> usize;
+
+    /// Samples per signal level / symbol, used to derive CSMA timing
+    fn samples_per_symbol(&self) -> usize;
+
+    /// Samples the receive path holds back before frames come out
+    fn rx_latency(&self) -> usize {
+        0
+    }
+
+    /// Receive buffer usage, if the backend tracks it
+    fn decode_stats(&self) -> Option<DecodeStats> {
+        None
+    }
+
+    fn reset_decode_stats(&mut self) {}
+}
+
+/// Baseband line coding (4B5B / Manchester) over PhyEncoder / PhyDecoder
+pub struct BasebandBackend {
+    kind: LineCodingKind,
+    samples_per_level: usize,
+    rx_latency: usize,
+    encoder: PhyEncoder,
+    decoder: PhyDecoder,
+}
+
+impl BasebandBackend {
+    pub fn new(
+        params: &PhyParams,
+        line_coding: LineCodingKind,
+        fec: FecKind,
+        local_addr: MacAddr,
+    ) -> Self {
+        let agc = Agc::default();
+        Self {
+            kind: line_coding,
+            samples_per_level: params.samples_per_level,
+            rx_latency: agc.latency(),
+            encoder: PhyEncoder::new(params, line_coding, fec),
+            decoder: PhyDecoder::new(params, line_coding, fec, local_addr)
+                .with_agc(agc),
+        }
+    }
+}
+
+impl ModulationBackend for BasebandBackend {
+    fn name(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    fn encode_frames(&self, frames: &[Frame], gap_samples: usize) -> Vec<f32> {
+        self.encoder
+            .encode_frames(frames, gap_samples)
+    }
+
+    fn encode_frames_iter<'a>(
+        &'a self,
+        frames: &'a [Frame],
+        gap_samples: usize,
+    ) -> Box<dyn Iterator<Item = Vec<f32>> + 'a> {
+        Box::new(
+            self.encoder
+                .encode_frames_iter(frames, gap_samples),
+        )
+    }
+
+    fn feed_samples(&mut self, samples: &[f32]) -> Vec<Frame> {
+        self.decoder
+            .process_samples(samples)
+    }
+
+    fn airtime(&self, frame: &Frame) -> usize {
+        self.encoder
+            .frame_samples(frame)
+    }
+
+    fn effective_payload_limit(&self) -> usize {
+        MAX_FRAME_DATA_SIZE
+    }
+
+    fn samples_per_symbol(&self) -> usize {
+        self.samples_per_level
+    }
+
+    fn rx_latency(&self) -> usize {
+        self.rx_latency
+    }
+
+    fn decode_stats(&self) -> Option<DecodeStats> {
+        Some(self.decoder.stats())
+    }
+
+    fn reset_decode_stats(&mut self) {
+        self.decoder.reset_stats();
+    }
+}
+
+/// Which backend a node runs, must match on both ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModulationKind {
+    /// BasebandBackend with the session's line coding and FEC
+    #[default]
+    Baseband,
+    /// BPSK on a carrier, see phy::psk
+    Psk,
+}
+
+impl ModulationKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ModulationKind::Baseband => "baseband",
+            ModulationKind::Psk => "psk",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "baseband" => Some(Self::Baseband),
+            "psk" | "bpsk" => Some(Self::Psk),
+            _ => None,
+        }
+    }
+
+    /// The backend, `line_coding` and `fec` only apply to baseband
+    pub fn create(
+        self,
+        params: &PhyParams,
+        line_coding: LineCodingKind,
+        fec: FecKind,
+        local_addr: MacAddr,
+    ) -> Box<dyn ModulationBackend> {
+        match self {
+            ModulationKind::Baseband => Box::new(BasebandBackend::new(
+                params,
+                line_coding,
+                fec,
+                local_addr,
+            )),
+            ModulationKind::Psk => Box::new(PskBackend::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::FrameType;
+    use crate::utils::consts::AGC_WINDOW_SAMPLES;
+
+    fn roundtrip(params: &PhyParams, kind: LineCodingKind) {
+        let tx: Box<dyn ModulationBackend> =
+            Box::new(BasebandBackend::new(params, kind, FecKind::None, 1));
+        let mut rx: Box<dyn ModulationBackend> =
+            Box::new(BasebandBackend::new(params, kind, FecKind::None, 2));
+        assert_eq!(tx.samples_per_symbol(), params.samples_per_level);
+
+        let frames: Vec<_> = (0..3u8)
+            .map(|seq| {
+                Frame::new_data(
+                    seq.into(),
+                    1,
+                    2,
+                    vec![seq; tx.effective_payload_limit()],
+                )
+            })
+            .collect();
+        let mut samples = tx.encode_frames(&frames, 50);
+        assert_eq!(
+            samples.len(),
+            frames
+                .iter()
+                .map(|f| tx.airtime(f))
+                .sum::<usize>()
+                + 2 * 50
+        );
+        // Flush the receiver's AGC, which lags by half a window
+        samples.extend(vec![0.0; 100 + AGC_WINDOW_SAMPLES]);
+
+        let decoded = rx.feed_samples(&samples);
+        assert_eq!(decoded.len(), 3);
+        for (frame, got) in frames.iter().zip(&decoded) {
+            assert_eq!(got.frame_type, FrameType::Data);
+            assert_eq!(got.sequence, frame.sequence);
+            assert_eq!(got.data, frame.data);
+        }
+        assert!(rx.decode_stats().is_some());
+    }
+
+    #[test]
+    fn test_baseband_backend_roundtrip() {
+        let params = PhyParams::default();
+        roundtrip(&params, LineCodingKind::FourBFiveB);
+        roundtrip(&params, LineCodingKind::Manchester);
+        roundtrip(&params, LineCodingKind::EightBTenB);
+    }
+
+    #[test]
+    fn test_modulation_kind_picks_backend() {
+        let params = PhyParams::default();
+        let kind = LineCodingKind::Manchester;
+        for modulation in [ModulationKind::Baseband, ModulationKind::Psk] {
+            assert_eq!(
+                ModulationKind::from_name(modulation.name()),
+                Some(modulation)
+            );
+        }
+        assert_eq!(ModulationKind::from_name("BPSK"), Some(ModulationKind::Psk));
+        assert_eq!(ModulationKind::from_name("ofdm"), None);
+
+        let baseband =
+            ModulationKind::Baseband.create(&params, kind, FecKind::None, 1);
+        assert_eq!(baseband.name(), kind.name());
+        let psk = ModulationKind::Psk.create(&params, kind, FecKind::None, 1);
+        assert_eq!(psk.name(), "BPSK");
+        assert!(psk.decode_stats().is_none());
+    }
+
+    #[test]
+    fn test_baseband_backend_with_params() {
+        // A slower link with a longer preamble, as a noisy room might need
+        for samples_per_level in [2, 5, 8] {
+            let params = PhyParams {
+                samples_per_level,
+                preamble_pattern_bytes: 4,
+                ..PhyParams::default()
+            };
+            roundtrip(&params, LineCodingKind::FourBFiveB);
+            roundtrip(&params, LineCodingKind::Manchester);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// Capture files (.tmk)
+//
+// A capture is raw received audio plus what it takes to decode it the same
+// way again: sample rate, line coding, FEC, preamble and PHY parameters.
+// The `capture` command records one from the sound card, `--dump-audio`
+// writes one for every frame the decoder gives up on and `replay` decodes
+// either. Captures committed under tests/corpus, each next to a sidecar
+// JSON with the frames it must decode to, are replayed by the tests so a
+// fixed decoder bug stays fixed. `trim` cuts a capture down and blanks its
+// payloads before it is committed.
+//
+// Layout, little endian: b"TMK1", u32 header length, the header as JSON,
+// u64 sample count, then the samples as f32.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::params::PhyParams;
+use super::{FecKind, Frame, LineCodingKind, PhyDecoder, PhyEncoder};
+use super::{PreambleKind, decoder::DecodeStats};
+use crate::mac::types::BROADCAST;
+use crate::utils::consts::{DUMP_POLL_MS, SAMPLE_RATE};
+
+const MAGIC: &[u8; 4] = b"TMK1";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CaptureHeader {
+    pub sample_rate: u32,
+    /// LineCodingKind::name
+    pub line_coding: String,
+    /// FecKind::name
+    pub fec: String,
+    /// (f0 Hz, f1 Hz, length in samples) of a chirp preamble, None for
+    /// the byte pattern
+    pub chirp: Option<(f32, f32, usize)>,
+    pub samples_per_level: usize,
+    pub preamble_pattern_bytes: usize,
+    pub inter_frame_gap_samples: usize,
+    pub energy_threshold: f32,
+    /// Why it was taken: "capture", or the decoder failure
+    pub reason: String,
+    pub unix_ms: u64,
+}
+
+impl CaptureHeader {
+    /// Header for audio at SAMPLE_RATE taken now
+    pub fn new(
+        params: &PhyParams,
+        line_coding: LineCodingKind,
+        fec: FecKind,
+        preamble: PreambleKind,
+        reason: &str,
+    ) -> Self {
+        Self {
+            sample_rate: SAMPLE_RATE,
+            line_coding: line_coding.name().to_string(),
+            fec: fec.name().to_string(),
+            chirp: match preamble {
+                PreambleKind::BytePattern => None,
+                PreambleKind::Chirp { f0, f1, len } => Some((f0, f1, len)),
+            },
+            samples_per_level: params.samples_per_level,
+            preamble_pattern_bytes: params.preamble_pattern_bytes,
+            inter_frame_gap_samples: params.inter_frame_gap_samples,
+            energy_threshold: params.energy_threshold,
+            reason: reason.to_string(),
+            unix_ms: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+        }
+    }
+
+    pub fn params(&self) -> PhyParams {
+        PhyParams {
+            samples_per_level: self.samples_per_level,
+            preamble_pattern_bytes: self.preamble_pattern_bytes,
+            inter_frame_gap_samples: self.inter_frame_gap_samples,
+            energy_threshold: self.energy_threshold,
+        }
+    }
+
+    pub fn preamble(&self) -> PreambleKind {
+        match self.chirp {
+            Some((f0, f1, len)) => PreambleKind::Chirp { f0, f1, len },
+            None => PreambleKind::BytePattern,
+        }
+    }
+
+    fn kinds(&self) -> Result<(LineCodingKind, FecKind), String> {
+        let line_coding = LineCodingKind::from_name(&self.line_coding)
+            .ok_or_else(|| {
+                format!("Unknown line coding '{}'", self.line_coding)
+            })?;
+        let fec = FecKind::from_name(&self.fec)
+            .ok_or_else(|| format!("Unknown FEC '{}'", self.fec))?;
+        self.params().validate()?;
+        Ok((line_coding, fec))
+    }
+
+    /// A promiscuous decoder set up like the one the capture was taken for
+    pub fn decoder(&self) -> Result<PhyDecoder, String> {
+        let (line_coding, fec) = self.kinds()?;
+        Ok(PhyDecoder::new(&self.params(), line_coding, fec, BROADCAST)
+            .with_preamble(self.preamble())
+            .promiscuous())
+    }
+
+    /// The encoder the frames in the capture were sent with
+    pub fn encoder(&self) -> Result<PhyEncoder, String> {
+        let (line_coding, fec) = self.kinds()?;
+        Ok(PhyEncoder::new(&self.params(), line_coding, fec)
+            .with_preamble(self.preamble()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub header: CaptureHeader,
+    pub samples: Vec<f32>,
+}
+
+impl Capture {
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let header = serde_json::to_vec(&self.header)?;
+        let mut out = io::BufWriter::new(std::fs::File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&(header.len() as u32).to_le_bytes())?;
+        out.write_all(&header)?;
+        out.write_all(&(self.samples.len() as u64).to_le_bytes())?;
+        for sample in &self.samples {
+            out.write_all(&sample.to_le_bytes())?;
+        }
+        out.flush()
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let invalid =
+            |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        let mut input = io::BufReader::new(std::fs::File::open(path)?);
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid(format!(
+                "{} is not a capture file",
+                path.display()
+            )));
+        }
+        let mut len = [0u8; 4];
+        input.read_exact(&mut len)?;
+        let mut header = vec![0u8; u32::from_le_bytes(len) as usize];
+        input.read_exact(&mut header)?;
+        let header: CaptureHeader = serde_json::from_slice(&header)?;
+        let mut count = [0u8; 8];
+        input.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count) as usize;
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        if bytes.len() != count * 4 {
+            return Err(invalid(format!(
+                "{} holds {} bytes of samples, the header says {} samples",
+                path.display(),
+                bytes.len(),
+                count
+            )));
+        }
+        Ok(Self {
+            header,
+            samples: bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        })
+    }
+
+    /// Keep only the samples between `from` and `to`
+    pub fn truncate(&mut self, from: usize, to: usize) {
+        let to = to.min(self.samples.len());
+        self.samples.truncate(to);
+        self.samples
+            .drain(..from.min(to));
+    }
+
+    /// Decode the whole capture, returning the frames, where their
+    /// preambles start and the decoder's counters
+    pub fn decode(&self) -> Result<(Vec<Frame>, Vec<u64>, DecodeStats), String> {
+        let mut decoder = self.header.decoder()?;
+        let mut frames = Vec::new();
+        let mut offsets = Vec::new();
+        // Fed in blocks as it would have been live
+        let block = SAMPLE_RATE as usize * DUMP_POLL_MS as usize / 1000;
+        for block in self.samples.chunks(block) {
+            frames.extend(decoder.process_samples(block));
+            offsets.extend_from_slice(decoder.frame_offsets());
+        }
+        Ok((frames, offsets, decoder.stats()))
+    }
+
+    /// Replace the payload of every frame that decodes with zeros, keeping
+    /// the noise and distortion around it: the frame as sent is subtracted
+    /// at the gain it was received with and the blanked one added back.
+    /// Frames that don't decode are left alone. Returns how many were
+    /// blanked.
+    pub fn anonymize(&mut self) -> Result<usize, String> {
+        if self.header.sample_rate != SAMPLE_RATE {
+            return Err(format!(
+                "Can only anonymize captures at {} Hz, not {} Hz",
+                SAMPLE_RATE, self.header.sample_rate
+            ));
+        }
+        let encoder = self.header.encoder()?;
+        let (frames, offsets, _) = self.decode()?;
+        let mut blanked = 0;
+        for (frame, &offset) in frames.iter().zip(&offsets) {
+            if frame
+                .data
+                .iter()
+                .all(|&b| b == 0)
+            {
+                continue;
+            }
+            let sent = encoder.encode_frame(frame);
+            let replacement = encoder.encode_frame(&Frame {
+                data: vec![0; frame.data.len()],
+                ..frame.clone()
+            });
+            let start = offset as usize;
+            let end = (start + sent.len()).min(self.samples.len());
+            let received = &mut self.samples[start.min(end)..end];
+            let energy: f32 = sent
+                .iter()
+                .map(|s| s * s)
+                .sum();
+            let gain = received
+                .iter()
+                .zip(&sent)
+                .map(|(r, s)| r * s)
+                .sum::<f32>()
+                / energy.max(f32::EPSILON);
+            for ((r, s), b) in received
+                .iter_mut()
+                .zip(&sent)
+                .zip(&replacement)
+            {
+                *r += gain * (b - s);
+            }
+            blanked += 1;
+        }
+
+        let (after, _, _) = self.decode()?;
+        if after.len() != frames.len() {
+            return Err(format!(
+                "{} frames decoded before blanking, {} after",
+                frames.len(),
+                after.len()
+            ));
+        }
+        Ok(blanked)
+    }
+}
+
+/// What a corpus capture must decode to, kept next to it as
+/// `<name>.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub frames: usize,
+    /// What the capture exercises
+    #[serde(default)]
+    pub note: String,
+}
+
+impl Sidecar {
+    pub fn path(capture: &Path) -> PathBuf {
+        capture.with_extension("json")
+    }
+
+    pub fn read(capture: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(Self::path(
+            capture,
+        ))?)?)
+    }
+
+    pub fn write(&self, capture: &Path) -> io::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        std::fs::write(Self::path(capture), json)
+    }
+}
+
+/// The .tmk files in `dir`, sorted
+pub fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut captures: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    captures.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| ext == "tmk")
+    });
+    captures.sort();
+    Ok(captures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::consts::INTER_FRAME_GAP_SAMPLES;
+    use rand::{Rng, SeedableRng};
+
+    fn noisy_capture(kind: LineCodingKind, preamble: PreambleKind) -> Capture {
+        let header = CaptureHeader::new(
+            &PhyParams::default(),
+            kind,
+            FecKind::None,
+            preamble,
+            "capture",
+        );
+        let frames: Vec<Frame> = (0..3u8)
+            .map(|i| Frame::new_data(i.into(), 1, 2, vec![0x40 + i; 32]))
+            .collect();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut samples = vec![0.0; 300];
+        samples.extend(
+            header
+                .encoder()
+                .unwrap()
+                .encode_frames(&frames, INTER_FRAME_GAP_SAMPLES),
+        );
+        samples.extend(vec![0.0; 300]);
+        for sample in &mut samples {
+            *sample = *sample * 0.6 + rng.random_range(-0.02..0.02);
+        }
+        Capture { header, samples }
+    }
+
+    #[test]
+    fn test_capture_round_trips_through_a_file() {
+        let path = std::env::temp_dir()
+            .join(format!("trackmaker-capture-{}.tmk", std::process::id()));
+        let capture = noisy_capture(
+            LineCodingKind::Manchester,
+            PreambleKind::default_chirp(),
+        );
+        capture.write(&path).unwrap();
+        let read = Capture::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, capture);
+        assert_eq!(read.decode().unwrap().0.len(), 3);
+    }
+
+    #[test]
+    fn test_anonymize_blanks_payloads_and_keeps_frames() {
+        let mut capture =
+            noisy_capture(LineCodingKind::FourBFiveB, PreambleKind::BytePattern);
+        let (before, offsets, _) = capture.decode().unwrap();
+        assert_eq!(before.len(), 3);
+        assert_eq!(offsets[0], 300);
+
+        assert_eq!(capture.anonymize().unwrap(), 3);
+        let (after, _, _) = capture.decode().unwrap();
+        for (before, after) in before.iter().zip(&after) {
+            assert_eq!(after.sequence, before.sequence);
+            assert_eq!(after.data.len(), before.data.len());
+            assert!(
+                after
+                    .data
+                    .iter()
+                    .all(|&b| b == 0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_truncate_keeps_the_range() {
+        let mut capture =
+            noisy_capture(LineCodingKind::FourBFiveB, PreambleKind::BytePattern);
+        let kept = capture.samples[100..250].to_vec();
+        capture.truncate(100, 250);
+        assert_eq!(capture.samples, kept);
+        capture.truncate(500, 1000);
+        assert!(capture.samples.is_empty());
+    }
+}
@@ -1,7 +1,29 @@
-// CRC8 implementation for frame integrity checking
-// Polynomial: x^8 + x^2 + x + 1 (0x07)
+// CRC implementations for frame integrity checking
+// CRC8:  x^8 + x^2 + x + 1 (0x07)
+// CRC32: IEEE 802.3 (reflected 0xEDB88320), as used by Ethernet / zlib
 
 const CRC8_POLYNOMIAL: u8 = 0x07;
+const CRC32_POLYNOMIAL: u32 = 0xEDB88320;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
 
 /// Calculate CRC8 checksum for given data
 pub fn calculate_crc8(data: &[u8]) -> u8 {
@@ -26,6 +48,20 @@ pub fn verify_crc8(data: &[u8], expected_crc: u8) -> bool {
     calculate_crc8(data) == expected_crc
 }
 
+/// Calculate CRC32 (IEEE) checksum for given data
+pub fn calculate_crc32(data: &[u8]) -> u32 {
+    !data
+        .iter()
+        .fold(0xFFFF_FFFFu32, |crc, &byte| {
+            CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+        })
+}
+
+/// Verify CRC32 checksum
+pub fn verify_crc32(data: &[u8], expected_crc: u32) -> bool {
+    calculate_crc32(data) == expected_crc
+}
+
 /// Convert byte to bit array (MSB first)
 pub fn byte_to_bits(byte: u8) -> [u8; 8] {
     let mut bits = [0u8; 8];
@@ -90,6 +126,20 @@ mod tests {
         assert!(!verify_crc8(&modified, crc));
     }
 
+    #[test]
+    fn test_crc32() {
+        // Standard check value
+        assert_eq!(calculate_crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(calculate_crc32(b""), 0);
+
+        let data = b"Hello, World!";
+        let crc = calculate_crc32(data);
+        assert!(verify_crc32(data, crc));
+        let mut modified = data.to_vec();
+        modified[3] ^= 0x10;
+        assert!(!verify_crc32(&modified, crc));
+    }
+
     #[test]
     fn test_bit_conversion() {
         let byte = 0b10110011;
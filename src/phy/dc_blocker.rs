@@ -0,0 +1,68 @@
+// DC blocker for the receive path
+//
+//   y[n] = x[n] - x[n-1] + r * y[n-1],   r = exp(-2 pi fc / fs)
+//
+// A first-order high-pass that removes sound card bias and slow baseline
+// wander ahead of the slicers. The filter state carries over between calls,
+// so a stream may be fed in arbitrary chunks.
+
+use crate::utils::consts::SAMPLE_RATE;
+
+pub struct DcBlocker {
+    r: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl DcBlocker {
+    pub fn new(cutoff_hz: f32) -> Self {
+        Self {
+            r: (-2.0 * std::f32::consts::PI * cutoff_hz / SAMPLE_RATE as f32)
+                .exp(),
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        samples
+            .iter()
+            .map(|&x| {
+                let y = x - self.prev_input + self.r * self.prev_output;
+                self.prev_input = x;
+                self.prev_output = y;
+                y
+            })
+            .collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.prev_input = 0.0;
+        self.prev_output = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_removed_and_chunking_irrelevant() {
+        let input: Vec<f32> = (0..48000)
+            .map(|n| 0.2 + if n % 6 < 3 { 1.0 } else { -1.0 })
+            .collect();
+
+        let whole = DcBlocker::new(20.0).process(&input);
+        let mut blocker = DcBlocker::new(20.0);
+        let chunked: Vec<f32> = input
+            .chunks(1000)
+            .flat_map(|chunk| blocker.process(chunk))
+            .collect();
+        assert_eq!(whole, chunked);
+
+        // After settling the output is centred on zero
+        let tail = &whole[24000..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        assert!(mean.abs() < 0.01, "mean {}", mean);
+    }
+}
@@ -1,26 +1,93 @@
+use super::agc::Agc;
+use super::capture::CaptureHeader;
+use super::dc_blocker::DcBlocker;
+use super::fec::{self, FecKind};
 use super::frame::Frame;
 use super::line_coding::{LineCode, LineCodingKind};
+use super::params::PhyParams;
+use super::preamble::{self, PreambleKind};
+use super::rate;
 use crate::mac;
+use crate::mac::types::BROADCAST;
 use crate::phy::FrameType;
-use crate::utils::consts::{MAX_FRAME_DATA_SIZE, PHY_HEADER_BYTES};
+use crate::utils::consts::{
+    CHIRP_CORRELATION_THRESHOLD, DC_BLOCK_CUTOFF_HZ, MAX_FRAME_DATA_SIZE,
+    PHY_HEADER_BYTES, SAMPLE_RATE,
+};
+use crate::utils::dump::{self, DebugDump};
+use crate::utils::metrics;
+use std::borrow::Cow;
 use tracing::{debug, trace, warn};
 
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
+/// Snapshot of the decoder's sample buffer usage and reception counters
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DecodeStats {
+    /// Samples currently retained in the decoder buffer
+    pub buffered_samples: usize,
+    /// Largest occupancy observed since creation (or last reset)
+    pub peak_buffered_samples: usize,
+    /// Upper bound on retained samples
+    pub max_buffered_samples: usize,
+    /// Samples dropped because they exceeded the retention cap
+    pub discarded_samples: u64,
+    pub preambles_detected: u64,
+    /// Frames for us whose checksum matched
+    pub frames_crc_ok: u64,
+    pub frames_crc_failed: u64,
+    /// Frame bits recovered by the line decoder
+    pub bits_decoded: u64,
+    /// RMS of the raw input of the latest `process_samples` call
+    pub last_signal_rms: f32,
+    /// Detected preambles that did not lead to a complete frame (bad
+    /// header, line code errors, frame pushed out of the buffer)
+    pub sync_losses: u64,
+    /// Estimated SNR (dB) of the latest frame for us, see phy::rate
+    pub last_snr_db: f32,
+}
+
+impl std::fmt::Display for DecodeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} preambles, {} CRC ok, {} CRC failed, {} sync losses, {} bits, rms {:.3}",
+            self.preambles_detected,
+            self.frames_crc_ok,
+            self.frames_crc_failed,
+            self.sync_losses,
+            self.bits_decoded,
+            self.last_signal_rms
+        )
+    }
+}
+
 enum DecoderState {
     Searching,
     Decoding(usize), // Stores the start of a potential frame
 }
 
 pub struct PhyDecoder {
+    params: PhyParams,
     line_code: Box<dyn LineCode>,
+    line_coding_kind: LineCodingKind,
+    fec: FecKind,
     preamble: Vec<f32>,
+    preamble_bytes: usize,
+    preamble_kind: PreambleKind,
     state: DecoderState,
+    // Sub-sample part of the frame start found by chirp sync
+    frame_frac: f32,
 
     // Correlation-based sync
     correlation_threshold: f32,
     preamble_energy: f32,
+    polarity_invariant: bool,
+
+    // Input conditioning ahead of sync and slicing
+    dc_blocker: Option<DcBlocker>,
+    agc: Option<Agc>,
 
     // Sample buffer for processing
     sample_buffer: Vec<f32>,
@@ -28,51 +95,221 @@ pub struct PhyDecoder {
 
     max_frame_bytes: usize,
 
+    // Retention cap: one preamble of look-back plus the largest possible frame
+    max_buffered_samples: usize,
+    peak_buffered_samples: usize,
+    discarded_samples: u64,
+    // Reception counters, buffer fields unused
+    counters: DecodeStats,
+
     decoded_frames: Vec<Frame>,
+    // Input sample index of each decoded frame's preamble
+    frame_offsets: Vec<u64>,
+    // Samples ever dropped from the front of the buffer
+    drained_samples: u64,
     local_addr: mac::types::MacAddr,
+    // Keep frames addressed to others too
+    promiscuous: bool,
 }
 
 impl PhyDecoder {
     pub fn new(
-        samples_per_level: usize,
-        preamble_bytes: usize,
+        params: &PhyParams,
         line_coding_kind: LineCodingKind,
+        fec: FecKind,
         local_addr: mac::types::MacAddr,
     ) -> Self {
+        let samples_per_level = params.samples_per_level;
+        let preamble_bytes = params.preamble_pattern_bytes;
         let line_code = line_coding_kind.create(samples_per_level);
         let preamble = line_code.generate_preamble(preamble_bytes);
 
         // for correlation normalization, this is pre-computed
-        let preamble_energy: f32 = preamble
-            .iter()
-            .map(|x| x * x)
-            .sum::<f32>()
-            .sqrt();
+        let preamble_energy = signal_norm(&preamble);
+
+        let max_frame_bytes = MAX_FRAME_DATA_SIZE * 2; // 1x for encoder raw data + header + CRC...
+        let max_buffered_samples = retention_cap(
+            line_code.as_ref(),
+            fec,
+            preamble.len(),
+            max_frame_bytes,
+        );
+
+        let polarity_invariant = line_code.polarity_invariant();
 
         Self {
+            params: *params,
             line_code,
+            line_coding_kind,
+            fec,
             preamble,
+            preamble_bytes,
+            preamble_kind: PreambleKind::BytePattern,
             state: DecoderState::Searching,
+            frame_frac: 0.0,
             // TODO: adjust threshold
             correlation_threshold: 0.9, // Increased threshold
             preamble_energy,
+            polarity_invariant,
+            dc_blocker: Some(DcBlocker::new(DC_BLOCK_CUTOFF_HZ)),
+            agc: None,
             sample_buffer: Vec::new(),
             buffer_offset: 0,
-            max_frame_bytes: MAX_FRAME_DATA_SIZE * 2, // 1x for encoder raw data + header + CRC...
+            max_frame_bytes,
+            max_buffered_samples,
+            peak_buffered_samples: 0,
+            discarded_samples: 0,
+            counters: DecodeStats::default(),
             decoded_frames: Vec::new(),
+            frame_offsets: Vec::new(),
+            drained_samples: 0,
             local_addr,
+            promiscuous: false,
         }
     }
 
+    /// Synchronize on a different preamble; also resets the correlation
+    /// threshold to the default for that kind
+    pub fn with_preamble(mut self, kind: PreambleKind) -> Self {
+        let (preamble, threshold) = match kind {
+            PreambleKind::BytePattern => (
+                self.line_code
+                    .generate_preamble(self.preamble_bytes),
+                0.9,
+            ),
+            PreambleKind::Chirp { f0, f1, len } => (
+                preamble::generate_chirp(f0, f1, len),
+                CHIRP_CORRELATION_THRESHOLD,
+            ),
+        };
+        self.preamble_energy = signal_norm(&preamble);
+        self.max_buffered_samples = retention_cap(
+            self.line_code.as_ref(),
+            self.fec,
+            preamble.len(),
+            self.max_frame_bytes,
+        );
+        self.preamble = preamble;
+        self.preamble_kind = kind;
+        self.correlation_threshold = threshold;
+        self
+    }
+
+    /// Normalized correlation (0..1) needed to accept a preamble
+    pub fn with_correlation_threshold(mut self, threshold: f32) -> Self {
+        self.correlation_threshold = threshold;
+        self
+    }
+
+    /// Change the DC blocker cutoff, None to feed samples through unfiltered
+    pub fn with_dc_cutoff(mut self, cutoff_hz: Option<f32>) -> Self {
+        self.dc_blocker = cutoff_hz.map(DcBlocker::new);
+        self
+    }
+
+    /// Return every frame decoded, not only those for us, as when
+    /// replaying a dump
+    pub fn promiscuous(mut self) -> Self {
+        self.promiscuous = true;
+        self
+    }
+
+    /// Normalize incoming samples with `agc` before sync and slicing.
+    /// Frames come out `agc.latency()` samples later.
+    pub fn with_agc(mut self, agc: Agc) -> Self {
+        self.agc = Some(agc);
+        self
+    }
+
     // entry point for processing incoming samples
     pub fn process_samples(&mut self, samples: &[f32]) -> Vec<Frame> {
         self.decoded_frames.clear();
-        self.sample_buffer
-            .extend_from_slice(samples);
+        self.frame_offsets.clear();
+        if !samples.is_empty() {
+            self.counters.last_signal_rms =
+                signal_norm(samples) / (samples.len() as f32).sqrt();
+        }
+
+        // Feed large inputs piecewise so the buffer never holds more than
+        // the retention cap plus one chunk
+        for chunk in samples.chunks(self.max_buffered_samples) {
+            let filtered = match &mut self.dc_blocker {
+                Some(dc_blocker) => Cow::Owned(dc_blocker.process(chunk)),
+                None => Cow::Borrowed(chunk),
+            };
+            match &mut self.agc {
+                Some(agc) => self
+                    .sample_buffer
+                    .extend(agc.process(&filtered)),
+                None => self
+                    .sample_buffer
+                    .extend_from_slice(&filtered),
+            }
+            self.peak_buffered_samples = self
+                .peak_buffered_samples
+                .max(self.sample_buffer.len());
+            self.run_state_machine();
+            self.trim_buffer();
+        }
+
+        self.decoded_frames.clone()
+    }
+
+    /// Input sample index (counted over every process_samples call) where
+    /// the preamble of each frame the last call returned starts
+    pub fn frame_offsets(&self) -> &[u64] {
+        &self.frame_offsets
+    }
+
+    /// Header for a capture of what this decoder is fed, decodable the
+    /// same way again (see phy::capture)
+    pub fn capture_header(&self, reason: &str) -> CaptureHeader {
+        CaptureHeader::new(
+            &self.params,
+            self.line_coding_kind,
+            self.fec,
+            self.preamble_kind,
+            reason,
+        )
+    }
+
+    pub fn reset(&mut self) {
+        self.drained_samples += self.sample_buffer.len() as u64;
+        self.sample_buffer.clear();
+        self.buffer_offset = 0;
+        self.state = DecoderState::Searching;
+        self.line_code.reset();
+        if let Some(dc_blocker) = &mut self.dc_blocker {
+            dc_blocker.reset();
+        }
+        self.reset_stats();
+    }
 
+    /// Current buffer occupancy, retention statistics and counters
+    pub fn stats(&self) -> DecodeStats {
+        DecodeStats {
+            buffered_samples: self.sample_buffer.len(),
+            peak_buffered_samples: self.peak_buffered_samples,
+            max_buffered_samples: self.max_buffered_samples,
+            discarded_samples: self.discarded_samples,
+            ..self.counters
+        }
+    }
+
+    /// Zero the counters and the buffer peak
+    pub fn reset_stats(&mut self) {
+        self.peak_buffered_samples = self.sample_buffer.len();
+        self.discarded_samples = 0;
+        self.counters = DecodeStats::default();
+    }
+
+    fn run_state_machine(&mut self) {
         loop {
             let processed_len = match self.state {
-                DecoderState::Searching => self.search_for_preamble(),
+                DecoderState::Searching => match self.preamble_kind {
+                    PreambleKind::BytePattern => self.search_for_preamble(),
+                    PreambleKind::Chirp { .. } => self.search_for_chirp(),
+                },
                 DecoderState::Decoding(frame_start_offset) => {
                     self.decode_frame(frame_start_offset)
                 }
@@ -85,7 +322,11 @@ impl PhyDecoder {
                 break;
             }
         }
+    }
 
+    /// Drops samples that can no longer start a preamble and enforces the
+    /// retention cap.
+    fn trim_buffer(&mut self) {
         // Clean up processed part of the buffer
         if self.buffer_offset > 0 {
             let keep_overlap = self
@@ -97,25 +338,46 @@ impl PhyDecoder {
                 .saturating_sub(keep_overlap);
 
             if drain_end > 0 {
-                self.sample_buffer
-                    .drain(..drain_end);
-                self.buffer_offset -= drain_end;
-
-                // Adjust decoding offset if it's active
-                if let DecoderState::Decoding(start) = &mut self.state {
-                    *start = start.saturating_sub(drain_end);
-                }
+                self.drain_front(drain_end);
             }
         }
 
-        self.decoded_frames.clone()
+        // Should not happen while the state machine makes progress, but never
+        // let a stuck frame pin unbounded history
+        let excess = self
+            .sample_buffer
+            .len()
+            .saturating_sub(self.max_buffered_samples);
+        if excess > 0 {
+            warn!(
+                "Decoder buffer exceeded cap ({} > {}), discarding {} samples",
+                self.sample_buffer.len(),
+                self.max_buffered_samples,
+                excess
+            );
+            self.discarded_samples += excess as u64;
+            self.drain_front(excess);
+        }
     }
 
-    pub fn reset(&mut self) {
-        self.sample_buffer.clear();
-        self.buffer_offset = 0;
-        self.state = DecoderState::Searching;
-        self.line_code.reset();
+    fn drain_front(&mut self, count: usize) {
+        self.sample_buffer
+            .drain(..count);
+        self.drained_samples += count as u64;
+        self.buffer_offset = self
+            .buffer_offset
+            .saturating_sub(count);
+
+        // Adjust decoding offset if it's active
+        if let DecoderState::Decoding(start) = self.state {
+            if start >= count + self.preamble.len() {
+                self.state = DecoderState::Decoding(start - count);
+            } else {
+                // The frame's preamble fell off the buffer, give it up
+                self.counters.sync_losses += 1;
+                self.state = DecoderState::Searching;
+            }
+        }
     }
 
     /// Scans the buffer for a preamble.
@@ -129,21 +391,43 @@ impl PhyDecoder {
         let preamble_len = self.preamble.len();
         let window_count = search_area.len() - preamble_len + 1;
 
+        // Correlate with the window's mean taken out, so a baseline shift
+        // (the DC blocker's tail after a long NRZI run) can't push a
+        // preamble under the threshold
+        let n = preamble_len as f32;
+        let preamble_sum: f32 = self.preamble.iter().sum();
+        let preamble_mean = preamble_sum / n;
+        let preamble_norm = (self.preamble_energy * self.preamble_energy
+            - preamble_sum * preamble_mean)
+            .max(0.0)
+            .sqrt();
+
         // Calculate initial energy
         let mut window_energy: f32 = search_area[0..preamble_len]
             .iter()
             .map(|x| x * x)
             .sum();
+        let mut window_sum: f32 = search_area[0..preamble_len]
+            .iter()
+            .sum();
 
         for i in 0..window_count {
             let window = &search_area[i..i + preamble_len];
+            let centred_energy = window_energy - window_sum * window_sum / n;
 
             // Optimization: Skip dot product if energy is too low
-            let correlation = if window_energy < 1e-6 {
+            let correlation = if centred_energy < 1e-6 || preamble_norm < 1e-6 {
                 0.0
             } else {
-                let dot_product = self.compute_dot_product(window);
-                dot_product / (window_energy.sqrt() * self.preamble_energy)
+                let dot_product = self.compute_dot_product(window)
+                    - window_sum * preamble_mean;
+                dot_product / (centred_energy.sqrt() * preamble_norm)
+            };
+            // An inverted channel correlates negatively
+            let correlation = if self.polarity_invariant {
+                correlation.abs()
+            } else {
+                correlation
             };
 
             if correlation >= self.correlation_threshold {
@@ -201,6 +485,11 @@ impl PhyDecoder {
                         0.0
                     };
 
+                    let corr = if self.polarity_invariant {
+                        corr.abs()
+                    } else {
+                        corr
+                    };
                     if corr > best_corr {
                         best_corr = corr;
                         best_offset = j;
@@ -215,6 +504,8 @@ impl PhyDecoder {
                 // Preamble found, switch to decoding state
                 let frame_start_offset =
                     self.buffer_offset + best_offset + sync_len;
+                self.counters
+                    .preambles_detected += 1;
                 self.state = DecoderState::Decoding(frame_start_offset);
                 // Consume buffer up to the start of the preamble
                 return Some(i);
@@ -226,6 +517,7 @@ impl PhyDecoder {
                 let entering = search_area[i + preamble_len];
                 window_energy =
                     window_energy - leaving * leaving + entering * entering;
+                window_sum += entering - leaving;
                 // Prevent negative energy due to floating point errors
                 if window_energy < 0.0 {
                     window_energy = 0.0;
@@ -237,6 +529,149 @@ impl PhyDecoder {
         Some(window_count)
     }
 
+    /// Normalized correlation of the preamble with the buffer at `start`
+    fn correlation_at(&self, start: usize) -> f32 {
+        let window = &self.sample_buffer[start..start + self.preamble.len()];
+        let energy = signal_norm(window);
+        let corr = if energy < 1e-3 {
+            0.0
+        } else {
+            self.compute_dot_product(window) / (energy * self.preamble_energy)
+        };
+        if self.polarity_invariant {
+            corr.abs()
+        } else {
+            corr
+        }
+    }
+
+    /// Scans the buffer for a chirp, locating the frame start to a fraction
+    /// of a sample from the shape of the correlation peak.
+    /// Returns Some(bytes_consumed) or None if more data is needed.
+    fn search_for_chirp(&mut self) -> Option<usize> {
+        let preamble_len = self.preamble.len();
+        let available = self
+            .sample_buffer
+            .len()
+            .saturating_sub(self.buffer_offset);
+        if available < preamble_len + 1 {
+            return None;
+        }
+        let window_count = available - preamble_len + 1;
+
+        for i in 0..window_count {
+            let start = self.buffer_offset + i;
+            let corr = self.correlation_at(start);
+            if corr < self.correlation_threshold {
+                continue;
+            }
+
+            // Climb to the top of the peak, which needs one window past it
+            let mut peak = start;
+            let mut peak_corr = corr;
+            loop {
+                if peak + 1 + preamble_len > self.sample_buffer.len() {
+                    // Wait for more samples, keeping this window
+                    return if i > 0 { Some(i) } else { None };
+                }
+                let next = self.correlation_at(peak + 1);
+                if next <= peak_corr {
+                    break;
+                }
+                peak += 1;
+                peak_corr = next;
+            }
+
+            let frac = if peak > 0 {
+                preamble::refine_peak(
+                    self.correlation_at(peak - 1),
+                    peak_corr,
+                    self.correlation_at(peak + 1),
+                )
+            } else {
+                0.0
+            };
+            debug!(
+                "Chirp detected at offset {} (corr={:.3}, frac={:+.2})",
+                peak, peak_corr, frac
+            );
+
+            let (frame_start, frame_frac) = if frac < 0.0 {
+                (peak + preamble_len - 1, 1.0 + frac)
+            } else {
+                (peak + preamble_len, frac)
+            };
+            self.frame_frac = frame_frac;
+            self.counters
+                .preambles_detected += 1;
+            self.state = DecoderState::Decoding(frame_start);
+            // Consume buffer up to the start of the preamble
+            return Some(peak - self.buffer_offset);
+        }
+
+        Some(window_count)
+    }
+
+    /// `len` frame samples from `start`, shifted by the sub-sample part of
+    /// the chirp alignment. None if the buffer is still too short.
+    fn aligned_samples(
+        &self,
+        start: usize,
+        len: usize,
+    ) -> Option<Cow<'_, [f32]>> {
+        if self.frame_frac > 0.0 {
+            let samples = self
+                .sample_buffer
+                .get(start..start + len + 1)?;
+            Some(Cow::Owned(preamble::fractional_shift(
+                samples,
+                self.frame_frac,
+            )))
+        } else {
+            self.sample_buffer
+                .get(start..start + len)
+                .map(Cow::Borrowed)
+        }
+    }
+
+    /// Queue `len` buffered samples from `start`, with up to a preamble
+    /// of lead-in, for --debug-dumps and --dump-audio. The soft values of
+    /// the `line_samples` after the preamble go along as the constellation
+    /// when the line code gives them.
+    fn dump_failure(
+        &self,
+        reason: &'static str,
+        start: usize,
+        len: usize,
+        line_samples: usize,
+    ) {
+        if !dump::debug_dumps_selected() {
+            return;
+        }
+        let symbols = self
+            .aligned_samples(start + self.preamble.len(), line_samples)
+            .and_then(|samples| {
+                self.line_code
+                    .soft_decode(&samples)
+            })
+            .map(|soft| {
+                soft.into_iter()
+                    .map(|v| (v, 0.0))
+                    .collect()
+            });
+        let end = (start + len).min(self.sample_buffer.len());
+        let start = start
+            .saturating_sub(self.preamble.len())
+            .min(end);
+        dump::debug_dump(DebugDump {
+            reason,
+            samples: self.sample_buffer[start..end].to_vec(),
+            sample_rate: SAMPLE_RATE,
+            symbols,
+            capture: Some(self.capture_header(reason)),
+        });
+    }
+
     /// Tries to decode a full frame from the buffer.
     /// Returns Some(bytes_consumed) or None if more data is needed.
     fn decode_frame(&mut self, frame_start_offset: usize) -> Option<usize> {
@@ -248,65 +683,131 @@ impl PhyDecoder {
         let header_bits = 8 * PHY_HEADER_BYTES;
         let header_samples = self
             .line_code
-            .samples_for_bits(header_bits);
-        if self.sample_buffer.len() < frame_start_offset + header_samples {
-            return None; // Need more data
-        }
-
+            .samples_for_bits(
+                self.fec
+                    .coded_len(header_bits),
+            );
         // Decode header
-        let header_data = &self.sample_buffer
-            [frame_start_offset..frame_start_offset + header_samples];
-        let header_decoded = self
-            .line_code
-            .decode(header_data);
-
-        let (data_len_, _crc, data_type, _seq, _src, dst) =
-            match Frame::parse_header(&header_decoded) {
-                Some(vals) => vals,
-                None => {
-                    warn!(
-                        "Failed to parse header at offset {}. Returning to search.",
-                        preamble_start_offset
-                    );
-                    self.state = DecoderState::Searching;
-                    return Some(header_samples); // Consume 1 sample to avoid getting stuck
-                }
-            };
-        let data_len = data_len_ as usize;
+        let header_decoded = {
+            let header_data =
+                self.aligned_samples(frame_start_offset, header_samples)?; // Need more data
+            self.fec.decode(
+                &self
+                    .line_code
+                    .decode(&header_data),
+            )
+        };
+
+        let header = match Frame::parse_header(&header_decoded) {
+            Some(header) => header,
+            None => {
+                warn!(
+                    "Failed to parse header at offset {}. Returning to search.",
+                    preamble_start_offset
+                );
+                self.counters.sync_losses += 1;
+                self.dump_failure(
+                    "bad-header",
+                    preamble_start_offset,
+                    self.preamble.len() + header_samples,
+                    header_samples,
+                );
+                self.state = DecoderState::Searching;
+                return Some(header_samples); // Consume 1 sample to avoid getting stuck
+            }
+        };
+        let data_len = header.len;
+        let data_type = header.frame_type;
+        let dst = header.dst;
 
-        if data_type == FrameType::Data && data_len == 0
+        if matches!(data_type, FrameType::Data | FrameType::Aggregate)
+            && data_len == 0
             || data_len > self.max_frame_bytes
         {
             warn!(
                 "Invalid data_len={} at offset {}. Returning to search.",
                 data_len, preamble_start_offset
             );
+            self.counters.sync_losses += 1;
+            self.dump_failure(
+                "bad-length",
+                preamble_start_offset,
+                self.preamble.len() + header_samples,
+                header_samples,
+            );
             self.state = DecoderState::Searching;
             return Some(1); // Consume 1 sample
         }
 
         // Check if we have enough data for the full frame
-        let total_bytes = PHY_HEADER_BYTES + data_len; // header + data + crc
-        let total_bits = total_bytes * 8;
-        let total_samples = self
-            .line_code
-            .samples_for_bits(total_bits);
-
-        if self.sample_buffer.len() < frame_start_offset + total_samples {
-            return None; // Need more data
-        }
+        let body_bits = 8 * header.body_len(); // data + crc
+        let coded_body_bits = if header.conv_coded {
+            fec::conv_coded_len(body_bits)
+        } else {
+            body_bits
+        };
+        let total_bits = header_bits + coded_body_bits;
+        let coded_len = self.fec.coded_len(total_bits);
+        let coded_header_len = self
+            .fec
+            .coded_len(header_bits);
+        // Faster frames switch line code after the header (see phy::rate)
+        let body_code = rate::body_line_code(self.line_coding_kind, header.rate);
+        let total_samples = match &body_code {
+            Some(body_code) => {
+                header_samples
+                    + body_code.samples_for_bits(coded_len - coded_header_len)
+            }
+            None => self
+                .line_code
+                .samples_for_bits(coded_len),
+        };
 
         // Decode and parse the full frame
-        let frame_data = &self.sample_buffer
-            [frame_start_offset..frame_start_offset + total_samples];
-        let frame_bits = self
-            .line_code
-            .decode(frame_data);
-
-        let consumed_len = self.preamble.len()
-            + self
+        let frame_data =
+            self.aligned_samples(frame_start_offset, total_samples)?; // Need more data
+        let mut line_bits = match &body_code {
+            Some(body_code) => {
+                let mut bits = self
+                    .line_code
+                    .decode(&frame_data[..header_samples]);
+                bits.truncate(coded_header_len);
+                bits.extend(body_code.decode(&frame_data[header_samples..]));
+                bits
+            }
+            None => self
                 .line_code
-                .samples_for_bits(frame_bits.len());
+                .decode(&frame_data),
+        };
+        if let Some(interleaver) = header.interleave
+            && line_bits.len() >= coded_len
+        {
+            line_bits.truncate(coded_len);
+            let body = interleaver.deinterleave(&line_bits[coded_header_len..]);
+            line_bits.truncate(coded_header_len);
+            line_bits.extend(body);
+        }
+        let mut frame_bits = self.fec.decode(&line_bits);
+        // The samples the header's line code covers, for the dumps
+        let line_samples = match &body_code {
+            Some(_) => header_samples,
+            None => total_samples,
+        };
+
+        let consumed_len = match &body_code {
+            // Skip the whole frame, the body's line code can't say where a
+            // partial decode stopped
+            Some(_) => self.preamble.len() + total_samples,
+            None => {
+                self.preamble.len()
+                    + self
+                        .line_code
+                        .samples_for_bits(
+                            self.fec
+                                .coded_len(frame_bits.len()),
+                        )
+            }
+        };
 
         if frame_bits.len() < total_bits {
             warn!(
@@ -315,11 +816,24 @@ impl PhyDecoder {
                 total_bits,
                 consumed_len
             );
+            self.counters.sync_losses += 1;
+            self.dump_failure(
+                "line-decode",
+                preamble_start_offset,
+                self.preamble.len() + total_samples,
+                line_samples,
+            );
             self.state = DecoderState::Searching;
             return Some(consumed_len);
         }
 
-        if dst != self.local_addr {
+        // Frames carrying a NAV are overheard by everyone (see mac::nav)
+        if dst != self.local_addr
+            && dst != BROADCAST
+            && !header.nav
+            && !self.promiscuous
+        {
+            self.counters.bits_decoded += total_bits as u64;
             debug!(
                 "Frame not for us (dst={}, type={:?}). Consumed {} samples",
                 dst, data_type, consumed_len
@@ -328,6 +842,36 @@ impl PhyDecoder {
             return Some(consumed_len);
         }
 
+        if header.conv_coded {
+            // Soft decisions straight from the samples when nothing sits
+            // between the line code and the convolutional code
+            let soft = match self.fec {
+                FecKind::None if body_code.is_none() => self
+                    .line_code
+                    .soft_decode(&frame_data),
+                _ => None,
+            };
+            let body = match soft {
+                Some(soft) => {
+                    let body = &soft[header_bits..header_bits + coded_body_bits];
+                    match header.interleave {
+                        Some(interleaver) => fec::viterbi_decode_soft(
+                            &interleaver.deinterleave(body),
+                        ),
+                        None => fec::viterbi_decode_soft(body),
+                    }
+                }
+                None => fec::viterbi_decode_hard(
+                    &frame_bits[header_bits..header_bits + coded_body_bits],
+                ),
+            };
+            frame_bits.truncate(header_bits);
+            frame_bits.extend(body);
+        }
+
+        let snr_db = rate::estimate_snr_db(&frame_data);
+        self.counters.bits_decoded += total_bits as u64;
+        self.counters.last_snr_db = snr_db;
         match Frame::from_bits(&frame_bits) {
             Some(frame) => {
                 debug!(
@@ -338,8 +882,14 @@ impl PhyDecoder {
                     frame.src,
                     frame.dst
                 );
+                self.counters.frames_crc_ok += 1;
+                metrics::global()
+                    .frames_rx
+                    .inc();
                 self.decoded_frames
                     .push(frame);
+                self.frame_offsets
+                    .push(self.drained_samples + preamble_start_offset as u64);
                 self.state = DecoderState::Searching; // Go back to searching for the next frame
                 Some(consumed_len)
             }
@@ -348,6 +898,17 @@ impl PhyDecoder {
                     "Frame CRC failed at offset {}. Returning to search.",
                     preamble_start_offset
                 );
+                self.counters
+                    .frames_crc_failed += 1;
+                metrics::global()
+                    .crc_errors
+                    .inc();
+                self.dump_failure(
+                    "crc",
+                    preamble_start_offset,
+                    self.preamble.len() + total_samples,
+                    line_samples,
+                );
                 self.state = DecoderState::Searching;
                 // Consume the failed frame to move on
                 Some(consumed_len)
@@ -416,3 +977,688 @@ impl PhyDecoder {
         }
     }
 }
+
+fn signal_norm(samples: &[f32]) -> f32 {
+    samples
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Retention cap: one preamble of look-back plus the largest possible frame
+fn retention_cap(
+    line_code: &dyn LineCode,
+    fec: FecKind,
+    preamble_len: usize,
+    max_frame_bytes: usize,
+) -> usize {
+    2 * preamble_len
+        + line_code.samples_for_bits(fec.coded_len(
+            8 * PHY_HEADER_BYTES
+                + fec::conv_coded_len(8 * (max_frame_bytes + 4)),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::PhyEncoder;
+    use crate::utils::consts::INTER_FRAME_GAP_SAMPLES;
+
+    fn params(samples_per_level: usize) -> PhyParams {
+        PhyParams {
+            samples_per_level,
+            ..PhyParams::default()
+        }
+    }
+
+    #[test]
+    fn test_buffer_bounded_during_silence() {
+        let mut decoder = PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+            2,
+        );
+        let cap = decoder
+            .stats()
+            .max_buffered_samples;
+
+        // 10 minutes of silence at 48 kHz, delivered in 25 ms chunks
+        let chunk = vec![0.0f32; 1200];
+        for _ in 0..(48000 * 600 / chunk.len()) {
+            assert!(
+                decoder
+                    .process_samples(&chunk)
+                    .is_empty()
+            );
+            assert!(
+                decoder
+                    .stats()
+                    .buffered_samples
+                    <= cap
+            );
+        }
+        assert!(
+            decoder
+                .stats()
+                .peak_buffered_samples
+                <= cap + chunk.len()
+        );
+    }
+
+    #[test]
+    fn test_large_input_is_processed_piecewise() {
+        let encoder = PhyEncoder::new(
+            &PhyParams::default(),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+        );
+        let mut decoder = PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+            2,
+        );
+        let cap = decoder
+            .stats()
+            .max_buffered_samples;
+
+        let mut samples = vec![0.0f32; cap * 20];
+        samples.extend(encoder.encode_frame(&Frame::new_data(
+            7,
+            1,
+            2,
+            vec![0xAB; 64],
+        )));
+        samples.extend(vec![0.0f32; 100]);
+
+        let frames = decoder.process_samples(&samples);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].sequence, 7);
+        assert!(
+            decoder
+                .stats()
+                .peak_buffered_samples
+                <= 2 * cap
+        );
+    }
+
+    #[test]
+    fn test_nrzi_survives_inverted_channel() {
+        let encoder = PhyEncoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Nrzi,
+            FecKind::None,
+        );
+        let mut decoder = PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Nrzi,
+            FecKind::None,
+            2,
+        );
+        let frames: Vec<_> = (0..3u8)
+            .map(|seq| Frame::new_data(seq.into(), 1, 2, vec![seq ^ 0x5A; 40]))
+            .collect();
+
+        let mut samples = vec![0.0; 200];
+        samples.extend(encoder.encode_frames(&frames, 100));
+        samples.extend(vec![0.0; 200]);
+        let inverted: Vec<f32> = samples
+            .iter()
+            .map(|s| s * -1.0)
+            .collect();
+
+        let decoded = decoder.process_samples(&inverted);
+        assert_eq!(decoded.len(), frames.len());
+        for (frame, got) in frames.iter().zip(&decoded) {
+            assert_eq!(got.sequence, frame.sequence);
+            assert_eq!(got.data, frame.data);
+        }
+    }
+
+    #[test]
+    fn test_nrzi_zero_runs_back_to_back() {
+        // Zero payloads are one long NRZI level; the DC blocker's tail
+        // after it used to hide the next frame's preamble
+        let encoder = PhyEncoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Nrzi,
+            FecKind::None,
+        );
+        let mut decoder = PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Nrzi,
+            FecKind::None,
+            2,
+        );
+        let frames: Vec<_> = (0..5u16)
+            .map(|seq| Frame::new_data(seq, 1, 2, vec![0; 24]))
+            .collect();
+
+        let mut samples = vec![0.0; 400];
+        samples.extend(encoder.encode_frames(&frames, INTER_FRAME_GAP_SAMPLES));
+        samples.extend(vec![0.0; 400]);
+
+        let decoded = decoder.process_samples(&samples);
+        let seqs: Vec<u16> = decoded
+            .iter()
+            .map(|f| f.sequence)
+            .collect();
+        assert_eq!(seqs, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_crc32_rejects_random_bit_flips() {
+        use crate::phy::frame::ChecksumKind;
+        use rand::{Rng, SeedableRng};
+
+        let spl = 3;
+        let samples_per_bit = 2 * spl; // Manchester
+        let encoder = PhyEncoder::new(
+            &params(spl),
+            LineCodingKind::Manchester,
+            FecKind::None,
+        );
+        let frame = Frame::new_data(7, 1, 2, (0..100u8).collect());
+        assert_eq!(frame.checksum, ChecksumKind::Crc32);
+
+        let clean = encoder.encode_frame(&frame);
+        let body_start =
+            encoder.preamble_len() + 8 * PHY_HEADER_BYTES * samples_per_bit;
+        let body_bits = (clean.len() - body_start) / samples_per_bit;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x1003);
+        for _ in 0..200 {
+            let mut samples = clean.clone();
+            for _ in 0..rng.random_range(1..=4) {
+                let bit = rng.random_range(0..body_bits);
+                let start = body_start + bit * samples_per_bit;
+                for s in &mut samples[start..start + samples_per_bit] {
+                    *s = -*s;
+                }
+            }
+            samples.extend(vec![0.0; 100]);
+
+            let mut decoder = PhyDecoder::new(
+                &params(spl),
+                LineCodingKind::Manchester,
+                FecKind::None,
+                2,
+            );
+            assert!(
+                decoder
+                    .process_samples(&samples)
+                    .is_empty(),
+                "corrupted frame accepted"
+            );
+        }
+
+        // Control: the untouched stream decodes
+        let mut decoder = PhyDecoder::new(
+            &params(spl),
+            LineCodingKind::Manchester,
+            FecKind::None,
+            2,
+        );
+        let mut samples = clean;
+        samples.extend(vec![0.0; 100]);
+        assert_eq!(
+            decoder
+                .process_samples(&samples)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_hamming_corrects_one_error_per_codeword() {
+        let spl = 3;
+        let samples_per_bit = 2 * spl; // Manchester
+        let kind = LineCodingKind::Manchester;
+        let encoder = PhyEncoder::new(&params(spl), kind, FecKind::Hamming74);
+        let mut decoder =
+            PhyDecoder::new(&params(spl), kind, FecKind::Hamming74, 2);
+
+        let frames: Vec<_> = (0..8u8)
+            .map(|seq| {
+                Frame::new_data(seq.into(), 1, 2, vec![seq.wrapping_mul(37); 60])
+            })
+            .collect();
+        let mut samples = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let mut air = encoder.encode_frame(frame);
+            let coded_bits =
+                (air.len() - encoder.preamble_len()) / samples_per_bit;
+            // One flipped bit in every 7-bit codeword, header included
+            for word in 0..coded_bits / 7 {
+                let bit = word * 7 + (word + i) % 7;
+                let start = encoder.preamble_len() + bit * samples_per_bit;
+                for s in &mut air[start..start + samples_per_bit] {
+                    *s = -*s;
+                }
+            }
+            samples.extend(air);
+            samples.extend(vec![0.0; 100]);
+        }
+
+        let decoded = decoder.process_samples(&samples);
+        assert_eq!(decoded.len(), frames.len());
+        for (frame, got) in frames.iter().zip(&decoded) {
+            assert_eq!(got.data, frame.data);
+        }
+    }
+
+    #[test]
+    fn test_rate_switches_after_header() {
+        for kind in [
+            LineCodingKind::Manchester,
+            LineCodingKind::FourBFiveB,
+            LineCodingKind::EightBTenB,
+            LineCodingKind::Nrzi,
+        ] {
+            for fec in [FecKind::None, FecKind::Hamming74] {
+                let encoder = PhyEncoder::new(&PhyParams::default(), kind, fec);
+                let mut decoder =
+                    PhyDecoder::new(&PhyParams::default(), kind, fec, 2);
+
+                let frames: Vec<_> = (0..=rate::MAX_RATE_CODE)
+                    .map(|code| {
+                        let mut frame = Frame::new_probe(
+                            code.into(),
+                            1,
+                            2,
+                            code,
+                            vec![0x3C ^ code; 40],
+                        );
+                        frame.conv_coded = code == rate::MAX_RATE_CODE;
+                        frame
+                    })
+                    .collect();
+                let mut samples = Vec::new();
+                for frame in &frames {
+                    let air = encoder.encode_frame(frame);
+                    assert_eq!(air.len(), encoder.frame_samples(frame));
+                    samples.extend(air);
+                    samples.extend(vec![0.0; 100]);
+                }
+                // Faster bodies are shorter on the air
+                assert!(
+                    encoder.frame_samples(&frames[1])
+                        < encoder.frame_samples(&frames[0])
+                );
+
+                let decoded = decoder.process_samples(&samples);
+                assert_eq!(
+                    decoded.len(),
+                    frames.len(),
+                    "{} {}",
+                    kind,
+                    fec.name()
+                );
+                for (frame, got) in frames.iter().zip(&decoded) {
+                    assert_eq!(got.rate, frame.rate);
+                    assert_eq!(got.data, frame.data);
+                }
+                // A clean channel clears the bar of mac::autorate
+                assert!(
+                    decoder.stats().last_snr_db
+                        >= crate::utils::consts::PROBE_MIN_SNR_DB
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_conv_coded_body_survives_bit_errors() {
+        let spl = 3;
+        for (kind, samples_per_bit) in [
+            (LineCodingKind::Manchester, 2 * spl), // soft decisions
+            (LineCodingKind::Nrzi, spl),           // hard decisions
+        ] {
+            let encoder = PhyEncoder::new(&params(spl), kind, FecKind::None);
+            let mut decoder =
+                PhyDecoder::new(&params(spl), kind, FecKind::None, 2);
+            let mut frame = Frame::new_data(1, 1, 2, (0..64u8).collect());
+            frame.conv_coded = true;
+
+            let mut samples = encoder.encode_frame(&frame);
+            assert_eq!(samples.len(), encoder.frame_samples(&frame));
+            // Corrupt one coded bit every 50 past the header
+            let mut bit = 8 * PHY_HEADER_BYTES + 20;
+            while (bit + 1) * samples_per_bit
+                < samples.len() - encoder.preamble_len()
+            {
+                let start = encoder.preamble_len() + bit * samples_per_bit;
+                for s in &mut samples[start..start + samples_per_bit] {
+                    *s = -*s;
+                }
+                bit += 50;
+            }
+            samples.extend(vec![0.0; 100]);
+
+            let decoded = decoder.process_samples(&samples);
+            assert_eq!(decoded.len(), 1, "{}", kind);
+            assert_eq!(decoded[0].data, frame.data);
+            assert!(decoded[0].conv_coded);
+        }
+    }
+
+    #[test]
+    fn test_interleaving_survives_5ms_dropout() {
+        use crate::phy::interleaver::Interleaver;
+        use crate::utils::consts::SAMPLE_RATE;
+
+        let spl = 3;
+        let kind = LineCodingKind::Manchester;
+        let fec = FecKind::Hamming74;
+        let encoder = PhyEncoder::new(&params(spl), kind, fec);
+        let dropout = SAMPLE_RATE as usize * 5 / 1000;
+
+        let run = |interleave: Option<Interleaver>| {
+            let mut decoder = PhyDecoder::new(&params(spl), kind, fec, 2);
+            let mut frame = Frame::new_data(1, 1, 2, (0..128u8).collect());
+            frame.interleave = interleave;
+
+            let mut samples = encoder.encode_frame(&frame);
+            // Zero 5 ms in the middle of the body
+            let start = samples.len() / 2;
+            samples[start..start + dropout].fill(0.0);
+            samples.extend(vec![0.0; 100]);
+            decoder.process_samples(&samples)
+        };
+
+        assert!(run(None).is_empty());
+        let decoded = run(Some(Interleaver::new(64, 16).unwrap()));
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].data, (0..128u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_chirp_sync_with_fractional_delay() {
+        use crate::phy::preamble::{PreambleKind, fractional_shift};
+
+        let spl = 2;
+        let kind = LineCodingKind::FourBFiveB;
+        let chirp = PreambleKind::default_chirp();
+        let encoder = PhyEncoder::new(&params(spl), kind, FecKind::None)
+            .with_preamble(chirp);
+        let frames: Vec<_> = (0..3u8)
+            .map(|seq| Frame::new_data(seq.into(), 1, 2, vec![seq ^ 0xC3; 50]))
+            .collect();
+
+        for delay in [0.25, 0.5, 0.7] {
+            let mut decoder =
+                PhyDecoder::new(&params(spl), kind, FecKind::None, 2)
+                    .with_preamble(chirp);
+
+            let mut samples = vec![0.0; 333];
+            samples.extend(encoder.encode_frames(&frames, 200));
+            samples.extend(vec![0.0; 200]);
+            // Reading 1 - delay later after one leading zero delays the
+            // signal by `delay` samples
+            samples.insert(0, 0.0);
+            let delayed = fractional_shift(&samples, 1.0 - delay);
+
+            let decoded = decoder.process_samples(&delayed);
+            assert_eq!(decoded.len(), frames.len(), "delay {}", delay);
+            for (frame, got) in frames.iter().zip(&decoded) {
+                assert_eq!(got.data, frame.data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_agc_decodes_identically_at_any_gain() {
+        let spl = 3;
+        let frames: Vec<_> = (0..4u8)
+            .map(|seq| {
+                Frame::new_data(seq.into(), 1, 2, vec![seq.wrapping_mul(91); 80])
+            })
+            .collect();
+
+        for kind in [LineCodingKind::Manchester, LineCodingKind::FourBFiveB] {
+            let encoder = PhyEncoder::new(&params(spl), kind, FecKind::None);
+            let mut samples = vec![0.0; 500];
+            samples.extend(encoder.encode_frames(&frames, 300));
+            samples.extend(vec![0.0; 100 + Agc::default().latency()]);
+
+            let decode = |scale: f32| {
+                let mut decoder =
+                    PhyDecoder::new(&params(spl), kind, FecKind::None, 2)
+                        .with_agc(Agc::default());
+                let scaled: Vec<f32> = samples
+                    .iter()
+                    .map(|s| s * scale)
+                    .collect();
+                decoder.process_samples(&scaled)
+            };
+
+            let reference = decode(1.0);
+            assert_eq!(reference.len(), frames.len(), "{}", kind);
+            for scale in [0.05, 3.0] {
+                let decoded = decode(scale);
+                assert_eq!(
+                    decoded.len(),
+                    reference.len(),
+                    "{} x{}",
+                    kind,
+                    scale
+                );
+                for (a, b) in reference.iter().zip(&decoded) {
+                    assert_eq!(a.sequence, b.sequence);
+                    assert_eq!(a.data, b.data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_agc_ignores_quiet_noise() {
+        let mut decoder = PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Manchester,
+            FecKind::None,
+            2,
+        )
+        .with_agc(Agc::default());
+        let encoder = PhyEncoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Manchester,
+            FecKind::None,
+        );
+        // A frame far below the noise floor is treated as silence
+        let whisper: Vec<f32> = encoder
+            .encode_frame(&Frame::new_data(0, 1, 2, vec![1, 2, 3]))
+            .iter()
+            .map(|s| s * 0.001)
+            .collect();
+        assert!(
+            decoder
+                .process_samples(&whisper)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_dc_offset_and_drift_removed() {
+        use crate::utils::consts::SAMPLE_RATE;
+
+        let spl = 3;
+        let kind = LineCodingKind::FourBFiveB;
+        let encoder = PhyEncoder::new(&params(spl), kind, FecKind::None);
+        let frames: Vec<_> = (0..12u8)
+            .map(|seq| Frame::new_data(seq.into(), 1, 2, vec![seq ^ 0x0F; 100]))
+            .collect();
+
+        let mut samples = vec![0.0; 1000];
+        samples.extend(encoder.encode_frames(&frames, 1000));
+        samples.extend(vec![0.0; 1000]);
+        // Bias plus a slow wander large enough to push levels across zero
+        let fs = SAMPLE_RATE as f32;
+        for (n, s) in samples.iter_mut().enumerate() {
+            let t = n as f32 / fs;
+            *s += 0.2 + (2.0 * std::f32::consts::PI * 2.0 * t).sin();
+        }
+
+        let mut unfiltered =
+            PhyDecoder::new(&params(spl), kind, FecKind::None, 2)
+                .with_dc_cutoff(None);
+        assert!(
+            unfiltered
+                .process_samples(&samples)
+                .len()
+                < frames.len()
+        );
+
+        // Streamed in record-buffer sized chunks
+        let mut decoder = PhyDecoder::new(&params(spl), kind, FecKind::None, 2);
+        let decoded: Vec<_> = samples
+            .chunks(1024)
+            .flat_map(|chunk| decoder.process_samples(chunk))
+            .collect();
+        assert_eq!(decoded.len(), frames.len());
+        for (frame, got) in frames.iter().zip(&decoded) {
+            assert_eq!(got.data, frame.data);
+        }
+    }
+
+    #[test]
+    fn test_stats_count_good_and_corrupted_frames() {
+        let kind = LineCodingKind::Manchester;
+        let encoder =
+            PhyEncoder::new(&PhyParams::default(), kind, FecKind::None);
+        let mut decoder =
+            PhyDecoder::new(&PhyParams::default(), kind, FecKind::None, 2);
+        let frames: Vec<_> = (0..3u8)
+            .map(|seq| Frame::new_data(seq.into(), 1, 2, vec![seq; 40]))
+            .collect();
+        let mut samples = vec![0.0; 200];
+        samples.extend(encoder.encode_frames(&frames, 100));
+        samples.extend(vec![0.0; 200]);
+
+        assert_eq!(
+            decoder
+                .process_samples(&samples)
+                .len(),
+            3
+        );
+        let stats = decoder.stats();
+        assert_eq!(stats.preambles_detected, 3);
+        assert_eq!(stats.frames_crc_ok, 3);
+        assert_eq!(stats.frames_crc_failed, 0);
+        assert_eq!(stats.sync_losses, 0);
+        let frame_bits: usize = frames
+            .iter()
+            .map(|f| f.to_bits().len())
+            .sum();
+        assert_eq!(stats.bits_decoded, frame_bits as u64);
+        assert!(stats.last_signal_rms > 0.5);
+
+        // Flip one payload bit of the middle frame
+        decoder.reset_stats();
+        assert_eq!(decoder.stats().frames_crc_ok, 0);
+        let flip = 200
+            + encoder.frame_samples(&frames[0])
+            + 100
+            + encoder.preamble_len()
+            + (8 * PHY_HEADER_BYTES + 20) * 6;
+        for s in &mut samples[flip..flip + 6] {
+            *s = -*s;
+        }
+
+        assert_eq!(
+            decoder
+                .process_samples(&samples)
+                .len(),
+            2
+        );
+        let stats = decoder.stats();
+        assert_eq!(stats.preambles_detected, 3);
+        assert_eq!(stats.frames_crc_ok, 2);
+        assert_eq!(stats.frames_crc_failed, 1);
+    }
+
+    #[test]
+    fn test_aggregate_with_bad_subpacket_delivered() {
+        use crate::mac::aggregation::{self, SUBPACKET_HEADER_BYTES, SubPacket};
+
+        let kind = LineCodingKind::Manchester;
+        let encoder =
+            PhyEncoder::new(&PhyParams::default(), kind, FecKind::None);
+        let mut decoder =
+            PhyDecoder::new(&PhyParams::default(), kind, FecKind::None, 2);
+        let pieces: Vec<SubPacket> = (0..3u8)
+            .map(|id| SubPacket {
+                id,
+                data: vec![id; 20],
+            })
+            .collect();
+        let frame = Frame::new(
+            FrameType::Aggregate,
+            9,
+            1,
+            2,
+            aggregation::encode_aggregate(&pieces),
+        );
+        let mut samples = vec![0.0; 200];
+        samples.extend(encoder.encode_frame(&frame));
+        samples.extend(vec![0.0; 200]);
+
+        // Flip one data bit of the second sub-packet
+        let byte = PHY_HEADER_BYTES + 2 * SUBPACKET_HEADER_BYTES + 20 + 4;
+        let flip = 200 + encoder.preamble_len() + (8 * byte + 3) * 6;
+        for s in &mut samples[flip..flip + 6] {
+            *s = -*s;
+        }
+
+        let decoded = decoder.process_samples(&samples);
+        assert_eq!(decoded.len(), 1);
+        let (delivered, status) =
+            aggregation::decode_aggregate(&decoded[0].data);
+        assert_eq!(status, vec![true, false, true]);
+        assert_eq!(
+            delivered
+                .iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+        let acked = aggregation::decode_ack_bitmap(
+            &aggregation::encode_ack_bitmap(&status),
+            status.len(),
+        )
+        .unwrap();
+        assert_eq!(acked, vec![true, false, true]);
+    }
+
+    #[test]
+    fn test_broadcast_reaches_every_address() {
+        let kind = LineCodingKind::FourBFiveB;
+        let encoder =
+            PhyEncoder::new(&PhyParams::default(), kind, FecKind::None);
+        let mut samples = encoder.encode_frames(
+            &[
+                Frame::new_data(1, 1, BROADCAST, vec![0xB0; 30]),
+                Frame::new_data(2, 1, 3, vec![0x03; 30]),
+            ],
+            100,
+        );
+        samples.extend(vec![0.0; 200]);
+
+        for local in [2, 3, 7] {
+            let mut decoder = PhyDecoder::new(
+                &PhyParams::default(),
+                kind,
+                FecKind::None,
+                local,
+            );
+            let frames = decoder.process_samples(&samples);
+            assert_eq!(frames[0].dst, BROADCAST);
+            assert_eq!(frames[0].data, vec![0xB0; 30]);
+            // The unicast frame only reaches its own destination
+            assert_eq!(frames.len(), if local == 3 { 2 } else { 1 });
+        }
+    }
+}
@@ -0,0 +1,216 @@
+// Echo canceller for the receive path
+//
+//   y[n] = x[n] - g * r[n - d]
+//
+// While we transmit, the microphone picks up our own speaker. We know
+// exactly what was played (r), so that echo is a delayed, scaled copy of
+// it: a one-tap estimator finds the delay d by cross-correlation and the
+// gain g by least squares, then subtracts the copy. The recording is fed
+// in order in arbitrary chunks, the delay is tracked from one to the next.
+
+use crate::utils::consts::{ECHO_LOCK_CORRELATION, ECHO_TRACK_SAMPLES};
+
+pub struct EchoCanceller {
+    /// What was played, empty when there is nothing to cancel
+    reference: Vec<f32>,
+    /// Reference samples played before the recording started
+    lead: usize,
+    /// Samples fed since the recording started
+    position: usize,
+    max_delay: usize,
+    /// Delay and gain once the echo is found
+    estimate: Option<(usize, f32)>,
+}
+
+impl EchoCanceller {
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            reference: Vec::new(),
+            lead: 0,
+            position: 0,
+            max_delay,
+            estimate: None,
+        }
+    }
+
+    /// Cancel `played` from the recording that starts now, after `lead` of
+    /// its samples were already out
+    pub fn start(&mut self, played: &[f32], lead: usize) {
+        self.reference = played.to_vec();
+        self.lead = lead;
+        self.position = 0;
+        self.estimate = None;
+    }
+
+    pub fn stop(&mut self) {
+        self.reference.clear();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.reference.is_empty()
+    }
+
+    /// Delay (samples) and gain of the last echo, once found
+    pub fn estimate(&self) -> Option<(usize, f32)> {
+        self.estimate
+    }
+
+    /// The next recorded `samples`, minus our echo
+    pub fn cancel(&mut self, samples: &[f32]) -> Vec<f32> {
+        let mut cleaned = samples.to_vec();
+        if !self.is_active() {
+            return cleaned;
+        }
+        // Reference index of samples[0], before the delay
+        let first = self.lead + self.position;
+        self.position += samples.len();
+
+        self.track(first, samples);
+        if let Some((delay, gain)) = self.estimate {
+            for (i, sample) in cleaned.iter_mut().enumerate() {
+                *sample -= gain * self.reference_at(first + i, delay);
+            }
+        }
+
+        if first + samples.len() >= self.reference.len() + self.max_delay {
+            // Even the longest echo is over
+            self.stop();
+        }
+        cleaned
+    }
+
+    /// Search the delay whose copy best explains `samples`, all of them
+    /// until the echo is found, around it afterwards
+    fn track(&mut self, first: usize, samples: &[f32]) {
+        let recorded: f32 = samples
+            .iter()
+            .map(|s| s * s)
+            .sum();
+        if recorded <= 0.0 {
+            return;
+        }
+        let delays = match self.estimate {
+            Some((delay, _)) => {
+                delay.saturating_sub(ECHO_TRACK_SAMPLES)
+                    ..=(delay + ECHO_TRACK_SAMPLES).min(self.max_delay)
+            }
+            None => 0..=self.max_delay,
+        };
+
+        let mut best: Option<(usize, f32, f32)> = None;
+        for delay in delays {
+            let (mut correlation, mut energy) = (0.0f32, 0.0f32);
+            for (i, sample) in samples.iter().enumerate() {
+                let reference = self.reference_at(first + i, delay);
+                correlation += sample * reference;
+                energy += reference * reference;
+            }
+            if energy <= 0.0 {
+                continue;
+            }
+            // Energy of `samples` the scaled copy accounts for
+            let explained = correlation * correlation / energy;
+            if best.is_none_or(|(_, best, _)| explained > best) {
+                best = Some((delay, explained, correlation / energy));
+            }
+        }
+
+        if let Some((delay, explained, gain)) = best
+            && explained >= ECHO_LOCK_CORRELATION * recorded
+        {
+            self.estimate = Some((delay, gain));
+        }
+    }
+
+    /// Reference sample heard at reference index `index` after `delay`
+    fn reference_at(&self, index: usize, delay: usize) -> f32 {
+        index
+            .checked_sub(delay)
+            .and_then(|k| self.reference.get(k))
+            .copied()
+            .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// +-1 levels without a period
+    fn levels(len: u32) -> Vec<f32> {
+        (0..len)
+            .map(|n| {
+                if n.wrapping_mul(2654435761) >> 31 == 1 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_delayed_echo_removed_and_chunking_irrelevant() {
+        let played = levels(4800);
+        let other: Vec<f32> = (0..6000)
+            .map(|n| 0.1 * (n as f32 * 0.05).sin())
+            .collect();
+        // Half the amplitude (-6 dB), 300 samples late, over another signal
+        let recorded: Vec<f32> = (0..6000usize)
+            .map(|n| {
+                let echo = n
+                    .checked_sub(300)
+                    .and_then(|k| played.get(k))
+                    .map_or(0.0, |s| 0.5 * s);
+                echo + other[n]
+            })
+            .collect();
+
+        let mut canceller = EchoCanceller::new(1000);
+        canceller.start(&played, 0);
+        let cleaned: Vec<f32> = recorded
+            .chunks(480)
+            .flat_map(|chunk| canceller.cancel(chunk))
+            .collect();
+
+        let (delay, gain) = canceller.estimate().unwrap();
+        assert_eq!(delay, 300);
+        assert!((gain - 0.5).abs() < 0.02, "gain {}", gain);
+        let residual = cleaned
+            .iter()
+            .zip(&other)
+            .map(|(c, o)| (c - o).abs())
+            .fold(0.0, f32::max);
+        assert!(residual < 0.05, "residual {}", residual);
+    }
+
+    #[test]
+    fn test_recording_started_after_playback() {
+        let played = levels(960);
+        // Half duplex: the recording resumes as playback ends and picks up
+        // the last 100 samples of a 100 samples late echo
+        let recorded: Vec<f32> = played[played.len() - 100..]
+            .iter()
+            .map(|s| 0.5 * s)
+            .chain(std::iter::repeat_n(0.0, 500))
+            .collect();
+
+        let mut canceller = EchoCanceller::new(400);
+        canceller.start(&played, played.len());
+        let cleaned = canceller.cancel(&recorded);
+
+        assert_eq!(
+            canceller
+                .estimate()
+                .map(|(delay, _)| delay),
+            Some(100)
+        );
+        assert!(
+            cleaned
+                .iter()
+                .all(|s| s.abs() < 1e-4)
+        );
+        // Past the longest delay there is nothing left to cancel
+        assert!(!canceller.is_active());
+    }
+}
@@ -1,30 +1,42 @@
+use super::fec::{self, FecKind};
 use super::frame::Frame;
 use super::line_coding::{LineCode, LineCodingKind};
+use super::params::PhyParams;
+use super::preamble::{self, PreambleKind};
+use super::rate;
+use crate::utils::consts::PHY_HEADER_BYTES;
 use tracing::{debug, info};
 
 pub struct PhyEncoder {
     line_code: Box<dyn LineCode>,
+    line_coding_kind: LineCodingKind,
+    fec: FecKind,
     preamble: Vec<f32>,
+    preamble_bytes: usize,
 }
 
 impl PhyEncoder {
     /// Create a new physical layer encoder
     ///
     /// # Arguments
-    /// * `samples_per_level` - Number of samples per Manchester level (not per bit)
+    /// * `params` - Samples per level (per Manchester level, not per bit)
+    ///   and preamble length, see PhyParams
     ///   For example, with 48000 Hz sample rate and 12000 bps bit rate:
     ///   - 对于曼彻斯特编码：samples_per_level = samples_per_level
     ///   - 对于 4B5B 编码：samples_per_level = 每个编码比特的采样数
     pub fn new(
-        samples_per_level: usize,
-        preamble_bytes: usize,
+        params: &PhyParams,
         line_coding_kind: LineCodingKind,
+        fec: FecKind,
     ) -> Self {
+        let samples_per_level = params.samples_per_level;
+        let preamble_bytes = params.preamble_pattern_bytes;
         let line_code = line_coding_kind.create(samples_per_level);
         let preamble = line_code.generate_preamble(preamble_bytes);
 
         info!("PhyEncoder initialized:");
         info!("  - line coding: {}", line_coding_kind.name());
+        info!("  - FEC: {}", fec.name());
         info!("  - samples_per_level: {}", samples_per_level);
         info!(
             "  - preamble length: {} samples ({} bytes pattern)",
@@ -34,17 +46,52 @@ impl PhyEncoder {
 
         Self {
             line_code,
+            line_coding_kind,
+            fec,
             preamble,
+            preamble_bytes,
         }
     }
 
+    /// Send a different preamble ahead of each frame
+    pub fn with_preamble(mut self, kind: PreambleKind) -> Self {
+        self.preamble = match kind {
+            PreambleKind::BytePattern => self
+                .line_code
+                .generate_preamble(self.preamble_bytes),
+            PreambleKind::Chirp { f0, f1, len } => {
+                preamble::generate_chirp(f0, f1, len)
+            }
+        };
+        info!(
+            "  - preamble: {} ({} samples)",
+            kind.name(),
+            self.preamble.len()
+        );
+        self
+    }
+
     /// Encode a frame into audio samples
     /// Returns: [Preamble] [Frame Data]
     pub fn encode_frame(&self, frame: &Frame) -> Vec<f32> {
-        let frame_bits = frame.to_bits();
-        let frame_samples = self
-            .line_code
-            .encode(&frame_bits);
+        let frame_bits = self.coded_bits(frame);
+        let frame_samples =
+            match rate::body_line_code(self.line_coding_kind, frame.rate) {
+                // Header at the base rate, body at the frame's rate
+                Some(body_code) => {
+                    let header_len = self
+                        .fec
+                        .coded_len(8 * PHY_HEADER_BYTES);
+                    let mut samples = self
+                        .line_code
+                        .encode(&frame_bits[..header_len]);
+                    samples.extend(body_code.encode(&frame_bits[header_len..]));
+                    samples
+                }
+                None => self
+                    .line_code
+                    .encode(&frame_bits),
+            };
 
         debug!(
             "Encoding frame: seq={}, data_len={}, total_bits={}, total_samples={}",
@@ -62,6 +109,28 @@ impl PhyEncoder {
         output
     }
 
+    /// Frame bits after the per-frame convolutional code, the FEC stage and
+    /// body interleaving
+    fn coded_bits(&self, frame: &Frame) -> Vec<u8> {
+        let mut bits = frame.to_bits();
+        if frame.conv_coded {
+            let body = fec::conv_encode(&bits[8 * PHY_HEADER_BYTES..]);
+            bits.truncate(8 * PHY_HEADER_BYTES);
+            bits.extend(body);
+        }
+
+        let mut coded = self.fec.encode(&bits);
+        if let Some(interleaver) = frame.interleave {
+            let header_len = self
+                .fec
+                .coded_len(8 * PHY_HEADER_BYTES);
+            let body = interleaver.interleave(&coded[header_len..]);
+            coded.truncate(header_len);
+            coded.extend(body);
+        }
+        coded
+    }
+
     /// Encode multiple frames with inter-frame gaps
     ///
     /// # Arguments
@@ -91,10 +160,54 @@ impl PhyEncoder {
         output
     }
 
+    /// Like `encode_frames`, but lazily yields one chunk per frame (the
+    /// frame followed by its gap) so long transfers never hold the whole
+    /// track in memory
+    pub fn encode_frames_iter<'a>(
+        &'a self,
+        frames: &'a [Frame],
+        inter_frame_gap_samples: usize,
+    ) -> impl Iterator<Item = Vec<f32>> + 'a {
+        let last = frames.len().saturating_sub(1);
+        frames
+            .iter()
+            .enumerate()
+            .map(move |(i, frame)| {
+                let mut samples = self.encode_frame(frame);
+                if i < last {
+                    samples.resize(samples.len() + inter_frame_gap_samples, 0.0);
+                }
+                samples
+            })
+    }
+
     /// Get preamble length in samples
     pub fn preamble_len(&self) -> usize {
         self.preamble.len()
     }
+
+    /// Number of samples `encode_frame` produces for this frame
+    pub fn frame_samples(&self, frame: &Frame) -> usize {
+        let coded_len = self.coded_bits(frame).len();
+        match rate::body_line_code(self.line_coding_kind, frame.rate) {
+            Some(body_code) => {
+                let header_len = self
+                    .fec
+                    .coded_len(8 * PHY_HEADER_BYTES);
+                self.preamble.len()
+                    + self
+                        .line_code
+                        .samples_for_bits(header_len)
+                    + body_code.samples_for_bits(coded_len - header_len)
+            }
+            None => {
+                self.preamble.len()
+                    + self
+                        .line_code
+                        .samples_for_bits(coded_len)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -102,9 +215,20 @@ mod tests {
     use super::*;
     use crate::phy::line_coding::LineCodingKind;
 
+    fn params(samples_per_level: usize) -> PhyParams {
+        PhyParams {
+            samples_per_level,
+            ..PhyParams::default()
+        }
+    }
+
     #[test]
     fn test_encoder() {
-        let encoder = PhyEncoder::new(2, 2, LineCodingKind::FourBFiveB);
+        let encoder = PhyEncoder::new(
+            &params(2),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+        );
         let frame = Frame::new_data(1, 0, 1, vec![0x12, 0x34, 0x56]);
         let samples = encoder.encode_frame(&frame);
 
@@ -114,7 +238,11 @@ mod tests {
 
     #[test]
     fn test_multiple_frames() {
-        let encoder = PhyEncoder::new(2, 2, LineCodingKind::FourBFiveB);
+        let encoder = PhyEncoder::new(
+            &params(2),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+        );
         let frames = vec![
             Frame::new_data(0, 0, 1, vec![0x01, 0x02]),
             Frame::new_data(1, 0, 1, vec![0x03, 0x04]),
@@ -125,4 +253,40 @@ mod tests {
         // Should have content
         assert!(samples.len() > 0);
     }
+
+    #[test]
+    fn test_streaming_matches_encode_frames() {
+        let encoder = PhyEncoder::new(
+            &PhyParams::default(),
+            LineCodingKind::Manchester,
+            FecKind::None,
+        );
+        let frames: Vec<_> = (0..5u8)
+            .map(|seq| {
+                Frame::new_data(
+                    seq.into(),
+                    0,
+                    1,
+                    vec![seq; 10 + 20 * seq as usize],
+                )
+            })
+            .collect();
+
+        let whole = encoder.encode_frames(&frames, 100);
+        let chunks: Vec<_> = encoder
+            .encode_frames_iter(&frames, 100)
+            .collect();
+        assert_eq!(chunks.len(), frames.len());
+
+        let streamed: Vec<u32> = chunks
+            .iter()
+            .flatten()
+            .map(|s| s.to_bits())
+            .collect();
+        let expected: Vec<u32> = whole
+            .iter()
+            .map(|s| s.to_bits())
+            .collect();
+        assert_eq!(streamed, expected);
+    }
 }
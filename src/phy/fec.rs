@@ -0,0 +1,252 @@
+// Forward error correction between framing and line coding
+//
+// Hamming(7,4): every nibble d1..d4 becomes [p1 p2 d1 p3 d2 d3 d4] with
+//   p1 = d1^d2^d4, p2 = d1^d3^d4, p3 = d2^d3^d4
+// The syndrome gives the (1-based) position of a single flipped bit.
+// FEC is not signalled on the air, both ends must be configured alike.
+//
+// Convolutional code: rate 1/2, K = 7, generators 171 / 133 (octal), with
+// K-1 zero tail bits so the trellis ends in state 0. Unlike FecKind it is
+// switched on per frame by a header flag and only covers the frame body.
+// Decoding is a full 64-state Viterbi over soft values (> 0 means 1);
+// hard bits are fed in as +/-1.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FecKind {
+    #[default]
+    None,
+    Hamming74,
+}
+
+impl FecKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FecKind::None => "none",
+            FecKind::Hamming74 => "hamming74",
+        }
+    }
+
+    /// The kind `name` (or a common spelling of it) stands for, ignoring
+    /// case
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "none" => Some(FecKind::None),
+            "hamming" | "hamming74" => Some(FecKind::Hamming74),
+            _ => None,
+        }
+    }
+
+    /// Number of coded bits for `num_bits` data bits
+    pub fn coded_len(self, num_bits: usize) -> usize {
+        match self {
+            FecKind::None => num_bits,
+            FecKind::Hamming74 => num_bits.div_ceil(4) * 7,
+        }
+    }
+
+    pub fn encode(self, bits: &[u8]) -> Vec<u8> {
+        match self {
+            FecKind::None => bits.to_vec(),
+            FecKind::Hamming74 => {
+                let mut coded = Vec::with_capacity(self.coded_len(bits.len()));
+                for chunk in bits.chunks(4) {
+                    let mut d = [0u8; 4];
+                    d[..chunk.len()].copy_from_slice(chunk);
+                    let [d1, d2, d3, d4] = d;
+                    coded.extend_from_slice(&[
+                        d1 ^ d2 ^ d4,
+                        d1 ^ d3 ^ d4,
+                        d1,
+                        d2 ^ d3 ^ d4,
+                        d2,
+                        d3,
+                        d4,
+                    ]);
+                }
+                coded
+            }
+        }
+    }
+
+    /// Decode coded bits, correcting one flipped bit per codeword.
+    /// A trailing partial codeword is dropped.
+    pub fn decode(self, coded: &[u8]) -> Vec<u8> {
+        match self {
+            FecKind::None => coded.to_vec(),
+            FecKind::Hamming74 => {
+                let mut bits = Vec::with_capacity(coded.len() / 7 * 4);
+                for word in coded.chunks_exact(7) {
+                    let mut c = [0u8; 7];
+                    c.copy_from_slice(word);
+                    let syndrome = (c[0] ^ c[2] ^ c[4] ^ c[6])
+                        | (c[1] ^ c[2] ^ c[5] ^ c[6]) << 1
+                        | (c[3] ^ c[4] ^ c[5] ^ c[6]) << 2;
+                    if syndrome != 0 {
+                        c[syndrome as usize - 1] ^= 1;
+                    }
+                    bits.extend_from_slice(&[c[2], c[4], c[5], c[6]]);
+                }
+                bits
+            }
+        }
+    }
+}
+
+impl fmt::Display for FecKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub const CONV_CONSTRAINT_LEN: usize = 7;
+const CONV_TAIL_BITS: usize = CONV_CONSTRAINT_LEN - 1;
+const CONV_STATES: usize = 1 << CONV_TAIL_BITS;
+const CONV_G0: u8 = 0o171;
+const CONV_G1: u8 = 0o133;
+
+/// Encoder outputs for `state` (last 6 inputs, newest at bit 5) and `bit`
+fn conv_outputs(state: usize, bit: u8) -> (u8, u8) {
+    let reg = (bit << CONV_TAIL_BITS) | state as u8;
+    (
+        (reg & CONV_G0).count_ones() as u8 & 1,
+        (reg & CONV_G1).count_ones() as u8 & 1,
+    )
+}
+
+/// Coded bits for `num_bits` data bits, tail included
+pub fn conv_coded_len(num_bits: usize) -> usize {
+    2 * (num_bits + CONV_TAIL_BITS)
+}
+
+pub fn conv_encode(bits: &[u8]) -> Vec<u8> {
+    let mut coded = Vec::with_capacity(conv_coded_len(bits.len()));
+    let mut state = 0usize;
+    for &bit in bits
+        .iter()
+        .chain([0u8; CONV_TAIL_BITS].iter())
+    {
+        let bit = bit & 1;
+        let (o0, o1) = conv_outputs(state, bit);
+        coded.push(o0);
+        coded.push(o1);
+        state = ((bit as usize) << (CONV_TAIL_BITS - 1)) | (state >> 1);
+    }
+    coded
+}
+
+/// Viterbi decoding of hard bits
+pub fn viterbi_decode_hard(coded: &[u8]) -> Vec<u8> {
+    let soft: Vec<f32> = coded
+        .iter()
+        .map(|&b| if b != 0 { 1.0 } else { -1.0 })
+        .collect();
+    viterbi_decode_soft(&soft)
+}
+
+/// Viterbi decoding of soft values (sign = bit, magnitude = confidence).
+/// Returns the data bits without the tail.
+pub fn viterbi_decode_soft(soft: &[f32]) -> Vec<u8> {
+    let steps = soft.len() / 2;
+    if steps < CONV_TAIL_BITS {
+        return Vec::new();
+    }
+
+    let mut metrics = [f32::NEG_INFINITY; CONV_STATES];
+    metrics[0] = 0.0;
+    // decisions[t][state]: low bit of the predecessor state
+    let mut decisions = vec![[0u8; CONV_STATES]; steps];
+
+    for (t, pair) in soft
+        .chunks_exact(2)
+        .enumerate()
+    {
+        let mut next = [f32::NEG_INFINITY; CONV_STATES];
+        for (state, metric) in next.iter_mut().enumerate() {
+            let bit = (state >> (CONV_TAIL_BITS - 1)) as u8;
+            for low in 0..2 {
+                let prev = ((state << 1) & (CONV_STATES - 1)) | low;
+                if metrics[prev] == f32::NEG_INFINITY {
+                    continue;
+                }
+                let (o0, o1) = conv_outputs(prev, bit);
+                let branch = if o0 == 1 { pair[0] } else { -pair[0] }
+                    + if o1 == 1 { pair[1] } else { -pair[1] };
+                let candidate = metrics[prev] + branch;
+                if candidate > *metric {
+                    *metric = candidate;
+                    decisions[t][state] = low as u8;
+                }
+            }
+        }
+        metrics = next;
+    }
+
+    // The tail forces the path back to state 0
+    let mut state = 0usize;
+    let mut bits = Vec::with_capacity(steps);
+    for t in (0..steps).rev() {
+        bits.push((state >> (CONV_TAIL_BITS - 1)) as u8);
+        state =
+            ((state << 1) & (CONV_STATES - 1)) | decisions[t][state] as usize;
+    }
+    bits.reverse();
+    bits.truncate(steps - CONV_TAIL_BITS);
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hamming74_corrects_every_single_error() {
+        for nibble in 0..16u8 {
+            let bits: Vec<u8> = (0..4)
+                .map(|j| (nibble >> (3 - j)) & 1)
+                .collect();
+            let coded = FecKind::Hamming74.encode(&bits);
+            assert_eq!(coded.len(), 7);
+            assert_eq!(FecKind::Hamming74.decode(&coded), bits);
+
+            for pos in 0..7 {
+                let mut corrupted = coded.clone();
+                corrupted[pos] ^= 1;
+                assert_eq!(FecKind::Hamming74.decode(&corrupted), bits);
+            }
+        }
+    }
+
+    #[test]
+    fn test_conv_roundtrip_and_hard_correction() {
+        let bits: Vec<u8> = (0..200u32)
+            .map(|i| ((i * 7 + i / 3) % 2) as u8)
+            .collect();
+        let coded = conv_encode(&bits);
+        assert_eq!(coded.len(), conv_coded_len(bits.len()));
+        assert_eq!(viterbi_decode_hard(&coded), bits);
+
+        // Scattered errors, well within the free distance of 10
+        let mut corrupted = coded.clone();
+        for pos in (5..corrupted.len()).step_by(40) {
+            corrupted[pos] ^= 1;
+            corrupted[pos + 1] ^= 1;
+        }
+        assert_eq!(viterbi_decode_hard(&corrupted), bits);
+    }
+
+    #[test]
+    fn test_soft_decisions_use_confidence() {
+        let bits = vec![1, 0, 1, 1, 0, 0, 1, 0, 1, 1, 1, 0];
+        let mut soft: Vec<f32> = conv_encode(&bits)
+            .iter()
+            .map(|&b| if b == 1 { 1.0 } else { -1.0 })
+            .collect();
+        // Weakly wrong values are outvoted by confident neighbours
+        for i in [2, 3, 9, 14, 15] {
+            soft[i] *= -0.2;
+        }
+        assert_eq!(viterbi_decode_soft(&soft), bits);
+    }
+}
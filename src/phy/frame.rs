@@ -1,26 +1,70 @@
-// Frame format: [Preamble] [Frame Type] [Sequence] [Length] [Data] [CRC8]
-
-use crate::utils::consts::PHY_HEADER_BYTES;
-
-use super::crc::{bits_to_bytes, bytes_to_bits, calculate_crc8, verify_crc8};
+// Frame format: [Preamble] [Length] [CRC8] [Frame Type] [Sequence:2] [Src] [Dst] [Hops] [Data] [CRC32]
+//
+// The Frame Type byte carries the checksum kind in bit 6:
+//   0 = CRC8 in the header
+//   1 = CRC32 trailer over header and data, header CRC8 byte is 0
+// bit 5 flags a convolutionally coded body (see phy::fec) and bit 4 is the
+// version bit: set for this layout with a 16-bit sequence number. Frames of
+// the original 1-byte sequence layout lack it and are rejected.
+// The Hops byte keeps the hop count in its low 3 bits and the interleaver
+// code (see phy::interleaver) in the upper 5; old frames read as "off".
+// The byte came with mac::relay and made the header a byte longer than
+// the first layout: that layout also lacks the version bit, so its frames
+// are rejected rather than misread.
+// Bit 3 of the Frame Type byte flags a piggybacked ACK: the body then starts
+// with the acknowledged sequence [AckSeq:2], counted in Length and covered
+// by the checksum, and the frame type is the low 3 bits.
+// Bit 7 flags a NAV: the body then starts with [Nav:2], the milliseconds
+// the channel stays reserved after this frame ends (rest of the exchange,
+// see mac::nav), ahead of any [AckSeq:2]. Frames without it read as "no
+// NAV", so older peers interoperate. RTS and CTS always carry one.
+// The top 2 bits of Length select the body rate (see phy::rate), 0 keeps
+// the link's samples per level as older frames do. The bit below them flags
+// timestamps: the body then carries [Queued:4] [Sent:4] after any NAV, the
+// sender's wall clock in milliseconds when the frame was queued and when
+// its playback started (see mac::delay). Length keeps 13 bits.
+// An aggregate's CRC8 covers the header (bar the CRC byte) and any NAV,
+// timestamps and AckSeq only: its sub-packets carry their own CRC8s (see
+// mac::aggregation), so one bad sub-packet doesn't lose the rest.
+
+use crate::mac::types::BROADCAST;
+use crate::utils::consts::{CRC32_MIN_PAYLOAD_BYTES, PHY_HEADER_BYTES};
+
+use super::crc::{
+    bits_to_bytes, bytes_to_bits, calculate_crc8, calculate_crc32, verify_crc8,
+    verify_crc32,
+};
+use super::interleaver::Interleaver;
+use super::rate::{MAX_RATE_CODE, RateCode};
 use tracing::debug;
 
 pub type CRCType = u8;
-pub type SeqType = u8;
+pub type SeqType = u16;
 pub type LenType = usize;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FrameType {
+    Probe = 0x00, // Rate probe, see mac::autorate
     Data = 0x01,
     Ack = 0x02,
-    // Reserved for future use
+    Aggregate = 0x03, // Sub-packets with their own CRC, see mac::aggregation
+    Nack = 0x04,      // Missing sequence bitmap, see mac::arq
+    Rts = 0x05,       // Request to send, with a reservation duration
+    Cts = 0x06,       // Clear to send, with the remaining reservation
+    Beacon = 0x07,    // Neighbor announcement, see mac::discovery
 }
 
 impl FrameType {
     pub fn from_u8(value: u8) -> Option<Self> {
         match value {
+            0x00 => Some(FrameType::Probe),
             0x01 => Some(FrameType::Data),
             0x02 => Some(FrameType::Ack),
+            0x03 => Some(FrameType::Aggregate),
+            0x04 => Some(FrameType::Nack),
+            0x05 => Some(FrameType::Rts),
+            0x06 => Some(FrameType::Cts),
+            0x07 => Some(FrameType::Beacon),
             _ => None,
         }
     }
@@ -30,20 +74,138 @@ impl FrameType {
     }
 }
 
+const NAV_FLAG: u8 = 1 << 7;
+const CHECKSUM_SHIFT: u8 = 6;
+const CONV_FLAG: u8 = 1 << 5;
+const VERSION_FLAG: u8 = 1 << 4;
+const PIGGYBACK_FLAG: u8 = 1 << 3;
+const FRAME_TYPE_MASK: u8 = PIGGYBACK_FLAG - 1;
+const INTERLEAVE_SHIFT: u8 = 3;
+const HOPS_MASK: u8 = (1 << INTERLEAVE_SHIFT) - 1;
+const RATE_SHIFT: u8 = 6;
+const TIMESTAMP_FLAG: u8 = 1 << 5;
+const LEN_HIGH_MASK: u8 = TIMESTAMP_FLAG - 1;
+
+/// Which checksum protects a frame (1-bit header field)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    Crc8 = 0,
+    Crc32 = 1,
+}
+
+impl ChecksumKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ChecksumKind::Crc8),
+            1 => Some(ChecksumKind::Crc32),
+            _ => None,
+        }
+    }
+
+    /// Checksum used by default for a new frame
+    pub fn for_frame(frame_type: FrameType, data_len: usize) -> Self {
+        // Aggregates are checked per sub-packet by the MAC
+        if frame_type != FrameType::Aggregate
+            && data_len > CRC32_MIN_PAYLOAD_BYTES
+        {
+            ChecksumKind::Crc32
+        } else {
+            ChecksumKind::Crc8
+        }
+    }
+
+    /// Bytes appended after the data
+    pub fn trailer_len(self) -> usize {
+        match self {
+            ChecksumKind::Crc8 => 0,
+            ChecksumKind::Crc32 => 4,
+        }
+    }
+}
+
+/// Sender's wall clock (milliseconds, wrapping) along a frame's way out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTimestamp {
+    /// When the frame was queued
+    pub queued_ms: u32,
+    /// When its playback started, the last time it was sent
+    pub sent_ms: u32,
+}
+
+/// Parsed PHY header fields
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub len: LenType,
+    pub crc: CRCType,
+    pub frame_type: FrameType,
+    pub checksum: ChecksumKind,
+    /// Body (data + trailer) is convolutionally coded
+    pub conv_coded: bool,
+    /// Body is interleaved with this block size
+    pub interleave: Option<Interleaver>,
+    /// Body starts with a NAV duration
+    pub nav: bool,
+    /// Body carries timestamps (after the NAV)
+    pub timestamp: bool,
+    /// Body starts with a piggybacked ACK sequence (after the NAV and
+    /// timestamps)
+    pub piggyback: bool,
+    /// Body rate code, 0 = the link's base rate
+    pub rate: RateCode,
+    pub sequence: SeqType,
+    pub src: u8,
+    pub dst: u8,
+    pub hops: u8,
+}
+
+impl FrameHeader {
+    /// Bytes following the header: data and checksum trailer
+    pub fn body_len(&self) -> usize {
+        self.len + self.checksum.trailer_len()
+    }
+
+    /// NAV, timestamp and AckSeq bytes ahead of the data
+    fn prefix_len(&self) -> usize {
+        2 * self.nav as usize
+            + 8 * self.timestamp as usize
+            + 2 * self.piggyback as usize
+    }
+}
+
+/// CRC8 of an aggregate: the header without its CRC byte, then the body
+/// fields ahead of the sub-packets
+fn aggregate_crc8(header: &[u8], prefix: &[u8]) -> CRCType {
+    let covered: Vec<u8> = header[..2]
+        .iter()
+        .chain(&header[3..PHY_HEADER_BYTES])
+        .chain(prefix)
+        .copied()
+        .collect();
+    calculate_crc8(&covered)
+}
+
 /// PHY Frame structure
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub frame_type: FrameType,
-    pub sequence: u8,  // Sequence number for ordering and ACK
-    pub src: u8,       // Source address
-    pub dst: u8,       // Destination address
-    pub data: Vec<u8>, // Payload data
+    pub sequence: SeqType, // Sequence number for ordering and ACK
+    pub src: u8,           // Source address
+    pub dst: u8,           // Destination address
+    pub hops: u8,          // Times this frame has been relayed
+    pub checksum: ChecksumKind,
+    pub conv_coded: bool, // Body sent with the convolutional code
+    pub interleave: Option<Interleaver>, // Body interleaving on the air
+    pub piggyback_ack: Option<SeqType>, // ACK riding along with this frame
+    pub nav_ms: Option<u16>, // Channel reserved this long after the frame
+    pub timestamp: Option<FrameTimestamp>, // Queued / sent, see mac::delay
+    pub rate: RateCode,   // Body rate, see phy::rate
+    pub data: Vec<u8>,    // Payload data
 }
 
 impl Frame {
     pub fn new(
         frame_type: FrameType,
-        sequence: u8,
+        sequence: SeqType,
         src: u8,
         dst: u8,
         data: Vec<u8>,
@@ -53,41 +215,153 @@ impl Frame {
             sequence,
             src,
             dst,
+            hops: 0,
+            checksum: ChecksumKind::for_frame(frame_type, data.len()),
+            conv_coded: false,
+            interleave: None,
+            piggyback_ack: None,
+            nav_ms: None,
+            timestamp: None,
+            rate: 0,
             data,
         }
     }
 
-    pub fn new_data(sequence: u8, src: u8, dst: u8, data: Vec<u8>) -> Self {
+    pub fn new_data(sequence: SeqType, src: u8, dst: u8, data: Vec<u8>) -> Self {
         Self::new(FrameType::Data, sequence, src, dst, data)
     }
 
-    pub fn new_ack(sequence: u8, from: u8, to: u8) -> Self {
+    pub fn new_ack(sequence: SeqType, from: u8, to: u8) -> Self {
         Self::new(FrameType::Ack, sequence, from, to, Vec::new())
     }
 
-    pub fn new_ack_mix(sequence: u8, from: u8, to: u8, data: Vec<u8>) -> Self {
+    pub fn new_ack_mix(
+        sequence: SeqType,
+        from: u8,
+        to: u8,
+        data: Vec<u8>,
+    ) -> Self {
         Self::new(FrameType::Ack, sequence, from, to, data)
     }
 
+    /// NACK starting at `first_missing`, `bitmap` from
+    /// mac::arq::encode_nack_bitmap
+    pub fn new_nack(
+        first_missing: SeqType,
+        from: u8,
+        to: u8,
+        bitmap: Vec<u8>,
+    ) -> Self {
+        Self::new(FrameType::Nack, first_missing, from, to, bitmap)
+    }
+
+    /// RTS reserving the channel for `duration_ms` after it ends
+    pub fn new_rts(
+        sequence: SeqType,
+        from: u8,
+        to: u8,
+        duration_ms: u16,
+    ) -> Self {
+        let mut frame =
+            Self::new(FrameType::Rts, sequence, from, to, Vec::new());
+        frame.nav_ms = Some(duration_ms);
+        frame
+    }
+
+    /// CTS answering the RTS `sequence`, `duration_ms` still reserved
+    pub fn new_cts(
+        sequence: SeqType,
+        from: u8,
+        to: u8,
+        duration_ms: u16,
+    ) -> Self {
+        let mut frame =
+            Self::new(FrameType::Cts, sequence, from, to, Vec::new());
+        frame.nav_ms = Some(duration_ms);
+        frame
+    }
+
+    /// Broadcast beacon, `payload` from mac::discovery::Beacon::to_bytes
+    pub fn new_beacon(sequence: SeqType, from: u8, payload: Vec<u8>) -> Self {
+        Self::new(FrameType::Beacon, sequence, from, BROADCAST, payload)
+    }
+
+    /// Rate probe `index` of a burst, body sent at `rate`
+    pub fn new_probe(
+        index: SeqType,
+        from: u8,
+        to: u8,
+        rate: RateCode,
+        payload: Vec<u8>,
+    ) -> Self {
+        let mut frame = Self::new(FrameType::Probe, index, from, to, payload);
+        frame.rate = rate;
+        frame
+    }
+
     /// Serialize frame to bytes (without preamble)
-    /// Format: [Len:2] [CRC:1] [Type:1] [Seq:1] [Src:1] [Dst:1] [Data:N]
+    /// Format: [Len:2] [CRC:1] [Type:1] [Seq:2] [Src:1] [Dst:1] [Hops:1] [Nav:0/2] [Queued:0/4] [Sent:0/4] [AckSeq:0/2] [Data:N] [CRC32:0/4]
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
-        // Data length (2 bytes, big-endian)
-        let len = self.data.len() as LenType;
-        bytes.push((len >> 8) as u8);
+        let mut body = Vec::with_capacity(self.data.len() + 4);
+        if let Some(nav) = self.nav_ms {
+            body.extend_from_slice(&nav.to_be_bytes());
+        }
+        if let Some(stamp) = self.timestamp {
+            body.extend_from_slice(&stamp.queued_ms.to_be_bytes());
+            body.extend_from_slice(&stamp.sent_ms.to_be_bytes());
+        }
+        if let Some(ack) = self.piggyback_ack {
+            body.extend_from_slice(&ack.to_be_bytes());
+        }
+        let prefix_len = body.len();
+        body.extend_from_slice(&self.data);
+
+        // Rate code (2 bits) + timestamp flag + body length (13 bits,
+        // big-endian)
+        let len = body.len() as LenType;
+        let timestamp_flag = if self.timestamp.is_some() {
+            TIMESTAMP_FLAG
+        } else {
+            0
+        };
+        bytes.push(
+            self.rate << RATE_SHIFT
+                | timestamp_flag
+                | (len >> 8) as u8 & LEN_HIGH_MASK,
+        );
         bytes.push((len & 0xFF) as u8);
 
-        // CRC8
-        let crc = calculate_crc8(&self.data);
+        // CRC8, an aggregate's is filled in once the header is complete
+        let crc = match self.checksum {
+            ChecksumKind::Crc8 if self.frame_type != FrameType::Aggregate => {
+                calculate_crc8(&body)
+            }
+            _ => 0,
+        };
         bytes.push(crc);
 
-        // Frame type (1 byte)
-        bytes.push(self.frame_type.to_u8());
-
-        // Sequence number (1 byte)
-        bytes.push(self.sequence);
+        // NAV flag + checksum kind + conv flag + version flag + piggyback
+        // flag + frame type (3 bits)
+        let nav_flag = if self.nav_ms.is_some() { NAV_FLAG } else { 0 };
+        let conv_flag = if self.conv_coded { CONV_FLAG } else { 0 };
+        let piggyback_flag = if self.piggyback_ack.is_some() {
+            PIGGYBACK_FLAG
+        } else {
+            0
+        };
+        bytes.push(
+            nav_flag
+                | (self.checksum as u8) << CHECKSUM_SHIFT
+                | conv_flag
+                | VERSION_FLAG
+                | piggyback_flag
+                | self.frame_type.to_u8(),
+        );
+
+        // Sequence number (2 bytes, big-endian)
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
 
         // Source address (1 byte)
         bytes.push(self.src);
@@ -95,8 +369,26 @@ impl Frame {
         // Destination address (1 byte)
         bytes.push(self.dst);
 
-        // Data
-        bytes.extend_from_slice(&self.data);
+        // Interleaver code (5 bits) + hop count (3 bits)
+        bytes.push(
+            Interleaver::header_code(self.interleave) << INTERLEAVE_SHIFT
+                | self.hops & HOPS_MASK,
+        );
+
+        if self.frame_type == FrameType::Aggregate
+            && self.checksum == ChecksumKind::Crc8
+        {
+            bytes[2] = aggregate_crc8(&bytes, &body[..prefix_len]);
+        }
+
+        // NAV, piggybacked ACK and data
+        bytes.extend(body);
+
+        // CRC32 over header and data (4 bytes, big-endian)
+        if self.checksum == ChecksumKind::Crc32 {
+            let crc = calculate_crc32(&bytes);
+            bytes.extend_from_slice(&crc.to_be_bytes());
+        }
 
         bytes
     }
@@ -106,73 +398,170 @@ impl Frame {
         bytes_to_bits(&self.to_bytes())
     }
 
-    pub fn parse_header(
-        bits: &[u8],
-    ) -> Option<(LenType, CRCType, FrameType, SeqType, u8, u8)> {
+    pub fn parse_header(bits: &[u8]) -> Option<FrameHeader> {
         let bytes = bits_to_bytes(bits);
         Self::parse_header_bytes(&bytes)
     }
 
-    fn parse_header_bytes(
-        bytes: &[u8],
-    ) -> Option<(LenType, CRCType, FrameType, SeqType, u8, u8)> {
+    fn parse_header_bytes(bytes: &[u8]) -> Option<FrameHeader> {
         if bytes.len() < PHY_HEADER_BYTES {
             debug!("PHY Header too short: {} bytes", bytes.len());
             return None;
         }
 
-        // Parse length
-        let len: LenType = ((bytes[0] as usize) << 8) | (bytes[1] as usize);
+        // Parse rate and length
+        let rate: RateCode = bytes[0] >> RATE_SHIFT;
+        if rate > MAX_RATE_CODE {
+            debug!("Unknown rate code {}, dropping", rate);
+            return None;
+        }
+        let timestamp = bytes[0] & TIMESTAMP_FLAG != 0;
+        let len: LenType =
+            (((bytes[0] & LEN_HIGH_MASK) as usize) << 8) | (bytes[1] as usize);
 
         // Parse CRC
         let crc: CRCType = bytes[2];
 
-        // Parse frame type
-        let frame_type: FrameType = FrameType::from_u8(bytes[3])?;
+        // Parse version, checksum kind, conv flag and frame type
+        if bytes[3] & VERSION_FLAG == 0 {
+            debug!("Frame of the 8-bit sequence layout, dropping");
+            return None;
+        }
+        let nav = bytes[3] & NAV_FLAG != 0;
+        let checksum =
+            ChecksumKind::from_u8((bytes[3] & !NAV_FLAG) >> CHECKSUM_SHIFT)?;
+        let conv_coded = bytes[3] & CONV_FLAG != 0;
+        let piggyback = bytes[3] & PIGGYBACK_FLAG != 0;
+        let frame_type: FrameType =
+            FrameType::from_u8(bytes[3] & FRAME_TYPE_MASK)?;
 
         // Parse sequence
-        let sequence: SeqType = bytes[4];
+        let sequence = SeqType::from_be_bytes([bytes[4], bytes[5]]);
 
         // Parse source address
-        let src: u8 = bytes[5];
+        let src: u8 = bytes[6];
 
         // Parse destination address
-        let dst: u8 = bytes[6];
+        let dst: u8 = bytes[7];
+
+        // Parse interleaver and hop count
+        let interleave =
+            Interleaver::from_header_code(bytes[8] >> INTERLEAVE_SHIFT);
+        let hops: u8 = bytes[8] & HOPS_MASK;
 
-        Some((len, crc, frame_type, sequence, src, dst))
+        Some(FrameHeader {
+            len,
+            crc,
+            frame_type,
+            checksum,
+            conv_coded,
+            interleave,
+            nav,
+            timestamp,
+            piggyback,
+            rate,
+            sequence,
+            src,
+            dst,
+            hops,
+        })
     }
 
     /// Deserialize frame from bytes (without preamble)
-    /// Returns None if CRC check fails or format is invalid
+    /// Returns None if the declared checksum fails or format is invalid
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        let (len, crc, frame_type, sequence, src, dst) =
-            Self::parse_header_bytes(&bytes[..PHY_HEADER_BYTES])?;
-
-        // Extract CRC and data
-        let data_bytes =
-            &bytes[PHY_HEADER_BYTES..PHY_HEADER_BYTES + len as usize];
-
-        // Verify CRC
-        if !verify_crc8(data_bytes, crc) {
-            debug!("CRC check failed");
+        if bytes.len() < PHY_HEADER_BYTES {
+            debug!("Frame too short: {} bytes", bytes.len());
             return None;
         }
+        let header = Self::parse_header_bytes(&bytes[..PHY_HEADER_BYTES])?;
 
         // Check if we have enough data
-        if bytes.len() < PHY_HEADER_BYTES + len as usize {
+        let data_end = PHY_HEADER_BYTES + header.len;
+        if bytes.len() < PHY_HEADER_BYTES + header.body_len() {
             debug!("Frame data incomplete");
             return None;
         }
+        let body_bytes = &bytes[PHY_HEADER_BYTES..data_end];
+
+        let crc_ok = match header.checksum {
+            ChecksumKind::Crc8 if header.frame_type == FrameType::Aggregate => {
+                let prefix_len = header
+                    .prefix_len()
+                    .min(header.len);
+                aggregate_crc8(
+                    &bytes[..PHY_HEADER_BYTES],
+                    &body_bytes[..prefix_len],
+                ) == header.crc
+            }
+            ChecksumKind::Crc8 => verify_crc8(body_bytes, header.crc),
+            ChecksumKind::Crc32 => {
+                let trailer: [u8; 4] = bytes[data_end..data_end + 4]
+                    .try_into()
+                    .ok()?;
+                verify_crc32(&bytes[..data_end], u32::from_be_bytes(trailer))
+            }
+        };
+        if !crc_ok {
+            debug!("{:?} check failed", header.checksum);
+            return None;
+        }
 
-        // Extract data
-        let data = data_bytes.to_vec();
+        let (nav_ms, body_bytes) = if header.nav {
+            if body_bytes.len() < 2 {
+                debug!("NAV flag set without a duration");
+                return None;
+            }
+            let nav = u16::from_be_bytes([body_bytes[0], body_bytes[1]]);
+            (Some(nav), &body_bytes[2..])
+        } else {
+            (None, body_bytes)
+        };
+        let (timestamp, body_bytes) = if header.timestamp {
+            if body_bytes.len() < 8 {
+                debug!("Timestamp flag set without timestamps");
+                return None;
+            }
+            let stamp = |at: usize| {
+                u32::from_be_bytes(
+                    body_bytes[at..at + 4]
+                        .try_into()
+                        .unwrap(),
+                )
+            };
+            let timestamp = FrameTimestamp {
+                queued_ms: stamp(0),
+                sent_ms: stamp(4),
+            };
+            (Some(timestamp), &body_bytes[8..])
+        } else {
+            (None, body_bytes)
+        };
+        let (piggyback_ack, data_bytes) = if header.piggyback {
+            if body_bytes.len() < 2 {
+                debug!("Piggyback flag set without an ACK sequence");
+                return None;
+            }
+            let ack = SeqType::from_be_bytes([body_bytes[0], body_bytes[1]]);
+            (Some(ack), &body_bytes[2..])
+        } else {
+            (None, body_bytes)
+        };
 
         Some(Frame {
-            frame_type,
-            sequence,
-            src,
-            dst,
-            data,
+            frame_type: header.frame_type,
+            sequence: header.sequence,
+            src: header.src,
+            dst: header.dst,
+            hops: header.hops,
+            checksum: header.checksum,
+            conv_coded: header.conv_coded,
+            interleave: header.interleave,
+            piggyback_ack,
+            nav_ms,
+            timestamp,
+            rate: header.rate,
+            data: data_bytes.to_vec(),
         })
     }
 
@@ -182,3 +571,269 @@ impl Frame {
         Self::from_bytes(&bytes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_selection_roundtrip() {
+        let short = Frame::new_data(1, 1, 2, vec![7; 8]);
+        let long = Frame::new_data(2, 1, 2, vec![7; 100]);
+        assert_eq!(short.checksum, ChecksumKind::Crc8);
+        assert_eq!(long.checksum, ChecksumKind::Crc32);
+        assert_eq!(long.to_bytes().len(), PHY_HEADER_BYTES + 100 + 4);
+
+        for frame in [short, long] {
+            let parsed = Frame::from_bytes(&frame.to_bytes()).unwrap();
+            assert_eq!(parsed.checksum, frame.checksum);
+            assert_eq!(parsed.data, frame.data);
+        }
+    }
+
+    #[test]
+    fn test_crc8_frame_layout() {
+        // Built by hand: type byte with only the version flag
+        let data = vec![0xAB; 16];
+        let mut bytes =
+            vec![0x00, 16, calculate_crc8(&data), 0x11, 0x01, 0x2C, 1, 2, 0];
+        bytes.extend_from_slice(&data);
+
+        let frame = Frame::from_bytes(&bytes).unwrap();
+        assert_eq!(frame.checksum, ChecksumKind::Crc8);
+        assert_eq!(frame.frame_type, FrameType::Data);
+        assert_eq!(frame.sequence, 300);
+        assert_eq!(frame.data, data);
+        assert_eq!(frame.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_aggregate_crc_covers_header_only() {
+        use crate::mac::aggregation::{self, SUBPACKET_HEADER_BYTES, SubPacket};
+
+        let pieces: Vec<SubPacket> = (0..3u8)
+            .map(|id| SubPacket {
+                id,
+                data: vec![id; 30],
+            })
+            .collect();
+        let mut frame = Frame::new(
+            FrameType::Aggregate,
+            5,
+            1,
+            2,
+            aggregation::encode_aggregate(&pieces),
+        );
+        frame.nav_ms = Some(40);
+        assert_eq!(frame.checksum, ChecksumKind::Crc8);
+        let bytes = frame.to_bytes();
+
+        // A bad sub-packet leaves the frame to the MAC
+        let mut corrupted = bytes.clone();
+        let second = PHY_HEADER_BYTES + 2 + 30 + 2 * SUBPACKET_HEADER_BYTES;
+        corrupted[second + 7] ^= 0x10;
+        let parsed = Frame::from_bytes(&corrupted).unwrap();
+        let (_, status) = aggregation::decode_aggregate(&parsed.data);
+        assert_eq!(status, vec![true, false, true]);
+
+        // Header and NAV are still checked
+        for at in [4, 7, PHY_HEADER_BYTES + 1] {
+            let mut corrupted = bytes.clone();
+            corrupted[at] ^= 0x01;
+            assert!(Frame::from_bytes(&corrupted).is_none(), "byte {}", at);
+        }
+    }
+
+    #[test]
+    fn test_8bit_sequence_frame_rejected() {
+        // The original layout: 1-byte sequence, type byte without flags
+        let data = vec![0xAB; 64];
+        let mut bytes = vec![0x00, 64, calculate_crc8(&data), 0x01, 9, 1, 2, 0];
+        bytes.extend_from_slice(&data);
+        assert!(Frame::parse_header(&bytes_to_bits(&bytes)).is_none());
+        assert!(Frame::from_bytes(&bytes).is_none());
+    }
+
+    #[test]
+    fn test_conv_flag_roundtrip() {
+        let mut frame = Frame::new_data(4, 1, 2, vec![1, 2, 3]);
+        frame.conv_coded = true;
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[3] & FRAME_TYPE_MASK, FrameType::Data.to_u8());
+
+        let header = Frame::parse_header(&bytes_to_bits(&bytes)).unwrap();
+        assert!(header.conv_coded);
+        assert_eq!(header.frame_type, FrameType::Data);
+        assert!(
+            Frame::from_bytes(&bytes)
+                .unwrap()
+                .conv_coded
+        );
+    }
+
+    #[test]
+    fn test_interleave_code_shares_hops_byte() {
+        let mut frame = Frame::new_data(4, 1, 2, vec![1, 2, 3]);
+        frame.hops = 3;
+        frame.interleave = Some(Interleaver::new(64, 16).unwrap());
+        let parsed = Frame::from_bytes(&frame.to_bytes()).unwrap();
+        assert_eq!(parsed.hops, 3);
+        assert_eq!(parsed.interleave, frame.interleave);
+    }
+
+    #[test]
+    fn test_rts_cts_roundtrip() {
+        let rts = Frame::new_rts(0x0102, 1, 2, 1500);
+        let parsed = Frame::from_bytes(&rts.to_bytes()).unwrap();
+        assert_eq!(parsed.frame_type, FrameType::Rts);
+        assert_eq!(parsed.sequence, 0x0102);
+        assert_eq!((parsed.src, parsed.dst), (1, 2));
+        assert_eq!(parsed.nav_ms, Some(1500));
+        assert!(parsed.data.is_empty());
+
+        let cts = Frame::new_cts(0x0102, 2, 1, 1400);
+        let parsed = Frame::from_bytes(&cts.to_bytes()).unwrap();
+        assert_eq!(parsed.frame_type, FrameType::Cts);
+        assert_eq!(parsed.nav_ms, Some(1400));
+    }
+
+    #[test]
+    fn test_nav_roundtrip() {
+        let mut frame = Frame::new_data(3, 1, 2, vec![0xAA; 40]);
+        frame.nav_ms = Some(0x0203);
+        frame.piggyback_ack = Some(9);
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[3] & NAV_FLAG, NAV_FLAG);
+        // NAV leads the body, ahead of the piggybacked ACK
+        assert_eq!(
+            &bytes[PHY_HEADER_BYTES..PHY_HEADER_BYTES + 4],
+            &[0x02, 0x03, 0x00, 0x09]
+        );
+
+        let parsed = Frame::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.checksum, ChecksumKind::Crc32);
+        assert_eq!(parsed.nav_ms, Some(0x0203));
+        assert_eq!(parsed.piggyback_ack, Some(9));
+        assert_eq!(parsed.data, vec![0xAA; 40]);
+
+        // Frames without a NAV keep the old layout
+        let plain = Frame::new_ack(1, 2, 1);
+        assert_eq!(plain.to_bytes()[3] & NAV_FLAG, 0);
+        assert_eq!(
+            Frame::from_bytes(&plain.to_bytes())
+                .unwrap()
+                .nav_ms,
+            None
+        );
+    }
+
+    #[test]
+    fn test_piggyback_ack_roundtrip() {
+        let mut frame = Frame::new_data(7, 1, 2, vec![9, 8, 7]);
+        frame.piggyback_ack = Some(0x1234);
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[3] & PIGGYBACK_FLAG, PIGGYBACK_FLAG);
+        // Length counts the ACK sequence, which leads the body
+        assert_eq!(bytes[1], 5);
+        assert_eq!(
+            &bytes[PHY_HEADER_BYTES..PHY_HEADER_BYTES + 2],
+            &[0x12, 0x34]
+        );
+
+        let parsed = Frame::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.frame_type, FrameType::Data);
+        assert_eq!(parsed.piggyback_ack, Some(0x1234));
+        assert_eq!(parsed.data, vec![9, 8, 7]);
+
+        // The ACK sequence is covered by the checksum
+        let mut corrupted = bytes.clone();
+        corrupted[PHY_HEADER_BYTES + 1] ^= 0x01;
+        assert!(Frame::from_bytes(&corrupted).is_none());
+
+        let plain = Frame::new_data(7, 1, 2, vec![9, 8, 7]);
+        assert_eq!(
+            Frame::from_bytes(&plain.to_bytes())
+                .unwrap()
+                .piggyback_ack,
+            None
+        );
+    }
+
+    #[test]
+    fn test_timestamp_roundtrip() {
+        let mut frame = Frame::new_data(4, 1, 2, vec![0x11; 20]);
+        frame.nav_ms = Some(0x0102);
+        frame.piggyback_ack = Some(0x0304);
+        frame.rate = MAX_RATE_CODE;
+        frame.timestamp = Some(FrameTimestamp {
+            queued_ms: 0xA0B0C0D0,
+            sent_ms: 0xA0B0C0E5,
+        });
+        let bytes = frame.to_bytes();
+        assert_eq!(bytes[0] & TIMESTAMP_FLAG, TIMESTAMP_FLAG);
+        // Length still reads right next to the flag and the rate
+        assert_eq!(bytes[1], 2 + 8 + 2 + 20);
+        // Between the NAV and the piggybacked ACK
+        assert_eq!(
+            &bytes[PHY_HEADER_BYTES..PHY_HEADER_BYTES + 12],
+            &[
+                0x01, 0x02, 0xA0, 0xB0, 0xC0, 0xD0, 0xA0, 0xB0, 0xC0, 0xE5,
+                0x03, 0x04
+            ]
+        );
+
+        let header = Frame::parse_header(&bytes_to_bits(&bytes)).unwrap();
+        assert!(header.timestamp);
+        assert_eq!(header.len, 32);
+        assert_eq!(header.rate, MAX_RATE_CODE);
+        let parsed = Frame::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.timestamp, frame.timestamp);
+        assert_eq!(parsed.nav_ms, Some(0x0102));
+        assert_eq!(parsed.piggyback_ack, Some(0x0304));
+        assert_eq!(parsed.data, vec![0x11; 20]);
+
+        // Covered by the checksum
+        let mut corrupted = bytes.clone();
+        corrupted[PHY_HEADER_BYTES + 5] ^= 0x01;
+        assert!(Frame::from_bytes(&corrupted).is_none());
+
+        // Frames without keep the old layout
+        let plain = Frame::new_data(4, 1, 2, vec![0x11; 20]);
+        assert_eq!(plain.to_bytes()[0] & TIMESTAMP_FLAG, 0);
+        assert_eq!(
+            Frame::from_bytes(&plain.to_bytes())
+                .unwrap()
+                .timestamp,
+            None
+        );
+    }
+
+    #[test]
+    fn test_rate_code_shares_length() {
+        let probe = Frame::new_probe(2, 1, 2, MAX_RATE_CODE, vec![0x5A; 40]);
+        let bytes = probe.to_bytes();
+        assert_eq!(bytes[0] >> RATE_SHIFT, MAX_RATE_CODE);
+        assert_eq!(bytes[3] & FRAME_TYPE_MASK, FrameType::Probe.to_u8());
+
+        let header = Frame::parse_header(&bytes_to_bits(&bytes)).unwrap();
+        assert_eq!(header.len, 40);
+        assert_eq!(header.rate, MAX_RATE_CODE);
+        let parsed = Frame::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.frame_type, FrameType::Probe);
+        assert_eq!(parsed.rate, MAX_RATE_CODE);
+        assert_eq!(parsed.data, probe.data);
+
+        // Codes past the table are rejected
+        let mut unknown = bytes.clone();
+        unknown[0] |= 0xC0;
+        assert!(Frame::parse_header(&bytes_to_bits(&unknown)).is_none());
+    }
+
+    #[test]
+    fn test_crc32_covers_header() {
+        let frame = Frame::new_data(3, 1, 2, vec![0x55; 40]);
+        let mut bytes = frame.to_bytes();
+        bytes[5] ^= 0x01; // sequence
+        assert!(Frame::from_bytes(&bytes).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+// Block interleaver against burst errors (clicks, pops, dropouts)
+//
+// Bits are written row by row into a rows x cols block and sent column by
+// column, so a burst of up to `rows` bits on the air lands `cols` bits apart
+// after deinterleaving. A short last block skips the missing cells, so no
+// padding is needed. Only the frame body is interleaved: the header carries
+// the block size (see Frame::interleave) and must be readable first.
+//
+// Header code (5 bits): [enabled:1] [log2(rows) - 3:2] [log2(cols) - 3:2]
+
+/// Supported block dimensions, indexed by their 2-bit header code
+const DIMENSIONS: [usize; 4] = [8, 16, 32, 64];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interleaver {
+    rows: usize,
+    cols: usize,
+}
+
+impl Default for Interleaver {
+    fn default() -> Self {
+        Self { rows: 8, cols: 16 }
+    }
+}
+
+impl Interleaver {
+    pub fn new(rows: usize, cols: usize) -> Result<Self, String> {
+        if !DIMENSIONS.contains(&rows) || !DIMENSIONS.contains(&cols) {
+            return Err(format!(
+                "Unsupported interleaver {}x{}, rows and columns must be one of {:?}",
+                rows, cols, DIMENSIONS
+            ));
+        }
+        Ok(Self { rows, cols })
+    }
+
+    /// Parse "ROWSxCOLS", e.g. "8x16"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (rows, cols) = spec
+            .split_once(['x', 'X'])
+            .ok_or_else(|| {
+                format!("Invalid interleaver '{}', expected ROWSxCOLS", spec)
+            })?;
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid interleaver '{}': {}", spec, e))
+        };
+        Self::new(parse(rows)?, parse(cols)?)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// 5-bit header code, 0 = no interleaving
+    pub fn header_code(interleaver: Option<Self>) -> u8 {
+        let Some(il) = interleaver else {
+            return 0;
+        };
+        let code = |v: usize| {
+            DIMENSIONS
+                .iter()
+                .position(|&d| d == v)
+                .unwrap() as u8
+        };
+        0x10 | code(il.rows) << 2 | code(il.cols)
+    }
+
+    pub fn from_header_code(code: u8) -> Option<Self> {
+        if code & 0x10 == 0 {
+            return None;
+        }
+        Some(Self {
+            rows: DIMENSIONS[(code >> 2 & 0x03) as usize],
+            cols: DIMENSIONS[(code & 0x03) as usize],
+        })
+    }
+
+    /// Send order of the cells of a block holding `len` values
+    fn block_order(&self, len: usize) -> Vec<usize> {
+        (0..self.cols)
+            .flat_map(|col| (0..self.rows).map(move |row| row * self.cols + col))
+            .filter(|&index| index < len)
+            .collect()
+    }
+
+    pub fn interleave<T: Copy>(&self, input: &[T]) -> Vec<T> {
+        let mut output = Vec::with_capacity(input.len());
+        for block in input.chunks(self.rows * self.cols) {
+            output.extend(
+                self.block_order(block.len())
+                    .into_iter()
+                    .map(|i| block[i]),
+            );
+        }
+        output
+    }
+
+    pub fn deinterleave<T: Copy>(&self, input: &[T]) -> Vec<T> {
+        let mut output = input.to_vec();
+        let block_len = self.rows * self.cols;
+        for (n, block) in input
+            .chunks(block_len)
+            .enumerate()
+        {
+            for (&i, &value) in self
+                .block_order(block.len())
+                .iter()
+                .zip(block)
+            {
+                output[n * block_len + i] = value;
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip_with_short_last_block() {
+        let il = Interleaver::default();
+        for len in [0, 5, 128, 300] {
+            let data: Vec<u16> = (0..len as u16).collect();
+            let sent = il.interleave(&data);
+            assert_eq!(sent.len(), data.len());
+            assert_eq!(il.deinterleave(&sent), data);
+        }
+    }
+
+    #[test]
+    fn test_burst_is_spread() {
+        let il = Interleaver::new(16, 8).unwrap();
+        let data: Vec<usize> = (0..256).collect();
+        let sent = il.interleave(&data);
+        // A burst of `rows` consecutive bits hits positions `cols` apart
+        let mut hit: Vec<usize> = sent[20..36].to_vec();
+        hit.sort();
+        assert!(
+            hit.windows(2)
+                .all(|w| w[1] - w[0] >= 7)
+        );
+    }
+
+    #[test]
+    fn test_header_code_and_parse() {
+        assert_eq!(Interleaver::header_code(None), 0);
+        assert_eq!(Interleaver::from_header_code(0), None);
+        for spec in ["8x16", "64x8", "16X64"] {
+            let il = Interleaver::parse(spec).unwrap();
+            let code = Interleaver::header_code(Some(il));
+            assert!(code < 32);
+            assert_eq!(Interleaver::from_header_code(code), Some(il));
+        }
+        assert!(Interleaver::parse("10x16").is_err());
+        assert!(Interleaver::parse("8").is_err());
+    }
+}
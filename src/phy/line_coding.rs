@@ -2,6 +2,8 @@ use std::fmt;
 
 use tracing::{debug, warn};
 
+use crate::utils::consts::NRZI_INITIAL_LEVEL;
+
 /// Trait for line coding
 pub trait LineCode: Send {
     fn encode(&self, bits: &[u8]) -> Vec<f32>;
@@ -21,12 +23,26 @@ pub trait LineCode: Send {
     }
 
     fn reset(&mut self);
+
+    /// Whether an inverted signal decodes identically, so the receiver may
+    /// accept a negatively correlated preamble
+    fn polarity_invariant(&self) -> bool {
+        false
+    }
+
+    /// Per-bit soft values (> 0 means 1, magnitude = confidence) for
+    /// codes whose bits map directly onto levels
+    fn soft_decode(&self, _samples: &[f32]) -> Option<Vec<f32>> {
+        None
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum LineCodingKind {
     Manchester,
     FourBFiveB,
+    EightBTenB,
+    Nrzi,
 }
 
 impl LineCodingKind {
@@ -34,6 +50,20 @@ impl LineCodingKind {
         match self {
             LineCodingKind::Manchester => "Manchester",
             LineCodingKind::FourBFiveB => "4B5B",
+            LineCodingKind::EightBTenB => "8B10B",
+            LineCodingKind::Nrzi => "NRZI",
+        }
+    }
+
+    /// The kind `name` (or a common spelling of it) stands for, ignoring
+    /// case
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "manchester" | "manchester-biphase" => Some(Self::Manchester),
+            "4b5b" | "4b5b-nrz" => Some(Self::FourBFiveB),
+            "8b10b" => Some(Self::EightBTenB),
+            "nrzi" => Some(Self::Nrzi),
+            _ => None,
         }
     }
 
@@ -45,6 +75,12 @@ impl LineCodingKind {
             LineCodingKind::FourBFiveB => {
                 Box::new(FourBFiveBCodec::new(samples_per_level))
             }
+            LineCodingKind::EightBTenB => {
+                Box::new(EightBTenBCodec::new(samples_per_level))
+            }
+            LineCodingKind::Nrzi => {
+                Box::new(NrziCodec::new(samples_per_level, NRZI_INITIAL_LEVEL))
+            }
         }
     }
 }
@@ -126,6 +162,20 @@ impl LineCode for ManchesterCodec {
         num_bits * self.samples_per_level * 2
     }
 
+    fn soft_decode(&self, samples: &[f32]) -> Option<Vec<f32>> {
+        let samples_per_bit = self.samples_per_level * 2;
+        Some(
+            samples
+                .chunks_exact(samples_per_bit)
+                .map(|bit| {
+                    let (first, second) = bit.split_at(self.samples_per_level);
+                    (second.iter().sum::<f32>() - first.iter().sum::<f32>())
+                        / samples_per_bit as f32
+                })
+                .collect(),
+        )
+    }
+
     fn reset(&mut self) {
         // Manchester is stateless
     }
@@ -154,6 +204,15 @@ const FOURB_FIVEB_ENCODE_TABLE: [u8; 16] = [
     0b11101, // 0xF
 ];
 
+/// The nibble whose symbol differs from `symbol` in the fewest bits
+fn nearest_4b5b_nibble(symbol: u8) -> u8 {
+    (0..16u8)
+        .min_by_key(|&nibble| {
+            (FOURB_FIVEB_ENCODE_TABLE[nibble as usize] ^ symbol).count_ones()
+        })
+        .unwrap()
+}
+
 fn decode_4b5b_symbol(symbol: u8) -> Option<u8> {
     match symbol {
         0b11110 => Some(0x0),
@@ -239,7 +298,9 @@ impl LineCode for FourBFiveBCodec {
         samples
     }
 
-    /// Decode samples using NRZI and 4B/5B.
+    /// Decode samples using NRZI and 4B/5B. An invalid symbol becomes the
+    /// nearest valid one, so a slicing error costs a nibble, not the rest
+    /// of the frame, and FEC gets a chance at it.
     fn decode(&self, samples: &[f32]) -> Vec<u8> {
         if samples.is_empty() {
             return Vec::new();
@@ -274,15 +335,10 @@ impl LineCode for FourBFiveBCodec {
             bit_count += 1;
 
             if bit_count == 5 {
-                if let Some(nibble) = decode_4b5b_symbol(current_symbol) {
-                    for j in 0..4 {
-                        decoded_bits.push((nibble >> (3 - j)) & 1);
-                    }
-                } else {
-                    // Error handling: if an invalid symbol is found, we might stop or fill with errors.
-                    // For now, we stop to avoid propagating errors.
-                    warn!("Decoding stopped due to invalid 4B/5B symbol.");
-                    break;
+                let nibble = decode_4b5b_symbol(current_symbol)
+                    .unwrap_or_else(|| nearest_4b5b_nibble(current_symbol));
+                for j in 0..4 {
+                    decoded_bits.push((nibble >> (3 - j)) & 1);
                 }
                 current_symbol = 0;
                 bit_count = 0;
@@ -332,6 +388,275 @@ impl LineCode for FourBFiveBCodec {
     }
 }
 
+// ============================================================================
+// 8B10B
+// ============================================================================
+
+/// 5b/6b sub-block (abcdei, a = MSB) for EDCBA, as (RD-, RD+)
+const EIGHTB_TENB_6B_TABLE: [(u8, u8); 32] = [
+    (0b100111, 0b011000), // D.00
+    (0b011101, 0b100010), // D.01
+    (0b101101, 0b010010), // D.02
+    (0b110001, 0b110001), // D.03
+    (0b110101, 0b001010), // D.04
+    (0b101001, 0b101001), // D.05
+    (0b011001, 0b011001), // D.06
+    (0b111000, 0b000111), // D.07
+    (0b111001, 0b000110), // D.08
+    (0b100101, 0b100101), // D.09
+    (0b010101, 0b010101), // D.10
+    (0b110100, 0b110100), // D.11
+    (0b001101, 0b001101), // D.12
+    (0b101100, 0b101100), // D.13
+    (0b011100, 0b011100), // D.14
+    (0b010111, 0b101000), // D.15
+    (0b011011, 0b100100), // D.16
+    (0b100011, 0b100011), // D.17
+    (0b010011, 0b010011), // D.18
+    (0b110010, 0b110010), // D.19
+    (0b001011, 0b001011), // D.20
+    (0b101010, 0b101010), // D.21
+    (0b011010, 0b011010), // D.22
+    (0b111010, 0b000101), // D.23
+    (0b110011, 0b001100), // D.24
+    (0b100110, 0b100110), // D.25
+    (0b010110, 0b010110), // D.26
+    (0b110110, 0b001001), // D.27
+    (0b001110, 0b001110), // D.28
+    (0b101110, 0b010001), // D.29
+    (0b011110, 0b100001), // D.30
+    (0b101011, 0b010100), // D.31
+];
+
+/// 3b/4b sub-block (fghj, f = MSB) for HGF, as (RD-, RD+)
+const EIGHTB_TENB_4B_TABLE: [(u8, u8); 8] = [
+    (0b1011, 0b0100), // D.x.0
+    (0b1001, 0b1001), // D.x.1
+    (0b0101, 0b0101), // D.x.2
+    (0b1100, 0b0011), // D.x.3
+    (0b1101, 0b0010), // D.x.4
+    (0b1010, 0b1010), // D.x.5
+    (0b0110, 0b0110), // D.x.6
+    (0b1110, 0b0001), // D.x.P7
+];
+
+/// D.x.A7, replaces P7 to avoid a run of five equal bits
+const EIGHTB_TENB_A7: (u8, u8) = (0b0111, 0b1000);
+
+/// Running disparity after sending `code` of `width` bits, None if `code`
+/// is not allowed at `rd` (more than +/-2 or pushing the wrong way)
+fn next_disparity(code: u8, width: u32, rd: i8) -> Option<i8> {
+    let disparity = 2 * code.count_ones() as i8 - width as i8;
+    match (disparity, rd) {
+        (0, _) => Some(rd),
+        (2, -1) => Some(1),
+        (-2, 1) => Some(-1),
+        _ => None,
+    }
+}
+
+fn encode_8b10b_symbol(byte: u8, rd: &mut i8) -> u16 {
+    let pick = |(neg, pos): (u8, u8), rd: i8| if rd < 0 { neg } else { pos };
+
+    let x = (byte & 0x1F) as usize;
+    let y = (byte >> 5) as usize;
+
+    let six = pick(EIGHTB_TENB_6B_TABLE[x], *rd);
+    *rd = next_disparity(six, 6, *rd).unwrap_or(*rd);
+
+    let use_a7 = y == 7
+        && (*rd < 0 && matches!(x, 17 | 18 | 20)
+            || *rd > 0 && matches!(x, 11 | 13 | 14));
+    let four = if use_a7 {
+        pick(EIGHTB_TENB_A7, *rd)
+    } else {
+        pick(EIGHTB_TENB_4B_TABLE[y], *rd)
+    };
+    *rd = next_disparity(four, 4, *rd).unwrap_or(*rd);
+
+    ((six as u16) << 4) | four as u16
+}
+
+/// Decode one 10-bit symbol, None on an invalid code or disparity error
+fn decode_8b10b_symbol(symbol: u16, rd: &mut i8) -> Option<u8> {
+    let six = (symbol >> 4) as u8;
+    let four = (symbol & 0x0F) as u8;
+    let valid = |(neg, pos): (u8, u8), code: u8, rd: i8| {
+        if rd < 0 { neg == code } else { pos == code }
+    };
+
+    let x = EIGHTB_TENB_6B_TABLE
+        .iter()
+        .position(|&entry| valid(entry, six, *rd))?;
+    let mid_rd = next_disparity(six, 6, *rd)?;
+
+    let y = EIGHTB_TENB_4B_TABLE
+        .iter()
+        .position(|&entry| valid(entry, four, mid_rd))
+        .or_else(|| valid(EIGHTB_TENB_A7, four, mid_rd).then_some(7))?;
+    *rd = next_disparity(four, 4, mid_rd)?;
+
+    Some(((y as u8) << 5) | x as u8)
+}
+
+/// 8b/10b with running disparity, NRZ levels (1 -> +1, 0 -> -1).
+/// Every `encode` / `decode` call starts at RD-.
+pub struct EightBTenBCodec {
+    samples_per_level: usize,
+}
+
+impl EightBTenBCodec {
+    pub fn new(samples_per_level: usize) -> Self {
+        Self { samples_per_level }
+    }
+}
+
+impl LineCode for EightBTenBCodec {
+    fn encode(&self, bits: &[u8]) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.samples_for_bits(bits.len()));
+        let mut rd = -1;
+
+        for chunk in bits.chunks(8) {
+            // Short last chunk is padded with zeros
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |acc, (j, &bit)| acc | ((bit & 1) << (7 - j)));
+            let symbol = encode_8b10b_symbol(byte, &mut rd);
+
+            for j in (0..10).rev() {
+                let level = if (symbol >> j) & 1 == 1 { 1.0 } else { -1.0 };
+                samples.extend(vec![level; self.samples_per_level]);
+            }
+        }
+
+        samples
+    }
+
+    /// Stops at the first invalid symbol or disparity error, so the caller
+    /// sees fewer bits than expected and drops the frame
+    fn decode(&self, samples: &[f32]) -> Vec<u8> {
+        let samples_per_symbol = self.samples_per_level * 10;
+        let num_symbols = samples.len() / samples_per_symbol;
+        let mut bits = Vec::with_capacity(num_symbols * 8);
+        let mut rd = -1;
+
+        for chunk in samples
+            .chunks_exact(samples_per_symbol)
+            .take(num_symbols)
+        {
+            let symbol = chunk
+                .chunks_exact(self.samples_per_level)
+                .fold(0u16, |acc, level| {
+                    let bit = level.iter().sum::<f32>() > 0.0;
+                    (acc << 1) | bit as u16
+                });
+
+            match decode_8b10b_symbol(symbol, &mut rd) {
+                Some(byte) => {
+                    bits.extend((0..8).map(|j| (byte >> (7 - j)) & 1));
+                }
+                None => {
+                    warn!(
+                        "Decoding stopped due to invalid 8B/10B symbol {:010b}",
+                        symbol
+                    );
+                    break;
+                }
+            }
+        }
+
+        bits
+    }
+
+    fn samples_for_bits(&self, num_bits: usize) -> usize {
+        num_bits.div_ceil(8) * 10 * self.samples_per_level
+    }
+
+    fn reset(&mut self) {
+        // Running disparity restarts with every encode / decode call
+    }
+}
+
+// ============================================================================
+// NRZI
+// ============================================================================
+/// NRZI: 1 -> transition, 0 -> no transition.
+/// Every encoded block starts with one reference level at `initial_level`,
+/// so the first bit has something to compare against and the decoder never
+/// looks at absolute levels.
+pub struct NrziCodec {
+    samples_per_level: usize,
+    initial_level: f32,
+}
+
+impl NrziCodec {
+    pub fn new(samples_per_level: usize, initial_level: f32) -> Self {
+        Self {
+            samples_per_level,
+            initial_level: initial_level.signum(),
+        }
+    }
+
+    fn level_avg(&self, samples: &[f32], index: usize) -> f32 {
+        let start = index * self.samples_per_level;
+        samples[start..start + self.samples_per_level]
+            .iter()
+            .sum::<f32>()
+            / self.samples_per_level as f32
+    }
+}
+
+impl LineCode for NrziCodec {
+    fn encode(&self, bits: &[u8]) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.samples_for_bits(bits.len()));
+        let mut current_level = self.initial_level;
+        samples.extend(vec![current_level; self.samples_per_level]);
+
+        for &bit in bits {
+            if bit != 0 {
+                current_level = -current_level;
+            }
+            samples.extend(vec![current_level; self.samples_per_level]);
+        }
+
+        samples
+    }
+
+    fn decode(&self, samples: &[f32]) -> Vec<u8> {
+        let num_levels = samples.len() / self.samples_per_level;
+        if num_levels == 0 {
+            return Vec::new();
+        }
+
+        let mut bits = Vec::with_capacity(num_levels - 1);
+        let mut last_avg = self.level_avg(samples, 0);
+        for i in 1..num_levels {
+            let current_avg = self.level_avg(samples, i);
+            bits.push(if last_avg * current_avg < 0.0 { 1 } else { 0 });
+            // Avoid last_avg being zero
+            if current_avg.abs() > 1e-6 {
+                last_avg = current_avg;
+            }
+        }
+
+        bits
+    }
+
+    fn samples_for_bits(&self, num_bits: usize) -> usize {
+        // One reference level in front
+        (num_bits + 1) * self.samples_per_level
+    }
+
+    fn reset(&mut self) {
+        // Each block carries its own reference level
+    }
+
+    fn polarity_invariant(&self) -> bool {
+        true
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -344,6 +669,15 @@ mod tests {
         let decoded = codec.decode(&samples);
 
         assert_eq!(bits, decoded);
+
+        let soft = codec
+            .soft_decode(&samples)
+            .unwrap();
+        let sliced: Vec<u8> = soft
+            .iter()
+            .map(|&v| (v > 0.0) as u8)
+            .collect();
+        assert_eq!(sliced, bits);
     }
 
     #[test]
@@ -364,6 +698,21 @@ mod tests {
         assert_eq!(bits, decoded);
     }
 
+    #[test]
+    fn test_4b5b_invalid_symbol_does_not_truncate() {
+        let codec = FourBFiveBCodec::new(4);
+        let bits = vec![1, 0, 1, 0, 0, 1, 1, 1, 0, 0, 0, 0, 1, 1, 1, 1];
+        let mut samples = codec.encode(&bits);
+        // One level sliced wrong: two transitions in the first symbol
+        for s in &mut samples[..4] {
+            *s = -*s;
+        }
+        let decoded = codec.decode(&samples);
+
+        assert_eq!(decoded.len(), bits.len());
+        assert_eq!(decoded[4..], bits[4..]);
+    }
+
     #[test]
     fn test_4b5b_preamble_length() {
         let codec = FourBFiveBCodec::new(4);
@@ -374,4 +723,72 @@ mod tests {
         // 20 encoded bits * 4 samples_per_level = 80 samples
         assert_eq!(preamble.len(), 80);
     }
+
+    fn byte_bits(bytes: &[u8]) -> Vec<u8> {
+        bytes
+            .iter()
+            .flat_map(|b| (0..8).map(move |j| (b >> (7 - j)) & 1))
+            .collect()
+    }
+
+    #[test]
+    fn test_8b10b_all_bytes_roundtrip() {
+        let codec = EightBTenBCodec::new(2);
+        // Every byte value, after both RD- and RD+
+        let bytes: Vec<u8> = (0..=255u8)
+            .flat_map(|b| [b, 0x00, b, 0x07])
+            .collect();
+        let bits = byte_bits(&bytes);
+        let samples = codec.encode(&bits);
+        assert_eq!(samples.len(), codec.samples_for_bits(bits.len()));
+        assert_eq!(codec.decode(&samples), bits);
+
+        // DC balanced: running disparity never leaves +/-1
+        let sum: f32 = samples.iter().sum();
+        assert!(sum.abs() <= 2.0 * 2.0);
+    }
+
+    #[test]
+    fn test_8b10b_disparity_error_detected() {
+        let codec = EightBTenBCodec::new(2);
+        let bits = byte_bits(&[0x12, 0x00, 0x34]);
+
+        // Inverting the first symbol sends 0x12's code word with RD+ framing:
+        // 010011 1011 -> 101100 0100, the 4b part is invalid at that RD
+        let mut samples = codec.encode(&bits);
+        for s in &mut samples[..20] {
+            *s = -*s;
+        }
+        assert!(
+            codec
+                .decode(&samples)
+                .is_empty()
+        );
+
+        // 0x00 follows at RD+ as 011000; losing one of its ones gives
+        // 001000 (disparity -4). Only the byte before it survives.
+        let mut samples = codec.encode(&bits);
+        for s in &mut samples[22..24] {
+            *s = -*s;
+        }
+        assert_eq!(codec.decode(&samples), bits[..8]);
+    }
+
+    #[test]
+    fn test_nrzi_ignores_polarity() {
+        let bits = vec![1, 0, 0, 1, 1, 1, 0, 1, 0, 0, 0, 1];
+        for initial_level in [1.0, -1.0] {
+            let codec = NrziCodec::new(3, initial_level);
+            let samples = codec.encode(&bits);
+            assert_eq!(samples.len(), codec.samples_for_bits(bits.len()));
+            assert_eq!(samples[0], initial_level);
+            assert_eq!(codec.decode(&samples), bits);
+
+            let inverted: Vec<f32> = samples
+                .iter()
+                .map(|s| -s)
+                .collect();
+            assert_eq!(codec.decode(&inverted), bits);
+        }
+    }
 }
@@ -1,13 +1,26 @@
 // Physical layer module for Project 2
 // Implements baseband transmission with line coding
 
+pub mod agc;
+pub mod backend;
+pub mod capture;
 pub mod crc;
+pub mod dc_blocker;
 pub mod decoder;
+pub mod echo;
 pub mod encoder;
+pub mod fec;
 pub mod frame;
+pub mod interleaver;
 pub mod line_coding;
+pub mod params;
+pub mod preamble;
+pub mod psk;
+pub mod rate;
 
 pub use decoder::PhyDecoder;
 pub use encoder::PhyEncoder;
+pub use fec::FecKind;
 pub use frame::{Frame, FrameType};
 pub use line_coding::LineCodingKind;
+pub use preamble::PreambleKind;
@@ -0,0 +1,166 @@
+// Physical layer parameters that can be tuned without recompiling
+//
+// Defaults are the constants in utils::consts. main builds one PhyParams
+// from the command line and the config file ([phy] section) and hands it
+// to everything that encodes, decodes or senses the channel, so a new
+// speaker / microphone pair can be tried with e.g. --samples-per-level 4.
+// Both ends of a link must agree on the samples per level and the preamble.
+
+use crate::utils::consts::{
+    ENERGY_THRESHOLD, INTER_FRAME_GAP_SAMPLES, PREAMBLE_PATTERN_BYTES,
+    SAMPLES_PER_LEVEL,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhyParams {
+    /// Samples per level (Manchester level or 4B5B bit)
+    pub samples_per_level: usize,
+    /// Number of 0xAA pattern bytes in the preamble
+    pub preamble_pattern_bytes: usize,
+    /// Silence between frames sent back to back
+    pub inter_frame_gap_samples: usize,
+    /// Level above which the channel counts as busy until the noise floor
+    /// is known, see mac::noise
+    pub energy_threshold: f32,
+}
+
+impl Default for PhyParams {
+    fn default() -> Self {
+        Self {
+            samples_per_level: SAMPLES_PER_LEVEL,
+            preamble_pattern_bytes: PREAMBLE_PATTERN_BYTES,
+            inter_frame_gap_samples: INTER_FRAME_GAP_SAMPLES,
+            energy_threshold: ENERGY_THRESHOLD,
+        }
+    }
+}
+
+impl PhyParams {
+    /// Reject values the encoder or the channel sensing can't work with
+    pub fn validate(&self) -> Result<(), String> {
+        if self.samples_per_level == 0 {
+            return Err("samples per level must be at least 1".to_string());
+        }
+        if self.preamble_pattern_bytes == 0 {
+            return Err(
+                "the preamble needs at least one pattern byte".to_string()
+            );
+        }
+        if self.energy_threshold.is_nan() || self.energy_threshold <= 0.0 {
+            return Err(format!(
+                "energy threshold {} is not above zero",
+                self.energy_threshold
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phy::{FecKind, Frame, LineCodingKind, PhyDecoder, PhyEncoder};
+
+    fn roundtrip(params: &PhyParams, kind: LineCodingKind) -> Vec<Frame> {
+        let encoder = PhyEncoder::new(params, kind, FecKind::Hamming74);
+        let mut decoder = PhyDecoder::new(params, kind, FecKind::Hamming74, 2);
+        let frames: Vec<_> = (0..3u8)
+            .map(|seq| Frame::new_data(seq.into(), 1, 2, vec![seq ^ 0x3C; 40]))
+            .collect();
+        let mut samples = vec![0.0; 200];
+        samples.extend(
+            encoder.encode_frames(&frames, params.inter_frame_gap_samples),
+        );
+        samples.extend(vec![0.0; 200]);
+        let decoded = decoder.process_samples(&samples);
+        assert_eq!(decoded.len(), frames.len(), "{:?} {:?}", params, kind);
+        for (frame, got) in frames.iter().zip(&decoded) {
+            assert_eq!(got.sequence, frame.sequence);
+            assert_eq!(got.data, frame.data);
+        }
+        decoded
+    }
+
+    #[test]
+    fn test_symmetric_at_any_samples_per_level() {
+        for samples_per_level in [1, 2, 4, 6] {
+            let params = PhyParams {
+                samples_per_level,
+                preamble_pattern_bytes: 3,
+                inter_frame_gap_samples: 100,
+                ..PhyParams::default()
+            };
+            assert!(params.validate().is_ok());
+            for kind in [
+                LineCodingKind::FourBFiveB,
+                LineCodingKind::Manchester,
+                LineCodingKind::EightBTenB,
+                LineCodingKind::Nrzi,
+            ] {
+                roundtrip(&params, kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_airtime_scales_with_samples_per_level() {
+        let frame = Frame::new_data(1, 1, 2, vec![0xA5; 32]);
+        let airtime = |samples_per_level| {
+            let params = PhyParams {
+                samples_per_level,
+                ..PhyParams::default()
+            };
+            PhyEncoder::new(&params, LineCodingKind::Manchester, FecKind::None)
+                .frame_samples(&frame)
+        };
+        assert_eq!(airtime(6), 2 * airtime(3));
+    }
+
+    #[test]
+    fn test_mismatched_samples_per_level_decodes_nothing() {
+        let frame = Frame::new_data(1, 1, 2, vec![0x42; 32]);
+        let tx = PhyParams {
+            samples_per_level: 6,
+            ..PhyParams::default()
+        };
+        let encoder =
+            PhyEncoder::new(&tx, LineCodingKind::FourBFiveB, FecKind::None);
+        let mut decoder = PhyDecoder::new(
+            &PhyParams::default(),
+            LineCodingKind::FourBFiveB,
+            FecKind::None,
+            2,
+        );
+        let mut samples = encoder.encode_frame(&frame);
+        samples.extend(vec![0.0; 200]);
+        assert!(
+            decoder
+                .process_samples(&samples)
+                .iter()
+                .all(|got| got.data != frame.data)
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_unusable_values() {
+        assert!(
+            PhyParams::default()
+                .validate()
+                .is_ok()
+        );
+        let zero_spl = PhyParams {
+            samples_per_level: 0,
+            ..PhyParams::default()
+        };
+        assert!(zero_spl.validate().is_err());
+        let no_threshold = PhyParams {
+            energy_threshold: 0.0,
+            ..PhyParams::default()
+        };
+        assert!(
+            no_threshold
+                .validate()
+                .is_err()
+        );
+    }
+}
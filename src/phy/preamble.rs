@@ -0,0 +1,128 @@
+// Frame synchronization preambles
+//
+// BytePattern: 0xAA bytes plus a sync byte, sent with the frame's line code.
+// Chirp:       a linear frequency sweep f0 -> f1. Its sharp autocorrelation
+//              peak survives multipath and an unsettled mic AGC better, and
+//              the peak shape gives the frame start to a fraction of a sample.
+
+use crate::utils::consts::{
+    CHIRP_F0_HZ, CHIRP_F1_HZ, CHIRP_LEN_SAMPLES, SAMPLE_RATE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PreambleKind {
+    #[default]
+    BytePattern,
+    /// Sweep from `f0` to `f1` Hz over `len` samples
+    Chirp { f0: f32, f1: f32, len: usize },
+}
+
+impl PreambleKind {
+    /// Chirp with the default band and length from utils::consts
+    pub fn default_chirp() -> Self {
+        Self::Chirp {
+            f0: CHIRP_F0_HZ,
+            f1: CHIRP_F1_HZ,
+            len: CHIRP_LEN_SAMPLES,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BytePattern => "byte pattern",
+            Self::Chirp { .. } => "chirp",
+        }
+    }
+}
+
+/// Linear chirp from `f0` to `f1` Hz, `len` samples at SAMPLE_RATE
+pub fn generate_chirp(f0: f32, f1: f32, len: usize) -> Vec<f32> {
+    let fs = SAMPLE_RATE as f32;
+    let duration = len as f32 / fs;
+    let rate = (f1 - f0) / duration;
+    (0..len)
+        .map(|n| {
+            let t = n as f32 / fs;
+            (2.0 * std::f32::consts::PI * (f0 * t + 0.5 * rate * t * t)).sin()
+        })
+        .collect()
+}
+
+/// Fractional offset of a correlation peak from its neighbours, by fitting
+/// a parabola; within (-0.5, 0.5) for a true local maximum
+pub fn refine_peak(before: f32, peak: f32, after: f32) -> f32 {
+    let curvature = before - 2.0 * peak + after;
+    if curvature.abs() < 1e-9 {
+        return 0.0;
+    }
+    (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+}
+
+/// `samples` read `frac` (0..1) of a sample later, by linear interpolation;
+/// one sample shorter than the input
+pub fn fractional_shift(samples: &[f32], frac: f32) -> Vec<f32> {
+    samples
+        .windows(2)
+        .map(|w| w[0] * (1.0 - frac) + w[1] * frac)
+        .collect()
+}
+
+/// Normalized correlation of `reference` with every window of `signal`
+/// as long as it, in -1..=1
+pub fn cross_correlate(signal: &[f32], reference: &[f32]) -> Vec<f32> {
+    if signal.len() < reference.len() {
+        return Vec::new();
+    }
+    let ref_norm = reference
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt();
+
+    signal
+        .windows(reference.len())
+        .map(|window| {
+            let mut dot = 0.0;
+            let mut energy = 0.0;
+            for (w, r) in window.iter().zip(reference) {
+                dot += w * r;
+                energy += w * w;
+            }
+            if energy > 1e-6 && ref_norm > 1e-6 {
+                dot / (energy.sqrt() * ref_norm)
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chirp_peak_is_sharp_and_refined() {
+        let chirp = generate_chirp(CHIRP_F0_HZ, CHIRP_F1_HZ, CHIRP_LEN_SAMPLES);
+        let mut signal = vec![0.0; 100];
+        signal.extend(&chirp);
+        signal.extend(vec![0.0; 100]);
+        // Move the chirp 0.75 samples earlier
+        let delayed = fractional_shift(&signal, 0.75);
+
+        let corr = cross_correlate(&delayed, &chirp);
+        let peak = corr
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap()
+            .0;
+        assert_eq!(peak, 99);
+        assert!(corr[peak] > 0.9);
+        // Away from the peak the chirp does not correlate
+        assert!(corr[peak + 20].abs() < 0.3);
+
+        let frac = refine_peak(corr[peak - 1], corr[peak], corr[peak + 1]);
+        assert!((peak as f32 + frac - 99.25).abs() < 0.1, "{}", frac);
+    }
+}
@@ -0,0 +1,344 @@
+// BPSK modulation backend
+//
+// Each bit is PSK_SAMPLES_PER_SYMBOL samples of a PSK_CARRIER_HZ sine, as
+// is for 1 and inverted for 0. A frame goes out as the default chirp (see
+// phy::preamble) followed by Frame::to_bits. The receiver finds the chirp by
+// normalized correlation, which gives the carrier phase along with the
+// frame start, then correlates each symbol with the carrier. FEC, the
+// convolutional code, interleaving and body rates belong to the baseband
+// path: frames are sent without them.
+
+use std::f32::consts::PI;
+
+use tracing::debug;
+
+use super::backend::ModulationBackend;
+use super::crc::bits_to_bytes;
+use super::frame::Frame;
+use super::preamble;
+use crate::utils::consts::{
+    CHIRP_CORRELATION_THRESHOLD, CHIRP_F0_HZ, CHIRP_F1_HZ, CHIRP_LEN_SAMPLES,
+    MAX_FRAME_DATA_SIZE, PHY_HEADER_BYTES, PSK_CARRIER_HZ,
+    PSK_SAMPLES_PER_SYMBOL, SAMPLE_RATE,
+};
+use crate::utils::dump::{self, DebugDump};
+
+/// Samples from the first correlation above the threshold searched for the
+/// peak
+const PEAK_SEARCH_SAMPLES: usize = 64;
+
+pub struct PskBackend {
+    /// One symbol of carrier, inverted for 0
+    symbol: Vec<f32>,
+    /// The carrier a quarter period ahead, for the Q of dumped symbols
+    quadrature: Vec<f32>,
+    preamble: Vec<f32>,
+    preamble_energy: f32,
+    /// Received samples not consumed yet
+    buffer: Vec<f32>,
+    /// Where the preamble search resumes in `buffer`
+    search_from: usize,
+    /// Peak of the last preamble dumped, so the retries at its neighbours
+    /// aren't dumped again
+    dumped_peak: Option<usize>,
+}
+
+impl Default for PskBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PskBackend {
+    pub fn new() -> Self {
+        let phase =
+            |n: usize| 2.0 * PI * PSK_CARRIER_HZ * n as f32 / SAMPLE_RATE as f32;
+        let symbol = (0..PSK_SAMPLES_PER_SYMBOL)
+            .map(|n| phase(n).sin())
+            .collect();
+        let quadrature = (0..PSK_SAMPLES_PER_SYMBOL)
+            .map(|n| phase(n).cos())
+            .collect();
+        let preamble = preamble::generate_chirp(
+            CHIRP_F0_HZ,
+            CHIRP_F1_HZ,
+            CHIRP_LEN_SAMPLES,
+        );
+        let preamble_energy = preamble
+            .iter()
+            .map(|s| s * s)
+            .sum();
+        Self {
+            symbol,
+            quadrature,
+            preamble,
+            preamble_energy,
+            buffer: Vec::new(),
+            search_from: 0,
+            dumped_peak: None,
+        }
+    }
+
+    /// Bits on the air: the frame without the baseband body coding
+    fn frame_bits(frame: &Frame) -> Vec<u8> {
+        let mut plain = frame.clone();
+        plain.conv_coded = false;
+        plain.interleave = None;
+        plain.rate = 0;
+        plain.to_bits()
+    }
+
+    fn modulate(&self, frame: &Frame, out: &mut Vec<f32>) {
+        out.extend_from_slice(&self.preamble);
+        for bit in Self::frame_bits(frame) {
+            let sign = if bit == 1 { 1.0 } else { -1.0 };
+            out.extend(
+                self.symbol
+                    .iter()
+                    .map(|s| sign * s),
+            );
+        }
+    }
+
+    /// Whether the buffer holds `count` symbols from `start`
+    fn holds(&self, start: usize, count: usize) -> bool {
+        start + count * PSK_SAMPLES_PER_SYMBOL <= self.buffer.len()
+    }
+
+    /// Correlation of each of the `count` symbols from `start` with
+    /// `carrier`
+    fn correlations(
+        &self,
+        start: usize,
+        count: usize,
+        carrier: &[f32],
+    ) -> Vec<f32> {
+        self.buffer[start..start + count * PSK_SAMPLES_PER_SYMBOL]
+            .chunks(PSK_SAMPLES_PER_SYMBOL)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .zip(carrier)
+                    .map(|(r, s)| r * s)
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Bits of the `count` symbols from `start`
+    fn demodulate(&self, start: usize, count: usize) -> Vec<u8> {
+        self.correlations(start, count, &self.symbol)
+            .into_iter()
+            .map(|dot| (dot > 0.0) as u8)
+            .collect()
+    }
+
+    /// Queue the frame after the preamble at `peak` with the I/Q of its
+    /// `count` symbols from `start`, for --debug-dumps
+    fn dump_failure(
+        &mut self,
+        reason: &'static str,
+        peak: usize,
+        start: usize,
+        count: usize,
+    ) {
+        if !dump::debug_dumps_selected()
+            || self
+                .dumped_peak
+                .is_some_and(|dumped| peak < dumped + PEAK_SEARCH_SAMPLES)
+        {
+            return;
+        }
+        self.dumped_peak = Some(peak);
+        let symbols = self
+            .correlations(start, count, &self.symbol)
+            .into_iter()
+            .zip(self.correlations(start, count, &self.quadrature))
+            .collect();
+        dump::debug_dump(DebugDump {
+            reason,
+            samples: self.buffer[peak..start + count * PSK_SAMPLES_PER_SYMBOL]
+                .to_vec(),
+            sample_rate: SAMPLE_RATE,
+            symbols: Some(symbols),
+            capture: None,
+        });
+    }
+
+    /// Normalized correlation of the preamble with the buffer from `at`
+    fn correlation(&self, at: usize) -> f32 {
+        let window = &self.buffer[at..at + self.preamble.len()];
+        let (dot, energy) = window
+            .iter()
+            .zip(&self.preamble)
+            .fold((0.0, 0.0), |(dot, energy), (r, p)| {
+                (dot + r * p, energy + r * r)
+            });
+        if energy <= f32::EPSILON {
+            return 0.0;
+        }
+        dot / (energy * self.preamble_energy).sqrt()
+    }
+
+    /// Start of the next preamble, None until one has arrived along with
+    /// the samples its peak is searched in
+    fn find_preamble(&mut self) -> Option<usize> {
+        let len = self.preamble.len();
+        while self.search_from + len + PEAK_SEARCH_SAMPLES <= self.buffer.len() {
+            let at = self.search_from;
+            if self.correlation(at) < CHIRP_CORRELATION_THRESHOLD {
+                self.search_from += 1;
+                continue;
+            }
+            return (at..at + PEAK_SEARCH_SAMPLES).max_by(|&a, &b| {
+                self.correlation(a)
+                    .total_cmp(&self.correlation(b))
+            });
+        }
+        None
+    }
+}
+
+impl ModulationBackend for PskBackend {
+    fn name(&self) -> &'static str {
+        "BPSK"
+    }
+
+    fn encode_frames(&self, frames: &[Frame], gap_samples: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            if i > 0 {
+                output.extend(vec![0.0; gap_samples]);
+            }
+            self.modulate(frame, &mut output);
+        }
+        output
+    }
+
+    fn feed_samples(&mut self, samples: &[f32]) -> Vec<Frame> {
+        self.buffer
+            .extend_from_slice(samples);
+        let mut frames = Vec::new();
+        while let Some(peak) = self.find_preamble() {
+            let start = peak + self.preamble.len();
+            let header_bits = 8 * PHY_HEADER_BYTES;
+            if !self.holds(start, header_bits) {
+                self.search_from = peak;
+                break;
+            }
+            let header =
+                Frame::parse_header(&self.demodulate(start, header_bits))
+                    .filter(|header| header.len <= 2 * MAX_FRAME_DATA_SIZE);
+            let Some(header) = header else {
+                debug!("No valid header after the preamble at {}", peak);
+                self.dump_failure("bad-header", peak, start, header_bits);
+                self.search_from = peak + 1;
+                continue;
+            };
+            let bits = 8 * (PHY_HEADER_BYTES + header.body_len());
+            if !self.holds(start, bits) {
+                self.search_from = peak;
+                break;
+            }
+            match Frame::from_bytes(&bits_to_bytes(
+                &self.demodulate(start, bits),
+            )) {
+                Some(frame) => {
+                    frames.push(frame);
+                    self.search_from = start + bits * PSK_SAMPLES_PER_SYMBOL;
+                }
+                None => {
+                    debug!(
+                        "Frame after the preamble at {} failed its check",
+                        peak
+                    );
+                    self.dump_failure("crc", peak, start, bits);
+                    self.search_from = peak + 1;
+                }
+            }
+        }
+        // Nothing before the search point is looked at again
+        self.buffer
+            .drain(..self.search_from);
+        self.dumped_peak = self
+            .dumped_peak
+            .and_then(|peak| peak.checked_sub(self.search_from));
+        self.search_from = 0;
+        frames
+    }
+
+    fn airtime(&self, frame: &Frame) -> usize {
+        self.preamble.len()
+            + Self::frame_bits(frame).len() * PSK_SAMPLES_PER_SYMBOL
+    }
+
+    fn effective_payload_limit(&self) -> usize {
+        MAX_FRAME_DATA_SIZE
+    }
+
+    fn samples_per_symbol(&self) -> usize {
+        PSK_SAMPLES_PER_SYMBOL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn frames() -> Vec<Frame> {
+        (0..3u8)
+            .map(|i| Frame::new_data(i as u16, 1, 2, vec![i ^ 0x5A; 40]))
+            .collect()
+    }
+
+    #[test]
+    fn test_frames_survive_offset_noise_and_chunking() {
+        let backend = PskBackend::new();
+        let frames = frames();
+        let mut rng = StdRng::seed_from_u64(7);
+        // Any offset, so the carrier phase at the receiver is arbitrary
+        let mut samples = vec![0.0; 333];
+        samples.extend(backend.encode_frames(&frames, 200));
+        samples.extend(vec![0.0; 500]);
+        for sample in samples.iter_mut() {
+            *sample = 0.5 * *sample + rng.random_range(-0.1..0.1);
+        }
+        assert_eq!(
+            backend.airtime(&frames[0]) * 3 + 2 * 200 + 333 + 500,
+            samples.len()
+        );
+
+        let mut receiver = PskBackend::new();
+        let mut received = Vec::new();
+        for chunk in samples.chunks(97) {
+            received.extend(receiver.feed_samples(chunk));
+        }
+        assert_eq!(received.len(), frames.len());
+        for (got, sent) in received.iter().zip(&frames) {
+            assert_eq!(got.sequence, sent.sequence);
+            assert_eq!(got.data, sent.data);
+        }
+        // Consumed as it went
+        assert!(receiver.buffer.len() < 500 + CHIRP_LEN_SAMPLES);
+    }
+
+    #[test]
+    fn test_baseband_body_coding_is_not_sent() {
+        let backend = PskBackend::new();
+        let mut frame = Frame::new_data(9, 1, 2, vec![0xC3; 24]);
+        let plain = backend.airtime(&frame);
+        frame.conv_coded = true;
+        frame.rate = 1;
+        assert_eq!(backend.airtime(&frame), plain);
+
+        let mut receiver = PskBackend::new();
+        let mut samples = backend.encode_frame(&frame);
+        samples.extend(vec![0.0; 100]);
+        let received = receiver.feed_samples(&samples);
+        assert_eq!(received.len(), 1);
+        assert!(!received[0].conv_coded);
+        assert_eq!(received[0].data, frame.data);
+    }
+}
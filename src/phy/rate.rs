@@ -0,0 +1,92 @@
+// Per-frame body rate
+//
+// Preamble and header always go out at the link's samples per level, so
+// any receiver can read them. A non-zero rate code in the header means the
+// body (everything after the header) follows at FAST_SAMPLES_PER_LEVEL:
+//
+//   [Preamble] [Header @ base] [Body @ rate]
+//
+// The line code and the FEC stage run separately over header and body, the
+// decoder picks the body's line code per frame. mac::autorate finds the
+// fastest rate a link carries.
+
+use super::line_coding::{LineCode, LineCodingKind};
+use crate::utils::consts::FAST_SAMPLES_PER_LEVEL;
+
+/// Body rate selector in the frame header, 0 = the link's base rate
+pub type RateCode = u8;
+
+/// Highest code a header may carry
+pub const MAX_RATE_CODE: RateCode = FAST_SAMPLES_PER_LEVEL.len() as RateCode;
+
+/// Samples per level of the body at `rate`, None for unknown codes
+pub fn samples_per_level(rate: RateCode, base: usize) -> Option<usize> {
+    match rate {
+        0 => Some(base),
+        _ => FAST_SAMPLES_PER_LEVEL
+            .get(rate as usize - 1)
+            .copied(),
+    }
+}
+
+/// Line code for the body of a frame at `rate`, None at the base rate
+pub fn body_line_code(
+    kind: LineCodingKind,
+    rate: RateCode,
+) -> Option<Box<dyn LineCode>> {
+    if rate == 0 {
+        return None;
+    }
+    samples_per_level(rate, 0).map(|spl| kind.create(spl))
+}
+
+/// Rough SNR of a received frame in dB: the line codes send two levels
+/// ±A, so the spread of |x| around its mean is taken as noise
+pub fn estimate_snr_db(samples: &[f32]) -> f32 {
+    const MAX_SNR_DB: f32 = 60.0;
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let n = samples.len() as f32;
+    let level = samples
+        .iter()
+        .map(|s| s.abs())
+        .sum::<f32>()
+        / n;
+    let noise = samples
+        .iter()
+        .map(|s| (s.abs() - level).powi(2))
+        .sum::<f32>()
+        / n;
+    if noise <= f32::EPSILON * level * level {
+        return MAX_SNR_DB;
+    }
+    (10.0 * (level * level / noise).log10()).clamp(-MAX_SNR_DB, MAX_SNR_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snr_estimate_tracks_noise() {
+        let clean: Vec<f32> = (0..1200)
+            .map(|i| if i % 6 < 3 { 1.0 } else { -1.0 })
+            .collect();
+        // Deterministic ±0.1 and ±0.5 disturbances
+        let noisy = |amp: f32| -> Vec<f32> {
+            clean
+                .iter()
+                .enumerate()
+                .map(|(i, s)| s + if i % 2 == 0 { amp } else { -amp })
+                .collect()
+        };
+        let high = estimate_snr_db(&noisy(0.1));
+        let low = estimate_snr_db(&noisy(0.5));
+        assert_eq!(estimate_snr_db(&clean), 60.0);
+        assert!((high - 20.0).abs() < 1.0, "{}", high);
+        assert!(low < high);
+        assert_eq!(samples_per_level(0, 3), Some(3));
+        assert_eq!(samples_per_level(MAX_RATE_CODE + 1, 3), None);
+    }
+}
@@ -0,0 +1,655 @@
+// Live terminal dashboard (--tui on tx, rx and router)
+//
+// The MAC thread publishes DashboardEvents on a channel: a status snapshot
+// at most every DASHBOARD_STATUS_MS (audio state, buffer fill, signal,
+// link counters) and every CSMA state change; log lines come in through
+// the tracing writer from log_writer. The dashboard thread folds them into
+// a DashboardState and redraws the whole screen with plain ANSI escapes
+// every DASHBOARD_REFRESH_MS. Nothing here touches the audio buffers.
+// Without a terminal on stdout start() gives None and the progress bars
+// stay in charge. On exit the tail of the log pane is left on stderr.
+
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+use crate::audio::recorder::{AppShared, AppState};
+use crate::audio::ring::SampleRing;
+use crate::mac::stats::LinkStats;
+use crate::phy::decoder::DecodeStats;
+use crate::utils::consts::{
+    DASHBOARD_HISTORY, DASHBOARD_LOG_LINES, DASHBOARD_REFRESH_MS,
+    DASHBOARD_STATUS_MS, DASHBOARD_TRANSITIONS,
+};
+
+/// What the MAC thread knows about its link at one moment
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkStatus {
+    pub app_state: AppState,
+    /// Share of the record / playback ring in use, 0 to 1
+    pub record_fill: f32,
+    pub playback_fill: f32,
+    /// Of the latest frame decoded, see phy::rate
+    pub snr_db: f32,
+    /// Of the latest samples decoded
+    pub rms: f32,
+    pub stats: LinkStats,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DashboardEvent {
+    Status(Box<LinkStatus>),
+    /// The CSMA state machine moved to this state
+    Csma(&'static str),
+    Log(String),
+}
+
+impl LinkStatus {
+    /// Taken on the MAC thread, which owns `decode` and `stats`
+    pub fn snapshot(
+        shared: &AppShared,
+        decode: Option<DecodeStats>,
+        stats: LinkStats,
+    ) -> Self {
+        let fill = |ring: &SampleRing| {
+            ring.len() as f32 / ring.capacity().max(1) as f32
+        };
+        let decode = decode.unwrap_or_default();
+        Self {
+            app_state: shared.app_state.get(),
+            record_fill: fill(&shared.record_buffer),
+            playback_fill: fill(&shared.playback_buffer),
+            snr_db: decode.last_snr_db,
+            rms: decode.last_signal_rms,
+            stats,
+        }
+    }
+}
+
+/// The publishing end, held by the MAC
+pub struct DashboardFeed {
+    events: Sender<DashboardEvent>,
+    last_status: Option<Instant>,
+    csma: Option<&'static str>,
+}
+
+impl DashboardFeed {
+    pub fn new(events: Sender<DashboardEvent>) -> Self {
+        Self {
+            events,
+            last_status: None,
+            csma: None,
+        }
+    }
+
+    /// Whether DASHBOARD_STATUS_MS has passed since the last status
+    pub fn status_due(&self) -> bool {
+        self.last_status
+            .is_none_or(|at| {
+                at.elapsed() >= Duration::from_millis(DASHBOARD_STATUS_MS)
+            })
+    }
+
+    pub fn status(&mut self, status: LinkStatus) {
+        self.last_status = Some(Instant::now());
+        let _ = self
+            .events
+            .send(DashboardEvent::Status(Box::new(status)));
+    }
+
+    /// Publish `state` if the CSMA state machine wasn't in it already
+    pub fn csma(&mut self, state: &'static str) {
+        if self.csma != Some(state) {
+            self.csma = Some(state);
+            let _ = self
+                .events
+                .send(DashboardEvent::Csma(state));
+        }
+    }
+}
+
+/// A counter the publisher may reset (the router does every few seconds),
+/// summed across resets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunningTotal {
+    base: u64,
+    last: u64,
+}
+
+impl RunningTotal {
+    pub fn update(&mut self, value: u64) {
+        if value < self.last {
+            self.base += self.last;
+        }
+        self.last = value;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.base + self.last
+    }
+}
+
+/// Everything the widgets show, built from the events alone
+#[derive(Debug, Clone, Default)]
+pub struct DashboardState {
+    pub status: Option<LinkStatus>,
+    /// Recent SNR and RMS readings, oldest first
+    pub snr_history: VecDeque<f32>,
+    pub rms_history: VecDeque<f32>,
+    pub frames_sent: RunningTotal,
+    pub frames_received: RunningTotal,
+    pub retransmissions: RunningTotal,
+    pub crc_failures: RunningTotal,
+    pub csma_state: Option<&'static str>,
+    /// (time since start, from, to), oldest first
+    pub transitions: VecDeque<(Duration, &'static str, &'static str)>,
+    pub log: VecDeque<String>,
+}
+
+fn push_bounded<T>(queue: &mut VecDeque<T>, item: T, limit: usize) {
+    queue.push_back(item);
+    while queue.len() > limit {
+        queue.pop_front();
+    }
+}
+
+impl DashboardState {
+    /// Fold in `event`, which arrived `at` after the dashboard started
+    pub fn apply(&mut self, event: DashboardEvent, at: Duration) {
+        match event {
+            DashboardEvent::Status(status) => {
+                // The signal readings only change with a new decode
+                if self
+                    .status
+                    .as_ref()
+                    .is_none_or(|last| {
+                        last.snr_db != status.snr_db || last.rms != status.rms
+                    })
+                {
+                    push_bounded(
+                        &mut self.snr_history,
+                        status.snr_db,
+                        DASHBOARD_HISTORY,
+                    );
+                    push_bounded(
+                        &mut self.rms_history,
+                        status.rms,
+                        DASHBOARD_HISTORY,
+                    );
+                }
+                self.frames_sent
+                    .update(status.stats.frames_sent);
+                self.frames_received
+                    .update(status.stats.frames_received);
+                self.retransmissions
+                    .update(status.stats.retransmissions);
+                self.crc_failures
+                    .update(status.stats.crc_failures);
+                self.status = Some(*status);
+            }
+            DashboardEvent::Csma(state) => {
+                if self.csma_state == Some(state) {
+                    return;
+                }
+                if let Some(from) = self.csma_state {
+                    push_bounded(
+                        &mut self.transitions,
+                        (at, from, state),
+                        DASHBOARD_TRANSITIONS,
+                    );
+                }
+                self.csma_state = Some(state);
+            }
+            DashboardEvent::Log(line) => {
+                for line in line
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                {
+                    push_bounded(
+                        &mut self.log,
+                        line.to_string(),
+                        DASHBOARD_LOG_LINES,
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn app_state_name(state: AppState) -> &'static str {
+    match state {
+        AppState::Recording => "Recording",
+        AppState::Playing => "Playing",
+        AppState::Idle => "Idle",
+        AppState::RecordingAndPlaying => "Recording+Playing",
+    }
+}
+
+/// `[#####-----]  50%`, `width` cells between the brackets
+fn fill_bar(fill: f32, width: usize) -> String {
+    let fill = fill.clamp(0.0, 1.0);
+    let full = (fill * width as f32).round() as usize;
+    format!(
+        "[{}{}] {:3.0}%",
+        "#".repeat(full),
+        "-".repeat(width - full),
+        fill * 100.0
+    )
+}
+
+/// The last `width` values as block characters, scaled to their range
+fn sparkline(values: &VecDeque<f32>, width: usize) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let values: Vec<f32> = values
+        .iter()
+        .skip(
+            values
+                .len()
+                .saturating_sub(width),
+        )
+        .copied()
+        .filter(|v| v.is_finite())
+        .collect();
+    let low = values
+        .iter()
+        .copied()
+        .fold(f32::INFINITY, f32::min);
+    let high = values
+        .iter()
+        .copied()
+        .fold(f32::NEG_INFINITY, f32::max);
+    values
+        .iter()
+        .map(|v| {
+            let level = if high > low {
+                (v - low) / (high - low) * (BLOCKS.len() - 1) as f32
+            } else {
+                0.0
+            };
+            BLOCKS[level.round() as usize]
+        })
+        .collect()
+}
+
+fn clock(at: Duration) -> String {
+    let secs = at.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        at.subsec_millis()
+    )
+}
+
+/// `line` cut to `width` characters
+fn fit(line: String, width: usize) -> String {
+    match line.char_indices().nth(width) {
+        Some((cut, _)) => line[..cut].to_string(),
+        None => line,
+    }
+}
+
+/// The screen as `height` lines of at most `width` characters
+pub fn render(
+    state: &DashboardState,
+    title: &str,
+    uptime: Duration,
+    width: usize,
+    height: usize,
+) -> Vec<String> {
+    let rule = |name: &str| {
+        let head = format!("── {} ", name);
+        let rest = width.saturating_sub(head.chars().count());
+        format!("{}{}", head, "─".repeat(rest))
+    };
+    let bar_width = width
+        .saturating_sub(20)
+        .clamp(10, 40);
+
+    let mut lines = vec![format!(
+        "TrackMaker-rs {}  up {}",
+        title,
+        &clock(uptime)[..8]
+    )];
+    match &state.status {
+        Some(status) => {
+            lines.push(format!(
+                "Audio: {:<18} CSMA: {}",
+                app_state_name(status.app_state),
+                state
+                    .csma_state
+                    .unwrap_or("-")
+            ));
+            lines.push(format!(
+                "Record   {}",
+                fill_bar(status.record_fill, bar_width)
+            ));
+            lines.push(format!(
+                "Playback {}",
+                fill_bar(status.playback_fill, bar_width)
+            ));
+            lines.push(format!(
+                "SNR {:5.1} dB {}",
+                status.snr_db,
+                sparkline(&state.snr_history, bar_width)
+            ));
+            lines.push(format!(
+                "RMS {:8.4} {}",
+                status.rms,
+                sparkline(&state.rms_history, bar_width)
+            ));
+        }
+        None => lines.push("Waiting for the link...".to_string()),
+    }
+    lines.push(format!(
+        "Frames: sent {}  received {}  retransmitted {}  CRC failed {}",
+        state.frames_sent.total(),
+        state.frames_received.total(),
+        state.retransmissions.total(),
+        state.crc_failures.total()
+    ));
+
+    // What is left is split between the transitions and the log
+    let room = height.saturating_sub(lines.len() + 2);
+    let transitions_room = (room / 3).min(state.transitions.len());
+    lines.push(rule("CSMA"));
+    lines.extend(
+        state
+            .transitions
+            .iter()
+            .skip(state.transitions.len() - transitions_room)
+            .map(|(at, from, to)| format!("{} {} -> {}", clock(*at), from, to)),
+    );
+    lines.push(rule("Log"));
+    let log_room = height.saturating_sub(lines.len());
+    lines.extend(
+        state
+            .log
+            .iter()
+            .skip(
+                state
+                    .log
+                    .len()
+                    .saturating_sub(log_room),
+            )
+            .cloned(),
+    );
+
+    lines.truncate(height);
+    lines
+        .into_iter()
+        .map(|line| fit(line, width))
+        .collect()
+}
+
+/// Columns and rows of the terminal on stdout, 80x24 if unknown
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe {
+        libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0
+    };
+    if ok && size.ws_col > 0 && size.ws_row > 0 {
+        (size.ws_col as usize, size.ws_row as usize)
+    } else {
+        (80, 24)
+    }
+}
+
+/// Sends what tracing writes to the log pane
+struct LogWriter(Sender<DashboardEvent>);
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf).into_owned();
+        // Gone once the dashboard stopped, the line is dropped then
+        let _ = self
+            .0
+            .send(DashboardEvent::Log(line));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Dashboard {
+    events: Sender<DashboardEvent>,
+    running: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal, None if stdout isn't one
+    pub fn start(title: &str) -> Option<Self> {
+        if !io::stdout().is_terminal() {
+            return None;
+        }
+        let (events, rx) = crossbeam_channel::unbounded();
+        let running = Arc::new(AtomicBool::new(true));
+        let handle = {
+            let running = running.clone();
+            let title = title.to_string();
+            thread::spawn(move || draw_loop(&title, rx, &running))
+        };
+        Some(Self {
+            events,
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    /// For the MAC thread to publish on
+    pub fn sender(&self) -> Sender<DashboardEvent> {
+        self.events.clone()
+    }
+
+    /// For init_logging_to, log lines go to the log pane
+    pub fn log_writer(&self) -> BoxMakeWriter {
+        let events = self.events.clone();
+        BoxMakeWriter::new(move || LogWriter(events.clone()))
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.running
+            .store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn draw_loop(
+    title: &str,
+    events: Receiver<DashboardEvent>,
+    running: &AtomicBool,
+) {
+    let start = Instant::now();
+    let mut state = DashboardState::default();
+    let mut out = io::stdout();
+    // Alternate screen, cursor hidden
+    let _ = write!(out, "\x1b[?1049h\x1b[?25l");
+    while running.load(Ordering::SeqCst) {
+        let frame_end =
+            Instant::now() + Duration::from_millis(DASHBOARD_REFRESH_MS);
+        while let Ok(event) = events.recv_deadline(frame_end) {
+            state.apply(event, start.elapsed());
+        }
+        let (width, height) = terminal_size();
+        let mut screen = String::from("\x1b[H");
+        for line in render(&state, title, start.elapsed(), width, height) {
+            screen.push_str(&line);
+            screen.push_str("\x1b[K\r\n");
+        }
+        screen.push_str("\x1b[J");
+        let _ = out.write_all(screen.as_bytes());
+        let _ = out.flush();
+    }
+    let _ = write!(out, "\x1b[?25h\x1b[?1049l");
+    let _ = out.flush();
+
+    // The alternate screen is gone, leave the end of the log behind
+    for event in events.try_iter() {
+        state.apply(event, start.elapsed());
+    }
+    let (_, height) = terminal_size();
+    let mut err = io::stderr().lock();
+    for line in state.log.iter().skip(
+        state
+            .log
+            .len()
+            .saturating_sub(height.saturating_sub(1)),
+    ) {
+        let _ = writeln!(err, "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(stats: LinkStats, snr_db: f32) -> DashboardEvent {
+        DashboardEvent::Status(Box::new(LinkStatus {
+            app_state: AppState::Recording,
+            record_fill: 0.25,
+            playback_fill: 0.0,
+            snr_db,
+            rms: 0.1,
+            stats,
+        }))
+    }
+
+    #[test]
+    fn test_totals_survive_resets() {
+        let mut state = DashboardState::default();
+        let sent = |frames_sent, retransmissions| LinkStats {
+            frames_sent,
+            retransmissions,
+            ..LinkStats::default()
+        };
+        for (frames, retransmissions) in [(3, 0), (7, 1), (2, 0), (5, 2)] {
+            state.apply(
+                status(sent(frames, retransmissions), 10.0),
+                Duration::ZERO,
+            );
+        }
+        // 7 before the reset, 5 after it
+        assert_eq!(state.frames_sent.total(), 12);
+        assert_eq!(state.retransmissions.total(), 3);
+        assert_eq!(state.frames_received.total(), 0);
+    }
+
+    #[test]
+    fn test_history_and_transitions_bounded() {
+        let mut state = DashboardState::default();
+        for i in 0..DASHBOARD_HISTORY + 10 {
+            state.apply(status(LinkStats::default(), i as f32), Duration::ZERO);
+        }
+        // Repeated readings are not new ones
+        state.apply(
+            status(LinkStats::default(), (DASHBOARD_HISTORY + 9) as f32),
+            Duration::ZERO,
+        );
+        assert_eq!(state.snr_history.len(), DASHBOARD_HISTORY);
+        assert_eq!(state.snr_history.front(), Some(&10.0));
+
+        let states = [
+            "Sensing",
+            "WaitingForDIFS",
+            "Backoff",
+            "Backoff",
+            "Transmitting",
+        ];
+        for (i, name) in states.iter().enumerate() {
+            state.apply(
+                DashboardEvent::Csma(name),
+                Duration::from_millis(i as u64),
+            );
+        }
+        let transitions: Vec<_> = state
+            .transitions
+            .iter()
+            .map(|(_, from, to)| (*from, *to))
+            .collect();
+        assert_eq!(
+            transitions,
+            vec![
+                ("Sensing", "WaitingForDIFS"),
+                ("WaitingForDIFS", "Backoff"),
+                ("Backoff", "Transmitting"),
+            ]
+        );
+        assert_eq!(state.transitions[2].0, Duration::from_millis(4));
+
+        for i in 0..DASHBOARD_LOG_LINES + 5 {
+            state.apply(
+                DashboardEvent::Log(format!("line {}\n", i)),
+                Duration::ZERO,
+            );
+        }
+        assert_eq!(state.log.len(), DASHBOARD_LOG_LINES);
+        assert_eq!(
+            state.log.back().unwrap(),
+            &format!("line {}", DASHBOARD_LOG_LINES + 4)
+        );
+    }
+
+    #[test]
+    fn test_render_fits_the_screen() {
+        let mut state = DashboardState::default();
+        let lines = render(&state, "rx", Duration::ZERO, 60, 12);
+        assert!(lines[1].starts_with("Waiting"));
+
+        state.apply(
+            status(
+                LinkStats {
+                    frames_sent: 4,
+                    frames_received: 9,
+                    ..LinkStats::default()
+                },
+                12.5,
+            ),
+            Duration::ZERO,
+        );
+        state.apply(DashboardEvent::Csma("Sensing"), Duration::ZERO);
+        state.apply(DashboardEvent::Csma("Backoff"), Duration::from_secs(61));
+        for i in 0..30 {
+            state.apply(
+                DashboardEvent::Log(format!("log {} {}", i, "x".repeat(80))),
+                Duration::ZERO,
+            );
+        }
+        let lines = render(&state, "rx", Duration::from_secs(3723), 60, 20);
+        assert_eq!(lines.len(), 20);
+        assert!(
+            lines
+                .iter()
+                .all(|line| line.chars().count() <= 60)
+        );
+        assert!(lines[0].ends_with("up 01:02:03"));
+        assert!(lines[1].contains("Recording") && lines[1].contains("Backoff"));
+        assert!(lines[2].contains("[") && lines[2].ends_with(" 25%"));
+        assert!(lines[4].starts_with("SNR  12.5 dB"));
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.contains("sent 4  received 9"))
+        );
+        assert!(
+            lines
+                .iter()
+                .any(|line| line == "00:01:01.000 Sensing -> Backoff")
+        );
+        // The newest log lines, as many as fit
+        assert!(lines[19].starts_with("log 29 "));
+    }
+}
@@ -1,9 +1,10 @@
 use crate::audio::recorder::{AppShared, AppState};
+pub mod dashboard;
 pub mod progress;
 use crate::ui::progress::ProgressManager;
 
 pub fn print_banner() {
-    println!("TrackMaker-rs");
+    eprintln!("TrackMaker-rs");
 }
 
 pub fn update_progress(
@@ -11,34 +12,16 @@ pub fn update_progress(
     recording_duration_samples: usize,
     progress_manager: &ProgressManager,
 ) {
-    let current_state = {
-        let state = shared
-            .app_state
-            .lock()
-            .unwrap();
-        state.clone()
-    };
+    let current_state = shared.app_state.get();
 
     match current_state {
         AppState::Recording => {
-            let recorded_samples = {
-                let recorded = shared
-                    .record_buffer
-                    .lock()
-                    .unwrap();
-                recorded.len()
-            };
+            let recorded_samples = shared.record_buffer.len();
             let _ = progress_manager
                 .set_position("recording", recorded_samples as u64);
         }
         AppState::Playing => {
-            let remaining_samples = {
-                let playback = shared
-                    .playback_buffer
-                    .lock()
-                    .unwrap();
-                playback.len()
-            };
+            let remaining_samples = shared.playback_buffer.len();
             // 防止下溢：确保 remaining_samples 不会超过 recording_duration_samples
             let played_samples =
                 if remaining_samples > recording_duration_samples {
@@ -50,13 +33,7 @@ pub fn update_progress(
                 progress_manager.set_position("playback", played_samples as u64);
         }
         AppState::RecordingAndPlaying => {
-            let recorded_samples = {
-                let recorded = shared
-                    .record_buffer
-                    .lock()
-                    .unwrap();
-                recorded.len()
-            };
+            let recorded_samples = shared.record_buffer.len();
             let _ = progress_manager
                 .set_position("playrec", recorded_samples as u64);
         }
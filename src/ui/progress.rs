@@ -1,10 +1,66 @@
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{
+    MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
+};
 use std::collections::HashMap;
+use std::fmt::Write;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::phy::frame::SeqType;
+
+/// What a bar built by add_bytes_bar / add_frames_bar counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Bytes,
+    Frames,
+}
+
+impl BarKind {
+    /// `amount` in the bar's unit
+    pub fn amount(self, amount: u64) -> String {
+        match self {
+            BarKind::Bytes => format_bytes(amount),
+            BarKind::Frames if amount == 1 => "1 frame".to_string(),
+            BarKind::Frames => format!("{} frames", amount),
+        }
+    }
+
+    /// `per_sec` in the bar's unit
+    pub fn rate(self, per_sec: f64) -> String {
+        match self {
+            BarKind::Bytes => format!("{}/s", format_bytes(per_sec as u64)),
+            BarKind::Frames => format!("{:.1} frames/s", per_sec),
+        }
+    }
+}
+
+/// Frames sent again on a bar, shown next to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retransmissions {
+    pub count: u64,
+    /// Of the latest retransmission
+    pub last_sequence: Option<SeqType>,
+}
+
+impl std::fmt::Display for Retransmissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.last_sequence {
+            Some(seq) => write!(f, "{} retx (last seq {})", self.count, seq),
+            None => write!(f, "{} retx", self.count),
+        }
+    }
+}
+
+struct TypedBar {
+    kind: BarKind,
+    retransmissions: Arc<Mutex<Retransmissions>>,
+}
 
 pub struct ProgressManager {
     mp: MultiProgress,
     bars: Arc<Mutex<HashMap<String, ProgressBar>>>,
+    /// Bars built by add_bytes_bar / add_frames_bar
+    typed: Mutex<HashMap<String, TypedBar>>,
 }
 
 impl ProgressManager {
@@ -12,6 +68,139 @@ impl ProgressManager {
         Self {
             mp: MultiProgress::new(),
             bars: Arc::new(Mutex::new(HashMap::new())),
+            typed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bars that keep count but are never drawn, e.g. under --tui
+    pub fn hidden() -> Self {
+        Self {
+            mp: MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            bars: Arc::new(Mutex::new(HashMap::new())),
+            typed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A bar counting `total_bytes` (0 = not known yet, see set_length)
+    /// with the transfer rate and ETA
+    pub fn add_bytes_bar(
+        &self,
+        id: &str,
+        total_bytes: u64,
+    ) -> Result<(), String> {
+        self.add_typed_bar(id, total_bytes, BarKind::Bytes)
+    }
+
+    /// A bar counting `total_frames` (0 = not known yet) with the frame
+    /// rate, ETA and retransmissions, see note_retransmissions
+    pub fn add_frames_bar(
+        &self,
+        id: &str,
+        total_frames: u64,
+    ) -> Result<(), String> {
+        self.add_typed_bar(id, total_frames, BarKind::Frames)
+    }
+
+    fn add_typed_bar(
+        &self,
+        id: &str,
+        total: u64,
+        kind: BarKind,
+    ) -> Result<(), String> {
+        let mut bars = self
+            .bars
+            .lock()
+            .map_err(|e| format!("Lock error: {}", e))?;
+        if bars.contains_key(id) {
+            return Err(format!("Progress bar '{}' already exists", id));
+        }
+
+        let retransmissions = Arc::new(Mutex::new(Retransmissions::default()));
+        let retx = retransmissions.clone();
+        let style = ProgressStyle::default_bar()
+            .template(templates::TYPED)
+            .unwrap()
+            .progress_chars("█▉▊▋▌▍▎▏ ")
+            .with_key(
+                "amount",
+                move |state: &ProgressState, w: &mut dyn Write| {
+                    let len = state.len().unwrap_or(0);
+                    let _ =
+                        write!(w, "{}", format_progress(kind, state.pos(), len));
+                },
+            )
+            .with_key("rate", move |state: &ProgressState, w: &mut dyn Write| {
+                let _ = write!(w, "{}", kind.rate(state.per_sec()));
+            })
+            .with_key("eta_left", |state: &ProgressState, w: &mut dyn Write| {
+                let left =
+                    eta(state.pos(), state.len().unwrap_or(0), state.per_sec());
+                let _ = write!(w, "ETA {}", format_eta(left));
+            })
+            .with_key("retx", move |_: &ProgressState, w: &mut dyn Write| {
+                if let Ok(retx) = retx.lock()
+                    && retx.count > 0
+                {
+                    let _ = write!(w, " · {}", retx);
+                }
+            });
+        let pb = self.mp.add(if total > 0 {
+            ProgressBar::new(total)
+        } else {
+            ProgressBar::no_length()
+        });
+        pb.set_style(style);
+        pb.set_prefix(id.to_string());
+
+        bars.insert(id.to_string(), pb);
+        self.typed
+            .lock()
+            .map_err(|e| format!("Lock error: {}", e))?
+            .insert(
+                id.to_string(),
+                TypedBar {
+                    kind,
+                    retransmissions,
+                },
+            );
+        Ok(())
+    }
+
+    /// Count `count` frames sent again on bar `id`, the latest with
+    /// sequence `sequence`
+    pub fn note_retransmissions(
+        &self,
+        id: &str,
+        count: u64,
+        sequence: SeqType,
+    ) -> Result<(), String> {
+        let typed = self
+            .typed
+            .lock()
+            .map_err(|e| format!("Lock error: {}", e))?;
+        let bar = typed
+            .get(id)
+            .ok_or_else(|| format!("Progress bar '{}' not found", id))?;
+        let mut retx = bar
+            .retransmissions
+            .lock()
+            .map_err(|e| format!("Lock error: {}", e))?;
+        retx.count += count;
+        retx.last_sequence = Some(sequence);
+        Ok(())
+    }
+
+    /// Total of bar `id`, e.g. once a bytes bar's size is known
+    pub fn set_length(&self, id: &str, total: u64) -> Result<(), String> {
+        let bars = self
+            .bars
+            .lock()
+            .map_err(|e| format!("Lock error: {}", e))?;
+        if let Some(pb) = bars.get(id) {
+            pb.set_length(total);
+            Ok(())
+        } else {
+            Err(format!("Progress bar '{}' not found", id))
         }
     }
 
@@ -125,17 +314,43 @@ impl ProgressManager {
     }
 
     /// 完成进度条（保留显示）
-    pub fn finish(&self, id: &str, message: &str) -> Result<(), String> {
+    ///
+    /// Bars built by add_bytes_bar / add_frames_bar get a summary after
+    /// `message`, reached the total or not. Returns the final message.
+    pub fn finish(&self, id: &str, message: &str) -> Result<String, String> {
         let bars = self
             .bars
             .lock()
             .map_err(|e| format!("Lock error: {}", e))?;
-        if let Some(pb) = bars.get(id) {
-            pb.finish_with_message(message.to_string());
-            Ok(())
-        } else {
-            Err(format!("Progress bar '{}' not found", id))
-        }
+        let Some(pb) = bars.get(id) else {
+            return Err(format!("Progress bar '{}' not found", id));
+        };
+        let typed = self
+            .typed
+            .lock()
+            .map_err(|e| format!("Lock error: {}", e))?;
+        let message = match typed.get(id) {
+            Some(bar) => {
+                let retx = *bar
+                    .retransmissions
+                    .lock()
+                    .map_err(|e| format!("Lock error: {}", e))?;
+                format!(
+                    "{}: {}",
+                    message,
+                    summary(
+                        bar.kind,
+                        pb.position(),
+                        pb.length().unwrap_or(0),
+                        pb.elapsed(),
+                        retx,
+                    )
+                )
+            }
+            None => message.to_string(),
+        };
+        pb.finish_with_message(message.clone());
+        Ok(message)
     }
 
     /// 检查进度条是否存在
@@ -185,13 +400,184 @@ impl Default for ProgressManager {
     }
 }
 
+/// `bytes` in B, KiB or MiB
+pub fn format_bytes(bytes: u64) -> String {
+    const KIB: f64 = 1024.0;
+    let value = bytes as f64;
+    if value < KIB {
+        format!("{} B", bytes)
+    } else if value < KIB * KIB {
+        format!("{:.1} KiB", value / KIB)
+    } else {
+        format!("{:.1} MiB", value / (KIB * KIB))
+    }
+}
+
+/// `1h02m03s`, `2m03s` or `3s`, `--` if unknown
+pub fn format_eta(eta: Option<Duration>) -> String {
+    let Some(eta) = eta else {
+        return "--".to_string();
+    };
+    let secs = eta.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+/// Time left to get from `pos` to `len` at `per_sec`, None without a total
+/// or a rate yet
+pub fn eta(pos: u64, len: u64, per_sec: f64) -> Option<Duration> {
+    if len == 0 || per_sec <= 0.0 || !per_sec.is_finite() {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        len.saturating_sub(pos) as f64 / per_sec,
+    ))
+}
+
+/// `3/4 frames`, `1.0 KiB/4.0 KiB`, just `pos` without a total
+pub fn format_progress(kind: BarKind, pos: u64, len: u64) -> String {
+    match kind {
+        _ if len == 0 => kind.amount(pos),
+        BarKind::Frames => format!("{}/{}", pos, kind.amount(len)),
+        BarKind::Bytes => format!("{}/{}", format_bytes(pos), format_bytes(len)),
+    }
+}
+
+/// `3/4 frames in 2.0 s, 1.5 frames/s, 2 retx (last seq 7)`
+pub fn summary(
+    kind: BarKind,
+    pos: u64,
+    len: u64,
+    elapsed: Duration,
+    retransmissions: Retransmissions,
+) -> String {
+    let secs = elapsed.as_secs_f64();
+    let mut summary = format!(
+        "{} in {:.1} s, {} average",
+        format_progress(kind, pos, len),
+        secs,
+        kind.rate(pos as f64 / secs.max(f64::EPSILON))
+    );
+    if retransmissions.count > 0 {
+        summary.push_str(&format!(", {}", retransmissions));
+    }
+    summary
+}
+
 pub mod templates {
     pub const RECORDING: &str =
         "\u{f94a} REC  [{bar:30.red}] {percent}% ({pos}/{len} samples) {msg}";
     pub const PLAYBACK: &str =
         "\u{f909} PLAY [{bar:30.green}] {percent}% ({pos}/{len} samples) {msg}";
-    pub const SENDER: &str =
-        "\u{f048a} SEND [{bar:30.cyan}] {percent}% ({pos}/{len} frames) {msg}";
-    pub const RECEIVER: &str =
-        "\u{f04e6} RECV [{bar:30.blue}] {percent}% ({pos}/{len} frames) {msg}";
+    /// add_bytes_bar / add_frames_bar, the keys besides prefix and msg are
+    /// filled in by ProgressManager
+    pub const TYPED: &str = "{prefix:>8} [{bar:30.cyan}] {percent:>3}% \
+         {amount} {rate} {eta_left}{retx} {msg}";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0 MiB");
+        assert_eq!(BarKind::Bytes.rate(2048.0), "2.0 KiB/s");
+        assert_eq!(BarKind::Frames.rate(2.25), "2.2 frames/s");
+        assert_eq!(BarKind::Frames.amount(1), "1 frame");
+
+        assert_eq!(format_eta(None), "--");
+        assert_eq!(format_eta(Some(Duration::from_secs(7))), "7s");
+        assert_eq!(format_eta(Some(Duration::from_secs(125))), "2m05s");
+        assert_eq!(format_eta(Some(Duration::from_secs(3723))), "1h02m03s");
+
+        assert_eq!(eta(25, 100, 5.0), Some(Duration::from_secs(15)));
+        // No total or no rate yet
+        assert_eq!(eta(25, 0, 5.0), None);
+        assert_eq!(eta(0, 100, 0.0), None);
+
+        let retx = Retransmissions {
+            count: 2,
+            last_sequence: Some(7),
+        };
+        assert_eq!(
+            summary(BarKind::Frames, 3, 4, Duration::from_secs(2), retx),
+            "3/4 frames in 2.0 s, 1.5 frames/s average, 2 retx (last seq 7)"
+        );
+        assert_eq!(
+            summary(
+                BarKind::Bytes,
+                2048,
+                0,
+                Duration::from_secs(4),
+                Retransmissions::default()
+            ),
+            "2.0 KiB in 4.0 s, 512 B/s average"
+        );
+    }
+
+    #[test]
+    fn test_finish_summarizes_short_transfers() {
+        let progress = ProgressManager::hidden();
+        progress
+            .add_bytes_bar("file", 4096)
+            .unwrap();
+        progress
+            .add_frames_bar("sender", 10)
+            .unwrap();
+        assert!(
+            progress
+                .add_frames_bar("sender", 1)
+                .is_err()
+        );
+
+        progress
+            .inc("file", 1024)
+            .unwrap();
+        let message = progress
+            .finish("file", "Incomplete")
+            .unwrap();
+        assert!(
+            message.starts_with("Incomplete: 1.0 KiB/4.0 KiB in "),
+            "{}",
+            message
+        );
+        assert!(message.ends_with("/s average"), "{}", message);
+
+        progress
+            .inc("sender", 3)
+            .unwrap();
+        progress
+            .note_retransmissions("sender", 2, 5)
+            .unwrap();
+        progress
+            .note_retransmissions("sender", 1, 9)
+            .unwrap();
+        let message = progress
+            .finish("sender", "Timed out")
+            .unwrap();
+        assert!(message.starts_with("Timed out: 3/10 frames in "));
+        assert!(message.ends_with(", 3 retx (last seq 9)"), "{}", message);
+
+        // Bars made with create_bar keep their plain message
+        progress
+            .create_bar("recording", 100, templates::RECORDING, "rec")
+            .unwrap();
+        assert!(
+            progress
+                .note_retransmissions("recording", 1, 0)
+                .is_err()
+        );
+        assert_eq!(
+            progress
+                .finish("recording", "Finished")
+                .unwrap(),
+            "Finished"
+        );
+    }
 }
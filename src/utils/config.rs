@@ -0,0 +1,548 @@
+// TOML config files for the command line
+//
+//   [global]   top-level options (backend, tx-gain, key-file, ...)
+//   [phy]      options shared by subcommands (encoding, fec, ...), for
+//              every subcommand and the top level where they exist
+//   [router]   options of one subcommand, named as on the command line
+//   [ip-host]  ...
+//
+// Keys are the long option names (`local-ip`, or `local_ip`), positional
+// arguments go by their name. A flag is `true` or `false`, a repeatable
+// option an array. The file doesn't get a parser of its own: its values are
+// turned into the arguments they stand for and handed to clap with the
+// command line, so they are checked the same way and fill the same
+// structures. Precedence is default < [phy] < [global] or the subcommand's
+// section < command line; an option given on the command line is never
+// taken from the file. Sections and keys nothing takes are warned about.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+use tracing::warn;
+
+pub const GLOBAL_SECTION: &str = "global";
+pub const PHY_SECTION: &str = "phy";
+/// The option naming the config file, never taken from it
+pub const CONFIG_ARG: &str = "config";
+
+pub fn load(path: &Path) -> Result<Table, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    text.parse::<Table>()
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The file `args` name with --config, if any, loaded and checked against
+/// `command`, its unknown keys logged
+pub fn from_args(
+    command: &Command,
+    args: &[OsString],
+) -> Result<Option<Table>, String> {
+    let Some(matches) = probe(command, args) else {
+        return Ok(None);
+    };
+    let Ok(Some(mut path)) = matches.try_get_raw(CONFIG_ARG) else {
+        return Ok(None);
+    };
+    let Some(path) = path.next() else {
+        return Ok(None);
+    };
+    let config = load(Path::new(path))?;
+    for warning in unknown_keys(command, &config) {
+        warn!("{}: {}", Path::new(path).display(), warning);
+    }
+    Ok(Some(config))
+}
+
+/// `args` with the options `config` sets and they don't added: top-level
+/// ones in front of the subcommand, the subcommand's at the end
+pub fn merge_args(
+    command: &Command,
+    args: &[OsString],
+    config: &Table,
+) -> Vec<OsString> {
+    let mut merged = args.to_vec();
+    let Some(matches) = probe(command, args) else {
+        // --help and the like, clap answers them
+        return merged;
+    };
+    let subcommand = matches
+        .subcommand()
+        .and_then(|(name, sub_matches)| {
+            Some((command.find_subcommand(name)?, sub_matches))
+        });
+
+    let given = |id: &str| {
+        let on_command_line = |m: &ArgMatches| {
+            m.value_source(id) == Some(ValueSource::CommandLine)
+        };
+        // Global options may come after the subcommand
+        on_command_line(&matches)
+            || subcommand.is_some_and(|(_, m)| {
+                m.try_get_raw(id).is_ok() && on_command_line(m)
+            })
+    };
+    let global = tokens_for(command, GLOBAL_SECTION, config, given);
+    merged.splice(1.min(merged.len())..1.min(merged.len()), global);
+
+    if let Some((sub, sub_matches)) = subcommand {
+        let given_in_sub = |id: &str| {
+            sub_matches.value_source(id) == Some(ValueSource::CommandLine)
+        };
+        merged.extend(tokens_for(sub, sub.get_name(), config, given_in_sub));
+    }
+    merged
+}
+
+/// Every option of the top level and of each subcommand, or only
+/// `section`, as `args` and `config` would set them. Options without a
+/// value are left out, and so are subcommands without any.
+pub fn effective(
+    command: &Command,
+    args: &[OsString],
+    config: &Table,
+    section: Option<&str>,
+) -> Table {
+    let mut effective = Table::new();
+    let program = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| command.get_name().into());
+
+    if section.is_none_or(|s| same_key(s, GLOBAL_SECTION))
+        && let Some(matches) = probe(command, &merge_args(command, args, config))
+    {
+        effective.insert(
+            GLOBAL_SECTION.to_string(),
+            Value::Table(values(command, &matches)),
+        );
+    }
+    for sub in configurable_subcommands(command) {
+        if section.is_some_and(|s| !same_key(s, sub.get_name())) {
+            continue;
+        }
+        // The subcommand alone, the file filling in what it sets
+        let sub_args = [program.clone(), sub.get_name().into()];
+        let merged = merge_args(command, &sub_args, config);
+        if let Some(matches) = probe(command, &merged)
+            && let Some(sub_matches) = matches.subcommand_matches(sub.get_name())
+        {
+            let values = values(sub, sub_matches);
+            if !values.is_empty() {
+                effective
+                    .insert(sub.get_name().to_string(), Value::Table(values));
+            }
+        }
+    }
+    effective
+}
+
+/// What in `config` no option takes
+pub fn unknown_keys(command: &Command, config: &Table) -> Vec<String> {
+    let mut unknown = Vec::new();
+    for (section, table) in config {
+        let Value::Table(table) = table else {
+            unknown.push(format!("'{}' is not in a section", section));
+            continue;
+        };
+        let takes: Vec<&Command> = if same_key(section, GLOBAL_SECTION) {
+            vec![command]
+        } else if same_key(section, PHY_SECTION) {
+            std::iter::once(command)
+                .chain(configurable_subcommands(command))
+                .collect()
+        } else if let Some(sub) = configurable_subcommands(command)
+            .find(|sub| same_key(section, sub.get_name()))
+        {
+            vec![sub]
+        } else {
+            unknown.push(format!("unknown section [{}]", section));
+            continue;
+        };
+        for key in table.keys() {
+            if !takes
+                .iter()
+                .any(|c| find_arg(c, key).is_some())
+            {
+                unknown.push(format!("unknown key '{}' in [{}]", key, section));
+            }
+        }
+    }
+    unknown
+}
+
+/// Parse `args` tolerating missing required options, None on --help,
+/// --version or a parse error
+fn probe(command: &Command, args: &[OsString]) -> Option<ArgMatches> {
+    command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+        .ok()
+}
+
+fn configurable_subcommands(
+    command: &Command,
+) -> impl Iterator<Item = &Command> {
+    command
+        .get_subcommands()
+        .filter(|sub| sub.get_name() != "help")
+}
+
+fn configurable_args(command: &Command) -> impl Iterator<Item = &Arg> {
+    command
+        .get_arguments()
+        .filter(|arg| {
+            !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+                && arg.get_id() != CONFIG_ARG
+        })
+}
+
+/// `local-ip` and `local_ip` alike
+fn same_key(a: &str, b: &str) -> bool {
+    a.replace('-', "_") == b.replace('-', "_")
+}
+
+/// The key an argument goes by in the file
+fn key_of(arg: &Arg) -> &str {
+    arg.get_long()
+        .unwrap_or(arg.get_id().as_str())
+}
+
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    configurable_args(command).find(|arg| {
+        same_key(key, key_of(arg)) || same_key(key, arg.get_id().as_str())
+    })
+}
+
+/// Arguments for the options of `command` that [`section`] or [phy] set
+/// and `given` doesn't have yet
+fn tokens_for(
+    command: &Command,
+    section: &str,
+    config: &Table,
+    given: impl Fn(&str) -> bool,
+) -> Vec<OsString> {
+    let table = |name: &str| {
+        config
+            .iter()
+            .find(|(key, _)| same_key(key, name))
+            .and_then(|(_, value)| value.as_table())
+    };
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    for arg in configurable_args(command) {
+        let id = arg.get_id().as_str();
+        if given(id) {
+            continue;
+        }
+        let value = [table(section), table(PHY_SECTION)]
+            .into_iter()
+            .flatten()
+            .find_map(|table| {
+                table
+                    .iter()
+                    .find(|(key, _)| {
+                        same_key(key, key_of(arg)) || same_key(key, id)
+                    })
+                    .map(|(_, value)| value)
+            });
+        let Some(value) = value else {
+            continue;
+        };
+        // Setting a default changes nothing, except that options requiring
+        // others would then demand them (a dumped configuration has every
+        // default in it)
+        if is_default(arg, value) {
+            continue;
+        }
+        match tokens(arg, value) {
+            Ok(tokens) if arg.is_positional() => positionals.extend(tokens),
+            Ok(tokens) => options.extend(tokens),
+            Err(e) => warn!("[{}] {}: {}", section, key_of(arg), e),
+        }
+    }
+    // Positionals last, in their order, after an option could take them
+    if !positionals.is_empty() {
+        options.push("--".into());
+        options.extend(positionals);
+    }
+    options
+}
+
+/// The arguments setting `arg` to `value`
+fn tokens(arg: &Arg, value: &Value) -> Result<Vec<OsString>, String> {
+    let flag = || OsString::from(format!("--{}", key_of(arg)));
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(set))
+        | (ArgAction::SetFalse, Value::Boolean(set)) => {
+            let flips = matches!(arg.get_action(), ArgAction::SetTrue) == *set;
+            Ok(if flips { vec![flag()] } else { Vec::new() })
+        }
+        (ArgAction::SetTrue | ArgAction::SetFalse, _) => {
+            Err("expected true or false".to_string())
+        }
+        (ArgAction::Count, Value::Integer(n)) if *n >= 0 => {
+            Ok(vec![flag(); *n as usize])
+        }
+        (ArgAction::Count, _) => Err("expected a count".to_string()),
+        (action, Value::Array(items)) => {
+            if !matches!(action, ArgAction::Append)
+                && arg
+                    .get_num_args()
+                    .is_none_or(|range| range.max_values() <= 1)
+            {
+                return Err("takes a single value, not an array".to_string());
+            }
+            let mut tokens = Vec::new();
+            for item in items {
+                tokens.extend(option_tokens(arg, scalar(item)?, flag()));
+            }
+            Ok(tokens)
+        }
+        (_, value) => Ok(option_tokens(arg, scalar(value)?, flag())),
+    }
+}
+
+fn is_default(arg: &Arg, value: &Value) -> bool {
+    let defaults = arg.get_default_values();
+    let values = match value {
+        Value::Array(items) => items.iter().collect(),
+        value => vec![value],
+    };
+    !defaults.is_empty()
+        && defaults.len() == values.len()
+        && defaults
+            .iter()
+            .zip(values)
+            .all(|(default, value)| {
+                scalar(value).is_ok_and(|value| *default == *value)
+            })
+}
+
+fn option_tokens(arg: &Arg, value: String, flag: OsString) -> Vec<OsString> {
+    if arg.is_positional() {
+        vec![value.into()]
+    } else {
+        vec![flag, value.into()]
+    }
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(x) => Ok(x.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        Value::Datetime(d) => Ok(d.to_string()),
+        Value::Array(_) | Value::Table(_) => {
+            Err("expected a single value".to_string())
+        }
+    }
+}
+
+/// The values `matches` holds for the options of `command`, defaults
+/// included, options without a value left out
+fn values(command: &Command, matches: &ArgMatches) -> Table {
+    let mut table = Table::new();
+    for arg in configurable_args(command) {
+        let Ok(Some(raw)) = matches.try_get_raw(arg.get_id().as_str()) else {
+            continue;
+        };
+        let raw: Vec<&OsStr> = raw.collect();
+        let value = match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => Value::Boolean(
+                raw.first()
+                    .is_some_and(|v| *v == "true"),
+            ),
+            ArgAction::Count => Value::Integer(raw.len() as i64),
+            ArgAction::Append => Value::Array(
+                raw.iter()
+                    .map(|v| typed(v))
+                    .collect(),
+            ),
+            _ if raw.len() > 1 => Value::Array(
+                raw.iter()
+                    .map(|v| typed(v))
+                    .collect(),
+            ),
+            _ => match raw.first() {
+                Some(v) => typed(v),
+                None => continue,
+            },
+        };
+        table.insert(key_of(arg).to_string(), value);
+    }
+    table
+}
+
+/// A number if it reads back the same, a string otherwise
+fn typed(raw: &OsStr) -> Value {
+    let raw = raw.to_string_lossy();
+    if let Ok(n) = raw.parse::<i64>()
+        && n.to_string() == raw
+    {
+        return Value::Integer(n);
+    }
+    if let Ok(x) = raw.parse::<f64>()
+        && x.is_finite()
+        && x.to_string() == raw
+    {
+        return Value::Float(x);
+    }
+    Value::String(raw.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Command {
+        Command::new("tm")
+            .arg(
+                Arg::new("config")
+                    .long("config")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("tx_gain")
+                    .long("tx-gain")
+                    .global(true)
+                    .default_value("1"),
+            )
+            .subcommand(
+                Command::new("send")
+                    .arg(Arg::new("file").required(true))
+                    .arg(
+                        Arg::new("encoding")
+                            .long("encoding")
+                            .default_value("4b5b"),
+                    )
+                    .arg(
+                        Arg::new("verbose")
+                            .long("verbose")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("routes")
+                            .long("route")
+                            .action(ArgAction::Append),
+                    ),
+            )
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter()
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_file_values_become_arguments() {
+        let config: Table = r#"
+            [global]
+            tx-gain = 0.5
+            [phy]
+            encoding = "nrzi"
+            [send]
+            file = "a.bin"
+            verbose = true
+            route = ["10.0.0.0/8:10.0.0.1:tun", "0.0.0.0/0:1.1.1.1:eth"]
+        "#
+        .parse()
+        .unwrap();
+        let merged = merge_args(
+            &command(),
+            &args(&["tm", "send", "--encoding", "manchester"]),
+            &config,
+        );
+        assert_eq!(
+            merged,
+            args(&[
+                "tm",
+                "--tx-gain",
+                "0.5",
+                "send",
+                "--encoding",
+                "manchester",
+                "--verbose",
+                "--route",
+                "10.0.0.0/8:10.0.0.1:tun",
+                "--route",
+                "0.0.0.0/0:1.1.1.1:eth",
+                "--",
+                "a.bin",
+            ])
+        );
+        let matches = command()
+            .try_get_matches_from(&merged)
+            .unwrap();
+        let send = matches
+            .subcommand_matches("send")
+            .unwrap();
+        assert_eq!(
+            send.get_one::<String>("file")
+                .unwrap(),
+            "a.bin"
+        );
+        assert!(send.get_flag("verbose"));
+    }
+
+    #[test]
+    fn test_unknown_keys_reported() {
+        let config: Table = r#"
+            stray = 1
+            [phy]
+            encoding = "nrzi"
+            samples = 3
+            [send]
+            local_ip = "x"
+            [recv]
+            file = "b"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(
+            unknown_keys(&command(), &config),
+            vec![
+                "'stray' is not in a section",
+                "unknown key 'samples' in [phy]",
+                "unknown key 'local_ip' in [send]",
+                "unknown section [recv]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_effective_round_trip() {
+        let config: Table = r#"
+            [phy]
+            encoding = "nrzi"
+            [send]
+            route = ["a", "b"]
+        "#
+        .parse()
+        .unwrap();
+        let argv = args(&["tm", "--tx-gain", "0.25", "send"]);
+        let effective = effective(&command(), &argv, &config, None);
+        assert_eq!(
+            toml::to_string(&effective).unwrap(),
+            "[global]\ntx-gain = 0.25\n\n\
+             [send]\nencoding = \"nrzi\"\nverbose = false\nroute = [\"a\", \"b\"]\n"
+        );
+        // Read back in, the same configuration
+        let reread = toml::to_string(&effective)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            super::effective(&command(), &args(&["tm"]), &reread, None),
+            effective
+        );
+        assert!(unknown_keys(&command(), &reread).is_empty());
+    }
+}
@@ -3,6 +3,8 @@ pub const DEFAULT_TIMEOUT: usize = 30;
 
 /// 日志级别（可被 RUST_LOG 覆盖）
 pub const LOG_LEVEL: &str = "info";
+/// Size at which a --log-file is closed and the next one started
+pub const LOG_ROTATE_BYTES: u64 = 64 * 1024 * 1024;
 
 /// JACK 客户端名称
 pub const JACK_CLIENT_NAME: &str = "track_maker";
@@ -28,13 +30,107 @@ pub const BIT_RATE: u32 = 12000;
 
 /// Samples per level (Manchester level or 4B5B bit)
 pub const SAMPLES_PER_LEVEL: usize = 3;
+/// Faster body rates a frame header can select (rate codes 1, 2); code 0
+/// keeps the link's own samples per level (see phy::rate)
+pub const FAST_SAMPLES_PER_LEVEL: [usize; 2] = [2, 1];
 
 // Frame Parameters
 /// Number of 0xAA pattern bytes in preamble
 pub const PREAMBLE_PATTERN_BYTES: usize = 2;
 
+/// Default chirp preamble: a 10 ms up-sweep across most of the audio band
+pub const CHIRP_F0_HZ: f32 = 2000.0;
+pub const CHIRP_F1_HZ: f32 = 10000.0;
+pub const CHIRP_LEN_SAMPLES: usize = 480;
+/// Normalized correlation a chirp must reach to count as detected
+pub const CHIRP_CORRELATION_THRESHOLD: f32 = 0.6;
+
+/// BPSK backend (see phy::psk): one carrier cycle per symbol, 6 kbit/s
+pub const PSK_CARRIER_HZ: f32 = 6000.0;
+pub const PSK_SAMPLES_PER_SYMBOL: usize = 8;
+
+/// Cutoff of the receiver's DC blocker; far below the symbol rate, well
+/// above sound card baseline wander
+pub const DC_BLOCK_CUTOFF_HZ: f32 = 20.0;
+
+// Receiver AGC
+/// Samples in the running RMS window
+pub const AGC_WINDOW_SAMPLES: usize = 1024;
+/// RMS the AGC scales the signal to (the encoders emit +-1 levels)
+pub const AGC_TARGET_LEVEL: f32 = 1.0;
+/// How fast the gain drops when the signal gets louder
+pub const AGC_ATTACK_MS: f32 = 1.0;
+/// How fast the gain recovers when the signal gets quieter
+pub const AGC_DECAY_MS: f32 = 50.0;
+/// Below this RMS the input is treated as silence and not decoded
+pub const AGC_NOISE_FLOOR: f32 = 0.005;
+
+// Echo cancellation (--echo-cancel)
+/// Longest round trip from our speaker back to our microphone searched for
+pub const ECHO_MAX_DELAY_MS: u32 = 100;
+pub const ECHO_MAX_DELAY_SAMPLES: usize =
+    (SAMPLE_RATE as usize * ECHO_MAX_DELAY_MS as usize) / 1000;
+/// Once the echo is found, its delay is only searched this far around
+pub const ECHO_TRACK_SAMPLES: usize = 16;
+/// Share of the recorded energy the delayed copy must explain to be taken
+/// for our echo
+pub const ECHO_LOCK_CORRELATION: f32 = 0.5;
+
+// Audio dumps (--dump-audio)
+/// Minutes of audio per dump file before a new one is started
+pub const DUMP_ROTATE_MINUTES: u64 = 10;
+/// How often the dump thread drains the audio it was handed
+pub const DUMP_POLL_MS: u64 = 50;
+
+// Debug dumps of failed frames (--debug-dumps)
+/// FFT size of the spectrograms, 5.3 ms at 48 kHz
+pub const DEBUG_DUMP_FFT_SIZE: usize = 256;
+/// Failed frames waiting for the writer before more are dropped
+pub const DEBUG_DUMP_QUEUE: usize = 16;
+/// How long exiting waits for queued dumps to be written
+pub const DEBUG_DUMP_FLUSH_MS: u64 = 2000;
+/// Default length of a `capture`
+pub const CAPTURE_SECONDS: u64 = 10;
+
+// Latency calibration (calibrate)
+/// Longest round trip a calibration chirp is listened for
+pub const CALIBRATE_MAX_LATENCY_MS: u64 = 250;
+/// Silence between calibration chirps, for echoes to die out
+pub const CALIBRATE_GAP_MS: u64 = 100;
+/// Chirps played by default
+pub const CALIBRATE_ROUNDS: usize = 10;
+
+// Output level (--tx-gain)
+/// Share of full scale the encoded +-1 levels are played at
+pub const TX_GAIN: f32 = 0.7;
+/// Played samples are left alone up to this level and soft clipped past it
+pub const SOFT_CLIP_KNEE: f32 = 0.8;
+/// Input samples above this count as clipped
+pub const CLIP_LEVEL: f32 = 0.99;
+/// The input clipping this long in a row is warned about
+pub const CLIP_WARN_MS: u64 = 3;
+/// How often the output level and input clipping are logged
+pub const LEVEL_LOG_MS: u64 = 1000;
+
+// Per-frame delays (--timestamps)
+/// Upper bounds of the delay histogram buckets, plus one for the rest
+pub const DELAY_BUCKETS_MS: [u32; 10] =
+    [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+// File transfer (see mac::file_transfer)
+/// How long the sender waits for the receiver's verdict after the trailer
+pub const FILE_VERDICT_TIMEOUT_MS: u64 = 3000;
+/// Deflate level for --compress, 0 (store) to 10 (slowest)
+pub const DEFLATE_LEVEL: u8 = 6;
+
+/// Level of the NRZI reference symbol; either polarity decodes the same
+pub const NRZI_INITIAL_LEVEL: f32 = 1.0;
+
 /// Maximum data payload per frame (bytes)
 pub const MAX_FRAME_DATA_SIZE: usize = 128;
+/// With --fragment, the TUN adapter's default MTU in frames: the kernel
+/// may hand it packets this many frames long, split before sending
+pub const TUN_MAX_FRAGMENTS: usize = 4;
 
 /// Milliseconds between frames
 pub const INTER_FRAME_GAP_MS: u32 = 1;
@@ -44,12 +140,73 @@ pub const INTER_FRAME_GAP_SAMPLES: usize =
     (SAMPLE_RATE as usize * INTER_FRAME_GAP_MS as usize) / 1000;
 
 pub const ACK_TIMEOUT_MS: u64 = 200;
+/// Failed exchanges (no ACK / CTS) after which the sender drops a frame
+pub const MAX_RETRIES: u32 = 8;
+
+/// In duplex transfers, how long an ACK waits for an outgoing data frame to
+/// ride on before it is sent on its own (well below ACK_TIMEOUT_MS)
+pub const PIGGYBACK_DELAY_MS: u64 = 30;
+
+/// Go-Back-N window: data frames a sender keeps in flight by default
+pub const ARQ_WINDOW: usize = 4;
+/// Largest window accepted, far below half the 16-bit sequence space
+pub const ARQ_MAX_WINDOW: usize = 64;
+/// Duplicate ACKs that resend a lost frame before its timer fires
+pub const FAST_RETRANSMIT_DUP_ACKS: usize = 3;
+
+/// With RTS/CTS on, bursts carrying more data bytes than this are preceded
+/// by an RTS
+pub const RTS_THRESHOLD_BYTES: usize = 64;
 
-pub const PHY_HEADER_BYTES: usize = 7; // Length (2) + CRC (1) + Frame Type (1) + Sequence (1) + Src (1) + Dst (1)
+/// Time between neighbor discovery beacons (see mac::discovery)
+pub const BEACON_INTERVAL_MS: u64 = 5000;
+/// A neighbor not heard from for this long leaves the table (3 beacons)
+pub const NEIGHBOR_EXPIRY_MS: u64 = 3 * BEACON_INTERVAL_MS;
+
+/// Rate probing (see mac::autorate): probes sent per candidate rate
+pub const PROBES_PER_RATE: usize = 4;
+/// Probe payload bytes, about a short data frame
+pub const PROBE_PAYLOAD_BYTES: usize = 32;
+/// Share of probes that must pass their CRC for a rate to count as reliable
+pub const PROBE_MIN_SUCCESS: f32 = 0.75;
+/// Estimated SNR a rate needs to count as reliable
+pub const PROBE_MIN_SNR_DB: f32 = 10.0;
+
+/// Recent sequence numbers the receiver remembers to spot duplicates: far
+/// more than a sender has in flight, far fewer than the 16-bit space
+pub const RX_SEQUENCE_WINDOW: usize = 1024;
+
+/// After re-ACKing a duplicate, further ACKs for that sequence are held back
+/// this long (must stay well below ACK_TIMEOUT_MS)
+pub const DUP_ACK_SUPPRESSION_MS: u64 = 50;
+
+pub const PHY_HEADER_BYTES: usize = 9; // Length (2) + CRC (1) + Frame Type (1) + Sequence (2) + Src (1) + Dst (1) + Hops (1)
+
+/// Data/ACK payloads longer than this carry a CRC32 trailer instead of
+/// relying on the header CRC8
+pub const CRC32_MIN_PAYLOAD_BYTES: usize = 16;
+
+/// Frames that have been relayed this many times are not forwarded again
+/// (the header keeps 3 bits for the hop count)
+pub const RELAY_MAX_HOPS: u8 = 4;
+
+/// Maximum payload of an aggregate frame (bytes), bounded by the decoder limit
+pub const AGGREGATE_MAX_BYTES: usize = MAX_FRAME_DATA_SIZE * 2;
+/// Data bytes per sub-packet when a file transfer uses aggregation
+pub const AGGREGATE_SUBPACKET_SIZE: usize = 48;
 
 // --- CSMA/CA Constants ---
-/// Energy level threshold to consider the channel busy.
+/// Energy level threshold to consider the channel busy until the noise
+/// floor is known. A floor above it means the channel is jammed.
 pub const ENERGY_THRESHOLD: f32 = 0.5;
+/// Busy threshold above the measured noise floor (x2 = +6 dB, see mac::noise)
+pub const NOISE_FLOOR_MARGIN: f32 = 2.0;
+/// Lowest busy threshold however quiet the floor
+pub const ENERGY_MIN_THRESHOLD: f32 = 0.1;
+/// Span the noise floor is taken over (quietest chunk in it)
+pub const NOISE_FLOOR_WINDOW_MS: u64 = 1000;
+/// A floor above ENERGY_THRESHOLD this long is reported as jamming
+pub const JAM_DETECT_MS: u64 = 3000;
 /// Energy detection minimum samples
 pub const ENERGY_DETECTION_SAMPLES: usize = 20;
 /// Distributed Inter-frame Space (DIFS) in milliseconds.
@@ -66,9 +223,112 @@ pub const SLOT_TIME_MS: u64 = 5;
 pub const IP_TTL: u8 = 64;
 /// Default MTU for Aethernet (should be smaller than Ethernet MTU of 1500/3)
 pub const DEFAULT_MTU: usize = 200;
+/// A datagram still missing fragments after this long is dropped
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 30_000;
+/// Payload bytes buffered for reassembly at most, over all datagrams
+pub const REASSEMBLY_MEMORY_CAP: usize = 256 * 1024;
+/// ICMP errors (Time Exceeded) a router sends per second at most
+pub const ICMP_ERROR_RATE_PER_SEC: f32 = 10.0;
+/// Back-to-back ICMP errors allowed before the rate limit kicks in
+pub const ICMP_ERROR_BURST: f32 = 10.0;
+
+// --- ARP Constants ---
+/// Learned ARP entries are forgotten after this long without a refresh
+pub const ARP_ENTRY_TTL_MS: u64 = 60_000;
+/// How long a resolution waits for a reply before giving up
+pub const ARP_RESOLVE_TIMEOUT_MS: u64 = 3000;
+/// The router repeats an unanswered ARP request after this long
+pub const ARP_RETRY_INTERVAL_MS: u64 = 1000;
+/// Repeats before the router gives up and answers Host Unreachable
+pub const ARP_MAX_RETRIES: u32 = 3;
+/// Router ARP entries go stale (and get refreshed on use) after this long
+pub const ROUTER_ARP_TTL_SECS: u64 = 300;
+/// The TAP bridge forgets which node an Ethernet address is behind after
+/// this long without a frame from it
+pub const TAP_STATION_TTL_SECS: u64 = 300;
+
+// --- NAPT Constants ---
+/// External ports the router hands out to translated TCP/UDP flows
+pub const NAPT_PORT_FIRST: u16 = 49152;
+pub const NAPT_PORT_LAST: u16 = 65535;
+/// Idle time after which an established TCP connection is forgotten
+pub const NAPT_TCP_IDLE_SECS: u64 = 7440;
+/// Same for TCP connections still opening or already closing
+pub const NAPT_TCP_TRANSITORY_SECS: u64 = 240;
+/// Idle time after which a translated UDP flow is forgotten
+pub const NAPT_UDP_IDLE_SECS: u64 = 300;
 
 // --- Ping Constants ---
 pub const PING_PACKET_COUNT: u16 = 10;
 pub const PING_PAYLOAD_SIZE: usize = 32;
 pub const PING_TIMEOUT_MS: u64 = 2000;
 pub const PING_INTERVAL_MS: u64 = 1000;
+
+// --- UDP Echo Constants ---
+/// Port of the echo service (RFC 862)
+pub const UDP_ECHO_PORT: u16 = 7;
+/// How long udp-send waits for its echo
+pub const UDP_ECHO_TIMEOUT_MS: u64 = 5000;
+
+// --- TCP Constants ---
+/// Port tcp-server listens on and tcp-client connects to by default
+pub const TCP_DEFAULT_PORT: u16 = 8080;
+/// Largest TCP payload per segment, so a segment fits DEFAULT_MTU
+pub const TCP_MSS: usize = DEFAULT_MTU - 40;
+/// Fixed receive window, also the most a sender keeps in flight. One
+/// segment: the link is half duplex, so a second one would only collide
+/// with the ACK of the first.
+pub const TCP_WINDOW: usize = TCP_MSS;
+/// Retransmission timeout before the first RTT sample
+pub const TCP_RTO_INITIAL_MS: u64 = 2000;
+pub const TCP_RTO_MIN_MS: u64 = 500;
+pub const TCP_RTO_MAX_MS: u64 = 16_000;
+/// Retransmissions of one segment before the connection is given up
+pub const TCP_MAX_RETRIES: u32 = 8;
+/// How long a closed connection lingers to re-ACK a lost final FIN
+pub const TCP_TIME_WAIT_MS: u64 = 2000;
+/// How long close() waits for the peer's FIN once ours is acknowledged
+pub const TCP_FIN_TIMEOUT_MS: u64 = 30_000;
+/// Longest single wait for a segment while blocked without a timer
+pub const TCP_POLL_MS: u64 = 100;
+
+// --- Capture Constants ---
+/// Size at which a --capture file is closed and the next one started
+pub const CAPTURE_ROTATE_BYTES: u64 = 16 * 1024 * 1024;
+
+// --- Router Constants ---
+/// Packets the router sends over the acoustic link are fragmented to this
+pub const ROUTER_ACOUSTIC_MTU: usize = 140;
+/// How often the router logs its acoustic link counters
+pub const ROUTER_STATS_INTERVAL_SECS: u64 = 10;
+/// How often the router logs its traffic counters
+pub const ROUTER_COUNTERS_INTERVAL_SECS: u64 = 30;
+/// Flows the router keeps counters for, see net::counters
+pub const ROUTER_FLOW_TABLE_SIZE: usize = 256;
+/// Busiest flows shown in the counter summary
+pub const ROUTER_TOP_FLOWS: usize = 5;
+/// Where the router's management socket listens (see net::management)
+pub const ROUTER_MGMT_ADDR: &str = "127.0.0.1:7878";
+/// Failed sends in a row after which an acoustic next hop counts as down
+pub const ROUTER_HOP_MAX_FAILURES: u32 = 3;
+/// How often a down acoustic next hop is probed
+pub const ROUTER_HOP_PROBE_INTERVAL_MS: u64 = 2000;
+
+// --- Metrics Constants ---
+/// Upper bounds (ms) of the link_rtt_ms histogram buckets
+pub const METRICS_RTT_BUCKETS_MS: [f64; 8] =
+    [50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0];
+/// Longest HTTP request head the metrics endpoint reads
+pub const METRICS_MAX_REQUEST_BYTES: usize = 8192;
+
+// --- Dashboard Constants ---
+/// How often the --tui dashboard redraws
+pub const DASHBOARD_REFRESH_MS: u64 = 200;
+/// How often the MAC publishes a link status to the dashboard at most
+pub const DASHBOARD_STATUS_MS: u64 = 250;
+/// SNR / RMS readings kept for the sparklines
+pub const DASHBOARD_HISTORY: usize = 120;
+/// Log lines kept for the log pane
+pub const DASHBOARD_LOG_LINES: usize = 500;
+/// CSMA state transitions kept
+pub const DASHBOARD_TRANSITIONS: usize = 50;
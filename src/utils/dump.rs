@@ -1,3 +1,17 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Sender, TrySendError};
+use tracing::{debug, info, warn};
+
+use super::consts::{DEBUG_DUMP_FFT_SIZE, DEBUG_DUMP_QUEUE};
+use crate::phy::capture::{Capture, CaptureHeader};
+use crate::phy::crc::calculate_crc32;
+
 use serde::{Deserialize, Serialize};
 use symphonia;
 
@@ -44,3 +58,723 @@ pub fn dump_to_wav(
 
     Ok(())
 }
+
+/// Streams samples into `<prefix>-<unix secs>-<index>.wav` files under a
+/// directory, 32-bit float mono, starting a new file every
+/// `rotate_samples`. Headers are kept up to date as it goes, so the files
+/// are readable even if the process dies before `finish`.
+pub struct WavDumper {
+    dir: PathBuf,
+    prefix: String,
+    started_unix_secs: u64,
+    sample_rate: u32,
+    rotate_samples: usize,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    /// Samples in the current file
+    written: usize,
+    /// Files started so far
+    files: Vec<PathBuf>,
+}
+
+impl WavDumper {
+    pub fn new(
+        dir: &Path,
+        prefix: &str,
+        sample_rate: u32,
+        rotate_samples: usize,
+    ) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            prefix: prefix.to_string(),
+            started_unix_secs: super::report::unix_now_secs(),
+            sample_rate,
+            rotate_samples: rotate_samples.max(1),
+            writer: None,
+            written: 0,
+            files: Vec::new(),
+        })
+    }
+
+    /// Append `samples`, rotating files where due
+    pub fn write(&mut self, mut samples: &[f32]) -> Result<(), hound::Error> {
+        while !samples.is_empty() {
+            if self.writer.is_none() || self.written == self.rotate_samples {
+                self.rotate()?;
+            }
+            let writer = self
+                .writer
+                .as_mut()
+                .expect("Rotated in a writer");
+            let n = samples
+                .len()
+                .min(self.rotate_samples - self.written);
+            for &sample in &samples[..n] {
+                writer.write_sample(sample)?;
+            }
+            self.written += n;
+            samples = &samples[n..];
+        }
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Vec<PathBuf>, hound::Error> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        Ok(self.files)
+    }
+
+    fn rotate(&mut self) -> Result<(), hound::Error> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        let path = self.dir.join(format!(
+            "{}-{}-{:03}.wav",
+            self.prefix,
+            self.started_unix_secs,
+            self.files.len()
+        ));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        self.writer = Some(hound::WavWriter::create(&path, spec)?);
+        self.written = 0;
+        self.files.push(path);
+        Ok(())
+    }
+}
+
+/// Sample rate and first channel of a WAV file, as f32 in [-1, 1]
+pub fn read_wav(path: &Path) -> Result<(u32, Vec<f32>), hound::Error> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let channels = spec.channels.max(1) as usize;
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok((
+        spec.sample_rate,
+        samples
+            .into_iter()
+            .step_by(channels)
+            .collect(),
+    ))
+}
+
+/// Magnitude spectra of `samples`, one per Hann windowed frame of
+/// `fft_size` (a power of two) with half a frame of hop. Each spectrum
+/// has `fft_size / 2 + 1` bins from DC to Nyquist. Samples short of a
+/// whole last frame are left out.
+pub fn stft(samples: &[f32], fft_size: usize) -> Vec<Vec<f32>> {
+    assert!(
+        fft_size.is_power_of_two() && fft_size >= 2,
+        "FFT size {} is not a power of two",
+        fft_size
+    );
+    let hop = fft_size / 2;
+    let window: Vec<f32> = (0..fft_size)
+        .map(|i| {
+            let phase = 2.0 * std::f32::consts::PI * i as f32 / fft_size as f32;
+            0.5 - 0.5 * phase.cos()
+        })
+        .collect();
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while start + fft_size <= samples.len() {
+        let mut bins: Vec<(f32, f32)> = samples[start..start + fft_size]
+            .iter()
+            .zip(&window)
+            .map(|(&sample, &w)| (sample * w, 0.0))
+            .collect();
+        fft(&mut bins);
+        frames.push(
+            bins[..=fft_size / 2]
+                .iter()
+                .map(|&(re, im)| (re * re + im * im).sqrt())
+                .collect(),
+        );
+        start += hop;
+    }
+    frames
+}
+
+/// In-place iterative radix-2 FFT of (re, im) pairs
+fn fft(data: &mut [(f32, f32)]) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f32::consts::PI / len as f32;
+        for chunk in data.chunks_mut(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (re, im) = chunk[k + len / 2];
+                let twiddled = (re * cos - im * sin, re * sin + im * cos);
+                let even = chunk[k];
+                chunk[k] = (even.0 + twiddled.0, even.1 + twiddled.1);
+                chunk[k + len / 2] = (even.0 - twiddled.0, even.1 - twiddled.1);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// Write `samples` as a spectrogram PNG: time left to right, DC at the
+/// bottom, the top 80 dB mapped from black through red and yellow to
+/// white. The sample rate and FFT size go into the PNG text chunks.
+pub fn dump_spectrogram(
+    path: &Path,
+    samples: &[f32],
+    fs: u32,
+    fft_size: usize,
+) -> std::io::Result<()> {
+    let frames = stft(samples, fft_size);
+    if frames.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} samples are fewer than one {} point FFT",
+                samples.len(),
+                fft_size
+            ),
+        ));
+    }
+    let db: Vec<Vec<f32>> = frames
+        .iter()
+        .map(|bins| {
+            bins.iter()
+                .map(|&magnitude| 20.0 * (magnitude + 1e-9).log10())
+                .collect()
+        })
+        .collect();
+    let peak = db
+        .iter()
+        .flatten()
+        .fold(f32::MIN, |a, &b| a.max(b));
+    let (width, height) = (frames.len(), fft_size / 2 + 1);
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for row in 0..height {
+        let bin = height - 1 - row;
+        for column in &db {
+            let level = ((column[bin] - peak + 80.0) / 80.0).clamp(0.0, 1.0);
+            rgb.extend(heat(level));
+        }
+    }
+    write_png(
+        path,
+        width,
+        height,
+        &rgb,
+        &[
+            ("Sample-Rate", fs.to_string()),
+            ("FFT-Size", fft_size.to_string()),
+            ("Hop", (fft_size / 2).to_string()),
+        ],
+    )
+}
+
+/// Black, red, yellow, white as `level` goes from 0 to 1
+fn heat(level: f32) -> [u8; 3] {
+    let channel =
+        |from: f32| ((level * 3.0 - from).clamp(0.0, 1.0) * 255.0) as u8;
+    [channel(0.0), channel(1.0), channel(2.0)]
+}
+
+/// Write I/Q `symbols` to `<path>.csv` and as a scatter plot to
+/// `<path>.png`, axes through the origin
+pub fn dump_constellation(
+    path: &Path,
+    symbols: &[(f32, f32)],
+) -> std::io::Result<()> {
+    let mut csv = BufWriter::new(File::create(path.with_extension("csv"))?);
+    writeln!(csv, "i,q")?;
+    for (i, q) in symbols {
+        writeln!(csv, "{},{}", i, q)?;
+    }
+    csv.flush()?;
+
+    const SIZE: usize = 256;
+    let scale = symbols
+        .iter()
+        .fold(0.0f32, |a, &(i, q)| a.max(i.abs()).max(q.abs()))
+        .max(1e-9)
+        * 1.1;
+    let mut rgb = vec![0u8; SIZE * SIZE * 3];
+    let mut plot = |x: usize, y: usize, color: [u8; 3]| {
+        let at = (y * SIZE + x) * 3;
+        rgb[at..at + 3].copy_from_slice(&color);
+    };
+    for k in 0..SIZE {
+        plot(k, SIZE / 2, [64, 64, 64]);
+        plot(SIZE / 2, k, [64, 64, 64]);
+    }
+    let to_pixel = |v: f32| {
+        (((v / scale + 1.0) / 2.0 * (SIZE - 1) as f32).round() as usize)
+            .min(SIZE - 1)
+    };
+    for &(i, q) in symbols {
+        // Q grows upwards
+        plot(to_pixel(i), SIZE - 1 - to_pixel(q), [255, 255, 255]);
+    }
+    write_png(
+        &path.with_extension("png"),
+        SIZE,
+        SIZE,
+        &rgb,
+        &[("Symbols", symbols.len().to_string())],
+    )
+}
+
+/// 8-bit RGB PNG with `text` as tEXt chunks
+fn write_png(
+    path: &Path,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+    text: &[(&str, String)],
+) -> std::io::Result<()> {
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        out.extend((data.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend(kind);
+        out.extend(data);
+        let crc = calculate_crc32(&out[start..]);
+        out.extend(crc.to_be_bytes());
+    }
+
+    debug_assert_eq!(rgb.len(), width * height * 3);
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut header = Vec::with_capacity(13);
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, deflate, no filtering, not interlaced
+    header.extend([8, 2, 0, 0, 0]);
+    chunk(&mut png, b"IHDR", &header);
+    for (key, value) in text {
+        let mut data = key.as_bytes().to_vec();
+        data.push(0);
+        data.extend(value.as_bytes());
+        chunk(&mut png, b"tEXt", &data);
+    }
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in rgb.chunks(width * 3) {
+        raw.push(0);
+        raw.extend(row);
+    }
+    chunk(
+        &mut png,
+        b"IDAT",
+        &miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6),
+    );
+    chunk(&mut png, b"IEND", &[]);
+    std::fs::write(path, png)
+}
+
+/// Samples (and symbols, for receivers that slice into them) of a frame
+/// the decoder gave up on, for --debug-dumps and --dump-audio
+pub struct DebugDump {
+    /// Short tag naming the failure, used in the file names
+    pub reason: &'static str,
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub symbols: Option<Vec<(f32, f32)>>,
+    /// How to decode the samples again, for a capture file
+    pub capture: Option<CaptureHeader>,
+}
+
+/// Where failed frames go
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugDumpOptions {
+    /// Spectrograms and constellations (--debug-dumps)
+    pub plots: Option<PathBuf>,
+    /// Capture files (--dump-audio)
+    pub captures: Option<PathBuf>,
+}
+
+static DEBUG_DUMPS: OnceLock<Sender<DebugDump>> = OnceLock::new();
+/// Dumps queued but not written yet
+static DEBUG_DUMPS_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Write plots and captures of every frame the decoders give up on into
+/// the directories in `options` from now on. The files are written on a
+/// thread of their own, so the decoders only pay for a copy of the
+/// samples.
+pub fn select_debug_dumps(options: DebugDumpOptions) -> std::io::Result<()> {
+    for dir in [&options.plots, &options.captures]
+        .into_iter()
+        .flatten()
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+    let (tx, rx) = crossbeam_channel::bounded::<DebugDump>(DEBUG_DUMP_QUEUE);
+    if DEBUG_DUMPS.set(tx).is_err() {
+        warn!("Debug dumps already selected, ignoring {:?}", options);
+        return Ok(());
+    }
+    for dir in [&options.plots, &options.captures]
+        .into_iter()
+        .flatten()
+    {
+        info!("Dumping failed frames to {}", dir.display());
+    }
+    std::thread::Builder::new()
+        .name("debug-dumps".to_string())
+        .spawn(move || {
+            for dump in rx {
+                let reason = dump.reason;
+                if let Err(err) = write_debug_dump(&options, dump) {
+                    warn!("Failed to write {} debug dump: {}", reason, err);
+                }
+                DEBUG_DUMPS_PENDING.fetch_sub(1, Ordering::AcqRel);
+            }
+        })?;
+    Ok(())
+}
+
+/// Whether failed frames are dumped, to skip copying samples when not
+pub fn debug_dumps_selected() -> bool {
+    DEBUG_DUMPS.get().is_some()
+}
+
+/// Hand `dump` to the writer thread. Dropped if the writer has fallen
+/// behind rather than holding up the caller.
+pub fn debug_dump(dump: DebugDump) {
+    let Some(tx) = DEBUG_DUMPS.get() else {
+        return;
+    };
+    DEBUG_DUMPS_PENDING.fetch_add(1, Ordering::AcqRel);
+    if let Err(err) = tx.try_send(dump) {
+        DEBUG_DUMPS_PENDING.fetch_sub(1, Ordering::AcqRel);
+        if let TrySendError::Full(dump) = err {
+            debug!("Debug dump queue full, dropping {} dump", dump.reason);
+        }
+    }
+}
+
+/// Wait up to `timeout` for queued debug dumps to be written, so they
+/// aren't lost when the process exits
+pub fn flush_debug_dumps(timeout: Duration) {
+    if !debug_dumps_selected() {
+        return;
+    }
+    let deadline = Instant::now() + timeout;
+    while DEBUG_DUMPS_PENDING.load(Ordering::Acquire) > 0 {
+        if Instant::now() >= deadline {
+            warn!(
+                "{} debug dumps not written before exit",
+                DEBUG_DUMPS_PENDING.load(Ordering::Acquire)
+            );
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Under `plots`: `<unix ms>-<reason>-spectrogram.png`, plus
+/// `<unix ms>-<reason>-constellation.{csv,png}` with symbols. Under
+/// `captures`: `<unix ms>-<reason>.tmk`.
+fn write_debug_dump(
+    options: &DebugDumpOptions,
+    dump: DebugDump,
+) -> std::io::Result<()> {
+    let unix_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let stem = format!("{}-{}", unix_ms, dump.reason);
+    if let Some(dir) = &options.plots {
+        dump_spectrogram(
+            &dir.join(format!("{}-spectrogram.png", stem)),
+            &dump.samples,
+            dump.sample_rate,
+            DEBUG_DUMP_FFT_SIZE,
+        )?;
+        if let Some(symbols) = &dump.symbols {
+            dump_constellation(
+                &dir.join(format!("{}-constellation", stem)),
+                symbols,
+            )?;
+        }
+    }
+    if let (Some(dir), Some(header)) = (&options.captures, dump.capture) {
+        Capture {
+            header,
+            samples: dump.samples,
+        }
+        .write(&dir.join(format!("{}.tmk", stem)))?;
+    }
+    debug!("Wrote {} debug dump {}", dump.reason, stem);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dumper_rotates_and_reads_back() {
+        let dir = std::env::temp_dir()
+            .join(format!("trackmaker-dump-{}-rotate", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let samples: Vec<f32> = (0..250)
+            .map(|i| i as f32 / 250.0)
+            .collect();
+
+        let mut dumper = WavDumper::new(&dir, "rx", 48000, 100).unwrap();
+        for chunk in samples.chunks(30) {
+            dumper.write(chunk).unwrap();
+        }
+        // Readable before finishing
+        let mut written: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        written.sort();
+        assert_eq!(
+            read_wav(&written[0])
+                .unwrap()
+                .1,
+            &samples[..100]
+        );
+        let files = dumper.finish().unwrap();
+
+        assert_eq!(files.len(), 3);
+        assert!(
+            files[2]
+                .to_string_lossy()
+                .ends_with("-002.wav")
+        );
+        let mut read_back = Vec::new();
+        for file in &files {
+            let (rate, part) = read_wav(file).unwrap();
+            assert_eq!(rate, 48000);
+            assert!(part.len() <= 100);
+            read_back.extend(part);
+        }
+        assert_eq!(read_back, samples);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stft_dimensions() {
+        let frames = stft(&vec![0.0; 1024], 256);
+        // Hop of 128: frames start at 0, 128, ..., 768
+        assert_eq!(frames.len(), 7);
+        assert!(
+            frames
+                .iter()
+                .all(|bins| bins.len() == 129)
+        );
+        assert!(stft(&[0.0; 255], 256).is_empty());
+    }
+
+    #[test]
+    fn test_pure_tone_peaks_in_its_bin() {
+        let (fs, fft_size, bin) = (48000.0, 256, 32);
+        let freq = bin as f32 * fs / fft_size as f32;
+        let tone: Vec<f32> = (0..2048)
+            .map(|i| (2.0 * std::f32::consts::PI * freq * i as f32 / fs).sin())
+            .collect();
+        for bins in stft(&tone, fft_size) {
+            let peak = (0..bins.len())
+                .max_by(|&a, &b| bins[a].total_cmp(&bins[b]))
+                .unwrap();
+            assert_eq!(peak, bin);
+        }
+    }
+
+    #[test]
+    fn test_spectrogram_png_matches_stft() {
+        let dir = std::env::temp_dir()
+            .join(format!("trackmaker-dump-{}-png", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tone.png");
+        let samples: Vec<f32> = (0..1024)
+            .map(|i| (i as f32 * 0.3).sin())
+            .collect();
+        dump_spectrogram(&path, &samples, 48000, 256).unwrap();
+
+        let png = std::fs::read(&path).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(
+            u32::from_be_bytes(
+                png[16..20]
+                    .try_into()
+                    .unwrap()
+            ),
+            7
+        );
+        assert_eq!(
+            u32::from_be_bytes(
+                png[20..24]
+                    .try_into()
+                    .unwrap()
+            ),
+            129
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_frame_becomes_a_capture() {
+        use crate::phy::params::PhyParams;
+        use crate::phy::{FecKind, LineCodingKind, PreambleKind};
+
+        let dir = std::env::temp_dir()
+            .join(format!("trackmaker-dump-{}-failures", std::process::id()));
+        let options = DebugDumpOptions {
+            plots: None,
+            captures: Some(dir.clone()),
+        };
+        std::fs::create_dir_all(&dir).unwrap();
+        let header = CaptureHeader::new(
+            &PhyParams::default(),
+            LineCodingKind::Manchester,
+            FecKind::None,
+            PreambleKind::BytePattern,
+            "crc",
+        );
+        let samples: Vec<f32> = (0..600)
+            .map(|i| (i as f32 * 0.1).sin())
+            .collect();
+        write_debug_dump(
+            &options,
+            DebugDump {
+                reason: "crc",
+                samples: samples.clone(),
+                sample_rate: 48000,
+                symbols: None,
+                capture: Some(header.clone()),
+            },
+        )
+        .unwrap();
+
+        let written = crate::phy::capture::list(&dir).unwrap();
+        assert_eq!(written.len(), 1);
+        assert!(
+            written[0]
+                .to_string_lossy()
+                .ends_with("-crc.tmk")
+        );
+        let capture = Capture::read(&written[0]).unwrap();
+        assert_eq!(capture.header, header);
+        assert_eq!(capture.samples, samples);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_frames_dump_their_constellation() {
+        use crate::phy::backend::ModulationBackend;
+        use crate::phy::params::PhyParams;
+        use crate::phy::psk::PskBackend;
+        use crate::phy::{FecKind, Frame, LineCodingKind};
+        use crate::phy::{PhyDecoder, PhyEncoder};
+        use crate::utils::consts::{
+            CHIRP_LEN_SAMPLES, PHY_HEADER_BYTES, PSK_SAMPLES_PER_SYMBOL,
+        };
+
+        let dir = std::env::temp_dir().join(format!(
+            "trackmaker-dump-{}-constellation",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        select_debug_dumps(DebugDumpOptions {
+            plots: Some(dir.clone()),
+            captures: None,
+        })
+        .unwrap();
+        let frame = Frame::new_data(3, 1, 2, vec![0x3C; 37]);
+        let bits = frame.to_bits().len();
+        // Constellations with a row per symbol of the frame, among whatever
+        // the other tests' decoders dump meanwhile
+        let dumped = || {
+            flush_debug_dumps(Duration::from_secs(10));
+            let files: Vec<PathBuf> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            let mut found = 0;
+            for path in &files {
+                if path
+                    .to_string_lossy()
+                    .ends_with("-crc-constellation.csv")
+                {
+                    let csv = std::fs::read_to_string(path).unwrap();
+                    if csv.lines().count() == bits + 1 {
+                        assert!(files.contains(&path.with_extension("png")));
+                        found += 1;
+                    }
+                }
+            }
+            for path in files {
+                std::fs::remove_file(path).unwrap();
+            }
+            found
+        };
+
+        let psk = PskBackend::new();
+        let mut samples = psk.encode_frame(&frame);
+        let flip = CHIRP_LEN_SAMPLES
+            + 8 * (PHY_HEADER_BYTES + 5) * PSK_SAMPLES_PER_SYMBOL;
+        for s in &mut samples[flip..flip + PSK_SAMPLES_PER_SYMBOL] {
+            *s = -*s;
+        }
+        samples.extend(vec![0.0; 200]);
+        assert!(
+            PskBackend::new()
+                .feed_samples(&samples)
+                .is_empty()
+        );
+        assert_eq!(dumped(), 1);
+
+        let kind = LineCodingKind::Manchester;
+        let params = PhyParams::default();
+        let encoder = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut decoder = PhyDecoder::new(&params, kind, FecKind::None, 2);
+        let mut samples = vec![0.0; 200];
+        samples.extend(encoder.encode_frame(&frame));
+        samples.extend(vec![0.0; 200]);
+        let bit = 2 * params.samples_per_level;
+        let flip =
+            200 + encoder.preamble_len() + 8 * (PHY_HEADER_BYTES + 5) * bit;
+        for s in &mut samples[flip..flip + bit] {
+            *s = -*s;
+        }
+        assert!(
+            decoder
+                .process_samples(&samples)
+                .is_empty()
+        );
+        assert_eq!(dumped(), 1);
+        // Other tests' decoders may be dumping here by now
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
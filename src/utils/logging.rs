@@ -1,15 +1,457 @@
-use tracing_subscriber::{EnvFilter, fmt};
+// Logging setup
+//
+// The level (--log-level, else RUST_LOG, else LOG_LEVEL) is the base of an
+// EnvFilter that --log-filter directives such as `trackmaker_rs::phy=warn`
+// are added to, later directives for the same target winning. Events are
+// either compact text on stderr or, with --log-json, one JSON object per
+// line on stdout; --log-file sends them to a file instead, rotated by size.
+// Progress bars and the banner go to stderr, so stdout stays parseable.
 
-pub fn init_logging() {
-    let env_filter = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new(crate::utils::consts::LOG_LEVEL))
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde_json::{Map, Value, json};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::net::pcap_utils::rotated_path;
+use crate::utils::consts::{LOG_LEVEL, LOG_ROTATE_BYTES};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogOptions {
+    /// Level for every target, None = RUST_LOG or LOG_LEVEL
+    pub level: Option<String>,
+    /// Comma-separated EnvFilter directives on top of the level
+    pub filter: Option<String>,
+    /// One JSON object per event instead of text
+    pub json: bool,
+    /// Write to this file instead of stdout / stderr
+    pub file: Option<PathBuf>,
+    /// Size at which the file is closed and the next one started
+    pub rotate_bytes: u64,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            level: None,
+            filter: None,
+            json: false,
+            file: None,
+            rotate_bytes: LOG_ROTATE_BYTES,
+        }
+    }
+}
+
+pub fn init_logging_with(options: &LogOptions) -> Result<(), String> {
+    let writer = match &options.file {
+        Some(path) => BoxMakeWriter::new(Mutex::new(RotatingFile::create(
+            path,
+            options.rotate_bytes,
+        )?)),
+        None if options.json => BoxMakeWriter::new(io::stdout),
+        None => BoxMakeWriter::new(io::stderr),
+    };
+    install(options, writer, options.file.is_none())
+}
+
+/// init_logging_with, but to `writer` (such as the dashboard's log pane)
+/// without colors, `options.file` is ignored
+pub fn init_logging_to(
+    options: &LogOptions,
+    writer: BoxMakeWriter,
+) -> Result<(), String> {
+    install(options, writer, false)
+}
+
+fn install(
+    options: &LogOptions,
+    writer: BoxMakeWriter,
+    ansi: bool,
+) -> Result<(), String> {
+    let env = std::env::var(EnvFilter::DEFAULT_ENV).ok();
+    let filter = build_filter(
+        options.level.as_deref(),
+        env.as_deref(),
+        options.filter.as_deref(),
+    )?;
+    subscriber(options, filter, writer, ansi)
+        .try_init()
+        .map_err(|e| format!("Failed to set up logging: {}", e))
+}
+
+/// Run `f` with text logging to stderr, for what is logged before the
+/// options for init_logging_with are known
+pub fn with_startup_logging<T>(f: impl FnOnce() -> T) -> T {
+    let filter = build_filter(None, None, None).expect("LOG_LEVEL is valid");
+    let subscriber = subscriber(
+        &LogOptions::default(),
+        filter,
+        BoxMakeWriter::new(io::stderr),
+        true,
+    );
+    tracing::subscriber::with_default(subscriber, f)
+}
+
+fn subscriber(
+    options: &LogOptions,
+    filter: EnvFilter,
+    writer: BoxMakeWriter,
+    ansi: bool,
+) -> Box<dyn Subscriber + Send + Sync> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    if options.json {
+        Box::new(
+            builder
+                .event_format(JsonFormat)
+                .finish(),
+        )
+    } else {
+        Box::new(
+            builder
+                .with_target(false)
+                .with_level(true)
+                .compact()
+                .finish(),
+        )
+    }
+}
+
+/// The filter for `level` (else `env`, RUST_LOG's value, else LOG_LEVEL)
+/// plus the `filter` directives
+pub fn build_filter(
+    level: Option<&str>,
+    env: Option<&str>,
+    filter: Option<&str>,
+) -> Result<EnvFilter, String> {
+    let base = match level {
+        Some(level) => {
+            LevelFilter::from_str(level)
+                .map_err(|_| format!("Unknown log level '{}'", level))?;
+            level
+        }
+        None => env
+            .filter(|env| !env.trim().is_empty())
+            .unwrap_or(LOG_LEVEL),
+    };
+    let directives = match filter {
+        Some(filter) if !filter.trim().is_empty() => {
+            format!("{},{}", base, filter)
+        }
+        _ => base.to_string(),
+    };
+    EnvFilter::builder()
+        .parse(&directives)
+        .map_err(|e| format!("Bad log filter '{}': {}", directives, e))
+}
+
+/// `{"timestamp", "level", "target", "fields": {"message", ...}}`
+pub struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        writeln!(writer, "{}", event_json(&timestamp, event))
+    }
+}
+
+fn event_json(timestamp: &str, event: &Event<'_>) -> Value {
+    let mut fields = JsonFields(Map::new());
+    event.record(&mut fields);
+    let metadata = event.metadata();
+    json!({
+        "timestamp": timestamp,
+        "level": metadata.level().as_str(),
+        "target": metadata.target(),
+        "fields": fields.0,
+    })
+}
+
+/// Numbers and booleans stay what they are, the rest is formatted
+struct JsonFields(Map<String, Value>);
+
+impl JsonFields {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.0
+            .insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, json!(format!("{:?}", value)));
+    }
+}
+
+/// A log file that moves on to `<stem>.1.<ext>`, `<stem>.2.<ext>` ... at
+/// `rotate_bytes`, keeping the earlier ones. Events are never split.
+pub struct RotatingFile {
+    base: PathBuf,
+    rotate_bytes: u64,
+    index: u32,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn create(path: &Path, rotate_bytes: u64) -> Result<Self, String> {
+        let base = path.to_path_buf();
+        Ok(Self {
+            file: Self::open(&base)?,
+            base,
+            rotate_bytes: rotate_bytes.max(1),
+            index: 0,
+            written: 0,
+        })
+    }
+
+    fn open(path: &Path) -> Result<BufWriter<File>, String> {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .map(BufWriter::new)
+            .map_err(|e| format!("Cannot open {}: {}", path.display(), e))
+    }
+
+    /// File the next event goes to
+    pub fn path(&self) -> PathBuf {
+        rotated_path(&self.base, self.index)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0
+            && self.written + buf.len() as u64 > self.rotate_bytes
+        {
+            self.file.flush()?;
+            self.index += 1;
+            self.file = Self::open(&self.path()).map_err(io::Error::other)?;
+            self.written = 0;
+        }
+        self.file.write_all(buf)?;
+        // Flushed per event so a crash loses nothing
+        self.file.flush()?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tracing::{debug, info, trace, warn};
+
+    /// Writer keeping everything logged to it
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .lock()
+                .unwrap()
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    fn capture(options: &LogOptions, f: impl FnOnce()) -> Vec<String> {
+        let filter = build_filter(
+            options.level.as_deref(),
+            None,
+            options.filter.as_deref(),
+        )
         .unwrap();
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = subscriber(
+            options,
+            filter,
+            BoxMakeWriter::new(move || writer.clone()),
+            false,
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        captured.lines()
+    }
+
+    #[test]
+    fn test_filter_parsing() {
+        assert!(build_filter(Some("debug"), None, None).is_ok());
+        assert!(build_filter(Some("loud"), None, None).is_err());
+        assert!(build_filter(None, None, Some("phy=nope")).is_err());
+        // The level beats RUST_LOG, which beats LOG_LEVEL
+        let hint = |level, env| {
+            build_filter(level, env, None)
+                .unwrap()
+                .max_level_hint()
+        };
+        assert_eq!(hint(Some("warn"), Some("trace")), Some(LevelFilter::WARN));
+        assert_eq!(hint(None, Some("trace")), Some(LevelFilter::TRACE));
+        assert_eq!(hint(None, Some(" ")), Some(LevelFilter::INFO));
+        assert_eq!(
+            build_filter(
+                Some("warn"),
+                None,
+                Some("trackmaker_rs::phy=trace,trackmaker_rs::net=off")
+            )
+            .unwrap()
+            .to_string()
+            .split(',')
+            .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_per_module_levels() {
+        let options = LogOptions {
+            level: Some("warn".to_string()),
+            filter: Some(
+                "trackmaker_rs::net::router=debug,trackmaker_rs::phy=error"
+                    .to_string(),
+            ),
+            ..LogOptions::default()
+        };
+        let lines = capture(&options, || {
+            debug!(target: "trackmaker_rs::net::router", "route added");
+            trace!(target: "trackmaker_rs::net::router", "too fine");
+            warn!(target: "trackmaker_rs::phy::decoder", "sync lost");
+            warn!(target: "trackmaker_rs::mac::csma", "retrying");
+            info!(target: "trackmaker_rs::mac::csma", "sent");
+        });
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].contains("route added"));
+        assert!(lines[1].contains("retrying"));
+    }
+
+    #[test]
+    fn test_json_events() {
+        let options = LogOptions {
+            json: true,
+            ..LogOptions::default()
+        };
+        let lines = capture(&options, || {
+            info!(
+                target: "trackmaker_rs::net::router",
+                packets = 12u64,
+                rtt_ms = 3.5,
+                up = true,
+                hop = ?"192.168.1.2",
+                "forwarded {} bytes",
+                640
+            );
+            warn!(target: "trackmaker_rs::phy", "quote \" and\nnewline");
+            debug!("filtered out");
+        });
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+
+        let event: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["target"], "trackmaker_rs::net::router");
+        assert!(
+            event["timestamp"]
+                .as_str()
+                .unwrap()
+                .ends_with('Z')
+        );
+        let fields = &event["fields"];
+        assert_eq!(fields["message"], "forwarded 640 bytes");
+        assert_eq!(fields["packets"], 12);
+        assert_eq!(fields["rtt_ms"], 3.5);
+        assert_eq!(fields["up"], true);
+        assert_eq!(fields["hop"], "\"192.168.1.2\"");
+
+        let event: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(event["level"], "WARN");
+        assert_eq!(event["fields"]["message"], "quote \" and\nnewline");
+    }
 
-    fmt()
-        .with_env_filter(env_filter)
-        .with_target(false)
-        .with_level(true)
-        .compact()
-        .with_writer(std::io::stdout)
-        .init();
+    #[test]
+    fn test_log_file_rotates() {
+        let path = std::env::temp_dir()
+            .join(format!("trackmaker-log-{}-rotate.log", std::process::id()));
+        let mut file = RotatingFile::create(&path, 100).unwrap();
+        for i in 0..5 {
+            file.write_all(format!("{:039}\n", i).as_bytes())
+                .unwrap();
+        }
+        // Two 40-byte events fit in each file
+        assert_eq!(file.path(), rotated_path(&path, 2));
+        let lines: Vec<usize> = (0..3)
+            .map(|i| {
+                let file = rotated_path(&path, i);
+                let text = std::fs::read_to_string(&file).unwrap();
+                std::fs::remove_file(&file).unwrap();
+                text.lines().count()
+            })
+            .collect();
+        assert_eq!(lines, vec![2, 2, 1]);
+    }
 }
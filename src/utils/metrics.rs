@@ -0,0 +1,541 @@
+// Prometheus metrics
+//
+// Process-wide counters and histograms that the PHY decoder and the MACs
+// (CsmaNode, AcousticInterface) update as they go, and a minimal HTTP
+// server (--metrics-addr) answering GET /metrics with the text exposition
+// format, version 0.0.4. Gauges are worked out at scrape time: the busy
+// ratio from the carrier sense counters, the rest by collectors, such as
+// the one a running router registers for its per-interface counters and
+// NAT sessions (see net::router).
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::utils::consts::{METRICS_MAX_REQUEST_BYTES, METRICS_RTT_BUCKETS_MS};
+
+/// Prefix of every metric name
+pub const NAMESPACE: &str = "trackmaker";
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Observations counted into buckets with fixed upper bounds
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    /// Observations up to each bound, not cumulative
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            buckets: bounds
+                .iter()
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Some(slot) = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+        {
+            self.buckets[slot].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count
+            .fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |bits| Some((f64::from_bits(bits) + value).to_bits()),
+        );
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+            .load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(
+            self.sum
+                .load(Ordering::Relaxed),
+        )
+    }
+
+    /// (upper bound, observations up to it) per bucket, `+Inf` last
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        let mut buckets: Vec<(f64, u64)> = self
+            .bounds
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&bound, count)| {
+                total += count.load(Ordering::Relaxed);
+                (bound, total)
+            })
+            .collect();
+        buckets.push((f64::INFINITY, self.count()));
+        buckets
+    }
+}
+
+/// Adds metrics owned elsewhere to a scrape
+pub type Collector = Box<dyn Fn(&mut Exposition) + Send + Sync>;
+
+pub struct Metrics {
+    /// Frames put on the air, ACKs and retransmissions included
+    pub frames_tx: Counter,
+    /// Frames for us decoded with a matching checksum
+    pub frames_rx: Counter,
+    /// Frames for us whose checksum did not match
+    pub crc_errors: Counter,
+    /// Time from sending a frame to its ACK
+    pub link_rtt_ms: Histogram,
+    /// Carrier sense decisions, and how many found the channel busy
+    pub channel_sensed: Counter,
+    pub channel_busy: Counter,
+    collectors: Mutex<Vec<(String, Collector)>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            frames_tx: Counter::default(),
+            frames_rx: Counter::default(),
+            crc_errors: Counter::default(),
+            link_rtt_ms: Histogram::new(&METRICS_RTT_BUCKETS_MS),
+            channel_sensed: Counter::default(),
+            channel_busy: Counter::default(),
+            collectors: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// The metrics of this process
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    pub fn record_rtt(&self, rtt: Duration) {
+        self.link_rtt_ms
+            .observe(rtt.as_secs_f64() * 1000.0);
+    }
+
+    pub fn record_channel_sense(&self, busy: bool) {
+        self.channel_sensed.inc();
+        if busy {
+            self.channel_busy.inc();
+        }
+    }
+
+    /// Share of carrier sense decisions that found the channel busy
+    pub fn channel_busy_ratio(&self) -> f64 {
+        self.channel_busy.get() as f64
+            / self
+                .channel_sensed
+                .get()
+                .max(1) as f64
+    }
+
+    /// Add `collector` to every scrape, replacing one of the same name
+    pub fn register(&self, name: &str, collector: Collector) {
+        if let Ok(mut collectors) = self.collectors.lock() {
+            collectors.retain(|(other, _)| other != name);
+            collectors.push((name.to_string(), collector));
+        }
+    }
+
+    pub fn unregister(&self, name: &str) {
+        if let Ok(mut collectors) = self.collectors.lock() {
+            collectors.retain(|(other, _)| other != name);
+        }
+    }
+
+    /// Everything in the text exposition format
+    pub fn render(&self) -> String {
+        let mut out = Exposition::default();
+        out.counter(
+            "frames_tx_total",
+            "Frames put on the air",
+            &[],
+            self.frames_tx.get(),
+        );
+        out.counter(
+            "frames_rx_total",
+            "Frames decoded with a matching checksum",
+            &[],
+            self.frames_rx.get(),
+        );
+        out.counter(
+            "crc_errors_total",
+            "Frames whose checksum did not match",
+            &[],
+            self.crc_errors.get(),
+        );
+        out.counter(
+            "channel_sensed_total",
+            "Carrier sense decisions",
+            &[],
+            self.channel_sensed.get(),
+        );
+        out.counter(
+            "channel_busy_total",
+            "Carrier sense decisions that found the channel busy",
+            &[],
+            self.channel_busy.get(),
+        );
+        out.gauge(
+            "channel_busy_ratio",
+            "Share of carrier sense decisions that found the channel busy",
+            &[],
+            self.channel_busy_ratio(),
+        );
+        out.histogram(
+            "link_rtt_ms",
+            "Time from sending a frame to its ACK",
+            &self.link_rtt_ms,
+        );
+        if let Ok(collectors) = self.collectors.lock() {
+            for (_, collector) in collectors.iter() {
+                collector(&mut out);
+            }
+        }
+        out.0
+    }
+}
+
+/// A scrape being written, one HELP / TYPE block per metric
+#[derive(Default)]
+pub struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP {}_{} {}", NAMESPACE, name, help);
+        let _ = writeln!(self.0, "# TYPE {}_{} {}", NAMESPACE, name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let _ = write!(self.0, "{}_{}", NAMESPACE, name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", format_value(value));
+    }
+
+    pub fn counter(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        value: u64,
+    ) {
+        self.header(name, help, "counter");
+        self.sample(name, labels, value as f64);
+    }
+
+    pub fn gauge(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        self.header(name, help, "gauge");
+        self.sample(name, labels, value);
+    }
+
+    /// One metric with a sample per label set, e.g. per interface
+    pub fn counter_family(
+        &mut self,
+        name: &str,
+        help: &str,
+        samples: &[(Vec<(&str, &str)>, u64)],
+    ) {
+        self.header(name, help, "counter");
+        for (labels, value) in samples {
+            self.sample(name, labels, *value as f64);
+        }
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "histogram");
+        let bucket = format!("{}_bucket", name);
+        for (bound, count) in histogram.cumulative() {
+            let le = format_value(bound);
+            self.sample(&bucket, &[("le", &le)], count as f64);
+        }
+        self.sample(&format!("{}_sum", name), &[], histogram.sum());
+        self.sample(&format!("{}_count", name), &[], histogram.count() as f64);
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer scrapes on `listener` as long as `running` says so, one at a
+/// time
+pub fn serve(
+    listener: TcpListener,
+    metrics: &Metrics,
+    running: impl Fn() -> bool,
+) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        warn!("Metrics endpoint unusable: {}", e);
+        return;
+    }
+    while running() {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(e) = handle_connection(stream, metrics) {
+                    debug!("Metrics request from {} failed: {}", peer, e);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => warn!("Metrics accept failed: {}", e),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader =
+        BufReader::new(stream).take(METRICS_MAX_REQUEST_BYTES as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are read and ignored
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && !line.trim().is_empty() {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path))
+            if path == "/metrics" || path.starts_with("/metrics?") =>
+        {
+            ("200 OK", metrics.render())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("400 Bad Request", "Bad request\n".to_string()),
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    writer.flush()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicBool;
+
+    /// GET `path` from a server for `metrics`, the status line and body
+    pub(crate) fn scrape(metrics: &Metrics, path: &str) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let running = AtomicBool::new(true);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                serve(listener, metrics, || running.load(Ordering::SeqCst))
+            });
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: text/plain\r\n\r\n",
+                path, addr
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream
+                .read_to_string(&mut response)
+                .unwrap();
+            running.store(false, Ordering::SeqCst);
+            let (head, body) = response
+                .split_once("\r\n\r\n")
+                .unwrap();
+            (
+                head.lines()
+                    .next()
+                    .unwrap()
+                    .to_string(),
+                body.to_string(),
+            )
+        })
+    }
+
+    /// Sample lines by name with labels, e.g. `x_bucket{le="50"}`
+    pub(crate) fn parse(body: &str) -> HashMap<String, f64> {
+        body.lines()
+            .filter(|line| !line.starts_with('#') && !line.is_empty())
+            .map(|line| {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                let value = match value {
+                    "+Inf" => f64::INFINITY,
+                    value => value.parse().unwrap(),
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scrape_after_activity() {
+        let metrics = Metrics::default();
+        metrics.frames_tx.add(5);
+        metrics.frames_tx.inc();
+        metrics.frames_rx.add(4);
+        metrics.crc_errors.inc();
+        for busy in [true, false, false, false] {
+            metrics.record_channel_sense(busy);
+        }
+        for ms in [40, 120, 130, 20_000] {
+            metrics.record_rtt(Duration::from_millis(ms));
+        }
+        metrics.register(
+            "nat",
+            Box::new(|out| out.gauge("nat_sessions", "NAT sessions", &[], 3.0)),
+        );
+
+        let (status, body) = scrape(&metrics, "/metrics");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(body.contains("# TYPE trackmaker_frames_tx_total counter\n"));
+        assert!(body.contains("# TYPE trackmaker_link_rtt_ms histogram\n"));
+        let samples = parse(&body);
+        assert_eq!(samples["trackmaker_frames_tx_total"], 6.0);
+        assert_eq!(samples["trackmaker_frames_rx_total"], 4.0);
+        assert_eq!(samples["trackmaker_crc_errors_total"], 1.0);
+        assert_eq!(samples["trackmaker_channel_busy_ratio"], 0.25);
+        assert_eq!(samples["trackmaker_nat_sessions"], 3.0);
+        assert_eq!(samples["trackmaker_link_rtt_ms_bucket{le=\"50\"}"], 1.0);
+        assert_eq!(samples["trackmaker_link_rtt_ms_bucket{le=\"200\"}"], 3.0);
+        assert_eq!(samples["trackmaker_link_rtt_ms_bucket{le=\"10000\"}"], 3.0);
+        assert_eq!(samples["trackmaker_link_rtt_ms_bucket{le=\"+Inf\"}"], 4.0);
+        assert_eq!(samples["trackmaker_link_rtt_ms_count"], 4.0);
+        assert_eq!(samples["trackmaker_link_rtt_ms_sum"], 20_290.0);
+
+        metrics.unregister("nat");
+        let (_, body) = scrape(&metrics, "/metrics");
+        assert!(!parse(&body).contains_key("trackmaker_nat_sessions"));
+    }
+
+    #[test]
+    fn test_decoded_frames_counted() {
+        use crate::phy::params::PhyParams;
+        use crate::phy::{
+            FecKind, Frame, LineCodingKind, PhyDecoder, PhyEncoder,
+        };
+
+        let params = PhyParams::default();
+        let kind = LineCodingKind::FourBFiveB;
+        let encoder = PhyEncoder::new(&params, kind, FecKind::None);
+        let mut decoder = PhyDecoder::new(&params, kind, FecKind::None, 2);
+        let before = global().frames_rx.get();
+        let mut samples = vec![0.0; 200];
+        samples.extend(encoder.encode_frame(&Frame::new_data(
+            1,
+            1,
+            2,
+            vec![7; 32],
+        )));
+        samples.extend(vec![0.0; 200]);
+        assert_eq!(
+            decoder
+                .process_samples(&samples)
+                .len(),
+            1
+        );
+
+        // Other tests decode meanwhile, so at least one more
+        let (_, body) = scrape(global(), "/metrics");
+        assert!(parse(&body)["trackmaker_frames_rx_total"] > before as f64);
+    }
+
+    #[test]
+    fn test_other_paths_not_found() {
+        let metrics = Metrics::default();
+        assert_eq!(scrape(&metrics, "/").0, "HTTP/1.1 404 Not Found");
+        assert_eq!(scrape(&metrics, "/metrics?x=1").0, "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn test_labels_escaped() {
+        let mut out = Exposition::default();
+        out.counter_family(
+            "router_packets_total",
+            "Packets",
+            &[
+                (vec![("interface", "wifi"), ("direction", "rx")], 7),
+                (vec![("interface", "a\"b")], 1),
+            ],
+        );
+        assert_eq!(
+            out.0,
+            "# HELP trackmaker_router_packets_total Packets\n\
+             # TYPE trackmaker_router_packets_total counter\n\
+             trackmaker_router_packets_total{interface=\"wifi\",direction=\"rx\"} 7\n\
+             trackmaker_router_packets_total{interface=\"a\\\"b\"} 1\n"
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod config;
 pub mod consts;
 pub mod dump;
 pub mod logging;
+pub mod metrics;
+pub mod report;
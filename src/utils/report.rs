@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::mac::stats::LinkStats;
+
+pub const REPORT_FILE_NAME: &str = "report.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    Completed,
+    Aborted,
+}
+
+/// Configuration a Tx/Rx session ran with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionConfig {
+    /// "tx" or "rx"
+    pub role: String,
+    pub encoding: String,
+    /// "baseband" or "psk", see phy::backend::ModulationKind
+    #[serde(default = "default_modulation")]
+    pub modulation: String,
+    pub sample_rate: u32,
+    pub samples_per_level: usize,
+    pub fec: String,
+    pub local_addr: u8,
+    pub remote_addr: u8,
+    pub aggregate: bool,
+}
+
+/// Reports from before --modulation ran baseband
+fn default_modulation() -> String {
+    "baseband".to_string()
+}
+
+/// The file that was sent or written
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileReport {
+    pub path: String,
+    pub size_bytes: u64,
+    /// FNV-1a 64-bit digest, hex encoded
+    pub digest: String,
+    /// The file passed the SHA-256 check of the transfer protocol (see
+    /// mac::file_transfer), on the sender as the receiver's verdict. None
+    /// when it was never checked.
+    pub verified: Option<bool>,
+    /// Bytes that went over the link deflated (`--compress`), None when
+    /// the file went as is
+    #[serde(default)]
+    pub compressed_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimingReport {
+    pub started_unix_secs: u64,
+    pub total_secs: f64,
+    pub airtime_secs: f64,
+}
+
+/// Final report of a Tx/Rx session, written as report.json and printed by
+/// `--json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionReport {
+    pub status: SessionStatus,
+    pub config: SessionConfig,
+    pub link: LinkStats,
+    pub file: Option<FileReport>,
+    pub timing: TimingReport,
+    pub errors: Vec<String>,
+}
+
+impl SessionReport {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// Write report.json into `session_dir`, creating it if needed
+    pub fn write(
+        &self,
+        session_dir: &Path,
+    ) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(session_dir)?;
+        let path = session_dir.join(REPORT_FILE_NAME);
+        std::fs::write(&path, self.to_json()?)?;
+        Ok(path)
+    }
+}
+
+impl FileReport {
+    pub fn new(path: &str, data: &[u8]) -> Self {
+        Self {
+            path: path.to_string(),
+            size_bytes: data.len() as u64,
+            digest: file_digest(data),
+            verified: None,
+            compressed_bytes: None,
+        }
+    }
+}
+
+/// FNV-1a 64-bit digest of `data`, hex encoded
+pub fn file_digest(data: &[u8]) -> String {
+    let hash = data
+        .iter()
+        .fold(0xcbf29ce484222325u64, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Default session directory: ./tmp/sessions/<role>-<unix secs>
+pub fn default_session_dir(role: &str, started_unix_secs: u64) -> PathBuf {
+    PathBuf::from("./tmp/sessions")
+        .join(format!("{}-{}", role, started_unix_secs))
+}
+
+pub fn unix_now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_report(status: SessionStatus) -> SessionReport {
+        SessionReport {
+            status,
+            config: SessionConfig {
+                role: "rx".to_string(),
+                encoding: "4B5B".to_string(),
+                modulation: "baseband".to_string(),
+                sample_rate: 48000,
+                samples_per_level: 3,
+                fec: "none".to_string(),
+                local_addr: 2,
+                remote_addr: 1,
+                aggregate: false,
+            },
+            link: LinkStats {
+                frames_received: 4,
+                acks_sent: 4,
+                ..Default::default()
+            },
+            file: Some(FileReport::new("OUTPUT1to2.bin", b"hello")),
+            timing: TimingReport {
+                started_unix_secs: 1,
+                total_secs: 2.5,
+                airtime_secs: 0.1,
+            },
+            errors: vec![],
+        }
+    }
+
+    #[test]
+    fn test_report_roundtrip_and_fields() {
+        let dir = std::env::temp_dir()
+            .join(format!("trackmaker-report-{}", std::process::id()));
+        let report = sample_report(SessionStatus::Completed);
+        let path = report.write(&dir).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let parsed: SessionReport = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed, report);
+
+        let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+        for field in ["status", "config", "link", "file", "timing", "errors"] {
+            assert!(value.get(field).is_some(), "missing {}", field);
+        }
+        assert_eq!(value["status"], "completed");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_aborted_status_serialization() {
+        let mut report = sample_report(SessionStatus::Aborted);
+        report.file = None;
+        report
+            .errors
+            .push("Interrupted".to_string());
+        let value: serde_json::Value =
+            serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(value["status"], "aborted");
+        assert!(value["file"].is_null());
+    }
+
+    #[test]
+    fn test_file_digest() {
+        // Reference values of FNV-1a 64
+        assert_eq!(file_digest(b""), "cbf29ce484222325");
+        assert_eq!(file_digest(b"a"), "af63dc4c8601ec8c");
+    }
+}
@@ -0,0 +1,132 @@
+// The realtime process callback must not allocate
+//
+// Installs a counting global allocator, which is why this is a test binary
+// of its own: the counter only sees allocations made by the thread playing
+// the sound card, and only while it is inside the callback.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use trackmaker_rs::audio::recorder::{
+    AppShared, AppState, build_process_closure,
+};
+
+/// Counts allocations made by threads that asked for it
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING
+            .try_with(|counting| counting.get())
+            .unwrap_or(false)
+        {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_callback_streams_64_sample_blocks_without_allocating() {
+    const BLOCK: usize = 64;
+    const BLOCKS: usize = 20_000;
+    let total = BLOCK * BLOCKS;
+    let shared = AppShared::new(BLOCK * 16);
+    shared
+        .app_state
+        .set(AppState::RecordingAndPlaying);
+
+    // The MAC side: queues the outgoing stream, drains the recording
+    let feeder = {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let samples: Vec<f32> = (0..total)
+                .map(|i| -(i as f32))
+                .collect();
+            let mut queued = 0;
+            while queued < total {
+                queued += shared
+                    .playback_buffer
+                    .push_slice(
+                        &samples[queued
+                            ..(queued + shared.playback_buffer.free())
+                                .min(total)],
+                    );
+                thread::yield_now();
+            }
+            shared
+                .playback_buffer
+                .finish();
+        })
+    };
+    let drain = {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let mut recorded = Vec::with_capacity(total);
+            while recorded.len() < total {
+                recorded.extend(shared.record_buffer.pop_all());
+                thread::yield_now();
+            }
+            recorded
+        })
+    };
+
+    // The sound card: a block whenever both rings are ready for one
+    let mut process = build_process_closure(shared.clone());
+    let mut input = [0.0; BLOCK];
+    let mut output = [0.0; BLOCK];
+    let mut played = Vec::with_capacity(total);
+    for block in 0..BLOCKS {
+        while shared.playback_buffer.len() < BLOCK
+            || shared.record_buffer.free() < BLOCK
+        {
+            thread::yield_now();
+        }
+        for (i, sample) in input.iter_mut().enumerate() {
+            *sample = (block * BLOCK + i) as f32;
+        }
+        COUNTING.with(|counting| counting.set(true));
+        process(&input, &mut output);
+        COUNTING.with(|counting| counting.set(false));
+        played.extend_from_slice(&output);
+    }
+    // Played out, back to recording only
+    feeder.join().unwrap();
+    while shared.record_buffer.free() < BLOCK {
+        thread::yield_now();
+    }
+    process(&input, &mut output);
+
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+    assert_eq!(shared.record_overflows(), 0);
+    assert_eq!(shared.playback_underflows(), 0);
+    assert_eq!(shared.app_state.get(), AppState::Recording);
+    assert!(
+        played
+            .iter()
+            .enumerate()
+            .all(|(i, s)| *s == -(i as f32))
+    );
+    let recorded = drain.join().unwrap();
+    assert!(
+        recorded[..total]
+            .iter()
+            .enumerate()
+            .all(|(i, s)| *s == i as f32)
+    );
+}